@@ -0,0 +1,212 @@
+//! Executor benchmarks, each run at several `ExecConfig::concurrency` levels.
+
+use std::{sync::Arc, time::Duration};
+
+use bumpalo::Bump;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rdb_analyzer::{
+  data::{
+    kv::chaos::{ChaosConfig, ChaosKv, LatencyDistribution, OpFaults},
+    mock_kv::MockKv,
+    treewalker::{
+      asm::codegen::compile_twscript,
+      exec::{generate_root_map, ExecConfig, Executor},
+      typeck::GlobalTyckContext,
+      vm::TwVm,
+      vm_value::{VmListValue, VmListValueKind, VmType, VmValue},
+    },
+    value::PrimitiveValue,
+  },
+  schema::{
+    compile::{compile, CompiledSchema, PrimitiveType},
+    grammar::parse,
+  },
+  storage_plan::{planner::generate_plan_for_schema, StoragePlan},
+};
+use tokio::runtime::Runtime;
+
+const CONCURRENCY_LEVELS: &[usize] = &[1, 8, 64];
+const FIELD_CHAIN_DEPTH: usize = 32;
+const SET_SIZE: i64 = 1000;
+
+/// A schema with its plan, and a KV store to run scripts against.
+struct Fixture {
+  rt: Runtime,
+  schema: CompiledSchema,
+  plan: StoragePlan,
+  kv: ChaosKv<MockKv>,
+}
+
+impl Fixture {
+  fn new(source: &str) -> Self {
+    let alloc = Bump::new();
+    let ast = parse(&alloc, source).unwrap();
+    let schema = compile(&ast).unwrap();
+    let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+    Self {
+      rt: Runtime::new().unwrap(),
+      schema,
+      plan,
+      kv: bench_kv(),
+    }
+  }
+
+  fn root_params<'a>(&'a self, params: Vec<Arc<VmValue<'a>>>) -> Vec<Arc<VmValue<'a>>> {
+    let mut all_params = vec![Arc::new(
+      generate_root_map(&self.schema, &self.plan).unwrap(),
+    )];
+    all_params.extend(params);
+    all_params
+  }
+
+  /// Runs `code` once, with `params` after the root map.
+  fn run_once<'a>(&'a self, code: &str, params: Vec<Arc<VmValue<'a>>>) {
+    let script = compile_twscript(code).unwrap();
+    let vm = TwVm::new(&self.schema, &self.plan, &script).unwrap();
+    let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+    let mut executor = Executor::new(&vm, &self.kv, &type_info);
+    self
+      .rt
+      .block_on(executor.run_graph(0, &self.root_params(params)))
+      .unwrap();
+  }
+
+  /// Benchmarks `code` at each concurrency level, with `params` after the root map.
+  fn bench<'a>(&'a self, c: &mut Criterion, name: &str, code: &str, params: Vec<Arc<VmValue<'a>>>) {
+    let script = compile_twscript(code).unwrap();
+    let vm = TwVm::new(&self.schema, &self.plan, &script).unwrap();
+    let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+    let params = self.root_params(params);
+
+    let mut group = c.benchmark_group(name);
+    for &concurrency in CONCURRENCY_LEVELS {
+      group.bench_with_input(
+        BenchmarkId::new("concurrency", concurrency),
+        &concurrency,
+        |b, &concurrency| {
+          let config = ExecConfig {
+            concurrency,
+            ..Default::default()
+          };
+          b.iter(|| {
+            let mut executor = Executor::new_with_config(&vm, &self.kv, &type_info, &config);
+            self.rt.block_on(executor.run_graph(0, &params)).unwrap()
+          })
+        },
+      );
+    }
+    group.finish();
+  }
+}
+
+/// Adds the latency in `RDB_BENCH_KV_LATENCY_US` to every read, to measure how well the executor
+/// overlaps outstanding requests. Tokio timers have millisecond granularity.
+fn bench_kv() -> ChaosKv<MockKv> {
+  let latency = std::env::var("RDB_BENCH_KV_LATENCY_US")
+    .ok()
+    .map(|x| Duration::from_micros(x.parse().expect("bad RDB_BENCH_KV_LATENCY_US")))
+    .unwrap_or_default();
+  let read = OpFaults {
+    latency: LatencyDistribution::Constant(latency),
+    error_rate: 0.0,
+  };
+  let mut kv = ChaosKv::new(
+    MockKv::new(),
+    ChaosConfig {
+      get: read.clone(),
+      scan_keys: read.clone(),
+      scan_next: read,
+      ..Default::default()
+    },
+  );
+  kv.set_sleep_fn(|x| Box::pin(tokio::time::sleep(x)));
+  kv
+}
+
+fn int64_list<'a>(n: i64) -> Arc<VmValue<'a>> {
+  let mut list = rpds::List::new_sync();
+  for i in 0..n {
+    list.push_front_mut(Arc::new(VmValue::Primitive(PrimitiveValue::Int64(i))));
+  }
+  Arc::new(VmValue::List(VmListValue {
+    member_ty: VmType::Primitive(PrimitiveType::Int64),
+    kind: VmListValueKind::Fresh(list),
+  }))
+}
+
+fn field_chain(c: &mut Criterion) {
+  let mut source = String::new();
+  for i in 0..FIELD_CHAIN_DEPTH {
+    source += &format!("type T{} {{ next: T{} }}\n", i, i + 1);
+  }
+  source += &format!("type T{} {{ value: int64 }}\n", FIELD_CHAIN_DEPTH);
+  source += "export T0 head;\n";
+  let fixture = Fixture::new(&source);
+
+  let path = format!("root.head{}", ".next".repeat(FIELD_CHAIN_DEPTH));
+  fixture.run_once(
+    &format!(
+      "graph main(root: schema) {{ t_insert(value) {} 42; }}",
+      path
+    ),
+    vec![],
+  );
+  fixture.bench(
+    c,
+    "field_chain",
+    &format!(
+      "graph main(root: schema): int64 {{ return {}.value; }}",
+      path
+    ),
+    vec![],
+  );
+}
+
+const SET_SCHEMA: &str = r#"
+type Item {
+  @primary
+  id: int64,
+  value: int64,
+}
+type Store {
+  items: set<Item>,
+}
+export Store store;
+"#;
+
+const BULK_INSERT: &str = r#"
+graph main(root: schema, ids: list<int64>) {
+  t_insert(items) root.store $ build_set $ reduce(to_item) create_map create_list(Item) ids;
+}
+graph to_item(_unused: map{}, current: list<Item>, id: int64): list<Item> {
+  return (build_table(Item) $ m_insert(id) id $ m_insert(value) id create_map) : current;
+}
+"#;
+
+const REDUCE_SUM: &str = r#"
+graph main(root: schema): int64 {
+  return reduce(sum) create_map 0 root.store.items;
+}
+graph sum(_unused: map{}, current: int64, item: Item): int64 {
+  return current + item.value;
+}
+"#;
+
+fn set_reduce(c: &mut Criterion) {
+  let fixture = Fixture::new(SET_SCHEMA);
+  fixture.run_once(BULK_INSERT, vec![int64_list(SET_SIZE)]);
+  fixture.bench(c, "set_reduce", REDUCE_SUM, vec![]);
+}
+
+fn fresh_set_insert(c: &mut Criterion) {
+  let fixture = Fixture::new(SET_SCHEMA);
+  fixture.bench(
+    c,
+    "fresh_set_insert",
+    BULK_INSERT,
+    vec![int64_list(SET_SIZE)],
+  );
+}
+
+criterion_group!(benches, field_chain, set_reduce, fresh_set_insert);
+criterion_main!(benches);
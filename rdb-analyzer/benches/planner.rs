@@ -0,0 +1,89 @@
+//! Benchmarks of the compile-time passes: storage plan generation and script typeck.
+
+use bumpalo::Bump;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rdb_analyzer::{
+  data::treewalker::{asm::codegen::compile_twscript, typeck::GlobalTyckContext, vm::TwVm},
+  schema::{
+    compile::{compile, CompiledSchema},
+    grammar::parse,
+  },
+  storage_plan::planner::generate_plan_for_schema,
+};
+
+const SCHEMA_SIZES: &[usize] = &[10, 100, 500];
+const SCRIPT_SIZES: &[usize] = &[10, 100, 500];
+
+fn compile_schema(source: &str) -> CompiledSchema {
+  let alloc = Bump::new();
+  let ast = parse(&alloc, source).unwrap();
+  compile(&ast).unwrap()
+}
+
+/// A schema with `n` types that each have primitive fields, a nested table and a set of the next
+/// type, with every type exported.
+fn large_schema(n: usize) -> String {
+  let mut source = String::new();
+  for i in 0..n {
+    source += &format!(
+      "type T{i} {{ @primary id: string, a: int64, b: string, c: list<double>, d: map<string, bytes>, ",
+      i = i
+    );
+    if i + 1 < n {
+      source += &format!("next: T{j}, children: set<T{j}>, ", j = i + 1);
+    }
+    source += "}\n";
+    source += &format!("export T{i} t{i};\n", i = i);
+  }
+  source
+}
+
+/// A script with `n` graphs, each adding a chain of constants and calling the next graph.
+fn large_script(n: usize) -> String {
+  let mut source = String::from("graph main(root: schema): int64 { return call(g0) [0]; }\n");
+  for i in 0..n {
+    source += &format!("graph g{}(x: int64): int64 {{\n  a0 = x + 1;\n", i);
+    for j in 1..16 {
+      source += &format!("  a{} = a{} + {};\n", j, j - 1, j);
+    }
+    if i + 1 < n {
+      source += &format!("  return call(g{}) [a15];\n}}\n", i + 1);
+    } else {
+      source += "  return a15;\n}\n";
+    }
+  }
+  source
+}
+
+fn plan_generation(c: &mut Criterion) {
+  let mut group = c.benchmark_group("plan_generation");
+  for &n in SCHEMA_SIZES {
+    let schema = compile_schema(&large_schema(n));
+    group.bench_with_input(BenchmarkId::new("fresh", n), &schema, |b, schema| {
+      b.iter(|| generate_plan_for_schema(&Default::default(), &Default::default(), schema).unwrap())
+    });
+
+    let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+    group.bench_with_input(BenchmarkId::new("migrate", n), &schema, |b, schema| {
+      b.iter(|| generate_plan_for_schema(&plan, schema, schema).unwrap())
+    });
+  }
+  group.finish();
+}
+
+fn script_typeck(c: &mut Criterion) {
+  let schema = compile_schema("");
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let mut group = c.benchmark_group("script_typeck");
+  for &n in SCRIPT_SIZES {
+    let script = compile_twscript(&large_script(n)).unwrap();
+    let vm = TwVm::new(&schema, &plan, &script).unwrap();
+    group.bench_with_input(BenchmarkId::from_parameter(n), &vm, |b, vm| {
+      b.iter(|| GlobalTyckContext::new(vm).unwrap().typeck().unwrap())
+    });
+  }
+  group.finish();
+}
+
+criterion_group!(benches, plan_generation, script_typeck);
+criterion_main!(benches);
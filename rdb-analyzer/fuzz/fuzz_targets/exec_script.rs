@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use once_cell::sync::Lazy;
+use rdb_analyzer::{
+  data::treewalker::fuzz::{fuzz_schema, run_fuzz_input},
+  schema::compile::CompiledSchema,
+  storage_plan::StoragePlan,
+};
+
+static SCHEMA: Lazy<(CompiledSchema, StoragePlan)> = Lazy::new(|| fuzz_schema().unwrap());
+
+fuzz_target!(|data: &[u8]| {
+  run_fuzz_input(&SCHEMA.0, &SCHEMA.1, data);
+});
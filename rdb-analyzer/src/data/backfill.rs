@@ -0,0 +1,348 @@
+use std::{collections::HashSet, sync::Arc};
+
+use anyhow::Result;
+use async_recursion::async_recursion;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+  schema::compile::{CompiledSchema, FieldType},
+  storage_plan::StoragePlan,
+};
+
+use super::{
+  keyenc::successor_of_bounded_prefix,
+  kv::{KeyValueStore, KvTransaction, ScanOptions},
+  pathwalker::{get_with_rename_fallback, PathWalker},
+  value::PrimitiveValue,
+};
+
+#[derive(Error, Debug)]
+pub enum BackfillError {
+  #[error("missing type: {0}")]
+  MissingType(String),
+}
+
+/// The progress of the backfill of the sort key index of a set, stored at `K 0x06`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SortIndexProgress {
+  /// The id of the backfill, from `StorageNode::sort_index_backfill`. Progress recorded for
+  /// another id is discarded.
+  pub backfill_id: u64,
+
+  /// The encoded primary key of the last member indexed.
+  pub last_primary_key: Option<Vec<u8>>,
+
+  /// Whether all members are indexed.
+  pub ready: bool,
+}
+
+/// Options of `backfill_sort_indexes`.
+#[derive(Clone, Debug)]
+pub struct BackfillOptions {
+  /// Max number of members indexed in one transaction.
+  pub batch_size: usize,
+}
+
+impl Default for BackfillOptions {
+  fn default() -> Self {
+    Self { batch_size: 100 }
+  }
+}
+
+/// Result of `backfill_sort_indexes`.
+#[derive(Clone, Debug, Default)]
+pub struct BackfillReport {
+  pub sets_checked: u64,
+
+  /// Number of sets whose backfill completed in this run.
+  pub sets_backfilled: u64,
+
+  /// Number of sort key index entries written.
+  pub members_indexed: u64,
+}
+
+/// Backfills the sort key index of the sets reachable from the exports of `schema` that `plan`
+/// marks with `StorageNode::sort_index_backfill`. Scans by sort key on such a set fail until its
+/// backfill is complete.
+///
+/// Runs against a live store: each transaction records its progress, so an interrupted backfill
+/// resumes where it stopped, and members written concurrently are indexed by the executor. Once
+/// all sets are backfilled, the marks can be dropped with
+/// `StoragePlan::clear_sort_index_backfills`. Sets nested in list elements are not reached, and
+/// the planner never marks them.
+pub async fn backfill_sort_indexes(
+  store: &dyn KeyValueStore,
+  schema: &CompiledSchema,
+  plan: &StoragePlan,
+  options: &BackfillOptions,
+) -> Result<BackfillReport> {
+  let batch_size = options.batch_size.max(1);
+  let mut report = BackfillReport::default();
+  let mut pending = vec![];
+
+  let txn = store.begin_transaction().await?;
+  for (export_name, export_ty) in &schema.exports {
+    let walker = PathWalker::from_export(plan, export_name)?;
+    match export_ty {
+      // Top-level tables do not necessarily have their own key written.
+      FieldType::Table(x) => collect_table_sets(&*txn, schema, x, walker, &mut pending).await?,
+      _ => collect_sets(&*txn, schema, export_ty, walker, &mut pending).await?,
+    }
+  }
+  txn.commit().await?;
+
+  while let Some((walker, member_ty)) = pending.pop() {
+    report.sets_checked += 1;
+    if let (Some(backfill_id), FieldType::Table(name)) =
+      (walker.node().sort_index_backfill, member_ty)
+    {
+      let specialized_ty = schema
+        .types
+        .get(name)
+        .ok_or_else(|| BackfillError::MissingType(name.to_string()))?;
+      if let Some((sort_key, _)) = specialized_ty.sort_key() {
+        backfill_set(
+          store,
+          &walker,
+          backfill_id,
+          sort_key,
+          batch_size,
+          &mut report,
+        )
+        .await?;
+      }
+    }
+    if may_contain_sets(schema, member_ty, &mut HashSet::new()) {
+      collect_member_sets(store, schema, &walker, member_ty, batch_size, &mut pending).await?;
+    }
+  }
+  Ok(report)
+}
+
+/// Returns whether scans by sort key can use the sort key index of the set at `walker`, i.e. the
+/// set has no pending backfill, or its backfill is complete.
+pub async fn sort_index_ready(txn: &dyn KvTransaction, walker: &PathWalker<'_>) -> Result<bool> {
+  let backfill_id = match walker.node().sort_index_backfill {
+    Some(x) => x,
+    None => return Ok(true),
+  };
+  Ok(matches!(
+    read_progress(txn, walker).await?,
+    Some(x) if x.backfill_id == backfill_id && x.ready
+  ))
+}
+
+/// Reads the backfill progress of the set at `walker`.
+pub async fn read_progress(
+  txn: &dyn KvTransaction,
+  walker: &PathWalker<'_>,
+) -> Result<Option<SortIndexProgress>> {
+  match txn.get(&walker.set_sort_index_progress_key()?).await? {
+    Some(x) => Ok(Some(rmp_serde::from_slice(&x)?)),
+    None => Ok(None),
+  }
+}
+
+/// Indexes the members of a set by `sort_key`, resuming from the recorded progress.
+async fn backfill_set(
+  store: &dyn KeyValueStore,
+  walker: &Arc<PathWalker<'_>>,
+  backfill_id: u64,
+  sort_key: &str,
+  batch_size: usize,
+  report: &mut BackfillReport,
+) -> Result<()> {
+  let progress_key = walker.set_sort_index_progress_key()?;
+  let prefix = walker.set_fast_scan_prefix()?;
+  let end = successor_of_bounded_prefix(&prefix);
+  loop {
+    let txn = store.begin_transaction().await?;
+    let mut progress = match read_progress(&*txn, walker).await? {
+      Some(x) if x.backfill_id == backfill_id => x,
+      _ => {
+        // Entries left over from an earlier sort key would show up in scans.
+        let sort_key_prefix = walker.set_sort_key_prefix()?;
+        txn
+          .delete_range(
+            &sort_key_prefix,
+            &successor_of_bounded_prefix(&sort_key_prefix),
+          )
+          .await?;
+        SortIndexProgress {
+          backfill_id,
+          last_primary_key: None,
+          ready: false,
+        }
+      }
+    };
+    if progress.ready {
+      return Ok(());
+    }
+
+    let mut cursor = prefix.clone();
+    if let Some(x) = &progress.last_primary_key {
+      // Continue right after the last member indexed.
+      cursor.extend_from_slice(x);
+      cursor.push(0x00);
+    }
+    let mut it = txn
+      .scan(
+        &cursor,
+        &end,
+        &ScanOptions {
+          limit: Some(batch_size),
+          ..Default::default()
+        },
+      )
+      .await?;
+    let mut keys = vec![];
+    while let Some((k, _)) = it.next().await? {
+      keys.push(k);
+    }
+    drop(it);
+
+    for k in &keys {
+      let primary_key = &k[prefix.len()..];
+      let field_walker = walker.enter_set_raw(primary_key)?.enter_field(sort_key)?;
+      let value = get_with_rename_fallback(&*txn, &field_walker)
+        .await?
+        .map(PrimitiveValue::decode_stored)
+        .transpose()?;
+      txn
+        .put(
+          &walker.set_sort_key_entry(value.as_ref(), primary_key)?,
+          &[],
+        )
+        .await?;
+      report.members_indexed += 1;
+    }
+
+    if let Some(last) = keys.last() {
+      progress.last_primary_key = Some(last[prefix.len()..].to_vec());
+    }
+    progress.ready = keys.len() < batch_size;
+    txn
+      .put(&progress_key, &rmp_serde::to_vec_named(&progress)?)
+      .await?;
+    txn.commit().await?;
+
+    if progress.ready {
+      report.sets_backfilled += 1;
+      return Ok(());
+    }
+  }
+}
+
+/// Collects the sets nested in the members of a set.
+async fn collect_member_sets<'a>(
+  store: &dyn KeyValueStore,
+  schema: &'a CompiledSchema,
+  walker: &Arc<PathWalker<'a>>,
+  member_ty: &'a FieldType,
+  batch_size: usize,
+  pending: &mut Vec<(Arc<PathWalker<'a>>, &'a FieldType)>,
+) -> Result<()> {
+  let prefix = walker.set_fast_scan_prefix()?;
+  let end = successor_of_bounded_prefix(&prefix);
+  let mut cursor = prefix.clone();
+  loop {
+    let txn = store.begin_transaction().await?;
+    let mut it = txn
+      .scan(
+        &cursor,
+        &end,
+        &ScanOptions {
+          limit: Some(batch_size),
+          ..Default::default()
+        },
+      )
+      .await?;
+    let mut keys = vec![];
+    while let Some((k, _)) = it.next().await? {
+      keys.push(k);
+    }
+    drop(it);
+
+    for k in &keys {
+      let member = walker.enter_set_raw(&k[prefix.len()..])?;
+      collect_sets(&*txn, schema, member_ty, member, pending).await?;
+    }
+    txn.commit().await?;
+
+    match keys.last() {
+      Some(last) if keys.len() == batch_size => {
+        cursor = last.clone();
+        cursor.push(0x00);
+      }
+      _ => return Ok(()),
+    }
+  }
+}
+
+/// Returns whether a value of type `ty` can contain sets or maps, so that the members of a set of
+/// this type have to be scanned for nested sets.
+fn may_contain_sets<'a>(
+  schema: &'a CompiledSchema,
+  ty: &'a FieldType,
+  visited: &mut HashSet<&'a str>,
+) -> bool {
+  match ty {
+    FieldType::Set(_) | FieldType::Map(_) => true,
+    FieldType::Table(name) => {
+      if !visited.insert(&**name) {
+        return false;
+      }
+      match schema.types.get(name) {
+        Some(x) => x
+          .fields
+          .values()
+          .any(|(ty, _)| may_contain_sets(schema, ty, visited)),
+        None => false,
+      }
+    }
+    FieldType::Primitive(_) | FieldType::Struct(_) | FieldType::List(_) => false,
+  }
+}
+
+/// Collects the sets at or below a location of type `ty`.
+#[async_recursion]
+async fn collect_sets<'a>(
+  txn: &dyn KvTransaction,
+  schema: &'a CompiledSchema,
+  ty: &'a FieldType,
+  walker: Arc<PathWalker<'a>>,
+  pending: &mut Vec<(Arc<PathWalker<'a>>, &'a FieldType)>,
+) -> Result<()> {
+  match ty {
+    FieldType::Set(member_ty) | FieldType::Map(member_ty) => pending.push((walker, member_ty)),
+    FieldType::Table(x) => {
+      // Nested tables always have their table key written. Checking it here also stops the
+      // recursion on recursive types.
+      if txn.get(walker.key()).await?.is_some() {
+        collect_table_sets(txn, schema, x, walker, pending).await?;
+      }
+    }
+    FieldType::Primitive(_) | FieldType::Struct(_) | FieldType::List(_) => {}
+  }
+  Ok(())
+}
+
+#[async_recursion]
+async fn collect_table_sets<'a>(
+  txn: &dyn KvTransaction,
+  schema: &'a CompiledSchema,
+  name: &str,
+  walker: Arc<PathWalker<'a>>,
+  pending: &mut Vec<(Arc<PathWalker<'a>>, &'a FieldType)>,
+) -> Result<()> {
+  let specialized_ty = schema
+    .types
+    .get(name)
+    .ok_or_else(|| BackfillError::MissingType(name.to_string()))?;
+  for (field_name, (field_ty, _)) in &specialized_ty.fields {
+    let field_walker = walker.enter_field(field_name)?;
+    collect_sets(txn, schema, field_ty, field_walker, pending).await?;
+  }
+  Ok(())
+}
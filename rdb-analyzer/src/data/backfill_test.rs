@@ -0,0 +1,265 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use crate::{
+  data::{
+    keyenc::successor_prefix,
+    kv::KeyValueStore,
+    mock_kv::MockKv,
+    pathwalker::PathWalker,
+    treewalker::{
+      asm::codegen::compile_twscript,
+      exec::{generate_root_map, ExecError, Executor},
+      typeck::GlobalTyckContext,
+      vm::TwVm,
+      vm_value::VmValue,
+    },
+    value::PrimitiveValue,
+  },
+  schema::compile::CompiledSchema,
+  storage_plan::{planner::generate_plan_for_schema, StoragePlan},
+  testutil::compile_schema_source,
+};
+
+use super::backfill::{backfill_sort_indexes, read_progress, BackfillOptions, SortIndexProgress};
+
+const SCHEMA_V1: &str = r#"
+type Event {
+  @primary
+  id: string,
+  at: int64,
+}
+export set<Event> events;
+"#;
+
+const SCHEMA_V2: &str = r#"
+type Event {
+  @primary
+  id: string,
+  @sort_key
+  at: int64,
+}
+export set<Event> events;
+"#;
+
+const SCRIPT: &str = r#"
+export graph put(root: schema, id: string, at: int64) {
+  s_insert root.events $ build_table(Event) $ m_insert(id) id $ m_insert(at) at create_map;
+}
+export graph sorted(root: schema): string {
+  return sorted_reduce(concat) create_map "" root.events;
+}
+graph concat(_unused: map{}, current: string, item: Event): string {
+  return current + item.id;
+}
+"#;
+
+async fn run(
+  schema: &CompiledSchema,
+  plan: &StoragePlan,
+  kv: &MockKv,
+  graph: &str,
+  params: Vec<PrimitiveValue>,
+) -> Result<Option<PrimitiveValue>> {
+  let script = compile_twscript(SCRIPT).unwrap();
+  let vm = TwVm::new(schema, plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let mut params_vm = vec![Arc::new(generate_root_map(schema, plan).unwrap())];
+  params_vm.extend(params.into_iter().map(|x| Arc::new(VmValue::Primitive(x))));
+  let mut executor = Executor::new(&vm, kv, &type_info);
+  let graph = vm.lookup_exported_graph_by_name(graph).unwrap();
+  let output = executor.run_graph(graph, &params_vm).await?;
+  Ok(output.and_then(|x| match &*x {
+    VmValue::Primitive(x) => Some(x.clone()),
+    _ => None,
+  }))
+}
+
+async fn put(schema: &CompiledSchema, plan: &StoragePlan, kv: &MockKv, id: &str, at: i64) {
+  run(
+    schema,
+    plan,
+    kv,
+    "put",
+    vec![PrimitiveValue::String(id.into()), PrimitiveValue::Int64(at)],
+  )
+  .await
+  .unwrap();
+}
+
+#[tokio::test]
+async fn plan_sort_index_backfill() {
+  let schema_v1 = compile_schema_source(SCHEMA_V1);
+  let schema_v2 = compile_schema_source(SCHEMA_V2);
+  let plan_v1 =
+    generate_plan_for_schema(&Default::default(), &Default::default(), &schema_v1).unwrap();
+  assert!(plan_v1.nodes["events"].sort_index_backfill.is_none());
+  assert!(!plan_v1.has_sort_index_backfills());
+
+  // A new set has no members to backfill.
+  let plan =
+    generate_plan_for_schema(&Default::default(), &Default::default(), &schema_v2).unwrap();
+  assert!(plan.nodes["events"].sort_index_backfill.is_none());
+
+  // Adding a sort key to an existing set starts a backfill, which is kept until cleared.
+  let mut plan_v2 = generate_plan_for_schema(&plan_v1, &schema_v1, &schema_v2).unwrap();
+  let backfill_id = plan_v2.nodes["events"].sort_index_backfill.unwrap();
+  assert!(plan_v2.has_sort_index_backfills());
+  let plan = generate_plan_for_schema(&plan_v2, &schema_v2, &schema_v2).unwrap();
+  assert_eq!(plan.nodes["events"].sort_index_backfill, Some(backfill_id));
+  plan_v2.clear_sort_index_backfills();
+  assert!(!plan_v2.has_sort_index_backfills());
+  let plan = generate_plan_for_schema(&plan_v2, &schema_v2, &schema_v2).unwrap();
+  assert!(plan.nodes["events"].sort_index_backfill.is_none());
+
+  // Removing the sort key drops it, and adding it back starts a new backfill.
+  let plan = generate_plan_for_schema(&plan_v2, &schema_v2, &schema_v1).unwrap();
+  assert!(plan.nodes["events"].sort_index_backfill.is_none());
+  let plan = generate_plan_for_schema(&plan, &schema_v1, &schema_v2).unwrap();
+  let new_id = plan.nodes["events"].sort_index_backfill.unwrap();
+  assert_ne!(new_id, backfill_id);
+}
+
+#[tokio::test]
+async fn no_sort_index_backfill_in_lists() {
+  let schema_v1 = compile_schema_source(
+    r#"
+    type Log {
+      events: set<Event>,
+    }
+    type Event {
+      @primary
+      id: string,
+      at: int64,
+    }
+    export list<Log> logs;
+  "#,
+  );
+  let schema_v2 = compile_schema_source(
+    r#"
+    type Log {
+      events: set<Event>,
+    }
+    type Event {
+      @primary
+      id: string,
+      @sort_key
+      at: int64,
+    }
+    export list<Log> logs;
+  "#,
+  );
+  let plan_v1 =
+    generate_plan_for_schema(&Default::default(), &Default::default(), &schema_v1).unwrap();
+  let plan_v2 = generate_plan_for_schema(&plan_v1, &schema_v1, &schema_v2).unwrap();
+  let events = |plan: &StoragePlan| plan.nodes["logs"].set.as_ref().unwrap().children["events"].key;
+
+  // The set keeps its data, but is not marked since the backfill cannot reach it.
+  assert_eq!(events(&plan_v2), events(&plan_v1));
+  assert!(!plan_v2.has_sort_index_backfills());
+}
+
+#[tokio::test]
+async fn backfill_sort_index() {
+  let _ = pretty_env_logger::try_init();
+  let schema_v1 = compile_schema_source(SCHEMA_V1);
+  let schema_v2 = compile_schema_source(SCHEMA_V2);
+  let plan_v1 =
+    generate_plan_for_schema(&Default::default(), &Default::default(), &schema_v1).unwrap();
+  let plan_v2 = generate_plan_for_schema(&plan_v1, &schema_v1, &schema_v2).unwrap();
+  let backfill_id = plan_v2.nodes["events"].sort_index_backfill.unwrap();
+  let kv = MockKv::new();
+
+  for (id, at) in &[("a", 30), ("b", 10), ("c", 50), ("d", 20), ("e", 40)] {
+    put(&schema_v1, &plan_v1, &kv, id, *at).await;
+  }
+
+  // Scans by sort key fail until the index is backfilled, but writes maintain it already.
+  put(&schema_v2, &plan_v2, &kv, "f", 0).await;
+  let e = run(&schema_v2, &plan_v2, &kv, "sorted", vec![])
+    .await
+    .unwrap_err();
+  assert!(matches!(
+    e.downcast_ref::<ExecError>(),
+    Some(ExecError::SortIndexNotReady(_))
+  ));
+
+  let options = BackfillOptions { batch_size: 2 };
+  let report = backfill_sort_indexes(&kv, &schema_v2, &plan_v2, &options)
+    .await
+    .unwrap();
+  assert_eq!(report.sets_checked, 1);
+  assert_eq!(report.sets_backfilled, 1);
+  assert_eq!(report.members_indexed, 6);
+  assert_eq!(
+    run(&schema_v2, &plan_v2, &kv, "sorted", vec![])
+      .await
+      .unwrap(),
+    Some(PrimitiveValue::String("fbdaec".into()))
+  );
+
+  // A completed backfill is not run again.
+  let report = backfill_sort_indexes(&kv, &schema_v2, &plan_v2, &options)
+    .await
+    .unwrap();
+  assert_eq!(report.sets_backfilled, 0);
+  assert_eq!(report.members_indexed, 0);
+
+  // An interrupted backfill resumes after the last member indexed.
+  let events = PathWalker::from_export(&plan_v2, "events").unwrap();
+  let sort_key_prefix = events.set_sort_key_prefix().unwrap();
+  let last_primary_key = events
+    .encode_primary_key(&PrimitiveValue::String("d".into()))
+    .to_vec();
+  let txn = kv.begin_transaction().await.unwrap();
+  txn
+    .delete_range(
+      &sort_key_prefix,
+      &successor_prefix(&sort_key_prefix).unwrap(),
+    )
+    .await
+    .unwrap();
+  let progress = SortIndexProgress {
+    backfill_id,
+    last_primary_key: Some(last_primary_key),
+    ready: false,
+  };
+  txn
+    .put(
+      &events.set_sort_index_progress_key().unwrap(),
+      &rmp_serde::to_vec_named(&progress).unwrap(),
+    )
+    .await
+    .unwrap();
+  txn.commit().await.unwrap();
+  let report = backfill_sort_indexes(&kv, &schema_v2, &plan_v2, &options)
+    .await
+    .unwrap();
+  assert_eq!(report.members_indexed, 2);
+  assert_eq!(
+    run(&schema_v2, &plan_v2, &kv, "sorted", vec![])
+      .await
+      .unwrap(),
+    Some(PrimitiveValue::String("fe".into()))
+  );
+
+  let txn = kv.begin_transaction().await.unwrap();
+  let progress = read_progress(&*txn, &events).await.unwrap().unwrap();
+  assert_eq!(progress.backfill_id, backfill_id);
+  assert!(progress.ready);
+
+  // A new backfill discards the progress and the entries of the previous one.
+  let mut plan_v3 = plan_v2.clone();
+  plan_v3.nodes.get_mut("events").unwrap().sort_index_backfill = Some(backfill_id + 1);
+  let report = backfill_sort_indexes(&kv, &schema_v2, &plan_v3, &options)
+    .await
+    .unwrap();
+  assert_eq!(report.members_indexed, 6);
+  assert_eq!(
+    run(&schema_v2, &plan_v3, &kv, "sorted", vec![])
+      .await
+      .unwrap(),
+    Some(PrimitiveValue::String("fbdaec".into()))
+  );
+}
@@ -0,0 +1,180 @@
+use anyhow::Result;
+use thiserror::Error;
+
+use super::{
+  keyenc::successor_of_bounded_prefix,
+  kv::{KvTransaction, ScanOptions},
+  pathwalker::PathWalker,
+};
+
+/// The size of a blob chunk. A `@blob` field with the key `K` stores its length in bytes at `K`,
+/// and its bytes split into chunks at `K 0x05 <chunk index>`, with the index as a big-endian u32.
+/// All chunks are full, except for the last one.
+pub const BLOB_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Error, Debug)]
+pub enum BlobError {
+  #[error("blob too large: {0} bytes")]
+  TooLarge(u64),
+
+  #[error("corrupted blob: {0}")]
+  Corrupted(String),
+}
+
+/// The length of the blob at `walker`, or `None` if it is absent.
+pub async fn blob_length(txn: &dyn KvTransaction, walker: &PathWalker<'_>) -> Result<Option<u64>> {
+  match txn.get(walker.key()).await? {
+    Some(x) => Ok(Some(rmp_serde::from_slice(&x)?)),
+    None => Ok(None),
+  }
+}
+
+/// Reads the whole blob at `walker`, or `None` if it is absent.
+pub async fn read_blob(
+  txn: &dyn KvTransaction,
+  walker: &PathWalker<'_>,
+) -> Result<Option<Vec<u8>>> {
+  let len = match blob_length(txn, walker).await? {
+    Some(x) => x,
+    None => return Ok(None),
+  };
+  let prefix = walker.blob_chunk_prefix()?;
+  let mut it = txn
+    .scan(
+      &prefix,
+      &successor_of_bounded_prefix(&prefix),
+      &ScanOptions {
+        values: true,
+        ..Default::default()
+      },
+    )
+    .await?;
+  let mut data = Vec::with_capacity(len as usize);
+  while let Some((k, v)) = it.next().await? {
+    let expected = (data.len() / BLOB_CHUNK_SIZE) as u32;
+    if k[prefix.len()..] != expected.to_be_bytes() {
+      return Err(BlobError::Corrupted(format!("missing chunk {}", expected)).into());
+    }
+    data.extend_from_slice(&v);
+  }
+  if data.len() as u64 != len {
+    return Err(
+      BlobError::Corrupted(format!("expected {} bytes, found {}", len, data.len())).into(),
+    );
+  }
+  Ok(Some(data))
+}
+
+/// Reads at most `len` bytes of the blob at `walker`, starting at `offset`. Only the chunks that
+/// overlap the range are read. Returns `None` if the blob is absent, and an empty buffer if
+/// `offset` is past its end.
+pub async fn read_blob_range(
+  txn: &dyn KvTransaction,
+  walker: &PathWalker<'_>,
+  offset: u64,
+  len: u64,
+) -> Result<Option<Vec<u8>>> {
+  let total = match blob_length(txn, walker).await? {
+    Some(x) => x,
+    None => return Ok(None),
+  };
+  let start = offset.min(total);
+  let end = offset.saturating_add(len).min(total);
+  let mut data = Vec::with_capacity((end - start) as usize);
+  let mut pos = start;
+  while pos < end {
+    let index = pos / BLOB_CHUNK_SIZE as u64;
+    let chunk = txn
+      .get(&walker.blob_chunk_key(index as u32)?)
+      .await?
+      .ok_or_else(|| BlobError::Corrupted(format!("missing chunk {}", index)))?;
+    let chunk_start = (pos % BLOB_CHUNK_SIZE as u64) as usize;
+    let chunk_end = ((end - index * BLOB_CHUNK_SIZE as u64) as usize).min(chunk.len());
+    if chunk_start >= chunk_end {
+      return Err(BlobError::Corrupted(format!("short chunk {}", index)).into());
+    }
+    data.extend_from_slice(&chunk[chunk_start..chunk_end]);
+    pos += (chunk_end - chunk_start) as u64;
+  }
+  Ok(Some(data))
+}
+
+/// Replaces the blob at `walker` with `data`. The chunks of the previous value are cleared, so no
+/// chunk outlives the blob it belonged to.
+pub async fn write_blob(
+  txn: &dyn KvTransaction,
+  walker: &PathWalker<'_>,
+  data: &[u8],
+) -> Result<()> {
+  delete_chunks(txn, walker).await?;
+  put_chunks(txn, walker, 0, data).await?;
+  txn
+    .put(walker.key(), &rmp_serde::to_vec(&(data.len() as u64))?)
+    .await?;
+  Ok(())
+}
+
+/// Appends `data` to the blob at `walker`, creating it if it is absent. Only the last chunk of
+/// the existing blob is rewritten.
+pub async fn append_blob(
+  txn: &dyn KvTransaction,
+  walker: &PathWalker<'_>,
+  data: &[u8],
+) -> Result<()> {
+  let len = blob_length(txn, walker).await?.unwrap_or(0);
+  let new_len = len + data.len() as u64;
+  let partial = (len % BLOB_CHUNK_SIZE as u64) as usize;
+  let first_index = len / BLOB_CHUNK_SIZE as u64;
+  if partial == 0 {
+    put_chunks(txn, walker, first_index, data).await?;
+  } else {
+    let mut tail = txn
+      .get(&walker.blob_chunk_key(first_index as u32)?)
+      .await?
+      .ok_or_else(|| BlobError::Corrupted(format!("missing chunk {}", first_index)))?;
+    if tail.len() != partial {
+      return Err(BlobError::Corrupted(format!("short chunk {}", first_index)).into());
+    }
+    tail.extend_from_slice(data);
+    put_chunks(txn, walker, first_index, &tail).await?;
+  }
+  txn.put(walker.key(), &rmp_serde::to_vec(&new_len)?).await?;
+  Ok(())
+}
+
+/// Deletes the blob at `walker` and all its chunks.
+pub async fn delete_blob(txn: &dyn KvTransaction, walker: &PathWalker<'_>) -> Result<()> {
+  txn.delete(walker.key()).await?;
+  delete_chunks(txn, walker).await
+}
+
+async fn delete_chunks(txn: &dyn KvTransaction, walker: &PathWalker<'_>) -> Result<()> {
+  let prefix = walker.blob_chunk_prefix()?;
+  txn
+    .delete_range(&prefix, &successor_of_bounded_prefix(&prefix))
+    .await
+}
+
+/// Writes `data` as the chunks starting at `first_index`.
+async fn put_chunks(
+  txn: &dyn KvTransaction,
+  walker: &PathWalker<'_>,
+  first_index: u64,
+  data: &[u8],
+) -> Result<()> {
+  let chunk_count = (data.len() + BLOB_CHUNK_SIZE - 1) / BLOB_CHUNK_SIZE;
+  if first_index + chunk_count as u64 > u32::MAX as u64 + 1 {
+    return Err(
+      BlobError::TooLarge(first_index * BLOB_CHUNK_SIZE as u64 + data.len() as u64).into(),
+    );
+  }
+  for (i, chunk) in data.chunks(BLOB_CHUNK_SIZE).enumerate() {
+    txn
+      .put(
+        &walker.blob_chunk_key((first_index + i as u64) as u32)?,
+        chunk,
+      )
+      .await?;
+  }
+  Ok(())
+}
@@ -0,0 +1,252 @@
+use std::sync::Arc;
+
+use crate::{
+  data::{
+    inspect::{decode_key, DecodedKeyKind, DecodedValue},
+    kv::KeyValueStore,
+    mock_kv::MockKv,
+    pathwalker::{KeyKind, PathWalker},
+    treewalker::{
+      asm::codegen::compile_twscript,
+      exec::{generate_root_map, Executor},
+      typeck::{GlobalTyckContext, TypeckDiagnostic, TypeckError},
+      vm::TwVm,
+      vm_value::VmValue,
+    },
+    value::PrimitiveValue,
+  },
+  storage_plan::planner::generate_plan_for_schema,
+  testutil::compile_schema_source,
+};
+
+use super::blob::{
+  append_blob, blob_length, delete_blob, read_blob, read_blob_range, write_blob, BLOB_CHUNK_SIZE,
+};
+
+const SCHEMA: &str = r#"
+type File {
+  @primary
+  name: string,
+  @blob
+  content: bytes,
+}
+export set<File> files;
+"#;
+
+fn data(len: usize, seed: u8) -> Vec<u8> {
+  (0..len)
+    .map(|i| (i as u8).wrapping_mul(31) ^ seed)
+    .collect()
+}
+
+#[tokio::test]
+async fn blob_chunks() {
+  let _ = pretty_env_logger::try_init();
+  let schema = compile_schema_source(SCHEMA);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  assert!(plan.nodes["files"].set.as_ref().unwrap().children["content"].blob);
+  let walker = PathWalker::from_export(&plan, "files")
+    .unwrap()
+    .enter_set(&PrimitiveValue::String("a".into()))
+    .unwrap()
+    .enter_field("content")
+    .unwrap();
+  let kv = MockKv::new();
+
+  let big = data(BLOB_CHUNK_SIZE * 2 + 100, 1);
+  let txn = kv.begin_transaction().await.unwrap();
+  assert!(read_blob(&*txn, &walker).await.unwrap().is_none());
+  write_blob(&*txn, &walker, &big).await.unwrap();
+  assert_eq!(
+    blob_length(&*txn, &walker).await.unwrap(),
+    Some(big.len() as u64)
+  );
+  assert_eq!(read_blob(&*txn, &walker).await.unwrap().unwrap(), big);
+
+  // Ranges across chunk boundaries, and past the end.
+  for (offset, len) in &[
+    (0u64, 10u64),
+    (BLOB_CHUNK_SIZE as u64 - 5, 10),
+    (BLOB_CHUNK_SIZE as u64 * 2, 1000),
+    (big.len() as u64, 10),
+  ] {
+    let start = (*offset as usize).min(big.len());
+    let end = (*offset as usize + *len as usize).min(big.len());
+    assert_eq!(
+      read_blob_range(&*txn, &walker, *offset, *len)
+        .await
+        .unwrap()
+        .unwrap(),
+      big[start..end].to_vec()
+    );
+  }
+  txn.commit().await.unwrap();
+  assert_eq!(kv.key_count().await, 4);
+
+  // A shorter value drops the chunks it no longer needs.
+  let small = data(10, 2);
+  let txn = kv.begin_transaction().await.unwrap();
+  write_blob(&*txn, &walker, &small).await.unwrap();
+  txn.commit().await.unwrap();
+  assert_eq!(kv.key_count().await, 2);
+
+  // Appends fill the last chunk before starting new ones.
+  let mut expected = small.clone();
+  let txn = kv.begin_transaction().await.unwrap();
+  for seed in 3..6 {
+    let x = data(BLOB_CHUNK_SIZE / 2, seed);
+    append_blob(&*txn, &walker, &x).await.unwrap();
+    expected.extend_from_slice(&x);
+  }
+  assert_eq!(read_blob(&*txn, &walker).await.unwrap().unwrap(), expected);
+  txn.commit().await.unwrap();
+  assert_eq!(kv.key_count().await, 3);
+
+  let txn = kv.begin_transaction().await.unwrap();
+  delete_blob(&*txn, &walker).await.unwrap();
+  assert!(blob_length(&*txn, &walker).await.unwrap().is_none());
+  txn.commit().await.unwrap();
+  assert_eq!(kv.key_count().await, 0);
+
+  // Chunk keys map back to their field.
+  let decoded = PathWalker::decode_key(&plan, &walker.blob_chunk_key(1).unwrap())
+    .unwrap()
+    .unwrap();
+  assert_eq!(decoded.kind, KeyKind::BlobChunk(1));
+  assert_eq!(decoded.walker, walker);
+  let decoded = decode_key(&plan, &schema, &walker.blob_chunk_key(7).unwrap())
+    .unwrap()
+    .unwrap();
+  assert_eq!(decoded.kind, DecodedKeyKind::BlobChunk(7));
+  assert_eq!(decoded.to_string(), r#"files["a"].content <blob chunk 7>"#);
+  let decoded = decode_key(&plan, &schema, walker.key()).unwrap().unwrap();
+  assert!(matches!(
+    decoded
+      .decode_value(&rmp_serde::to_vec(&42u64).unwrap())
+      .unwrap(),
+    DecodedValue::Counter(42)
+  ));
+}
+
+const SCRIPT: &str = r#"
+export graph put(root: schema, name: string, content: bytes) {
+  s_insert root.files $ build_table(File) $ m_insert(name) name $ m_insert(content) content create_map;
+}
+export graph clear(root: schema, name: string) {
+  t_insert(content) (point_get root.files name) null<bytes>;
+}
+export graph remove(root: schema, name: string) {
+  s_delete root.files name;
+}
+export graph get(root: schema, name: string): bytes {
+  return (point_get root.files name).content;
+}
+export graph append(root: schema, name: string, content: bytes) {
+  blob_append(content) (point_get root.files name) content;
+}
+export graph len(root: schema, name: string): int64 {
+  return blob_len(content) (point_get root.files name);
+}
+export graph read(root: schema, name: string, offset: int64): bytes {
+  return blob_read(content) (point_get root.files name) offset 10;
+}
+"#;
+
+/// The number of stored chunks of the blob at `walker`.
+async fn chunk_count(kv: &MockKv, walker: &PathWalker<'_>) -> usize {
+  let prefix = walker.blob_chunk_prefix().unwrap();
+  kv.dump()
+    .await
+    .iter()
+    .filter(|(k, _)| k.starts_with(&prefix))
+    .count()
+}
+
+#[tokio::test]
+async fn blob_fields() {
+  let _ = pretty_env_logger::try_init();
+  let schema = compile_schema_source(SCHEMA);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let script = compile_twscript(SCRIPT).unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let kv = MockKv::new();
+  let walker = PathWalker::from_export(&plan, "files")
+    .unwrap()
+    .enter_set(&PrimitiveValue::String("a".into()))
+    .unwrap()
+    .enter_field("content")
+    .unwrap();
+  let run = |graph: &'static str, params: Vec<PrimitiveValue>| {
+    let (vm, type_info, kv, schema, plan) = (&vm, &type_info, &kv, &schema, &plan);
+    async move {
+      let mut params_vm = vec![Arc::new(generate_root_map(schema, plan).unwrap())];
+      params_vm.extend(params.into_iter().map(|x| Arc::new(VmValue::Primitive(x))));
+      let mut executor = Executor::new(vm, kv, type_info);
+      let graph = vm.lookup_exported_graph_by_name(graph).unwrap();
+      let output = executor.run_graph(graph, &params_vm).await.unwrap()?;
+      match &*output {
+        VmValue::Primitive(x) => Some(x.clone()),
+        _ => None,
+      }
+    }
+  };
+  let name = || PrimitiveValue::String("a".into());
+
+  let big = data(BLOB_CHUNK_SIZE * 3, 1);
+  run("put", vec![name(), PrimitiveValue::Bytes(big.clone())]).await;
+  assert_eq!(chunk_count(&kv, &walker).await, 3);
+  assert_eq!(
+    run("get", vec![name()]).await,
+    Some(PrimitiveValue::Bytes(big.clone()))
+  );
+  assert_eq!(
+    run(
+      "read",
+      vec![name(), PrimitiveValue::Int64(BLOB_CHUNK_SIZE as i64 - 5)]
+    )
+    .await,
+    Some(PrimitiveValue::Bytes(
+      big[BLOB_CHUNK_SIZE - 5..BLOB_CHUNK_SIZE + 5].to_vec()
+    ))
+  );
+  run("append", vec![name(), PrimitiveValue::Bytes(vec![1, 2, 3])]).await;
+  assert_eq!(
+    run("len", vec![name()]).await,
+    Some(PrimitiveValue::Int64(big.len() as i64 + 3))
+  );
+  assert_eq!(chunk_count(&kv, &walker).await, 4);
+
+  // Overwrites and deletes leave no chunks behind.
+  run("put", vec![name(), PrimitiveValue::Bytes(vec![4, 5])]).await;
+  assert_eq!(chunk_count(&kv, &walker).await, 1);
+  run("clear", vec![name()]).await;
+  assert_eq!(chunk_count(&kv, &walker).await, 0);
+  assert_eq!(run("get", vec![name()]).await, None);
+  run("put", vec![name(), PrimitiveValue::Bytes(big)]).await;
+  run("remove", vec![name()]).await;
+  assert_eq!(chunk_count(&kv, &walker).await, 0);
+  assert!(
+    blob_length(&*kv.begin_transaction().await.unwrap(), &walker)
+      .await
+      .unwrap()
+      .is_none()
+  );
+
+  // Blob opcodes only apply to `@blob` fields.
+  let script = compile_twscript(
+    r#"
+    graph main(root: schema): int64 {
+      return blob_len(name) (point_get root.files "a");
+    }
+    "#,
+  )
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let e = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap_err();
+  let e = e.downcast_ref::<TypeckDiagnostic>().unwrap();
+  assert!(matches!(
+    e.error.downcast_ref::<TypeckError>(),
+    Some(TypeckError::NotBlobField(..))
+  ));
+}
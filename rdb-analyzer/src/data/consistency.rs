@@ -0,0 +1,325 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_recursion::async_recursion;
+use thiserror::Error;
+
+use crate::{
+  schema::compile::{CompiledSchema, FieldType},
+  storage_plan::StoragePlan,
+};
+
+use super::{
+  keyenc::successor_of_bounded_prefix,
+  kv::{KeyValueStore, KvTransaction, ScanOptions},
+  pathwalker::{selector_len, PathWalker},
+};
+
+#[derive(Error, Debug)]
+pub enum ConsistencyError {
+  #[error("missing type: {0}")]
+  MissingType(String),
+}
+
+/// Options of `audit_sets`.
+#[derive(Clone, Debug)]
+pub struct AuditOptions {
+  /// Repair the inconsistencies found: dangling index entries and orphan data are deleted, and
+  /// missing index entries are rebuilt.
+  pub repair: bool,
+
+  /// Max number of members checked in one transaction.
+  pub batch_size: usize,
+}
+
+impl Default for AuditOptions {
+  fn default() -> Self {
+    Self {
+      repair: false,
+      batch_size: 100,
+    }
+  }
+}
+
+/// An inconsistency between the fast scan index and the data of a set.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Inconsistency {
+  /// Key of the set.
+  pub set_key: Vec<u8>,
+
+  /// Encoded primary key of the member. For `MalformedDataKey`, the rest of the key after the
+  /// data prefix instead.
+  pub primary_key: Vec<u8>,
+
+  pub kind: InconsistencyKind,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum InconsistencyKind {
+  /// A fast scan index entry without a member. Scans return a member that cannot be read.
+  DanglingIndexEntry,
+
+  /// A member without a fast scan index entry. Scans skip the member.
+  MissingIndexEntry,
+
+  /// Member data without the presence marker of the member, left behind by a partial write or
+  /// delete.
+  OrphanData,
+
+  /// A key in the data subspace that does not start with a valid primary key.
+  MalformedDataKey,
+}
+
+/// Result of `audit_sets`.
+#[derive(Clone, Debug, Default)]
+pub struct AuditReport {
+  pub sets_checked: u64,
+
+  /// Number of fast scan index entries checked.
+  pub members_checked: u64,
+  pub inconsistencies: Vec<Inconsistency>,
+
+  /// Number of inconsistencies repaired. Zero unless `AuditOptions::repair` is set.
+  pub repaired: u64,
+}
+
+/// Audits the sets and maps reachable from the exports of `schema`: reports members present in
+/// only one of the fast scan index and the data subspace, and optionally repairs them. The
+/// executor always writes both in the same transaction, but a partial write can escape, e.g.
+/// through manual tooling. Sets nested in list elements are not checked.
+pub async fn audit_sets(
+  store: &dyn KeyValueStore,
+  schema: &CompiledSchema,
+  plan: &StoragePlan,
+  options: &AuditOptions,
+) -> Result<AuditReport> {
+  let options = AuditOptions {
+    batch_size: options.batch_size.max(1),
+    ..options.clone()
+  };
+  let mut report = AuditReport::default();
+  let mut pending = vec![];
+
+  let txn = store.begin_transaction().await?;
+  for (export_name, export_ty) in &schema.exports {
+    let walker = PathWalker::from_export(plan, export_name)?;
+    match export_ty {
+      // Top-level tables do not necessarily have their own key written.
+      FieldType::Table(x) => collect_table_sets(&*txn, schema, x, walker, &mut pending).await?,
+      _ => collect_sets(&*txn, schema, export_ty, walker, &mut pending).await?,
+    }
+  }
+  txn.commit().await?;
+
+  while let Some((walker, member_ty)) = pending.pop() {
+    report.sets_checked += 1;
+    audit_index(
+      store,
+      schema,
+      &walker,
+      member_ty,
+      &options,
+      &mut report,
+      &mut pending,
+    )
+    .await?;
+    audit_data(store, &walker, &options, &mut report).await?;
+  }
+  Ok(report)
+}
+
+/// Checks that every fast scan index entry of a set has a member, and collects the sets nested
+/// in the members.
+async fn audit_index<'a>(
+  store: &dyn KeyValueStore,
+  schema: &'a CompiledSchema,
+  walker: &Arc<PathWalker<'a>>,
+  member_ty: &'a FieldType,
+  options: &AuditOptions,
+  report: &mut AuditReport,
+  pending: &mut Vec<(Arc<PathWalker<'a>>, &'a FieldType)>,
+) -> Result<()> {
+  let prefix = walker.set_fast_scan_prefix()?;
+  let end = successor_of_bounded_prefix(&prefix);
+  let mut cursor = prefix.clone();
+  loop {
+    let txn = store.begin_transaction().await?;
+    let mut it = txn
+      .scan(
+        &cursor,
+        &end,
+        &ScanOptions {
+          limit: Some(options.batch_size),
+          ..Default::default()
+        },
+      )
+      .await?;
+    let mut keys = vec![];
+    while let Some((k, _)) = it.next().await? {
+      keys.push(k);
+    }
+    drop(it);
+
+    for k in &keys {
+      let primary_key = &k[prefix.len()..];
+      let member = walker.enter_set_raw(primary_key)?;
+      report.members_checked += 1;
+      if txn.get(member.key()).await?.is_none() {
+        report.inconsistencies.push(Inconsistency {
+          set_key: walker.generate_key(),
+          primary_key: primary_key.to_vec(),
+          kind: InconsistencyKind::DanglingIndexEntry,
+        });
+        if options.repair {
+          txn.delete(k).await?;
+          report.repaired += 1;
+        }
+      } else {
+        collect_sets(&*txn, schema, member_ty, member, pending).await?;
+      }
+    }
+    txn.commit().await?;
+
+    match keys.last() {
+      Some(last) if keys.len() == options.batch_size => {
+        // Continue right after the last key.
+        cursor = last.clone();
+        cursor.push(0x00);
+      }
+      _ => return Ok(()),
+    }
+  }
+}
+
+/// Checks that every member in the data subspace of a set has a presence marker and a fast scan
+/// index entry.
+async fn audit_data(
+  store: &dyn KeyValueStore,
+  walker: &Arc<PathWalker<'_>>,
+  options: &AuditOptions,
+  report: &mut AuditReport,
+) -> Result<()> {
+  let prefix = walker.set_data_prefix()?;
+  let end = successor_of_bounded_prefix(&prefix);
+  let fast_scan_prefix = walker.set_fast_scan_prefix()?;
+  let mut cursor = prefix.clone();
+  loop {
+    let txn = store.begin_transaction().await?;
+    for _ in 0..options.batch_size {
+      let next = txn
+        .scan(
+          &cursor,
+          &end,
+          &ScanOptions {
+            limit: Some(1),
+            ..Default::default()
+          },
+        )
+        .await?
+        .next()
+        .await?;
+      let key = match next {
+        Some((k, _)) => k,
+        None => {
+          txn.commit().await?;
+          return Ok(());
+        }
+      };
+
+      let rest = &key[prefix.len()..];
+      let primary_key = match selector_len(rest) {
+        Some(x) if rest.get(x) == Some(&0x00) => &rest[..x],
+        _ => {
+          report.inconsistencies.push(Inconsistency {
+            set_key: walker.generate_key(),
+            primary_key: rest.to_vec(),
+            kind: InconsistencyKind::MalformedDataKey,
+          });
+          if options.repair {
+            txn.delete(&key).await?;
+            report.repaired += 1;
+          }
+          cursor = key.clone();
+          cursor.push(0x00);
+          continue;
+        }
+      };
+
+      let mut member_prefix = prefix.clone();
+      member_prefix.extend_from_slice(primary_key);
+      member_prefix.push(0x00);
+      let member_end = successor_of_bounded_prefix(&member_prefix);
+
+      let member = walker.enter_set_raw(primary_key)?;
+      let mut fast_scan_key = fast_scan_prefix.clone();
+      fast_scan_key.extend_from_slice(primary_key);
+      let kind = if txn.get(member.key()).await?.is_none() {
+        Some(InconsistencyKind::OrphanData)
+      } else if txn.get(&fast_scan_key).await?.is_none() {
+        Some(InconsistencyKind::MissingIndexEntry)
+      } else {
+        None
+      };
+      if let Some(kind) = kind {
+        report.inconsistencies.push(Inconsistency {
+          set_key: walker.generate_key(),
+          primary_key: primary_key.to_vec(),
+          kind,
+        });
+        if options.repair {
+          match kind {
+            InconsistencyKind::OrphanData => {
+              txn.delete_range(&member_prefix, &member_end).await?;
+            }
+            _ => txn.put(&fast_scan_key, &[]).await?,
+          }
+          report.repaired += 1;
+        }
+      }
+      cursor = member_end;
+    }
+    txn.commit().await?;
+  }
+}
+
+/// Collects the sets at or below a location of type `ty`.
+#[async_recursion]
+async fn collect_sets<'a>(
+  txn: &dyn KvTransaction,
+  schema: &'a CompiledSchema,
+  ty: &'a FieldType,
+  walker: Arc<PathWalker<'a>>,
+  pending: &mut Vec<(Arc<PathWalker<'a>>, &'a FieldType)>,
+) -> Result<()> {
+  match ty {
+    FieldType::Set(member_ty) | FieldType::Map(member_ty) => pending.push((walker, member_ty)),
+    FieldType::Table(x) => {
+      // Nested tables always have their table key written. Checking it here also stops the
+      // recursion on recursive types.
+      if txn.get(walker.key()).await?.is_some() {
+        collect_table_sets(txn, schema, x, walker, pending).await?;
+      }
+    }
+    FieldType::Primitive(_) | FieldType::Struct(_) | FieldType::List(_) => {}
+  }
+  Ok(())
+}
+
+#[async_recursion]
+async fn collect_table_sets<'a>(
+  txn: &dyn KvTransaction,
+  schema: &'a CompiledSchema,
+  name: &str,
+  walker: Arc<PathWalker<'a>>,
+  pending: &mut Vec<(Arc<PathWalker<'a>>, &'a FieldType)>,
+) -> Result<()> {
+  let specialized_ty = schema
+    .types
+    .get(name)
+    .ok_or_else(|| ConsistencyError::MissingType(name.to_string()))?;
+  for (field_name, (field_ty, _)) in &specialized_ty.fields {
+    let field_walker = walker.enter_field(field_name)?;
+    collect_sets(txn, schema, field_ty, field_walker, pending).await?;
+  }
+  Ok(())
+}
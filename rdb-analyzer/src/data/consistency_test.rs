@@ -0,0 +1,162 @@
+use std::sync::Arc;
+
+use bumpalo::Bump;
+
+use crate::{
+  data::{
+    consistency::{audit_sets, AuditOptions, InconsistencyKind},
+    keyenc::successor_prefix,
+    kv::{KeyValueStore, KvTransaction},
+    mock_kv::MockKv,
+    pathwalker::PathWalker,
+    value::PrimitiveValue,
+  },
+  schema::{compile::compile, grammar::parse},
+  storage_plan::planner::generate_plan_for_schema,
+};
+
+const SCHEMA: &str = r#"
+type Item {
+  @primary
+  id: string,
+  name: string,
+}
+type Store {
+  @primary
+  id: string,
+  items: set<Item>,
+}
+export set<Store> stores;
+"#;
+
+/// Inserts a member with its fast scan index entry, marker and `id` field.
+async fn insert_member<'a>(
+  txn: &dyn KvTransaction,
+  set: &Arc<PathWalker<'a>>,
+  id: &str,
+) -> Arc<PathWalker<'a>> {
+  let id = PrimitiveValue::String(id.into());
+  let mut fast_scan_key = set.set_fast_scan_prefix().unwrap();
+  fast_scan_key.extend_from_slice(&set.encode_primary_key(&id));
+  txn.put(&fast_scan_key, &[]).await.unwrap();
+  let member = set.enter_set(&id).unwrap();
+  txn.put(member.key(), &[]).await.unwrap();
+  txn
+    .put(
+      member.enter_field("id").unwrap().key(),
+      &rmp_serde::to_vec(&id).unwrap(),
+    )
+    .await
+    .unwrap();
+  member
+}
+
+#[tokio::test]
+async fn audit_and_repair_sets() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let schema = compile(&parse(&alloc, SCHEMA).unwrap()).unwrap();
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let stores = PathWalker::from_export(&plan, "stores").unwrap();
+
+  let kv = MockKv::new();
+  let txn = kv.begin_transaction().await.unwrap();
+  for id in &["a", "b", "c"] {
+    let store = insert_member(&*txn, &stores, id).await;
+    let items = store.enter_field("items").unwrap();
+    for id in &["x", "y"] {
+      insert_member(&*txn, &items, id).await;
+    }
+  }
+
+  // A dangling index entry.
+  let mut fast_scan_key = stores.set_fast_scan_prefix().unwrap();
+  fast_scan_key.extend_from_slice(&stores.encode_primary_key(&PrimitiveValue::String("d".into())));
+  txn.put(&fast_scan_key, &[]).await.unwrap();
+
+  // A member without its index entry, nested in another set.
+  let items = stores
+    .enter_set(&PrimitiveValue::String("b".into()))
+    .unwrap()
+    .enter_field("items")
+    .unwrap();
+  let mut fast_scan_key = items.set_fast_scan_prefix().unwrap();
+  fast_scan_key.extend_from_slice(&items.encode_primary_key(&PrimitiveValue::String("y".into())));
+  txn.delete(&fast_scan_key).await.unwrap();
+
+  // Data of a member without its marker.
+  let orphan = items
+    .enter_set(&PrimitiveValue::String("z".into()))
+    .unwrap();
+  txn
+    .put(orphan.enter_field("name").unwrap().key(), b"")
+    .await
+    .unwrap();
+  txn.commit().await.unwrap();
+
+  let options = AuditOptions {
+    repair: false,
+    batch_size: 2,
+  };
+  let report = audit_sets(&kv, &schema, &plan, &options).await.unwrap();
+  assert_eq!(report.sets_checked, 4);
+  assert_eq!(report.members_checked, 4 + 3 * 2 - 1);
+  assert_eq!(report.repaired, 0);
+  let mut kinds = report
+    .inconsistencies
+    .iter()
+    .map(|x| x.kind)
+    .collect::<Vec<_>>();
+  kinds.sort_by_key(|x| *x as u8);
+  assert_eq!(
+    kinds,
+    vec![
+      InconsistencyKind::DanglingIndexEntry,
+      InconsistencyKind::MissingIndexEntry,
+      InconsistencyKind::OrphanData,
+    ]
+  );
+  let missing = report
+    .inconsistencies
+    .iter()
+    .find(|x| x.kind == InconsistencyKind::MissingIndexEntry)
+    .unwrap();
+  assert_eq!(missing.set_key, items.generate_key());
+  assert_eq!(
+    missing.primary_key,
+    items
+      .encode_primary_key(&PrimitiveValue::String("y".into()))
+      .to_vec()
+  );
+
+  let report = audit_sets(
+    &kv,
+    &schema,
+    &plan,
+    &AuditOptions {
+      repair: true,
+      ..options.clone()
+    },
+  )
+  .await
+  .unwrap();
+  assert_eq!(report.inconsistencies.len(), 3);
+  assert_eq!(report.repaired, 3);
+
+  let report = audit_sets(&kv, &schema, &plan, &options).await.unwrap();
+  assert!(report.inconsistencies.is_empty());
+  assert_eq!(report.members_checked, 3 + 3 * 2);
+
+  let txn = kv.begin_transaction().await.unwrap();
+  assert!(txn.get(&fast_scan_key).await.unwrap().is_some());
+  let orphan_prefix = {
+    let mut x = items.set_data_prefix().unwrap();
+    x.extend_from_slice(&items.encode_primary_key(&PrimitiveValue::String("z".into())));
+    x
+  };
+  let mut it = txn
+    .scan_keys(&orphan_prefix, &successor_prefix(&orphan_prefix).unwrap())
+    .await
+    .unwrap();
+  assert!(it.next().await.unwrap().is_none());
+}
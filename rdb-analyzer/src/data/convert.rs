@@ -0,0 +1,177 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_recursion::async_recursion;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+  schema::compile::{CompiledSchema, FieldType, PrimitiveType},
+  storage_plan::{StorageNode, StoragePlan},
+};
+
+use super::{
+  keyenc::successor_of_bounded_prefix, kv::KvTransaction, pathwalker::PathWalker,
+  value::PrimitiveValue,
+};
+
+#[derive(Error, Debug)]
+pub enum ConvertError {
+  #[error("missing type: {0}")]
+  MissingType(String),
+}
+
+/// A widening conversion of stored primitive values from one type to another.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum PrimitiveConversion {
+  Int64ToDouble,
+  StringToBytes,
+}
+
+/// The conversions the planner is allowed to apply when primitive conversions are enabled.
+const REGISTRY: &[PrimitiveConversion] = &[
+  PrimitiveConversion::Int64ToDouble,
+  PrimitiveConversion::StringToBytes,
+];
+
+impl PrimitiveConversion {
+  /// Looks up the conversion from `from` to `to` in the registry.
+  pub fn lookup(from: PrimitiveType, to: PrimitiveType) -> Option<Self> {
+    REGISTRY
+      .iter()
+      .copied()
+      .find(|x| x.from_type() == from && x.to_type() == to)
+  }
+
+  pub fn from_type(&self) -> PrimitiveType {
+    match self {
+      Self::Int64ToDouble => PrimitiveType::Int64,
+      Self::StringToBytes => PrimitiveType::String,
+    }
+  }
+
+  pub fn to_type(&self) -> PrimitiveType {
+    match self {
+      Self::Int64ToDouble => PrimitiveType::Double,
+      Self::StringToBytes => PrimitiveType::Bytes,
+    }
+  }
+
+  /// Converts a value of the source type. Values of other types are returned unchanged.
+  pub fn apply(&self, value: PrimitiveValue) -> PrimitiveValue {
+    match (self, value) {
+      (Self::Int64ToDouble, PrimitiveValue::Int64(x)) => {
+        PrimitiveValue::Double((x as f64).to_bits())
+      }
+      (Self::StringToBytes, PrimitiveValue::String(x)) => PrimitiveValue::Bytes(x.into_bytes()),
+      (_, x) => x,
+    }
+  }
+}
+
+/// Converts the stored values of all leaf nodes in `plan` with a pending conversion in place, and
+/// returns the number of values converted.
+///
+/// Converted values are not distinguishable from unconverted ones, so this must be run exactly
+/// once per plan. Clear the pending conversions with `StoragePlan::clear_conversions` after the
+/// transaction commits.
+pub async fn convert_in_place(
+  txn: &dyn KvTransaction,
+  schema: &CompiledSchema,
+  plan: &StoragePlan,
+) -> Result<u64> {
+  let mut count = 0;
+  for (export_name, export_ty) in &schema.exports {
+    let walker = PathWalker::from_export(plan, export_name)?;
+    match export_ty {
+      // Top-level tables do not necessarily have their own key written.
+      FieldType::Table(x) => convert_table(txn, schema, x, walker, &mut count).await?,
+      _ => convert_field(txn, schema, export_ty, walker, &mut count).await?,
+    }
+  }
+  Ok(count)
+}
+
+#[async_recursion]
+async fn convert_field<'a>(
+  txn: &dyn KvTransaction,
+  schema: &'a CompiledSchema,
+  ty: &'a FieldType,
+  walker: Arc<PathWalker<'a>>,
+  count: &mut u64,
+) -> Result<()> {
+  if !has_conversion(walker.node()) {
+    return Ok(());
+  }
+
+  match ty {
+    FieldType::Primitive(_) => {
+      if let Some(conversion) = walker.node().conversion {
+        let key = walker.generate_key();
+        if let Some(x) = txn.get(&key).await? {
+          let value = PrimitiveValue::decode_stored(x)?;
+          txn
+            .put(&key, &conversion.apply(value).encode_stored())
+            .await?;
+          *count += 1;
+        }
+      }
+    }
+    FieldType::Struct(_) => {}
+    FieldType::Table(x) => {
+      // Nested tables always have their table key written. Checking it here also stops the
+      // recursion on recursive types.
+      if txn.get(walker.key()).await?.is_some() {
+        convert_table(txn, schema, x, walker, count).await?;
+      }
+    }
+    FieldType::Set(member_ty) | FieldType::Map(member_ty) => {
+      let prefix = walker.set_fast_scan_prefix()?;
+      let end = successor_of_bounded_prefix(&prefix);
+      let mut it = txn.scan_keys(&prefix, &end).await?;
+      while let Some(k) = it.next().await? {
+        let member = walker.enter_set_raw(&k[prefix.len()..])?;
+        convert_field(txn, schema, member_ty, member, count).await?;
+      }
+    }
+    FieldType::List(member_ty) => {
+      let len: u64 = match txn.get(walker.key()).await? {
+        Some(x) => rmp_serde::from_slice(&x)?,
+        None => 0,
+      };
+      for i in 0..len {
+        convert_field(txn, schema, member_ty, walker.enter_list(i)?, count).await?;
+      }
+    }
+  }
+  Ok(())
+}
+
+#[async_recursion]
+async fn convert_table<'a>(
+  txn: &dyn KvTransaction,
+  schema: &'a CompiledSchema,
+  name: &str,
+  walker: Arc<PathWalker<'a>>,
+  count: &mut u64,
+) -> Result<()> {
+  let specialized_ty = schema
+    .types
+    .get(name)
+    .ok_or_else(|| ConvertError::MissingType(name.to_string()))?;
+  for (field_name, (field_ty, _)) in &specialized_ty.fields {
+    let field_walker = walker.enter_field(field_name)?;
+    convert_field(txn, schema, field_ty, field_walker, count).await?;
+  }
+  Ok(())
+}
+
+fn has_conversion(node: &StorageNode) -> bool {
+  node.conversion.is_some()
+    || node
+      .set
+      .as_ref()
+      .map(|x| has_conversion(x))
+      .unwrap_or(false)
+    || node.children.values().any(has_conversion)
+}
@@ -0,0 +1,177 @@
+use bumpalo::Bump;
+
+use crate::{
+  data::{
+    convert::{convert_in_place, PrimitiveConversion},
+    kv::{KeyValueStore, KvTransaction},
+    mock_kv::MockKv,
+    pathwalker::PathWalker,
+    value::PrimitiveValue,
+  },
+  schema::{
+    compile::{compile, PrimitiveType},
+    grammar::parse,
+  },
+  storage_plan::planner::{
+    generate_plan_for_schema, generate_plan_for_schema_with_options, PlannerOptions,
+  },
+};
+
+const OLD_SCHEMA: &str = r#"
+type Item {
+  @primary
+  id: string,
+  count: int64,
+  name: string,
+}
+export set<Item> items;
+export int64 total;
+"#;
+
+const NEW_SCHEMA: &str = r#"
+type Item {
+  @primary
+  id: string,
+  count: double,
+  name: bytes,
+}
+export set<Item> items;
+export double total;
+"#;
+
+async fn put(txn: &dyn KvTransaction, key: &[u8], value: PrimitiveValue) {
+  txn
+    .put(key, &rmp_serde::to_vec(&value).unwrap())
+    .await
+    .unwrap();
+}
+
+/// Compares encodings, since doubles are not always decoded back as doubles.
+async fn assert_stored(txn: &dyn KvTransaction, key: &[u8], value: PrimitiveValue) {
+  assert_eq!(
+    txn.get(key).await.unwrap().unwrap(),
+    rmp_serde::to_vec(&value).unwrap()
+  );
+}
+
+#[test]
+fn registry() {
+  assert_eq!(
+    PrimitiveConversion::lookup(PrimitiveType::Int64, PrimitiveType::Double),
+    Some(PrimitiveConversion::Int64ToDouble)
+  );
+  assert_eq!(
+    PrimitiveConversion::lookup(PrimitiveType::String, PrimitiveType::Bytes),
+    Some(PrimitiveConversion::StringToBytes)
+  );
+  assert_eq!(
+    PrimitiveConversion::lookup(PrimitiveType::Double, PrimitiveType::Int64),
+    None
+  );
+  assert_eq!(
+    PrimitiveConversion::Int64ToDouble.apply(PrimitiveValue::Int64(-3)),
+    PrimitiveValue::Double((-3.0f64).to_bits())
+  );
+}
+
+#[tokio::test]
+async fn convert_primitives_in_place() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let old_schema = compile(&parse(&alloc, OLD_SCHEMA).unwrap()).unwrap();
+  let new_schema = compile(&parse(&alloc, NEW_SCHEMA).unwrap()).unwrap();
+  let old_plan =
+    generate_plan_for_schema(&Default::default(), &Default::default(), &old_schema).unwrap();
+
+  // Without the option, the changed fields get new keys.
+  let plan = generate_plan_for_schema(&old_plan, &old_schema, &new_schema).unwrap();
+  assert_ne!(plan.nodes["total"].key, old_plan.nodes["total"].key);
+
+  let mut plan = generate_plan_for_schema_with_options(
+    &old_plan,
+    &old_schema,
+    &new_schema,
+    &PlannerOptions {
+      convert_primitives: true,
+    },
+  )
+  .unwrap();
+  assert_eq!(plan.nodes["total"].key, old_plan.nodes["total"].key);
+  assert_eq!(
+    plan.nodes["total"].conversion,
+    Some(PrimitiveConversion::Int64ToDouble)
+  );
+
+  // Write some data with the old plan.
+  let kv = MockKv::new();
+  let txn = kv.begin_transaction().await.unwrap();
+  put(
+    &*txn,
+    &PathWalker::from_export(&old_plan, "total")
+      .unwrap()
+      .generate_key(),
+    PrimitiveValue::Int64(42),
+  )
+  .await;
+  let items = PathWalker::from_export(&old_plan, "items").unwrap();
+  let id = PrimitiveValue::String("a".into());
+  let mut fast_scan_key = items.set_fast_scan_prefix().unwrap();
+  fast_scan_key.extend_from_slice(&items.encode_primary_key(&id));
+  txn.put(&fast_scan_key, &[]).await.unwrap();
+  let item = items.enter_set(&id).unwrap();
+  txn.put(item.key(), &[]).await.unwrap();
+  put(
+    &*txn,
+    &item.enter_field("id").unwrap().generate_key(),
+    id.clone(),
+  )
+  .await;
+  put(
+    &*txn,
+    &item.enter_field("count").unwrap().generate_key(),
+    PrimitiveValue::Int64(-3),
+  )
+  .await;
+  put(
+    &*txn,
+    &item.enter_field("name").unwrap().generate_key(),
+    PrimitiveValue::String("x".into()),
+  )
+  .await;
+  txn.commit().await.unwrap();
+
+  let txn = kv.begin_transaction().await.unwrap();
+  assert_eq!(
+    convert_in_place(&*txn, &new_schema, &plan).await.unwrap(),
+    3
+  );
+  txn.commit().await.unwrap();
+  plan.clear_conversions();
+  assert!(plan.nodes["total"].conversion.is_none());
+
+  let txn = kv.begin_transaction().await.unwrap();
+  assert_stored(
+    &*txn,
+    &PathWalker::from_export(&plan, "total")
+      .unwrap()
+      .generate_key(),
+    PrimitiveValue::Double(42.0f64.to_bits()),
+  )
+  .await;
+  let item = PathWalker::from_export(&plan, "items")
+    .unwrap()
+    .enter_set(&id)
+    .unwrap();
+  for (field, value) in [
+    ("id", id.clone()),
+    ("count", PrimitiveValue::Double((-3.0f64).to_bits())),
+    ("name", PrimitiveValue::Bytes(b"x".to_vec())),
+  ] {
+    assert_stored(
+      &*txn,
+      &item.enter_field(field).unwrap().generate_key(),
+      value,
+    )
+    .await;
+  }
+}
@@ -0,0 +1,362 @@
+//! Decoding of raw keys and values in the key-value store, for debugging storage.
+
+use std::fmt::Display;
+
+use anyhow::Result;
+use byteorder::{BigEndian, ByteOrder};
+use thiserror::Error;
+
+use crate::{
+  schema::compile::{CompiledSchema, FieldType, PrimitiveType},
+  storage_plan::StoragePlan,
+};
+
+use super::{
+  backfill::SortIndexProgress,
+  keyenc,
+  pathwalker::{KeyKind, PathSegment, PathWalker},
+  value::{PackedValue, PrimitiveValue},
+};
+
+#[derive(Error, Debug)]
+pub enum InspectError {
+  #[error("export not found in schema: `{0}`")]
+  ExportNotFound(String),
+
+  #[error("type not found in schema: `{0}`")]
+  TypeNotFound(String),
+
+  #[error("field `{0}` not found in type `{1}`")]
+  FieldNotFound(String, String),
+
+  #[error("selector on non-collection type `{0}`")]
+  SelectorOnNonCollection(String),
+
+  #[error("invalid key component: {0}")]
+  InvalidKeyComponent(String),
+
+  #[error("unexpected non-empty value for type `{0}`")]
+  UnexpectedValue(String),
+}
+
+/// A segment of a decoded path.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecodedSegment {
+  /// An export, or a field of a table.
+  Field(String),
+
+  /// The primary key of a set member, or the key of a map entry.
+  Key(PrimitiveValue),
+
+  /// The index of a list element.
+  Index(u64),
+}
+
+/// What a decoded key stores about its location.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecodedKeyKind {
+  /// The location itself.
+  Node,
+
+  /// An entry of the fast scan index of a set, with the primary key of the member.
+  SetFastScan(PrimitiveValue),
+
+  /// An entry of the sort key index of a set, with the sort key value (`None` if null) and the
+  /// primary key of the member.
+  SetSortKey(Option<PrimitiveValue>, PrimitiveValue),
+
+  /// The counter of the `@auto` primary keys of a set.
+  SetAutoCounter,
+
+  /// The progress of the backfill of the sort key index of a set.
+  SetSortIndexProgress,
+
+  /// A chunk of a `@blob` field, with its index.
+  BlobChunk(u32),
+}
+
+/// A raw key mapped to its logical path.
+#[derive(Clone, Debug)]
+pub struct DecodedPath {
+  pub segments: Vec<DecodedSegment>,
+  pub kind: DecodedKeyKind,
+
+  /// The type of the location.
+  pub field_type: FieldType,
+
+  /// Whether the location is a `@blob` field, whose own key stores its length.
+  pub blob: bool,
+}
+
+/// A decoded stored value.
+#[derive(Clone, Debug)]
+pub enum DecodedValue {
+  /// The value of a primitive field.
+  Primitive(PrimitiveValue),
+
+  /// The packed value of a struct field.
+  Packed(PackedValue),
+
+  /// The empty presence marker of a table, a set, or an index entry.
+  Marker,
+
+  /// The length of a list or a blob, or the last generated `@auto` primary key of a set.
+  Counter(u64),
+
+  /// The progress of the backfill of the sort key index of a set.
+  SortIndexProgress(SortIndexProgress),
+}
+
+/// Maps a raw key to its logical path. Returns `None` if the key does not belong to any location
+/// in the plan.
+pub fn decode_key(
+  plan: &StoragePlan,
+  schema: &CompiledSchema,
+  key: &[u8],
+) -> Result<Option<DecodedPath>> {
+  let decoded = match PathWalker::decode_key(plan, key)? {
+    Some(x) => x,
+    None => return Ok(None),
+  };
+
+  let mut segments = vec![];
+  let mut field_type: Option<&FieldType> = None;
+  for segment in decoded.walker.path() {
+    match segment {
+      PathSegment::Field(name) => {
+        let ty = match field_type {
+          None => schema
+            .exports
+            .get(name)
+            .ok_or_else(|| InspectError::ExportNotFound(name.to_string()))?,
+          Some(FieldType::Table(ty)) => {
+            &schema
+              .types
+              .get(ty)
+              .ok_or_else(|| InspectError::TypeNotFound(ty.to_string()))?
+              .fields
+              .get(name)
+              .ok_or_else(|| InspectError::FieldNotFound(name.to_string(), ty.to_string()))?
+              .0
+          }
+          Some(x) => {
+            return Err(InspectError::FieldNotFound(name.to_string(), x.to_string()).into())
+          }
+        };
+        segments.push(DecodedSegment::Field(name.to_string()));
+        field_type = Some(ty);
+      }
+      PathSegment::Selector(selector) => match field_type {
+        Some(FieldType::List(member)) => {
+          if selector.len() != 8 {
+            return Err(InspectError::InvalidKeyComponent(hex::encode(&selector)).into());
+          }
+          segments.push(DecodedSegment::Index(BigEndian::read_u64(&selector)));
+          field_type = Some(&**member);
+        }
+        Some(FieldType::Set(member)) | Some(FieldType::Map(member)) => {
+          segments.push(DecodedSegment::Key(decode_key_component(&selector)?));
+          field_type = Some(&**member);
+        }
+        Some(x) => return Err(InspectError::SelectorOnNonCollection(x.to_string()).into()),
+        None => return Err(InspectError::InvalidKeyComponent(hex::encode(&selector)).into()),
+      },
+    }
+  }
+
+  let kind = match decoded.kind {
+    KeyKind::Node => DecodedKeyKind::Node,
+    KeyKind::SetFastScan(x) => DecodedKeyKind::SetFastScan(decode_key_component(&x)?),
+    KeyKind::SetSortKey(x) => {
+      let (sort_key, primary_key) = keyenc::split_sort_key_entry(&x)
+        .ok_or_else(|| InspectError::InvalidKeyComponent(hex::encode(&x)))?;
+      let sort_key = match sort_key {
+        [0x00] => None,
+        _ => Some(decode_sort_key_component(sort_key)?),
+      };
+      DecodedKeyKind::SetSortKey(sort_key, decode_key_component(primary_key)?)
+    }
+    KeyKind::SetAutoCounter => DecodedKeyKind::SetAutoCounter,
+    KeyKind::SetSortIndexProgress => DecodedKeyKind::SetSortIndexProgress,
+    KeyKind::BlobChunk(x) => DecodedKeyKind::BlobChunk(x),
+  };
+
+  Ok(Some(DecodedPath {
+    blob: decoded.walker.node().blob,
+    segments,
+    kind,
+    field_type: field_type
+      .cloned()
+      .expect("inconsistency: decoded path does not start with an export"),
+  }))
+}
+
+/// Decodes the value stored at a location of type `field_type`.
+pub fn decode_value(field_type: &FieldType, bytes: &[u8]) -> Result<DecodedValue> {
+  Ok(match field_type {
+    FieldType::Primitive(ty) => match (ty, PrimitiveValue::decode_stored(bytes.to_vec())?) {
+      // The bits of doubles that fit in an `i64` are decoded as `Int64`.
+      (PrimitiveType::Double, PrimitiveValue::Int64(x)) => {
+        DecodedValue::Primitive(PrimitiveValue::Double(x as u64))
+      }
+      (_, x) => DecodedValue::Primitive(x),
+    },
+    FieldType::Struct(_) => DecodedValue::Packed(rmp_serde::from_slice(bytes)?),
+    FieldType::List(_) => DecodedValue::Counter(rmp_serde::from_slice(bytes)?),
+    FieldType::Table(_) | FieldType::Set(_) | FieldType::Map(_) => {
+      if !bytes.is_empty() {
+        return Err(InspectError::UnexpectedValue(field_type.to_string()).into());
+      }
+      DecodedValue::Marker
+    }
+  })
+}
+
+impl DecodedPath {
+  /// Decodes the value stored under the key of this path.
+  pub fn decode_value(&self, bytes: &[u8]) -> Result<DecodedValue> {
+    match &self.kind {
+      DecodedKeyKind::Node if self.blob => Ok(DecodedValue::Counter(rmp_serde::from_slice(bytes)?)),
+      DecodedKeyKind::Node => decode_value(&self.field_type, bytes),
+      DecodedKeyKind::BlobChunk(_) => Ok(DecodedValue::Primitive(PrimitiveValue::Bytes(
+        bytes.to_vec(),
+      ))),
+      DecodedKeyKind::SetFastScan(_) | DecodedKeyKind::SetSortKey(..) => {
+        if !bytes.is_empty() {
+          return Err(InspectError::UnexpectedValue(self.field_type.to_string()).into());
+        }
+        Ok(DecodedValue::Marker)
+      }
+      DecodedKeyKind::SetAutoCounter => Ok(DecodedValue::Counter(rmp_serde::from_slice(bytes)?)),
+      DecodedKeyKind::SetSortIndexProgress => Ok(DecodedValue::SortIndexProgress(
+        rmp_serde::from_slice(bytes)?,
+      )),
+    }
+  }
+}
+
+impl Display for DecodedPath {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    for (i, segment) in self.segments.iter().enumerate() {
+      match segment {
+        DecodedSegment::Field(x) if i == 0 => write!(f, "{}", x)?,
+        DecodedSegment::Field(x) => write!(f, ".{}", x)?,
+        DecodedSegment::Key(x) => write!(f, "[{}]", x)?,
+        DecodedSegment::Index(x) => write!(f, "[{}]", x)?,
+      }
+    }
+    match &self.kind {
+      DecodedKeyKind::Node => Ok(()),
+      DecodedKeyKind::SetFastScan(x) => write!(f, " <fast scan {}>", x),
+      DecodedKeyKind::SetSortKey(Some(x), y) => write!(f, " <sort key {}, {}>", x, y),
+      DecodedKeyKind::SetSortKey(None, y) => write!(f, " <sort key null, {}>", y),
+      DecodedKeyKind::SetAutoCounter => write!(f, " <auto counter>"),
+      DecodedKeyKind::SetSortIndexProgress => write!(f, " <sort index progress>"),
+      DecodedKeyKind::BlobChunk(x) => write!(f, " <blob chunk {}>", x),
+    }
+  }
+}
+
+impl Display for DecodedValue {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Primitive(x) => write!(f, "{}", x),
+      Self::Packed(x) => write_packed(f, x),
+      Self::Marker => write!(f, "(present)"),
+      Self::Counter(x) => write!(f, "{}", x),
+      Self::SortIndexProgress(x) => write!(
+        f,
+        "backfill {:016x}: {}",
+        x.backfill_id,
+        match (&x.last_primary_key, x.ready) {
+          (_, true) => "ready".to_string(),
+          (Some(k), false) => format!("after {}", hex::encode(k)),
+          (None, false) => "not started".to_string(),
+        }
+      ),
+    }
+  }
+}
+
+fn write_packed(f: &mut std::fmt::Formatter<'_>, value: &PackedValue) -> std::fmt::Result {
+  match value {
+    PackedValue::P(x) => write!(f, "{}", x),
+    PackedValue::M(x) => {
+      write!(f, "{{")?;
+      for (i, (k, v)) in x.iter().enumerate() {
+        if i != 0 {
+          write!(f, ", ")?;
+        }
+        write!(f, "{}: ", serde_json::to_string(k).unwrap())?;
+        write_packed(f, v)?;
+      }
+      write!(f, "}}")
+    }
+    PackedValue::S(x) => {
+      write!(f, "[")?;
+      for (i, v) in x.iter().enumerate() {
+        if i != 0 {
+          write!(f, ", ")?;
+        }
+        write_packed(f, v)?;
+      }
+      write!(f, "]")
+    }
+  }
+}
+
+/// Decodes a key component encoded with `keyenc::encode_key`.
+fn decode_key_component(data: &[u8]) -> Result<PrimitiveValue> {
+  let invalid = || InspectError::InvalidKeyComponent(hex::encode(data));
+  Ok(match data.first() {
+    Some(0x01) => PrimitiveValue::Bytes(decode_terminated(&data[1..]).ok_or_else(invalid)?),
+    Some(0x02) => PrimitiveValue::String(keyenc::decode_string_key(data).ok_or_else(invalid)?),
+    Some(0x03) | Some(0x04) => decode_fixed(data).ok_or_else(invalid)?,
+    _ => return Err(invalid().into()),
+  })
+}
+
+/// Decodes a sort key component encoded with `keyenc::encode_sort_key`.
+fn decode_sort_key_component(data: &[u8]) -> Result<PrimitiveValue> {
+  let invalid = || InspectError::InvalidKeyComponent(hex::encode(data));
+  Ok(match data.first() {
+    Some(0x01) => PrimitiveValue::Bytes(decode_terminated(&data[1..]).ok_or_else(invalid)?),
+    Some(0x02) => PrimitiveValue::String(
+      String::from_utf8(decode_terminated(&data[1..]).ok_or_else(invalid)?)
+        .map_err(|_| invalid())?,
+    ),
+    Some(0x03) | Some(0x04) => decode_fixed(data).ok_or_else(invalid)?,
+    _ => return Err(invalid().into()),
+  })
+}
+
+/// Decodes an int64 or double key component.
+fn decode_fixed(data: &[u8]) -> Option<PrimitiveValue> {
+  if data.len() != 9 {
+    return None;
+  }
+  let x = BigEndian::read_u64(&data[1..]);
+  let top_bit = 1u64 << 63;
+  Some(match data[0] {
+    0x03 => PrimitiveValue::Int64((x ^ top_bit) as i64),
+    _ => PrimitiveValue::Double(if x & top_bit != 0 { x ^ top_bit } else { !x }),
+  })
+}
+
+/// Reverses the escaping of `0x00` as `0x00 0xff`. `data` must end with the `0x00` terminator.
+fn decode_terminated(data: &[u8]) -> Option<Vec<u8>> {
+  let mut out = Vec::with_capacity(data.len());
+  let mut it = data.iter();
+  while let Some(&b) = it.next() {
+    if b == 0x00 {
+      match it.next() {
+        Some(0xff) => out.push(0x00),
+        None => return Some(out),
+        Some(_) => return None,
+      }
+    } else {
+      out.push(b);
+    }
+  }
+  None
+}
@@ -0,0 +1,190 @@
+use bumpalo::Bump;
+
+use crate::{
+  data::{
+    pathwalker::PathWalker,
+    value::{PackedValue, PrimitiveValue},
+  },
+  schema::{
+    compile::{compile, FieldType, PrimitiveType},
+    grammar::parse,
+  },
+  storage_plan::planner::generate_plan_for_schema,
+};
+
+use super::inspect::{decode_key, decode_value, DecodedKeyKind, DecodedSegment, DecodedValue};
+
+#[test]
+fn inspect_keys_and_values() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+  type Item {
+    @primary
+    id: int64,
+    @sort_key
+    name: string,
+    tags: list<string>,
+  }
+  type Store {
+    @primary
+    name: string,
+    attrs: map<string, int64>,
+    items: set<Item>,
+  }
+  export set<Store> stores;
+  "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+
+  let store = PathWalker::from_export(&plan, "stores")
+    .unwrap()
+    .enter_set(&PrimitiveValue::String("a\0b".into()))
+    .unwrap();
+  let items = store.enter_field("items").unwrap();
+  let item = items.enter_set(&PrimitiveValue::Int64(-1)).unwrap();
+
+  let decoded = decode_key(
+    &plan,
+    &schema,
+    item
+      .enter_field("tags")
+      .unwrap()
+      .enter_list(3)
+      .unwrap()
+      .key(),
+  )
+  .unwrap()
+  .unwrap();
+  assert_eq!(
+    decoded.segments,
+    vec![
+      DecodedSegment::Field("stores".into()),
+      DecodedSegment::Key(PrimitiveValue::String("a\0b".into())),
+      DecodedSegment::Field("items".into()),
+      DecodedSegment::Key(PrimitiveValue::Int64(-1)),
+      DecodedSegment::Field("tags".into()),
+      DecodedSegment::Index(3),
+    ]
+  );
+  assert_eq!(decoded.kind, DecodedKeyKind::Node);
+  assert_eq!(
+    decoded.to_string(),
+    r#"stores["a\u0000b"].items[-1].tags[3]"#
+  );
+  match decoded
+    .decode_value(&rmp_serde::to_vec(&PrimitiveValue::String("x".into())).unwrap())
+    .unwrap()
+  {
+    DecodedValue::Primitive(x) => assert_eq!(x, PrimitiveValue::String("x".into())),
+    x => panic!("unexpected value: {:?}", x),
+  }
+
+  let decoded = decode_key(
+    &plan,
+    &schema,
+    store
+      .enter_field("attrs")
+      .unwrap()
+      .enter_set(&PrimitiveValue::String("k".into()))
+      .unwrap()
+      .key(),
+  )
+  .unwrap()
+  .unwrap();
+  assert_eq!(decoded.to_string(), r#"stores["a\u0000b"].attrs["k"]"#);
+  assert!(matches!(
+    decoded.field_type,
+    FieldType::Primitive(PrimitiveType::Int64)
+  ));
+
+  let mut fast_scan_key = items.set_fast_scan_prefix().unwrap();
+  fast_scan_key.extend_from_slice(&items.encode_primary_key(&PrimitiveValue::Int64(42)));
+  let decoded = decode_key(&plan, &schema, &fast_scan_key).unwrap().unwrap();
+  assert_eq!(
+    decoded.kind,
+    DecodedKeyKind::SetFastScan(PrimitiveValue::Int64(42))
+  );
+  assert_eq!(
+    decoded.to_string(),
+    r#"stores["a\u0000b"].items <fast scan 42>"#
+  );
+  assert!(matches!(
+    decoded.decode_value(&[]).unwrap(),
+    DecodedValue::Marker
+  ));
+
+  let sort_key_entry = items
+    .set_sort_key_entry(
+      Some(&PrimitiveValue::String("n\0".into())),
+      &items.encode_primary_key(&PrimitiveValue::Int64(42)),
+    )
+    .unwrap();
+  let decoded = decode_key(&plan, &schema, &sort_key_entry)
+    .unwrap()
+    .unwrap();
+  assert_eq!(
+    decoded.kind,
+    DecodedKeyKind::SetSortKey(
+      Some(PrimitiveValue::String("n\0".into())),
+      PrimitiveValue::Int64(42)
+    )
+  );
+
+  let decoded = decode_key(&plan, &schema, &items.set_auto_counter_key().unwrap())
+    .unwrap()
+    .unwrap();
+  assert_eq!(
+    decoded.to_string(),
+    r#"stores["a\u0000b"].items <auto counter>"#
+  );
+  assert!(matches!(
+    decoded
+      .decode_value(&rmp_serde::to_vec(&7u64).unwrap())
+      .unwrap(),
+    DecodedValue::Counter(7)
+  ));
+
+  assert!(decode_key(&plan, &schema, b"no such key")
+    .unwrap()
+    .is_none());
+}
+
+#[test]
+fn inspect_values() {
+  let ty = FieldType::Primitive(PrimitiveType::Double);
+  let value = decode_value(
+    &ty,
+    &rmp_serde::to_vec(&PrimitiveValue::Double(1.5f64.to_bits())).unwrap(),
+  )
+  .unwrap();
+  assert_eq!(value.to_string(), "1.5");
+
+  let ty = FieldType::Struct(Default::default());
+  let packed = PackedValue::M(
+    vec![
+      ("a".to_string(), PackedValue::P(PrimitiveValue::Int64(1))),
+      (
+        "b".to_string(),
+        PackedValue::S(vec![PackedValue::P(PrimitiveValue::String("x".into()))]),
+      ),
+    ]
+    .into_iter()
+    .collect(),
+  );
+  let value = decode_value(&ty, &rmp_serde::to_vec(&packed).unwrap()).unwrap();
+  assert_eq!(value.to_string(), r#"{"a": 1, "b": ["x"]}"#);
+
+  let ty = FieldType::Table("Item".into());
+  assert!(matches!(
+    decode_value(&ty, &[]).unwrap(),
+    DecodedValue::Marker
+  ));
+  assert!(decode_value(&ty, &[1]).is_err());
+}
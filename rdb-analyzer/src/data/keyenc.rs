@@ -0,0 +1,228 @@
+//! Order-preserving encodings of primitive values as key components.
+
+use std::iter::FromIterator;
+
+use byteorder::{BigEndian, ByteOrder};
+use smallvec::{smallvec, SmallVec};
+
+use super::value::{normalize_double_bits, PrimitiveValue};
+
+/// The version of the encodings in this module. Namespaces record the version their keys are
+/// written with, and are not used with another one.
+///
+/// Version 1 stored strings in key components unescaped, so deleting the member with key `"a"`
+/// also deleted the data of the member with key `"a\0b"`. Version 2 escapes `0x00` and `0x01`;
+/// other strings are encoded the same way by both versions.
+pub const KEY_ENCODING_VERSION: u32 = 2;
+
+const TOP_BIT: u64 = 1u64 << 63;
+
+/// Encodes a value as a key component. Comparing two encoded values of the same type bytewise
+/// gives the same result as comparing the values.
+///
+/// The encoding starts with a tag byte, which is never `0xff`:
+///
+/// - `0x01` bytes: `0x00` escaped as `0x00 0xff`, terminated with `0x00`.
+/// - `0x02` strings: `0x00` escaped as `0x01 0x01` and `0x01` as `0x01 0x02`, unterminated.
+/// - `0x03` int64: big-endian, with the top bit flipped.
+/// - `0x04` double: big-endian bits, flipped entirely if negative and with the top bit set
+///   otherwise.
+///
+/// https://activesphere.com/blog/2018/08/17/order-preserving-serialization
+pub fn encode_key(value: &PrimitiveValue) -> SmallVec<[u8; 9]> {
+  match value {
+    PrimitiveValue::Bytes(x) => encode_terminated(0x01, x),
+    PrimitiveValue::String(x) => {
+      let mut buf = smallvec![0x02u8];
+      for &b in x.as_bytes() {
+        match b {
+          0x00 | 0x01 => buf.extend_from_slice(&[0x01, b + 1]),
+          _ => buf.push(b),
+        }
+      }
+      buf
+    }
+    PrimitiveValue::Int64(x) => {
+      // Flip the top bit for order preservation.
+      let x = (*x as u64) ^ TOP_BIT;
+
+      let mut buf = smallvec![0u8; 9];
+      buf[0] = 0x03;
+      BigEndian::write_u64(&mut buf[1..], x);
+      buf
+    }
+    PrimitiveValue::Double(x) => {
+      // Negative doubles are flipped entirely and positive ones get the top bit set. NaN is
+      // normalized first so that it sorts after `inf`.
+      let x = normalize_double_bits(*x);
+
+      let x = if x & TOP_BIT != 0 { !x } else { x ^ TOP_BIT };
+
+      let mut buf = smallvec![0u8; 9];
+      buf[0] = 0x04;
+      BigEndian::write_u64(&mut buf[1..], x);
+      buf
+    }
+  }
+}
+
+/// Like `encode_key`, but strings are escaped and terminated like bytes so that the encoding is
+/// self-delimiting and can be followed by another key component.
+pub fn encode_sort_key(value: &PrimitiveValue) -> SmallVec<[u8; 9]> {
+  match value {
+    PrimitiveValue::String(x) => encode_terminated(0x02, x.as_bytes()),
+    _ => encode_key(value),
+  }
+}
+
+/// Encodes a string or byte array as a prefix of key components: the key components of exactly
+/// the values starting with `value` start with the result.
+pub fn encode_key_prefix(value: &PrimitiveValue) -> SmallVec<[u8; 9]> {
+  let mut buf = encode_key(value);
+  if let PrimitiveValue::Bytes(_) = value {
+    // Drop the terminator.
+    buf.pop();
+  }
+  buf
+}
+
+/// Like `encode_key_prefix`, for sort key components.
+pub fn encode_sort_key_prefix(value: &PrimitiveValue) -> SmallVec<[u8; 9]> {
+  let mut buf = encode_sort_key(value);
+  if let PrimitiveValue::String(_) | PrimitiveValue::Bytes(_) = value {
+    buf.pop();
+  }
+  buf
+}
+
+/// Returns the smallest key that is greater than all keys starting with `prefix`, to be used as
+/// the exclusive end of a scan over the prefix.
+///
+/// Trailing 0xff bytes are dropped and the last remaining byte is incremented, carrying into the
+/// bytes before it. Returns `None` if there is no such key because `prefix` is empty or consists of
+/// 0xff bytes only: the range of keys starting with it is unbounded.
+pub fn successor_prefix(prefix: &[u8]) -> Option<Vec<u8>> {
+  let mut key = prefix.to_vec();
+  while let Some(x) = key.pop() {
+    if x != 0xff {
+      key.push(x + 1);
+      return Some(key);
+    }
+  }
+  None
+}
+
+/// Like `successor_prefix`, for prefixes that have a successor because they contain a byte other
+/// than 0xff. This holds for all keys of a data store, which start with a storage key that is
+/// never all 0xff, and for prefixes ending with an encoded key component, whose tag is never 0xff.
+///
+/// Panics if `prefix` has no successor.
+pub fn successor_of_bounded_prefix(prefix: &[u8]) -> Vec<u8> {
+  successor_prefix(prefix).expect("inconsistency: prefix has no successor")
+}
+
+/// Decodes a string key component. Returns `None` if `key` is not a valid string key component.
+pub fn decode_string_key(key: &[u8]) -> Option<String> {
+  let (&tag, rest) = key.split_first()?;
+  if tag != 0x02 {
+    return None;
+  }
+  let mut out = Vec::with_capacity(rest.len());
+  let mut it = rest.iter();
+  while let Some(&b) = it.next() {
+    match b {
+      0x00 => return None,
+      0x01 => match *it.next()? {
+        x @ 0x01..=0x02 => out.push(x - 1),
+        _ => return None,
+      },
+      _ => out.push(b),
+    }
+  }
+  String::from_utf8(out).ok()
+}
+
+/// Decodes a key component encoded with `encode_key`. Returns `None` if `key` is not a valid key
+/// component. Strings are decoded as they are encoded, i.e. with their collation applied.
+pub fn decode_key(key: &[u8]) -> Option<PrimitiveValue> {
+  match *key.first()? {
+    0x01 => decode_terminated(&key[1..]).map(PrimitiveValue::Bytes),
+    0x02 => decode_string_key(key).map(PrimitiveValue::String),
+    0x03 if key.len() == 9 => Some(PrimitiveValue::Int64(
+      (BigEndian::read_u64(&key[1..]) ^ TOP_BIT) as i64,
+    )),
+    0x04 if key.len() == 9 => {
+      let x = BigEndian::read_u64(&key[1..]);
+      Some(PrimitiveValue::Double(if x & TOP_BIT != 0 {
+        x ^ TOP_BIT
+      } else {
+        !x
+      }))
+    }
+    _ => None,
+  }
+}
+
+/// Splits a sort key entry, with the prefix stripped, into the encoded sort key value and the
+/// primary key of the member.
+pub fn split_sort_key_entry(entry: &[u8]) -> Option<(&[u8], &[u8])> {
+  let len = match *entry.first()? {
+    0x00 => 1,
+    0x03 | 0x04 => 9,
+    0x01 | 0x02 => {
+      // Escaped, and terminated with a zero byte that is not followed by 0xff.
+      let mut i = 1;
+      loop {
+        match entry.get(i)? {
+          0x00 if entry.get(i + 1) != Some(&0xff) => break i + 1,
+          0x00 => i += 2,
+          _ => i += 1,
+        }
+      }
+    }
+    _ => return None,
+  };
+  if entry.len() < len {
+    return None;
+  }
+  Some(entry.split_at(len))
+}
+
+/// Reverses `encode_terminated`, without the tag. The terminator must be the last byte.
+fn decode_terminated(x: &[u8]) -> Option<Vec<u8>> {
+  let mut out = Vec::with_capacity(x.len());
+  let mut it = x.iter();
+  while let Some(&b) = it.next() {
+    match b {
+      0x00 => match it.as_slice() {
+        [0xff, ..] => {
+          it.next();
+          out.push(0x00);
+        }
+        [] => return Some(out),
+        _ => return None,
+      },
+      _ => out.push(b),
+    }
+  }
+  None
+}
+
+/// Escapes `0x00` as `0x00 0xff` and appends a `0x00` terminator.
+fn encode_terminated(tag: u8, x: &[u8]) -> SmallVec<[u8; 9]> {
+  SmallVec::from_iter(
+    std::iter::once(tag)
+      .chain(
+        x.iter()
+          .map(|&x| -> SmallVec<[u8; 2]> {
+            if x == 0 {
+              smallvec![0x00, 0xff]
+            } else {
+              smallvec![x]
+            }
+          })
+          .flatten(),
+      )
+      .chain(std::iter::once(0x00u8)),
+  )
+}
@@ -0,0 +1,213 @@
+use proptest::prelude::*;
+
+use crate::testutil::{arb_primitive_value_pair, natural_order};
+
+use super::{
+  keyenc::{
+    decode_key, decode_string_key, encode_key, encode_key_prefix, encode_sort_key,
+    encode_sort_key_prefix, split_sort_key_entry, successor_of_bounded_prefix, successor_prefix,
+  },
+  value::PrimitiveValue,
+};
+
+/// Strings made of the bytes around the escape and delimiter bytes.
+const CONTROL_STRING: &str = "[\\x00\\x01\\x02a\u{ff}]{0,8}";
+
+fn string(x: &str) -> PrimitiveValue {
+  PrimitiveValue::String(x.into())
+}
+
+/// All strings of up to three characters from `alphabet`.
+fn all_strings(alphabet: &[char]) -> Vec<String> {
+  let mut out = vec![String::new()];
+  let mut last = vec![String::new()];
+  for _ in 0..3 {
+    last = last
+      .iter()
+      .flat_map(|x| {
+        alphabet.iter().map(move |c| {
+          let mut x = x.clone();
+          x.push(*c);
+          x
+        })
+      })
+      .collect();
+    out.extend(last.iter().cloned());
+  }
+  out
+}
+
+proptest! {
+  #[test]
+  fn key_encoding_preserves_order((a, b) in arb_primitive_value_pair()) {
+    if let Some(x) = natural_order(&a, &b) {
+      prop_assert_eq!(encode_key(&a).cmp(&encode_key(&b)), x);
+      prop_assert_eq!(encode_sort_key(&a).cmp(&encode_sort_key(&b)), x);
+    }
+  }
+
+  #[test]
+  fn key_encoding_agrees_with_eq((a, b) in arb_primitive_value_pair()) {
+    prop_assert_eq!(a == b, encode_key(&a) == encode_key(&b));
+    prop_assert_eq!(a == b, encode_sort_key(&a) == encode_sort_key(&b));
+  }
+
+  #[test]
+  fn control_strings_preserve_order(a in CONTROL_STRING, b in CONTROL_STRING) {
+    prop_assert_eq!(encode_key(&string(&a)).cmp(&encode_key(&string(&b))), a.cmp(&b));
+    prop_assert_eq!(
+      encode_sort_key(&string(&a)).cmp(&encode_sort_key(&string(&b))),
+      a.cmp(&b)
+    );
+  }
+
+  #[test]
+  fn string_keys_roundtrip(a in any::<String>()) {
+    prop_assert_eq!(decode_string_key(&encode_key(&string(&a))), Some(a));
+  }
+
+  #[test]
+  fn keys_roundtrip((a, _) in arb_primitive_value_pair()) {
+    prop_assert_eq!(decode_key(&encode_key(&a)), Some(a));
+  }
+
+  #[test]
+  fn control_bytes_roundtrip(
+    a in prop::collection::vec(prop::sample::select(vec![0x00u8, 0x01, 0xff]), 0..8)
+  ) {
+    let a = PrimitiveValue::Bytes(a);
+    prop_assert_eq!(decode_key(&encode_key(&a)), Some(a));
+  }
+
+  #[test]
+  fn prefixes_match_exactly(a in CONTROL_STRING, p in "[\\x00\\x01a]{0,2}") {
+    let (a, p) = (string(&a), string(&p));
+    let starts_with = a.unwrap_string().starts_with(p.unwrap_string().as_str());
+    prop_assert_eq!(encode_key(&a).starts_with(&encode_key_prefix(&p)), starts_with);
+    prop_assert_eq!(encode_sort_key(&a).starts_with(&encode_sort_key_prefix(&p)), starts_with);
+  }
+
+  #[test]
+  fn sort_key_entries_split((a, b) in arb_primitive_value_pair()) {
+    let sort_key = encode_sort_key(&a);
+    let primary_key = encode_key(&b);
+    let mut entry = sort_key.to_vec();
+    entry.extend_from_slice(&primary_key);
+    prop_assert_eq!(
+      split_sort_key_entry(&entry),
+      Some((sort_key.as_slice(), primary_key.as_slice()))
+    );
+  }
+}
+
+/// Checks that the key encoding preserves the order of `values`, which must be sorted, and that
+/// the data keys of a set member never cover the data keys of another member.
+fn check_all_pairs(values: &[PrimitiveValue]) {
+  let keys = values.iter().map(|x| encode_key(x)).collect::<Vec<_>>();
+  for (i, a) in keys.iter().enumerate() {
+    let mut data_a = a.to_vec();
+    data_a.push(0x00);
+    for (j, b) in keys.iter().enumerate() {
+      assert_eq!(a.cmp(b), i.cmp(&j), "{} {}", values[i], values[j]);
+      let mut data_b = b.to_vec();
+      data_b.push(0x00);
+      assert_eq!(
+        data_b.starts_with(&data_a),
+        i == j,
+        "{} {}",
+        values[i],
+        values[j]
+      );
+    }
+  }
+}
+
+#[test]
+fn short_strings_exhaustively() {
+  let mut strings = all_strings(&['\0', '\x01', '\x02', 'a', '\u{ff}']);
+  strings.sort();
+  check_all_pairs(&strings.iter().map(|x| string(x)).collect::<Vec<_>>());
+}
+
+#[test]
+fn short_bytes_exhaustively() {
+  let mut values = all_strings(&['\0', '\x01', '\x7f'])
+    .into_iter()
+    .map(|x| x.into_bytes())
+    .collect::<Vec<_>>();
+  values.sort();
+  check_all_pairs(
+    &values
+      .into_iter()
+      .map(PrimitiveValue::Bytes)
+      .collect::<Vec<_>>(),
+  );
+}
+
+#[test]
+fn double_key_order() {
+  let ordered = [
+    f64::NEG_INFINITY,
+    f64::MIN,
+    -1.0,
+    -f64::MIN_POSITIVE,
+    0.0,
+    f64::MIN_POSITIVE,
+    1.0,
+    f64::MAX,
+    f64::INFINITY,
+    f64::NAN,
+  ];
+  for w in ordered.windows(2) {
+    assert!(
+      encode_key(&PrimitiveValue::Double(w[0].to_bits()))
+        < encode_key(&PrimitiveValue::Double(w[1].to_bits())),
+      "{} < {}",
+      w[0],
+      w[1]
+    );
+  }
+}
+
+#[test]
+fn version_1_compatibility() {
+  // Strings without 0x00 and 0x01 are encoded as in version 1.
+  assert_eq!(
+    encode_key(&string("ab\u{ff}")).as_slice(),
+    b"\x02ab\xc3\xbf"
+  );
+  assert_eq!(
+    encode_key(&string("a\0b\x01")).as_slice(),
+    b"\x02a\x01\x01b\x01\x02"
+  );
+  assert_eq!(decode_string_key(b"\x02a\x01\x01b"), Some("a\0b".into()));
+  assert_eq!(decode_string_key(b"\x02a\0b"), None);
+  assert_eq!(decode_string_key(b"\x02a\x01"), None);
+}
+
+#[test]
+fn prefix_successors() {
+  assert_eq!(successor_prefix(b"ab"), Some(b"ac".to_vec()));
+  assert_eq!(successor_prefix(b"a\xfe\xff"), Some(b"a\xff".to_vec()));
+  assert_eq!(successor_prefix(b"a\xff\xff"), Some(b"b".to_vec()));
+  assert_eq!(successor_prefix(b"\xff\xff"), None);
+  assert_eq!(successor_prefix(b""), None);
+
+  let prefix = encode_key_prefix(&string("ab"));
+  let end = successor_of_bounded_prefix(&prefix);
+  for s in &["ab", "abc", "ab\u{10ffff}"] {
+    let key = encode_key(&string(s));
+    assert!(
+      key.starts_with(&prefix) && key.as_slice() < end.as_slice(),
+      "{}",
+      s
+    );
+  }
+  assert!(encode_key(&string("ac")).as_slice() >= end.as_slice());
+}
+
+#[test]
+#[should_panic(expected = "prefix has no successor")]
+fn unbounded_prefix_has_no_successor() {
+  successor_of_bounded_prefix(b"\xff\xff");
+}
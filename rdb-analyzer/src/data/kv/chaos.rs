@@ -0,0 +1,278 @@
+use std::{
+  future::Future,
+  pin::Pin,
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use thiserror::Error;
+
+use super::{KeyValueStore, KvError, KvKeyIterator, KvTransaction};
+
+#[derive(Error, Debug)]
+pub enum ChaosError {
+  #[error("injected failure in `{0}`")]
+  Injected(&'static str),
+
+  #[error("scan truncated after {0} key(s)")]
+  ScanTruncated(usize),
+}
+
+/// A distribution of the latency added to an operation.
+#[derive(Copy, Clone, Debug)]
+pub enum LatencyDistribution {
+  Zero,
+  Constant(Duration),
+  Uniform {
+    min: Duration,
+    max: Duration,
+  },
+
+  /// `min` plus an exponentially distributed delay with mean `mean`. Models the long tail of
+  /// network round trips.
+  Exponential {
+    min: Duration,
+    mean: Duration,
+  },
+}
+
+impl Default for LatencyDistribution {
+  fn default() -> Self {
+    Self::Zero
+  }
+}
+
+impl LatencyDistribution {
+  fn sample<R: Rng>(&self, rng: &mut R) -> Duration {
+    match *self {
+      Self::Zero => Duration::from_secs(0),
+      Self::Constant(x) => x,
+      Self::Uniform { min, max } => {
+        if max <= min {
+          min
+        } else {
+          min + (max - min).mul_f64(rng.gen::<f64>())
+        }
+      }
+      Self::Exponential { min, mean } => {
+        let u: f64 = rng.gen();
+        min + mean.mul_f64(-(1.0 - u).ln())
+      }
+    }
+  }
+}
+
+/// The faults injected into one kind of operation.
+#[derive(Clone, Debug, Default)]
+pub struct OpFaults {
+  pub latency: LatencyDistribution,
+
+  /// Probability that the operation fails with `ChaosError::Injected` after its latency.
+  pub error_rate: f64,
+}
+
+/// Configuration of `ChaosKv`.
+#[derive(Clone, Debug, Default)]
+pub struct ChaosConfig {
+  pub seed: u64,
+
+  pub begin_transaction: OpFaults,
+  pub get: OpFaults,
+  pub put: OpFaults,
+  pub delete: OpFaults,
+  pub delete_range: OpFaults,
+  pub scan_keys: OpFaults,
+
+  /// Faults of each `KvKeyIterator::next` call on a scan.
+  pub scan_next: OpFaults,
+
+  /// Probability, per key scanned, that the scan is cut off before returning that key with
+  /// `ChaosError::ScanTruncated`.
+  pub scan_truncation_rate: f64,
+
+  /// Latency of commits. `error_rate` is the probability of failing with `KvError::Conflict`
+  /// without applying the writes.
+  pub commit: OpFaults,
+
+  /// Probability that a commit applies its writes but fails with `KvError::CommitStateUnknown`.
+  pub commit_state_unknown_rate: f64,
+}
+
+/// A `KeyValueStore` wrapper that adds latency and injects errors into the operations of its
+/// backend, to benchmark and test scripts under realistic network conditions.
+///
+/// Latencies are only added if a sleep function is set. All random choices are drawn from a
+/// single generator seeded with `ChaosConfig::seed`, so runs without concurrency are
+/// reproducible.
+pub struct ChaosKv<S> {
+  inner: S,
+  chaos: Arc<Chaos>,
+}
+
+struct Chaos {
+  config: ChaosConfig,
+  rng: Mutex<StdRng>,
+  sleep_fn: Option<fn(Duration) -> Pin<Box<dyn Future<Output = ()> + Send>>>,
+}
+
+struct ChaosTransaction {
+  inner: Box<dyn KvTransaction>,
+  chaos: Arc<Chaos>,
+}
+
+struct ChaosIterator {
+  inner: Box<dyn KvKeyIterator>,
+  chaos: Arc<Chaos>,
+  returned: usize,
+}
+
+impl<S: KeyValueStore> ChaosKv<S> {
+  pub fn new(inner: S, config: ChaosConfig) -> Self {
+    Self {
+      inner,
+      chaos: Arc::new(Chaos {
+        rng: Mutex::new(StdRng::seed_from_u64(config.seed)),
+        config,
+        sleep_fn: None,
+      }),
+    }
+  }
+
+  /// Sets the function used to wait for the simulated latencies.
+  ///
+  /// Must be called before any transaction is started.
+  pub fn set_sleep_fn(&mut self, f: fn(Duration) -> Pin<Box<dyn Future<Output = ()> + Send>>) {
+    Arc::get_mut(&mut self.chaos)
+      .expect("set_sleep_fn called with transactions in flight")
+      .sleep_fn = Some(f);
+  }
+
+  pub fn inner(&self) -> &S {
+    &self.inner
+  }
+}
+
+impl Chaos {
+  fn chance(&self, p: f64) -> bool {
+    p > 0.0 && self.rng.lock().unwrap().gen_bool(p.min(1.0))
+  }
+
+  /// Waits for the latency of an operation, and decides whether it fails.
+  async fn enter(&self, op: &'static str, faults: &OpFaults) -> Result<()> {
+    if let Some(f) = self.sleep_fn {
+      let latency = faults.latency.sample(&mut *self.rng.lock().unwrap());
+      if latency > Duration::from_secs(0) {
+        f(latency).await;
+      }
+    }
+    if self.chance(faults.error_rate) {
+      log::debug!("chaos: injected failure in `{}`", op);
+      return Err(ChaosError::Injected(op).into());
+    }
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl<S: KeyValueStore> KeyValueStore for ChaosKv<S> {
+  async fn begin_transaction(&self) -> Result<Box<dyn KvTransaction>> {
+    self
+      .chaos
+      .enter("begin_transaction", &self.chaos.config.begin_transaction)
+      .await?;
+    Ok(Box::new(ChaosTransaction {
+      inner: self.inner.begin_transaction().await?,
+      chaos: self.chaos.clone(),
+    }))
+  }
+
+  async fn begin_transaction_at(&self, version: i64) -> Result<Box<dyn KvTransaction>> {
+    self
+      .chaos
+      .enter("begin_transaction", &self.chaos.config.begin_transaction)
+      .await?;
+    Ok(Box::new(ChaosTransaction {
+      inner: self.inner.begin_transaction_at(version).await?,
+      chaos: self.chaos.clone(),
+    }))
+  }
+}
+
+#[async_trait]
+impl KvTransaction for ChaosTransaction {
+  async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    self.chaos.enter("get", &self.chaos.config.get).await?;
+    self.inner.get(key).await
+  }
+
+  async fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+    self.chaos.enter("put", &self.chaos.config.put).await?;
+    self.inner.put(key, value).await
+  }
+
+  async fn delete(&self, key: &[u8]) -> Result<()> {
+    self
+      .chaos
+      .enter("delete", &self.chaos.config.delete)
+      .await?;
+    self.inner.delete(key).await
+  }
+
+  async fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+    self
+      .chaos
+      .enter("delete_range", &self.chaos.config.delete_range)
+      .await?;
+    self.inner.delete_range(start, end).await
+  }
+
+  async fn scan_keys(&self, start: &[u8], end: &[u8]) -> Result<Box<dyn KvKeyIterator>> {
+    self
+      .chaos
+      .enter("scan_keys", &self.chaos.config.scan_keys)
+      .await?;
+    Ok(Box::new(ChaosIterator {
+      inner: self.inner.scan_keys(start, end).await?,
+      chaos: self.chaos.clone(),
+      returned: 0,
+    }))
+  }
+
+  async fn commit(self: Box<Self>) -> Result<(), KvError> {
+    let chaos = self.chaos;
+    if chaos.enter("commit", &chaos.config.commit).await.is_err() {
+      return Err(KvError::Conflict(Default::default()));
+    }
+    self.inner.commit().await?;
+    if chaos.chance(chaos.config.commit_state_unknown_rate) {
+      log::debug!("chaos: injected unknown commit state");
+      return Err(KvError::CommitStateUnknown);
+    }
+    Ok(())
+  }
+
+  async fn read_version(&self) -> Result<Option<i64>> {
+    self.inner.read_version().await
+  }
+}
+
+#[async_trait]
+impl KvKeyIterator for ChaosIterator {
+  async fn next(&mut self) -> Result<Option<Vec<u8>>> {
+    self
+      .chaos
+      .enter("scan_next", &self.chaos.config.scan_next)
+      .await?;
+    let key = self.inner.next().await?;
+    if key.is_some() {
+      if self.chaos.chance(self.chaos.config.scan_truncation_rate) {
+        return Err(ChaosError::ScanTruncated(self.returned).into());
+      }
+      self.returned += 1;
+    }
+    Ok(key)
+  }
+}
@@ -0,0 +1,93 @@
+use std::time::{Duration, Instant};
+
+use crate::data::{kv::KeyValueStore, mock_kv::MockKv};
+
+use super::{
+  chaos::{ChaosConfig, ChaosError, ChaosKv, LatencyDistribution, OpFaults},
+  KvError,
+};
+
+#[tokio::test]
+async fn chaos_passthrough() {
+  let kv = ChaosKv::new(MockKv::new(), ChaosConfig::default());
+  let txn = kv.begin_transaction().await.unwrap();
+  txn.put(b"a", b"1").await.unwrap();
+  txn.put(b"b", b"2").await.unwrap();
+  txn.commit().await.unwrap();
+
+  let txn = kv.begin_transaction().await.unwrap();
+  assert_eq!(txn.get(b"a").await.unwrap().unwrap(), b"1");
+  let mut it = txn.scan_keys(b"a", b"c").await.unwrap();
+  assert_eq!(it.next().await.unwrap().unwrap(), b"a");
+  assert_eq!(it.next().await.unwrap().unwrap(), b"b");
+  assert!(it.next().await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn chaos_latency() {
+  let mut kv = ChaosKv::new(
+    MockKv::new(),
+    ChaosConfig {
+      get: OpFaults {
+        latency: LatencyDistribution::Uniform {
+          min: Duration::from_millis(10),
+          max: Duration::from_millis(20),
+        },
+        error_rate: 0.0,
+      },
+      ..Default::default()
+    },
+  );
+  kv.set_sleep_fn(|x| Box::pin(tokio::time::sleep(x)));
+  let txn = kv.begin_transaction().await.unwrap();
+  let start = Instant::now();
+  for _ in 0..5 {
+    txn.get(b"a").await.unwrap();
+  }
+  assert!(start.elapsed() >= Duration::from_millis(50));
+}
+
+#[tokio::test]
+async fn chaos_errors() {
+  let kv = ChaosKv::new(
+    MockKv::new(),
+    ChaosConfig {
+      put: OpFaults {
+        error_rate: 1.0,
+        ..Default::default()
+      },
+      scan_truncation_rate: 1.0,
+      commit_state_unknown_rate: 1.0,
+      ..Default::default()
+    },
+  );
+  let txn = kv.begin_transaction().await.unwrap();
+  let e = txn.put(b"a", b"1").await.unwrap_err();
+  assert!(matches!(
+    e.downcast_ref::<ChaosError>(),
+    Some(ChaosError::Injected("put"))
+  ));
+
+  drop(txn);
+
+  let inner = kv.inner().begin_transaction().await.unwrap();
+  inner.put(b"a", b"1").await.unwrap();
+  inner.commit().await.unwrap();
+
+  let txn = kv.begin_transaction().await.unwrap();
+  let mut it = txn.scan_keys(b"a", b"b").await.unwrap();
+  let e = it.next().await.unwrap_err();
+  assert!(matches!(
+    e.downcast_ref::<ChaosError>(),
+    Some(ChaosError::ScanTruncated(0))
+  ));
+  txn.delete(b"a").await.unwrap();
+  assert!(matches!(
+    txn.commit().await,
+    Err(KvError::CommitStateUnknown)
+  ));
+
+  // Commits with an unknown state are still applied.
+  let txn = kv.inner().begin_transaction().await.unwrap();
+  assert!(txn.get(b"a").await.unwrap().is_none());
+}
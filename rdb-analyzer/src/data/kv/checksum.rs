@@ -0,0 +1,302 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use thiserror::Error;
+
+use super::{KeyValueStore, KvEntryIterator, KvError, KvKeyIterator, KvTransaction, ScanOptions};
+
+/// Starts every checksummed value. Msgpack values never start with `0xc1`, and the other formats
+/// that do (see `PrimitiveValue::encode_stored`) use a different second byte, so checksummed values
+/// can be told apart from values written without a checksum.
+const CHECKSUM_MARKER: [u8; 2] = [0xc1, 0xcc];
+
+/// Size of the header of a checksummed value: the marker and the checksum.
+const HEADER_SIZE: usize = CHECKSUM_MARKER.len() + 4;
+
+#[derive(Error, Debug)]
+pub enum ChecksumError {
+  #[error("checksum mismatch on key {0}: the stored value is corrupted")]
+  Mismatch(String),
+
+  #[error("value of key {0} was stored without a checksum")]
+  MissingChecksum(String),
+}
+
+/// A `KeyValueStore` wrapper that checksums the values of its backend, so that a value corrupted
+/// by the backend or by the disk fails the read with `ChecksumError::Mismatch` instead of being
+/// decoded into garbage.
+pub struct ChecksummedKv<S> {
+  inner: S,
+}
+
+struct ChecksummedTransaction {
+  inner: Box<dyn KvTransaction>,
+}
+
+struct VerifyingEntryIterator<'a> {
+  inner: Box<dyn KvEntryIterator + 'a>,
+  values: bool,
+}
+
+impl<S: KeyValueStore> ChecksummedKv<S> {
+  pub fn new(inner: S) -> Self {
+    Self { inner }
+  }
+
+  pub fn inner(&self) -> &S {
+    &self.inner
+  }
+}
+
+fn checksum(key: &[u8], value: &[u8]) -> u32 {
+  let mut hasher = crc32fast::Hasher::new();
+  hasher.update(&(key.len() as u32).to_be_bytes());
+  hasher.update(key);
+  hasher.update(value);
+  hasher.finalize()
+}
+
+/// Encodes `value` for storage under `key`, as `CHECKSUM_MARKER`, then a big-endian CRC-32 of the
+/// key and value, then the value.
+pub fn encode_checksummed(key: &[u8], value: &[u8]) -> Vec<u8> {
+  let mut out = Vec::with_capacity(HEADER_SIZE + value.len());
+  out.extend_from_slice(&CHECKSUM_MARKER);
+  out.extend_from_slice(&checksum(key, value).to_be_bytes());
+  out.extend_from_slice(value);
+  out
+}
+
+/// Verifies a value stored under `key` and strips its checksum.
+pub fn decode_checksummed(key: &[u8], mut stored: Vec<u8>) -> Result<Vec<u8>> {
+  if stored.len() < HEADER_SIZE || stored[..CHECKSUM_MARKER.len()] != CHECKSUM_MARKER {
+    return Err(ChecksumError::MissingChecksum(hex::encode(key)).into());
+  }
+  let mut expected = [0u8; 4];
+  expected.copy_from_slice(&stored[CHECKSUM_MARKER.len()..HEADER_SIZE]);
+  if checksum(key, &stored[HEADER_SIZE..]) != u32::from_be_bytes(expected) {
+    return Err(ChecksumError::Mismatch(hex::encode(key)).into());
+  }
+  stored.drain(..HEADER_SIZE);
+  Ok(stored)
+}
+
+#[async_trait]
+impl<S: KeyValueStore> KeyValueStore for ChecksummedKv<S> {
+  async fn begin_transaction(&self) -> Result<Box<dyn KvTransaction>> {
+    Ok(Box::new(ChecksummedTransaction {
+      inner: self.inner.begin_transaction().await?,
+    }))
+  }
+
+  async fn begin_transaction_at(&self, version: i64) -> Result<Box<dyn KvTransaction>> {
+    Ok(Box::new(ChecksummedTransaction {
+      inner: self.inner.begin_transaction_at(version).await?,
+    }))
+  }
+}
+
+#[async_trait]
+impl KvTransaction for ChecksummedTransaction {
+  async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    match self.inner.get(key).await? {
+      Some(x) => Ok(Some(decode_checksummed(key, x)?)),
+      None => Ok(None),
+    }
+  }
+
+  async fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+    self.inner.put(key, &encode_checksummed(key, value)).await
+  }
+
+  async fn delete(&self, key: &[u8]) -> Result<()> {
+    self.inner.delete(key).await
+  }
+
+  async fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+    self.inner.delete_range(start, end).await
+  }
+
+  async fn scan_keys(&self, start: &[u8], end: &[u8]) -> Result<Box<dyn KvKeyIterator>> {
+    self.inner.scan_keys(start, end).await
+  }
+
+  async fn scan<'a>(
+    &'a self,
+    start: &[u8],
+    end: &[u8],
+    options: &ScanOptions,
+  ) -> Result<Box<dyn KvEntryIterator + 'a>> {
+    Ok(Box::new(VerifyingEntryIterator {
+      inner: self.inner.scan(start, end, options).await?,
+      values: options.values,
+    }))
+  }
+
+  async fn commit(self: Box<Self>) -> Result<(), KvError> {
+    self.inner.commit().await
+  }
+
+  async fn read_version(&self) -> Result<Option<i64>> {
+    self.inner.read_version().await
+  }
+}
+
+#[async_trait]
+impl<'a> KvEntryIterator for VerifyingEntryIterator<'a> {
+  async fn next(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    match self.inner.next().await? {
+      Some((k, v)) if self.values => {
+        let v = decode_checksummed(&k, v)?;
+        Ok(Some((k, v)))
+      }
+      x => Ok(x),
+    }
+  }
+}
+
+/// What `scan_checksums` does with values whose checksum does not match.
+#[derive(Clone, Copy)]
+pub enum ChecksumRepair<'a> {
+  /// Only report them.
+  None,
+
+  /// Delete them, so that reads see the values as absent instead of failing.
+  Delete,
+
+  /// Replace them with the value of the same key in another store, e.g. a replica. Values the
+  /// other store does not have are only reported.
+  CopyFrom(&'a dyn KeyValueStore),
+}
+
+/// Options of `scan_checksums`.
+#[derive(Clone)]
+pub struct ChecksumScanOptions<'a> {
+  pub repair: ChecksumRepair<'a>,
+
+  /// Add checksums to the values stored without one, e.g. before checksums were enabled on the
+  /// store. Otherwise they are reported.
+  pub add_missing: bool,
+
+  /// Max number of values checked in one transaction.
+  pub batch_size: usize,
+}
+
+impl<'a> Default for ChecksumScanOptions<'a> {
+  fn default() -> Self {
+    Self {
+      repair: ChecksumRepair::None,
+      add_missing: false,
+      batch_size: 100,
+    }
+  }
+}
+
+/// A stored value that failed verification.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CorruptValue {
+  pub key: Vec<u8>,
+
+  /// Whether the value has no checksum, rather than a mismatching one.
+  pub missing_checksum: bool,
+}
+
+/// Result of `scan_checksums`.
+#[derive(Clone, Debug, Default)]
+pub struct ChecksumScanReport {
+  pub values_checked: u64,
+  pub corrupt_values: Vec<CorruptValue>,
+
+  /// Number of corrupt values repaired, or given a checksum with `add_missing`.
+  pub repaired: u64,
+}
+
+/// Verifies the checksums of the values in `[start, end)` of `backend`, the store wrapped by a
+/// `ChecksummedKv`. Each transaction checks at most `ChecksumScanOptions::batch_size` values, so
+/// the scan can run against a live store.
+pub async fn scan_checksums(
+  backend: &dyn KeyValueStore,
+  start: &[u8],
+  end: &[u8],
+  options: &ChecksumScanOptions<'_>,
+) -> Result<ChecksumScanReport> {
+  let batch_size = options.batch_size.max(1);
+  let mut report = ChecksumScanReport::default();
+  let mut cursor = start.to_vec();
+
+  loop {
+    let txn = backend.begin_transaction().await?;
+    let mut it = txn
+      .scan(
+        &cursor,
+        end,
+        &ScanOptions {
+          limit: Some(batch_size),
+          values: true,
+          ..Default::default()
+        },
+      )
+      .await?;
+    let mut batch = vec![];
+    while let Some(x) = it.next().await? {
+      batch.push(x);
+    }
+    drop(it);
+
+    for (k, v) in &batch {
+      report.values_checked += 1;
+      let e = match decode_checksummed(k, v.clone()) {
+        Ok(_) => continue,
+        Err(e) => e,
+      };
+      let missing_checksum = matches!(
+        e.downcast_ref::<ChecksumError>(),
+        Some(ChecksumError::MissingChecksum(_))
+      );
+      report.corrupt_values.push(CorruptValue {
+        key: k.clone(),
+        missing_checksum,
+      });
+      let repaired = if missing_checksum {
+        if options.add_missing {
+          txn.put(k, &encode_checksummed(k, v)).await?;
+        }
+        options.add_missing
+      } else {
+        repair(&*txn, k, options.repair).await?
+      };
+      if repaired {
+        report.repaired += 1;
+      }
+    }
+    txn.commit().await?;
+
+    match batch.last() {
+      Some((k, _)) if batch.len() == batch_size => {
+        // The smallest key after the last one checked.
+        cursor = k.clone();
+        cursor.push(0x00);
+      }
+      _ => break,
+    }
+  }
+  Ok(report)
+}
+
+async fn repair(txn: &dyn KvTransaction, key: &[u8], mode: ChecksumRepair<'_>) -> Result<bool> {
+  match mode {
+    ChecksumRepair::None => Ok(false),
+    ChecksumRepair::Delete => {
+      txn.delete(key).await?;
+      Ok(true)
+    }
+    ChecksumRepair::CopyFrom(source) => {
+      let value = source.begin_transaction().await?.get(key).await?;
+      match value {
+        Some(x) => {
+          txn.put(key, &encode_checksummed(key, &x)).await?;
+          Ok(true)
+        }
+        None => Ok(false),
+      }
+    }
+  }
+}
@@ -0,0 +1,164 @@
+use crate::data::{
+  kv::{KeyValueStore, ScanOptions},
+  mock_kv::MockKv,
+};
+
+use super::checksum::{
+  encode_checksummed, scan_checksums, ChecksumError, ChecksumRepair, ChecksumScanOptions,
+  ChecksummedKv, CorruptValue,
+};
+
+async fn put(kv: &dyn KeyValueStore, key: &[u8], value: &[u8]) {
+  let txn = kv.begin_transaction().await.unwrap();
+  txn.put(key, value).await.unwrap();
+  txn.commit().await.unwrap();
+}
+
+async fn get(kv: &dyn KeyValueStore, key: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+  let txn = kv.begin_transaction().await.unwrap();
+  txn.get(key).await
+}
+
+fn corrupt(key: &[u8], missing_checksum: bool) -> CorruptValue {
+  CorruptValue {
+    key: key.to_vec(),
+    missing_checksum,
+  }
+}
+
+#[tokio::test]
+async fn checksummed_roundtrip() {
+  let kv = ChecksummedKv::new(MockKv::new());
+  put(&kv, b"a", b"x").await;
+  put(&kv, b"b", b"").await;
+
+  assert_eq!(get(&kv, b"a").await.unwrap().unwrap(), b"x");
+  assert_eq!(get(&kv, b"b").await.unwrap().unwrap(), b"");
+  assert!(get(&kv, b"c").await.unwrap().is_none());
+  let dump = kv.inner().dump().await;
+  assert_eq!(dump[0].1, encode_checksummed(b"a", b"x"));
+
+  let txn = kv.begin_transaction().await.unwrap();
+  let mut it = txn
+    .scan(
+      b"a",
+      b"z",
+      &ScanOptions {
+        values: true,
+        ..Default::default()
+      },
+    )
+    .await
+    .unwrap();
+  assert_eq!(
+    it.next().await.unwrap().unwrap(),
+    (b"a".to_vec(), b"x".to_vec())
+  );
+  assert_eq!(it.next().await.unwrap().unwrap(), (b"b".to_vec(), vec![]));
+  assert!(it.next().await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn checksummed_corruption_fails() {
+  let kv = ChecksummedKv::new(MockKv::new());
+  put(&kv, b"a", b"hello").await;
+  let stored = kv.inner().dump().await[0].1.clone();
+
+  // A flipped bit in the value.
+  let mut flipped = stored.clone();
+  *flipped.last_mut().unwrap() ^= 1;
+  put(kv.inner(), b"a", &flipped).await;
+
+  // A valid value moved to another key.
+  put(kv.inner(), b"b", &stored).await;
+
+  for k in &[b"a", b"b"] {
+    let e = get(&kv, *k).await.unwrap_err();
+    assert!(matches!(
+      e.downcast_ref::<ChecksumError>(),
+      Some(ChecksumError::Mismatch(_))
+    ));
+  }
+
+  // A value written without the wrapper.
+  put(kv.inner(), b"c", b"hello").await;
+  let e = get(&kv, b"c").await.unwrap_err();
+  assert!(matches!(
+    e.downcast_ref::<ChecksumError>(),
+    Some(ChecksumError::MissingChecksum(_))
+  ));
+}
+
+#[tokio::test]
+async fn scan_and_repair() {
+  let _ = pretty_env_logger::try_init();
+  let kv = ChecksummedKv::new(MockKv::new());
+  let replica = MockKv::new();
+  for k in &[b"k0", b"k1", b"k2", b"k3", b"k4"] {
+    put(&kv, *k, b"value").await;
+    put(&replica, *k, b"value").await;
+  }
+  let mut flipped = encode_checksummed(b"k1", b"value");
+  flipped[6] ^= 1;
+  put(kv.inner(), b"k1", &flipped).await;
+  put(kv.inner(), b"k3", &flipped).await;
+  put(kv.inner(), b"k4", b"legacy").await;
+  let scan = |repair, add_missing| ChecksumScanOptions {
+    repair,
+    add_missing,
+    batch_size: 2,
+  };
+
+  let report = scan_checksums(kv.inner(), b"", b"\xff", &scan(ChecksumRepair::None, false))
+    .await
+    .unwrap();
+  assert_eq!(report.values_checked, 5);
+  assert_eq!(
+    report.corrupt_values,
+    vec![
+      corrupt(b"k1", false),
+      corrupt(b"k3", false),
+      corrupt(b"k4", true)
+    ]
+  );
+  assert_eq!(report.repaired, 0);
+
+  // Only the range is scanned.
+  let report = scan_checksums(kv.inner(), b"k2", b"k4", &scan(ChecksumRepair::None, false))
+    .await
+    .unwrap();
+  assert_eq!(report.values_checked, 2);
+  assert_eq!(report.corrupt_values, vec![corrupt(b"k3", false)]);
+
+  let report = scan_checksums(
+    kv.inner(),
+    b"",
+    b"\xff",
+    &scan(ChecksumRepair::CopyFrom(&replica), true),
+  )
+  .await
+  .unwrap();
+  assert_eq!(report.corrupt_values.len(), 3);
+  assert_eq!(report.repaired, 3);
+  assert_eq!(get(&kv, b"k1").await.unwrap().unwrap(), b"value");
+  assert_eq!(get(&kv, b"k4").await.unwrap().unwrap(), b"legacy");
+
+  put(kv.inner(), b"k2", &flipped).await;
+  let report = scan_checksums(
+    kv.inner(),
+    b"",
+    b"\xff",
+    &scan(ChecksumRepair::Delete, false),
+  )
+  .await
+  .unwrap();
+  assert_eq!(report.corrupt_values, vec![corrupt(b"k2", false)]);
+  assert_eq!(report.repaired, 1);
+  assert!(get(&kv, b"k2").await.unwrap().is_none());
+
+  let report = scan_checksums(kv.inner(), b"", b"\xff", &Default::default())
+    .await
+    .unwrap();
+  assert_eq!(report.values_checked, 4);
+  assert!(report.corrupt_values.is_empty());
+}
@@ -0,0 +1,303 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use aes_gcm_siv::{
+  aead::{Aead, NewAead, Payload},
+  Aes256GcmSiv, Key, Nonce,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use rand::Rng;
+use thiserror::Error;
+
+use super::{KeyValueStore, KvEntryIterator, KvError, KvKeyIterator, KvTransaction, ScanOptions};
+
+/// Version byte of encrypted values.
+const VALUE_FORMAT_V1: u8 = 0x01;
+
+const NONCE_SIZE: usize = 12;
+
+/// Size of the header of an encrypted value: the format byte, the key id and the nonce.
+const VALUE_HEADER_SIZE: usize = 1 + 4 + NONCE_SIZE;
+
+pub type EncryptionKey = [u8; 32];
+
+#[derive(Error, Debug)]
+pub enum EncryptionError {
+  #[error("encryption key {0} not found")]
+  KeyNotFound(u32),
+
+  #[error("encryption failed")]
+  EncryptFailed,
+
+  #[error("decryption failed: the value is corrupted, or was not written under this key")]
+  DecryptFailed,
+
+  #[error("range operations are not supported when keys are encrypted")]
+  RangeOnEncryptedKeys,
+}
+
+/// Source of the keys used by `EncryptedKv`.
+///
+/// Every key is identified by a key id that is stored with the values it encrypted, so that keys
+/// can be rotated: new values are written under the current key, and old values stay readable as
+/// long as the provider still returns their key.
+pub trait KeyProvider: Send + Sync {
+  /// The id of the key that new values are encrypted with.
+  fn current_key_id(&self) -> u32;
+
+  fn key(&self, key_id: u32) -> Option<EncryptionKey>;
+}
+
+/// A `KeyProvider` with a fixed set of keys.
+pub struct StaticKeyProvider {
+  current: u32,
+  keys: BTreeMap<u32, EncryptionKey>,
+}
+
+impl StaticKeyProvider {
+  pub fn new(key_id: u32, key: EncryptionKey) -> Self {
+    let mut keys = BTreeMap::new();
+    keys.insert(key_id, key);
+    Self {
+      current: key_id,
+      keys,
+    }
+  }
+
+  /// Adds a key that is only used to decrypt values written before a rotation.
+  pub fn with_key(mut self, key_id: u32, key: EncryptionKey) -> Self {
+    self.keys.insert(key_id, key);
+    self
+  }
+}
+
+impl KeyProvider for StaticKeyProvider {
+  fn current_key_id(&self) -> u32 {
+    self.current
+  }
+
+  fn key(&self, key_id: u32) -> Option<EncryptionKey> {
+    self.keys.get(&key_id).copied()
+  }
+}
+
+/// A `KeyValueStore` wrapper that encrypts the values of its backend with AES-256-GCM-SIV.
+///
+/// Each value is encrypted with a random nonce, and authenticated together with its key so that
+/// values cannot be moved between keys. An encrypted value is laid out as:
+///
+/// ```text
+/// 0x01 | key id (u32, big endian) | nonce (12 bytes) | ciphertext
+/// ```
+///
+/// Keys are stored in plaintext by default, which keeps scans and range deletes working. With
+/// `with_key_encryption`, keys are encrypted too, deterministically so that point gets still
+/// find them. Encrypted keys lose their order, so scans and range deletes fail with
+/// `EncryptionError::RangeOnEncryptedKeys`, and only workloads built on point operations can use
+/// this mode.
+pub struct EncryptedKv<S> {
+  inner: S,
+  cipher: Arc<Cipher>,
+}
+
+struct Cipher {
+  provider: Arc<dyn KeyProvider>,
+
+  /// The id of the key that keys are encrypted with, if keys are encrypted. Deterministic
+  /// encryption cannot rotate keys without rewriting the store, so this is fixed.
+  key_encryption: Option<u32>,
+}
+
+struct EncryptedTransaction {
+  inner: Box<dyn KvTransaction>,
+  cipher: Arc<Cipher>,
+}
+
+struct DecryptingEntryIterator<'a> {
+  inner: Box<dyn KvEntryIterator + 'a>,
+  cipher: Arc<Cipher>,
+  values: bool,
+}
+
+impl<S: KeyValueStore> EncryptedKv<S> {
+  pub fn new(inner: S, provider: Arc<dyn KeyProvider>) -> Self {
+    Self {
+      inner,
+      cipher: Arc::new(Cipher {
+        provider,
+        key_encryption: None,
+      }),
+    }
+  }
+
+  /// Also encrypts keys, with the key `key_id` of the provider.
+  pub fn with_key_encryption(self, key_id: u32) -> Self {
+    Self {
+      inner: self.inner,
+      cipher: Arc::new(Cipher {
+        provider: self.cipher.provider.clone(),
+        key_encryption: Some(key_id),
+      }),
+    }
+  }
+
+  pub fn inner(&self) -> &S {
+    &self.inner
+  }
+}
+
+impl Cipher {
+  fn aead(&self, key_id: u32) -> Result<Aes256GcmSiv> {
+    let key = self
+      .provider
+      .key(key_id)
+      .ok_or(EncryptionError::KeyNotFound(key_id))?;
+    Ok(Aes256GcmSiv::new(Key::from_slice(&key)))
+  }
+
+  /// The key stored in the backend for `key`.
+  fn encrypt_key(&self, key: &[u8]) -> Result<Vec<u8>> {
+    match self.key_encryption {
+      Some(key_id) => Ok(
+        self
+          .aead(key_id)?
+          .encrypt(Nonce::from_slice(&[0u8; NONCE_SIZE]), key)
+          .map_err(|_| EncryptionError::EncryptFailed)?,
+      ),
+      None => Ok(key.to_vec()),
+    }
+  }
+
+  fn encrypt_value(&self, key: &[u8], value: &[u8]) -> Result<Vec<u8>> {
+    let key_id = self.provider.current_key_id();
+    let mut nonce = [0u8; NONCE_SIZE];
+    rand::thread_rng().fill(&mut nonce);
+    let ciphertext = self
+      .aead(key_id)?
+      .encrypt(
+        Nonce::from_slice(&nonce),
+        Payload {
+          msg: value,
+          aad: key,
+        },
+      )
+      .map_err(|_| EncryptionError::EncryptFailed)?;
+
+    let mut out = Vec::with_capacity(VALUE_HEADER_SIZE + ciphertext.len());
+    out.push(VALUE_FORMAT_V1);
+    out.extend_from_slice(&key_id.to_be_bytes());
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(&ciphertext);
+    Ok(out)
+  }
+
+  /// Decrypts a value stored under the plaintext key `key`.
+  fn decrypt_value(&self, key: &[u8], value: &[u8]) -> Result<Vec<u8>> {
+    if value.len() < VALUE_HEADER_SIZE || value[0] != VALUE_FORMAT_V1 {
+      return Err(EncryptionError::DecryptFailed.into());
+    }
+    let mut key_id = [0u8; 4];
+    key_id.copy_from_slice(&value[1..5]);
+    let key_id = u32::from_be_bytes(key_id);
+    Ok(
+      self
+        .aead(key_id)?
+        .decrypt(
+          Nonce::from_slice(&value[5..VALUE_HEADER_SIZE]),
+          Payload {
+            msg: &value[VALUE_HEADER_SIZE..],
+            aad: key,
+          },
+        )
+        .map_err(|_| EncryptionError::DecryptFailed)?,
+    )
+  }
+
+  fn check_range(&self) -> Result<()> {
+    if self.key_encryption.is_some() {
+      return Err(EncryptionError::RangeOnEncryptedKeys.into());
+    }
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl<S: KeyValueStore> KeyValueStore for EncryptedKv<S> {
+  async fn begin_transaction(&self) -> Result<Box<dyn KvTransaction>> {
+    Ok(Box::new(EncryptedTransaction {
+      inner: self.inner.begin_transaction().await?,
+      cipher: self.cipher.clone(),
+    }))
+  }
+
+  async fn begin_transaction_at(&self, version: i64) -> Result<Box<dyn KvTransaction>> {
+    Ok(Box::new(EncryptedTransaction {
+      inner: self.inner.begin_transaction_at(version).await?,
+      cipher: self.cipher.clone(),
+    }))
+  }
+}
+
+#[async_trait]
+impl KvTransaction for EncryptedTransaction {
+  async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    match self.inner.get(&self.cipher.encrypt_key(key)?).await? {
+      Some(x) => Ok(Some(self.cipher.decrypt_value(key, &x)?)),
+      None => Ok(None),
+    }
+  }
+
+  async fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+    let value = self.cipher.encrypt_value(key, value)?;
+    self.inner.put(&self.cipher.encrypt_key(key)?, &value).await
+  }
+
+  async fn delete(&self, key: &[u8]) -> Result<()> {
+    self.inner.delete(&self.cipher.encrypt_key(key)?).await
+  }
+
+  async fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+    self.cipher.check_range()?;
+    self.inner.delete_range(start, end).await
+  }
+
+  async fn scan_keys(&self, start: &[u8], end: &[u8]) -> Result<Box<dyn KvKeyIterator>> {
+    self.cipher.check_range()?;
+    self.inner.scan_keys(start, end).await
+  }
+
+  async fn scan<'a>(
+    &'a self,
+    start: &[u8],
+    end: &[u8],
+    options: &ScanOptions,
+  ) -> Result<Box<dyn KvEntryIterator + 'a>> {
+    self.cipher.check_range()?;
+    Ok(Box::new(DecryptingEntryIterator {
+      inner: self.inner.scan(start, end, options).await?,
+      cipher: self.cipher.clone(),
+      values: options.values,
+    }))
+  }
+
+  async fn commit(self: Box<Self>) -> Result<(), KvError> {
+    self.inner.commit().await
+  }
+
+  async fn read_version(&self) -> Result<Option<i64>> {
+    self.inner.read_version().await
+  }
+}
+
+#[async_trait]
+impl<'a> KvEntryIterator for DecryptingEntryIterator<'a> {
+  async fn next(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    match self.inner.next().await? {
+      Some((k, v)) if self.values => {
+        let v = self.cipher.decrypt_value(&k, &v)?;
+        Ok(Some((k, v)))
+      }
+      x => Ok(x),
+    }
+  }
+}
@@ -0,0 +1,212 @@
+use std::sync::{
+  atomic::{AtomicU32, Ordering},
+  Arc,
+};
+
+use crate::data::{
+  kv::{KeyValueStore, ScanOptions},
+  mock_kv::MockKv,
+};
+
+use super::encrypted::{
+  EncryptedKv, EncryptionError, EncryptionKey, KeyProvider, StaticKeyProvider,
+};
+
+const KEY_1: EncryptionKey = [1u8; 32];
+const KEY_2: EncryptionKey = [2u8; 32];
+
+/// A provider whose current key can be switched while the store is in use.
+struct RotatingKeyProvider {
+  current: AtomicU32,
+}
+
+impl KeyProvider for RotatingKeyProvider {
+  fn current_key_id(&self) -> u32 {
+    self.current.load(Ordering::SeqCst)
+  }
+
+  fn key(&self, key_id: u32) -> Option<EncryptionKey> {
+    match key_id {
+      1 => Some(KEY_1),
+      2 => Some(KEY_2),
+      _ => None,
+    }
+  }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+  haystack.windows(needle.len()).any(|x| x == needle)
+}
+
+async fn put(kv: &dyn KeyValueStore, key: &[u8], value: &[u8]) {
+  let txn = kv.begin_transaction().await.unwrap();
+  txn.put(key, value).await.unwrap();
+  txn.commit().await.unwrap();
+}
+
+/// A copy of the raw entries of `kv`, to open under another key provider.
+async fn copy_of(kv: &MockKv) -> MockKv {
+  let out = MockKv::new();
+  for (k, v) in kv.dump().await {
+    put(&out, &k, &v).await;
+  }
+  out
+}
+
+async fn get(kv: &dyn KeyValueStore, key: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+  let txn = kv.begin_transaction().await.unwrap();
+  txn.get(key).await
+}
+
+#[tokio::test]
+async fn encrypted_roundtrip() {
+  let kv = EncryptedKv::new(MockKv::new(), Arc::new(StaticKeyProvider::new(1, KEY_1)));
+  put(&kv, b"a", b"secret-1").await;
+  put(&kv, b"b", b"secret-2").await;
+  put(&kv, b"c", b"").await;
+
+  assert_eq!(get(&kv, b"a").await.unwrap().unwrap(), b"secret-1");
+  assert_eq!(get(&kv, b"c").await.unwrap().unwrap(), b"");
+  assert!(get(&kv, b"d").await.unwrap().is_none());
+
+  let dump = kv.inner().dump().await;
+  assert_eq!(dump.len(), 3);
+  for (_, v) in &dump {
+    assert!(!contains(v, b"secret"));
+  }
+
+  let txn = kv.begin_transaction().await.unwrap();
+  let mut it = txn
+    .scan(
+      b"a",
+      b"z",
+      &ScanOptions {
+        values: true,
+        reverse: true,
+        ..Default::default()
+      },
+    )
+    .await
+    .unwrap();
+  assert_eq!(it.next().await.unwrap().unwrap(), (b"c".to_vec(), vec![]));
+  assert_eq!(
+    it.next().await.unwrap().unwrap(),
+    (b"b".to_vec(), b"secret-2".to_vec())
+  );
+  assert_eq!(
+    it.next().await.unwrap().unwrap(),
+    (b"a".to_vec(), b"secret-1".to_vec())
+  );
+  assert!(it.next().await.unwrap().is_none());
+  drop(it);
+
+  txn.delete_range(b"a", b"c").await.unwrap();
+  txn.commit().await.unwrap();
+  assert_eq!(kv.inner().key_count().await, 1);
+}
+
+#[tokio::test]
+async fn encrypted_key_rotation() {
+  let provider = Arc::new(RotatingKeyProvider {
+    current: AtomicU32::new(1),
+  });
+  let kv = EncryptedKv::new(MockKv::new(), provider.clone());
+  put(&kv, b"old", b"x").await;
+  provider.current.store(2, Ordering::SeqCst);
+  put(&kv, b"new", b"y").await;
+
+  assert_eq!(get(&kv, b"old").await.unwrap().unwrap(), b"x");
+  assert_eq!(get(&kv, b"new").await.unwrap().unwrap(), b"y");
+  let dump = kv.inner().dump().await;
+  assert_eq!(dump[0].0, b"new");
+  assert_eq!(dump[0].1[1..5], 2u32.to_be_bytes());
+  assert_eq!(dump[1].1[1..5], 1u32.to_be_bytes());
+
+  // Values under a key the provider no longer has are unreadable.
+  let retired = EncryptedKv::new(
+    copy_of(kv.inner()).await,
+    Arc::new(StaticKeyProvider::new(2, KEY_2)),
+  );
+  assert_eq!(get(&retired, b"new").await.unwrap().unwrap(), b"y");
+  let e = get(&retired, b"old").await.unwrap_err();
+  assert!(matches!(
+    e.downcast_ref::<EncryptionError>(),
+    Some(EncryptionError::KeyNotFound(1))
+  ));
+}
+
+#[tokio::test]
+async fn encrypted_tampering_fails() {
+  let kv = EncryptedKv::new(MockKv::new(), Arc::new(StaticKeyProvider::new(1, KEY_1)));
+  put(&kv, b"a", b"x").await;
+  put(&kv, b"b", b"y").await;
+  let dump = kv.inner().dump().await;
+
+  // A flipped bit in the ciphertext.
+  let mut tampered = dump[0].1.clone();
+  *tampered.last_mut().unwrap() ^= 1;
+  put(kv.inner(), b"a", &tampered).await;
+
+  // A valid ciphertext moved to another key.
+  put(kv.inner(), b"b", &dump[0].1).await;
+
+  // A plaintext value.
+  put(kv.inner(), b"c", b"z").await;
+
+  for k in &[b"a", b"b", b"c"] {
+    let e = get(&kv, *k).await.unwrap_err();
+    assert!(matches!(
+      e.downcast_ref::<EncryptionError>(),
+      Some(EncryptionError::DecryptFailed)
+    ));
+  }
+
+  // Under the wrong key.
+  put(&kv, b"d", b"w").await;
+  let other = EncryptedKv::new(
+    copy_of(kv.inner()).await,
+    Arc::new(StaticKeyProvider::new(1, KEY_2)),
+  );
+  let e = get(&other, b"d").await.unwrap_err();
+  assert!(matches!(
+    e.downcast_ref::<EncryptionError>(),
+    Some(EncryptionError::DecryptFailed)
+  ));
+}
+
+#[tokio::test]
+async fn encrypted_keys() {
+  let kv = EncryptedKv::new(MockKv::new(), Arc::new(StaticKeyProvider::new(1, KEY_1)))
+    .with_key_encryption(1);
+  put(&kv, b"secret-key", b"secret-value").await;
+  put(&kv, b"secret-key", b"secret-value-2").await;
+
+  assert_eq!(
+    get(&kv, b"secret-key").await.unwrap().unwrap(),
+    b"secret-value-2"
+  );
+  assert!(get(&kv, b"secret-other").await.unwrap().is_none());
+  let dump = kv.inner().dump().await;
+  assert_eq!(dump.len(), 1);
+  assert!(!contains(&dump[0].0, b"secret"));
+  assert!(!contains(&dump[0].1, b"secret"));
+
+  let txn = kv.begin_transaction().await.unwrap();
+  for e in vec![
+    txn.scan_keys(b"a", b"z").await.err().unwrap(),
+    txn
+      .scan(b"a", b"z", &Default::default())
+      .await
+      .err()
+      .unwrap(),
+    txn.delete_range(b"a", b"z").await.unwrap_err(),
+  ] {
+    assert!(matches!(
+      e.downcast_ref::<EncryptionError>(),
+      Some(EncryptionError::RangeOnEncryptedKeys)
+    ));
+  }
+  txn.delete(b"secret-key").await.unwrap();
+  txn.commit().await.unwrap();
+  assert_eq!(kv.inner().key_count().await, 0);
+}
@@ -0,0 +1,234 @@
+pub mod chaos;
+pub mod checksum;
+pub mod encrypted;
+
+#[cfg(test)]
+mod chaos_test;
+
+#[cfg(test)]
+mod checksum_test;
+
+#[cfg(test)]
+mod encrypted_test;
+
+#[cfg(test)]
+mod scan_test;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use thiserror::Error;
+
+#[async_trait]
+pub trait KeyValueStore: Send + Sync {
+  async fn begin_transaction(&self) -> Result<Box<dyn KvTransaction>>;
+
+  /// Begins a transaction that reads the store as of `version`, a read version previously
+  /// returned by `KvTransaction::read_version`.
+  ///
+  /// Fails with `KvError::SnapshotUnsupported` on backends that do not keep old versions.
+  async fn begin_transaction_at(&self, _version: i64) -> Result<Box<dyn KvTransaction>> {
+    Err(KvError::SnapshotUnsupported.into())
+  }
+}
+
+#[async_trait]
+impl<T: KeyValueStore + ?Sized> KeyValueStore for Box<T> {
+  async fn begin_transaction(&self) -> Result<Box<dyn KvTransaction>> {
+    (**self).begin_transaction().await
+  }
+
+  async fn begin_transaction_at(&self, version: i64) -> Result<Box<dyn KvTransaction>> {
+    (**self).begin_transaction_at(version).await
+  }
+}
+
+#[async_trait]
+pub trait KvTransaction: Send + Sync {
+  async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+  async fn put(&self, key: &[u8], value: &[u8]) -> Result<()>;
+  async fn delete(&self, key: &[u8]) -> Result<()>;
+  async fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()>;
+  async fn scan_keys(&self, start: &[u8], end: &[u8]) -> Result<Box<dyn KvKeyIterator>>;
+  async fn commit(self: Box<Self>) -> Result<(), KvError>;
+
+  /// The version of the store this transaction reads, or `None` if the backend does not support
+  /// snapshot reads.
+  async fn read_version(&self) -> Result<Option<i64>> {
+    Ok(None)
+  }
+
+  /// Scans the entries in `[start, end)`.
+  ///
+  /// The default implementation is built on `scan_keys` and `get`, and reads the whole key range
+  /// upfront for reverse scans. Backends that can return values with keys, or scan backwards,
+  /// should override it.
+  async fn scan<'a>(
+    &'a self,
+    start: &[u8],
+    end: &[u8],
+    options: &ScanOptions,
+  ) -> Result<Box<dyn KvEntryIterator + 'a>> {
+    let mut keys = self.scan_keys(start, end).await?;
+    let pending = if options.reverse {
+      let mut pending = vec![];
+      while let Some(k) = keys.next().await? {
+        pending.push(k);
+      }
+      Some(pending)
+    } else {
+      None
+    };
+    Ok(Box::new(KeyScanEntryIterator {
+      txn: self,
+      keys,
+      pending,
+      values: options.values,
+      budget: ScanBudget::new(options),
+    }))
+  }
+}
+
+#[async_trait]
+pub trait KvKeyIterator: Send + Sync {
+  async fn next(&mut self) -> Result<Option<Vec<u8>>>;
+}
+
+#[async_trait]
+pub trait KvEntryIterator: Send + Sync {
+  async fn next(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>>;
+}
+
+/// Options of `KvTransaction::scan`.
+#[derive(Clone, Debug, Default)]
+pub struct ScanOptions {
+  /// Max number of entries returned.
+  pub limit: Option<usize>,
+
+  /// Stop after the returned keys and values add up to at least this many bytes. The entry that
+  /// crosses the limit is still returned, so a scan always makes progress.
+  pub byte_limit: Option<usize>,
+
+  /// Whether to return values. Otherwise, entries are returned with empty values.
+  pub values: bool,
+
+  /// Scan in descending key order.
+  pub reverse: bool,
+}
+
+/// Tracks the row and byte limits of a scan.
+#[derive(Clone, Debug)]
+pub struct ScanBudget {
+  rows: Option<usize>,
+  bytes: Option<usize>,
+}
+
+impl ScanBudget {
+  pub fn new(options: &ScanOptions) -> Self {
+    Self {
+      rows: options.limit,
+      bytes: options.byte_limit,
+    }
+  }
+
+  /// Whether the scan has reached one of its limits.
+  pub fn exhausted(&self) -> bool {
+    self.rows == Some(0) || self.bytes == Some(0)
+  }
+
+  /// Charges an entry of `size` bytes to the budget.
+  pub fn charge(&mut self, size: usize) {
+    if let Some(x) = &mut self.rows {
+      *x = x.saturating_sub(1);
+    }
+    if let Some(x) = &mut self.bytes {
+      *x = x.saturating_sub(size);
+    }
+  }
+}
+
+struct KeyScanEntryIterator<'a, T: ?Sized> {
+  txn: &'a T,
+  keys: Box<dyn KvKeyIterator>,
+
+  /// Keys read upfront for a reverse scan, in ascending order.
+  pending: Option<Vec<Vec<u8>>>,
+  values: bool,
+  budget: ScanBudget,
+}
+
+#[async_trait]
+impl<'a, T: KvTransaction + ?Sized> KvEntryIterator for KeyScanEntryIterator<'a, T> {
+  async fn next(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    loop {
+      if self.budget.exhausted() {
+        return Ok(None);
+      }
+      let key = match &mut self.pending {
+        Some(x) => x.pop(),
+        None => self.keys.next().await?,
+      };
+      let key = match key {
+        Some(x) => x,
+        None => return Ok(None),
+      };
+      let value = if self.values {
+        match self.txn.get(&key).await? {
+          Some(x) => x,
+          // Deleted since the key was scanned.
+          None => continue,
+        }
+      } else {
+        vec![]
+      };
+      self.budget.charge(key.len() + value.len());
+      return Ok(Some((key, value)));
+    }
+  }
+}
+
+#[derive(Error, Debug)]
+pub enum KvError {
+  #[error("conflict")]
+  Conflict(ConflictInfo),
+
+  #[error("commit state unknown")]
+  CommitStateUnknown,
+
+  #[error("snapshot reads are not supported by this backend")]
+  SnapshotUnsupported,
+
+  #[error("version {0} is not available for snapshot reads")]
+  SnapshotUnavailable(i64),
+}
+
+/// Details of a commit conflict, as far as the backend can tell.
+#[derive(Clone, Debug, Default)]
+pub struct ConflictInfo {
+  /// Key ranges `[start, end)` that conflicted with other transactions. Empty if the backend
+  /// does not report them.
+  pub ranges: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl ConflictInfo {
+  /// Conflict details for a set of single keys.
+  pub fn from_keys<'a>(keys: impl IntoIterator<Item = &'a [u8]>) -> Self {
+    Self {
+      ranges: keys
+        .into_iter()
+        .map(|k| {
+          let mut end = k.to_vec();
+          end.push(0x00);
+          (k.to_vec(), end)
+        })
+        .collect(),
+    }
+  }
+
+  /// Whether any conflicting range overlaps `[start, end)`.
+  pub fn overlaps(&self, start: &[u8], end: &[u8]) -> bool {
+    self
+      .ranges
+      .iter()
+      .any(|(s, e)| s.as_slice() < end && start < e.as_slice())
+  }
+}
@@ -0,0 +1,118 @@
+use crate::data::mock_kv::MockKv;
+
+use super::{
+  chaos::{ChaosConfig, ChaosKv},
+  KeyValueStore, KvTransaction, ScanOptions,
+};
+
+async fn populate(kv: &dyn KeyValueStore) {
+  let txn = kv.begin_transaction().await.unwrap();
+  for (k, v) in &[("a", "1"), ("b", "22"), ("c", "333"), ("d", "4444")] {
+    txn.put(k.as_bytes(), v.as_bytes()).await.unwrap();
+  }
+  txn.delete(b"c").await.unwrap();
+  txn.commit().await.unwrap();
+}
+
+async fn scan_all(
+  txn: &dyn KvTransaction,
+  start: &[u8],
+  end: &[u8],
+  options: &ScanOptions,
+) -> Vec<(String, String)> {
+  let mut it = txn.scan(start, end, options).await.unwrap();
+  let mut out = vec![];
+  while let Some((k, v)) = it.next().await.unwrap() {
+    out.push((String::from_utf8(k).unwrap(), String::from_utf8(v).unwrap()));
+  }
+  out
+}
+
+fn pairs(x: &[(&str, &str)]) -> Vec<(String, String)> {
+  x.iter()
+    .map(|(k, v)| (k.to_string(), v.to_string()))
+    .collect()
+}
+
+async fn check_scan(kv: &dyn KeyValueStore) {
+  populate(kv).await;
+  let txn = kv.begin_transaction().await.unwrap();
+  let txn = &*txn;
+
+  let values = ScanOptions {
+    values: true,
+    ..Default::default()
+  };
+  assert_eq!(
+    scan_all(txn, b"a", b"z", &values).await,
+    pairs(&[("a", "1"), ("b", "22"), ("d", "4444")])
+  );
+  assert_eq!(
+    scan_all(txn, b"b", b"d", &Default::default()).await,
+    pairs(&[("b", "")])
+  );
+  assert_eq!(
+    scan_all(
+      txn,
+      b"a",
+      b"z",
+      &ScanOptions {
+        reverse: true,
+        ..values.clone()
+      }
+    )
+    .await,
+    pairs(&[("d", "4444"), ("b", "22"), ("a", "1")])
+  );
+  assert_eq!(
+    scan_all(
+      txn,
+      b"a",
+      b"z",
+      &ScanOptions {
+        limit: Some(2),
+        reverse: true,
+        ..Default::default()
+      }
+    )
+    .await,
+    pairs(&[("d", ""), ("b", "")])
+  );
+
+  // "a" + "1" is 2 bytes, and "b" + "22" crosses the limit.
+  assert_eq!(
+    scan_all(
+      txn,
+      b"a",
+      b"z",
+      &ScanOptions {
+        byte_limit: Some(3),
+        ..values.clone()
+      }
+    )
+    .await,
+    pairs(&[("a", "1"), ("b", "22")])
+  );
+  assert!(scan_all(
+    txn,
+    b"a",
+    b"z",
+    &ScanOptions {
+      limit: Some(0),
+      ..Default::default()
+    }
+  )
+  .await
+  .is_empty());
+}
+
+#[tokio::test]
+async fn mock_kv_scan() {
+  check_scan(&MockKv::new()).await;
+}
+
+#[tokio::test]
+async fn default_scan() {
+  // `ChaosKv` does not override `scan`.
+  check_scan(&ChaosKv::new(MockKv::new(), ChaosConfig::default())).await;
+}
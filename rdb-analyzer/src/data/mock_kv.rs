@@ -1,5 +1,5 @@
 use std::{
-  collections::HashMap,
+  collections::{HashMap, HashSet},
   sync::{
     atomic::{AtomicU64, Ordering},
     Arc,
@@ -7,13 +7,23 @@ use std::{
 };
 
 use async_trait::async_trait;
+use futures::lock::Mutex;
 use rpds::RedBlackTreeMapSync;
-use tokio::sync::Mutex;
 
-use super::kv::{KeyValueStore, KvError, KvKeyIterator, KvTransaction};
+use super::kv::{
+  ConflictInfo, KeyValueStore, KvEntryIterator, KvError, KvKeyIterator, KvTransaction, ScanBudget,
+  ScanOptions,
+};
 use anyhow::Result;
 
 /// A mocked KV store that simulates MVCC with snapshot isolation.
+///
+/// In strict mode, commits also conflict when a key read or a range scanned by the transaction
+/// was modified by another transaction committed in the meantime. This simulates serializable
+/// isolation and catches races hidden by write-write conflict detection alone.
+///
+/// Every commit with writes creates a new version of the store, and all versions are kept for
+/// snapshot reads.
 pub struct MockKv {
   store: MockStore,
 }
@@ -21,15 +31,24 @@ pub struct MockKv {
 pub struct MockTransaction {
   id: u64,
   store: MockStore,
+  read_version: i64,
   read_buffer: RedBlackTreeMapSync<Vec<u8>, (Option<Vec<u8>>, u64)>,
   buffer: Mutex<RedBlackTreeMapSync<Vec<u8>, (Option<Vec<u8>>, u64)>>,
   modified: Mutex<HashMap<Vec<u8>, u64>>,
+
+  /// Keys read and ranges scanned. Only recorded in strict mode.
+  read_keys: Mutex<HashSet<Vec<u8>>>,
+  read_ranges: Mutex<Vec<(Vec<u8>, Vec<u8>)>>,
 }
 
 #[derive(Clone)]
 struct MockStore {
   data: Arc<Mutex<RedBlackTreeMapSync<Vec<u8>, (Option<Vec<u8>>, u64)>>>,
   txn_count: Arc<AtomicU64>,
+  strict: bool,
+
+  /// Committed states of the store, indexed by version. Version 0 is the empty store.
+  versions: Arc<Mutex<Vec<RedBlackTreeMapSync<Vec<u8>, (Option<Vec<u8>>, u64)>>>>,
 }
 
 struct MockIterator {
@@ -38,28 +57,149 @@ struct MockIterator {
   end: Vec<u8>,
 }
 
+/// An entry iterator over `[start, end)`. Both bounds are moved inwards as entries are returned.
+struct MockEntryIterator {
+  map: RedBlackTreeMapSync<Vec<u8>, (Option<Vec<u8>>, u64)>,
+  start: Vec<u8>,
+  end: Vec<u8>,
+  values: bool,
+  reverse: bool,
+  budget: ScanBudget,
+}
+
 impl MockKv {
   pub fn new() -> Self {
+    Self::with_strictness(false)
+  }
+
+  /// Creates a store that also detects read-write conflicts.
+  pub fn new_strict() -> Self {
+    Self::with_strictness(true)
+  }
+
+  fn with_strictness(strict: bool) -> Self {
     MockKv {
       store: MockStore {
         data: Arc::new(Mutex::new(RedBlackTreeMapSync::new_sync())),
         txn_count: Arc::new(AtomicU64::new(0)),
+        strict,
+        versions: Arc::new(Mutex::new(vec![RedBlackTreeMapSync::new_sync()])),
       },
     }
   }
-}
 
-#[async_trait]
-impl KeyValueStore for MockKv {
-  async fn begin_transaction(&self) -> Result<Box<dyn KvTransaction>> {
-    let buffer = self.store.data.lock().await.clone();
-    Ok(Box::new(MockTransaction {
+  /// Number of committed keys.
+  pub async fn key_count(&self) -> usize {
+    self
+      .store
+      .data
+      .lock()
+      .await
+      .values()
+      .filter(|x| x.0.is_some())
+      .count()
+  }
+
+  /// Number of committed keys in `[start, end)`.
+  pub async fn key_count_in_range(&self, start: &[u8], end: &[u8]) -> usize {
+    self
+      .store
+      .data
+      .lock()
+      .await
+      .range(start.to_vec()..end.to_vec())
+      .filter(|(_, v)| v.0.is_some())
+      .count()
+  }
+
+  /// Committed entries in `[start, end)`, in key order.
+  pub async fn dump_range(&self, start: &[u8], end: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+    self
+      .store
+      .data
+      .lock()
+      .await
+      .range(start.to_vec()..end.to_vec())
+      .filter_map(|(k, v)| v.0.as_ref().map(|v| (k.clone(), v.clone())))
+      .collect()
+  }
+
+  /// All committed entries, in key order.
+  pub async fn dump(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+    self
+      .store
+      .data
+      .lock()
+      .await
+      .iter()
+      .filter_map(|(k, v)| v.0.as_ref().map(|v| (k.clone(), v.clone())))
+      .collect()
+  }
+
+  /// Deletes all keys starting with `prefix` outside of any transaction. Returns the number of
+  /// keys deleted.
+  ///
+  /// Concurrent transactions that modified the deleted keys conflict on commit.
+  pub async fn wipe_prefix(&self, prefix: &[u8]) -> usize {
+    let mut data = self.store.data.lock().await;
+    let keys = data
+      .iter()
+      .filter(|(k, v)| k.starts_with(prefix) && v.0.is_some())
+      .map(|(k, v)| (k.clone(), v.1))
+      .collect::<Vec<_>>();
+    for (k, version) in &keys {
+      data.insert_mut(k.clone(), (None, version + 1));
+    }
+    if !keys.is_empty() {
+      self.store.versions.lock().await.push(data.clone());
+    }
+    keys.len()
+  }
+
+  fn begin_at(
+    &self,
+    buffer: RedBlackTreeMapSync<Vec<u8>, (Option<Vec<u8>>, u64)>,
+    read_version: i64,
+  ) -> Box<dyn KvTransaction> {
+    Box::new(MockTransaction {
       id: self.store.txn_count.fetch_add(1, Ordering::SeqCst) + 1,
       store: self.store.clone(),
+      read_version,
       read_buffer: buffer.clone(),
       buffer: Mutex::new(buffer),
       modified: Mutex::new(HashMap::new()),
-    }))
+      read_keys: Mutex::new(HashSet::new()),
+      read_ranges: Mutex::new(Vec::new()),
+    })
+  }
+}
+
+impl Default for MockKv {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[async_trait]
+impl KeyValueStore for MockKv {
+  async fn begin_transaction(&self) -> Result<Box<dyn KvTransaction>> {
+    let data = self.store.data.lock().await;
+    let read_version = self.store.versions.lock().await.len() as i64 - 1;
+    Ok(self.begin_at(data.clone(), read_version))
+  }
+
+  async fn begin_transaction_at(&self, version: i64) -> Result<Box<dyn KvTransaction>> {
+    let versions = self.store.versions.lock().await;
+    let buffer = if version < 0 {
+      None
+    } else {
+      versions.get(version as usize).cloned()
+    };
+    drop(versions);
+    match buffer {
+      Some(x) => Ok(self.begin_at(x, version)),
+      None => Err(KvError::SnapshotUnavailable(version).into()),
+    }
   }
 }
 
@@ -67,6 +207,9 @@ impl KeyValueStore for MockKv {
 impl KvTransaction for MockTransaction {
   async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
     log::trace!("[txn {}] get {}", self.id, base64::encode(key));
+    if self.store.strict {
+      self.read_keys.lock().await.insert(key.to_vec());
+    }
     Ok(
       self
         .read_buffer
@@ -106,6 +249,7 @@ impl KvTransaction for MockTransaction {
   }
 
   async fn scan_keys(&self, start: &[u8], end: &[u8]) -> Result<Box<dyn KvKeyIterator>> {
+    self.record_range_read(start, end).await;
     Ok(Box::new(MockIterator {
       map: self.buffer.lock().await.clone(),
       current: start.to_vec(),
@@ -113,26 +257,73 @@ impl KvTransaction for MockTransaction {
     }))
   }
 
+  async fn scan<'a>(
+    &'a self,
+    start: &[u8],
+    end: &[u8],
+    options: &ScanOptions,
+  ) -> Result<Box<dyn KvEntryIterator + 'a>> {
+    self.record_range_read(start, end).await;
+    Ok(Box::new(MockEntryIterator {
+      map: self.buffer.lock().await.clone(),
+      start: start.to_vec(),
+      end: end.to_vec(),
+      values: options.values,
+      reverse: options.reverse,
+      budget: ScanBudget::new(options),
+    }))
+  }
+
   async fn commit(self: Box<Self>) -> Result<(), KvError> {
     let buffer = self.buffer.into_inner();
     let modified = self.modified.into_inner();
+    let read_keys = self.read_keys.into_inner();
+    let read_ranges = self.read_ranges.into_inner();
 
     let mut data = self.store.data.lock().await;
-    for (k, initial_version) in &modified {
-      if data.get(k).map(|x| x.1).unwrap_or_default() != *initial_version {
-        log::trace!("[txn {}] commit CONFLICT", self.id);
-        return Err(KvError::Conflict);
+    let mut conflicts = modified
+      .iter()
+      .filter(|(k, initial_version)| {
+        data.get(*k).map(|x| x.1).unwrap_or_default() != **initial_version
+      })
+      .map(|(k, _)| k.as_slice())
+      .collect::<Vec<_>>();
+
+    // Reads conflict with writes committed after the snapshot was taken.
+    let read_buffer = &self.read_buffer;
+    let snapshot_version = |k: &[u8]| read_buffer.get(k).map(|x| x.1).unwrap_or_default();
+    for k in &read_keys {
+      if data.get(k).map(|x| x.1).unwrap_or_default() != snapshot_version(k.as_slice()) {
+        conflicts.push(k.as_slice());
       }
     }
+    for (start, end) in &read_ranges {
+      for (k, v) in data.range(start.clone()..end.clone()) {
+        if v.1 != snapshot_version(k.as_slice()) {
+          conflicts.push(k.as_slice());
+        }
+      }
+    }
+    if !conflicts.is_empty() {
+      log::trace!("[txn {}] commit CONFLICT", self.id);
+      return Err(KvError::Conflict(ConflictInfo::from_keys(conflicts)));
+    }
 
-    for (k, _) in modified {
-      let value = buffer.get(&k).unwrap().clone();
-      data.insert_mut(k, value);
+    if !modified.is_empty() {
+      for (k, _) in modified {
+        let value = buffer.get(&k).unwrap().clone();
+        data.insert_mut(k, value);
+      }
+      self.store.versions.lock().await.push(data.clone());
     }
     log::trace!("[txn {}] commit OK", self.id);
     Ok(())
   }
 
+  async fn read_version(&self) -> Result<Option<i64>> {
+    Ok(Some(self.read_version))
+  }
+
   async fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
     log::trace!(
       "[txn {}] delete_range {} {}",
@@ -165,6 +356,18 @@ impl KvTransaction for MockTransaction {
   }
 }
 
+impl MockTransaction {
+  async fn record_range_read(&self, start: &[u8], end: &[u8]) {
+    if self.store.strict {
+      self
+        .read_ranges
+        .lock()
+        .await
+        .push((start.to_vec(), end.to_vec()));
+    }
+  }
+}
+
 #[async_trait]
 impl KvKeyIterator for MockIterator {
   async fn next(&mut self) -> Result<Option<Vec<u8>>> {
@@ -183,3 +386,32 @@ impl KvKeyIterator for MockIterator {
     }
   }
 }
+
+#[async_trait]
+impl KvEntryIterator for MockEntryIterator {
+  async fn next(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    while !self.budget.exhausted() && self.start < self.end {
+      let mut range = self.map.range(self.start.clone()..self.end.clone());
+      let entry = if self.reverse {
+        range.next_back()
+      } else {
+        range.next()
+      };
+      let (k, v) = match entry {
+        Some(x) => x,
+        None => break,
+      };
+      if self.reverse {
+        self.end = k.clone();
+      } else {
+        self.start = k.iter().copied().chain(std::iter::once(0x00u8)).collect();
+      }
+      if let Some(value) = &v.0 {
+        let value = if self.values { value.clone() } else { vec![] };
+        self.budget.charge(k.len() + value.len());
+        return Ok(Some((k.clone(), value)));
+      }
+    }
+    Ok(None)
+  }
+}
@@ -0,0 +1,132 @@
+use super::{
+  kv::{KeyValueStore, KvError},
+  mock_kv::MockKv,
+};
+
+async fn populate(kv: &MockKv) {
+  let txn = kv.begin_transaction().await.unwrap();
+  for (k, v) in &[("a/1", "x"), ("a/2", "y"), ("b/1", "z"), ("c", "w")] {
+    txn.put(k.as_bytes(), v.as_bytes()).await.unwrap();
+  }
+  txn.commit().await.unwrap();
+}
+
+#[tokio::test]
+async fn introspection() {
+  let kv = MockKv::new();
+  populate(&kv).await;
+  assert_eq!(kv.key_count().await, 4);
+  assert_eq!(kv.key_count_in_range(b"a/", b"a0").await, 2);
+  assert_eq!(
+    kv.dump_range(b"a/", b"a0").await,
+    vec![
+      (b"a/1".to_vec(), b"x".to_vec()),
+      (b"a/2".to_vec(), b"y".to_vec())
+    ]
+  );
+
+  assert_eq!(kv.wipe_prefix(b"a/").await, 2);
+  assert_eq!(kv.wipe_prefix(b"a/").await, 0);
+  assert_eq!(kv.key_count().await, 2);
+  assert_eq!(
+    kv.dump().await,
+    vec![
+      (b"b/1".to_vec(), b"z".to_vec()),
+      (b"c".to_vec(), b"w".to_vec())
+    ]
+  );
+}
+
+#[tokio::test]
+async fn wipe_conflicts_with_concurrent_writes() {
+  let kv = MockKv::new();
+  populate(&kv).await;
+  let txn = kv.begin_transaction().await.unwrap();
+  txn.put(b"a/1", b"updated").await.unwrap();
+  kv.wipe_prefix(b"a/").await;
+  assert!(matches!(txn.commit().await, Err(KvError::Conflict(_))));
+}
+
+#[tokio::test]
+async fn strict_read_write_conflicts() {
+  for strict in [false, true].iter().copied() {
+    let kv = if strict {
+      MockKv::new_strict()
+    } else {
+      MockKv::new()
+    };
+    populate(&kv).await;
+
+    // Write skew: each transaction reads what the other one writes.
+    let t1 = kv.begin_transaction().await.unwrap();
+    let t2 = kv.begin_transaction().await.unwrap();
+    t1.get(b"a/1").await.unwrap();
+    t1.put(b"a/2", b"t1").await.unwrap();
+    t2.get(b"a/2").await.unwrap();
+    t2.put(b"a/1", b"t2").await.unwrap();
+    t1.commit().await.unwrap();
+    assert_eq!(t2.commit().await.is_err(), strict);
+
+    // Phantom: a scanned range gains a key.
+    let t1 = kv.begin_transaction().await.unwrap();
+    let t2 = kv.begin_transaction().await.unwrap();
+    let mut it = t1.scan_keys(b"b/", b"b0").await.unwrap();
+    while it.next().await.unwrap().is_some() {}
+    t1.put(b"c", b"t1").await.unwrap();
+    t2.put(b"b/2", b"t2").await.unwrap();
+    t2.commit().await.unwrap();
+    assert_eq!(t1.commit().await.is_err(), strict);
+  }
+}
+
+#[tokio::test]
+async fn strict_allows_disjoint_transactions() {
+  let kv = MockKv::new_strict();
+  populate(&kv).await;
+  let t1 = kv.begin_transaction().await.unwrap();
+  let t2 = kv.begin_transaction().await.unwrap();
+  t1.get(b"a/1").await.unwrap();
+  t1.put(b"a/1", b"t1").await.unwrap();
+  t2.get(b"b/1").await.unwrap();
+  t2.put(b"b/1", b"t2").await.unwrap();
+  t1.commit().await.unwrap();
+  t2.commit().await.unwrap();
+}
+
+#[tokio::test]
+async fn snapshot_reads() {
+  let kv = MockKv::new();
+  let txn = kv.begin_transaction().await.unwrap();
+  assert_eq!(txn.read_version().await.unwrap(), Some(0));
+  drop(txn);
+  populate(&kv).await;
+
+  let txn = kv.begin_transaction().await.unwrap();
+  txn.put(b"a/1", b"updated").await.unwrap();
+  txn.commit().await.unwrap();
+  kv.wipe_prefix(b"b/").await;
+
+  let txn = kv.begin_transaction_at(1).await.unwrap();
+  assert_eq!(txn.read_version().await.unwrap(), Some(1));
+  assert_eq!(txn.get(b"a/1").await.unwrap(), Some(b"x".to_vec()));
+  assert_eq!(txn.get(b"b/1").await.unwrap(), Some(b"z".to_vec()));
+  let txn = kv.begin_transaction_at(2).await.unwrap();
+  assert_eq!(txn.get(b"a/1").await.unwrap(), Some(b"updated".to_vec()));
+  assert_eq!(txn.get(b"b/1").await.unwrap(), Some(b"z".to_vec()));
+  let txn = kv.begin_transaction().await.unwrap();
+  assert_eq!(txn.read_version().await.unwrap(), Some(3));
+  assert_eq!(txn.get(b"b/1").await.unwrap(), None);
+
+  // Writes at a past version conflict with the writes committed since.
+  let txn = kv.begin_transaction_at(1).await.unwrap();
+  txn.put(b"a/1", b"stale").await.unwrap();
+  assert!(matches!(txn.commit().await, Err(KvError::Conflict(_))));
+
+  for version in [-1, 4].iter().copied() {
+    let e = kv.begin_transaction_at(version).await.err().unwrap();
+    assert!(matches!(
+      e.downcast_ref::<KvError>(),
+      Some(KvError::SnapshotUnavailable(x)) if *x == version
+    ));
+  }
+}
@@ -1,10 +1,47 @@
+pub mod backfill;
+pub mod blob;
+pub mod consistency;
+pub mod convert;
+pub mod inspect;
+pub mod keyenc;
 pub mod kv;
+pub mod mock_kv;
 pub mod pathwalker;
+pub mod query;
+pub mod replication;
+pub mod stats;
 pub mod treewalker;
 pub mod value;
 
 #[cfg(test)]
-mod mock_kv;
+mod backfill_test;
+
+#[cfg(test)]
+mod blob_test;
+
+#[cfg(test)]
+mod consistency_test;
+
+#[cfg(test)]
+mod convert_test;
+
+#[cfg(test)]
+mod inspect_test;
+
+#[cfg(test)]
+mod keyenc_test;
+
+#[cfg(test)]
+mod mock_kv_test;
 
 #[cfg(test)]
 mod pathwalker_test;
+
+#[cfg(test)]
+mod replication_test;
+
+#[cfg(test)]
+mod stats_test;
+
+#[cfg(test)]
+mod value_test;
@@ -1,11 +1,13 @@
 use std::{ops::Deref, sync::Arc};
 
 use anyhow::Result;
+use once_cell::sync::OnceCell;
+use smallvec::SmallVec;
 
 use crate::storage_plan::{StorageNode, StoragePlan};
 use thiserror::Error;
 
-use super::value::PrimitiveValue;
+use super::{keyenc, kv::KvTransaction, value::PrimitiveValue};
 
 #[derive(Error, Debug)]
 pub enum PathWalkerError {
@@ -23,10 +25,63 @@ pub enum PathWalkerError {
 
   #[error("path too deep")]
   PathTooDeep,
+
+  #[error("blob chunk key requested on a non-blob node")]
+  NotBlob,
 }
 
 const MAX_DEPTH: usize = 64;
 
+/// A segment of the logical path of a `PathWalker`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PathSegment<'a> {
+  /// An export, or a field of a table.
+  Field(&'a str),
+
+  /// The encoded primary key of a set member or key of a map entry, or the big-endian index of a
+  /// list element.
+  Selector(Vec<u8>),
+}
+
+/// What a raw key decoded by `PathWalker::decode_key` stores about its location.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeyKind {
+  /// The location itself: the value of a leaf, or the presence marker of a table, set or member.
+  Node,
+
+  /// An entry of the fast scan index of a set, with the encoded primary key of the member.
+  SetFastScan(Vec<u8>),
+
+  /// An entry of the sort key index of a set, with the encoded sort key followed by the encoded
+  /// primary key of the member. See `keyenc::split_sort_key_entry`.
+  SetSortKey(Vec<u8>),
+
+  /// The counter of the `@auto` primary keys of a set.
+  SetAutoCounter,
+
+  /// The progress of the backfill of the sort key index of a set.
+  SetSortIndexProgress,
+
+  /// A chunk of a `@blob` leaf, with its index.
+  BlobChunk(u32),
+}
+
+/// A raw key mapped back to its location.
+#[derive(Debug)]
+pub struct DecodedKey<'a> {
+  pub walker: Arc<PathWalker<'a>>,
+  pub kind: KeyKind,
+}
+
+/// A location in the storage plan: an export, a field of a table, or a member of a set, map or
+/// list. `decode_key` maps a raw key back to its location; external tools may rely on both
+/// directions.
+///
+/// The key of a location is the concatenation of the storage keys of the nodes on its path,
+/// except for flattened nodes. The members of a set, map or list with the key `K` are stored at
+/// `K 0x00 <selector> 0x00`, and its indexes and counters at `K 0x01`, `K 0x03`, `K 0x04` and
+/// `K 0x06` (see the `set_*` methods). The chunks of a `@blob` leaf are stored at
+/// `K 0x05 <chunk index>`.
 #[derive(Debug)]
 pub struct PathWalker<'a> {
   /// The "actual" storage node, with subspace references resolved.
@@ -57,6 +112,10 @@ pub struct PathWalker<'a> {
   is_intermediate: bool,
 
   path_segment: Option<&'a str>,
+
+  /// The full key of this node, generated on first use. Children build their keys on top of the
+  /// cached keys of their ancestors instead of walking the whole path again.
+  key_cache: OnceCell<Box<[u8]>>,
 }
 
 #[derive(Clone, Debug)]
@@ -76,6 +135,7 @@ impl<'a> Deref for KeyCow<'a> {
 }
 
 impl<'a> PathWalker<'a> {
+  /// The location of a top-level export.
   pub fn from_export(plan: &'a StoragePlan, export_name: &str) -> Result<Arc<Self>> {
     let (export_name, export) = plan
       .nodes
@@ -90,13 +150,14 @@ impl<'a> PathWalker<'a> {
       should_flatten: export.flattened,
       is_intermediate: false,
       path_segment: Some(&**export_name),
+      key_cache: OnceCell::new(),
     }))
   }
 }
 
 impl<'a> PartialEq for PathWalker<'a> {
   fn eq(&self, other: &Self) -> bool {
-    self.generate_key_raw() == other.generate_key_raw()
+    self.key() == other.key()
   }
 }
 
@@ -160,21 +221,63 @@ impl<'a> PathWalker<'a> {
     result
   }
 
+  /// The storage node of this location, with subspace references resolved.
   pub fn node(&self) -> &'a StorageNode {
     self.node
   }
 
+  /// The logical path of this location, starting with the export.
+  pub fn path(&self) -> Vec<PathSegment<'a>> {
+    let mut link = Some(self);
+    let mut result = vec![];
+    while let Some(x) = link {
+      if x.is_intermediate {
+        // The key of the intermediate node of a set member is `0x00 <selector> 0x00`.
+        result.push(PathSegment::Selector(x.key[1..x.key.len() - 1].to_vec()));
+      } else if let Some(segment) = x.path_segment {
+        result.push(PathSegment::Field(segment));
+      }
+      link = x.link.as_ref().map(|x| &**x);
+    }
+    result.reverse();
+    result
+  }
+
+  /// The full key of this location. Same as `key`, but owned.
   pub fn generate_key(&self) -> Vec<u8> {
-    let components = self.generate_key_raw();
-    let len = components.iter().fold(0, |a, b| a + b.len());
-    let mut key = Vec::with_capacity(len);
-    for c in components.iter() {
-      key.extend_from_slice(*c);
+    self.key().to_vec()
+  }
+
+  /// The full key of this node. Generated once and cached.
+  pub fn key(&self) -> &[u8] {
+    self.key_cache.get_or_init(|| {
+      let prefix = self.link.as_ref().map(|x| x.child_prefix()).unwrap_or(&[]);
+      let mut key = Vec::with_capacity(prefix.len() + self.key.len());
+      key.extend_from_slice(prefix);
+      key.extend_from_slice(&self.key);
+      key.into_boxed_slice()
+    })
+  }
+
+  /// The prefix of the keys of the children of this node.
+  fn child_prefix(&self) -> &[u8] {
+    if self.should_flatten {
+      self.link.as_ref().map(|x| x.child_prefix()).unwrap_or(&[])
+    } else {
+      self.key()
     }
-    assert_eq!(key.len(), len);
-    key
   }
 
+  /// Returns the key that the data of this field was stored under before it was renamed, if the
+  /// storage plan records one.
+  pub fn generate_rename_fallback_key(&self) -> Option<Vec<u8>> {
+    let fallback = self.node.rename_fallback.as_ref()?;
+    let mut components = self.generate_key_raw();
+    *components.last_mut().unwrap() = &fallback[..];
+    Some(components.concat())
+  }
+
+  /// The key of this location with its components base64-encoded, for debugging.
   pub fn generate_key_pretty(&self) -> String {
     return self
       .generate_key_raw()
@@ -184,6 +287,12 @@ impl<'a> PathWalker<'a> {
       .join(" ");
   }
 
+  /// The location this one was entered from, e.g. the table of a field.
+  pub fn parent(&self) -> Option<&Arc<Self>> {
+    self.link.as_ref()
+  }
+
+  /// The location of a field of the table at this location.
   pub fn enter_field(self: &Arc<Self>, field_name: &str) -> Result<Arc<Self>> {
     // This check is not necessary for correctness but let's optimize our error message
     if self.node.set.is_some() {
@@ -212,6 +321,7 @@ impl<'a> PathWalker<'a> {
             should_flatten: false,
             is_intermediate: false,
             path_segment: Some(&**field_name),
+            key_cache: OnceCell::new(),
           }));
         }
         me = link.link.as_ref();
@@ -226,10 +336,13 @@ impl<'a> PathWalker<'a> {
         should_flatten: node.flattened,
         is_intermediate: false,
         path_segment: Some(&**field_name),
+        key_cache: OnceCell::new(),
       }))
     }
   }
 
+  /// The prefix of the fast scan index entries of the set at this location. Entries are
+  /// followed by the encoded primary key of a member.
   pub fn set_fast_scan_prefix(&self) -> Result<Vec<u8>> {
     self
       .node
@@ -242,6 +355,7 @@ impl<'a> PathWalker<'a> {
     Ok(key)
   }
 
+  /// The prefix of the data of all members of the set, map or list at this location.
   pub fn set_data_prefix(&self) -> Result<Vec<u8>> {
     self
       .node
@@ -254,6 +368,104 @@ impl<'a> PathWalker<'a> {
     Ok(key)
   }
 
+  /// The prefix of the sort key index entries of the set at this location.
+  pub fn set_sort_key_prefix(&self) -> Result<Vec<u8>> {
+    self
+      .node
+      .set
+      .as_ref()
+      .ok_or_else(|| PathWalkerError::NotSet)?;
+
+    let mut key = self.generate_key();
+    key.push(0x03u8);
+    Ok(key)
+  }
+
+  /// Returns the key of the counter that generates `@auto` primary keys of a set.
+  pub fn set_auto_counter_key(&self) -> Result<Vec<u8>> {
+    self
+      .node
+      .set
+      .as_ref()
+      .ok_or_else(|| PathWalkerError::NotSet)?;
+
+    let mut key = self.generate_key();
+    key.push(0x04u8);
+    Ok(key)
+  }
+
+  /// Returns the key of the progress of the backfill of the sort key index of a set.
+  pub fn set_sort_index_progress_key(&self) -> Result<Vec<u8>> {
+    self
+      .node
+      .set
+      .as_ref()
+      .ok_or_else(|| PathWalkerError::NotSet)?;
+
+    let mut key = self.generate_key();
+    key.push(0x06u8);
+    Ok(key)
+  }
+
+  /// The prefix of the chunk keys of the `@blob` leaf at this location.
+  pub fn blob_chunk_prefix(&self) -> Result<Vec<u8>> {
+    if !self.node.blob {
+      return Err(PathWalkerError::NotBlob.into());
+    }
+
+    let mut key = self.generate_key();
+    key.push(0x05u8);
+    Ok(key)
+  }
+
+  /// Returns the key of the chunk at `index` of the `@blob` leaf at this location.
+  pub fn blob_chunk_key(&self, index: u32) -> Result<Vec<u8>> {
+    let mut key = self.blob_chunk_prefix()?;
+    key.extend_from_slice(&index.to_be_bytes());
+    Ok(key)
+  }
+
+  /// Returns the key of the sort key entry of a set member, ordered by the member's sort key value
+  /// and then by its primary key. A null sort key value sorts first.
+  pub fn set_sort_key_entry(
+    &self,
+    sort_key_value: Option<&PrimitiveValue>,
+    primary_key: &[u8],
+  ) -> Result<Vec<u8>> {
+    let mut key = self.set_sort_key_prefix()?;
+    match sort_key_value {
+      Some(x) => key.extend_from_slice(&self.encode_sort_key(x)),
+      None => key.push(0x00u8),
+    }
+    key.extend_from_slice(primary_key);
+    Ok(key)
+  }
+
+  /// Encodes a primary key of the members of this set as a key component, with the collation of
+  /// the primary key field.
+  pub fn encode_primary_key(&self, primary_key: &PrimitiveValue) -> SmallVec<[u8; 9]> {
+    keyenc::encode_key(&primary_key.collated(self.node.key_collation))
+  }
+
+  /// Encodes a sort key value of the members of this set, with the collation of the sort key
+  /// field.
+  pub fn encode_sort_key(&self, sort_key_value: &PrimitiveValue) -> SmallVec<[u8; 9]> {
+    keyenc::encode_sort_key(&sort_key_value.collated(self.node.sort_key_collation))
+  }
+
+  /// Encodes a string prefix of the primary keys of the members of this set, or of the keys of
+  /// this map. See `keyenc::encode_key_prefix`.
+  pub fn encode_primary_key_prefix(&self, prefix: &PrimitiveValue) -> SmallVec<[u8; 9]> {
+    keyenc::encode_key_prefix(&prefix.collated(self.node.key_collation))
+  }
+
+  /// Encodes a string prefix of the sort key values of the members of this set.
+  pub fn encode_sort_key_prefix(&self, prefix: &PrimitiveValue) -> SmallVec<[u8; 9]> {
+    keyenc::encode_sort_key_prefix(&prefix.collated(self.node.sort_key_collation))
+  }
+
+  /// The location of the member of the set, map or list at this location with an already
+  /// encoded selector.
   pub fn enter_set_raw(self: &Arc<Self>, primary_key: &[u8]) -> Result<Arc<Self>> {
     let set = &**self
       .node
@@ -264,6 +476,10 @@ impl<'a> PathWalker<'a> {
     // 0x00 - data
     // 0x01 - key only
     // 0x02 - index
+    // 0x03 - sort key
+    // 0x04 - auto primary key counter
+    // 0x05 - blob chunk (below leaves)
+    // 0x06 - sort key index backfill progress
     let mut dynamic_key_bytes = vec![0x00u8];
     dynamic_key_bytes.extend_from_slice(primary_key);
     dynamic_key_bytes.push(0x00u8);
@@ -279,6 +495,7 @@ impl<'a> PathWalker<'a> {
       should_flatten: false,
       is_intermediate: true,
       path_segment: None,
+      key_cache: OnceCell::new(),
     });
 
     // And the table key.
@@ -290,10 +507,171 @@ impl<'a> PathWalker<'a> {
       should_flatten: true,
       is_intermediate: false,
       path_segment: None,
+      key_cache: OnceCell::new(),
     }))
   }
 
+  /// The location of the member of the set with a primary key, or the entry of the map with a
+  /// key, at this location.
   pub fn enter_set(self: &Arc<Self>, primary_key: &PrimitiveValue) -> Result<Arc<Self>> {
-    self.enter_set_raw(&primary_key.serialize_for_key_component())
+    self.enter_set_raw(&self.encode_primary_key(primary_key))
+  }
+
+  /// The encoded primary key of the member of the set at this location that a raw key belongs
+  /// to, either as part of the member's data or as its fast scan entry. Returns `None` for keys
+  /// outside of the members of the set.
+  pub fn set_member_of_key(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    self
+      .node
+      .set
+      .as_ref()
+      .ok_or_else(|| PathWalkerError::NotSet)?;
+
+    let rest = match key.strip_prefix(self.key()) {
+      Some(x) => x,
+      None => return Ok(None),
+    };
+    Ok(match rest.split_first() {
+      Some((0x00, rest)) => match selector_len(rest) {
+        Some(x) if rest.get(x) == Some(&0x00) => Some(rest[..x].to_vec()),
+        _ => None,
+      },
+      Some((0x01, rest)) if !rest.is_empty() => Some(rest.to_vec()),
+      _ => None,
+    })
+  }
+
+  /// Enters the element at `index` of a list.
+  ///
+  /// Lists share the layout of sets, with the big-endian index as the element key so that
+  /// elements are ordered by their index.
+  pub fn enter_list(self: &Arc<Self>, index: u64) -> Result<Arc<Self>> {
+    self.enter_set_raw(&index.to_be_bytes())
+  }
+
+  /// Maps a raw key back to its location. Returns `None` if the key does not belong to any
+  /// location in the plan.
+  ///
+  /// Selectors are split by their encoding: a selector starting with `0x00` is taken as a list
+  /// index, which holds for indices below 2^56.
+  pub fn decode_key(plan: &'a StoragePlan, key: &[u8]) -> Result<Option<DecodedKey<'a>>> {
+    // Exported tables are flattened, so the keys below them do not start with their own keys.
+    for name in plan.nodes.keys() {
+      if let Some(x) = Self::from_export(plan, name)?.decode_key_below(key)? {
+        return Ok(Some(x));
+      }
+    }
+    Ok(None)
+  }
+
+  fn decode_key_below(self: &Arc<Self>, key: &[u8]) -> Result<Option<DecodedKey<'a>>> {
+    let own_key = self.key();
+    if key == own_key {
+      return Ok(Some(DecodedKey {
+        walker: self.clone(),
+        kind: KeyKind::Node,
+      }));
+    }
+
+    if self.node.set.is_some() {
+      let rest = match key.strip_prefix(own_key) {
+        Some(x) => x,
+        None => return Ok(None),
+      };
+      let (tag, rest) = match rest.split_first() {
+        Some(x) => x,
+        None => return Ok(None),
+      };
+      let kind = match tag {
+        0x00 => {
+          let len = match selector_len(rest) {
+            Some(x) if rest.get(x) == Some(&0x00) => x,
+            _ => return Ok(None),
+          };
+          return self.enter_set_raw(&rest[..len])?.decode_key_below(key);
+        }
+        0x01 => KeyKind::SetFastScan(rest.to_vec()),
+        0x03 => KeyKind::SetSortKey(rest.to_vec()),
+        0x04 if rest.is_empty() => KeyKind::SetAutoCounter,
+        0x06 if rest.is_empty() => KeyKind::SetSortIndexProgress,
+        _ => return Ok(None),
+      };
+      return Ok(Some(DecodedKey {
+        walker: self.clone(),
+        kind,
+      }));
+    }
+
+    if self.node.blob {
+      return Ok(
+        key
+          .strip_prefix(own_key)
+          .and_then(|x| x.strip_prefix(&[0x05u8][..]))
+          .filter(|x| x.len() == 4)
+          .map(|x| DecodedKey {
+            walker: self.clone(),
+            kind: KeyKind::BlobChunk(u32::from_be_bytes([x[0], x[1], x[2], x[3]])),
+          }),
+      );
+    }
+
+    // Non-flattened fields are below their own key, and flattened ones below the prefix of
+    // their parent.
+    if !key.starts_with(self.child_prefix()) {
+      return Ok(None);
+    }
+    for name in self.node.children.keys() {
+      let child = self.enter_field(name)?;
+      if child.should_flatten || key.starts_with(child.key()) {
+        if let Some(x) = child.decode_key_below(key)? {
+          return Ok(Some(x));
+        }
+      }
+    }
+    Ok(None)
+  }
+}
+
+/// The length of the selector at the start of `data`, the part of a member data key after the
+/// `0x00` tag.
+pub(crate) fn selector_len(data: &[u8]) -> Option<usize> {
+  let len = match *data.first()? {
+    // List index
+    0x00 => 8,
+    0x01 => {
+      // Escaped, and terminated with a zero byte that is not followed by 0xff.
+      let mut i = 1;
+      loop {
+        match data.get(i)? {
+          0x00 if data.get(i + 1) != Some(&0xff) => break i + 1,
+          0x00 => i += 2,
+          _ => i += 1,
+        }
+      }
+    }
+    // Strings never contain 0x00.
+    0x02 => data.iter().position(|x| *x == 0x00)?,
+    0x03 | 0x04 => 9,
+    _ => return None,
+  };
+  if data.len() < len {
+    None
+  } else {
+    Some(len)
+  }
+}
+
+/// Gets the value of a leaf field, falling back to the key the field was stored under before it
+/// was renamed if nothing is stored under its current key.
+pub async fn get_with_rename_fallback(
+  txn: &dyn KvTransaction,
+  walker: &PathWalker<'_>,
+) -> Result<Option<Vec<u8>>> {
+  match txn.get(walker.key()).await? {
+    Some(x) => Ok(Some(x)),
+    None => match walker.generate_rename_fallback_key() {
+      Some(key) => txn.get(&key).await,
+      None => Ok(None),
+    },
   }
 }
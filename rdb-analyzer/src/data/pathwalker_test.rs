@@ -3,7 +3,7 @@ use std::{collections::HashSet, sync::Arc};
 use bumpalo::Bump;
 
 use crate::{
-  data::value::PrimitiveValue,
+  data::{keyenc, value::PrimitiveValue},
   schema::{
     compile::{compile, CompiledSchema, FieldAnnotationList, FieldType},
     grammar::parse,
@@ -11,7 +11,7 @@ use crate::{
   storage_plan::{planner::generate_plan_for_schema, StorageNode, StoragePlan},
 };
 
-use super::pathwalker::PathWalker;
+use super::pathwalker::{KeyKind, PathSegment, PathWalker};
 
 fn print_path_examples(
   schema: &CompiledSchema,
@@ -37,7 +37,17 @@ fn print_path_examples(
         recursion_set.remove(&(field as *const _ as usize));
       }
     }
-    FieldType::Primitive(_) => {}
+    FieldType::Primitive(_) | FieldType::Struct(_) => {}
+    FieldType::Map(_) => {
+      let walker = walker
+        .enter_set(&PrimitiveValue::String("key".into()))
+        .unwrap();
+      println!("{}[\"key\"] -> {}", path, walker.generate_key_pretty());
+    }
+    FieldType::List(_) => {
+      let walker = walker.enter_list(0).unwrap();
+      println!("{}[0] -> {}", path, walker.generate_key_pretty());
+    }
     FieldType::Set(ty) => {
       let specialized_ty = match &**ty {
         FieldType::Table(x) => schema.types.get(x).unwrap(),
@@ -196,3 +206,158 @@ fn basic() {
     );
   }
 }
+
+#[test]
+fn decode_key() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+  type Item {
+    @primary
+    id: int64,
+    tags: list<string>,
+    recursive: Item,
+  }
+  type Store {
+    @primary
+    name: string,
+    attrs: map<string, int64>,
+    items: set<Item>,
+  }
+  export set<Store> stores;
+  export Store main_store;
+  "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+
+  let store = PathWalker::from_export(&plan, "stores")
+    .unwrap()
+    .enter_set(&PrimitiveValue::String("a\0b".into()))
+    .unwrap();
+  let item = store
+    .enter_field("items")
+    .unwrap()
+    .enter_set(&PrimitiveValue::Int64(-1))
+    .unwrap();
+  let walkers = vec![
+    PathWalker::from_export(&plan, "stores").unwrap(),
+    store.clone(),
+    store.enter_field("name").unwrap(),
+    store
+      .enter_field("attrs")
+      .unwrap()
+      .enter_set(&PrimitiveValue::String("k".into()))
+      .unwrap(),
+    item.enter_field("id").unwrap(),
+    item.enter_field("tags").unwrap().enter_list(3).unwrap(),
+    item
+      .enter_field("recursive")
+      .unwrap()
+      .enter_field("recursive")
+      .unwrap()
+      .enter_field("id")
+      .unwrap(),
+    PathWalker::from_export(&plan, "main_store")
+      .unwrap()
+      .enter_field("name")
+      .unwrap(),
+  ];
+  for walker in walkers {
+    let decoded = PathWalker::decode_key(&plan, walker.key())
+      .unwrap()
+      .unwrap_or_else(|| panic!("cannot decode {:?}", walker.path()));
+    assert_eq!(decoded.kind, KeyKind::Node);
+    assert_eq!(decoded.walker.path(), walker.path());
+    assert_eq!(decoded.walker.key(), walker.key());
+  }
+
+  assert_eq!(
+    item.path(),
+    vec![
+      PathSegment::Field("stores"),
+      PathSegment::Selector(keyenc::encode_key(&PrimitiveValue::String("a\0b".into())).to_vec()),
+      PathSegment::Field("items"),
+      PathSegment::Selector(keyenc::encode_key(&PrimitiveValue::Int64(-1)).to_vec()),
+    ]
+  );
+
+  let items = store.enter_field("items").unwrap();
+  let primary_key = items.encode_primary_key(&PrimitiveValue::Int64(42));
+  let mut fast_scan_key = items.set_fast_scan_prefix().unwrap();
+  fast_scan_key.extend_from_slice(&primary_key);
+  let decoded = PathWalker::decode_key(&plan, &fast_scan_key)
+    .unwrap()
+    .unwrap();
+  assert_eq!(decoded.walker.path(), items.path());
+  assert_eq!(decoded.kind, KeyKind::SetFastScan(primary_key.to_vec()));
+
+  let decoded = PathWalker::decode_key(&plan, &items.set_auto_counter_key().unwrap())
+    .unwrap()
+    .unwrap();
+  assert_eq!(decoded.kind, KeyKind::SetAutoCounter);
+
+  assert!(PathWalker::decode_key(&plan, b"no such key")
+    .unwrap()
+    .is_none());
+}
+
+#[test]
+fn set_member_of_key() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+  type Item {
+    @primary
+    id: string,
+    tags: list<string>,
+  }
+  export set<Item> items;
+  "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+
+  let items = PathWalker::from_export(&plan, "items").unwrap();
+  let primary_key = items
+    .encode_primary_key(&PrimitiveValue::String("a".into()))
+    .to_vec();
+  let item = items.enter_set_raw(&primary_key).unwrap();
+  for key in vec![
+    item.key().to_vec(),
+    item.enter_field("id").unwrap().generate_key(),
+    item
+      .enter_field("tags")
+      .unwrap()
+      .enter_list(2)
+      .unwrap()
+      .generate_key(),
+    [items.set_fast_scan_prefix().unwrap(), primary_key.clone()].concat(),
+  ] {
+    assert_eq!(
+      items.set_member_of_key(&key).unwrap(),
+      Some(primary_key.clone())
+    );
+  }
+
+  assert!(items
+    .set_member_of_key(&items.set_auto_counter_key().unwrap())
+    .unwrap()
+    .is_none());
+  assert!(items.set_member_of_key(b"no such key").unwrap().is_none());
+  assert!(item
+    .enter_field("id")
+    .unwrap()
+    .set_member_of_key(item.key())
+    .is_err());
+}
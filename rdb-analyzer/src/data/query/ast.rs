@@ -0,0 +1,142 @@
+use std::{cmp::Ordering, collections::BTreeMap, fmt::Display};
+
+use crate::data::value::PrimitiveValue;
+
+/// A path query, e.g. `.items[id = 42].name`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PathQuery {
+  /// Name of the exported field the path starts from.
+  pub root: String,
+  pub segments: Vec<PathSegment>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PathSegment {
+  /// `.field`
+  Field(String),
+
+  /// `[field = value, ...]`. All predicates must hold.
+  Filter(Vec<Predicate>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Predicate {
+  pub field: String,
+  pub op: CompareOp,
+  pub value: Operand,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CompareOp {
+  Eq,
+  Lt,
+  Le,
+  Gt,
+  Ge,
+
+  /// `starts_with`, on string fields.
+  StartsWith,
+}
+
+impl CompareOp {
+  /// Returns whether `ordering`, the result of comparing the field value with the operand,
+  /// satisfies this operator. Always false for `StartsWith`, which is not decided by ordering.
+  pub fn matches(&self, ordering: Ordering) -> bool {
+    match self {
+      Self::Eq => ordering == Ordering::Equal,
+      Self::Lt => ordering == Ordering::Less,
+      Self::Le => ordering != Ordering::Greater,
+      Self::Gt => ordering == Ordering::Greater,
+      Self::Ge => ordering != Ordering::Less,
+      Self::StartsWith => false,
+    }
+  }
+}
+
+impl Display for CompareOp {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "{}",
+      match self {
+        Self::Eq => "=",
+        Self::Lt => "<",
+        Self::Le => "<=",
+        Self::Gt => ">",
+        Self::Ge => ">=",
+        Self::StartsWith => "starts_with",
+      }
+    )
+  }
+}
+
+/// The right-hand side of a predicate.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operand {
+  Literal(PrimitiveValue),
+
+  /// `?`. Positional placeholders are numbered by the order in which they are planned.
+  Positional,
+
+  /// `$name`. Named placeholders with the same name share a parameter slot.
+  Named(String),
+
+  /// A subquery on another path, e.g. `.users[id = "u1"].id`.
+  Path(PathQuery),
+}
+
+/// A literal value: a primitive, or an object for table-typed fields.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+  Primitive(PrimitiveValue),
+  Object(BTreeMap<String, Value>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Statement {
+  /// `.items[id = 42].name`
+  Read(PathQuery),
+
+  /// `.items[id = 42].name = "x"`
+  Assign(PathQuery, Value),
+
+  /// `delete .items[id = 42]`
+  Delete(PathQuery),
+
+  /// `insert .items { id: 42, name: "x" }`
+  Insert(PathQuery, BTreeMap<String, Value>),
+
+  /// `.items[id > 42] | sum(.weight)`
+  Aggregate(PathQuery, Aggregate),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Aggregate {
+  pub func: AggregateFn,
+
+  /// Path to the aggregated field, relative to each member. Empty for `count()`.
+  pub fields: Vec<String>,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AggregateFn {
+  Count,
+  Sum,
+  Min,
+  Max,
+}
+
+impl Display for AggregateFn {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "{}",
+      match self {
+        Self::Count => "count",
+        Self::Sum => "sum",
+        Self::Min => "min",
+        Self::Max => "max",
+      }
+    )
+  }
+}
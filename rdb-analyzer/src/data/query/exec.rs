@@ -0,0 +1,1119 @@
+use std::{cmp::Ordering, collections::BTreeMap, sync::Arc};
+
+use anyhow::Result;
+use async_recursion::async_recursion;
+use thiserror::Error;
+
+use crate::{
+  data::{
+    backfill, blob,
+    keyenc::{self, split_sort_key_entry, successor_of_bounded_prefix},
+    kv::{KvTransaction, ScanOptions},
+    pathwalker::{get_with_rename_fallback, PathWalker},
+    treewalker::{
+      exec::current_millis,
+      serialize::{SerializedVmValue, TaggedVmValue, VmValueEncodeConfig},
+      vm_value::{VmType, VmValue},
+    },
+    value::{PackedValue, PrimitiveValue},
+  },
+  schema::{
+    check::CheckPredicate,
+    compile::{CompiledSchema, FieldType, CREATED_AT_FIELD, DELETED_AT_FIELD, UPDATED_AT_FIELD},
+  },
+  storage_plan::StoragePlan,
+};
+
+use super::{
+  ast::{AggregateFn, CompareOp, Value},
+  planner::{QueryPlan, QueryStep, ScanBound},
+};
+
+#[derive(Error, Debug)]
+pub enum QueryExecError {
+  #[error("stack underflow")]
+  StackUnderflow,
+
+  #[error("unexpected value on stack for step `{0}`")]
+  UnexpectedStackValue(String),
+
+  #[error("unbalanced stack at the end of the plan")]
+  UnbalancedStack,
+
+  #[error("type not found: `{0}`")]
+  TypeNotFound(String),
+
+  #[error("parameter slot {0} is not bound")]
+  UnboundParam(u32),
+
+  #[error("value does not match type `{0}`")]
+  ValueTypeMismatch(String),
+
+  #[error("integer overflow in sum")]
+  SumOverflow,
+
+  #[error("bad sort key entry in storage")]
+  BadSortKeyEntry,
+
+  #[error("the sort key index of set `{0}` is not backfilled yet")]
+  SortIndexNotReady(String),
+
+  #[error("check constraint violated on type `{ty}`: `{check}`")]
+  ConstraintViolation { ty: String, check: String },
+}
+
+enum StackValue<'a> {
+  Null,
+  Primitive(PrimitiveValue),
+  Object(BTreeMap<String, Value>),
+  Path(Arc<PathWalker<'a>>),
+  List(Vec<StackValue<'a>>),
+  Loaded(SerializedVmValue),
+}
+
+/// Runs a query plan against a transaction and returns the fulfilled values, in order.
+pub async fn exec_query_plan(
+  schema: &CompiledSchema,
+  storage_plan: &StoragePlan,
+  txn: &dyn KvTransaction,
+  query_plan: &QueryPlan,
+  config: &VmValueEncodeConfig,
+) -> Result<Vec<SerializedVmValue>> {
+  let executor = QueryExecutor {
+    schema,
+    storage_plan,
+    txn,
+    config,
+  };
+  executor.run(query_plan).await
+}
+
+struct QueryExecutor<'a> {
+  schema: &'a CompiledSchema,
+  storage_plan: &'a StoragePlan,
+  txn: &'a dyn KvTransaction,
+  config: &'a VmValueEncodeConfig,
+}
+
+impl<'a> QueryExecutor<'a> {
+  async fn run(&self, query_plan: &QueryPlan) -> Result<Vec<SerializedVmValue>> {
+    let mut stack: Vec<StackValue<'a>> = vec![];
+    let mut output = vec![];
+
+    for step in &query_plan.steps {
+      log::trace!("query step: {:?}", step);
+      match step {
+        QueryStep::Const(x) => stack.push(StackValue::Primitive(x.clone())),
+        QueryStep::Param(i) => return Err(QueryExecError::UnboundParam(*i).into()),
+        QueryStep::Root(name) => stack.push(StackValue::Path(PathWalker::from_export(
+          self.storage_plan,
+          name,
+        )?)),
+        QueryStep::Field(name) => {
+          let value = pop(&mut stack)?;
+          stack.push(enter_field(value, name)?);
+        }
+        QueryStep::PointGet => {
+          let keys = pop_keys(&mut stack, step)?;
+          let value = pop(&mut stack)?;
+          stack.push(match keys {
+            Keys::One(Some(key)) => self.point_get(value, &key).await?,
+            Keys::One(None) => StackValue::Null,
+            Keys::Many(keys) => self.point_get_any(value, &keys).await?,
+          });
+        }
+        QueryStep::RangeScanKeys { reverse } => {
+          let value = pop(&mut stack)?;
+          stack.push(self.range_scan_keys(value, None, None, *reverse).await?);
+        }
+        QueryStep::RangeScan {
+          start,
+          end,
+          reverse,
+        } => {
+          let end = match end {
+            ScanBound::Unbounded => Some(None),
+            _ => pop_scan_key(&mut stack, step, *end == ScanBound::Included)?.map(Some),
+          };
+          let start = match start {
+            ScanBound::Unbounded => Some(None),
+            _ => pop_scan_key(&mut stack, step, *start == ScanBound::Excluded)?.map(Some),
+          };
+          let value = pop(&mut stack)?;
+          stack.push(match (start, end) {
+            (Some(start), Some(end)) => {
+              self
+                .range_scan_keys(value, start.as_ref(), end.as_ref(), *reverse)
+                .await?
+            }
+
+            // A bound from a subquery that returned null matches nothing.
+            _ => StackValue::Null,
+          });
+        }
+        QueryStep::SortKeyScan { start, end } => {
+          let end = match end {
+            ScanBound::Unbounded => Some(None),
+            _ => pop_scan_key(&mut stack, step, *end == ScanBound::Included)?.map(Some),
+          };
+          let start = match start {
+            ScanBound::Unbounded => Some(None),
+            _ => pop_scan_key(&mut stack, step, *start == ScanBound::Excluded)?.map(Some),
+          };
+          let value = pop(&mut stack)?;
+          stack.push(match (start, end) {
+            (Some(start), Some(end)) => {
+              self
+                .sort_key_scan(value, start.as_ref(), end.as_ref())
+                .await?
+            }
+            _ => StackValue::Null,
+          });
+        }
+        QueryStep::PrefixScan { by_sort_key } => {
+          let prefix = pop_scan_key(&mut stack, step, false)?;
+          let value = pop(&mut stack)?;
+          stack.push(match prefix {
+            Some(prefix) => {
+              let (start, end) = prefix.into_prefix_bounds();
+              if *by_sort_key {
+                self.sort_key_scan(value, Some(&start), Some(&end)).await?
+              } else {
+                self
+                  .range_scan_keys(value, Some(&start), Some(&end), false)
+                  .await?
+              }
+            }
+            None => StackValue::Null,
+          });
+        }
+        QueryStep::FilterBy(field, op) => {
+          let operands = pop_keys(&mut stack, step)?;
+          let value = pop(&mut stack)?;
+          stack.push(
+            self
+              .filter_by(value, field, *op, operands.as_slice())
+              .await?,
+          );
+        }
+        QueryStep::SkipDeleted => {
+          let value = pop(&mut stack)?;
+          stack.push(self.skip_deleted(value).await?);
+        }
+        QueryStep::LensGet(ty) => {
+          let value = pop(&mut stack)?;
+          stack.push(StackValue::Loaded(self.lens_get(value, ty).await?));
+        }
+        QueryStep::LoadPrimitive => {
+          let value = pop(&mut stack)?;
+          stack.push(self.load_primitive(value).await?);
+        }
+        QueryStep::ConstObject(x) => stack.push(StackValue::Object(x.clone())),
+        QueryStep::PointPut(ty) => {
+          let value = pop_value(&mut stack, step)?;
+          let key = pop_primitive(&mut stack, step)?;
+          let set = pop(&mut stack)?;
+          self.point_put(set, &key, ty, &value).await?;
+        }
+        QueryStep::LensPut { ty, touch } => {
+          let value = pop_value(&mut stack, step)?;
+          let target = pop(&mut stack)?;
+          self.lens_put(target, ty, *touch, &value).await?;
+        }
+        QueryStep::PointDelete(ty) => {
+          let keys = pop_keys(&mut stack, step)?;
+          let set = pop(&mut stack)?;
+          self.point_delete(set, ty, keys.as_slice()).await?;
+        }
+        QueryStep::Aggregate(func) => {
+          let value = pop(&mut stack)?;
+          stack.push(StackValue::Loaded(self.aggregate(value, *func).await?));
+        }
+        QueryStep::Fulfill => match pop(&mut stack)? {
+          StackValue::Loaded(x) => output.push(x),
+          StackValue::Null => output.push(SerializedVmValue::Null(None)),
+          _ => return Err(QueryExecError::UnexpectedStackValue(format!("{:?}", step)).into()),
+        },
+      }
+    }
+
+    if !stack.is_empty() {
+      return Err(QueryExecError::UnbalancedStack.into());
+    }
+    Ok(output)
+  }
+
+  #[async_recursion]
+  async fn point_get(&self, set: StackValue<'a>, key: &PrimitiveValue) -> Result<StackValue<'a>> {
+    Ok(match set {
+      StackValue::Null => StackValue::Null,
+      StackValue::Path(walker) => {
+        let walker = walker.enter_set(key)?;
+
+        // Set members always have their table key written.
+        if self.txn.get(walker.key()).await?.is_some() {
+          StackValue::Path(walker)
+        } else {
+          StackValue::Null
+        }
+      }
+      StackValue::List(members) => {
+        let mut out = Vec::with_capacity(members.len());
+        for x in members {
+          out.push(self.point_get(x, key).await?);
+        }
+        StackValue::List(out)
+      }
+      _ => return Err(QueryExecError::UnexpectedStackValue("PointGet".into()).into()),
+    })
+  }
+
+  /// Looks up multiple keys in a set, and pushes the list of members that exist.
+  #[async_recursion]
+  async fn point_get_any(
+    &self,
+    set: StackValue<'a>,
+    keys: &[PrimitiveValue],
+  ) -> Result<StackValue<'a>> {
+    Ok(match set {
+      StackValue::Null => StackValue::Null,
+      StackValue::Path(walker) => {
+        let mut out = vec![];
+        for key in keys {
+          let member = self
+            .point_get(StackValue::Path(walker.clone()), key)
+            .await?;
+          if !matches!(member, StackValue::Null) {
+            out.push(member);
+          }
+        }
+        StackValue::List(out)
+      }
+      StackValue::List(sets) => {
+        let mut out = Vec::with_capacity(sets.len());
+        for x in sets {
+          out.push(self.point_get_any(x, keys).await?);
+        }
+        StackValue::List(out)
+      }
+      _ => return Err(QueryExecError::UnexpectedStackValue("PointGet".into()).into()),
+    })
+  }
+
+  /// Scans the members of a set. `start` and `end` are the encoded primary key bounds, inclusive
+  /// and exclusive respectively. Empty bounds are unbounded.
+  #[async_recursion]
+  async fn range_scan_keys(
+    &self,
+    set: StackValue<'a>,
+    start: Option<&ScanKey>,
+    end: Option<&ScanKey>,
+    reverse: bool,
+  ) -> Result<StackValue<'a>> {
+    Ok(match set {
+      StackValue::Null => StackValue::Null,
+      StackValue::Path(walker) => {
+        let range_prefix = walker.set_fast_scan_prefix()?;
+        let mut range_start = range_prefix.clone();
+        if let Some(start) = start {
+          range_start.extend_from_slice(&start.encode_primary_key(&walker));
+        }
+        let range_end = match end {
+          Some(end) => [&range_prefix[..], &end.encode_primary_key(&walker)[..]].concat(),
+          None => successor_of_bounded_prefix(&range_prefix),
+        };
+
+        let mut members = vec![];
+        let mut it = self
+          .txn
+          .scan(
+            &range_start,
+            &range_end,
+            &ScanOptions {
+              reverse,
+              ..Default::default()
+            },
+          )
+          .await?;
+        while let Some((k, _)) = it.next().await? {
+          let k = k.strip_prefix(range_prefix.as_slice()).unwrap();
+          members.push(StackValue::Path(walker.enter_set_raw(k)?));
+        }
+        StackValue::List(members)
+      }
+      StackValue::List(sets) => {
+        let mut out = Vec::with_capacity(sets.len());
+        for x in sets {
+          out.push(self.range_scan_keys(x, start, end, reverse).await?);
+        }
+        StackValue::List(out)
+      }
+      _ => return Err(QueryExecError::UnexpectedStackValue("RangeScanKeys".into()).into()),
+    })
+  }
+
+  /// Scans the members of a set in the order of their sort key, between the sort key bounds
+  /// `start` and `end`. Missing bounds are unbounded.
+  #[async_recursion]
+  async fn sort_key_scan(
+    &self,
+    set: StackValue<'a>,
+    start: Option<&ScanKey>,
+    end: Option<&ScanKey>,
+  ) -> Result<StackValue<'a>> {
+    Ok(match set {
+      StackValue::Null => StackValue::Null,
+      StackValue::Path(walker) => {
+        if !backfill::sort_index_ready(self.txn, &walker).await? {
+          return Err(QueryExecError::SortIndexNotReady(walker.generate_key_pretty()).into());
+        }
+        let range_prefix = walker.set_sort_key_prefix()?;
+        let mut range_start = range_prefix.clone();
+        match start {
+          Some(start) => range_start.extend_from_slice(&start.encode_sort_key(&walker)),
+
+          // Skip members with a null sort key: they never match a comparison.
+          None => range_start.push(0x01),
+        }
+        let range_end = match end {
+          Some(end) => [&range_prefix[..], &end.encode_sort_key(&walker)[..]].concat(),
+          None => successor_of_bounded_prefix(&range_prefix),
+        };
+
+        let mut members = vec![];
+        let mut it = self.txn.scan_keys(&range_start, &range_end).await?;
+        while let Some(k) = it.next().await? {
+          let k = k.strip_prefix(range_prefix.as_slice()).unwrap();
+          let (_, primary_key) =
+            split_sort_key_entry(k).ok_or_else(|| QueryExecError::BadSortKeyEntry)?;
+          members.push(StackValue::Path(walker.enter_set_raw(primary_key)?));
+        }
+        StackValue::List(members)
+      }
+      StackValue::List(sets) => {
+        let mut out = Vec::with_capacity(sets.len());
+        for x in sets {
+          out.push(self.sort_key_scan(x, start, end).await?);
+        }
+        StackValue::List(out)
+      }
+      _ => return Err(QueryExecError::UnexpectedStackValue("SortKeyScan".into()).into()),
+    })
+  }
+
+  #[async_recursion]
+  async fn filter_by(
+    &self,
+    value: StackValue<'a>,
+    field: &str,
+    op: CompareOp,
+    operands: &[PrimitiveValue],
+  ) -> Result<StackValue<'a>> {
+    Ok(match value {
+      StackValue::Null => StackValue::Null,
+      StackValue::Path(walker) => {
+        let field_value: Option<PrimitiveValue> =
+          get_with_rename_fallback(self.txn, &walker.enter_field(field)?)
+            .await?
+            .map(PrimitiveValue::decode_stored)
+            .transpose()?;
+
+        // Missing fields never match. With multiple operands, any of them can match.
+        match field_value {
+          Some(x) if operands.iter().any(|y| predicate_holds(op, &x, y)) => {
+            StackValue::Path(walker)
+          }
+          _ => StackValue::Null,
+        }
+      }
+      StackValue::List(members) => {
+        let mut out = Vec::with_capacity(members.len());
+        for x in members {
+          let is_member = matches!(x, StackValue::Path(_));
+          let x = self.filter_by(x, field, op, operands).await?;
+          if is_member && matches!(x, StackValue::Null) {
+            continue;
+          }
+          out.push(x);
+        }
+        StackValue::List(out)
+      }
+      _ => return Err(QueryExecError::UnexpectedStackValue("FilterBy".into()).into()),
+    })
+  }
+
+  /// Drops the deleted members of a `@soft_delete` set.
+  #[async_recursion]
+  async fn skip_deleted(&self, value: StackValue<'a>) -> Result<StackValue<'a>> {
+    Ok(match value {
+      StackValue::Null => StackValue::Null,
+      StackValue::Path(walker) => {
+        let deleted_at = walker.enter_field(DELETED_AT_FIELD)?;
+        if self.txn.get(deleted_at.key()).await?.is_some() {
+          StackValue::Null
+        } else {
+          StackValue::Path(walker)
+        }
+      }
+      StackValue::List(members) => {
+        let mut out = Vec::with_capacity(members.len());
+        for x in members {
+          let is_member = matches!(x, StackValue::Path(_));
+          let x = self.skip_deleted(x).await?;
+          if is_member && matches!(x, StackValue::Null) {
+            continue;
+          }
+          out.push(x);
+        }
+        StackValue::List(out)
+      }
+      _ => return Err(QueryExecError::UnexpectedStackValue("SkipDeleted".into()).into()),
+    })
+  }
+
+  #[async_recursion]
+  async fn load_primitive(&self, value: StackValue<'a>) -> Result<StackValue<'a>> {
+    Ok(match value {
+      StackValue::Null => StackValue::Null,
+      StackValue::Path(walker) if walker.node().blob => {
+        match blob::read_blob(self.txn, &walker).await? {
+          Some(x) => StackValue::Primitive(PrimitiveValue::Bytes(x)),
+          None => StackValue::Null,
+        }
+      }
+      StackValue::Path(walker) => match get_with_rename_fallback(self.txn, &walker).await? {
+        Some(x) => StackValue::Primitive(PrimitiveValue::decode_stored(x)?),
+        None => StackValue::Null,
+      },
+      StackValue::List(values) => {
+        let mut out = Vec::with_capacity(values.len());
+        for x in values {
+          out.push(self.load_primitive(x).await?);
+        }
+        StackValue::List(out)
+      }
+      _ => return Err(QueryExecError::UnexpectedStackValue("LoadPrimitive".into()).into()),
+    })
+  }
+
+  #[async_recursion]
+  async fn lens_get(&self, value: StackValue<'a>, ty: &FieldType) -> Result<SerializedVmValue> {
+    Ok(match value {
+      StackValue::Null => SerializedVmValue::Null(None),
+      StackValue::Path(walker) => self.load(&walker, ty).await?,
+      StackValue::List(members) => {
+        let mut out = Vec::with_capacity(members.len());
+        for x in members {
+          out.push(self.lens_get(x, ty).await?);
+        }
+        SerializedVmValue::Tagged(TaggedVmValue::L(out))
+      }
+      _ => return Err(QueryExecError::UnexpectedStackValue("LensGet".into()).into()),
+    })
+  }
+
+  /// Folds the values under a path or a list of paths. `count` counts the paths without loading
+  /// them. Other aggregates are null if there are no values.
+  async fn aggregate(&self, value: StackValue<'a>, func: AggregateFn) -> Result<SerializedVmValue> {
+    let mut walkers = vec![];
+    flatten_paths(value, &mut walkers)?;
+    if func == AggregateFn::Count {
+      return SerializedVmValue::encode(
+        &VmValue::Primitive(PrimitiveValue::Int64(walkers.len() as i64)),
+        self.config,
+      );
+    }
+
+    let mut acc: Option<PrimitiveValue> = None;
+    for walker in walkers {
+      let x = match get_with_rename_fallback(self.txn, &walker).await? {
+        Some(x) => PrimitiveValue::decode_stored(x)?,
+        None => continue,
+      };
+      acc = Some(match (acc, func) {
+        (None, _) => x,
+        (Some(PrimitiveValue::Int64(a)), AggregateFn::Sum) => match x {
+          PrimitiveValue::Int64(b) => {
+            PrimitiveValue::Int64(a.checked_add(b).ok_or(QueryExecError::SumOverflow)?)
+          }
+          _ => return Err(QueryExecError::ValueTypeMismatch("int64".into()).into()),
+        },
+        (Some(PrimitiveValue::Double(a)), AggregateFn::Sum) => match x {
+          PrimitiveValue::Double(b) => {
+            PrimitiveValue::double(f64::from_bits(a) + f64::from_bits(b))
+          }
+          _ => return Err(QueryExecError::ValueTypeMismatch("double".into()).into()),
+        },
+        (Some(a), AggregateFn::Min) if compare_primitive(&x, &a) == Ordering::Less => x,
+        (Some(a), AggregateFn::Max) if compare_primitive(&x, &a) == Ordering::Greater => x,
+        (Some(a), AggregateFn::Min) | (Some(a), AggregateFn::Max) => a,
+        (Some(a), _) => {
+          return Err(QueryExecError::ValueTypeMismatch(a.get_type().to_string()).into())
+        }
+      });
+    }
+    match acc {
+      Some(x) => SerializedVmValue::encode(&VmValue::Primitive(x), self.config),
+      None => Ok(SerializedVmValue::Null(None)),
+    }
+  }
+
+  /// Loads the value under a path. Tables are loaded with all of their primitive, struct and
+  /// table fields. Sets and maps are not loaded, and lists are only loaded when directly selected.
+  #[async_recursion]
+  async fn load(&self, walker: &Arc<PathWalker<'a>>, ty: &FieldType) -> Result<SerializedVmValue> {
+    match ty {
+      FieldType::Primitive(_) if walker.node().blob => {
+        match blob::read_blob(self.txn, walker).await? {
+          Some(x) => {
+            SerializedVmValue::encode(&VmValue::Primitive(PrimitiveValue::Bytes(x)), self.config)
+          }
+          None => Ok(SerializedVmValue::Null(None)),
+        }
+      }
+      FieldType::Primitive(_) => {
+        let raw_data: Option<PrimitiveValue> = get_with_rename_fallback(self.txn, walker)
+          .await?
+          .map(PrimitiveValue::decode_stored)
+          .transpose()?;
+        match raw_data {
+          Some(x) => SerializedVmValue::encode(&VmValue::Primitive(x), self.config),
+          None => Ok(SerializedVmValue::Null(None)),
+        }
+      }
+      FieldType::Struct(_) => {
+        let raw_data: Option<PackedValue> = get_with_rename_fallback(self.txn, walker)
+          .await?
+          .map(|x| rmp_serde::from_slice(&x))
+          .transpose()?;
+        match raw_data {
+          Some(x) => {
+            SerializedVmValue::encode(&VmValue::unpack(&VmType::from(ty), &x), self.config)
+          }
+          None => Ok(SerializedVmValue::Null(None)),
+        }
+      }
+      FieldType::List(member_ty) => {
+        let len: u64 = match self.txn.get(walker.key()).await? {
+          Some(x) => rmp_serde::from_slice(&x)?,
+          None => return Ok(SerializedVmValue::Null(None)),
+        };
+        let mut elements = Vec::with_capacity(len as usize);
+        for i in 0..len {
+          elements.push(self.load(&walker.enter_list(i)?, member_ty).await?);
+        }
+        Ok(SerializedVmValue::Tagged(TaggedVmValue::L(elements)))
+      }
+      FieldType::Table(name) => {
+        let specialized_ty = self
+          .schema
+          .types
+          .get(name)
+          .ok_or_else(|| QueryExecError::TypeNotFound(name.to_string()))?;
+        let mut fields = BTreeMap::new();
+        for (field_name, (field_ty, _)) in &specialized_ty.fields {
+          let field_walker = walker.enter_field(field_name)?;
+          let value = match field_ty {
+            FieldType::Primitive(_) | FieldType::Struct(_) => {
+              self.load(&field_walker, field_ty).await?
+            }
+            FieldType::Table(_) => {
+              // Nested tables always have their table key written. Checking it here also stops
+              // the recursion on recursive types.
+              if self.txn.get(field_walker.key()).await?.is_some() {
+                self.load(&field_walker, field_ty).await?
+              } else {
+                SerializedVmValue::Null(None)
+              }
+            }
+            FieldType::Set(_) | FieldType::List(_) | FieldType::Map(_) => continue,
+          };
+          fields.insert(field_name.to_string(), value);
+        }
+        Ok(SerializedVmValue::Tagged(TaggedVmValue::M(fields)))
+      }
+      FieldType::Set(_) | FieldType::Map(_) => {
+        Err(QueryExecError::UnexpectedStackValue("LensGet".into()).into())
+      }
+    }
+  }
+
+  #[async_recursion]
+  async fn point_put(
+    &self,
+    set: StackValue<'a>,
+    key: &PrimitiveValue,
+    ty: &FieldType,
+    value: &Value,
+  ) -> Result<()> {
+    match set {
+      StackValue::Null => {}
+      StackValue::Path(walker) => {
+        let key = walker.encode_primary_key(key);
+        let mut fast_scan_key = walker.set_fast_scan_prefix()?;
+        fast_scan_key.extend_from_slice(&key);
+        self.txn.put(&fast_scan_key, &[]).await?;
+
+        if let (Some(sort_key), Value::Object(fields)) = (self.sort_key_of(ty), value) {
+          let old_value = self.delete_sort_key_entry(&walker, &key, sort_key).await?;
+
+          // Fields not present in the object are left untouched, and so is the sort key value.
+          let new_value = match fields.get(sort_key) {
+            Some(Value::Primitive(x)) => Some(x),
+            Some(_) => return Err(QueryExecError::ValueTypeMismatch(ty.to_string()).into()),
+            None => old_value.as_ref(),
+          };
+          let entry = walker.set_sort_key_entry(new_value, &key)?;
+          self.txn.put(&entry, &[]).await?;
+        }
+        self.write(walker.enter_set_raw(&key)?, ty, value).await?;
+      }
+      StackValue::List(sets) => {
+        for x in sets {
+          self.point_put(x, key, ty, value).await?;
+        }
+      }
+      _ => return Err(QueryExecError::UnexpectedStackValue("PointPut".into()).into()),
+    }
+    Ok(())
+  }
+
+  #[async_recursion]
+  async fn lens_put(
+    &self,
+    target: StackValue<'a>,
+    ty: &FieldType,
+    touch: bool,
+    value: &Value,
+  ) -> Result<()> {
+    match target {
+      StackValue::Null => {}
+      StackValue::Path(walker) => {
+        self.write(walker.clone(), ty, value).await?;
+        if touch {
+          let table = walker
+            .parent()
+            .ok_or_else(|| QueryExecError::UnexpectedStackValue("LensPut".into()))?;
+          self.touch_timestamps(table, false).await?;
+        }
+      }
+      StackValue::List(targets) => {
+        for x in targets {
+          self.lens_put(x, ty, touch, value).await?;
+        }
+      }
+      _ => return Err(QueryExecError::UnexpectedStackValue("LensPut".into()).into()),
+    }
+    Ok(())
+  }
+
+  #[async_recursion]
+  async fn point_delete(
+    &self,
+    set: StackValue<'a>,
+    ty: &FieldType,
+    keys: &[PrimitiveValue],
+  ) -> Result<()> {
+    match set {
+      StackValue::Null => {}
+      StackValue::Path(walker) => {
+        let soft_delete = self.has_soft_delete(ty);
+        for key in keys {
+          let key = walker.encode_primary_key(key);
+          if soft_delete {
+            self.soft_delete(&walker, &key).await?;
+            continue;
+          }
+          if let Some(sort_key) = self.sort_key_of(ty) {
+            self.delete_sort_key_entry(&walker, &key, sort_key).await?;
+          }
+          let mut fast_scan_key = walker.set_fast_scan_prefix()?;
+          fast_scan_key.extend_from_slice(&key);
+
+          let mut data_start_key = walker.set_data_prefix()?;
+          data_start_key.extend_from_slice(&key);
+          data_start_key.push(0x00);
+          let data_end_key = successor_of_bounded_prefix(&data_start_key);
+
+          self.txn.delete(&fast_scan_key).await?;
+          self
+            .txn
+            .delete_range(&data_start_key, &data_end_key)
+            .await?;
+        }
+      }
+      StackValue::List(sets) => {
+        for x in sets {
+          self.point_delete(x, ty, keys).await?;
+        }
+      }
+      _ => return Err(QueryExecError::UnexpectedStackValue("PointDelete".into()).into()),
+    }
+    Ok(())
+  }
+
+  /// Marks a member of a `@soft_delete` set as deleted, if it exists and is not deleted yet. Like
+  /// in the treewalker, the member keeps its fast scan key and sort key entry.
+  async fn soft_delete(&self, walker: &Arc<PathWalker<'a>>, primary_key: &[u8]) -> Result<()> {
+    let member = walker.enter_set_raw(primary_key)?;
+    if self.txn.get(member.key()).await?.is_none() {
+      return Ok(());
+    }
+    let deleted_at = member.enter_field(DELETED_AT_FIELD)?;
+    if self.txn.get(deleted_at.key()).await?.is_none() {
+      let now = PrimitiveValue::Int64(current_millis()).encode_stored();
+      self.txn.put(deleted_at.key(), &now).await?;
+    }
+    Ok(())
+  }
+
+  fn has_soft_delete(&self, ty: &FieldType) -> bool {
+    match ty {
+      FieldType::Table(x) => self
+        .schema
+        .types
+        .get(x)
+        .map(|x| x.has_soft_delete())
+        .unwrap_or(false),
+      _ => false,
+    }
+  }
+
+  fn sort_key_of(&self, ty: &FieldType) -> Option<&'a str> {
+    match ty {
+      FieldType::Table(x) => self.schema.types.get(x)?.sort_key().map(|x| x.0),
+      _ => None,
+    }
+  }
+
+  /// Deletes the sort key entry of a set member for its currently stored sort key value, and
+  /// returns that value.
+  async fn delete_sort_key_entry(
+    &self,
+    walker: &Arc<PathWalker<'a>>,
+    primary_key: &[u8],
+    sort_key: &str,
+  ) -> Result<Option<PrimitiveValue>> {
+    let field_walker = walker.enter_set_raw(primary_key)?.enter_field(sort_key)?;
+    let old_value: Option<PrimitiveValue> = get_with_rename_fallback(self.txn, &field_walker)
+      .await?
+      .map(PrimitiveValue::decode_stored)
+      .transpose()?;
+    let entry = walker.set_sort_key_entry(old_value.as_ref(), primary_key)?;
+    self.txn.delete(&entry).await?;
+    Ok(old_value)
+  }
+
+  /// Sets `updated_at` of the `@timestamps` table at `walker` to the current time. With `created`,
+  /// also sets `created_at` if the table has none yet.
+  async fn touch_timestamps(&self, walker: &Arc<PathWalker<'a>>, created: bool) -> Result<()> {
+    let now = PrimitiveValue::Int64(current_millis()).encode_stored();
+    if created {
+      let created_at = walker.enter_field(CREATED_AT_FIELD)?;
+      if self.txn.get(created_at.key()).await?.is_none() {
+        self.txn.put(created_at.key(), &now).await?;
+      }
+    }
+    let updated_at = walker.enter_field(UPDATED_AT_FIELD)?;
+    self.txn.put(updated_at.key(), &now).await?;
+    Ok(())
+  }
+
+  /// Checks the constraints of a table type against the fields written, merged with the stored
+  /// fields that the object leaves untouched.
+  async fn check_constraints(
+    &self,
+    walker: &Arc<PathWalker<'a>>,
+    ty: &str,
+    checks: &[CheckPredicate],
+    fields: &BTreeMap<String, Value>,
+  ) -> Result<()> {
+    for check in checks {
+      let mut values = BTreeMap::new();
+      for name in check.referenced_fields() {
+        let value = match fields.get(name) {
+          Some(Value::Primitive(x)) => Some(x.clone()),
+          Some(Value::Object(_)) => None,
+          None => get_with_rename_fallback(self.txn, &walker.enter_field(name)?)
+            .await?
+            .map(PrimitiveValue::decode_stored)
+            .transpose()?,
+        };
+        if let Some(value) = value {
+          values.insert(name, value);
+        }
+      }
+      if check.is_violated_by(&|name: &str| values.get(name).cloned()) {
+        return Err(
+          QueryExecError::ConstraintViolation {
+            ty: ty.to_string(),
+            check: check.to_string(),
+          }
+          .into(),
+        );
+      }
+    }
+    Ok(())
+  }
+
+  /// Writes a value under a path. Like table inserts in the treewalker, fields not present in an
+  /// object are left untouched.
+  #[async_recursion]
+  async fn write(&self, walker: Arc<PathWalker<'a>>, ty: &FieldType, value: &Value) -> Result<()> {
+    if let Some(key) = walker.generate_rename_fallback_key() {
+      self.txn.delete(&key).await?;
+    }
+
+    match (ty, value) {
+      (FieldType::Primitive(_), Value::Primitive(PrimitiveValue::Bytes(x)))
+        if walker.node().blob =>
+      {
+        blob::write_blob(self.txn, &walker, x).await?;
+      }
+      (FieldType::Primitive(_), Value::Primitive(x)) => {
+        let value = x.encode_stored();
+        self.txn.put(walker.key(), &value).await?;
+      }
+      (FieldType::Struct(_), Value::Object(_)) => {
+        // Structs are replaced as a whole.
+        let value = rmp_serde::to_vec(&pack_value(value))?;
+        self.txn.put(walker.key(), &value).await?;
+      }
+      (FieldType::Table(name), Value::Object(fields)) => {
+        let specialized_ty = self
+          .schema
+          .types
+          .get(name)
+          .ok_or_else(|| QueryExecError::TypeNotFound(name.to_string()))?;
+        self
+          .check_constraints(&walker, name, &specialized_ty.checks, fields)
+          .await?;
+        self.txn.put(walker.key(), &[]).await?;
+        for (k, v) in fields {
+          let (field_ty, _) = specialized_ty
+            .fields
+            .get(k.as_str())
+            .ok_or_else(|| QueryExecError::ValueTypeMismatch(name.to_string()))?;
+          self.write(walker.enter_field(k)?, field_ty, v).await?;
+        }
+        if specialized_ty.has_timestamps() {
+          self.touch_timestamps(&walker, true).await?;
+        }
+
+        // Writing a deleted member restores it.
+        if specialized_ty.has_soft_delete() {
+          self
+            .txn
+            .delete(walker.enter_field(DELETED_AT_FIELD)?.key())
+            .await?;
+        }
+      }
+      _ => return Err(QueryExecError::ValueTypeMismatch(ty.to_string()).into()),
+    }
+    Ok(())
+  }
+}
+
+/// A bound of a range scan. With `skip`, the key itself is excluded from the start of the range,
+/// or included at the end of the range.
+///
+/// With `prefix`, the key is a string prefix: the bound is the first key with the prefix, or with
+/// `skip`, the first key after all keys with the prefix.
+///
+/// Bounds are encoded for each set they are applied to, with the collations of the set.
+struct ScanKey {
+  key: PrimitiveValue,
+  skip: bool,
+  prefix: bool,
+}
+
+impl ScanKey {
+  /// Returns the bounds of the range of keys starting with this key.
+  fn into_prefix_bounds(self) -> (ScanKey, ScanKey) {
+    (
+      ScanKey {
+        key: self.key.clone(),
+        skip: false,
+        prefix: true,
+      },
+      ScanKey {
+        key: self.key,
+        skip: true,
+        prefix: true,
+      },
+    )
+  }
+
+  /// Encodes the bound on the primary key of a set. Appending a zero byte yields a bound that
+  /// sorts after the key but before any other encoded key of the same type.
+  fn encode_primary_key(&self, walker: &PathWalker) -> Vec<u8> {
+    if self.prefix {
+      return self.finish_prefix(walker.encode_primary_key_prefix(&self.key).to_vec());
+    }
+    let mut key = walker.encode_primary_key(&self.key).to_vec();
+    if self.skip {
+      key.push(0x00);
+    }
+    key
+  }
+
+  /// Encodes the bound on the sort key of a set. 0xff is appended instead of a zero byte: sort key
+  /// entries continue with the primary key, which never starts with 0xff.
+  fn encode_sort_key(&self, walker: &PathWalker) -> Vec<u8> {
+    if self.prefix {
+      return self.finish_prefix(walker.encode_sort_key_prefix(&self.key).to_vec());
+    }
+    let mut key = walker.encode_sort_key(&self.key).to_vec();
+    if self.skip {
+      key.push(0xff);
+    }
+    key
+  }
+
+  /// Encoded prefixes start with a type tag, which is never 0xff, so they always have a successor.
+  fn finish_prefix(&self, key: Vec<u8>) -> Vec<u8> {
+    if self.skip {
+      successor_of_bounded_prefix(&key)
+    } else {
+      key
+    }
+  }
+}
+
+/// Pops a range scan bound, or returns `None` if a subquery for the bound returned null.
+fn pop_scan_key(
+  stack: &mut Vec<StackValue>,
+  step: &QueryStep,
+  skip: bool,
+) -> Result<Option<ScanKey>> {
+  let key = match pop_keys(stack, step)? {
+    Keys::One(Some(x)) => x,
+    Keys::One(None) => return Ok(None),
+    Keys::Many(_) => {
+      return Err(QueryExecError::UnexpectedStackValue(format!("{:?}", step)).into())
+    }
+  };
+  Ok(Some(ScanKey {
+    key,
+    skip,
+    prefix: false,
+  }))
+}
+
+/// Key operands of a step: a literal, or the result of a subquery.
+enum Keys {
+  /// A single key, or `None` if a single-valued subquery returned null.
+  One(Option<PrimitiveValue>),
+
+  /// The values of a multi-valued subquery, without nulls.
+  Many(Vec<PrimitiveValue>),
+}
+
+impl Keys {
+  fn as_slice(&self) -> &[PrimitiveValue] {
+    match self {
+      Self::One(Some(x)) => std::slice::from_ref(x),
+      Self::One(None) => &[],
+      Self::Many(x) => x,
+    }
+  }
+}
+
+fn pop_keys(stack: &mut Vec<StackValue>, step: &QueryStep) -> Result<Keys> {
+  fn flatten(value: StackValue, out: &mut Vec<PrimitiveValue>) -> bool {
+    match value {
+      StackValue::Null => true,
+      StackValue::Primitive(x) => {
+        out.push(x);
+        true
+      }
+      StackValue::List(xs) => xs.into_iter().all(|x| flatten(x, out)),
+      _ => false,
+    }
+  }
+
+  Ok(match pop(stack)? {
+    StackValue::Primitive(x) => Keys::One(Some(x)),
+    StackValue::Null => Keys::One(None),
+    StackValue::List(xs) => {
+      let mut keys = vec![];
+      if !xs.into_iter().all(|x| flatten(x, &mut keys)) {
+        return Err(QueryExecError::UnexpectedStackValue(format!("{:?}", step)).into());
+      }
+      Keys::Many(keys)
+    }
+    _ => return Err(QueryExecError::UnexpectedStackValue(format!("{:?}", step)).into()),
+  })
+}
+
+/// Returns whether a field value satisfies a predicate against an operand.
+fn predicate_holds(op: CompareOp, value: &PrimitiveValue, operand: &PrimitiveValue) -> bool {
+  match (op, value, operand) {
+    (CompareOp::StartsWith, PrimitiveValue::String(x), PrimitiveValue::String(y)) => {
+      x.starts_with(y.as_str())
+    }
+    (CompareOp::StartsWith, _, _) => false,
+    _ => op.matches(compare_primitive(value, operand)),
+  }
+}
+
+/// Compares two primitive values of the same type, in the order of their key encoding.
+fn compare_primitive(a: &PrimitiveValue, b: &PrimitiveValue) -> Ordering {
+  keyenc::encode_key(a).cmp(&keyenc::encode_key(b))
+}
+
+fn pack_value(value: &Value) -> PackedValue {
+  match value {
+    Value::Primitive(x) => PackedValue::P(x.clone()),
+    Value::Object(x) => PackedValue::M(x.iter().map(|(k, v)| (k.clone(), pack_value(v))).collect()),
+  }
+}
+
+fn flatten_paths<'a>(value: StackValue<'a>, out: &mut Vec<Arc<PathWalker<'a>>>) -> Result<()> {
+  match value {
+    StackValue::Null => {}
+    StackValue::Path(x) => out.push(x),
+    StackValue::List(xs) => {
+      for x in xs {
+        flatten_paths(x, out)?;
+      }
+    }
+    _ => return Err(QueryExecError::UnexpectedStackValue("Aggregate".into()).into()),
+  }
+  Ok(())
+}
+
+fn pop_primitive(stack: &mut Vec<StackValue>, step: &QueryStep) -> Result<PrimitiveValue> {
+  match pop(stack)? {
+    StackValue::Primitive(x) => Ok(x),
+    _ => Err(QueryExecError::UnexpectedStackValue(format!("{:?}", step)).into()),
+  }
+}
+
+fn pop_value(stack: &mut Vec<StackValue>, step: &QueryStep) -> Result<Value> {
+  match pop(stack)? {
+    StackValue::Primitive(x) => Ok(Value::Primitive(x)),
+    StackValue::Object(x) => Ok(Value::Object(x)),
+    _ => Err(QueryExecError::UnexpectedStackValue(format!("{:?}", step)).into()),
+  }
+}
+
+fn pop<'a>(stack: &mut Vec<StackValue<'a>>) -> Result<StackValue<'a>> {
+  stack
+    .pop()
+    .ok_or_else(|| QueryExecError::StackUnderflow.into())
+}
+
+fn enter_field<'a>(value: StackValue<'a>, name: &str) -> Result<StackValue<'a>> {
+  Ok(match value {
+    StackValue::Null => StackValue::Null,
+    StackValue::Path(walker) => StackValue::Path(walker.enter_field(name)?),
+    StackValue::List(members) => StackValue::List(
+      members
+        .into_iter()
+        .map(|x| enter_field(x, name))
+        .collect::<Result<_>>()?,
+    ),
+    _ => return Err(QueryExecError::UnexpectedStackValue("Field".into()).into()),
+  })
+}
@@ -0,0 +1,683 @@
+use std::{sync::Arc, time::Duration};
+
+use bumpalo::Bump;
+
+use crate::{
+  data::{
+    kv::KeyValueStore,
+    mock_kv::MockKv,
+    treewalker::{
+      asm::codegen::compile_twscript,
+      exec::{generate_root_map, Executor},
+      serialize::{SerializedVmValue, VmValueEncodeConfig},
+      typeck::GlobalTyckContext,
+      vm::TwVm,
+    },
+    value::{PrimitiveValue, COMPRESSION_THRESHOLD},
+  },
+  schema::{
+    compile::{compile, CompiledSchema},
+    grammar::parse,
+  },
+  storage_plan::{planner::generate_plan_for_schema, StoragePlan},
+};
+
+use super::{
+  exec::exec_query_plan,
+  lower::to_treewalker,
+  parser::parse_statement,
+  planner::{QueryPlanner, QueryStep},
+};
+
+const SCHEMA: &str = r#"
+type Item {
+  @primary
+  id: int64,
+  name: string,
+  inner: Inner,
+  tags: set<Tag>,
+}
+type Inner {
+  value: string,
+}
+type Tag {
+  @primary
+  name: string,
+}
+type Event {
+  @primary
+  id: int64,
+  @sort_key
+  at: int64,
+}
+@timestamps
+type Note {
+  @primary
+  id: int64,
+  text: string,
+}
+export set<Item> items;
+export set<Event> events;
+@soft_delete
+type Doc {
+  @primary
+  id: int64,
+  name: string,
+}
+export set<Note> notes;
+export set<Doc> docs;
+"#;
+
+const WRITER: &str = r#"
+graph main(root: schema) {
+  s_insert root.items $ build_table(Item)
+    $ m_insert(id) 1
+    $ m_insert(name) "first"
+    $ m_insert(inner) (build_table(Inner) $ m_insert(value) "inner_1" create_map)
+    create_map;
+  s_insert root.items $ build_table(Item)
+    $ m_insert(id) 2
+    $ m_insert(name) "second"
+    $ m_insert(inner) (build_table(Inner) $ m_insert(value) "inner_2" create_map)
+    create_map;
+  s_insert (point_get root.items 1).tags $ build_table(Tag) $ m_insert(name) "a" create_map;
+  s_insert (point_get root.items 1).tags $ build_table(Tag) $ m_insert(name) "b" create_map;
+}
+"#;
+
+struct Fixture {
+  schema: CompiledSchema,
+  plan: StoragePlan,
+  kv: MockKv,
+}
+
+async fn fixture() -> Fixture {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(&alloc, SCHEMA).unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let f = Fixture {
+    schema,
+    plan,
+    kv: MockKv::new(),
+  };
+  write(&f, WRITER).await;
+  f
+}
+
+async fn write(f: &Fixture, writer: &str) {
+  let script = compile_twscript(writer).unwrap();
+  let vm = TwVm::new(&f.schema, &f.plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let mut executor = Executor::new(&vm, &f.kv, &type_info);
+  executor
+    .run_graph(
+      0,
+      &[Arc::new(generate_root_map(&f.schema, &f.plan).unwrap())],
+    )
+    .await
+    .unwrap();
+}
+
+async fn run_statements(f: &Fixture, queries: &[&str]) -> Vec<serde_json::Value> {
+  run_planned(f, QueryPlanner::new(&f.schema), queries).await
+}
+
+async fn run_planned(
+  f: &Fixture,
+  mut planner: QueryPlanner<'_>,
+  queries: &[&str],
+) -> Vec<serde_json::Value> {
+  for q in queries {
+    planner.add_statement(&parse_statement(q).unwrap()).unwrap();
+  }
+  let query_plan = planner.finish().unwrap();
+  let txn = f.kv.begin_transaction().await.unwrap();
+  let output = exec_query_plan(
+    &f.schema,
+    &f.plan,
+    &*txn,
+    &query_plan,
+    &VmValueEncodeConfig {
+      enable_int64: true,
+      ..Default::default()
+    },
+  )
+  .await
+  .unwrap();
+  txn.commit().await.unwrap();
+  output
+    .iter()
+    .map(|x| serde_json::to_value(x).unwrap())
+    .collect()
+}
+
+#[tokio::test]
+async fn point_get() {
+  let f = fixture().await;
+  let output = run_statements(
+    &f,
+    &[
+      ".items[id = 1].name",
+      ".items[id = 2].inner.value",
+      ".items[id = 3].name",
+      ".items[id = 1].tags[name = \"b\"].name",
+      ".items[id = 1].tags[name = \"c\"].name",
+    ],
+  )
+  .await;
+  assert_eq!(
+    output,
+    vec![
+      serde_json::json!("first"),
+      serde_json::json!("inner_2"),
+      serde_json::json!(null),
+      serde_json::json!("b"),
+      serde_json::json!(null),
+    ]
+  );
+}
+
+#[tokio::test]
+async fn scan_and_load() {
+  let f = fixture().await;
+  let output = run_statements(&f, &[".items.name", ".items[id = 1]", ".items.tags.name"]).await;
+  assert_eq!(output[0], serde_json::json!({ "L": ["first", "second"] }));
+  assert_eq!(
+    output[1],
+    serde_json::json!({
+      "M": {
+        "id": 1,
+        "name": "first",
+        "inner": { "M": { "value": "inner_1" } },
+      }
+    })
+  );
+  assert_eq!(
+    output[2],
+    serde_json::json!({ "L": [{ "L": ["a", "b"] }, { "L": [] }] })
+  );
+}
+
+#[tokio::test]
+async fn writes() {
+  let f = fixture().await;
+  let output = run_statements(
+    &f,
+    &[
+      "insert .items { id: 3, name: \"third\", inner: { value: \"inner_3\" } }",
+      ".items[id = 1].name = \"first_updated\"",
+      ".items[id = 2].inner = { value: \"inner_2_updated\" }",
+      ".items[id = 4].name = \"missing\"",
+      "delete .items[id = 1].tags[name = \"a\"]",
+    ],
+  )
+  .await;
+  assert!(output.is_empty());
+
+  let output = run_statements(
+    &f,
+    &[
+      ".items.name",
+      ".items[id = 2].inner.value",
+      ".items[id = 3].inner.value",
+      ".items[id = 1].tags.name",
+    ],
+  )
+  .await;
+  assert_eq!(
+    output,
+    vec![
+      serde_json::json!({ "L": ["first_updated", "second", "third"] }),
+      serde_json::json!("inner_2_updated"),
+      serde_json::json!("inner_3"),
+      serde_json::json!({ "L": ["b"] }),
+    ]
+  );
+
+  run_statements(&f, &["delete .items[id = 2]"]).await;
+  let output = run_statements(&f, &[".items.name", ".items[id = 2].inner.value"]).await;
+  assert_eq!(
+    output,
+    vec![
+      serde_json::json!({ "L": ["first_updated", "third"] }),
+      serde_json::json!(null),
+    ]
+  );
+}
+
+#[tokio::test]
+async fn lowered_reads_match() {
+  let f = fixture().await;
+  let queries = [
+    ".items[id = 1].name",
+    ".items[id = 2].inner.value",
+    ".items[id = 3].name",
+    ".items[id = 1].tags[name = \"c\"]",
+    ".items.name",
+    ".items[id = 1]",
+    ".items.tags.name",
+  ];
+  let expected = run_statements(&f, &queries).await;
+
+  let mut planner = QueryPlanner::new(&f.schema);
+  for q in &queries {
+    planner.add_statement(&parse_statement(q).unwrap()).unwrap();
+  }
+  let script = to_treewalker(&f.schema, &planner.finish().unwrap()).unwrap();
+  let vm = TwVm::new(&f.schema, &f.plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let mut executor = Executor::new(&vm, &f.kv, &type_info);
+  let output = executor
+    .run_graph(
+      script.entry as usize,
+      &[Arc::new(generate_root_map(&f.schema, &f.plan).unwrap())],
+    )
+    .await
+    .unwrap()
+    .unwrap();
+  let output = SerializedVmValue::encode(
+    &output,
+    &VmValueEncodeConfig {
+      enable_int64: true,
+      ..Default::default()
+    },
+  )
+  .unwrap();
+  let output = serde_json::to_value(&output).unwrap();
+  for (i, expected) in expected.iter().enumerate() {
+    assert_eq!(&output["M"][format!("r{}", i)], expected, "{}", queries[i]);
+  }
+}
+
+#[tokio::test]
+async fn bound_params() {
+  let f = fixture().await;
+  let mut planner = QueryPlanner::new(&f.schema);
+  planner
+    .add_statement(&parse_statement(".items[id = $id].name").unwrap())
+    .unwrap();
+  let query_plan = planner.finish().unwrap();
+
+  let txn = f.kv.begin_transaction().await.unwrap();
+  assert!(
+    exec_query_plan(&f.schema, &f.plan, &*txn, &query_plan, &Default::default())
+      .await
+      .is_err()
+  );
+
+  for (id, name) in &[
+    (1, serde_json::json!("first")),
+    (2, serde_json::json!("second")),
+  ] {
+    let bound = query_plan.bind(&[PrimitiveValue::Int64(*id)]).unwrap();
+    let output = exec_query_plan(
+      &f.schema,
+      &f.plan,
+      &*txn,
+      &bound,
+      &VmValueEncodeConfig {
+        enable_int64: true,
+        ..Default::default()
+      },
+    )
+    .await
+    .unwrap();
+    assert_eq!(&serde_json::to_value(&output[0]).unwrap(), name);
+  }
+}
+
+#[tokio::test]
+async fn filters() {
+  let f = fixture().await;
+  let output = run_statements(
+    &f,
+    &[
+      ".items[id >= 1, id < 2].name",
+      ".items[id > 1].name",
+      ".items[id <= 2].name",
+      ".items[name = \"second\"].id",
+      ".items[id = 1, name = \"second\"]",
+      ".items.tags[name > \"a\"].name",
+    ],
+  )
+  .await;
+  assert_eq!(
+    output,
+    vec![
+      serde_json::json!({ "L": ["first"] }),
+      serde_json::json!({ "L": ["second"] }),
+      serde_json::json!({ "L": ["first", "second"] }),
+      serde_json::json!({ "L": [2] }),
+      serde_json::json!(null),
+      serde_json::json!({ "L": [{ "L": ["b"] }, { "L": [] }] }),
+    ]
+  );
+}
+
+#[tokio::test]
+async fn aggregates() {
+  let f = fixture().await;
+  let output = run_statements(
+    &f,
+    &[
+      ".items | count()",
+      ".items[id > 1] | count()",
+      ".items[id = 1] | count()",
+      ".items[id = 9] | count()",
+      ".items.tags | count()",
+      ".items | sum(.id)",
+      ".items | min(.id)",
+      ".items | max(.name)",
+      ".items | max(.inner.value)",
+      ".items[id > 5] | sum(.id)",
+    ],
+  )
+  .await;
+  assert_eq!(
+    output,
+    vec![
+      serde_json::json!(2),
+      serde_json::json!(1),
+      serde_json::json!(1),
+      serde_json::json!(0),
+      serde_json::json!(2),
+      serde_json::json!(3),
+      serde_json::json!(1),
+      serde_json::json!("second"),
+      serde_json::json!("inner_2"),
+      serde_json::json!(null),
+    ]
+  );
+}
+
+#[tokio::test]
+async fn aggregate_compressed_values() {
+  let f = fixture().await;
+  // Stored compressed, being longer than `COMPRESSION_THRESHOLD`.
+  let long_name = "z".repeat(COMPRESSION_THRESHOLD * 2);
+  write(
+    &f,
+    &format!(
+      r#"
+graph main(root: schema) {{
+  s_insert root.items $ build_table(Item)
+    $ m_insert(id) 3
+    $ m_insert(name) "{}"
+    $ m_insert(inner) (build_table(Inner) $ m_insert(value) "inner_3" create_map)
+    create_map;
+}}
+"#,
+      long_name
+    ),
+  )
+  .await;
+  let output = run_statements(
+    &f,
+    &[
+      ".items | max(.name)",
+      ".items[id = 3] | min(.name)",
+      ".items | min(.name)",
+    ],
+  )
+  .await;
+  assert_eq!(
+    output,
+    vec![
+      serde_json::json!(long_name),
+      serde_json::json!(long_name),
+      serde_json::json!("first"),
+    ]
+  );
+}
+
+#[tokio::test]
+async fn subqueries() {
+  let f = fixture().await;
+  let output = run_statements(
+    &f,
+    &[
+      ".items[id = .items[id = 2].id].inner.value",
+      ".items[id = .items[name = \"second\"].id].name",
+      ".items[name = .items[id = 1].name].id",
+      ".items[id > .items[id = 1].id].name",
+      ".items[id = .items[id = 9].id]",
+      ".items[id > .items[id = 9].id].name",
+    ],
+  )
+  .await;
+  assert_eq!(
+    output,
+    vec![
+      serde_json::json!("inner_2"),
+      serde_json::json!({ "L": ["second"] }),
+      serde_json::json!({ "L": [1] }),
+      serde_json::json!({ "L": ["second"] }),
+      serde_json::json!(null),
+      serde_json::json!(null),
+    ]
+  );
+}
+
+#[tokio::test]
+async fn sort_key_scans() {
+  let f = fixture().await;
+  run_statements(
+    &f,
+    &[
+      "insert .events { id: 1, at: 30 }",
+      "insert .events { id: 2, at: 10 }",
+      "insert .events { id: 3, at: 20 }",
+      "insert .events { id: 4 }",
+    ],
+  )
+  .await;
+  run_statements(
+    &f,
+    &["insert .events { id: 3, at: 40 }", "delete .events[id = 1]"],
+  )
+  .await;
+  let output = run_statements(
+    &f,
+    &[
+      ".events[at > 0].id",
+      ".events[at >= 10, at < 40].id",
+      ".events[at <= 40, id > 2].id",
+      ".events.id",
+    ],
+  )
+  .await;
+  assert_eq!(
+    output,
+    vec![
+      serde_json::json!({ "L": [2, 3] }),
+      serde_json::json!({ "L": [2] }),
+      serde_json::json!({ "L": [3] }),
+      serde_json::json!({ "L": [2, 3, 4] }),
+    ]
+  );
+}
+
+#[tokio::test]
+async fn prefix_scans() {
+  let f = fixture().await;
+  run_statements(
+    &f,
+    &[
+      "insert .items[id = 1].tags { name: \"ab\" }",
+      "insert .items[id = 1].tags { name: \"abc\" }",
+    ],
+  )
+  .await;
+  let output = run_statements(
+    &f,
+    &[
+      ".items.tags[name starts_with \"a\"].name",
+      ".items.tags[name starts_with \"ab\"].name",
+      ".items.tags[name starts_with \"c\"].name",
+      ".items.tags[name starts_with \"\"] | count()",
+      ".items[name starts_with \"s\"].id",
+    ],
+  )
+  .await;
+  assert_eq!(
+    output,
+    vec![
+      serde_json::json!({ "L": [{ "L": ["a", "ab", "abc"] }, { "L": [] }] }),
+      serde_json::json!({ "L": [{ "L": ["ab", "abc"] }, { "L": [] }] }),
+      serde_json::json!({ "L": [{ "L": [] }, { "L": [] }] }),
+      serde_json::json!(4),
+      serde_json::json!({ "L": [2] }),
+    ]
+  );
+}
+
+#[tokio::test]
+async fn reverse_scans() {
+  let f = fixture().await;
+  let mut planner = QueryPlanner::new(&f.schema);
+  planner
+    .add_statement(&parse_statement(".items.name").unwrap())
+    .unwrap();
+  planner
+    .add_statement(&parse_statement(".items[id >= 1].name").unwrap())
+    .unwrap();
+  let mut query_plan = planner.finish().unwrap();
+  for step in &mut query_plan.steps {
+    if let QueryStep::RangeScanKeys { reverse } | QueryStep::RangeScan { reverse, .. } = step {
+      *reverse = true;
+    }
+  }
+
+  let txn = f.kv.begin_transaction().await.unwrap();
+  let config = VmValueEncodeConfig {
+    enable_int64: true,
+    ..Default::default()
+  };
+  let output = exec_query_plan(&f.schema, &f.plan, &*txn, &query_plan, &config)
+    .await
+    .unwrap();
+  for x in &output {
+    assert_eq!(
+      serde_json::to_value(x).unwrap(),
+      serde_json::json!({ "L": ["second", "first"] })
+    );
+  }
+
+  // Only full scans can be lowered.
+  query_plan.steps.truncate(
+    query_plan
+      .steps
+      .iter()
+      .position(|x| matches!(x, QueryStep::Fulfill))
+      .unwrap()
+      + 1,
+  );
+  let script = to_treewalker(&f.schema, &query_plan).unwrap();
+  let vm = TwVm::new(&f.schema, &f.plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let mut executor = Executor::new(&vm, &f.kv, &type_info);
+  let output = executor
+    .run_graph(
+      script.entry as usize,
+      &[Arc::new(generate_root_map(&f.schema, &f.plan).unwrap())],
+    )
+    .await
+    .unwrap()
+    .unwrap();
+  let output = serde_json::to_value(&SerializedVmValue::encode(&output, &config).unwrap()).unwrap();
+  assert_eq!(
+    output["M"]["r0"],
+    serde_json::json!({ "L": ["second", "first"] })
+  );
+}
+
+#[tokio::test]
+async fn timestamps() {
+  let f = fixture().await;
+  let read = [".notes[id = 1].created_at", ".notes[id = 1].updated_at"];
+  run_statements(&f, &["insert .notes { id: 1, text: \"a\" }"]).await;
+  let output = run_statements(&f, &read).await;
+  let created_at = output[0].as_i64().unwrap();
+  assert!(created_at > 0);
+  assert_eq!(output[1], output[0]);
+
+  tokio::time::sleep(Duration::from_millis(5)).await;
+  run_statements(&f, &[".notes[id = 1].text = \"b\""]).await;
+  let output = run_statements(&f, &read).await;
+  assert_eq!(output[0].as_i64().unwrap(), created_at);
+  let updated_at = output[1].as_i64().unwrap();
+  assert!(updated_at > created_at);
+
+  // Re-inserting keeps the creation time.
+  tokio::time::sleep(Duration::from_millis(5)).await;
+  run_statements(&f, &["insert .notes { id: 1, text: \"c\" }"]).await;
+  let output = run_statements(&f, &read).await;
+  assert_eq!(output[0].as_i64().unwrap(), created_at);
+  assert!(output[1].as_i64().unwrap() > updated_at);
+}
+
+#[tokio::test]
+async fn soft_delete() {
+  let f = fixture().await;
+  run_statements(
+    &f,
+    &[
+      "insert .docs { id: 1, name: \"a\" }",
+      "insert .docs { id: 2, name: \"b\" }",
+      "delete .docs[id = 1]",
+    ],
+  )
+  .await;
+  let read = [
+    ".docs.name",
+    ".docs[id = 1].name",
+    ".docs[id >= 1].name",
+    ".docs | count()",
+  ];
+  assert_eq!(
+    run_statements(&f, &read).await,
+    vec![
+      serde_json::json!({ "L": ["b"] }),
+      serde_json::json!(null),
+      serde_json::json!({ "L": ["b"] }),
+      serde_json::json!(1),
+    ]
+  );
+
+  // Deleted members are kept, with the time they were deleted at.
+  let mut planner = QueryPlanner::new(&f.schema);
+  planner.set_include_deleted(true);
+  let output = run_planned(
+    &f,
+    planner,
+    &[".docs | count()", ".docs[id = 1].deleted_at"],
+  )
+  .await;
+  assert_eq!(output[0], serde_json::json!(2));
+  let deleted_at = output[1].as_i64().unwrap();
+  assert!(deleted_at > 0);
+
+  // Deleting again keeps the original deletion time.
+  tokio::time::sleep(Duration::from_millis(5)).await;
+  run_statements(&f, &["delete .docs[id = 1]"]).await;
+  let mut planner = QueryPlanner::new(&f.schema);
+  planner.set_include_deleted(true);
+  let output = run_planned(&f, planner, &[".docs[id = 1].deleted_at"]).await;
+  assert_eq!(output[0].as_i64().unwrap(), deleted_at);
+
+  // Re-inserting restores the member.
+  run_statements(&f, &["insert .docs { id: 1, name: \"c\" }"]).await;
+  assert_eq!(
+    run_statements(&f, &read).await,
+    vec![
+      serde_json::json!({ "L": ["c", "b"] }),
+      serde_json::json!("c"),
+      serde_json::json!({ "L": ["c", "b"] }),
+      serde_json::json!(2),
+    ]
+  );
+}
@@ -0,0 +1,664 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use rpds::RedBlackTreeMapSync;
+use thiserror::Error;
+
+use crate::{
+  data::{
+    treewalker::{
+      bytecode::{TwGraph, TwGraphNode, TwScript},
+      vm_value::{VmConst, VmListType, VmType},
+    },
+    value::PrimitiveValue,
+  },
+  schema::compile::{CompiledSchema, FieldType},
+};
+
+use super::{
+  ast::Value,
+  planner::{QueryPlan, QueryStep},
+};
+
+/// Name of the exported graph in lowered scripts.
+///
+/// The graph takes the schema as its only parameter, and returns a map with the fulfilled values
+/// under `r0`, `r1`, ...
+pub const QUERY_GRAPH_NAME: &str = "query";
+
+#[derive(Error, Debug)]
+pub enum LowerError {
+  #[error("step cannot be lowered in this position: {0}")]
+  Unsupported(String),
+
+  #[error("cannot load recursive type `{0}`")]
+  RecursiveLoad(String),
+
+  #[error("type not found: `{0}`")]
+  TypeNotFound(String),
+
+  #[error("stack underflow")]
+  StackUnderflow,
+}
+
+/// Lowers a query plan into a treewalker script, so that it can run on the executor with its
+/// type checking and transaction handling.
+///
+/// Differences from `exec_query_plan`:
+///
+/// - Nulls are dropped from the lists produced by set scans.
+/// - Writes are effects of the graph, and are not ordered relative to reads.
+/// - Writes through set scans and to exported fields are not supported.
+/// - Range scans, filters on non-key fields, subqueries and aggregates are not supported.
+pub fn to_treewalker(schema: &CompiledSchema, plan: &QueryPlan) -> Result<TwScript> {
+  let mut lowering = Lowering {
+    schema,
+    script: TwScript::default(),
+  };
+  let graph_index = lowering.reserve_graph();
+  let mut g = GraphBuilder::default();
+  let root = g.push(TwGraphNode::LoadParam(0), vec![], None);
+  let mut stack = vec![];
+  let mut outputs = vec![];
+  lowering.lower_steps(&mut g, root, &mut stack, &plan.steps, &mut outputs)?;
+
+  let mut named_outputs = vec![];
+  let mut output_type = None;
+  if !outputs.is_empty() {
+    let mut map_ty = RedBlackTreeMapSync::new_sync();
+    for (i, (node, ty)) in outputs.into_iter().enumerate() {
+      let key = format!("r{}", i);
+      named_outputs.push((lowering.ident(&key), node));
+      map_ty.insert_mut(key, ty);
+    }
+    output_type = Some(lowering.ty(VmType::Map(map_ty)));
+  }
+
+  let param_types = vec![lowering.ty(VmType::Schema)];
+  lowering.script.graphs[graph_index as usize] = TwGraph {
+    name: QUERY_GRAPH_NAME.into(),
+    exported: true,
+    nodes: g.nodes,
+    output: None,
+    named_outputs,
+    param_constraints: vec![],
+    param_types,
+    output_type,
+  };
+  lowering.script.entry = graph_index;
+  Ok(lowering.script)
+}
+
+/// A symbolic stack value.
+enum Sym {
+  Const(PrimitiveValue),
+  Object(BTreeMap<String, Value>),
+
+  /// A value in the current graph. `field_of` is the table node and field name it was read from.
+  Node {
+    node: u32,
+    ty: FieldType,
+    field_of: Option<(u32, String)>,
+  },
+
+  /// Members of a set. Steps applied to it are deferred into the subgraph of a reduce.
+  Scan {
+    set: u32,
+    member_ty: FieldType,
+    steps: Vec<QueryStep>,
+    reverse: bool,
+  },
+
+  /// A loaded value.
+  Loaded {
+    node: u32,
+    ty: VmType<String>,
+  },
+}
+
+#[derive(Default)]
+struct GraphBuilder {
+  nodes: Vec<(TwGraphNode, Vec<u32>, Option<u32>)>,
+}
+
+impl GraphBuilder {
+  fn push(&mut self, node: TwGraphNode, in_edges: Vec<u32>, precondition: Option<u32>) -> u32 {
+    self.nodes.push((node, in_edges, precondition));
+    (self.nodes.len() - 1) as u32
+  }
+}
+
+struct Lowering<'a> {
+  schema: &'a CompiledSchema,
+  script: TwScript,
+}
+
+impl<'a> Lowering<'a> {
+  fn lower_steps(
+    &mut self,
+    g: &mut GraphBuilder,
+    root: u32,
+    stack: &mut Vec<Sym>,
+    steps: &[QueryStep],
+    outputs: &mut Vec<(u32, VmType<String>)>,
+  ) -> Result<()> {
+    for step in steps {
+      match step {
+        QueryStep::Const(x) => stack.push(Sym::Const(x.clone())),
+        QueryStep::ConstObject(x) => stack.push(Sym::Object(x.clone())),
+        // The treewalker skips deleted members itself.
+        QueryStep::SkipDeleted => {}
+        QueryStep::Param(_)
+        | QueryStep::RangeScan { .. }
+        | QueryStep::SortKeyScan { .. }
+        | QueryStep::PrefixScan { .. }
+        | QueryStep::FilterBy(_, _)
+        | QueryStep::Aggregate(_) => return Err(unsupported(step)),
+        QueryStep::Root(name) => {
+          let ty = self
+            .schema
+            .exports
+            .get(name.as_str())
+            .ok_or_else(|| LowerError::TypeNotFound(name.clone()))?
+            .clone();
+          let node = g.push(TwGraphNode::GetField(self.ident(name)), vec![root], None);
+          stack.push(Sym::Node {
+            node,
+            ty,
+            field_of: None,
+          });
+        }
+        QueryStep::Field(name) => match pop(stack)? {
+          Sym::Node { node, ty, .. } => {
+            let field_ty = self.lookup_field(&ty, name)?;
+            let field_node = g.push(TwGraphNode::GetField(self.ident(name)), vec![node], None);
+            stack.push(Sym::Node {
+              node: field_node,
+              ty: field_ty,
+              field_of: Some((node, name.clone())),
+            });
+          }
+          Sym::Scan {
+            set,
+            member_ty,
+            mut steps,
+            reverse,
+          } => {
+            steps.push(step.clone());
+            stack.push(Sym::Scan {
+              set,
+              member_ty,
+              steps,
+              reverse,
+            });
+          }
+          _ => return Err(unsupported(step)),
+        },
+        QueryStep::PointGet => {
+          let key = match pop(stack)? {
+            Sym::Const(x) => x,
+            _ => return Err(unsupported(step)),
+          };
+          match pop(stack)? {
+            Sym::Node {
+              node,
+              ty: FieldType::Set(member_ty),
+              ..
+            } => {
+              let key = g.push(
+                TwGraphNode::LoadConst(self.konst(VmConst::Primitive(key))),
+                vec![],
+                None,
+              );
+              let member = g.push(TwGraphNode::GetSetElement, vec![key, node], None);
+
+              // The executor yields null for missing members.
+              let null_ty = VmType::<String>::from(&*member_ty);
+              let member = self.guard_present(g, member, null_ty);
+              stack.push(Sym::Node {
+                node: member,
+                ty: *member_ty,
+                field_of: None,
+              });
+            }
+            Sym::Scan {
+              set,
+              member_ty,
+              mut steps,
+              reverse,
+            } => {
+              steps.push(QueryStep::Const(key));
+              steps.push(step.clone());
+              stack.push(Sym::Scan {
+                set,
+                member_ty,
+                steps,
+                reverse,
+              });
+            }
+            _ => return Err(unsupported(step)),
+          }
+        }
+        QueryStep::RangeScanKeys { reverse } => match pop(stack)? {
+          Sym::Node {
+            node,
+            ty: FieldType::Set(member_ty),
+            ..
+          } => stack.push(Sym::Scan {
+            set: node,
+            member_ty: *member_ty,
+            steps: vec![],
+            reverse: *reverse,
+          }),
+          Sym::Scan {
+            set,
+            member_ty,
+            mut steps,
+            reverse,
+          } => {
+            steps.push(step.clone());
+            stack.push(Sym::Scan {
+              set,
+              member_ty,
+              steps,
+              reverse,
+            });
+          }
+          _ => return Err(unsupported(step)),
+        },
+        QueryStep::LensGet(ty) => match pop(stack)? {
+          Sym::Node { node, .. } => {
+            let (node, ty) = match ty {
+              FieldType::Table(_) => {
+                // Without the guard, a null table would load into a map of nulls.
+                let is_null = g.push(TwGraphNode::IsNull, vec![node], None);
+                let not_null = g.push(TwGraphNode::Not, vec![is_null], None);
+                let (loaded, loaded_ty) = self.load(g, node, ty, Some(not_null), &mut vec![])?;
+                let null = g.push(
+                  TwGraphNode::LoadConst(self.konst(VmConst::Null(loaded_ty.clone()))),
+                  vec![],
+                  Some(is_null),
+                );
+                (
+                  g.push(TwGraphNode::Select, vec![loaded, null], None),
+                  loaded_ty,
+                )
+              }
+              _ => self.load(g, node, ty, None, &mut vec![])?,
+            };
+            stack.push(Sym::Loaded { node, ty });
+          }
+          Sym::Scan {
+            set,
+            member_ty,
+            mut steps,
+            reverse,
+          } => {
+            steps.push(step.clone());
+            let (node, ty) = self.lower_scan(g, set, member_ty, &steps, reverse)?;
+            stack.push(Sym::Loaded { node, ty });
+          }
+          _ => return Err(unsupported(step)),
+        },
+        // `InsertIntoTable` maintains the timestamps itself.
+        QueryStep::LensPut { ty, .. } => {
+          let value = pop(stack)?;
+          match pop(stack)? {
+            Sym::Node {
+              field_of: Some((table, field)),
+              ..
+            } => {
+              let value = self.emit_value(g, value, ty)?;
+              g.push(
+                TwGraphNode::InsertIntoTable(self.ident(&field)),
+                vec![value, table],
+                None,
+              );
+            }
+            _ => return Err(unsupported(step)),
+          }
+        }
+        QueryStep::PointPut(ty) => {
+          let value = pop(stack)?;
+          pop(stack)?;
+          match pop(stack)? {
+            Sym::Node {
+              node,
+              ty: FieldType::Set(_),
+              ..
+            } => {
+              let value = self.emit_value(g, value, ty)?;
+              g.push(TwGraphNode::InsertIntoSet, vec![value, node], None);
+            }
+            _ => return Err(unsupported(step)),
+          }
+        }
+        QueryStep::PointDelete(_) => {
+          let key = match pop(stack)? {
+            Sym::Const(x) => x,
+            _ => return Err(unsupported(step)),
+          };
+          match pop(stack)? {
+            Sym::Node {
+              node,
+              ty: FieldType::Set(_),
+              ..
+            } => {
+              let key = g.push(
+                TwGraphNode::LoadConst(self.konst(VmConst::Primitive(key))),
+                vec![],
+                None,
+              );
+              g.push(TwGraphNode::DeleteFromSet, vec![key, node], None);
+            }
+            _ => return Err(unsupported(step)),
+          }
+        }
+        QueryStep::Fulfill => match pop(stack)? {
+          Sym::Loaded { node, ty } => outputs.push((node, ty)),
+          _ => return Err(unsupported(step)),
+        },
+      }
+    }
+    Ok(())
+  }
+
+  /// Lowers the deferred steps of a set scan into a reduce subgraph that collects the loaded value
+  /// of each member into a list.
+  fn lower_scan(
+    &mut self,
+    g: &mut GraphBuilder,
+    set: u32,
+    member_ty: FieldType,
+    steps: &[QueryStep],
+    reverse: bool,
+  ) -> Result<(u32, VmType<String>)> {
+    let subgraph_index = self.reserve_graph();
+    let mut sub = GraphBuilder::default();
+    sub.push(TwGraphNode::LoadParam(0), vec![], None);
+    let acc = sub.push(TwGraphNode::LoadParam(1), vec![], None);
+    let item = sub.push(TwGraphNode::LoadParam(2), vec![], None);
+    let mut stack = vec![Sym::Node {
+      node: item,
+      ty: member_ty.clone(),
+      field_of: None,
+    }];
+    let (value, value_ty) = match self.lower_scan_steps(&mut sub, &mut stack, steps)? {
+      Sym::Loaded { node, ty } => (node, ty),
+      _ => return Err(LowerError::Unsupported("scan without load".into()).into()),
+    };
+    let output = prepend_non_null(&mut sub, value, acc);
+    let list_ty = VmType::List(VmListType {
+      ty: Box::new(value_ty.clone()),
+    });
+    let param_types = vec![
+      self.ty(VmType::Map(RedBlackTreeMapSync::new_sync())),
+      self.ty(list_ty.clone()),
+      self.ty(VmType::from(&member_ty)),
+    ];
+    let output_type = Some(self.ty(list_ty.clone()));
+    self.script.graphs[subgraph_index as usize] = TwGraph {
+      name: format!("{}_scan_{}", QUERY_GRAPH_NAME, subgraph_index),
+      exported: false,
+      nodes: sub.nodes,
+      output: Some(output),
+      named_outputs: vec![],
+      param_constraints: vec![],
+      param_types,
+      output_type,
+    };
+
+    let ctx = g.push(TwGraphNode::CreateMap, vec![], None);
+    let init = g.push(TwGraphNode::CreateList(self.ty(value_ty)), vec![], None);
+
+    // Prepending reverses the scan order, so scan in the opposite order.
+    let node = g.push(
+      TwGraphNode::reduce(subgraph_index, false, !reverse),
+      vec![ctx, init, set],
+      None,
+    );
+    Ok((node, list_ty))
+  }
+
+  fn lower_scan_steps(
+    &mut self,
+    g: &mut GraphBuilder,
+    stack: &mut Vec<Sym>,
+    steps: &[QueryStep],
+  ) -> Result<Sym> {
+    let mut outputs = vec![];
+
+    // Scans never start from the root.
+    self.lower_steps(g, u32::MAX, stack, steps, &mut outputs)?;
+    if stack.len() != 1 || !outputs.is_empty() {
+      return Err(LowerError::Unsupported("scan".into()).into());
+    }
+    pop(stack)
+  }
+
+  /// Loads a value into the current graph. Tables are loaded into maps with all of their primitive
+  /// and table fields, like in the executor.
+  fn load(
+    &mut self,
+    g: &mut GraphBuilder,
+    node: u32,
+    ty: &FieldType,
+    precondition: Option<u32>,
+    visiting: &mut Vec<String>,
+  ) -> Result<(u32, VmType<String>)> {
+    let name = match ty {
+      FieldType::Primitive(x) => return Ok((node, VmType::Primitive(*x))),
+      FieldType::Struct(_) => return Ok((node, VmType::from(ty))),
+      FieldType::Table(x) => x,
+      FieldType::Set(_) => return Err(LowerError::Unsupported("loading a set".into()).into()),
+      FieldType::List(_) => return Err(LowerError::Unsupported("loading a list".into()).into()),
+      FieldType::Map(_) => return Err(LowerError::Unsupported("loading a map".into()).into()),
+    };
+    if visiting.iter().any(|x| x.as_str() == &**name) {
+      return Err(LowerError::RecursiveLoad(name.to_string()).into());
+    }
+    visiting.push(name.to_string());
+
+    let schema = self.schema;
+    let specialized_ty = schema
+      .types
+      .get(name)
+      .ok_or_else(|| LowerError::TypeNotFound(name.to_string()))?;
+    let mut map = g.push(TwGraphNode::CreateMap, vec![], precondition);
+    let mut map_ty = RedBlackTreeMapSync::new_sync();
+    for (field_name, (field_ty, _)) in &specialized_ty.fields {
+      if let FieldType::Set(_) | FieldType::List(_) | FieldType::Map(_) = field_ty {
+        continue;
+      }
+      let field_ident = self.ident(field_name);
+      let value = g.push(TwGraphNode::GetField(field_ident), vec![node], None);
+      let (value, value_ty) = match field_ty {
+        FieldType::Table(_) => {
+          // Nested tables are only loaded if present, like in the executor.
+          let present = self.present_or_false(g, value);
+          let absent = g.push(TwGraphNode::Not, vec![present], None);
+          let kept = g.push(TwGraphNode::Nop, vec![value], Some(present));
+          let (loaded, loaded_ty) = self.load(g, kept, field_ty, Some(present), visiting)?;
+          let null = g.push(
+            TwGraphNode::LoadConst(self.konst(VmConst::Null(loaded_ty.clone()))),
+            vec![],
+            Some(absent),
+          );
+          (
+            g.push(TwGraphNode::Select, vec![loaded, null], None),
+            loaded_ty,
+          )
+        }
+        _ => self.load(g, value, field_ty, None, visiting)?,
+      };
+      map = g.push(
+        TwGraphNode::InsertIntoMap(field_ident),
+        vec![value, map],
+        None,
+      );
+      map_ty.insert_mut(field_name.to_string(), value_ty);
+    }
+
+    visiting.pop();
+    Ok((map, VmType::Map(map_ty)))
+  }
+
+  /// Emits a literal value of type `ty`. Objects are built into tables.
+  fn emit_value(&mut self, g: &mut GraphBuilder, value: Sym, ty: &FieldType) -> Result<u32> {
+    let value = match value {
+      Sym::Const(x) => Value::Primitive(x),
+      Sym::Object(x) => Value::Object(x),
+      _ => return Err(LowerError::Unsupported("non-literal value".into()).into()),
+    };
+    self.emit_literal(g, &value, ty)
+  }
+
+  fn emit_literal(&mut self, g: &mut GraphBuilder, value: &Value, ty: &FieldType) -> Result<u32> {
+    match (value, ty) {
+      (Value::Primitive(x), _) => Ok(g.push(
+        TwGraphNode::LoadConst(self.konst(VmConst::Primitive(x.clone()))),
+        vec![],
+        None,
+      )),
+      (Value::Object(fields), FieldType::Table(name)) => {
+        let mut map = g.push(TwGraphNode::CreateMap, vec![], None);
+        for (k, v) in fields {
+          let field_ty = self.lookup_field(ty, k)?;
+          let v = self.emit_literal(g, v, &field_ty)?;
+          map = g.push(
+            TwGraphNode::InsertIntoMap(self.ident(k)),
+            vec![v, map],
+            None,
+          );
+        }
+        Ok(g.push(TwGraphNode::BuildTable(self.ident(name)), vec![map], None))
+      }
+      (Value::Object(fields), FieldType::Struct(members)) => {
+        let mut map = g.push(TwGraphNode::CreateMap, vec![], None);
+        for (k, v) in fields {
+          let member_ty = members
+            .get(k.as_str())
+            .ok_or_else(|| LowerError::TypeNotFound(k.clone()))?;
+          let v = self.emit_literal(g, v, member_ty)?;
+          map = g.push(
+            TwGraphNode::InsertIntoMap(self.ident(k)),
+            vec![v, map],
+            None,
+          );
+        }
+        Ok(map)
+      }
+      _ => Err(LowerError::Unsupported("object value for non-table type".into()).into()),
+    }
+  }
+
+  /// Replaces a table that is not present in the store with null.
+  fn guard_present(&mut self, g: &mut GraphBuilder, node: u32, null_ty: VmType<String>) -> u32 {
+    let present = self.present_or_false(g, node);
+    let absent = g.push(TwGraphNode::Not, vec![present], None);
+    let kept = g.push(TwGraphNode::Nop, vec![node], Some(present));
+    let null = g.push(
+      TwGraphNode::LoadConst(self.konst(VmConst::Null(null_ty))),
+      vec![],
+      Some(absent),
+    );
+    g.push(TwGraphNode::Select, vec![kept, null], None)
+  }
+
+  /// Returns a node that is true if the table or set is present in the store, and false if it is
+  /// absent or null. Never null, so that it can be negated for use as a precondition.
+  fn present_or_false(&mut self, g: &mut GraphBuilder, node: u32) -> u32 {
+    let is_null = g.push(TwGraphNode::IsNull, vec![node], None);
+    let not_null = g.push(TwGraphNode::Not, vec![is_null], None);
+    let present = g.push(TwGraphNode::IsPresent, vec![node], Some(not_null));
+    let false_ = g.push(
+      TwGraphNode::LoadConst(self.konst(VmConst::Bool(false))),
+      vec![],
+      Some(is_null),
+    );
+    g.push(TwGraphNode::Select, vec![present, false_], None)
+  }
+
+  fn lookup_field(&self, ty: &FieldType, name: &str) -> Result<FieldType> {
+    let table_name = match ty {
+      FieldType::Table(x) => x,
+      _ => return Err(LowerError::TypeNotFound(name.to_string()).into()),
+    };
+    self
+      .schema
+      .types
+      .get(table_name)
+      .and_then(|x| x.fields.get(name))
+      .map(|(x, _)| x.clone())
+      .ok_or_else(|| LowerError::TypeNotFound(format!("{}.{}", table_name, name)).into())
+  }
+
+  fn reserve_graph(&mut self) -> u32 {
+    self.script.graphs.push(TwGraph {
+      name: String::new(),
+      exported: false,
+      nodes: vec![],
+      output: None,
+      named_outputs: vec![],
+      param_constraints: vec![],
+      param_types: vec![],
+      output_type: None,
+    });
+    (self.script.graphs.len() - 1) as u32
+  }
+
+  fn ident(&mut self, x: &str) -> u32 {
+    let idents = &mut self.script.idents;
+    match idents.iter().position(|y| y == x) {
+      Some(i) => i as u32,
+      None => {
+        idents.push(x.to_string());
+        (idents.len() - 1) as u32
+      }
+    }
+  }
+
+  fn ty(&mut self, x: VmType<String>) -> u32 {
+    let types = &mut self.script.types;
+    match types.iter().position(|y| *y == x) {
+      Some(i) => i as u32,
+      None => {
+        types.push(x);
+        (types.len() - 1) as u32
+      }
+    }
+  }
+
+  fn konst(&mut self, x: VmConst) -> u32 {
+    let consts = &mut self.script.consts;
+    match consts.iter().position(|y| *y == x) {
+      Some(i) => i as u32,
+      None => {
+        consts.push(x);
+        (consts.len() - 1) as u32
+      }
+    }
+  }
+}
+
+/// Prepends `value` to `list`, or returns `list` unchanged if `value` is null. A null output would
+/// stop the reduce.
+fn prepend_non_null(g: &mut GraphBuilder, value: u32, list: u32) -> u32 {
+  let is_null = g.push(TwGraphNode::IsNull, vec![value], None);
+  let not_null = g.push(TwGraphNode::Not, vec![is_null], None);
+  let prepended = g.push(
+    TwGraphNode::PrependToList,
+    vec![value, list],
+    Some(not_null),
+  );
+  let kept = g.push(TwGraphNode::Nop, vec![list], Some(is_null));
+  g.push(TwGraphNode::Select, vec![prepended, kept], None)
+}
+
+fn pop(stack: &mut Vec<Sym>) -> Result<Sym> {
+  stack.pop().ok_or_else(|| LowerError::StackUnderflow.into())
+}
+
+fn unsupported(step: &QueryStep) -> anyhow::Error {
+  LowerError::Unsupported(format!("{:?}", step)).into()
+}
@@ -0,0 +1,11 @@
+pub mod ast;
+pub mod exec;
+pub mod lower;
+pub mod parser;
+pub mod planner;
+
+#[cfg(test)]
+mod planner_test;
+
+#[cfg(test)]
+mod exec_test;
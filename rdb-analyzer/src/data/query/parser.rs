@@ -0,0 +1,450 @@
+use std::{collections::BTreeMap, fmt::Display, iter::Peekable, str::CharIndices};
+
+use anyhow::Result;
+use thiserror::Error;
+
+use crate::data::value::PrimitiveValue;
+
+use super::ast::{
+  Aggregate, AggregateFn, CompareOp, Operand, PathQuery, PathSegment, Predicate, Statement, Value,
+};
+
+#[derive(Error, Debug)]
+pub enum QueryParseError {
+  #[error("unexpected character `{0}` at offset {1}")]
+  UnexpectedChar(char, usize),
+
+  #[error("unexpected end of input")]
+  UnexpectedEof,
+
+  #[error("unexpected token `{0}`")]
+  UnexpectedToken(String),
+
+  #[error("bad literal: {0}")]
+  BadLiteral(String),
+
+  #[error("duplicate key in object: `{0}`")]
+  DuplicateKey(String),
+
+  #[error("unknown function: `{0}`")]
+  UnknownFunction(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+  Dot,
+  LBracket,
+  RBracket,
+  Eq,
+  Lt,
+  Le,
+  Gt,
+  Ge,
+  LBrace,
+  RBrace,
+  Colon,
+  Comma,
+  Question,
+  Pipe,
+  LParen,
+  RParen,
+  Ident(String),
+  Placeholder(String),
+  Literal(PrimitiveValue),
+}
+
+impl Display for Token {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Dot => write!(f, "."),
+      Self::LBracket => write!(f, "["),
+      Self::RBracket => write!(f, "]"),
+      Self::Eq => write!(f, "="),
+      Self::Lt => write!(f, "<"),
+      Self::Le => write!(f, "<="),
+      Self::Gt => write!(f, ">"),
+      Self::Ge => write!(f, ">="),
+      Self::LBrace => write!(f, "{{"),
+      Self::RBrace => write!(f, "}}"),
+      Self::Colon => write!(f, ":"),
+      Self::Comma => write!(f, ","),
+      Self::Question => write!(f, "?"),
+      Self::Pipe => write!(f, "|"),
+      Self::LParen => write!(f, "("),
+      Self::RParen => write!(f, ")"),
+      Self::Ident(x) => write!(f, "{}", x),
+      Self::Placeholder(x) => write!(f, "${}", x),
+      Self::Literal(x) => write!(f, "{}", x),
+    }
+  }
+}
+
+/// Parses a path query like `.items[id = 42].name`.
+///
+/// Filters can hold several comma-separated predicates, which can be equalities, comparisons or
+/// string prefix matches: `.items[id >= 10, id < 20, name = "x", tag starts_with "a"]`.
+///
+/// Predicates can compare against placeholders instead of literals: `.items[id = ?]` or
+/// `.items[id = $id]`, or against the result of another path query:
+/// `.orders[user = .users[name = "x"].id]`.
+pub fn parse_path_query(input: &str) -> Result<PathQuery> {
+  let mut parser = Parser {
+    tokens: tokenize(input)?,
+    pos: 0,
+  };
+  let query = parser.path_query()?;
+  parser.expect_eof()?;
+  Ok(query)
+}
+
+/// Parses a read or write statement.
+///
+/// - `.items[id = 42].name` reads a path.
+/// - `.items[id = 42].name = "x"` assigns to a field.
+/// - `delete .items[id = 42]` deletes a set member.
+/// - `insert .items { id: 42, name: "x" }` inserts a set member.
+/// - `.items[id > 42] | count()` aggregates set members. `sum`, `min` and `max` take the path to a
+///   field of each member, e.g. `sum(.weight)`.
+pub fn parse_statement(input: &str) -> Result<Statement> {
+  let mut parser = Parser {
+    tokens: tokenize(input)?,
+    pos: 0,
+  };
+  let stmt = parser.statement()?;
+  parser.expect_eof()?;
+  Ok(stmt)
+}
+
+struct Parser {
+  tokens: Vec<Token>,
+  pos: usize,
+}
+
+impl Parser {
+  fn peek(&self) -> Option<&Token> {
+    self.tokens.get(self.pos)
+  }
+
+  fn next(&mut self) -> Result<Token> {
+    let token = self
+      .tokens
+      .get(self.pos)
+      .cloned()
+      .ok_or_else(|| QueryParseError::UnexpectedEof)?;
+    self.pos += 1;
+    Ok(token)
+  }
+
+  fn expect(&mut self, expected: Token) -> Result<()> {
+    let token = self.next()?;
+    if token != expected {
+      return Err(QueryParseError::UnexpectedToken(token.to_string()).into());
+    }
+    Ok(())
+  }
+
+  fn expect_eof(&self) -> Result<()> {
+    match self.peek() {
+      Some(x) => Err(QueryParseError::UnexpectedToken(x.to_string()).into()),
+      None => Ok(()),
+    }
+  }
+
+  fn ident(&mut self) -> Result<String> {
+    match self.next()? {
+      Token::Ident(x) => Ok(x),
+      x => Err(QueryParseError::UnexpectedToken(x.to_string()).into()),
+    }
+  }
+
+  fn literal(&mut self) -> Result<PrimitiveValue> {
+    match self.next()? {
+      Token::Literal(x) => Ok(x),
+      x => Err(QueryParseError::UnexpectedToken(x.to_string()).into()),
+    }
+  }
+
+  fn predicate(&mut self) -> Result<Predicate> {
+    let field = self.ident()?;
+    let op = match self.next()? {
+      Token::Eq => CompareOp::Eq,
+      Token::Lt => CompareOp::Lt,
+      Token::Le => CompareOp::Le,
+      Token::Gt => CompareOp::Gt,
+      Token::Ge => CompareOp::Ge,
+      Token::Ident(x) if x == "starts_with" => CompareOp::StartsWith,
+      x => return Err(QueryParseError::UnexpectedToken(x.to_string()).into()),
+    };
+    let value = self.operand()?;
+    Ok(Predicate { field, op, value })
+  }
+
+  fn operand(&mut self) -> Result<Operand> {
+    if self.peek() == Some(&Token::Dot) {
+      return Ok(Operand::Path(self.path_query()?));
+    }
+    match self.next()? {
+      Token::Literal(x) => Ok(Operand::Literal(x)),
+      Token::Question => Ok(Operand::Positional),
+      Token::Placeholder(x) => Ok(Operand::Named(x)),
+      x => Err(QueryParseError::UnexpectedToken(x.to_string()).into()),
+    }
+  }
+
+  fn statement(&mut self) -> Result<Statement> {
+    match self.peek() {
+      Some(Token::Ident(x)) if x == "delete" => {
+        self.pos += 1;
+        Ok(Statement::Delete(self.path_query()?))
+      }
+      Some(Token::Ident(x)) if x == "insert" => {
+        self.pos += 1;
+        let path = self.path_query()?;
+        let object = self.object()?;
+        Ok(Statement::Insert(path, object))
+      }
+      _ => {
+        let path = self.path_query()?;
+        match self.peek() {
+          Some(Token::Eq) => {
+            self.pos += 1;
+            Ok(Statement::Assign(path, self.value()?))
+          }
+          Some(Token::Pipe) => {
+            self.pos += 1;
+            Ok(Statement::Aggregate(path, self.aggregate()?))
+          }
+          _ => Ok(Statement::Read(path)),
+        }
+      }
+    }
+  }
+
+  fn aggregate(&mut self) -> Result<Aggregate> {
+    let name = self.ident()?;
+    let func = match name.as_str() {
+      "count" => AggregateFn::Count,
+      "sum" => AggregateFn::Sum,
+      "min" => AggregateFn::Min,
+      "max" => AggregateFn::Max,
+      _ => return Err(QueryParseError::UnknownFunction(name).into()),
+    };
+    self.expect(Token::LParen)?;
+    let mut fields = vec![];
+    while self.peek() == Some(&Token::Dot) {
+      self.pos += 1;
+      fields.push(self.ident()?);
+    }
+    self.expect(Token::RParen)?;
+    Ok(Aggregate { func, fields })
+  }
+
+  fn value(&mut self) -> Result<Value> {
+    if self.peek() == Some(&Token::LBrace) {
+      Ok(Value::Object(self.object()?))
+    } else {
+      Ok(Value::Primitive(self.literal()?))
+    }
+  }
+
+  fn object(&mut self) -> Result<BTreeMap<String, Value>> {
+    self.expect(Token::LBrace)?;
+    let mut fields = BTreeMap::new();
+    while self.peek() != Some(&Token::RBrace) {
+      let key = self.ident()?;
+      self.expect(Token::Colon)?;
+      let value = self.value()?;
+      if fields.insert(key.clone(), value).is_some() {
+        return Err(QueryParseError::DuplicateKey(key).into());
+      }
+      if self.peek() == Some(&Token::Comma) {
+        self.pos += 1;
+      } else {
+        break;
+      }
+    }
+    self.expect(Token::RBrace)?;
+    Ok(fields)
+  }
+
+  fn path_query(&mut self) -> Result<PathQuery> {
+    self.expect(Token::Dot)?;
+    let root = self.ident()?;
+    let mut segments = vec![];
+    loop {
+      match self.peek() {
+        Some(Token::Dot) => {
+          self.pos += 1;
+          segments.push(PathSegment::Field(self.ident()?));
+        }
+        Some(Token::LBracket) => {
+          self.pos += 1;
+          let mut predicates = vec![self.predicate()?];
+          while self.peek() == Some(&Token::Comma) {
+            self.pos += 1;
+            predicates.push(self.predicate()?);
+          }
+          self.expect(Token::RBracket)?;
+          segments.push(PathSegment::Filter(predicates));
+        }
+        _ => break,
+      }
+    }
+    Ok(PathQuery { root, segments })
+  }
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>> {
+  let mut it = input.char_indices().peekable();
+  let mut tokens = vec![];
+  while let Some(&(start, c)) = it.peek() {
+    match c {
+      _ if c.is_whitespace() => {
+        it.next();
+      }
+      '.' => {
+        it.next();
+        tokens.push(Token::Dot);
+      }
+      '[' => {
+        it.next();
+        tokens.push(Token::LBracket);
+      }
+      ']' => {
+        it.next();
+        tokens.push(Token::RBracket);
+      }
+      '=' => {
+        it.next();
+        tokens.push(Token::Eq);
+      }
+      '<' | '>' => {
+        it.next();
+        let or_eq = matches!(it.peek(), Some(&(_, '=')));
+        if or_eq {
+          it.next();
+        }
+        tokens.push(match (c, or_eq) {
+          ('<', false) => Token::Lt,
+          ('<', true) => Token::Le,
+          ('>', false) => Token::Gt,
+          _ => Token::Ge,
+        });
+      }
+      '{' => {
+        it.next();
+        tokens.push(Token::LBrace);
+      }
+      '}' => {
+        it.next();
+        tokens.push(Token::RBrace);
+      }
+      ':' => {
+        it.next();
+        tokens.push(Token::Colon);
+      }
+      ',' => {
+        it.next();
+        tokens.push(Token::Comma);
+      }
+      '?' => {
+        it.next();
+        tokens.push(Token::Question);
+      }
+      '|' => {
+        it.next();
+        tokens.push(Token::Pipe);
+      }
+      '(' => {
+        it.next();
+        tokens.push(Token::LParen);
+      }
+      ')' => {
+        it.next();
+        tokens.push(Token::RParen);
+      }
+      '$' => {
+        it.next();
+        let name = read_ident(input, &mut it);
+        if name.is_empty() {
+          return Err(QueryParseError::UnexpectedChar(c, start).into());
+        }
+        tokens.push(Token::Placeholder(name));
+      }
+      '"' => {
+        let s = read_string(input, &mut it)?;
+        tokens.push(Token::Literal(PrimitiveValue::String(s)));
+      }
+      'h' if input[start + 1..].starts_with('"') => {
+        it.next();
+        let s = read_string(input, &mut it)?;
+        let bytes = hex::decode(&s).map_err(|_| QueryParseError::BadLiteral(s))?;
+        tokens.push(Token::Literal(PrimitiveValue::Bytes(bytes)));
+      }
+      _ if c.is_ascii_digit() || c == '-' => {
+        let mut end = start;
+        while let Some(&(i, c)) = it.peek() {
+          let is_sign = (c == '-' || c == '+')
+            && (i == start || input[..i].ends_with(|x| x == 'e' || x == 'E'));
+          if c.is_ascii_digit() || c == '.' || c == 'e' || c == 'E' || is_sign {
+            end = i + c.len_utf8();
+            it.next();
+          } else {
+            break;
+          }
+        }
+        let text = &input[start..end];
+        let value = if text.contains(|x| x == '.' || x == 'e' || x == 'E') {
+          text.parse::<f64>().map(PrimitiveValue::double).ok()
+        } else {
+          text.parse::<i64>().map(PrimitiveValue::Int64).ok()
+        };
+        tokens.push(Token::Literal(
+          value.ok_or_else(|| QueryParseError::BadLiteral(text.to_string()))?,
+        ));
+      }
+      _ if c.is_ascii_alphabetic() || c == '_' => {
+        tokens.push(Token::Ident(read_ident(input, &mut it)));
+      }
+      _ => return Err(QueryParseError::UnexpectedChar(c, start).into()),
+    }
+  }
+  Ok(tokens)
+}
+
+/// Reads a JSON-style string literal. The iterator must be positioned at the opening quote.
+fn read_string(input: &str, it: &mut Peekable<CharIndices>) -> Result<String> {
+  let (start, _) = it.next().unwrap();
+  let mut escaped = false;
+  for (i, c) in it {
+    if escaped {
+      escaped = false;
+    } else if c == '\\' {
+      escaped = true;
+    } else if c == '"' {
+      let text = &input[start..=i];
+      return serde_json::from_str(text)
+        .map_err(|_| QueryParseError::BadLiteral(text.to_string()).into());
+    }
+  }
+  Err(QueryParseError::UnexpectedEof.into())
+}
+
+/// Reads an identifier starting at the current position. Returns an empty string if there is
+/// none.
+fn read_ident(input: &str, it: &mut Peekable<CharIndices>) -> String {
+  let start = match it.peek() {
+    Some(&(i, _)) => i,
+    None => return String::new(),
+  };
+  let mut end = start;
+  while let Some(&(i, c)) = it.peek() {
+    if c.is_ascii_alphanumeric() || c == '_' {
+      end = i + 1;
+      it.next();
+    } else {
+      break;
+    }
+  }
+  input[start..end].to_string()
+}
@@ -0,0 +1,947 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use thiserror::Error;
+
+use crate::{
+  data::{
+    stats::{numeric_value, SchemaStats, SetStats, ValueRange},
+    value::PrimitiveValue,
+  },
+  schema::compile::{CompiledSchema, FieldAnnotationList, FieldType, PrimitiveType},
+};
+
+use super::ast::{
+  Aggregate, AggregateFn, CompareOp, Operand, PathQuery, PathSegment, Predicate, Statement, Value,
+};
+
+/// Estimated fraction of the members selected by a comparison on a key whose range is unknown.
+const DEFAULT_RANGE_SELECTIVITY: f64 = 1.0 / 3.0;
+
+/// Estimated fraction of the members selected by a `starts_with` on a key.
+const PREFIX_SELECTIVITY: f64 = 1.0 / 10.0;
+
+#[derive(Error, Debug)]
+pub enum QueryPlanError {
+  #[error("export not found: `{0}`")]
+  ExportNotFound(String),
+
+  #[error("field `{0}` not found in type `{1}`")]
+  FieldNotFound(String, String),
+
+  #[error("cannot access field `{0}` on non-table type `{1}`")]
+  FieldOnNonTable(String, String),
+
+  #[error("cannot filter on non-set type `{0}`")]
+  FilterOnNonSet(String),
+
+  #[error("filter on field `{0}` is not supported: only primitive fields can be compared")]
+  UnsupportedFilter(String),
+
+  #[error("type mismatch on field `{field}`: expected {expected}, got {got}")]
+  LiteralTypeMismatch {
+    field: String,
+    expected: PrimitiveType,
+    got: PrimitiveType,
+  },
+
+  #[error("only fields can be assigned to")]
+  InvalidAssignTarget,
+
+  #[error("cannot assign to primary key `{0}`")]
+  AssignToPrimaryKey(String),
+
+  #[error("cannot assign to sort key `{0}`: insert the set member instead")]
+  AssignToSortKey(String),
+
+  #[error("cannot assign to field `{0}`: it is maintained by the store")]
+  AssignToReservedField(String),
+
+  #[error("only set members selected by primary key can be deleted")]
+  InvalidDeleteTarget,
+
+  #[error("cannot insert into non-set type `{0}`")]
+  InsertIntoNonSet(String),
+
+  #[error("missing primary key for type `{0}`")]
+  MissingPrimaryKey(String),
+
+  #[error("value for `{0}` does not match type `{1}`")]
+  ValueTypeMismatch(String, String),
+
+  #[error("parameter `{0}` is used as both {1} and {2}")]
+  ParamTypeConflict(String, PrimitiveType, PrimitiveType),
+
+  #[error("expected {expected} parameters, got {got}")]
+  ParamCountMismatch { expected: usize, got: usize },
+
+  #[error("`starts_with` on field `{0}` is not supported: only string fields have prefixes")]
+  PrefixOnNonString(String),
+
+  #[error("subquery for the range bound on `{0}` may return multiple values")]
+  MultiValuedBound(String),
+
+  #[error("invalid arguments to `{0}`")]
+  InvalidAggregateArgument(AggregateFn),
+
+  #[error("cannot compute `{0}` of type `{1}`")]
+  UnsupportedAggregateType(AggregateFn, String),
+
+  #[error("stack underflow at step {0}")]
+  StackUnderflow(usize),
+
+  #[error("unbalanced stack: {0} values left at the end of the plan")]
+  UnbalancedStack(usize),
+}
+
+/// A step of a query plan.
+///
+/// Plans are executed on a stack machine. Values on the stack are paths into the storage plan,
+/// primitive values, loaded values, or lists of those if the path went through a set scan.
+/// Steps applied to a list are applied to each of its elements.
+#[derive(Clone, Debug)]
+pub enum QueryStep {
+  /// Pushes a constant.
+  Const(PrimitiveValue),
+
+  /// Pushes the value bound to a parameter slot. Plans must be bound before they are executed.
+  Param(u32),
+
+  /// Pushes the path to an exported field.
+  Root(String),
+
+  /// Pops a table path and pushes the path to one of its fields.
+  Field(String),
+
+  /// Pops a primary key and a set path, and pushes the path to the set member with that primary
+  /// key, or null if there is no such member.
+  PointGet,
+
+  /// Pops a set path and pushes the list of paths to all of its members, in ascending primary key
+  /// order, or descending if `reverse`.
+  RangeScanKeys { reverse: bool },
+
+  /// Pops the end key if `end` is bounded, the start key if `start` is bounded, and a set path,
+  /// and pushes the list of paths to the members with a primary key in the range.
+  RangeScan {
+    start: ScanBound,
+    end: ScanBound,
+    reverse: bool,
+  },
+
+  /// Like `RangeScan`, but with bounds on the `@sort_key` field of the members. Pushes the list of
+  /// paths to the members in the range, ordered by their sort key.
+  SortKeyScan { start: ScanBound, end: ScanBound },
+
+  /// Pops a string prefix and a set path, and pushes the list of paths to the members whose
+  /// primary key starts with the prefix, or whose sort key does if `by_sort_key`.
+  PrefixScan { by_sort_key: bool },
+
+  /// Pops an operand and a set member path, and keeps the member if its field compares with the
+  /// operand as given. Members that don't match are removed from lists, or replaced with null.
+  FilterBy(String, CompareOp),
+
+  /// Pops a path and pushes the value stored under it.
+  LensGet(FieldType),
+
+  /// Pops a path to a primitive (or a list of paths) and pushes the value stored under it, or null
+  /// if there is none. Used for subquery operands.
+  LoadPrimitive,
+
+  /// Pushes an object, to be written as a table.
+  ConstObject(BTreeMap<String, Value>),
+
+  /// Pops a value, a primary key and a set path, and writes the value as the set member with
+  /// that primary key.
+  PointPut(FieldType),
+
+  /// Pops a set member path (or a list of paths) of a `@soft_delete` type, and drops the members
+  /// that are deleted. Deleted members are removed from lists, or replaced with null.
+  SkipDeleted,
+
+  /// Pops a value and a path, and writes the value under the path. With `touch`, the path is a
+  /// field of a `@timestamps` table, whose `updated_at` is refreshed.
+  LensPut { ty: FieldType, touch: bool },
+
+  /// Pops a primary key and a set path, and deletes the set member of the given type with that
+  /// primary key.
+  PointDelete(FieldType),
+
+  /// Pops a path or a list of paths, and pushes the aggregate of the values under them. Nested
+  /// lists are flattened, and nulls are skipped.
+  Aggregate(AggregateFn),
+
+  /// Pops a loaded value and appends it to the query output.
+  Fulfill,
+}
+
+impl QueryStep {
+  /// Returns the number of values popped and pushed by this step.
+  pub fn stack_effect(&self) -> (usize, usize) {
+    match self {
+      Self::Const(_) | Self::Param(_) | Self::Root(_) | Self::ConstObject(_) => (0, 1),
+      Self::Field(_)
+      | Self::RangeScanKeys { .. }
+      | Self::SkipDeleted
+      | Self::LensGet(_)
+      | Self::LoadPrimitive
+      | Self::Aggregate(_) => (1, 1),
+      Self::PointGet | Self::PrefixScan { .. } | Self::FilterBy(_, _) => (2, 1),
+      Self::RangeScan { start, end, .. } | Self::SortKeyScan { start, end } => {
+        let bounded = [*start, *end]
+          .iter()
+          .filter(|x| **x != ScanBound::Unbounded)
+          .count();
+        (1 + bounded, 1)
+      }
+      Self::Fulfill => (1, 0),
+      Self::LensPut { .. } | Self::PointDelete(_) => (2, 0),
+      Self::PointPut(_) => (3, 0),
+    }
+  }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ScanBound {
+  Unbounded,
+  Included,
+  Excluded,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct QueryPlan {
+  pub steps: Vec<QueryStep>,
+
+  /// Parameter slots referenced by `QueryStep::Param`, in the order they were first planned.
+  pub params: Vec<QueryParam>,
+
+  /// Predicates of all filters, in planning order.
+  pub predicates: Vec<PlannedPredicate>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlannedPredicate {
+  pub field: String,
+  pub op: CompareOp,
+
+  /// Whether the predicate was pushed down into a point get or range scan on the primary key, or
+  /// into a scan on the sort key. Other predicates are evaluated on each scanned member.
+  pub pushed_down: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueryParam {
+  /// Name of a named placeholder, or `None` for `?`.
+  pub name: Option<String>,
+  pub ty: PrimitiveType,
+}
+
+impl QueryPlan {
+  /// Returns the slot of a named parameter.
+  pub fn param_index(&self, name: &str) -> Option<u32> {
+    self
+      .params
+      .iter()
+      .position(|x| x.name.as_deref() == Some(name))
+      .map(|x| x as u32)
+  }
+
+  /// Returns a copy of this plan with each parameter slot replaced by the corresponding value of
+  /// `args`, ready to be executed. The plan itself can be reused with different arguments.
+  pub fn bind(&self, args: &[PrimitiveValue]) -> Result<QueryPlan> {
+    if args.len() != self.params.len() {
+      return Err(
+        QueryPlanError::ParamCountMismatch {
+          expected: self.params.len(),
+          got: args.len(),
+        }
+        .into(),
+      );
+    }
+    let args = self
+      .params
+      .iter()
+      .zip(args)
+      .enumerate()
+      .map(|(i, (param, arg))| {
+        let name = match &param.name {
+          Some(x) => format!("${}", x),
+          None => format!("?{}", i),
+        };
+        coerce_primitive(&name, param.ty, arg)
+      })
+      .collect::<Result<Vec<_>>>()?;
+    let steps = self
+      .steps
+      .iter()
+      .map(|x| match x {
+        QueryStep::Param(i) => QueryStep::Const(args[*i as usize].clone()),
+        _ => x.clone(),
+      })
+      .collect();
+    Ok(QueryPlan {
+      steps,
+      params: vec![],
+      predicates: self.predicates.clone(),
+    })
+  }
+
+  /// Whether the plan only reads from the store.
+  pub fn is_read_only(&self) -> bool {
+    !self.steps.iter().any(|x| {
+      matches!(
+        x,
+        QueryStep::PointPut(_) | QueryStep::LensPut { .. } | QueryStep::PointDelete(_)
+      )
+    })
+  }
+
+  /// Checks that no step pops from an empty stack, and that the stack is empty at the end.
+  pub fn check_stack_balance(&self) -> Result<()> {
+    let mut depth = 0usize;
+    for (i, step) in self.steps.iter().enumerate() {
+      let (pop, push) = step.stack_effect();
+      depth = depth
+        .checked_sub(pop)
+        .ok_or_else(|| QueryPlanError::StackUnderflow(i))?;
+      depth += push;
+    }
+    if depth != 0 {
+      return Err(QueryPlanError::UnbalancedStack(depth).into());
+    }
+    Ok(())
+  }
+}
+
+pub struct QueryPlanner<'a> {
+  schema: &'a CompiledSchema,
+  stats: Option<&'a SchemaStats>,
+  include_deleted: bool,
+  plan: QueryPlan,
+}
+
+impl<'a> QueryPlanner<'a> {
+  pub fn new(schema: &'a CompiledSchema) -> Self {
+    Self {
+      schema,
+      stats: None,
+      include_deleted: false,
+      plan: QueryPlan::default(),
+    }
+  }
+
+  /// Creates a planner that chooses between the scans of a set by their estimated cost, from the
+  /// statistics collected by `collect_stats`. Sets without statistics are planned as by `new`.
+  pub fn with_stats(schema: &'a CompiledSchema, stats: &'a SchemaStats) -> Self {
+    Self {
+      schema,
+      stats: Some(stats),
+      include_deleted: false,
+      plan: QueryPlan::default(),
+    }
+  }
+
+  /// Makes the queries planned from now on also select deleted members of `@soft_delete` sets.
+  pub fn set_include_deleted(&mut self, include_deleted: bool) {
+    self.include_deleted = include_deleted;
+  }
+
+  /// Plans a query. Each query fulfills exactly one output value.
+  pub fn add_query(&mut self, query: &PathQuery) -> Result<()> {
+    let mut ty = self.plan_path(&query.root, &query.segments)?;
+
+    // Loading a set loads all of its members.
+    if let FieldType::Set(member_ty) = ty {
+      self.scan_all_members(&member_ty);
+      ty = *member_ty;
+    }
+    self.plan.steps.push(QueryStep::LensGet(ty));
+    self.plan.steps.push(QueryStep::Fulfill);
+    Ok(())
+  }
+
+  /// Pushes a scan over all members of the set path on top of the stack.
+  fn scan_all_members(&mut self, member_ty: &FieldType) {
+    self
+      .plan
+      .steps
+      .push(QueryStep::RangeScanKeys { reverse: false });
+    self.skip_deleted(member_ty);
+  }
+
+  /// Drops deleted members from the members just selected, if they are `@soft_delete` tables and
+  /// the planner does not include deleted members.
+  fn skip_deleted(&mut self, member_ty: &FieldType) {
+    let soft_delete = match member_ty {
+      FieldType::Table(x) => self
+        .schema
+        .types
+        .get(x)
+        .map(|x| x.has_soft_delete())
+        .unwrap_or(false),
+      _ => false,
+    };
+    if soft_delete && !self.include_deleted {
+      self.plan.steps.push(QueryStep::SkipDeleted);
+    }
+  }
+
+  /// Plans a statement. Reads and aggregates fulfill exactly one output value, and writes fulfill
+  /// none.
+  pub fn add_statement(&mut self, stmt: &Statement) -> Result<()> {
+    match stmt {
+      Statement::Read(query) => self.add_query(query),
+      Statement::Assign(query, value) => {
+        let (last, parent) = match query.segments.split_last() {
+          Some((PathSegment::Field(x), parent)) => (x, parent),
+          _ => return Err(QueryPlanError::InvalidAssignTarget.into()),
+        };
+        let mut ty = self.plan_path(&query.root, parent)?;
+        if let FieldType::Set(member_ty) = ty {
+          self.scan_all_members(&member_ty);
+          ty = *member_ty;
+        }
+        if let Some(pk) = self.primary_key_of(&ty) {
+          if pk == last.as_str() {
+            return Err(QueryPlanError::AssignToPrimaryKey(last.clone()).into());
+          }
+        }
+        if self.sort_key_of(&ty) == Some(last.as_str()) {
+          return Err(QueryPlanError::AssignToSortKey(last.clone()).into());
+        }
+        if self.is_reserved_field(&ty, last) {
+          return Err(QueryPlanError::AssignToReservedField(last.clone()).into());
+        }
+        let field_ty = self.lookup_field(&ty, last)?.clone();
+        self.plan.steps.push(QueryStep::Field(last.clone()));
+        self.plan_value(last, &field_ty, value)?;
+        self.plan.steps.push(QueryStep::LensPut {
+          ty: field_ty,
+          touch: self.has_timestamps(&ty),
+        });
+        Ok(())
+      }
+      Statement::Delete(query) => {
+        let (predicate, parent) = match query.segments.split_last() {
+          Some((PathSegment::Filter(x), parent)) if x.len() == 1 => (&x[0], parent),
+          _ => return Err(QueryPlanError::InvalidDeleteTarget.into()),
+        };
+        let member_ty = match self.plan_path(&query.root, parent)? {
+          FieldType::Set(x) => *x,
+          _ => return Err(QueryPlanError::InvalidDeleteTarget.into()),
+        };
+        if predicate.op != CompareOp::Eq
+          || self.primary_key_of(&member_ty) != Some(predicate.field.as_str())
+        {
+          return Err(QueryPlanError::InvalidDeleteTarget.into());
+        }
+        self.plan_operand(&member_ty, predicate)?;
+        self.record_predicate(predicate, true);
+        self.plan.steps.push(QueryStep::PointDelete(member_ty));
+        Ok(())
+      }
+      Statement::Insert(query, object) => {
+        let member_ty = match self.plan_path(&query.root, &query.segments)? {
+          FieldType::Set(x) => *x,
+          x => return Err(QueryPlanError::InsertIntoNonSet(x.to_string()).into()),
+        };
+        let pk = self
+          .primary_key_of(&member_ty)
+          .ok_or_else(|| QueryPlanError::MissingPrimaryKey(member_ty.to_string()))?;
+        let pk_ty = self.lookup_field(&member_ty, pk)?;
+        let key = match object.get(pk) {
+          Some(Value::Primitive(x)) => coerce_literal(pk, pk_ty, x)?,
+          _ => return Err(QueryPlanError::MissingPrimaryKey(member_ty.to_string()).into()),
+        };
+        let object = Value::Object(object.clone());
+        self.plan.steps.push(QueryStep::Const(key));
+        self.plan_value("", &member_ty, &object)?;
+        self.plan.steps.push(QueryStep::PointPut(member_ty));
+        Ok(())
+      }
+      Statement::Aggregate(query, aggregate) => self.add_aggregate(query, aggregate),
+    }
+  }
+
+  /// Plans an aggregate over the members selected by `query`, folded inside the executor. Fulfills
+  /// exactly one output value.
+  fn add_aggregate(&mut self, query: &PathQuery, aggregate: &Aggregate) -> Result<()> {
+    let mut ty = self.plan_path(&query.root, &query.segments)?;
+    if let FieldType::Set(member_ty) = ty {
+      self.scan_all_members(&member_ty);
+      ty = *member_ty;
+    }
+
+    let func = aggregate.func;
+    match (func, aggregate.fields.is_empty()) {
+      (AggregateFn::Count, true) => {}
+      (AggregateFn::Count, false) | (_, true) => {
+        return Err(QueryPlanError::InvalidAggregateArgument(func).into())
+      }
+      _ => {
+        for field in &aggregate.fields {
+          ty = self.lookup_field(&ty, field)?.clone();
+          self.plan.steps.push(QueryStep::Field(field.clone()));
+        }
+        let supported = match ty {
+          FieldType::Primitive(PrimitiveType::Int64)
+          | FieldType::Primitive(PrimitiveType::Double) => true,
+          FieldType::Primitive(_) => func != AggregateFn::Sum,
+          _ => false,
+        };
+        if !supported {
+          return Err(QueryPlanError::UnsupportedAggregateType(func, ty.to_string()).into());
+        }
+      }
+    }
+    self.plan.steps.push(QueryStep::Aggregate(func));
+    self.plan.steps.push(QueryStep::Fulfill);
+    Ok(())
+  }
+
+  pub fn finish(self) -> Result<QueryPlan> {
+    self.plan.check_stack_balance()?;
+    Ok(self.plan)
+  }
+
+  /// Plans the steps that push the path (or list of paths) to `root.segments...`, and returns
+  /// its type.
+  fn plan_path(&mut self, root: &str, segments: &[PathSegment]) -> Result<FieldType> {
+    let mut ty = self
+      .schema
+      .exports
+      .get(root)
+      .ok_or_else(|| QueryPlanError::ExportNotFound(root.to_string()))?
+      .clone();
+    self.plan.steps.push(QueryStep::Root(root.to_string()));
+
+    // Path of the current location in `SchemaStats::sets`, until the path enters set members.
+    let mut stats_path = Some(root.to_string());
+
+    for segment in segments {
+      match segment {
+        PathSegment::Field(name) => {
+          // Accessing a field of a set accesses the field of each member.
+          if let FieldType::Set(member_ty) = ty {
+            self.scan_all_members(&member_ty);
+            ty = *member_ty;
+            stats_path = None;
+          }
+          ty = self.lookup_field(&ty, name)?.clone();
+          self.plan.steps.push(QueryStep::Field(name.clone()));
+          if let Some(x) = &mut stats_path {
+            x.push('.');
+            x.push_str(name);
+          }
+        }
+        PathSegment::Filter(predicates) => {
+          let member_ty = match ty {
+            FieldType::Set(x) => *x,
+            _ => return Err(QueryPlanError::FilterOnNonSet(ty.to_string()).into()),
+          };
+          let set_stats = match (self.stats, stats_path.take()) {
+            (Some(stats), Some(path)) => stats.sets.get(&path),
+            _ => None,
+          };
+          self.plan_filter(&member_ty, predicates, set_stats)?;
+          ty = member_ty;
+        }
+      }
+    }
+    Ok(ty)
+  }
+
+  /// Plans the steps that push a literal value, checking it against `ty`.
+  fn plan_value(&mut self, field: &str, ty: &FieldType, value: &Value) -> Result<()> {
+    let value = self.check_value(field, ty, value)?;
+    match value {
+      Value::Primitive(x) => self.plan.steps.push(QueryStep::Const(x)),
+      Value::Object(x) => self.plan.steps.push(QueryStep::ConstObject(x)),
+    }
+    Ok(())
+  }
+
+  fn check_value(&self, field: &str, ty: &FieldType, value: &Value) -> Result<Value> {
+    match (ty, value) {
+      (FieldType::Primitive(_), Value::Primitive(x)) => {
+        Ok(Value::Primitive(coerce_literal(field, ty, x)?))
+      }
+      (FieldType::Table(_), Value::Object(fields)) => {
+        let mut checked = BTreeMap::new();
+        for (k, v) in fields {
+          if self.is_reserved_field(ty, k) {
+            return Err(QueryPlanError::AssignToReservedField(k.clone()).into());
+          }
+          let field_ty = self.lookup_field(ty, k)?;
+          checked.insert(k.clone(), self.check_value(k, field_ty, v)?);
+        }
+        Ok(Value::Object(checked))
+      }
+      (FieldType::Struct(members), Value::Object(fields)) => {
+        let mut checked = BTreeMap::new();
+        for (k, v) in fields {
+          let member_ty = members
+            .get(k.as_str())
+            .ok_or_else(|| QueryPlanError::FieldNotFound(k.clone(), ty.to_string()))?;
+          checked.insert(k.clone(), self.check_value(k, member_ty, v)?);
+        }
+        Ok(Value::Object(checked))
+      }
+      _ => Err(QueryPlanError::ValueTypeMismatch(field.to_string(), ty.to_string()).into()),
+    }
+  }
+
+  fn primary_key_of(&self, ty: &FieldType) -> Option<&'a str> {
+    let specialized_ty = match ty {
+      FieldType::Table(x) => self.schema.types.get(x)?,
+      _ => return None,
+    };
+    specialized_ty
+      .fields
+      .iter()
+      .find(|(_, (_, annotations))| annotations.as_slice().is_primary())
+      .map(|(k, _)| &**k)
+  }
+
+  fn sort_key_of(&self, ty: &FieldType) -> Option<&'a str> {
+    match ty {
+      FieldType::Table(x) => self.schema.types.get(x)?.sort_key().map(|x| x.0),
+      _ => None,
+    }
+  }
+
+  fn has_timestamps(&self, ty: &FieldType) -> bool {
+    match ty {
+      FieldType::Table(x) => self
+        .schema
+        .types
+        .get(x)
+        .map(|x| x.has_timestamps())
+        .unwrap_or(false),
+      _ => false,
+    }
+  }
+
+  /// Whether a field of a table type is maintained by the store and cannot be written by queries,
+  /// like the fields added by `@timestamps` and `@soft_delete`.
+  fn is_reserved_field(&self, ty: &FieldType, name: &str) -> bool {
+    let specialized_ty = match ty {
+      FieldType::Table(x) => self.schema.types.get(x),
+      _ => None,
+    };
+    specialized_ty
+      .and_then(|x| x.fields.get(name))
+      .map(|(_, annotations)| {
+        annotations.as_slice().is_timestamp() || annotations.as_slice().is_deleted_at()
+      })
+      .unwrap_or(false)
+  }
+
+  fn lookup_field(&self, ty: &FieldType, name: &str) -> Result<&'a FieldType> {
+    let table_name = match ty {
+      FieldType::Table(x) => x,
+      _ => return Err(QueryPlanError::FieldOnNonTable(name.to_string(), ty.to_string()).into()),
+    };
+    let specialized_ty = self
+      .schema
+      .types
+      .get(table_name)
+      .ok_or_else(|| QueryPlanError::FieldNotFound(name.to_string(), table_name.to_string()))?;
+    let (field_ty, _) = specialized_ty
+      .fields
+      .get(name)
+      .ok_or_else(|| QueryPlanError::FieldNotFound(name.to_string(), table_name.to_string()))?;
+    Ok(field_ty)
+  }
+
+  /// Plans the steps that select the members of a set matching all of `predicates`.
+  ///
+  /// An equality on the primary key is pushed down into a point get. Otherwise, comparisons on the
+  /// primary key are pushed down into a range scan, or if there are none, comparisons on the sort
+  /// key are pushed down into a sort key scan. With `set_stats`, comparisons on the sort key are
+  /// pushed down instead if the sort key scan is estimated to read fewer bytes than the scan on the
+  /// primary key, and not pushed down at all otherwise. A `starts_with` on the chosen key is pushed
+  /// down into a prefix scan if there are no comparisons on it. Remaining predicates are evaluated
+  /// on each member. Members are returned in the order of the chosen scan.
+  fn plan_filter(
+    &mut self,
+    member_ty: &FieldType,
+    predicates: &[Predicate],
+    set_stats: Option<&SetStats>,
+  ) -> Result<()> {
+    for p in predicates {
+      if p.op == CompareOp::StartsWith
+        && *self.lookup_field(member_ty, &p.field)? != FieldType::Primitive(PrimitiveType::String)
+      {
+        return Err(QueryPlanError::PrefixOnNonString(p.field.clone()).into());
+      }
+    }
+
+    let pk = self.primary_key_of(member_ty);
+    let sort_key = self.sort_key_of(member_ty);
+    let is_range = |p: &Predicate| {
+      matches!(
+        p.op,
+        CompareOp::Gt | CompareOp::Ge | CompareOp::Lt | CompareOp::Le | CompareOp::StartsWith
+      )
+    };
+    let use_sort_key = match sort_key {
+      Some(sort_key)
+        if !predicates
+          .iter()
+          .any(|p| Some(p.field.as_str()) == pk && p.op == CompareOp::Eq)
+          && predicates
+            .iter()
+            .any(|p| p.field == sort_key && is_range(p)) =>
+      {
+        match set_stats {
+          Some(stats) => self.prefer_sort_key_scan(member_ty, pk, sort_key, predicates, stats)?,
+          None => !predicates
+            .iter()
+            .any(|p| Some(p.field.as_str()) == pk && is_range(p)),
+        }
+      }
+      _ => false,
+    };
+    let scan_key = if use_sort_key { sort_key } else { pk };
+    let on_pk = |p: &Predicate| Some(p.field.as_str()) == pk;
+    let on_scan_key = |p: &Predicate| Some(p.field.as_str()) == scan_key;
+    let mut pushed_down = vec![false; predicates.len()];
+
+    if let Some(i) = predicates
+      .iter()
+      .position(|p| on_pk(p) && p.op == CompareOp::Eq)
+    {
+      self.plan_operand(member_ty, &predicates[i])?;
+      self.plan.steps.push(QueryStep::PointGet);
+      pushed_down[i] = true;
+    } else {
+      let lower = predicates
+        .iter()
+        .position(|p| on_scan_key(p) && matches!(p.op, CompareOp::Gt | CompareOp::Ge));
+      let upper = predicates
+        .iter()
+        .position(|p| on_scan_key(p) && matches!(p.op, CompareOp::Lt | CompareOp::Le));
+      let mut bound = |i: Option<usize>, exclusive_op: CompareOp| -> Result<ScanBound> {
+        let i = match i {
+          Some(x) => x,
+          None => return Ok(ScanBound::Unbounded),
+        };
+        if self.plan_operand(member_ty, &predicates[i])? {
+          return Err(QueryPlanError::MultiValuedBound(predicates[i].field.clone()).into());
+        }
+        pushed_down[i] = true;
+        Ok(if predicates[i].op == exclusive_op {
+          ScanBound::Excluded
+        } else {
+          ScanBound::Included
+        })
+      };
+      let start = bound(lower, CompareOp::Gt)?;
+      let end = bound(upper, CompareOp::Lt)?;
+      let prefix = predicates
+        .iter()
+        .position(|p| on_scan_key(p) && p.op == CompareOp::StartsWith);
+      if let (ScanBound::Unbounded, ScanBound::Unbounded, Some(i)) = (start, end, prefix) {
+        if self.plan_operand(member_ty, &predicates[i])? {
+          return Err(QueryPlanError::MultiValuedBound(predicates[i].field.clone()).into());
+        }
+        pushed_down[i] = true;
+        self.plan.steps.push(QueryStep::PrefixScan {
+          by_sort_key: use_sort_key,
+        });
+      } else if use_sort_key {
+        self.plan.steps.push(QueryStep::SortKeyScan { start, end });
+      } else if start == ScanBound::Unbounded && end == ScanBound::Unbounded {
+        self
+          .plan
+          .steps
+          .push(QueryStep::RangeScanKeys { reverse: false });
+      } else {
+        self.plan.steps.push(QueryStep::RangeScan {
+          start,
+          end,
+          reverse: false,
+        });
+      }
+    }
+    self.skip_deleted(member_ty);
+
+    for (p, pushed_down) in predicates.iter().zip(pushed_down) {
+      if !pushed_down {
+        self.plan_operand(member_ty, p)?;
+        self
+          .plan
+          .steps
+          .push(QueryStep::FilterBy(p.field.clone(), p.op));
+      }
+      self.record_predicate(p, pushed_down);
+    }
+    Ok(())
+  }
+
+  /// Returns whether a scan on the sort key is estimated to read fewer bytes than a scan on the
+  /// primary key, given the comparisons on each key.
+  fn prefer_sort_key_scan(
+    &self,
+    member_ty: &FieldType,
+    pk: Option<&str>,
+    sort_key: &str,
+    predicates: &[Predicate],
+    stats: &SetStats,
+  ) -> Result<bool> {
+    // The sort key index is not ready, or the member type had no sort key.
+    let sort_key_sizes = match &stats.sort_key_sizes {
+      Some(x) => x,
+      None => return Ok(false),
+    };
+    let pk_selectivity = match pk {
+      Some(pk) => self.estimate_selectivity(member_ty, pk, stats.key_range, predicates)?,
+      None => 1.0,
+    };
+    let sort_key_selectivity =
+      self.estimate_selectivity(member_ty, sort_key, stats.sort_key_range, predicates)?;
+    Ok(
+      sort_key_selectivity * (sort_key_sizes.total as f64)
+        < pk_selectivity * (stats.key_sizes.total as f64),
+    )
+  }
+
+  /// Estimates the fraction of the members selected by the range comparisons on `field`.
+  ///
+  /// Literal bounds on a field whose range of values is known are interpolated within the range.
+  /// Other comparisons are assumed to select a fixed fraction of the members.
+  fn estimate_selectivity(
+    &self,
+    member_ty: &FieldType,
+    field: &str,
+    range: Option<ValueRange>,
+    predicates: &[Predicate],
+  ) -> Result<f64> {
+    let field_ty = self.lookup_field(member_ty, field)?;
+    let (mut lower, mut upper) = (0f64, 1f64);
+    let mut factor = 1f64;
+    for p in predicates.iter().filter(|p| p.field == field) {
+      if p.op == CompareOp::StartsWith {
+        factor *= PREFIX_SELECTIVITY;
+        continue;
+      }
+      let is_lower = matches!(p.op, CompareOp::Gt | CompareOp::Ge);
+      if !is_lower && !matches!(p.op, CompareOp::Lt | CompareOp::Le) {
+        continue;
+      }
+      let value = match &p.value {
+        Operand::Literal(x) => numeric_value(field_ty, &coerce_literal(field, field_ty, x)?),
+        _ => None,
+      };
+      match (value, range) {
+        (Some(x), Some(range)) if range.max > range.min => {
+          let position = ((x - range.min) / (range.max - range.min))
+            .max(0.0)
+            .min(1.0);
+          if is_lower {
+            lower = lower.max(position);
+          } else {
+            upper = upper.min(position);
+          }
+        }
+        _ => factor *= DEFAULT_RANGE_SELECTIVITY,
+      }
+    }
+    Ok((upper - lower).max(0.0) * factor)
+  }
+
+  /// Plans the steps that push the right-hand side of `predicate`, checked against the type of the
+  /// compared field. Returns whether the operand is a subquery that may yield multiple values, in
+  /// which case the predicate holds if it holds for any of them.
+  fn plan_operand(&mut self, member_ty: &FieldType, predicate: &Predicate) -> Result<bool> {
+    let field_ty = self.lookup_field(member_ty, &predicate.field)?;
+    let ty = match field_ty {
+      FieldType::Primitive(x) => *x,
+      _ => return Err(QueryPlanError::UnsupportedFilter(predicate.field.clone()).into()),
+    };
+    let step = match &predicate.value {
+      Operand::Literal(x) => QueryStep::Const(coerce_literal(&predicate.field, field_ty, x)?),
+      Operand::Positional => QueryStep::Param(self.add_param(None, ty)?),
+      Operand::Named(name) => QueryStep::Param(self.add_param(Some(name), ty)?),
+      Operand::Path(query) => {
+        // The subquery is planned inline: its steps run on top of the outer path on the stack.
+        let start = self.plan.steps.len();
+        let operand_ty = self.plan_path(&query.root, &query.segments)?;
+        if operand_ty != *field_ty {
+          return Err(
+            QueryPlanError::ValueTypeMismatch(predicate.field.clone(), operand_ty.to_string())
+              .into(),
+          );
+        }
+        let multi_valued = self.plan.steps[start..].iter().any(|x| {
+          matches!(
+            x,
+            QueryStep::RangeScanKeys { .. }
+              | QueryStep::RangeScan { .. }
+              | QueryStep::SortKeyScan { .. }
+              | QueryStep::PrefixScan { .. }
+          )
+        });
+        self.plan.steps.push(QueryStep::LoadPrimitive);
+        return Ok(multi_valued);
+      }
+    };
+    self.plan.steps.push(step);
+    Ok(false)
+  }
+
+  fn record_predicate(&mut self, predicate: &Predicate, pushed_down: bool) {
+    self.plan.predicates.push(PlannedPredicate {
+      field: predicate.field.clone(),
+      op: predicate.op,
+      pushed_down,
+    });
+  }
+
+  /// Allocates a parameter slot, or reuses the slot of a named parameter with the same name.
+  fn add_param(&mut self, name: Option<&String>, ty: PrimitiveType) -> Result<u32> {
+    if let Some(name) = name {
+      if let Some(index) = self.plan.param_index(name) {
+        let existing = self.plan.params[index as usize].ty;
+        if existing != ty {
+          return Err(QueryPlanError::ParamTypeConflict(name.clone(), existing, ty).into());
+        }
+        return Ok(index);
+      }
+    }
+    self.plan.params.push(QueryParam {
+      name: name.cloned(),
+      ty,
+    });
+    Ok((self.plan.params.len() - 1) as u32)
+  }
+}
+
+/// Checks a literal against the type of the field it is compared with. Integer literals are
+/// accepted for double fields.
+fn coerce_literal(field: &str, ty: &FieldType, value: &PrimitiveValue) -> Result<PrimitiveValue> {
+  match ty {
+    FieldType::Primitive(x) => coerce_primitive(field, *x, value),
+    _ => Err(QueryPlanError::UnsupportedFilter(field.to_string()).into()),
+  }
+}
+
+fn coerce_primitive(
+  field: &str,
+  expected: PrimitiveType,
+  value: &PrimitiveValue,
+) -> Result<PrimitiveValue> {
+  match (expected, value) {
+    (PrimitiveType::Double, PrimitiveValue::Int64(x)) => Ok(PrimitiveValue::double(*x as f64)),
+    _ if value.get_type() == expected => Ok(value.clone()),
+    _ => Err(
+      QueryPlanError::LiteralTypeMismatch {
+        field: field.to_string(),
+        expected,
+        got: value.get_type(),
+      }
+      .into(),
+    ),
+  }
+}
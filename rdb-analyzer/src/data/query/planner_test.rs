@@ -0,0 +1,731 @@
+use bumpalo::Bump;
+
+use crate::{
+  data::{
+    stats::{SchemaStats, SetStats, SizeDistribution, ValueRange},
+    value::PrimitiveValue,
+  },
+  schema::{
+    compile::{compile, CompiledSchema},
+    grammar::parse,
+  },
+};
+
+use super::{
+  ast::{AggregateFn, CompareOp},
+  parser::{parse_path_query, parse_statement},
+  planner::{QueryPlan, QueryPlanner, QueryStep, ScanBound},
+};
+
+const SCHEMA: &str = r#"
+type Item {
+  @primary
+  id: int64,
+  name: string,
+  tags: set<Tag>,
+}
+type Tag {
+  @primary
+  name: string,
+  weight: double,
+}
+export set<Item> items;
+export Item some_item;
+"#;
+
+fn compile_schema(schema: &str) -> CompiledSchema {
+  let alloc = Bump::new();
+  let ast = parse(&alloc, schema).unwrap();
+  compile(&ast).unwrap()
+}
+
+fn plan_queries(schema: &CompiledSchema, queries: &[&str]) -> anyhow::Result<QueryPlan> {
+  let mut planner = QueryPlanner::new(schema);
+  for q in queries {
+    planner.add_query(&parse_path_query(q)?)?;
+  }
+  planner.finish()
+}
+
+#[test]
+fn point_get_on_primary_key() {
+  let schema = compile_schema(SCHEMA);
+  let plan = plan_queries(&schema, &[".items[id = 42].name"]).unwrap();
+  println!("{:?}", plan);
+  assert!(matches!(
+    plan.steps.as_slice(),
+    [
+      QueryStep::Root(_),
+      QueryStep::Const(PrimitiveValue::Int64(42)),
+      QueryStep::PointGet,
+      QueryStep::Field(_),
+      QueryStep::LensGet(_),
+      QueryStep::Fulfill,
+    ]
+  ));
+}
+
+#[test]
+fn scan_on_set_field_access() {
+  let schema = compile_schema(SCHEMA);
+  let plan = plan_queries(&schema, &[".items.tags[name = \"a\"].weight"]).unwrap();
+  println!("{:?}", plan);
+  assert!(matches!(
+    plan.steps.as_slice(),
+    [
+      QueryStep::Root(_),
+      QueryStep::RangeScanKeys { reverse: false },
+      QueryStep::Field(_),
+      QueryStep::Const(PrimitiveValue::String(_)),
+      QueryStep::PointGet,
+      QueryStep::Field(_),
+      QueryStep::LensGet(_),
+      QueryStep::Fulfill,
+    ]
+  ));
+}
+
+#[test]
+fn stack_balance() {
+  let schema = compile_schema(SCHEMA);
+  let plan = plan_queries(
+    &schema,
+    &[
+      ".items",
+      ".items[id = 1]",
+      ".items[id = 1].tags[name = \"x\"]",
+      ".some_item.tags.weight",
+    ],
+  )
+  .unwrap();
+  plan.check_stack_balance().unwrap();
+
+  let mut plan = plan;
+  plan.steps.push(QueryStep::PointGet);
+  assert!(plan.check_stack_balance().is_err());
+
+  let plan = QueryPlan {
+    steps: vec![QueryStep::Root("items".into())],
+    ..Default::default()
+  };
+  assert!(plan.check_stack_balance().is_err());
+}
+
+#[test]
+fn plan_errors() {
+  let schema = compile_schema(SCHEMA);
+  for q in &[
+    ".unknown",
+    ".items[id = 1].unknown",
+    ".items[tags = 1]",
+    ".items[id = \"x\"]",
+    ".items[id > 1, name < 1]",
+    ".some_item[id = 1]",
+    ".items[id = 1].name.x",
+  ] {
+    assert!(plan_queries(&schema, &[q]).is_err(), "{}", q);
+  }
+}
+
+#[test]
+fn parse_errors() {
+  for q in &[
+    "",
+    "items",
+    ".items[",
+    ".items[id 1]",
+    ".items[id = 1",
+    ".items.",
+    ".items#",
+    ".items[id => 1]",
+    ".items[id = 1,]",
+    ".items[]",
+  ] {
+    assert!(parse_path_query(q).is_err(), "{}", q);
+  }
+  let q = parse_path_query(".items[id = -1.5e3].name").unwrap();
+  println!("{:?}", q);
+}
+
+#[test]
+fn write_statements() {
+  let schema = compile_schema(SCHEMA);
+  let mut planner = QueryPlanner::new(&schema);
+  for q in &[
+    ".items[id = 1].name = \"x\"",
+    ".items.name = \"x\"",
+    "delete .items[id = 1]",
+    "delete .items[id = 1].tags[name = \"a\"]",
+    "insert .items { id: 2, name: \"y\" }",
+    "insert .items[id = 2].tags { name: \"a\", weight: 1 }",
+  ] {
+    planner.add_statement(&parse_statement(q).unwrap()).unwrap();
+  }
+  let plan = planner.finish().unwrap();
+  println!("{:?}", plan);
+  assert!(!plan.steps.iter().any(|x| matches!(x, QueryStep::Fulfill)));
+  assert!(!plan.is_read_only());
+  assert!(
+    plan_queries(&schema, &[".items[id = 1].name", ".items[id > ?]"])
+      .unwrap()
+      .is_read_only()
+  );
+
+  for q in &[
+    ".items[id = 1].id = 2",
+    ".items[id = 1] = { name: \"x\" }",
+    ".items[id = 1].name = 1",
+    ".items[id = 1].name = { x: 1 }",
+    "delete .items[id = 1].name",
+    "delete .items[id > 1]",
+    "delete .items[id = 1, name = \"x\"]",
+    "delete .items",
+    "insert .items { name: \"x\" }",
+    "insert .items { id: 1, unknown: 1 }",
+    "insert .some_item { id: 1 }",
+  ] {
+    let mut planner = QueryPlanner::new(&schema);
+    assert!(
+      parse_statement(q)
+        .and_then(|x| planner.add_statement(&x))
+        .is_err(),
+      "{}",
+      q
+    );
+  }
+}
+
+#[test]
+fn placeholders() {
+  let schema = compile_schema(SCHEMA);
+  let plan = plan_queries(
+    &schema,
+    &[
+      ".items[id = ?].name",
+      ".items[id = $id].tags[name = $tag].weight",
+      ".items[id = $id].name",
+    ],
+  )
+  .unwrap();
+  println!("{:?}", plan);
+  assert_eq!(plan.params.len(), 3);
+  assert_eq!(plan.param_index("id"), Some(1));
+  assert_eq!(plan.param_index("tag"), Some(2));
+  assert_eq!(
+    plan
+      .steps
+      .iter()
+      .filter(|x| matches!(x, QueryStep::Param(1)))
+      .count(),
+    2
+  );
+
+  let bound = plan
+    .bind(&[
+      PrimitiveValue::Int64(1),
+      PrimitiveValue::Int64(2),
+      PrimitiveValue::String("a".into()),
+    ])
+    .unwrap();
+  assert!(bound.params.is_empty());
+  assert!(!bound.steps.iter().any(|x| matches!(x, QueryStep::Param(_))));
+  assert!(matches!(
+    bound.steps[1],
+    QueryStep::Const(PrimitiveValue::Int64(1))
+  ));
+
+  assert!(plan.bind(&[PrimitiveValue::Int64(1)]).is_err());
+  assert!(plan
+    .bind(&[
+      PrimitiveValue::Int64(1),
+      PrimitiveValue::String("x".into()),
+      PrimitiveValue::String("a".into()),
+    ])
+    .is_err());
+
+  // The same name cannot be used for keys of different types.
+  assert!(plan_queries(
+    &schema,
+    &[".items[id = $x]", ".items[id = 1].tags[name = $x]"]
+  )
+  .is_err());
+  for q in &[".items[id = $]", ".items[id = ??]", ".items[$id = 1]"] {
+    assert!(parse_path_query(q).is_err(), "{}", q);
+  }
+}
+
+#[test]
+fn multi_predicate_filters() {
+  let schema = compile_schema(SCHEMA);
+  let plan = plan_queries(&schema, &[".items[name = \"x\", id = 1]"]).unwrap();
+  println!("{:?}", plan);
+  assert!(matches!(
+    plan.steps.as_slice(),
+    [
+      QueryStep::Root(_),
+      QueryStep::Const(PrimitiveValue::Int64(1)),
+      QueryStep::PointGet,
+      QueryStep::Const(PrimitiveValue::String(_)),
+      QueryStep::FilterBy(_, CompareOp::Eq),
+      QueryStep::LensGet(_),
+      QueryStep::Fulfill,
+    ]
+  ));
+  assert_eq!(
+    plan
+      .predicates
+      .iter()
+      .map(|x| (x.field.as_str(), x.pushed_down))
+      .collect::<Vec<_>>(),
+    vec![("name", false), ("id", true)]
+  );
+
+  let plan = plan_queries(&schema, &[".items[id >= 10, id < 20].name"]).unwrap();
+  println!("{:?}", plan);
+  assert!(matches!(
+    plan.steps.as_slice(),
+    [
+      QueryStep::Root(_),
+      QueryStep::Const(PrimitiveValue::Int64(10)),
+      QueryStep::Const(PrimitiveValue::Int64(20)),
+      QueryStep::RangeScan {
+        start: ScanBound::Included,
+        end: ScanBound::Excluded,
+        reverse: false,
+      },
+      QueryStep::Field(_),
+      QueryStep::LensGet(_),
+      QueryStep::Fulfill,
+    ]
+  ));
+  assert!(plan.predicates.iter().all(|x| x.pushed_down));
+
+  let plan = plan_queries(&schema, &[".items[name >= \"a\", id > 1, id > 2]"]).unwrap();
+  println!("{:?}", plan);
+  assert!(matches!(
+    plan.steps.as_slice(),
+    [
+      QueryStep::Root(_),
+      QueryStep::Const(PrimitiveValue::Int64(1)),
+      QueryStep::RangeScan {
+        start: ScanBound::Excluded,
+        end: ScanBound::Unbounded,
+        reverse: false,
+      },
+      QueryStep::Const(_),
+      QueryStep::FilterBy(_, CompareOp::Ge),
+      QueryStep::Const(PrimitiveValue::Int64(2)),
+      QueryStep::FilterBy(_, CompareOp::Gt),
+      QueryStep::LensGet(_),
+      QueryStep::Fulfill,
+    ]
+  ));
+  plan.check_stack_balance().unwrap();
+
+  let plan = plan_queries(&schema, &[".items[name = \"x\"]"]).unwrap();
+  assert!(matches!(
+    plan.steps.as_slice(),
+    [
+      QueryStep::Root(_),
+      QueryStep::RangeScanKeys { reverse: false },
+      QueryStep::Const(_),
+      QueryStep::FilterBy(_, CompareOp::Eq),
+      QueryStep::LensGet(_),
+      QueryStep::Fulfill,
+    ]
+  ));
+  assert!(!plan.predicates[0].pushed_down);
+}
+
+#[test]
+fn aggregates() {
+  let schema = compile_schema(SCHEMA);
+  let mut planner = QueryPlanner::new(&schema);
+  for q in &[
+    ".items | count()",
+    ".items[id > 1] | sum(.id)",
+    ".items.tags | max(.weight)",
+    ".items | min(.name)",
+    ".some_item.tags | count()",
+  ] {
+    planner.add_statement(&parse_statement(q).unwrap()).unwrap();
+  }
+  let plan = planner.finish().unwrap();
+  println!("{:?}", plan);
+  assert_eq!(
+    plan
+      .steps
+      .iter()
+      .filter(|x| matches!(x, QueryStep::Aggregate(_)))
+      .count(),
+    5
+  );
+  assert!(matches!(
+    &plan.steps[..3],
+    [
+      QueryStep::Root(_),
+      QueryStep::RangeScanKeys { reverse: false },
+      QueryStep::Aggregate(AggregateFn::Count),
+    ]
+  ));
+
+  for q in &[
+    ".items | count(.id)",
+    ".items | sum()",
+    ".items | sum(.name)",
+    ".items | max(.tags)",
+    ".items | min(.unknown)",
+  ] {
+    let mut planner = QueryPlanner::new(&schema);
+    assert!(
+      parse_statement(q)
+        .and_then(|x| planner.add_statement(&x))
+        .is_err(),
+      "{}",
+      q
+    );
+  }
+  for q in &[
+    ".items | avg(.id)",
+    ".items | count(",
+    ".items |",
+    ".items | sum(id)",
+  ] {
+    assert!(parse_statement(q).is_err(), "{}", q);
+  }
+}
+
+#[test]
+fn subquery_operands() {
+  let schema = compile_schema(SCHEMA);
+  let plan = plan_queries(&schema, &[".items[id = .some_item.id].name"]).unwrap();
+  println!("{:?}", plan);
+  assert!(matches!(
+    plan.steps.as_slice(),
+    [
+      QueryStep::Root(_),
+      QueryStep::Root(_),
+      QueryStep::Field(_),
+      QueryStep::LoadPrimitive,
+      QueryStep::PointGet,
+      QueryStep::Field(_),
+      QueryStep::LensGet(_),
+      QueryStep::Fulfill,
+    ]
+  ));
+  plan_queries(
+    &schema,
+    &[
+      ".items[id = .items[name = \"x\"].id]",
+      ".items[name = .items.name]",
+      ".items[id > .items[id = 1].id]",
+    ],
+  )
+  .unwrap()
+  .check_stack_balance()
+  .unwrap();
+
+  for q in &[
+    ".items[id = .some_item.name]",
+    ".items[id = .some_item]",
+    ".items[id > .items.id]",
+    ".items[id = .unknown.id]",
+  ] {
+    assert!(plan_queries(&schema, &[q]).is_err(), "{}", q);
+  }
+}
+
+#[test]
+fn sort_key_scans() {
+  let schema = compile_schema(
+    r#"
+    type Event {
+      @primary
+      id: int64,
+      @sort_key
+      at: int64,
+      name: string,
+    }
+    export set<Event> events;
+  "#,
+  );
+  let plan = plan_queries(&schema, &[".events[at >= 10, name = \"x\"].id"]).unwrap();
+  println!("{:?}", plan);
+  assert!(matches!(
+    plan.steps.as_slice(),
+    [
+      QueryStep::Root(_),
+      QueryStep::Const(PrimitiveValue::Int64(10)),
+      QueryStep::SortKeyScan {
+        start: ScanBound::Included,
+        end: ScanBound::Unbounded,
+      },
+      QueryStep::Const(_),
+      QueryStep::FilterBy(_, CompareOp::Eq),
+      QueryStep::Field(_),
+      QueryStep::LensGet(_),
+      QueryStep::Fulfill,
+    ]
+  ));
+  plan.check_stack_balance().unwrap();
+
+  // Ranges on the primary key take precedence.
+  let plan = plan_queries(&schema, &[".events[at >= 10, id < 3]"]).unwrap();
+  assert!(matches!(
+    plan.steps[2],
+    QueryStep::RangeScan {
+      start: ScanBound::Unbounded,
+      end: ScanBound::Excluded,
+      reverse: false,
+    }
+  ));
+
+  let mut planner = QueryPlanner::new(&schema);
+  let err = planner
+    .add_statement(&parse_statement(".events[id = 1].at = 5").unwrap())
+    .unwrap_err();
+  assert!(err.to_string().contains("cannot assign to sort key"));
+}
+
+#[test]
+fn timestamps() {
+  let schema = compile_schema(
+    r#"
+    @timestamps
+    type Note {
+      @primary
+      id: int64,
+      text: string,
+    }
+    export set<Note> notes;
+  "#,
+  );
+  let mut planner = QueryPlanner::new(&schema);
+  planner
+    .add_statement(&parse_statement(".notes[id = 1].text = \"x\"").unwrap())
+    .unwrap();
+  let plan = planner.finish().unwrap();
+  assert!(matches!(
+    plan.steps.last(),
+    Some(QueryStep::LensPut { touch: true, .. })
+  ));
+
+  for q in &[
+    ".notes[id = 1].created_at = 1",
+    ".notes.updated_at = 1",
+    "insert .notes { id: 1, created_at: 1 }",
+  ] {
+    let mut planner = QueryPlanner::new(&schema);
+    let err = planner
+      .add_statement(&parse_statement(q).unwrap())
+      .unwrap_err();
+    assert!(err.to_string().contains("maintained by the store"), "{}", q);
+  }
+}
+
+#[test]
+fn soft_delete() {
+  let schema = compile_schema(
+    r#"
+    @soft_delete
+    type Doc {
+      @primary
+      id: int64,
+      name: string,
+    }
+    export set<Doc> docs;
+  "#,
+  );
+  let plan = plan_queries(&schema, &[".docs.name", ".docs[id = 1]"]).unwrap();
+  assert!(matches!(
+    plan.steps.as_slice(),
+    [
+      QueryStep::Root(_),
+      QueryStep::RangeScanKeys { .. },
+      QueryStep::SkipDeleted,
+      QueryStep::Field(_),
+      QueryStep::LensGet(_),
+      QueryStep::Fulfill,
+      QueryStep::Root(_),
+      QueryStep::Const(_),
+      QueryStep::PointGet,
+      QueryStep::SkipDeleted,
+      QueryStep::LensGet(_),
+      QueryStep::Fulfill,
+    ]
+  ));
+
+  let mut planner = QueryPlanner::new(&schema);
+  planner.set_include_deleted(true);
+  planner
+    .add_query(&parse_path_query(".docs.name").unwrap())
+    .unwrap();
+  let plan = planner.finish().unwrap();
+  assert!(!plan
+    .steps
+    .iter()
+    .any(|x| matches!(x, QueryStep::SkipDeleted)));
+
+  let mut planner = QueryPlanner::new(&schema);
+  let err = planner
+    .add_statement(&parse_statement(".docs[id = 1].deleted_at = 1").unwrap())
+    .unwrap_err();
+  assert!(err.to_string().contains("maintained by the store"));
+}
+
+#[test]
+fn prefix_scans() {
+  let schema = compile_schema(
+    r#"
+    type User {
+      @primary
+      name: string,
+      @sort_key
+      nick: string,
+      age: int64,
+    }
+    export set<User> users;
+  "#,
+  );
+  let plan = plan_queries(&schema, &[".users[name starts_with \"al\"].age"]).unwrap();
+  println!("{:?}", plan);
+  assert!(matches!(
+    plan.steps.as_slice(),
+    [
+      QueryStep::Root(_),
+      QueryStep::Const(PrimitiveValue::String(_)),
+      QueryStep::PrefixScan { by_sort_key: false },
+      QueryStep::Field(_),
+      QueryStep::LensGet(_),
+      QueryStep::Fulfill,
+    ]
+  ));
+  plan.check_stack_balance().unwrap();
+
+  let plan = plan_queries(&schema, &[".users[nick starts_with \"al\", age > 3]"]).unwrap();
+  assert!(matches!(
+    plan.steps.as_slice(),
+    [
+      QueryStep::Root(_),
+      QueryStep::Const(_),
+      QueryStep::PrefixScan { by_sort_key: true },
+      QueryStep::Const(_),
+      QueryStep::FilterBy(_, CompareOp::Gt),
+      QueryStep::LensGet(_),
+      QueryStep::Fulfill,
+    ]
+  ));
+
+  // Comparisons on the same key take precedence.
+  let plan = plan_queries(&schema, &[".users[name starts_with \"al\", name < \"b\"]"]).unwrap();
+  assert!(matches!(
+    plan.steps.as_slice(),
+    [
+      QueryStep::Root(_),
+      QueryStep::Const(_),
+      QueryStep::RangeScan { .. },
+      QueryStep::Const(_),
+      QueryStep::FilterBy(_, CompareOp::StartsWith),
+      QueryStep::LensGet(_),
+      QueryStep::Fulfill,
+    ]
+  ));
+
+  let err = plan_queries(&schema, &[".users[age starts_with \"1\"]"]).unwrap_err();
+  assert!(err.to_string().contains("only string fields have prefixes"));
+}
+
+#[test]
+fn scans_chosen_by_stats() {
+  let schema = compile_schema(
+    r#"
+    type Event {
+      @primary
+      id: int64,
+      @sort_key
+      at: int64,
+    }
+    export set<Event> events;
+  "#,
+  );
+  let sizes = |count: u64, size: u64| SizeDistribution {
+    count,
+    total: count * size,
+    min: size,
+    max: size,
+    histogram: vec![],
+  };
+  let mut stats = SchemaStats::default();
+  stats.sets.insert(
+    "events".into(),
+    SetStats {
+      collected_at: 0,
+      cardinality: 1000,
+      exact: true,
+      key_sizes: sizes(1000, 20),
+      sort_key_sizes: Some(sizes(1000, 30)),
+      key_range: Some(ValueRange {
+        min: 0.0,
+        max: 1000.0,
+      }),
+      sort_key_range: Some(ValueRange {
+        min: 0.0,
+        max: 100.0,
+      }),
+    },
+  );
+  let plan_with_stats = |stats: &SchemaStats, query: &str| {
+    let mut planner = QueryPlanner::with_stats(&schema, stats);
+    planner
+      .add_query(&parse_path_query(query).unwrap())
+      .unwrap();
+    planner.finish().unwrap()
+  };
+
+  // A narrow range on the sort key beats a wide range on the primary key.
+  let plan = plan_with_stats(&stats, ".events[at >= 90, id > 100]");
+  println!("{:?}", plan);
+  assert!(matches!(
+    plan.steps[2],
+    QueryStep::SortKeyScan {
+      start: ScanBound::Included,
+      end: ScanBound::Unbounded,
+    }
+  ));
+
+  // A narrow range on the primary key beats a wide range on the sort key.
+  let plan = plan_with_stats(&stats, ".events[at >= 10, id < 100]");
+  assert!(matches!(
+    plan.steps[2],
+    QueryStep::RangeScan {
+      start: ScanBound::Unbounded,
+      end: ScanBound::Excluded,
+      reverse: false,
+    }
+  ));
+
+  // A wide range on the sort key is evaluated on a scan of all members.
+  let plan = plan_with_stats(&stats, ".events[at >= 10]");
+  assert!(matches!(
+    plan.steps.as_slice(),
+    [
+      QueryStep::Root(_),
+      QueryStep::RangeScanKeys { reverse: false },
+      QueryStep::Const(_),
+      QueryStep::FilterBy(_, CompareOp::Ge),
+      QueryStep::LensGet(_),
+      QueryStep::Fulfill,
+    ]
+  ));
+  let plan = plan_with_stats(&SchemaStats::default(), ".events[at >= 10]");
+  assert!(matches!(plan.steps[2], QueryStep::SortKeyScan { .. }));
+
+  // Bounds that are not literals select a fixed fraction of the members.
+  let plan = plan_with_stats(&stats, ".events[at >= ?]");
+  assert!(matches!(plan.steps[2], QueryStep::SortKeyScan { .. }));
+
+  // Sort key indexes that are not backfilled yet are not used.
+  stats.sets.get_mut("events").unwrap().sort_key_sizes = None;
+  let plan = plan_with_stats(&stats, ".events[at >= 90]");
+  assert!(matches!(plan.steps[1], QueryStep::RangeScanKeys { .. }));
+}
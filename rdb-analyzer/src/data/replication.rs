@@ -0,0 +1,558 @@
+//! Logical replication from one key-value store to another.
+
+use std::{
+  collections::VecDeque,
+  sync::{Arc, Mutex as SyncMutex},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::lock::Mutex;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::storage_plan::StoragePlan;
+
+use super::{
+  keyenc::successor_prefix,
+  kv::{KeyValueStore, KvEntryIterator, KvError, KvKeyIterator, KvTransaction, ScanOptions},
+  pathwalker::{KeyKind, PathSegment, PathWalker},
+};
+
+/// The end of the keyspace of a data store, e.g. as copied by `Replicator::bootstrap`. Keys start
+/// with a 12-byte storage key, and storage keys are never all `0xff`.
+pub const KEYSPACE_END: [u8; 13] = [0xff; 13];
+
+/// Max number of entries copied in one transaction by `Replicator::bootstrap`.
+const BOOTSTRAP_BATCH_SIZE: usize = 1000;
+
+/// Max number of batches fetched from the log at once by `Replicator::catch_up`.
+const CATCH_UP_FETCH_SIZE: usize = 100;
+
+#[derive(Error, Debug)]
+pub enum ReplicationError {
+  #[error("change log truncated: changes since {0} are no longer available")]
+  LogTruncated(u64),
+
+  #[error("cannot translate the range deletion of `{0}`..`{1}`")]
+  UntranslatableRange(String, String),
+}
+
+/// A write of a committed transaction.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Mutation {
+  Put(Vec<u8>, Vec<u8>),
+  Delete(Vec<u8>),
+  DeleteRange(Vec<u8>, Vec<u8>),
+}
+
+/// The writes of one committed transaction, in the order they were made.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChangeBatch {
+  pub seq: u64,
+  pub mutations: Vec<Mutation>,
+}
+
+/// The writes committed to a store, in commit order.
+///
+/// Commits through the wrapped store are serialized, so that the order of the log is the order
+/// in which the writes were applied. The log is kept in memory and holds at most `capacity`
+/// transactions.
+pub struct ChangeLog {
+  capacity: usize,
+  state: Mutex<ChangeLogState>,
+}
+
+struct ChangeLogState {
+  /// Sequence number of the first batch in `batches`.
+  first_seq: u64,
+  batches: VecDeque<ChangeBatch>,
+}
+
+impl ChangeLogState {
+  fn next_seq(&self) -> u64 {
+    self.first_seq + self.batches.len() as u64
+  }
+}
+
+/// A store whose committed writes are recorded in a `ChangeLog`.
+pub struct LoggedKv<S> {
+  inner: S,
+  log: Arc<ChangeLog>,
+}
+
+struct LoggedTransaction {
+  inner: Box<dyn KvTransaction>,
+  log: Arc<ChangeLog>,
+  mutations: SyncMutex<Vec<Mutation>>,
+}
+
+impl ChangeLog {
+  /// Creates a log that keeps the last `capacity` committed transactions.
+  pub fn new(capacity: usize) -> Arc<Self> {
+    Arc::new(Self {
+      capacity,
+      state: Mutex::new(ChangeLogState {
+        first_seq: 0,
+        batches: VecDeque::new(),
+      }),
+    })
+  }
+
+  pub fn capacity(&self) -> usize {
+    self.capacity
+  }
+
+  /// Wraps a store so that the transactions committed through it are recorded in this log.
+  pub fn wrap<S: KeyValueStore>(self: &Arc<Self>, inner: S) -> LoggedKv<S> {
+    LoggedKv {
+      inner,
+      log: self.clone(),
+    }
+  }
+
+  /// The sequence number of the next committed transaction.
+  pub async fn next_seq(&self) -> u64 {
+    self.state.lock().await.next_seq()
+  }
+
+  /// Returns up to `limit` batches, starting with the one with sequence number `seq`.
+  pub async fn changes_since(&self, seq: u64, limit: usize) -> Result<Vec<ChangeBatch>> {
+    let state = self.state.lock().await;
+    if seq < state.first_seq {
+      return Err(ReplicationError::LogTruncated(seq).into());
+    }
+    Ok(
+      state
+        .batches
+        .iter()
+        .skip((seq - state.first_seq) as usize)
+        .take(limit)
+        .cloned()
+        .collect(),
+    )
+  }
+}
+
+impl<S> LoggedKv<S> {
+  pub fn inner(&self) -> &S {
+    &self.inner
+  }
+
+  pub fn log(&self) -> &Arc<ChangeLog> {
+    &self.log
+  }
+}
+
+#[async_trait]
+impl<S: KeyValueStore> KeyValueStore for LoggedKv<S> {
+  async fn begin_transaction(&self) -> Result<Box<dyn KvTransaction>> {
+    Ok(Box::new(LoggedTransaction {
+      inner: self.inner.begin_transaction().await?,
+      log: self.log.clone(),
+      mutations: SyncMutex::new(vec![]),
+    }))
+  }
+
+  async fn begin_transaction_at(&self, version: i64) -> Result<Box<dyn KvTransaction>> {
+    Ok(Box::new(LoggedTransaction {
+      inner: self.inner.begin_transaction_at(version).await?,
+      log: self.log.clone(),
+      mutations: SyncMutex::new(vec![]),
+    }))
+  }
+}
+
+#[async_trait]
+impl KvTransaction for LoggedTransaction {
+  async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    self.inner.get(key).await
+  }
+
+  async fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+    self.inner.put(key, value).await?;
+    self
+      .mutations
+      .lock()
+      .unwrap()
+      .push(Mutation::Put(key.to_vec(), value.to_vec()));
+    Ok(())
+  }
+
+  async fn delete(&self, key: &[u8]) -> Result<()> {
+    self.inner.delete(key).await?;
+    self
+      .mutations
+      .lock()
+      .unwrap()
+      .push(Mutation::Delete(key.to_vec()));
+    Ok(())
+  }
+
+  async fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+    self.inner.delete_range(start, end).await?;
+    self
+      .mutations
+      .lock()
+      .unwrap()
+      .push(Mutation::DeleteRange(start.to_vec(), end.to_vec()));
+    Ok(())
+  }
+
+  async fn scan_keys(&self, start: &[u8], end: &[u8]) -> Result<Box<dyn KvKeyIterator>> {
+    self.inner.scan_keys(start, end).await
+  }
+
+  async fn scan<'a>(
+    &'a self,
+    start: &[u8],
+    end: &[u8],
+    options: &ScanOptions,
+  ) -> Result<Box<dyn KvEntryIterator + 'a>> {
+    self.inner.scan(start, end, options).await
+  }
+
+  async fn commit(self: Box<Self>) -> Result<(), KvError> {
+    let me = *self;
+    let mutations = me.mutations.into_inner().unwrap();
+    if mutations.is_empty() {
+      return me.inner.commit().await;
+    }
+
+    let mut state = me.log.state.lock().await;
+    match me.inner.commit().await {
+      Ok(()) => {
+        let seq = state.next_seq();
+        state.batches.push_back(ChangeBatch { seq, mutations });
+        while state.batches.len() > me.log.capacity {
+          state.batches.pop_front();
+          state.first_seq += 1;
+        }
+        Ok(())
+      }
+      Err(KvError::CommitStateUnknown) => {
+        // The writes may or may not have been applied, so no replica can follow the log past
+        // this point.
+        log::error!("commit state unknown, truncating the change log");
+        state.first_seq = state.next_seq() + 1;
+        state.batches.clear();
+        Err(KvError::CommitStateUnknown)
+      }
+      Err(e) => Err(e),
+    }
+  }
+
+  async fn read_version(&self) -> Result<Option<i64>> {
+    self.inner.read_version().await
+  }
+}
+
+/// How keys of the source store map to keys of the target store.
+#[derive(Clone, Debug)]
+pub enum KeyTranslation<'a> {
+  /// Keys are copied unchanged.
+  Identity,
+
+  /// Keys starting with `from` are copied with `from` replaced by `to`. Other keys are skipped.
+  Prefix { from: Vec<u8>, to: Vec<u8> },
+
+  /// Keys are mapped by their location between two storage plans of the same schema, e.g. when
+  /// the target store was deployed separately. Keys of locations missing in the target plan are
+  /// skipped.
+  Plan {
+    source: &'a StoragePlan,
+    target: &'a StoragePlan,
+  },
+}
+
+impl<'a> KeyTranslation<'a> {
+  /// The key of the target store for a key of the source store, or `None` if the key is not
+  /// replicated.
+  pub fn translate_key(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    match self {
+      Self::Identity => Ok(Some(key.to_vec())),
+      Self::Prefix { from, to } => Ok(
+        key
+          .strip_prefix(from.as_slice())
+          .map(|rest| [to.as_slice(), rest].concat()),
+      ),
+      Self::Plan { source, target } => translate_plan_key(source, target, key),
+    }
+  }
+
+  /// The range of the target store for a range deletion on the source store, or `None` if the
+  /// range is not replicated.
+  ///
+  /// Translated ranges must cover the keys starting with a prefix, which is what the executor
+  /// deletes. Arbitrary ranges cannot be translated between storage plans.
+  pub fn translate_range(&self, start: &[u8], end: &[u8]) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    match self {
+      Self::Identity => return Ok(Some((start.to_vec(), end.to_vec()))),
+      Self::Prefix { from, to } => {
+        if let (Some(s), Some(e)) = (
+          start.strip_prefix(from.as_slice()),
+          end.strip_prefix(from.as_slice()),
+        ) {
+          return Ok(Some((
+            [to.as_slice(), s].concat(),
+            [to.as_slice(), e].concat(),
+          )));
+        }
+      }
+      _ => {}
+    }
+    if successor_prefix(start).as_deref() != Some(end) {
+      return Err(
+        ReplicationError::UntranslatableRange(base64::encode(start), base64::encode(end)).into(),
+      );
+    }
+    let start = match self {
+      // Set data prefixes (`K 0x00`) are not keys of any location.
+      Self::Plan { source, target } if start.last() == Some(&0x00) => {
+        match translate_plan_key(source, target, start)? {
+          Some(x) => Some(x),
+          None => translate_set_data_prefix(source, target, &start[..start.len() - 1])?,
+        }
+      }
+      // A range around the whole prefix would be skipped below.
+      Self::Prefix { from, .. } if from.starts_with(start) && from.as_slice() != start => {
+        return Err(
+          ReplicationError::UntranslatableRange(base64::encode(start), base64::encode(end)).into(),
+        );
+      }
+      _ => self.translate_key(start)?,
+    };
+    Ok(start.map(|start| {
+      let end = successor_prefix(&start).unwrap_or_else(|| KEYSPACE_END.to_vec());
+      (start, end)
+    }))
+  }
+
+  /// The range of the source store that is replicated.
+  fn source_range(&self) -> (Vec<u8>, Vec<u8>) {
+    match self {
+      Self::Prefix { from, .. } => prefix_range(from),
+      _ => (vec![], KEYSPACE_END.to_vec()),
+    }
+  }
+
+  /// The range of the target store that replicated keys are written to.
+  fn target_range(&self) -> (Vec<u8>, Vec<u8>) {
+    match self {
+      Self::Prefix { to, .. } => prefix_range(to),
+      _ => (vec![], KEYSPACE_END.to_vec()),
+    }
+  }
+}
+
+fn prefix_range(prefix: &[u8]) -> (Vec<u8>, Vec<u8>) {
+  (
+    prefix.to_vec(),
+    successor_prefix(prefix).unwrap_or_else(|| KEYSPACE_END.to_vec()),
+  )
+}
+
+/// Walks the path of `walker` from the exports of `target`. Returns `None` if a field on the path
+/// is missing in `target`.
+fn walk_target<'a>(
+  target: &'a StoragePlan,
+  walker: &PathWalker<'_>,
+) -> Result<Option<Arc<PathWalker<'a>>>> {
+  let mut current: Option<Arc<PathWalker<'a>>> = None;
+  for segment in walker.path() {
+    current = Some(match (current, segment) {
+      (None, PathSegment::Field(x)) => {
+        if !target.nodes.contains_key(x) {
+          return Ok(None);
+        }
+        PathWalker::from_export(target, x)?
+      }
+      (Some(w), PathSegment::Field(x)) => {
+        if !w.node().children.contains_key(x) {
+          return Ok(None);
+        }
+        w.enter_field(x)?
+      }
+      (Some(w), PathSegment::Selector(x)) => w.enter_set_raw(&x)?,
+      (None, PathSegment::Selector(_)) => return Ok(None),
+    });
+  }
+  Ok(current)
+}
+
+fn translate_plan_key(
+  source: &StoragePlan,
+  target: &StoragePlan,
+  key: &[u8],
+) -> Result<Option<Vec<u8>>> {
+  let decoded = match PathWalker::decode_key(source, key)? {
+    Some(x) => x,
+    None => return Ok(None),
+  };
+  let walker = match walk_target(target, &decoded.walker)? {
+    Some(x) => x,
+    None => return Ok(None),
+  };
+  Ok(Some(match decoded.kind {
+    KeyKind::Node => walker.generate_key(),
+    KeyKind::SetFastScan(x) => [walker.set_fast_scan_prefix()?, x].concat(),
+    KeyKind::SetSortKey(x) => [walker.set_sort_key_prefix()?, x].concat(),
+    KeyKind::SetAutoCounter => walker.set_auto_counter_key()?,
+    KeyKind::SetSortIndexProgress => walker.set_sort_index_progress_key()?,
+    KeyKind::BlobChunk(x) => walker.blob_chunk_key(x)?,
+  }))
+}
+
+/// Translates the data prefix of the set whose key is `set_key`.
+fn translate_set_data_prefix(
+  source: &StoragePlan,
+  target: &StoragePlan,
+  set_key: &[u8],
+) -> Result<Option<Vec<u8>>> {
+  let decoded = match PathWalker::decode_key(source, set_key)? {
+    Some(x) if x.kind == KeyKind::Node && x.walker.node().set.is_some() => x,
+    _ => return Ok(None),
+  };
+  walk_target(target, &decoded.walker)?
+    .map(|x| x.set_data_prefix())
+    .transpose()
+}
+
+/// Applies the changes of a `ChangeLog` to a target store: copies the source once with
+/// `bootstrap`, then tails the log with `catch_up`, applying each committed transaction in one
+/// transaction. A replica that falls behind the log fails with `ReplicationError::LogTruncated`
+/// and has to be bootstrapped again.
+pub struct Replicator<'a> {
+  log: Arc<ChangeLog>,
+  target: &'a dyn KeyValueStore,
+  translation: KeyTranslation<'a>,
+
+  /// Sequence number of the next batch to apply.
+  position: u64,
+}
+
+impl<'a> Replicator<'a> {
+  /// Creates a replicator that applies the changes starting at `position`, for a target that
+  /// already holds the state of the source before them.
+  pub fn new(
+    log: Arc<ChangeLog>,
+    target: &'a dyn KeyValueStore,
+    translation: KeyTranslation<'a>,
+    position: u64,
+  ) -> Self {
+    Self {
+      log,
+      target,
+      translation,
+      position,
+    }
+  }
+
+  /// Replaces the replicated range of `target` with a copy of the contents of `source`, and
+  /// returns a replicator that continues from the copied state.
+  ///
+  /// The copy is read from one snapshot of `source`, taken while no transaction is committing,
+  /// so that the changes logged after it are exactly the ones it misses. It is written to
+  /// `target` in several transactions, so `target` is inconsistent until the copy completes.
+  pub async fn bootstrap<S: KeyValueStore>(
+    source: &LoggedKv<S>,
+    target: &'a dyn KeyValueStore,
+    translation: KeyTranslation<'a>,
+  ) -> Result<Replicator<'a>> {
+    let (snapshot, position) = {
+      let state = source.log.state.lock().await;
+      (source.inner.begin_transaction().await?, state.next_seq())
+    };
+
+    let (start, end) = translation.target_range();
+    let txn = target.begin_transaction().await?;
+    txn.delete_range(&start, &end).await?;
+    txn.commit().await?;
+
+    let (mut start, end) = translation.source_range();
+    loop {
+      let mut it = snapshot
+        .scan(
+          &start,
+          &end,
+          &ScanOptions {
+            limit: Some(BOOTSTRAP_BATCH_SIZE),
+            values: true,
+            ..Default::default()
+          },
+        )
+        .await?;
+      let txn = target.begin_transaction().await?;
+      let mut last_key = None;
+      while let Some((k, v)) = it.next().await? {
+        if let Some(k) = translation.translate_key(&k)? {
+          txn.put(&k, &v).await?;
+        }
+        last_key = Some(k);
+      }
+      drop(it);
+      txn.commit().await?;
+      match last_key {
+        Some(mut k) => {
+          k.push(0x00);
+          start = k;
+        }
+        None => break,
+      }
+    }
+
+    Ok(Self::new(source.log.clone(), target, translation, position))
+  }
+
+  /// Sequence number of the next batch to apply.
+  pub fn position(&self) -> u64 {
+    self.position
+  }
+
+  /// Applies the changes logged since the last call. Returns the number of batches applied.
+  ///
+  /// Batches only contain absolute writes, so a batch whose commit fails on the target is
+  /// applied again by the next call.
+  pub async fn catch_up(&mut self) -> Result<usize> {
+    let mut applied = 0;
+    loop {
+      let batches = self
+        .log
+        .changes_since(self.position, CATCH_UP_FETCH_SIZE)
+        .await?;
+      if batches.is_empty() {
+        return Ok(applied);
+      }
+      for batch in batches {
+        self.apply(&batch).await?;
+        self.position = batch.seq + 1;
+        applied += 1;
+      }
+    }
+  }
+
+  async fn apply(&self, batch: &ChangeBatch) -> Result<()> {
+    let txn = self.target.begin_transaction().await?;
+    for m in &batch.mutations {
+      match m {
+        Mutation::Put(k, v) => {
+          if let Some(k) = self.translation.translate_key(k)? {
+            txn.put(&k, v).await?;
+          }
+        }
+        Mutation::Delete(k) => {
+          if let Some(k) = self.translation.translate_key(k)? {
+            txn.delete(&k).await?;
+          }
+        }
+        Mutation::DeleteRange(start, end) => {
+          if let Some((start, end)) = self.translation.translate_range(start, end)? {
+            txn.delete_range(&start, &end).await?;
+          }
+        }
+      }
+    }
+    txn.commit().await?;
+    Ok(())
+  }
+}
@@ -0,0 +1,235 @@
+use std::sync::Arc;
+
+use crate::{
+  data::{
+    kv::KeyValueStore,
+    mock_kv::MockKv,
+    replication::{ChangeLog, KeyTranslation, ReplicationError, Replicator},
+    treewalker::{
+      asm::codegen::compile_twscript,
+      bytecode::TwScript,
+      exec::{generate_root_map, Executor},
+      typeck::GlobalTyckContext,
+      vm::TwVm,
+      vm_value::VmValue,
+    },
+    value::PrimitiveValue,
+  },
+  schema::compile::CompiledSchema,
+  storage_plan::{planner::generate_plan_for_schema, StoragePlan},
+  testutil::compile_schema_source,
+};
+
+async fn put(kv: &dyn KeyValueStore, entries: &[(&str, &str)]) {
+  let txn = kv.begin_transaction().await.unwrap();
+  for (k, v) in entries {
+    txn.put(k.as_bytes(), v.as_bytes()).await.unwrap();
+  }
+  txn.commit().await.unwrap();
+}
+
+async fn delete_prefix(kv: &dyn KeyValueStore, prefix: &str) {
+  let txn = kv.begin_transaction().await.unwrap();
+  let mut end = prefix.as_bytes().to_vec();
+  *end.last_mut().unwrap() += 1;
+  txn.delete_range(prefix.as_bytes(), &end).await.unwrap();
+  txn.commit().await.unwrap();
+}
+
+fn entries(x: &[(&str, &str)]) -> Vec<(Vec<u8>, Vec<u8>)> {
+  x.iter()
+    .map(|(k, v)| (k.as_bytes().to_vec(), v.as_bytes().to_vec()))
+    .collect()
+}
+
+#[tokio::test]
+async fn replicate_unchanged_keys() {
+  let _ = pretty_env_logger::try_init();
+  let log = ChangeLog::new(100);
+  let source = log.wrap(MockKv::new());
+  let target = MockKv::new();
+  put(&target, &[("stale", "x")]).await;
+  put(&source, &[("a/1", "x"), ("a/2", "y")]).await;
+
+  let mut replicator = Replicator::bootstrap(&source, &target, KeyTranslation::Identity)
+    .await
+    .unwrap();
+  assert_eq!(replicator.position(), 1);
+  assert_eq!(target.dump().await, source.inner().dump().await);
+
+  put(&source, &[("b/1", "z")]).await;
+  delete_prefix(&source, "a/").await;
+  put(&source, &[("a/3", "w")]).await;
+  assert_eq!(replicator.catch_up().await.unwrap(), 3);
+  assert_eq!(replicator.catch_up().await.unwrap(), 0);
+  assert_eq!(target.dump().await, entries(&[("a/3", "w"), ("b/1", "z")]));
+}
+
+#[tokio::test]
+async fn replicate_to_another_prefix() {
+  let _ = pretty_env_logger::try_init();
+  let log = ChangeLog::new(100);
+  let source = log.wrap(MockKv::new());
+  let target = MockKv::new();
+  put(&target, &[("other", "kept"), ("z/0", "stale")]).await;
+  put(&source, &[("a/1", "x"), ("b/1", "y")]).await;
+
+  let translation = KeyTranslation::Prefix {
+    from: b"a/".to_vec(),
+    to: b"z/".to_vec(),
+  };
+  let mut replicator = Replicator::bootstrap(&source, &target, translation)
+    .await
+    .unwrap();
+  assert_eq!(
+    target.dump().await,
+    entries(&[("other", "kept"), ("z/1", "x")])
+  );
+
+  put(&source, &[("a/2", "y"), ("c/1", "z")]).await;
+  delete_prefix(&source, "a/1").await;
+  replicator.catch_up().await.unwrap();
+  assert_eq!(
+    target.dump().await,
+    entries(&[("other", "kept"), ("z/2", "y")])
+  );
+}
+
+#[tokio::test]
+async fn lagging_replicas_fail() {
+  let log = ChangeLog::new(2);
+  let source = log.wrap(MockKv::new());
+  let target = MockKv::new();
+  let mut replicator = Replicator::bootstrap(&source, &target, KeyTranslation::Identity)
+    .await
+    .unwrap();
+  for i in 0..3 {
+    put(&source, &[("k", format!("{}", i).as_str())]).await;
+  }
+  assert_eq!(log.next_seq().await, 3);
+  let e = replicator.catch_up().await.unwrap_err();
+  assert!(matches!(
+    e.downcast_ref::<ReplicationError>(),
+    Some(ReplicationError::LogTruncated(0))
+  ));
+
+  let mut replicator = Replicator::bootstrap(&source, &target, KeyTranslation::Identity)
+    .await
+    .unwrap();
+  assert_eq!(replicator.position(), 3);
+  assert_eq!(replicator.catch_up().await.unwrap(), 0);
+  assert_eq!(target.dump().await, entries(&[("k", "2")]));
+}
+
+const SCHEMA: &str = r#"
+type Item {
+  @primary
+  id: string,
+  name: string,
+}
+export set<Item> items;
+"#;
+
+const SCRIPT: &str = r#"
+export graph insert(root: schema, id: string, name: string) {
+  s_insert root.items $ build_table(Item) $ m_insert(id) id $ m_insert(name) name create_map;
+}
+export graph remove(root: schema, id: string) {
+  s_delete root.items id;
+}
+export graph get(root: schema, id: string): string {
+  return (point_get root.items id).name;
+}
+export graph count(root: schema): int64 {
+  return reduce(add) create_map 0 root.items;
+}
+graph add(ctx: map{}, acc: int64, item: Item): int64 {
+  return acc + 1;
+}
+"#;
+
+async fn run(
+  schema: &CompiledSchema,
+  plan: &StoragePlan,
+  script: &TwScript,
+  kv: &dyn KeyValueStore,
+  graph: &str,
+  params: &[&str],
+) -> Option<PrimitiveValue> {
+  let vm = TwVm::new(schema, plan, script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let mut params_vm = vec![Arc::new(generate_root_map(schema, plan).unwrap())];
+  for x in params {
+    params_vm.push(Arc::new(VmValue::Primitive(PrimitiveValue::String(
+      x.to_string(),
+    ))));
+  }
+  let mut executor = Executor::new(&vm, kv, &type_info);
+  let graph = vm.lookup_exported_graph_by_name(graph).unwrap();
+  let output = executor.run_graph(graph, &params_vm).await.unwrap()?;
+  match &*output {
+    VmValue::Primitive(x) => Some(x.clone()),
+    _ => None,
+  }
+}
+
+#[tokio::test]
+async fn replicate_between_plans() {
+  let _ = pretty_env_logger::try_init();
+  let schema = compile_schema_source(SCHEMA);
+  let source_plan =
+    generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let target_plan =
+    generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  assert_ne!(
+    source_plan.nodes["items"].key,
+    target_plan.nodes["items"].key
+  );
+  let script = compile_twscript(SCRIPT).unwrap();
+
+  let log = ChangeLog::new(100);
+  let source = log.wrap(MockKv::new());
+  let target = MockKv::new();
+  for (id, name) in &[("a", "first"), ("b", "second")] {
+    run(
+      &schema,
+      &source_plan,
+      &script,
+      &source,
+      "insert",
+      &[*id, *name],
+    )
+    .await;
+  }
+
+  let translation = KeyTranslation::Plan {
+    source: &source_plan,
+    target: &target_plan,
+  };
+  let mut replicator = Replicator::bootstrap(&source, &target, translation)
+    .await
+    .unwrap();
+  run(
+    &schema,
+    &source_plan,
+    &script,
+    &source,
+    "insert",
+    &["c", "third"],
+  )
+  .await;
+  run(&schema, &source_plan, &script, &source, "remove", &["a"]).await;
+  assert_eq!(replicator.catch_up().await.unwrap(), 2);
+
+  assert_eq!(target.key_count().await, source.inner().key_count().await);
+  for (id, name) in &[("a", None), ("b", Some("second")), ("c", Some("third"))] {
+    assert_eq!(
+      run(&schema, &target_plan, &script, &target, "get", &[*id]).await,
+      name.map(|x| PrimitiveValue::String(x.into()))
+    );
+  }
+  assert_eq!(
+    run(&schema, &target_plan, &script, &target, "count", &[]).await,
+    Some(PrimitiveValue::Int64(2))
+  );
+}
@@ -0,0 +1,445 @@
+use std::{
+  collections::BTreeMap,
+  sync::Arc,
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+  schema::compile::{CompiledSchema, FieldAnnotationList, FieldType, PrimitiveType},
+  storage_plan::StoragePlan,
+};
+
+use super::{
+  backfill::sort_index_ready,
+  keyenc::{split_sort_key_entry, successor_of_bounded_prefix},
+  kv::{KeyValueStore, KvTransaction, ScanOptions},
+  pathwalker::{get_with_rename_fallback, PathWalker},
+  value::PrimitiveValue,
+};
+
+/// Prefix of the keys of the statistics of each set in the stats store, followed by the path of
+/// the set.
+const SET_STATS_PREFIX: &[u8] = b"set\x00";
+
+#[derive(Error, Debug)]
+pub enum StatsError {
+  #[error("missing type: {0}")]
+  MissingType(String),
+
+  #[error("bad sort key entry in storage")]
+  BadSortKeyEntry,
+}
+
+/// Options of `collect_stats`.
+#[derive(Clone, Debug)]
+pub struct StatsOptions {
+  /// Max number of keys scanned in one transaction.
+  pub batch_size: usize,
+
+  /// Max number of keys scanned in each index of a set.
+  pub max_members: u64,
+}
+
+impl Default for StatsOptions {
+  fn default() -> Self {
+    Self {
+      batch_size: 1000,
+      max_members: 100000,
+    }
+  }
+}
+
+/// Statistics of the sets of a schema.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SchemaStats {
+  /// Statistics of each set, by the path of the set from its export, e.g. `items` for an exported
+  /// set, or `config.users` for a set field of an exported table.
+  pub sets: BTreeMap<String, SetStats>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SetStats {
+  /// Milliseconds since the epoch.
+  pub collected_at: u64,
+
+  /// Number of members.
+  pub cardinality: u64,
+
+  /// Whether `cardinality` is exact. Otherwise sampling stopped at `StatsOptions::max_members`,
+  /// and it is a lower bound.
+  pub exact: bool,
+
+  /// Sizes of the keys of the fast scan index.
+  pub key_sizes: SizeDistribution,
+
+  /// Sizes of the keys of the sort key index, for the members with a non-null sort key. `None`
+  /// if the member type has no sort key, or its index is not backfilled yet.
+  pub sort_key_sizes: Option<SizeDistribution>,
+
+  /// Range of the primary key values, if they are numeric.
+  pub key_range: Option<ValueRange>,
+
+  /// Range of the non-null sort key values, if they are numeric.
+  pub sort_key_range: Option<ValueRange>,
+}
+
+/// A distribution of sizes in bytes.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SizeDistribution {
+  pub count: u64,
+  pub total: u64,
+  pub min: u64,
+  pub max: u64,
+
+  /// `histogram[i]` is the number of sizes in `[2^i, 2^(i+1))`. Zero sizes are counted in
+  /// `histogram[0]`.
+  pub histogram: Vec<u64>,
+}
+
+/// The smallest and largest values of a numeric field.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ValueRange {
+  pub min: f64,
+  pub max: f64,
+}
+
+impl SizeDistribution {
+  pub fn add(&mut self, size: u64) {
+    self.min = if self.count == 0 {
+      size
+    } else {
+      self.min.min(size)
+    };
+    self.max = self.max.max(size);
+    self.count += 1;
+    self.total += size;
+    let bucket = (64 - size.leading_zeros()).saturating_sub(1) as usize;
+    if self.histogram.len() <= bucket {
+      self.histogram.resize(bucket + 1, 0);
+    }
+    self.histogram[bucket] += 1;
+  }
+
+  /// The mean size, or `None` if there are no sizes.
+  pub fn mean(&self) -> Option<f64> {
+    if self.count == 0 {
+      None
+    } else {
+      Some(self.total as f64 / self.count as f64)
+    }
+  }
+}
+
+/// Collects the statistics of the sets reachable from the exports of `schema` through table
+/// fields, for cost-based query planning. Sets nested in set members, maps or lists are not
+/// sampled. Sampling stops after `StatsOptions::max_members` keys of an index, in which case the
+/// cardinality is a lower bound.
+pub async fn collect_stats(
+  store: &dyn KeyValueStore,
+  schema: &CompiledSchema,
+  plan: &StoragePlan,
+  options: &StatsOptions,
+) -> Result<SchemaStats> {
+  let options = StatsOptions {
+    batch_size: options.batch_size.max(1),
+    ..options.clone()
+  };
+  let collected_at = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap()
+    .as_millis() as u64;
+  let mut pending = vec![];
+  for (export_name, export_ty) in &schema.exports {
+    let walker = PathWalker::from_export(plan, export_name)?;
+    collect_sets(
+      schema,
+      export_ty,
+      walker,
+      export_name.to_string(),
+      &mut vec![],
+      &mut pending,
+    )?;
+  }
+
+  let mut stats = SchemaStats::default();
+  for (path, walker, member_ty) in pending {
+    let set_stats =
+      collect_set_stats(store, schema, &walker, member_ty, &options, collected_at).await?;
+    stats.sets.insert(path, set_stats);
+  }
+  Ok(stats)
+}
+
+/// Replaces the statistics in `store` with `stats`. Statistics are kept in a store of their own,
+/// apart from the data.
+pub async fn save_stats(store: &dyn KeyValueStore, stats: &SchemaStats) -> Result<()> {
+  let txn = store.begin_transaction().await?;
+  txn
+    .delete_range(
+      SET_STATS_PREFIX,
+      &successor_of_bounded_prefix(SET_STATS_PREFIX),
+    )
+    .await?;
+  for (path, set_stats) in &stats.sets {
+    txn
+      .put(
+        &[SET_STATS_PREFIX, path.as_bytes()].concat(),
+        &rmp_serde::to_vec_named(set_stats)?,
+      )
+      .await?;
+  }
+  txn.commit().await?;
+  Ok(())
+}
+
+/// Loads the statistics saved in `store`. Returns empty statistics if none were saved.
+pub async fn load_stats(store: &dyn KeyValueStore) -> Result<SchemaStats> {
+  let txn = store.begin_transaction().await?;
+  let mut it = txn
+    .scan(
+      SET_STATS_PREFIX,
+      &successor_of_bounded_prefix(SET_STATS_PREFIX),
+      &ScanOptions {
+        values: true,
+        ..Default::default()
+      },
+    )
+    .await?;
+  let mut stats = SchemaStats::default();
+  while let Some((k, v)) = it.next().await? {
+    let path = String::from_utf8(k[SET_STATS_PREFIX.len()..].to_vec())?;
+    stats.sets.insert(path, rmp_serde::from_slice(&v)?);
+  }
+  Ok(stats)
+}
+
+/// Collects the sets at or below a location of type `ty`, without entering set members.
+fn collect_sets<'a>(
+  schema: &'a CompiledSchema,
+  ty: &'a FieldType,
+  walker: Arc<PathWalker<'a>>,
+  path: String,
+  tables_in_stack: &mut Vec<&'a str>,
+  pending: &mut Vec<(String, Arc<PathWalker<'a>>, &'a FieldType)>,
+) -> Result<()> {
+  match ty {
+    FieldType::Set(member_ty) => pending.push((path, walker, member_ty)),
+    FieldType::Table(name) => {
+      // Stops the recursion on recursive types.
+      if tables_in_stack.contains(&&**name) {
+        return Ok(());
+      }
+      let specialized_ty = schema
+        .types
+        .get(name)
+        .ok_or_else(|| StatsError::MissingType(name.to_string()))?;
+      tables_in_stack.push(&**name);
+      for (field_name, (field_ty, _)) in &specialized_ty.fields {
+        collect_sets(
+          schema,
+          field_ty,
+          walker.enter_field(field_name)?,
+          format!("{}.{}", path, field_name),
+          tables_in_stack,
+          pending,
+        )?;
+      }
+      tables_in_stack.pop();
+    }
+    FieldType::Primitive(_) | FieldType::Struct(_) | FieldType::List(_) | FieldType::Map(_) => {}
+  }
+  Ok(())
+}
+
+async fn collect_set_stats(
+  store: &dyn KeyValueStore,
+  schema: &CompiledSchema,
+  walker: &Arc<PathWalker<'_>>,
+  member_ty: &FieldType,
+  options: &StatsOptions,
+  collected_at: u64,
+) -> Result<SetStats> {
+  let specialized_ty = match member_ty {
+    FieldType::Table(name) => Some(
+      schema
+        .types
+        .get(name)
+        .ok_or_else(|| StatsError::MissingType(name.to_string()))?,
+    ),
+    _ => None,
+  };
+  let primary_key = specialized_ty.and_then(|x| {
+    x.fields
+      .iter()
+      .find(|(_, (_, annotations))| annotations.as_slice().is_primary())
+      .map(|(name, (ty, _))| (&**name, ty))
+  });
+  let sort_key = specialized_ty.and_then(|x| x.sort_key());
+
+  let prefix = walker.set_fast_scan_prefix()?;
+  let keys = scan_index(store, &prefix, &prefix, options).await?;
+  let mut stats = SetStats {
+    collected_at,
+    cardinality: keys.sizes.count,
+    exact: keys.exact,
+    key_sizes: keys.sizes,
+    sort_key_sizes: None,
+    key_range: None,
+    sort_key_range: None,
+  };
+
+  let txn = store.begin_transaction().await?;
+  if let (Some((field, ty)), Some(first), Some(last)) = (primary_key, &keys.first, &keys.last) {
+    stats.key_range = value_range(&*txn, walker, first, last, field, ty).await?;
+  }
+  if let Some((field, ty)) = sort_key {
+    if sort_index_ready(&*txn, walker).await? {
+      // Null sort keys are encoded as `0x00`, and are skipped by scans on the sort key.
+      let prefix = walker.set_sort_key_prefix()?;
+      let entries = scan_index(store, &prefix, &[&prefix[..], &[0x01]].concat(), options).await?;
+      if let (Some(first), Some(last)) = (&entries.first, &entries.last) {
+        let primary_key_of = |x: &[u8]| {
+          split_sort_key_entry(x)
+            .map(|x| x.1.to_vec())
+            .ok_or(StatsError::BadSortKeyEntry)
+        };
+        stats.sort_key_range = value_range(
+          &*txn,
+          walker,
+          &primary_key_of(first)?,
+          &primary_key_of(last)?,
+          field,
+          ty,
+        )
+        .await?;
+      }
+      stats.sort_key_sizes = Some(entries.sizes);
+    }
+  }
+  txn.commit().await?;
+  Ok(stats)
+}
+
+/// The result of a scan over an index of a set.
+struct IndexScan {
+  sizes: SizeDistribution,
+  exact: bool,
+
+  /// The first and last keys, without the prefix of the index.
+  first: Option<Vec<u8>>,
+  last: Option<Vec<u8>>,
+}
+
+/// Scans the keys of an index from `start` to the end of `prefix`.
+async fn scan_index(
+  store: &dyn KeyValueStore,
+  prefix: &[u8],
+  start: &[u8],
+  options: &StatsOptions,
+) -> Result<IndexScan> {
+  let end = successor_of_bounded_prefix(prefix);
+  let mut cursor = start.to_vec();
+  let mut scan = IndexScan {
+    sizes: SizeDistribution::default(),
+    exact: true,
+    first: None,
+    last: None,
+  };
+  loop {
+    let remaining = options.max_members - scan.sizes.count;
+    if remaining == 0 {
+      // The scan is exact only if there are no more keys.
+      let txn = store.begin_transaction().await?;
+      scan.exact = txn.scan_keys(&cursor, &end).await?.next().await?.is_none();
+      return Ok(scan);
+    }
+    let limit = (options.batch_size as u64).min(remaining) as usize;
+
+    let txn = store.begin_transaction().await?;
+    let mut it = txn
+      .scan(
+        &cursor,
+        &end,
+        &ScanOptions {
+          limit: Some(limit),
+          ..Default::default()
+        },
+      )
+      .await?;
+    let mut keys = vec![];
+    while let Some((k, _)) = it.next().await? {
+      keys.push(k);
+    }
+    drop(it);
+    txn.commit().await?;
+
+    for k in &keys {
+      scan.sizes.add(k.len() as u64);
+    }
+    if scan.first.is_none() {
+      scan.first = keys.first().map(|x| x[prefix.len()..].to_vec());
+    }
+    match keys.last() {
+      Some(last) => {
+        scan.last = Some(last[prefix.len()..].to_vec());
+        if keys.len() < limit {
+          return Ok(scan);
+        }
+        cursor = last.clone();
+        cursor.push(0x00);
+      }
+      None => return Ok(scan),
+    }
+  }
+}
+
+/// The range of the values of a numeric field, from the members with the smallest and the largest
+/// value of the field in an index.
+async fn value_range(
+  txn: &dyn KvTransaction,
+  walker: &Arc<PathWalker<'_>>,
+  first_primary_key: &[u8],
+  last_primary_key: &[u8],
+  field: &str,
+  ty: &FieldType,
+) -> Result<Option<ValueRange>> {
+  let mut bounds = [0f64; 2];
+  for (bound, primary_key) in bounds
+    .iter_mut()
+    .zip(&[first_primary_key, last_primary_key])
+  {
+    let field_walker = walker.enter_set_raw(primary_key)?.enter_field(field)?;
+    let value = get_with_rename_fallback(txn, &field_walker)
+      .await?
+      .map(PrimitiveValue::decode_stored)
+      .transpose()?;
+    *bound = match value.and_then(|x| numeric_value(ty, &x)) {
+      Some(x) if x.is_finite() => x,
+      _ => return Ok(None),
+    };
+  }
+  Ok(Some(ValueRange {
+    min: bounds[0],
+    max: bounds[1],
+  }))
+}
+
+/// Converts a value of a numeric field to a double.
+pub fn numeric_value(ty: &FieldType, value: &PrimitiveValue) -> Option<f64> {
+  match (ty, value) {
+    (FieldType::Primitive(PrimitiveType::Int64), PrimitiveValue::Int64(x)) => Some(*x as f64),
+    (FieldType::Primitive(PrimitiveType::Double), PrimitiveValue::Double(x)) => {
+      Some(f64::from_bits(*x))
+    }
+
+    // The bits of doubles that fit in an `i64` are decoded as `Int64`.
+    (FieldType::Primitive(PrimitiveType::Double), PrimitiveValue::Int64(x)) => {
+      Some(f64::from_bits(*x as u64))
+    }
+    _ => None,
+  }
+}
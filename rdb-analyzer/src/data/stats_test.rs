@@ -0,0 +1,141 @@
+use std::sync::Arc;
+
+use crate::{
+  data::{
+    mock_kv::MockKv,
+    treewalker::{
+      asm::codegen::compile_twscript,
+      exec::{generate_root_map, Executor},
+      typeck::GlobalTyckContext,
+      vm::TwVm,
+      vm_value::VmValue,
+    },
+    value::PrimitiveValue,
+  },
+  schema::compile::CompiledSchema,
+  storage_plan::{planner::generate_plan_for_schema, StoragePlan},
+  testutil::compile_schema_source,
+};
+
+use super::stats::{
+  collect_stats, load_stats, save_stats, SizeDistribution, StatsOptions, ValueRange,
+};
+
+const SCHEMA: &str = r#"
+type Event {
+  @primary
+  id: int64,
+  @sort_key
+  at: int64,
+  name: string,
+}
+type Archive {
+  events: set<Event>,
+}
+export set<Event> events;
+export Archive archive;
+"#;
+
+const SCRIPT: &str = r#"
+export graph put(root: schema, id: int64, at: int64, name: string) {
+  s_insert root.events $ build_table(Event) $ m_insert(id) id $ m_insert(at) at $ m_insert(name) name create_map;
+}
+"#;
+
+async fn put(schema: &CompiledSchema, plan: &StoragePlan, kv: &MockKv, id: i64, at: i64) {
+  let script = compile_twscript(SCRIPT).unwrap();
+  let vm = TwVm::new(schema, plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let params = vec![
+    Arc::new(generate_root_map(schema, plan).unwrap()),
+    Arc::new(VmValue::Primitive(PrimitiveValue::Int64(id))),
+    Arc::new(VmValue::Primitive(PrimitiveValue::Int64(at))),
+    Arc::new(VmValue::Primitive(PrimitiveValue::String(
+      "x".repeat(id as usize),
+    ))),
+  ];
+  let mut executor = Executor::new(&vm, kv, &type_info);
+  let graph = vm.lookup_exported_graph_by_name("put").unwrap();
+  executor.run_graph(graph, &params).await.unwrap();
+}
+
+#[test]
+fn size_distribution() {
+  let mut sizes = SizeDistribution::default();
+  assert_eq!(sizes.mean(), None);
+  for x in &[0, 1, 3, 4, 7, 8] {
+    sizes.add(*x);
+  }
+  assert_eq!(sizes.count, 6);
+  assert_eq!(sizes.total, 23);
+  assert_eq!(sizes.min, 0);
+  assert_eq!(sizes.max, 8);
+  assert_eq!(sizes.histogram, vec![2, 1, 2, 1]);
+}
+
+#[tokio::test]
+async fn collect_set_stats() {
+  let _ = pretty_env_logger::try_init();
+  let schema = compile_schema_source(SCHEMA);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let kv = MockKv::new();
+  for (id, at) in &[(3, 10), (1, 50), (5, 20), (2, -40), (4, 30)] {
+    put(&schema, &plan, &kv, *id, *at).await;
+  }
+
+  let options = StatsOptions {
+    batch_size: 2,
+    ..Default::default()
+  };
+  let stats = collect_stats(&kv, &schema, &plan, &options).await.unwrap();
+  println!("{:?}", stats);
+  assert_eq!(
+    stats.sets.keys().map(|x| x.as_str()).collect::<Vec<_>>(),
+    vec!["archive.events", "events"]
+  );
+
+  let events = &stats.sets["events"];
+  assert_eq!(events.cardinality, 5);
+  assert!(events.exact);
+  assert_eq!(events.key_sizes.count, 5);
+  assert_eq!(events.key_sizes.histogram.iter().sum::<u64>(), 5);
+  assert_eq!(events.key_range, Some(ValueRange { min: 1.0, max: 5.0 }));
+  assert_eq!(events.sort_key_sizes.as_ref().unwrap().count, 5);
+  assert_eq!(
+    events.sort_key_range,
+    Some(ValueRange {
+      min: -40.0,
+      max: 50.0
+    })
+  );
+
+  let archived = &stats.sets["archive.events"];
+  assert_eq!(archived.cardinality, 0);
+  assert!(archived.exact);
+  assert_eq!(archived.key_range, None);
+
+  // Sampling stops at `max_members`.
+  let options = StatsOptions {
+    batch_size: 2,
+    max_members: 3,
+  };
+  let sampled = collect_stats(&kv, &schema, &plan, &options).await.unwrap();
+  assert_eq!(sampled.sets["events"].cardinality, 3);
+  assert!(!sampled.sets["events"].exact);
+  let options = StatsOptions {
+    batch_size: 2,
+    max_members: 5,
+  };
+  let sampled = collect_stats(&kv, &schema, &plan, &options).await.unwrap();
+  assert!(sampled.sets["events"].exact);
+
+  // Statistics are replaced as a whole when saved.
+  let stats_kv = MockKv::new();
+  assert!(load_stats(&stats_kv).await.unwrap().sets.is_empty());
+  save_stats(&stats_kv, &stats).await.unwrap();
+  assert_eq!(load_stats(&stats_kv).await.unwrap(), stats);
+  let mut fewer = stats.clone();
+  fewer.sets.remove("archive.events");
+  save_stats(&stats_kv, &fewer).await.unwrap();
+  assert_eq!(load_stats(&stats_kv).await.unwrap(), fewer);
+}
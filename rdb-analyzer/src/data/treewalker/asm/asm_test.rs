@@ -1,18 +1,26 @@
-use std::{sync::Arc, time::Instant};
+use std::{
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+  },
+  time::{Duration, Instant},
+};
 
 use anyhow::Result;
+use async_trait::async_trait;
 use bumpalo::Bump;
 
 use crate::{
   data::{
+    kv::{KeyValueStore, KvError, KvKeyIterator, KvTransaction},
     mock_kv::MockKv,
     treewalker::{
-      asm::codegen::compile_twscript,
-      exec::{generate_root_map, Executor},
+      asm::{codegen::compile_twscript, TwAsmErrors},
+      exec::{generate_root_map, ExecConfig, Executor},
       serialize::{SerializedVmValue, TaggedVmValue},
       typeck::GlobalTyckContext,
       vm::TwVm,
-      vm_value::{VmType, VmValue},
+      vm_value::{VmListValue, VmListValueKind, VmType, VmValue},
     },
     value::PrimitiveValue,
   },
@@ -26,6 +34,16 @@ use crate::{
 async fn simple_test_with_error<F: FnMut(Result<Option<Arc<VmValue>>>)>(
   schema: &str,
   scripts: &[&str],
+  check: F,
+) {
+  simple_test_with_config(schema, scripts, &MockKv::new(), &Default::default(), check).await
+}
+
+async fn simple_test_with_config<F: FnMut(Result<Option<Arc<VmValue>>>)>(
+  schema: &str,
+  scripts: &[&str],
+  kv: &dyn KeyValueStore,
+  config: &ExecConfig,
   mut check: F,
 ) {
   let alloc = Bump::new();
@@ -35,8 +53,6 @@ async fn simple_test_with_error<F: FnMut(Result<Option<Arc<VmValue>>>)>(
   drop(alloc);
   let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
 
-  let kv = MockKv::new();
-
   for &code in scripts {
     let start = Instant::now();
     let script = compile_twscript(code).unwrap();
@@ -51,7 +67,7 @@ async fn simple_test_with_error<F: FnMut(Result<Option<Arc<VmValue>>>)>(
     let tyck_end = Instant::now();
     println!("tyck took {:?}", tyck_end.duration_since(start));
 
-    let mut executor = Executor::new(&vm, &kv, &type_info);
+    let mut executor = Executor::new_with_config(&vm, kv, &type_info, config);
     let output = executor
       .run_graph(0, &[Arc::new(generate_root_map(&schema, &plan).unwrap())])
       .await;
@@ -356,6 +372,30 @@ async fn set_reduce() {
           create_map;
       }
       "#,
+      r#"
+      graph main(root: schema): string {
+        return (reduce(f, desc) from "id2" to "id4" create_map (
+          m_insert(first) true $
+          m_insert(result) "" create_map
+        ) root.items).result;
+      }
+      graph f(ctx: map{}, current: map {
+        first: bool,
+        result: string,
+      }, item: Item): map {
+        first: bool,
+        result: string,
+      } {
+        if !current.first {
+          r1 = current.result + " " + item.id;
+        } else {
+          r2 = item.id;
+        }
+        return m_insert(first) false
+          $ m_insert(result) (select r1 r2)
+          create_map;
+      }
+      "#,
     ],
     |x| {
       match chkindex {
@@ -378,6 +418,12 @@ async fn set_reduce() {
             VmValue::Primitive(PrimitiveValue::String("id2 id3".into()))
           );
         }
+        4 => {
+          assert_eq!(
+            **x.as_ref().unwrap(),
+            VmValue::Primitive(PrimitiveValue::String("id3 id2".into()))
+          );
+        }
         _ => unreachable!(),
       }
       chkindex += 1;
@@ -385,7 +431,7 @@ async fn set_reduce() {
   )
   .await;
 
-  assert_eq!(chkindex, 4);
+  assert_eq!(chkindex, 5);
 }
 
 #[tokio::test]
@@ -508,7 +554,7 @@ async fn throw_string() {
     |x| {
       assert_eq!(
         x.unwrap_err().to_string(),
-        "script thrown error: `test error`"
+        "script thrown error: `test error` (at 3:7)"
       );
       ok = true;
     },
@@ -531,7 +577,445 @@ async fn throw_null() {
     }
     "#],
     |x| {
-      assert_eq!(x.unwrap_err().to_string(), "script thrown null");
+      assert_eq!(x.unwrap_err().to_string(), "script thrown null (at 3:7)");
+      ok = true;
+    },
+  )
+  .await;
+
+  assert!(ok);
+}
+
+/// A store that counts its reads in flight. Each read takes a millisecond.
+struct InFlightKv {
+  inner: MockKv,
+  in_flight: Arc<AtomicUsize>,
+  peak: Arc<AtomicUsize>,
+}
+
+struct InFlightTransaction {
+  inner: Box<dyn KvTransaction>,
+  in_flight: Arc<AtomicUsize>,
+  peak: Arc<AtomicUsize>,
+}
+
+#[async_trait]
+impl KeyValueStore for InFlightKv {
+  async fn begin_transaction(&self) -> Result<Box<dyn KvTransaction>> {
+    Ok(Box::new(InFlightTransaction {
+      inner: self.inner.begin_transaction().await?,
+      in_flight: self.in_flight.clone(),
+      peak: self.peak.clone(),
+    }))
+  }
+}
+
+#[async_trait]
+impl KvTransaction for InFlightTransaction {
+  async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    let n = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+    self.peak.fetch_max(n, Ordering::SeqCst);
+    tokio::time::sleep(Duration::from_millis(1)).await;
+    let res = self.inner.get(key).await;
+    self.in_flight.fetch_sub(1, Ordering::SeqCst);
+    res
+  }
+
+  async fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+    self.inner.put(key, value).await
+  }
+
+  async fn delete(&self, key: &[u8]) -> Result<()> {
+    self.inner.delete(key).await
+  }
+
+  async fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+    self.inner.delete_range(start, end).await
+  }
+
+  async fn scan_keys(&self, start: &[u8], end: &[u8]) -> Result<Box<dyn KvKeyIterator>> {
+    self.inner.scan_keys(start, end).await
+  }
+
+  async fn commit(self: Box<Self>) -> Result<(), KvError> {
+    self.inner.commit().await
+  }
+}
+
+#[tokio::test]
+async fn concurrency_limit() {
+  let _ = pretty_env_logger::try_init();
+  const SCHEMA: &str = r#"
+  type Store {
+    a: int64,
+    b: int64,
+    c: int64,
+    d: int64,
+    e: int64,
+    f: int64,
+  }
+  export Store store;
+  "#;
+  const WRITER: &str = r#"
+  graph main(root: schema) {
+    s = root.store;
+    t_insert(a) s 1;
+    t_insert(b) s 2;
+    t_insert(c) s 3;
+    t_insert(d) s 4;
+    t_insert(e) s 5;
+    t_insert(f) s 6;
+  }
+  "#;
+  const READER: &str = r#"
+  graph main(root: schema): int64 {
+    s = root.store;
+    return s.a + s.b + s.c + s.d + s.e + s.f;
+  }
+  "#;
+
+  // Zero is treated as one.
+  for &concurrency in &[0usize, 1, 3, 64] {
+    let kv = InFlightKv {
+      inner: MockKv::new(),
+      in_flight: Arc::new(AtomicUsize::new(0)),
+      peak: Arc::new(AtomicUsize::new(0)),
+    };
+    let mut outputs = vec![];
+    simple_test_with_config(
+      SCHEMA,
+      &[WRITER, READER],
+      &kv,
+      &ExecConfig {
+        concurrency,
+        ..Default::default()
+      },
+      |x| outputs.push(x.unwrap()),
+    )
+    .await;
+    assert_eq!(
+      **outputs[1].as_ref().unwrap(),
+      VmValue::Primitive(PrimitiveValue::Int64(21))
+    );
+    let peak = kv.peak.load(Ordering::SeqCst);
+    println!("concurrency {}: peak {}", concurrency, peak);
+    assert!(peak <= concurrency.max(1));
+    if concurrency > 1 {
+      // The reads of the fields are independent, so more than one of them is in flight.
+      assert!(peak > 1);
+    }
+  }
+
+  // A single permit must not deadlock nested calls.
+  let mut ok = false;
+  simple_test_with_config(
+    "",
+    &[r#"
+    graph main(root: schema): int64 {
+      return call(fib) [15];
+    }
+    graph fib(x: int64): int64 {
+      if x == 1 || x == 2 {
+        v1 = 1;
+      } else {
+        v2 = call(fib) [x - 1] + call(fib) [x - 2];
+      }
+      return select v1 v2;
+    }
+    "#],
+    &MockKv::new(),
+    &ExecConfig {
+      concurrency: 1,
+      ..Default::default()
+    },
+    |x| {
+      assert_eq!(
+        **x.unwrap().as_ref().unwrap(),
+        VmValue::Primitive(PrimitiveValue::Int64(610))
+      );
+      ok = true;
+    },
+  )
+  .await;
+  assert!(ok);
+}
+
+#[tokio::test]
+async fn try_call() {
+  let _ = pretty_env_logger::try_init();
+  let mut ok = false;
+  simple_test(
+    r#"
+  "#,
+    &[r#"
+    graph main(root: schema): map { value: int64, failed: bool, message: string, fallback: int64 } {
+      good = try_call(checked_sub) [10, 3];
+      bad = try_call(checked_sub) [3, 10];
+      return m_insert(value) (unwrap_value good)
+        $ m_insert(failed) (is_error bad)
+        $ m_insert(message) (error_message bad)
+        $ m_insert(fallback) (unwrap_value bad ?? 0)
+        $ create_map;
+    }
+    graph checked_sub(a: int64, b: int64): int64 {
+      if a == b - 7 {
+        throw "negative result";
+      }
+      return a - b;
+    }
+    "#],
+    |x| {
+      let x = x.unwrap();
+      let x = x.unwrap_map();
+      assert_eq!(
+        **x.elements.get("value").unwrap(),
+        VmValue::Primitive(PrimitiveValue::Int64(7))
+      );
+      assert_eq!(**x.elements.get("failed").unwrap(), VmValue::Bool(true));
+      assert_eq!(
+        **x.elements.get("message").unwrap(),
+        VmValue::Primitive(PrimitiveValue::String("negative result".into()))
+      );
+      assert_eq!(
+        **x.elements.get("fallback").unwrap(),
+        VmValue::Primitive(PrimitiveValue::Int64(0))
+      );
+      ok = true;
+    },
+  )
+  .await;
+
+  assert!(ok);
+}
+
+#[tokio::test]
+async fn assert_failure() {
+  let _ = pretty_env_logger::try_init();
+  let mut ok = false;
+  simple_test_with_error(
+    r#"
+  "#,
+    &[r#"
+    graph main(root: schema): int64 {
+      return call(half) [3];
+    }
+    graph half(x: int64): int64 {
+      assert x == 2 || x == 4, "x must be 2 or 4";
+      return x - 1;
+    }
+    "#],
+    |x| {
+      assert_eq!(
+        x.unwrap_err().to_string(),
+        "assertion failed at graph 1 node 6: `x must be 2 or 4` (at 6:7)"
+      );
+      ok = true;
+    },
+  )
+  .await;
+
+  assert!(ok);
+}
+
+#[tokio::test]
+async fn conditional_region() {
+  let _ = pretty_env_logger::try_init();
+  let mut ok = false;
+  simple_test(
+    r#"
+  "#,
+    &[r#"
+    graph main(root: schema): map { positive: int64, negative: int64 } {
+      return m_insert(positive) (call(dec_if_positive) [1] ?? 42)
+        $ m_insert(negative) (call(dec_if_positive) [-1] ?? 42)
+        $ create_map;
+    }
+    graph dec_if_positive(x: int64): int64 {
+      if x != 0 && x != -1 {
+        v = x + 0;
+      }
+      y = v - 1;
+      return y;
+    }
+    "#],
+    |x| {
+      let x = x.unwrap();
+      let x = x.unwrap_map();
+      assert_eq!(
+        **x.elements.get("positive").unwrap(),
+        VmValue::Primitive(PrimitiveValue::Int64(0))
+      );
+      assert_eq!(
+        **x.elements.get("negative").unwrap(),
+        VmValue::Primitive(PrimitiveValue::Int64(42))
+      );
+      ok = true;
+    },
+  )
+  .await;
+
+  assert!(ok);
+}
+
+#[tokio::test]
+async fn loop_until() {
+  let _ = pretty_env_logger::try_init();
+  let mut ok = false;
+  simple_test(
+    r#"
+  "#,
+    &[r#"
+    graph main(root: schema): map { stopped: int64, bounded: int64 } {
+      return m_insert(stopped) (loop_until(inc_until, 1000) 300 0)
+        $ m_insert(bounded) (loop_until(inc_until, 5) 300 0)
+        $ create_map;
+    }
+    graph inc_until(limit: int64, acc: int64): int64 {
+      if acc == limit {
+        r1 = null<int64>;
+      } else {
+        r2 = acc + 1;
+      }
+      return select r1 r2;
+    }
+    "#],
+    |x| {
+      let x = x.unwrap();
+      let x = x.unwrap_map();
+      assert_eq!(
+        **x.elements.get("stopped").unwrap(),
+        VmValue::Primitive(PrimitiveValue::Int64(300))
+      );
+      assert_eq!(
+        **x.elements.get("bounded").unwrap(),
+        VmValue::Primitive(PrimitiveValue::Int64(5))
+      );
+      ok = true;
+    },
+  )
+  .await;
+
+  assert!(ok);
+}
+
+#[tokio::test]
+async fn map_iteration() {
+  let _ = pretty_env_logger::try_init();
+  let mut ok = false;
+  simple_test(
+    r#"
+  "#,
+    &[r#"
+    graph main(root: schema): map {
+      total: int64,
+      joined: string,
+      keys: list<string>,
+      values: list<int64>,
+    } {
+      m = m_insert(b) 2 $ m_insert(c) 3 $ m_insert(a) 1 $ create_map;
+      return m_insert(total) (reduce_map(sum) create_map 0 m)
+        $ m_insert(joined) (reduce_map(join) create_map "" m)
+        $ m_insert(keys) (map_keys m)
+        $ m_insert(values) (map_values m)
+        $ create_map;
+    }
+    graph sum(_unused: map{}, acc: int64, key: string, value: int64): int64 {
+      return acc + value;
+    }
+    graph join(_unused: map{}, acc: string, key: string, value: int64): string {
+      return acc + key;
+    }
+    "#],
+    |x| {
+      let x = x.unwrap();
+      let x = x.unwrap_map();
+      let string = |x: &str| VmValue::Primitive(PrimitiveValue::String(x.into()));
+      let int64 = |x: i64| VmValue::Primitive(PrimitiveValue::Int64(x));
+      fn list<'a, 'b>(x: &'b VmValue<'a>) -> Vec<&'b VmValue<'a>> {
+        match x {
+          VmValue::List(VmListValue {
+            kind: VmListValueKind::Fresh(x),
+            ..
+          }) => x.iter().map(|x| &**x).collect(),
+          _ => unreachable!(),
+        }
+      }
+      assert_eq!(**x.elements.get("total").unwrap(), int64(6));
+      assert_eq!(**x.elements.get("joined").unwrap(), string("abc"));
+      assert_eq!(
+        list(x.elements.get("keys").unwrap()),
+        vec![&string("a"), &string("b"), &string("c")]
+      );
+      assert_eq!(
+        list(x.elements.get("values").unwrap()),
+        vec![&int64(1), &int64(2), &int64(3)]
+      );
+      ok = true;
+    },
+  )
+  .await;
+
+  assert!(ok);
+}
+
+#[tokio::test]
+async fn merge() {
+  let _ = pretty_env_logger::try_init();
+  let mut ok = false;
+  simple_test(
+    r#"
+    type Item {
+      @primary
+      id: string,
+      name: string,
+      position: Point,
+    }
+    type Point {
+      x: int64,
+      y: int64,
+    }
+  "#,
+    &[r#"
+    graph main(root: schema): map {
+      name: string,
+      position: map { x: int64, y: int64 },
+      map: map { a: map { x: int64, y: int64 }, b: string },
+    } {
+      item = build_table(Item)
+        $ m_insert(id) "a"
+        $ m_insert(name) "old"
+        $ m_insert(position) (build_table(Point) $ m_insert(x) 1 $ m_insert(y) 2 create_map)
+        create_map;
+      patch = m_insert(name) "new"
+        $ m_insert(position) (m_insert(y) 3 create_map)
+        create_map;
+      left = m_insert(a) (m_insert(x) 1 create_map) create_map;
+      right = m_insert(a) (m_insert(y) 2 create_map) $ m_insert(b) "b" create_map;
+      merged = merge item patch;
+      return m_insert(name) merged.name
+        $ m_insert(position) (m_insert(x) merged.position.x $ m_insert(y) merged.position.y create_map)
+        $ m_insert(map) (merge left right)
+        create_map;
+    }
+    "#],
+    |x| {
+      let x = x.unwrap();
+      let serialized =
+        serde_json::to_value(&SerializedVmValue::encode(&*x, &Default::default()).unwrap())
+          .unwrap();
+      assert_eq!(
+        serialized,
+        serde_json::json!({
+          "M": {
+            "name": "new",
+            "position": { "M": { "x": "1", "y": "3" } },
+            "map": { "M": {
+              "a": { "M": { "x": "1", "y": "2" } },
+              "b": "b",
+            } },
+          }
+        })
+      );
       ok = true;
     },
   )
@@ -539,3 +1023,1068 @@ async fn throw_null() {
 
   assert!(ok);
 }
+
+#[tokio::test]
+async fn apply_patch() {
+  let _ = pretty_env_logger::try_init();
+  let mut chkindex = 0usize;
+  simple_test(
+    r#"
+    type Item {
+      @primary
+      id: string,
+      name: string,
+      count: int64,
+    }
+    export set<Item> items;
+  "#,
+    &[
+      r#"
+    graph main(root: schema) {
+      s_insert root.items $ build_table(Item)
+        $ m_insert(id) "a"
+        $ m_insert(name) "old"
+        $ m_insert(count) 1
+        create_map;
+    }
+    "#,
+      r#"
+    graph main(root: schema) {
+      t_patch (point_get root.items "a") $ m_insert(name) "new" $ m_insert(count) null<int64> create_map;
+    }
+    "#,
+      r#"
+    graph main(root: schema): map { name: string, count: int64 } {
+      item = point_get root.items "a";
+      return m_insert(name) item.name $ m_insert(count) item.count create_map;
+    }
+    "#,
+    ],
+    |x| {
+      if chkindex == 2 {
+        let x = x.unwrap();
+        let x = x.unwrap_map();
+        assert_eq!(
+          **x.elements.get("name").unwrap(),
+          VmValue::Primitive(PrimitiveValue::String("new".into()))
+        );
+        assert!(x.elements.get("count").unwrap().is_null());
+      }
+      chkindex += 1;
+    },
+  )
+  .await;
+
+  assert_eq!(chkindex, 3);
+}
+
+#[tokio::test]
+async fn collect_page() {
+  let _ = pretty_env_logger::try_init();
+  let mut chkindex = 0usize;
+  simple_test(
+    r#"
+    type Item {
+      @primary
+      id: string,
+    }
+    export set<Item> items;
+  "#,
+    &[
+      r#"
+    graph main(root: schema) {
+      s_insert root.items $ call(item) ["c"];
+      s_insert root.items $ call(item) ["a"];
+      s_insert root.items $ call(item) ["e"];
+      s_insert root.items $ call(item) ["b"];
+      s_insert root.items $ call(item) ["d"];
+    }
+    graph item(id: string): Item {
+      return build_table(Item) $ m_insert(id) id create_map;
+    }
+    "#,
+      r#"
+    graph main(root: schema): map { first: string, second: string, third: string, last_cursor: string, total: int64 } {
+      p1 = collect_page(2) null<string> root.items;
+      p2 = collect_page(2) p1.next_cursor root.items;
+      p3 = collect_page(2) p2.next_cursor root.items;
+      return m_insert(first) (head p1.items).id
+        $ m_insert(second) (head p2.items).id
+        $ m_insert(third) (head p3.items).id
+        $ m_insert(last_cursor) p3.next_cursor
+        $ m_insert(total) p1.total_estimate
+        create_map;
+    }
+    "#,
+      r#"
+    graph main(root: schema): map { first: string, total: int64 } {
+      a = call(item) ["a"];
+      b = call(item) ["b"];
+      c = call(item) ["c"];
+      s = build_set $ b : a : c : create_list(Item);
+      p1 = collect_page(2) null<string> s;
+      p2 = collect_page(2) p1.next_cursor s;
+      return m_insert(first) (head p2.items).id $ m_insert(total) p2.total_estimate create_map;
+    }
+    graph item(id: string): Item {
+      return build_table(Item) $ m_insert(id) id create_map;
+    }
+    "#,
+    ],
+    |x| {
+      let string = |x: &str| VmValue::Primitive(PrimitiveValue::String(x.into()));
+      match chkindex {
+        0 => {}
+        1 => {
+          let x = x.unwrap();
+          let x = x.unwrap_map();
+          assert_eq!(**x.elements.get("first").unwrap(), string("a"));
+          assert_eq!(**x.elements.get("second").unwrap(), string("c"));
+          assert_eq!(**x.elements.get("third").unwrap(), string("e"));
+          assert!(x.elements.get("last_cursor").unwrap().is_null());
+          assert!(x.elements.get("total").unwrap().is_null());
+        }
+        2 => {
+          let x = x.unwrap();
+          let x = x.unwrap_map();
+          assert_eq!(**x.elements.get("first").unwrap(), string("c"));
+          assert_eq!(
+            **x.elements.get("total").unwrap(),
+            VmValue::Primitive(PrimitiveValue::Int64(3))
+          );
+        }
+        _ => unreachable!(),
+      }
+      chkindex += 1;
+    },
+  )
+  .await;
+
+  assert_eq!(chkindex, 3);
+}
+
+#[tokio::test]
+async fn named_outputs() {
+  let _ = pretty_env_logger::try_init();
+  let mut ok = false;
+  simple_test(
+    r#"
+  "#,
+    &[r#"
+    graph main(root: schema) {
+      return call(stats) [4];
+    }
+    graph stats(x: int64) {
+      if x == 3 {
+        odd = "odd";
+      }
+      return { double: x + x, label: "four", odd: odd };
+    }
+    "#],
+    |x| {
+      let x = x.unwrap();
+      let x = x.unwrap_map();
+      assert_eq!(
+        **x.elements.get("double").unwrap(),
+        VmValue::Primitive(PrimitiveValue::Int64(8))
+      );
+      assert_eq!(
+        **x.elements.get("label").unwrap(),
+        VmValue::Primitive(PrimitiveValue::String("four".into()))
+      );
+      assert!(x.elements.get("odd").unwrap().is_null());
+      ok = true;
+    },
+  )
+  .await;
+
+  assert!(ok);
+}
+
+#[tokio::test]
+async fn strict_build_table() {
+  let _ = pretty_env_logger::try_init();
+  let mut chkindex = 0usize;
+  let script = |option: &str| {
+    format!(
+      r#"
+    graph main(root: schema): string {{
+      return call(build) [m_insert(id) "a" $ m_insert(name) "n" $ m_insert(nmae) "typo" create_map];
+    }}
+    graph build(m: map {{ id: string, name: string }}): string {{
+      return (build_table(Item{}) m).name;
+    }}
+    "#,
+      option
+    )
+  };
+  simple_test_with_error(
+    r#"
+    type Item {
+      @primary
+      id: string,
+      name: string,
+    }
+  "#,
+    &[&script(""), &script(", strict")],
+    |x| {
+      match chkindex {
+        0 => assert_eq!(
+          *x.unwrap().unwrap(),
+          VmValue::Primitive(PrimitiveValue::String("n".into()))
+        ),
+        1 => assert!(x
+          .unwrap_err()
+          .to_string()
+          .contains("map entries are not fields of type `Item`: `nmae`")),
+        _ => unreachable!(),
+      }
+      chkindex += 1;
+    },
+  )
+  .await;
+
+  assert_eq!(chkindex, 2);
+}
+
+#[tokio::test]
+async fn param_constraints() {
+  let _ = pretty_env_logger::try_init();
+  let mut chkindex = 0usize;
+  let script = |name: &str, age: i64| {
+    format!(
+      r#"
+    graph main(root: schema): string {{
+      return call(greet) ["{}", {}];
+    }}
+    graph greet(name: string @min_length(1) @max_length(8) @pattern("[a-z]+"), age: int64 @min(0) @max(150)): string {{
+      return name;
+    }}
+    "#,
+      name, age
+    )
+  };
+  simple_test_with_error(
+    r#"
+    type Item {
+      @primary
+      id: string,
+    }
+  "#,
+    &[
+      &script("ann", 30),
+      &script("", 30),
+      &script("Ann", 30),
+      &script("ann", 200),
+    ],
+    |x| {
+      match chkindex {
+        0 => assert_eq!(
+          *x.unwrap().unwrap(),
+          VmValue::Primitive(PrimitiveValue::String("ann".into()))
+        ),
+        1 => assert!(x
+          .unwrap_err()
+          .to_string()
+          .contains("param `name` of graph `greet` violates `@min_length(1)`")),
+        2 => assert!(x
+          .unwrap_err()
+          .to_string()
+          .contains("violates `@pattern(\"[a-z]+\")`")),
+        3 => assert!(x
+          .unwrap_err()
+          .to_string()
+          .contains("param `age` of graph `greet` violates `@max(150)`")),
+        _ => unreachable!(),
+      }
+      chkindex += 1;
+    },
+  )
+  .await;
+
+  assert_eq!(chkindex, 4);
+}
+
+#[tokio::test]
+async fn inline_structs() {
+  let _ = pretty_env_logger::try_init();
+  let mut chkindex = 0usize;
+  simple_test(
+    r#"
+    type Item {
+      @primary
+      id: string,
+      position: { x: int64, y: int64 },
+    }
+    export Item some_item;
+    export set<Item> many_items;
+  "#,
+    &[
+      r#"
+    graph main(root: schema) {
+      t_insert(position) root.some_item $ m_insert(x) 1 $ m_insert(y) 2 $ create_map;
+      s_insert root.many_items $ build_table(Item)
+        $ m_insert(id) "a"
+        $ m_insert(position) (m_insert(x) 3 $ m_insert(y) 4 $ create_map)
+        $ create_map;
+    }
+    "#,
+      r#"
+    graph main(root: schema): map {
+      x1: int64,
+      y1: int64,
+      x2: int64,
+      y2: int64,
+    } {
+      p1 = root.some_item.position;
+      p2 = (point_get root.many_items "a").position;
+      return m_insert(x1) p1.x
+        $ m_insert(y1) p1.y
+        $ m_insert(x2) p2.x
+        $ m_insert(y2) p2.y
+        $ create_map;
+    }
+    "#,
+    ],
+    |x| {
+      match chkindex {
+        0 => {}
+        1 => {
+          let x = x.unwrap();
+          let x = x.unwrap_map();
+          for (k, v) in [("x1", 1), ("y1", 2), ("x2", 3), ("y2", 4)] {
+            assert_eq!(
+              **x.elements.get(k).unwrap(),
+              VmValue::Primitive(PrimitiveValue::Int64(v))
+            );
+          }
+        }
+        _ => unreachable!(),
+      }
+      chkindex += 1;
+    },
+  )
+  .await;
+
+  assert_eq!(chkindex, 2);
+}
+
+#[tokio::test]
+async fn persisted_lists() {
+  let _ = pretty_env_logger::try_init();
+  let mut chkindex = 0usize;
+  simple_test(
+    r#"
+    type Item {
+      values: list<int64>,
+      points: list<{ x: int64, y: int64 }>,
+    }
+    export Item item;
+  "#,
+    &[
+      r#"
+    graph main(root: schema) {
+      values = 1 : 2 : 3 : create_list(int64);
+      t_insert(values) root.item values;
+    }
+    "#,
+      r#"
+    graph main(root: schema) {
+      l_push root.item.values 4;
+      l_push root.item.points $ m_insert(x) 1 $ m_insert(y) 2 $ create_map;
+    }
+    "#,
+      r#"
+    graph main(root: schema): map {
+      popped: int64,
+    } {
+      return m_insert(popped) (l_pop root.item.values) create_map;
+    }
+    "#,
+      r#"
+    graph main(root: schema): map {
+      first: int64,
+      third: int64,
+      missing: int64,
+      sum: int64,
+      px: int64,
+    } {
+      values = root.item.values;
+      return m_insert(first) (l_get values 0)
+        $ m_insert(third) (l_get values 2)
+        $ m_insert(missing) (l_get values 3)
+        $ m_insert(sum) (reduce(sum) create_map 0 values)
+        $ m_insert(px) (l_get root.item.points 0).x
+        $ create_map;
+    }
+
+    graph sum(_unused: map{}, current: int64, that: int64): int64 {
+      return current + that;
+    }
+    "#,
+    ],
+    |x| {
+      match chkindex {
+        0 | 1 => {}
+        2 => {
+          let x = x.unwrap();
+          assert_eq!(
+            **x.unwrap_map().elements.get("popped").unwrap(),
+            VmValue::Primitive(PrimitiveValue::Int64(4))
+          );
+        }
+        3 => {
+          let x = x.unwrap();
+          let x = x.unwrap_map();
+          for (k, v) in [("first", 1), ("third", 3), ("sum", 6), ("px", 1)] {
+            assert_eq!(
+              **x.elements.get(k).unwrap(),
+              VmValue::Primitive(PrimitiveValue::Int64(v))
+            );
+          }
+          assert!(x.elements.get("missing").unwrap().is_null());
+        }
+        _ => unreachable!(),
+      }
+      chkindex += 1;
+    },
+  )
+  .await;
+
+  assert_eq!(chkindex, 4);
+}
+
+#[tokio::test]
+async fn persisted_maps() {
+  let _ = pretty_env_logger::try_init();
+  let mut chkindex = 0usize;
+  simple_test(
+    r#"
+    type Store {
+      counts: map<string, int64>,
+      items: map<string, Item>,
+    }
+    type Item {
+      name: string,
+    }
+    export Store store;
+  "#,
+    &[
+      r#"
+    graph main(root: schema) {
+      counts = root.store.counts;
+      map_put counts "a" 1;
+      map_put counts "b" 2;
+      map_put counts "c" 3;
+      map_put root.store.items "x" $ build_table(Item) $ m_insert(name) "item_x" $ create_map;
+    }
+    "#,
+      r#"
+    graph main(root: schema) {
+      map_delete root.store.counts "b";
+    }
+    "#,
+      r#"
+    graph main(root: schema): map {
+      a: int64,
+      b: int64,
+      sum: int64,
+      tail: int64,
+      keys: string,
+      name: string,
+    } {
+      counts = root.store.counts;
+      return m_insert(a) (map_get counts "a")
+        $ m_insert(b) (map_get counts "b")
+        $ m_insert(sum) (reduce(sum) create_map 0 counts)
+        $ m_insert(tail) (reduce(sum) from "b" to null<string> create_map 0 counts)
+        $ m_insert(keys) (reduce(concat_keys) create_map "" counts)
+        $ m_insert(name) (map_get root.store.items "x").name
+        $ create_map;
+    }
+
+    graph sum(_unused: map{}, current: int64, entry: map { key: string, value: int64 }): int64 {
+      return current + entry.value;
+    }
+
+    graph concat_keys(_unused: map{}, current: string, entry: map { key: string, value: int64 }): string {
+      return current + entry.key;
+    }
+    "#,
+    ],
+    |x| {
+      match chkindex {
+        0 | 1 => {}
+        2 => {
+          let x = x.unwrap();
+          let x = x.unwrap_map();
+          for (k, v) in [("a", 1), ("sum", 4), ("tail", 3)] {
+            assert_eq!(
+              **x.elements.get(k).unwrap(),
+              VmValue::Primitive(PrimitiveValue::Int64(v))
+            );
+          }
+          assert!(x.elements.get("b").unwrap().is_null());
+          assert_eq!(
+            **x.elements.get("keys").unwrap(),
+            VmValue::Primitive(PrimitiveValue::String("ac".into()))
+          );
+          assert_eq!(
+            **x.elements.get("name").unwrap(),
+            VmValue::Primitive(PrimitiveValue::String("item_x".into()))
+          );
+        }
+        _ => unreachable!(),
+      }
+      chkindex += 1;
+    },
+  )
+  .await;
+
+  assert_eq!(chkindex, 3);
+}
+
+#[tokio::test]
+async fn sorted_reduce() {
+  let _ = pretty_env_logger::try_init();
+  let mut chkindex = 0usize;
+  simple_test(
+    r#"
+    type Event {
+      @primary
+      id: string,
+      @sort_key
+      at: int64,
+    }
+    export set<Event> events;
+  "#,
+    &[
+      r#"
+    graph main(root: schema) {
+      s_insert root.events $ build_table(Event) $ m_insert(id) "a" $ m_insert(at) 30 $ create_map;
+      s_insert root.events $ build_table(Event) $ m_insert(id) "b" $ m_insert(at) 10 $ create_map;
+      s_insert root.events $ build_table(Event) $ m_insert(id) "c" $ m_insert(at) 20 $ create_map;
+      s_insert root.events $ build_table(Event) $ m_insert(id) "d" $ m_insert(at) 40 $ create_map;
+    }
+    "#,
+      r#"
+    graph main(root: schema) {
+      s_insert root.events $ build_table(Event) $ m_insert(id) "c" $ m_insert(at) 50 $ create_map;
+      s_delete root.events "d";
+    }
+    "#,
+      r#"
+    graph main(root: schema): map {
+      all: string,
+      ranged: string,
+      by_primary_key: string,
+    } {
+      return m_insert(all) (sorted_reduce(concat) create_map "" root.events)
+        $ m_insert(ranged) (sorted_reduce(concat) from 20 to 50 create_map "" root.events)
+        $ m_insert(by_primary_key) (reduce(concat) create_map "" root.events)
+        $ create_map;
+    }
+
+    graph concat(_unused: map{}, current: string, item: Event): string {
+      return current + item.id;
+    }
+    "#,
+    ],
+    |x| {
+      match chkindex {
+        0 | 1 => {}
+        2 => {
+          let x = x.unwrap();
+          let x = x.unwrap_map();
+          for (k, v) in [("all", "bac"), ("ranged", "a"), ("by_primary_key", "abc")] {
+            assert_eq!(
+              **x.elements.get(k).unwrap(),
+              VmValue::Primitive(PrimitiveValue::String(v.into()))
+            );
+          }
+        }
+        _ => unreachable!(),
+      }
+      chkindex += 1;
+    },
+  )
+  .await;
+
+  assert_eq!(chkindex, 3);
+}
+
+#[tokio::test]
+async fn check_constraints() {
+  let _ = pretty_env_logger::try_init();
+  let mut chkindex = 0usize;
+  simple_test_with_error(
+    r#"
+    type Duration {
+      @primary
+      id: string,
+      start: int64,
+      end: int64,
+      check end >= start,
+    }
+    export set<Duration> durations;
+  "#,
+    &[
+      r#"
+    graph main(root: schema) {
+      s_insert root.durations $ build_table(Duration)
+        $ m_insert(id) "a" $ m_insert(start) 1 $ m_insert(end) 2 $ create_map;
+      s_insert root.durations $ build_table(Duration)
+        $ m_insert(id) "b" $ m_insert(start) 1 $ m_insert(end) null<int64> $ create_map;
+    }
+    "#,
+      r#"
+    graph main(root: schema) {
+      s_insert root.durations $ build_table(Duration)
+        $ m_insert(id) "c" $ m_insert(start) 2 $ m_insert(end) 1 $ create_map;
+    }
+    "#,
+    ],
+    |x| {
+      match chkindex {
+        0 => {
+          x.unwrap();
+        }
+        1 => assert_eq!(
+          x.unwrap_err().to_string(),
+          "check constraint violated on type `Duration<>`: `end >= start`"
+        ),
+        _ => unreachable!(),
+      }
+      chkindex += 1;
+    },
+  )
+  .await;
+
+  assert_eq!(chkindex, 2);
+}
+
+#[tokio::test]
+async fn auto_primary_keys() {
+  let _ = pretty_env_logger::try_init();
+  let mut chkindex = 0usize;
+  simple_test(
+    r#"
+    type Item {
+      @primary @auto
+      id: int64,
+      name: string,
+    }
+    export set<Item> items;
+  "#,
+    &[
+      r#"
+    graph main(root: schema): map { first: int64, second: int64, explicit: int64, third: int64 } {
+      first = s_insert root.items $ build_table(Item) $ m_insert(name) "a" $ create_map;
+      second = s_insert root.items $ build_table(Item) $ m_insert(name) "b" $ create_map;
+      explicit = s_insert root.items $ build_table(Item)
+        $ m_insert(id) 10 $ m_insert(name) "c" $ create_map;
+      third = s_insert root.items $ build_table(Item) $ m_insert(name) "d" $ create_map;
+      return m_insert(first) first $ m_insert(second) second
+        $ m_insert(explicit) explicit $ m_insert(third) third $ create_map;
+    }
+    "#,
+      r#"
+    graph main(root: schema): string {
+      return (point_get root.items 2).name;
+    }
+    "#,
+    ],
+    |x| {
+      match chkindex {
+        0 => {
+          let x = x.unwrap();
+          let x = x.unwrap_map();
+          let get = |k: &str| x.elements.get(k).unwrap().unwrap_primitive().clone();
+          assert_eq!(get("first"), PrimitiveValue::Int64(1));
+          assert_eq!(get("second"), PrimitiveValue::Int64(2));
+          assert_eq!(get("explicit"), PrimitiveValue::Int64(10));
+          assert_eq!(get("third"), PrimitiveValue::Int64(11));
+        }
+        1 => assert_eq!(
+          x.unwrap().unwrap_primitive(),
+          &PrimitiveValue::String("b".into())
+        ),
+        _ => unreachable!(),
+      }
+      chkindex += 1;
+    },
+  )
+  .await;
+
+  assert_eq!(chkindex, 2);
+}
+
+#[tokio::test]
+async fn timestamps() {
+  let _ = pretty_env_logger::try_init();
+  let mut chkindex = 0usize;
+  let mut created_at = None;
+  simple_test(
+    r#"
+    @timestamps
+    type Item {
+      @primary
+      id: string,
+      name: string,
+    }
+    export set<Item> items;
+  "#,
+    &[
+      r#"
+    graph main(root: schema) {
+      s_insert root.items $ build_table(Item) $ m_insert(id) "a" $ m_insert(name) "x" $ create_map;
+    }
+    "#,
+      r#"
+    graph main(root: schema): map { created_at: int64, updated_at: int64 } {
+      item = point_get root.items "a";
+      return m_insert(created_at) item.created_at $ m_insert(updated_at) item.updated_at $ create_map;
+    }
+    "#,
+      r#"
+    graph main(root: schema) {
+      t_insert(name) (point_get root.items "a") "y";
+      s_insert root.items $ build_table(Item)
+        $ m_insert(id) "a" $ m_insert(name) "z" $ m_insert(created_at) 0 $ create_map;
+    }
+    "#,
+      r#"
+    graph main(root: schema): map { created_at: int64, updated_at: int64 } {
+      item = point_get root.items "a";
+      return m_insert(created_at) item.created_at $ m_insert(updated_at) item.updated_at $ create_map;
+    }
+    "#,
+    ],
+    |x| {
+      match chkindex {
+        0 | 2 => {}
+        1 | 3 => {
+          let x = x.unwrap();
+          let x = x.unwrap_map();
+          let get = |k: &str| match x.elements.get(k).unwrap().unwrap_primitive() {
+            PrimitiveValue::Int64(x) => *x,
+            _ => unreachable!(),
+          };
+          assert!(get("created_at") > 0);
+          assert!(get("updated_at") >= get("created_at"));
+          match created_at {
+            None => created_at = Some(get("created_at")),
+            Some(x) => assert_eq!(get("created_at"), x),
+          }
+        }
+        _ => unreachable!(),
+      }
+      chkindex += 1;
+    },
+  )
+  .await;
+
+  assert_eq!(chkindex, 4);
+}
+
+#[tokio::test]
+async fn soft_delete() {
+  let _ = pretty_env_logger::try_init();
+  let mut chkindex = 0usize;
+  simple_test(
+    r#"
+    @soft_delete
+    type Event {
+      @primary
+      id: string,
+      @sort_key
+      at: int64,
+    }
+    export set<Event> events;
+  "#,
+    &[
+      r#"
+    graph main(root: schema) {
+      s_insert root.events $ build_table(Event) $ m_insert(id) "a" $ m_insert(at) 30 $ create_map;
+      s_insert root.events $ build_table(Event) $ m_insert(id) "b" $ m_insert(at) 10 $ create_map;
+      s_insert root.events $ build_table(Event) $ m_insert(id) "c" $ m_insert(at) 20 $ create_map;
+    }
+    "#,
+      r#"
+    graph main(root: schema) {
+      s_delete root.events "b";
+      s_delete root.events "c";
+    }
+    "#,
+      r#"
+    graph main(root: schema): map {
+      live: string,
+      all: string,
+      b_hidden: bool,
+      b_deleted_at_missing: bool,
+    } {
+      all_events = include_deleted root.events;
+      b = point_get all_events "b";
+      return m_insert(live) (reduce(concat) create_map "" root.events)
+        $ m_insert(all) (sorted_reduce(concat) create_map "" all_events)
+        $ m_insert(b_hidden) (is_null $ point_get root.events "b")
+        $ m_insert(b_deleted_at_missing) (is_null b.deleted_at)
+        $ create_map;
+    }
+
+    graph concat(_unused: map{}, current: string, item: Event): string {
+      return current + item.id;
+    }
+    "#,
+      r#"
+    graph main(root: schema): int64 {
+      s_insert root.events $ build_table(Event) $ m_insert(id) "c" $ m_insert(at) 20 $ create_map;
+      return purge_deleted root.events 9000000000000000;
+    }
+    "#,
+      r#"
+    graph main(root: schema): string {
+      return sorted_reduce(concat) create_map "" (include_deleted root.events);
+    }
+
+    graph concat(_unused: map{}, current: string, item: Event): string {
+      return current + item.id;
+    }
+    "#,
+    ],
+    |x| {
+      match chkindex {
+        0 | 1 => {}
+        2 => {
+          let x = x.unwrap();
+          let x = x.unwrap_map();
+          for (k, v) in [
+            (
+              "live",
+              VmValue::Primitive(PrimitiveValue::String("a".into())),
+            ),
+            (
+              "all",
+              VmValue::Primitive(PrimitiveValue::String("bca".into())),
+            ),
+            ("b_hidden", VmValue::Bool(true)),
+            ("b_deleted_at_missing", VmValue::Bool(false)),
+          ] {
+            assert_eq!(**x.elements.get(k).unwrap(), v);
+          }
+        }
+        3 => {
+          assert_eq!(*x.unwrap(), VmValue::Primitive(PrimitiveValue::Int64(1)));
+        }
+        4 => {
+          assert_eq!(
+            *x.unwrap(),
+            VmValue::Primitive(PrimitiveValue::String("ca".into()))
+          );
+        }
+        _ => unreachable!(),
+      }
+      chkindex += 1;
+    },
+  )
+  .await;
+
+  assert_eq!(chkindex, 5);
+}
+
+#[tokio::test]
+async fn collation() {
+  let _ = pretty_env_logger::try_init();
+  let mut chkindex = 0usize;
+  simple_test(
+    r#"
+    type User {
+      @primary @collate("nocase")
+      name: string,
+      @sort_key @collate("nocase", "nfc")
+      nick: string,
+    }
+    export set<User> users;
+  "#,
+    &[
+      r#"
+    graph main(root: schema) {
+      s_insert root.users $ build_table(User) $ m_insert(name) "Alice" $ m_insert(nick) "zed" $ create_map;
+      s_insert root.users $ build_table(User) $ m_insert(name) "bob" $ m_insert(nick) "Yak" $ create_map;
+      s_insert root.users $ build_table(User) $ m_insert(name) "CAROL" $ m_insert(nick) "xray" $ create_map;
+    }
+    "#,
+      r#"
+    graph main(root: schema) {
+      s_insert root.users $ build_table(User) $ m_insert(name) "ALICE" $ m_insert(nick) "Ann" $ create_map;
+    }
+    "#,
+      r#"
+    graph main(root: schema): map {
+      found: string,
+      all: string,
+      ranged: string,
+      sorted: string,
+    } {
+      return m_insert(found) (point_get root.users "alice").nick
+        $ m_insert(all) (reduce(concat) create_map "" root.users)
+        $ m_insert(ranged) (reduce(concat) from "B" to "CZ" create_map "" root.users)
+        $ m_insert(sorted) (sorted_reduce(concat) create_map "" root.users)
+        $ create_map;
+    }
+
+    graph concat(_unused: map{}, current: string, item: User): string {
+      return current + item.name;
+    }
+    "#,
+    ],
+    |x| {
+      match chkindex {
+        0 | 1 => {}
+        2 => {
+          let x = x.unwrap();
+          let x = x.unwrap_map();
+          for (k, v) in [
+            ("found", "Ann"),
+            ("all", "ALICEbobCAROL"),
+            ("ranged", "bobCAROL"),
+            ("sorted", "ALICECAROLbob"),
+          ] {
+            assert_eq!(
+              **x.elements.get(k).unwrap(),
+              VmValue::Primitive(PrimitiveValue::String(v.into()))
+            );
+          }
+        }
+        _ => unreachable!(),
+      }
+      chkindex += 1;
+    },
+  )
+  .await;
+
+  assert_eq!(chkindex, 3);
+}
+
+#[tokio::test]
+async fn prefix_reduce() {
+  let _ = pretty_env_logger::try_init();
+  let mut chkindex = 0usize;
+  simple_test(
+    r#"
+    type User {
+      @primary
+      name: string,
+      @sort_key
+      nick: string,
+    }
+    type Store {
+      scores: map<string, int64>,
+    }
+    export set<User> users;
+    export Store store;
+  "#,
+    &[
+      r#"
+    graph main(root: schema) {
+      s_insert root.users $ build_table(User) $ m_insert(name) "al" $ m_insert(nick) "zz" $ create_map;
+      s_insert root.users $ build_table(User) $ m_insert(name) "alice" $ m_insert(nick) "ann" $ create_map;
+      s_insert root.users $ build_table(User) $ m_insert(name) "alicia" $ m_insert(nick) "annie" $ create_map;
+      s_insert root.users $ build_table(User) $ m_insert(name) "bob" $ m_insert(nick) "anna" $ create_map;
+      s_insert root.users $ build_table(User) $ m_insert(name) "al\u00ff" $ m_insert(nick) "b" $ create_map;
+      map_put root.store.scores "alpha" 1;
+      map_put root.store.scores "alps" 2;
+      map_put root.store.scores "beta" 3;
+    }
+    "#,
+      r#"
+    graph main(root: schema): map {
+      by_name: string,
+      by_nick: string,
+      all: string,
+      none: string,
+      scores: int64,
+    } {
+      return m_insert(by_name) (reduce(concat) prefix "ali" create_map "" root.users)
+        $ m_insert(by_nick) (sorted_reduce(concat) prefix "ann" create_map "" root.users)
+        $ m_insert(all) (reduce(concat) prefix "" create_map "" root.users)
+        $ m_insert(none) (reduce(concat) prefix "c" create_map "" root.users)
+        $ m_insert(scores) (reduce(sum) prefix "alp" create_map 0 root.store.scores)
+        $ create_map;
+    }
+
+    graph concat(_unused: map{}, current: string, item: User): string {
+      return current + item.name + ",";
+    }
+
+    graph sum(_unused: map{}, current: int64, entry: map { key: string, value: int64 }): int64 {
+      return current + entry.value;
+    }
+    "#,
+    ],
+    |x| {
+      match chkindex {
+        0 => {}
+        1 => {
+          let x = x.unwrap();
+          let x = x.unwrap_map();
+          for (k, v) in [
+            ("by_name", "alice,alicia,"),
+            ("by_nick", "alice,bob,alicia,"),
+            ("all", "al,alice,alicia,al\u{ff},bob,"),
+            ("none", ""),
+          ] {
+            assert_eq!(
+              **x.elements.get(k).unwrap(),
+              VmValue::Primitive(PrimitiveValue::String(v.into()))
+            );
+          }
+          assert_eq!(
+            **x.elements.get("scores").unwrap(),
+            VmValue::Primitive(PrimitiveValue::Int64(3))
+          );
+        }
+        _ => unreachable!(),
+      }
+      chkindex += 1;
+    },
+  )
+  .await;
+
+  assert_eq!(chkindex, 2);
+}
+
+#[test]
+fn assembler_diagnostics() {
+  let source = r#"graph main(root: schema) {
+  a = missing + 1;
+  b = a + 1;
+  return call(nowhere) [b];
+}
+graph main(root: schema) {}
+"#;
+  let e = compile_twscript(source).unwrap_err();
+  let errors = e.downcast_ref::<TwAsmErrors>().unwrap();
+  let errors = errors
+    .0
+    .iter()
+    .map(|x| (x.line, x.column, x.error.to_string()))
+    .collect::<Vec<_>>();
+  assert_eq!(
+    errors,
+    vec![
+      (2, 7, "node not found: missing".to_string()),
+      (4, 10, "graph not found: nowhere".to_string()),
+      (6, 1, "duplicate graph: main".to_string()),
+    ]
+  );
+  assert_eq!(
+    e.to_string(),
+    "2:7: node not found: missing\n4:10: graph not found: nowhere\n6:1: duplicate graph: main"
+  );
+
+  let e = compile_twscript("graph main( {}").unwrap_err();
+  let errors = e.downcast_ref::<TwAsmErrors>().unwrap();
+  assert_eq!(errors.0.len(), 1);
+  assert_eq!((errors.0[0].line, errors.0[0].column), (1, 13));
+}
@@ -8,6 +8,8 @@ pub struct Root<'a> {
 }
 
 pub struct TypeAlias<'a> {
+  pub location_start: usize,
+  pub location_end: usize,
   pub name: &'a str,
   pub ty: Type<'a>,
 }
@@ -18,15 +20,26 @@ pub enum Item<'a> {
 }
 
 pub struct Graph<'a> {
+  pub location_start: usize,
+  pub location_end: usize,
   pub name: &'a str,
   pub exported: bool,
   pub params: Vec<'a, (&'a str, Option<Type<'a>>)>,
+  pub param_annotations: Vec<'a, ParamAnnotation<'a>>,
   pub return_type: Option<Type<'a>>,
   pub stmts: Vec<'a, Stmt<'a>>,
 }
 
+/// An annotation on a graph param, e.g. `@min_length(1)`.
+pub struct ParamAnnotation<'a> {
+  pub param_index: usize,
+  pub name: &'a str,
+  pub arg: Literal<'a>,
+}
+
 pub struct Stmt<'a> {
   pub location: usize,
+  pub location_end: usize,
   pub kind: StmtKind<'a>,
 }
 
@@ -34,6 +47,9 @@ pub enum StmtKind<'a> {
   Return {
     value: Expr<'a>,
   },
+  ReturnNamed {
+    outputs: Vec<'a, (&'a str, Expr<'a>)>,
+  },
   Node {
     name: Option<&'a str>,
     value: Expr<'a>,
@@ -46,6 +62,10 @@ pub enum StmtKind<'a> {
   Throw {
     value: Expr<'a>,
   },
+  Assert {
+    value: Expr<'a>,
+    message: &'a str,
+  },
 }
 
 pub struct Expr<'a> {
@@ -62,14 +82,18 @@ pub enum Type<'a> {
   Primitive(PrimitiveType),
   Set(&'a Type<'a>),
   List(&'a Type<'a>),
+  Dict(&'a Type<'a>),
   Map(Vec<'a, (&'a str, Type<'a>)>),
   Bool,
   Schema,
+  AuthContext,
+  Error,
+  OneOf(Vec<'a, Type<'a>>),
 }
 
 pub enum ExprKind<'a> {
   LoadConst(Literal<'a>),
-  BuildTable(Type<'a>, &'a Expr<'a>),
+  BuildTable(Type<'a>, bool, &'a Expr<'a>),
   BuildSet(&'a Expr<'a>),
   CreateMap,
   GetField(&'a str, &'a Expr<'a>),
@@ -90,21 +114,59 @@ pub enum ExprKind<'a> {
   IsNull(&'a Expr<'a>),
   OrElse(&'a Expr<'a>, &'a Expr<'a>),
   Call(&'a str, Vec<'a, Expr<'a>>),
+  TryCall(&'a str, Vec<'a, Expr<'a>>),
+  IsError(&'a Expr<'a>),
+  UnwrapValue(&'a Expr<'a>),
+  ErrorMessage(&'a Expr<'a>),
   Add(&'a Expr<'a>, &'a Expr<'a>),
   Sub(&'a Expr<'a>, &'a Expr<'a>),
   CreateList(Type<'a>),
-  Reduce(&'a str, &'a Expr<'a>, &'a Expr<'a>, &'a Expr<'a>),
+  Reduce(&'a str, bool, &'a Expr<'a>, &'a Expr<'a>, &'a Expr<'a>),
   RangeReduce(
     &'a str,
+    bool,
     &'a Expr<'a>,
     &'a Expr<'a>,
     &'a Expr<'a>,
     &'a Expr<'a>,
     &'a Expr<'a>,
   ),
+  SortedReduce(
+    &'a str,
+    Option<(&'a Expr<'a>, &'a Expr<'a>)>,
+    &'a Expr<'a>,
+    &'a Expr<'a>,
+    &'a Expr<'a>,
+  ),
+  PrefixReduce(
+    &'a str,
+    bool,
+    &'a Expr<'a>,
+    &'a Expr<'a>,
+    &'a Expr<'a>,
+    &'a Expr<'a>,
+  ),
+  LoopUntil(&'a str, u32, &'a Expr<'a>, &'a Expr<'a>),
+  ReduceMap(&'a str, &'a Expr<'a>, &'a Expr<'a>, &'a Expr<'a>),
+  MapKeys(&'a Expr<'a>),
+  MapValues(&'a Expr<'a>),
+  Merge(&'a Expr<'a>, &'a Expr<'a>),
+  ApplyPatch(&'a Expr<'a>, &'a Expr<'a>),
+  CollectPage(u32, &'a Expr<'a>, &'a Expr<'a>),
   Prepend(&'a Expr<'a>, &'a Expr<'a>),
   Pop(&'a Expr<'a>),
   Head(&'a Expr<'a>),
+  ListPush(&'a Expr<'a>, &'a Expr<'a>),
+  ListPopBack(&'a Expr<'a>),
+  GetListElement(&'a Expr<'a>, &'a Expr<'a>),
+  GetMapEntry(&'a Expr<'a>, &'a Expr<'a>),
+  PutMapEntry(&'a Expr<'a>, &'a Expr<'a>, &'a Expr<'a>),
+  DeleteMapEntry(&'a Expr<'a>, &'a Expr<'a>),
+  IncludeDeleted(&'a Expr<'a>),
+  PurgeDeleted(&'a Expr<'a>, &'a Expr<'a>),
+  BlobLength(&'a str, &'a Expr<'a>),
+  BlobRead(&'a str, &'a Expr<'a>, &'a Expr<'a>, &'a Expr<'a>),
+  BlobAppend(&'a str, &'a Expr<'a>, &'a Expr<'a>),
 }
 
 pub enum Literal<'a> {
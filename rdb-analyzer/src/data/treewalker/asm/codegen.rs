@@ -2,21 +2,30 @@ use std::collections::{HashMap, HashSet};
 
 use super::language::RootParser;
 use super::{ast, state::State};
-use crate::data::treewalker::asm::TwAsmError;
-use crate::data::treewalker::bytecode::{TwGraph, TwGraphNode, TwScript};
+use crate::data::treewalker::asm::{SourceSpan, TwAsmDiagnostic, TwAsmError, TwAsmErrors};
+use crate::data::treewalker::bytecode::{
+  TwGraph, TwGraphNode, TwParamConstraint, TwScript, TwSourceMap, TwSourcePosition,
+};
 use crate::data::treewalker::vm_value::{
-  VmConst, VmConstSetValue, VmListType, VmSetType, VmTableType, VmType,
+  VmConst, VmConstSetValue, VmDictType, VmListType, VmSetType, VmTableType, VmType,
 };
 use crate::data::value::PrimitiveValue;
 use crate::schema::compile::PrimitiveType;
-use crate::util::first_duplicate;
 use anyhow::Result;
 use bumpalo::boxed::Box as BumpBox;
 use bumpalo::Bump;
+use lalrpop_util::ParseError;
+use thiserror::Error;
 
 pub fn compile_twscript(input: &str) -> Result<TwScript> {
   let bump = Bump::new();
-  let root = parse(&bump, input)?;
+  let root = match parse(&bump, input) {
+    Ok(x) => x,
+    Err(e) => {
+      let span = parse_error_span(&e).unwrap_or(SourceSpan { start: 0, end: 0 });
+      return Err(TwAsmErrors(vec![diagnostic(input, span, e)]).into());
+    }
+  };
 
   let mut builder = Builder {
     bump: &bump,
@@ -26,66 +35,138 @@ pub fn compile_twscript(input: &str) -> Result<TwScript> {
     const_pool: HashMap::new(),
     type_aliases: HashMap::new(),
     root: &root,
+    errors: vec![],
   };
-  if let Some(x) = first_duplicate(root.graphs.iter().map(|x| x.name)) {
-    return Err(TwAsmError::DuplicateGraph(x.into()).into());
+  let mut graph_names = HashSet::new();
+  for g in &root.graphs {
+    if !graph_names.insert(g.name) {
+      builder.report(
+        span(g.location_start, g.location_end),
+        TwAsmError::DuplicateGraph(g.name.into()).into(),
+      );
+    }
   }
 
   // Collect type aliases
-  if let Some(x) = first_duplicate(root.type_aliases.iter().map(|x| x.name)) {
-    return Err(TwAsmError::DuplicateTypeAlias(x.into()).into());
-  }
   // XXX: Here we don't allow recursive type aliases - should this be changed?
   for alias in &root.type_aliases {
-    let vmtype = builder.generate_vmtype(&alias.ty)?;
-    builder.type_aliases.insert(alias.name, vmtype);
+    let alias_span = span(alias.location_start, alias.location_end);
+    if builder.type_aliases.contains_key(alias.name) {
+      builder.report(
+        alias_span,
+        TwAsmError::DuplicateTypeAlias(alias.name.into()).into(),
+      );
+      continue;
+    }
+    match builder.generate_vmtype(&alias.ty) {
+      Ok(vmtype) => {
+        builder.type_aliases.insert(alias.name, vmtype);
+      }
+      Err(e) => builder.report(alias_span, e),
+    }
   }
 
+  let mut graph_spans = vec![];
   for g in &root.graphs {
-    if let Some(x) = first_duplicate(g.params.iter().map(|x| x.0)) {
-      return Err(TwAsmError::DuplicateParam(x.into()).into());
+    let graph_span = span(g.location_start, g.location_end);
+    let mut param_names = HashSet::new();
+    let mut param_types = vec![];
+    for (name, ty) in &g.params {
+      if !param_names.insert(*name) {
+        builder.report(
+          graph_span,
+          TwAsmError::DuplicateParam(name.to_string()).into(),
+        );
+      }
+      let ty = match ty.as_ref().map(|x| builder.generate_vmtype(x)) {
+        Some(Ok(x)) => x,
+        Some(Err(e)) => {
+          builder.report(graph_span, e);
+          VmType::Unknown
+        }
+        None => VmType::Unknown,
+      };
+      param_types.push(builder.alloc_vmtype(ty));
+    }
+    let mut param_constraints = vec![];
+    for x in &g.param_annotations {
+      match param_constraint(x) {
+        Ok(c) => param_constraints.push((x.param_index as u32, c)),
+        Err(e) => builder.report(graph_span, e),
+      }
     }
+    let output_type = match g.return_type.as_ref().map(|x| builder.generate_vmtype(x)) {
+      Some(Ok(x)) => Some(builder.alloc_vmtype(x)),
+      Some(Err(e)) => {
+        builder.report(graph_span, e);
+        None
+      }
+      None => None,
+    };
     let target = TwGraph {
       name: g.name.to_string(),
       exported: g.exported,
       nodes: vec![],
       output: None,
-      param_types: g
-        .params
-        .iter()
-        .map(|(_, ty)| {
-          ty.as_ref()
-            .map(|x| builder.generate_vmtype(x))
-            .unwrap_or_else(|| Ok(VmType::Unknown))
-            .map(|x| builder.alloc_vmtype(x))
-        })
-        .collect::<Result<_>>()?,
-      output_type: g
-        .return_type
-        .as_ref()
-        .map(|x| builder.generate_vmtype(x))
-        .transpose()?
-        .map(|x| builder.alloc_vmtype(x)),
+      named_outputs: vec![],
+      param_constraints,
+      param_types,
+      output_type,
     };
     let output;
+    let spans;
     {
       let mut ctx = GraphContext {
         names: HashMap::new(),
         builder: &mut builder,
         target,
         condition_stack: vec![],
+        spans: vec![],
+        current_span: None,
       };
       for (i, (p, _)) in g.params.iter().enumerate() {
-        ctx.push_node((TwGraphNode::LoadParam(i as u32), vec![], None), Some(*p))?;
+        if !ctx.names.contains_key(p) {
+          ctx.push_node((TwGraphNode::LoadParam(i as u32), vec![], None), Some(*p))?;
+        }
       }
       for stmt in &g.stmts {
-        ctx.generate_stmt(g, stmt)?;
+        ctx.generate_stmt_or_report(g, stmt);
+      }
+
+      // The output type is inferred by typeck if not annotated.
+      let has_output = ctx.target.output.is_some() || !ctx.target.named_outputs.is_empty();
+      if has_output && g.return_type.is_none() {
+        ctx.target.output_type = Some(ctx.builder.alloc_vmtype(VmType::Unknown));
       }
       output = ctx.target;
+      spans = ctx.spans;
     }
     builder.script.graphs.push(output);
+    graph_spans.push(spans);
+  }
+
+  if !builder.errors.is_empty() {
+    let mut errors = std::mem::replace(&mut builder.errors, vec![]);
+    errors.sort_by_key(|x| x.0.start);
+    return Err(
+      TwAsmErrors(
+        errors
+          .into_iter()
+          .map(|(span, error)| diagnostic(input, span, error))
+          .collect(),
+      )
+      .into(),
+    );
   }
+
   builder.emit_pools();
+  let mut source_map = generate_source_map(input, graph_spans);
+  source_map.param_names = root
+    .graphs
+    .iter()
+    .map(|g| g.params.iter().map(|(name, _)| name.to_string()).collect())
+    .collect();
+  builder.script.source_map = Some(source_map);
   Ok(builder.script)
 }
 
@@ -97,6 +178,9 @@ struct Builder<'a> {
   const_pool: HashMap<VmConst, u32>,
   type_aliases: HashMap<&'a str, VmType<String>>,
   root: &'a ast::Root<'a>,
+
+  /// Errors found so far, with the spans they are attributed to.
+  errors: Vec<(SourceSpan, anyhow::Error)>,
 }
 
 struct GraphContext<'a, 'b> {
@@ -104,18 +188,62 @@ struct GraphContext<'a, 'b> {
   builder: &'b mut Builder<'a>,
   target: TwGraph,
   condition_stack: Vec<u32>,
+
+  /// The source span of each node in `target`.
+  spans: Vec<Option<SourceSpan>>,
+
+  /// The span of the innermost expression or statement being generated.
+  current_span: Option<SourceSpan>,
 }
 
 impl<'a, 'b> GraphContext<'a, 'b> {
+  /// Generates a statement, reporting errors instead of returning them so that the following
+  /// statements are still checked.
+  fn generate_stmt_or_report(&mut self, g: &ast::Graph<'a>, stmt: &ast::Stmt<'a>) {
+    let stmt_span = span(stmt.location, stmt.location_end);
+    let prev_span = self.current_span.replace(stmt_span);
+    if let Err(e) = self.generate_stmt(g, stmt) {
+      self.builder.report(stmt_span, e);
+
+      // Define the name of a failed node anyway, so that its uses are not reported as well.
+      if let ast::StmtKind::Node {
+        name: Some(name), ..
+      } = &stmt.kind
+      {
+        if !self.names.contains_key(name) {
+          self
+            .push_node((TwGraphNode::Nop, vec![], None), Some(*name))
+            .unwrap();
+        }
+      }
+    }
+    self.current_span = prev_span;
+  }
+
   fn generate_stmt(&mut self, g: &ast::Graph<'a>, stmt: &ast::Stmt<'a>) -> Result<()> {
     match &stmt.kind {
       ast::StmtKind::Return { value } => {
         let node = self.generate_expr(g, None, value)?;
-        if self.target.output.is_some() {
+        if self.target.output.is_some() || !self.target.named_outputs.is_empty() {
           return Err(TwAsmError::DuplicateReturn.into());
         }
         self.target.output = Some(node);
       }
+      ast::StmtKind::ReturnNamed { outputs } => {
+        if self.target.output.is_some() || !self.target.named_outputs.is_empty() {
+          return Err(TwAsmError::DuplicateReturn.into());
+        }
+        let mut named_outputs = vec![];
+        for (name, value) in outputs {
+          let ident = self.builder.alloc_ident(*name);
+          if named_outputs.iter().any(|(x, _)| *x == ident) {
+            return Err(TwAsmError::DuplicateOutputName(name.to_string()).into());
+          }
+          let node = self.generate_expr(g, None, value)?;
+          named_outputs.push((ident, node));
+        }
+        self.target.named_outputs = named_outputs;
+      }
       ast::StmtKind::If {
         precondition,
         if_body,
@@ -125,7 +253,7 @@ impl<'a, 'b> GraphContext<'a, 'b> {
         let condition_true = self.generate_condition(precondition)?;
         self.condition_stack.push(condition_true);
         for stmt in if_body {
-          self.generate_stmt(g, stmt)?;
+          self.generate_stmt_or_report(g, stmt);
         }
         self.condition_stack.pop().unwrap();
 
@@ -134,7 +262,7 @@ impl<'a, 'b> GraphContext<'a, 'b> {
           let condition_false = self.generate_condition(precondition)?;
           self.condition_stack.push(condition_false);
           for stmt in else_body {
-            self.generate_stmt(g, stmt)?;
+            self.generate_stmt_or_report(g, stmt);
           }
           self.condition_stack.pop().unwrap();
         }
@@ -142,6 +270,18 @@ impl<'a, 'b> GraphContext<'a, 'b> {
       ast::StmtKind::Node { name, value } => {
         self.generate_expr(g, *name, value)?;
       }
+      ast::StmtKind::Assert { value, message } => {
+        let x = self.generate_expr(g, None, value)?;
+        let message = self.builder.alloc_ident(*message);
+        self.push_node(
+          (
+            TwGraphNode::Assert(message),
+            vec![x],
+            self.condition_stack.last().copied(),
+          ),
+          None,
+        )?;
+      }
       ast::StmtKind::Throw { value } => {
         let x = self.generate_expr(g, None, value)?;
         self.push_node(
@@ -171,6 +311,19 @@ impl<'a, 'b> GraphContext<'a, 'b> {
     g: &ast::Graph<'a>,
     name: Option<&'a str>,
     expr: &ast::Expr<'a>,
+  ) -> Result<u32> {
+    let expr_span = span(expr.location_start, expr.location_end);
+    let prev_span = self.current_span.replace(expr_span);
+    let result = self.generate_expr_kind(g, name, expr);
+    self.current_span = prev_span;
+    result.map_err(|e| spanned(expr_span, e))
+  }
+
+  fn generate_expr_kind(
+    &mut self,
+    g: &ast::Graph<'a>,
+    name: Option<&'a str>,
+    expr: &ast::Expr<'a>,
   ) -> Result<u32> {
     use ast::ExprKind as K;
     let precondition = self.condition_stack.last().copied();
@@ -181,12 +334,23 @@ impl<'a, 'b> GraphContext<'a, 'b> {
         let r = self.generate_expr(g, None, r)?;
         self.push_node((TwGraphNode::And, vec![l, r], precondition), name)?
       }
-      K::BuildTable(ty, map) => {
+      K::BuildTable(ty, strict, map) => {
         let ty = self
           .builder
           .alloc_ident_external(&format_type_for_table(ty)?);
         let map = self.generate_expr(g, None, *map)?;
-        self.push_node((TwGraphNode::BuildTable(ty), vec![map], precondition), name)?
+        self.push_node(
+          (
+            if *strict {
+              TwGraphNode::BuildTableStrict(ty)
+            } else {
+              TwGraphNode::BuildTable(ty)
+            },
+            vec![map],
+            precondition,
+          ),
+          name,
+        )?
       }
       K::CreateMap => self.push_node((TwGraphNode::CreateMap, vec![], precondition), name)?,
       K::DeleteFromMap(field, map) => {
@@ -339,6 +503,33 @@ impl<'a, 'b> GraphContext<'a, 'b> {
           .collect::<Result<Vec<_>>>()?;
         self.push_node((TwGraphNode::Call(i as u32), params, precondition), name)?
       }
+      K::TryCall(target_graph, params) => {
+        let (i, _) = self
+          .builder
+          .root
+          .graphs
+          .iter()
+          .enumerate()
+          .find(|(_, x)| x.name == *target_graph)
+          .ok_or_else(|| TwAsmError::GraphNotFound(target_graph.to_string()))?;
+        let params = params
+          .iter()
+          .map(|x| self.generate_expr(g, None, x))
+          .collect::<Result<Vec<_>>>()?;
+        self.push_node((TwGraphNode::TryCall(i as u32), params, precondition), name)?
+      }
+      K::IsError(x) => {
+        let x = self.generate_expr(g, None, *x)?;
+        self.push_node((TwGraphNode::IsError, vec![x], precondition), name)?
+      }
+      K::UnwrapValue(x) => {
+        let x = self.generate_expr(g, None, *x)?;
+        self.push_node((TwGraphNode::UnwrapValue, vec![x], precondition), name)?
+      }
+      K::ErrorMessage(x) => {
+        let x = self.generate_expr(g, None, *x)?;
+        self.push_node((TwGraphNode::ErrorMessage, vec![x], precondition), name)?
+      }
       K::Add(l, r) => {
         let l = self.generate_expr(g, None, *l)?;
         let r = self.generate_expr(g, None, *r)?;
@@ -355,7 +546,7 @@ impl<'a, 'b> GraphContext<'a, 'b> {
         let ty = self.builder.alloc_vmtype(ty);
         self.push_node((TwGraphNode::CreateList(ty), vec![], precondition), name)?
       }
-      K::Reduce(target_graph, subgraph_param, reduce_init, list_or_set) => {
+      K::Reduce(target_graph, reverse, subgraph_param, reduce_init, list_or_set) => {
         let (i, _) = self
           .builder
           .root
@@ -370,12 +561,17 @@ impl<'a, 'b> GraphContext<'a, 'b> {
           self.generate_expr(g, None, *list_or_set)?,
         ];
         self.push_node(
-          (TwGraphNode::Reduce(i as u32, false), params, precondition),
+          (
+            TwGraphNode::reduce(i as u32, false, *reverse),
+            params,
+            precondition,
+          ),
           name,
         )?
       }
       K::RangeReduce(
         target_graph,
+        reverse,
         range_start,
         range_end,
         subgraph_param,
@@ -398,7 +594,136 @@ impl<'a, 'b> GraphContext<'a, 'b> {
           self.generate_expr(g, None, *range_end)?,
         ];
         self.push_node(
-          (TwGraphNode::Reduce(i as u32, true), params, precondition),
+          (
+            TwGraphNode::reduce(i as u32, true, *reverse),
+            params,
+            precondition,
+          ),
+          name,
+        )?
+      }
+      K::SortedReduce(target_graph, range, subgraph_param, reduce_init, set) => {
+        let (i, _) = self
+          .builder
+          .root
+          .graphs
+          .iter()
+          .enumerate()
+          .find(|(_, x)| x.name == *target_graph)
+          .ok_or_else(|| TwAsmError::GraphNotFound(target_graph.to_string()))?;
+        let mut params = vec![
+          self.generate_expr(g, None, *subgraph_param)?,
+          self.generate_expr(g, None, *reduce_init)?,
+          self.generate_expr(g, None, *set)?,
+        ];
+        if let Some((range_start, range_end)) = range {
+          params.push(self.generate_expr(g, None, *range_start)?);
+          params.push(self.generate_expr(g, None, *range_end)?);
+        }
+        self.push_node(
+          (
+            TwGraphNode::ReduceBySortKey(i as u32, range.is_some()),
+            params,
+            precondition,
+          ),
+          name,
+        )?
+      }
+      K::PrefixReduce(target_graph, by_sort_key, prefix, subgraph_param, reduce_init, set) => {
+        let (i, _) = self
+          .builder
+          .root
+          .graphs
+          .iter()
+          .enumerate()
+          .find(|(_, x)| x.name == *target_graph)
+          .ok_or_else(|| TwAsmError::GraphNotFound(target_graph.to_string()))?;
+        let params = vec![
+          self.generate_expr(g, None, *subgraph_param)?,
+          self.generate_expr(g, None, *reduce_init)?,
+          self.generate_expr(g, None, *set)?,
+          self.generate_expr(g, None, *prefix)?,
+        ];
+        self.push_node(
+          (
+            TwGraphNode::ReduceByPrefix(i as u32, *by_sort_key),
+            params,
+            precondition,
+          ),
+          name,
+        )?
+      }
+      K::LoopUntil(target_graph, max_iters, subgraph_param, loop_init) => {
+        let (i, _) = self
+          .builder
+          .root
+          .graphs
+          .iter()
+          .enumerate()
+          .find(|(_, x)| x.name == *target_graph)
+          .ok_or_else(|| TwAsmError::GraphNotFound(target_graph.to_string()))?;
+        let params = vec![
+          self.generate_expr(g, None, *subgraph_param)?,
+          self.generate_expr(g, None, *loop_init)?,
+        ];
+        self.push_node(
+          (
+            TwGraphNode::LoopUntil(i as u32, *max_iters),
+            params,
+            precondition,
+          ),
+          name,
+        )?
+      }
+      K::ReduceMap(target_graph, subgraph_param, reduce_init, map) => {
+        let (i, _) = self
+          .builder
+          .root
+          .graphs
+          .iter()
+          .enumerate()
+          .find(|(_, x)| x.name == *target_graph)
+          .ok_or_else(|| TwAsmError::GraphNotFound(target_graph.to_string()))?;
+        let params = vec![
+          self.generate_expr(g, None, *subgraph_param)?,
+          self.generate_expr(g, None, *reduce_init)?,
+          self.generate_expr(g, None, *map)?,
+        ];
+        self.push_node(
+          (TwGraphNode::ReduceMap(i as u32), params, precondition),
+          name,
+        )?
+      }
+      K::MapKeys(x) => {
+        let x = self.generate_expr(g, None, *x)?;
+        self.push_node((TwGraphNode::MapKeys, vec![x], precondition), name)?
+      }
+      K::MapValues(x) => {
+        let x = self.generate_expr(g, None, *x)?;
+        self.push_node((TwGraphNode::MapValues, vec![x], precondition), name)?
+      }
+      K::Merge(l, r) => {
+        let l = self.generate_expr(g, None, *l)?;
+        let r = self.generate_expr(g, None, *r)?;
+        self.push_node((TwGraphNode::Merge, vec![l, r], precondition), name)?
+      }
+      K::ApplyPatch(table, patch) => {
+        let table = self.generate_expr(g, None, *table)?;
+        let patch = self.generate_expr(g, None, *patch)?;
+        self.push_node(
+          (TwGraphNode::ApplyPatch, vec![patch, table], precondition),
+          name,
+        )?
+      }
+      K::CollectPage(page_size, cursor, set) => {
+        let cursor = self.generate_expr(g, None, *cursor)?;
+        let set = self.generate_expr(g, None, *set)?;
+        self.push_node(
+          (
+            TwGraphNode::CollectPage(*page_size),
+            vec![cursor, set],
+            precondition,
+          ),
           name,
         )?
       }
@@ -415,6 +740,95 @@ impl<'a, 'b> GraphContext<'a, 'b> {
         let x = self.generate_expr(g, None, *x)?;
         self.push_node((TwGraphNode::ListHead, vec![x], precondition), name)?
       }
+      K::ListPush(list, v) => {
+        let list = self.generate_expr(g, None, *list)?;
+        let v = self.generate_expr(g, None, *v)?;
+        self.push_node((TwGraphNode::ListPush, vec![v, list], precondition), name)?
+      }
+      K::ListPopBack(x) => {
+        let x = self.generate_expr(g, None, *x)?;
+        self.push_node((TwGraphNode::ListPopBack, vec![x], precondition), name)?
+      }
+      K::GetListElement(list, index) => {
+        let list = self.generate_expr(g, None, *list)?;
+        let index = self.generate_expr(g, None, *index)?;
+        self.push_node(
+          (TwGraphNode::GetListElement, vec![index, list], precondition),
+          name,
+        )?
+      }
+      K::GetMapEntry(map, key) => {
+        let map = self.generate_expr(g, None, *map)?;
+        let key = self.generate_expr(g, None, *key)?;
+        self.push_node(
+          (TwGraphNode::GetMapEntry, vec![key, map], precondition),
+          name,
+        )?
+      }
+      K::PutMapEntry(map, key, v) => {
+        let map = self.generate_expr(g, None, *map)?;
+        let key = self.generate_expr(g, None, *key)?;
+        let v = self.generate_expr(g, None, *v)?;
+        self.push_node(
+          (TwGraphNode::PutMapEntry, vec![key, v, map], precondition),
+          name,
+        )?
+      }
+      K::DeleteMapEntry(map, key) => {
+        let map = self.generate_expr(g, None, *map)?;
+        let key = self.generate_expr(g, None, *key)?;
+        self.push_node(
+          (TwGraphNode::DeleteMapEntry, vec![key, map], precondition),
+          name,
+        )?
+      }
+      K::IncludeDeleted(set) => {
+        let set = self.generate_expr(g, None, *set)?;
+        self.push_node((TwGraphNode::IncludeDeleted, vec![set], precondition), name)?
+      }
+      K::PurgeDeleted(set, cutoff) => {
+        let set = self.generate_expr(g, None, *set)?;
+        let cutoff = self.generate_expr(g, None, *cutoff)?;
+        self.push_node(
+          (TwGraphNode::PurgeDeleted, vec![cutoff, set], precondition),
+          name,
+        )?
+      }
+      K::BlobLength(field, table) => {
+        let field = self.builder.alloc_ident(*field);
+        let table = self.generate_expr(g, None, *table)?;
+        self.push_node(
+          (TwGraphNode::BlobLength(field), vec![table], precondition),
+          name,
+        )?
+      }
+      K::BlobRead(field, table, offset, len) => {
+        let field = self.builder.alloc_ident(*field);
+        let table = self.generate_expr(g, None, *table)?;
+        let offset = self.generate_expr(g, None, *offset)?;
+        let len = self.generate_expr(g, None, *len)?;
+        self.push_node(
+          (
+            TwGraphNode::BlobRead(field),
+            vec![offset, len, table],
+            precondition,
+          ),
+          name,
+        )?
+      }
+      K::BlobAppend(field, table, data) => {
+        let field = self.builder.alloc_ident(*field);
+        let table = self.generate_expr(g, None, *table)?;
+        let data = self.generate_expr(g, None, *data)?;
+        self.push_node(
+          (
+            TwGraphNode::BlobAppend(field),
+            vec![data, table],
+            precondition,
+          ),
+          name,
+        )?
+      }
       K::BuildSet(x) => {
         let x = self.generate_expr(g, None, *x)?;
         self.push_node((TwGraphNode::BuildSet, vec![x], precondition), name)?
@@ -430,6 +844,7 @@ impl<'a, 'b> GraphContext<'a, 'b> {
   ) -> Result<u32> {
     let index = self.target.nodes.len() as u32;
     self.target.nodes.push(node);
+    self.spans.push(self.current_span);
     if let Some(name) = name {
       if self.names.contains_key(name) {
         return Err(TwAsmError::DuplicateNodeName(name.into()).into());
@@ -448,6 +863,13 @@ impl<'a, 'b> GraphContext<'a, 'b> {
 }
 
 impl<'a> Builder<'a> {
+  fn report(&mut self, span: SourceSpan, error: anyhow::Error) {
+    match error.downcast::<Spanned>() {
+      Ok(x) => self.errors.push((x.span, x.error)),
+      Err(error) => self.errors.push((span, error)),
+    }
+  }
+
   fn alloc_vmtype(&mut self, ty: VmType<String>) -> u32 {
     if let Some(x) = self.vmtype_pool.get(&ty) {
       *x
@@ -531,9 +953,19 @@ impl<'a> Builder<'a> {
       ),
       ast::Type::Bool => VmType::Bool,
       ast::Type::Schema => VmType::Schema,
+      ast::Type::AuthContext => VmType::AuthContext,
+      ast::Type::Error => VmType::Error,
+      ast::Type::OneOf(x) => VmType::OneOf(
+        x.iter()
+          .map(|x| self.generate_vmtype(x))
+          .collect::<Result<_>>()?,
+      ),
       ast::Type::List(x) => VmType::List(VmListType {
         ty: Box::new(self.generate_vmtype(*x)?),
       }),
+      ast::Type::Dict(x) => VmType::Dict(VmDictType {
+        ty: Box::new(self.generate_vmtype(*x)?),
+      }),
     })
   }
 
@@ -565,6 +997,85 @@ fn parse<'a, 'b: 'a>(alloc: &'a Bump, input: &'b str) -> Result<ast::Root<'a>> {
   Ok(root)
 }
 
+/// An error attributed to a span of the source during code generation.
+#[derive(Error, Debug)]
+#[error("{error}")]
+struct Spanned {
+  span: SourceSpan,
+  error: anyhow::Error,
+}
+
+/// Attributes an error to `span`, unless it is already attributed to a more specific span.
+fn spanned(span: SourceSpan, error: anyhow::Error) -> anyhow::Error {
+  if error.is::<Spanned>() {
+    error
+  } else {
+    Spanned { span, error }.into()
+  }
+}
+
+fn generate_source_map(input: &str, graph_spans: Vec<Vec<Option<SourceSpan>>>) -> TwSourceMap {
+  let line_starts = std::iter::once(0)
+    .chain(input.match_indices('\n').map(|(i, _)| i + 1))
+    .collect::<Vec<_>>();
+  let position = |span: SourceSpan| {
+    let line = match line_starts.binary_search(&span.start) {
+      Ok(x) => x,
+      Err(x) => x - 1,
+    };
+    TwSourcePosition {
+      span,
+      line: line as u32 + 1,
+      column: input[line_starts[line]..span.start].chars().count() as u32 + 1,
+    }
+  };
+  TwSourceMap {
+    graphs: graph_spans
+      .into_iter()
+      .map(|x| x.into_iter().map(|x| x.map(position)).collect())
+      .collect(),
+    param_names: vec![],
+  }
+}
+
+fn param_constraint(x: &ast::ParamAnnotation) -> Result<TwParamConstraint> {
+  use ast::Literal as L;
+  Ok(match (x.name, &x.arg) {
+    ("min_length", L::Integer(n)) if *n >= 0 => TwParamConstraint::MinLength(*n as u64),
+    ("max_length", L::Integer(n)) if *n >= 0 => TwParamConstraint::MaxLength(*n as u64),
+    ("min", L::Integer(n)) => TwParamConstraint::Min(*n),
+    ("max", L::Integer(n)) => TwParamConstraint::Max(*n),
+    ("pattern", L::String(s)) => TwParamConstraint::Pattern(s.to_string()),
+    _ => return Err(TwAsmError::InvalidParamAnnotation(x.name.to_string()).into()),
+  })
+}
+
+fn span(start: usize, end: usize) -> SourceSpan {
+  SourceSpan { start, end }
+}
+
+fn diagnostic(input: &str, span: SourceSpan, error: anyhow::Error) -> TwAsmDiagnostic {
+  let (line, column) = span.line_column(input);
+  TwAsmDiagnostic {
+    span,
+    line,
+    column,
+    error,
+  }
+}
+
+fn parse_error_span(error: &anyhow::Error) -> Option<SourceSpan> {
+  match error.downcast_ref::<ParseError<usize, String, TwAsmError>>()? {
+    ParseError::InvalidToken { location } | ParseError::UnrecognizedEOF { location, .. } => {
+      Some(span(*location, *location))
+    }
+    ParseError::UnrecognizedToken { token, .. } | ParseError::ExtraToken { token } => {
+      Some(span(token.0, token.2))
+    }
+    ParseError::User { .. } => None,
+  }
+}
+
 fn format_type_for_table(ty: &ast::Type) -> Result<String> {
   Ok(match ty {
     ast::Type::Primitive(x) => match x {
@@ -574,6 +1085,8 @@ fn format_type_for_table(ty: &ast::Type) -> Result<String> {
       PrimitiveType::Double => "double".into(),
     },
     ast::Type::Set(x) => format!("set<{}>", format_type_for_table(x)?),
+    ast::Type::List(x) => format!("list<{}>", format_type_for_table(x)?),
+    ast::Type::Dict(x) => format!("map<string, {}>", format_type_for_table(x)?),
     ast::Type::Table { name, params } => format!(
       "{}<{}>",
       name,
@@ -9,6 +9,9 @@ mod asm_test;
 
 lalrpop_mod!(language, "/data/treewalker/asm/language.rs");
 
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -28,6 +31,12 @@ pub enum TwAsmError {
   #[error("duplicate return")]
   DuplicateReturn,
 
+  #[error("duplicate output name: `{0}`")]
+  DuplicateOutputName(String),
+
+  #[error("invalid param annotation: `@{0}`")]
+  InvalidParamAnnotation(String),
+
   #[error("param not found: {0}")]
   ParamNotFound(String),
 
@@ -45,4 +54,60 @@ pub enum TwAsmError {
 
   #[error("graph not found: {0}")]
   GraphNotFound(String),
+
+  #[error("invalid scan order: {0}")]
+  InvalidScanOrder(String),
+
+  #[error("invalid build_table option: {0}")]
+  InvalidBuildTableOption(String),
+}
+
+/// A range of the assembly source, in bytes.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SourceSpan {
+  pub start: usize,
+  pub end: usize,
+}
+
+impl SourceSpan {
+  /// Returns the one-based line and column of the start of this span in `source`.
+  pub fn line_column(&self, source: &str) -> (usize, usize) {
+    let mut start = self.start.min(source.len());
+    while !source.is_char_boundary(start) {
+      start -= 1;
+    }
+    let line_start = source[..start].rfind('\n').map(|x| x + 1).unwrap_or(0);
+    (
+      source[..line_start].matches('\n').count() + 1,
+      source[line_start..start].chars().count() + 1,
+    )
+  }
+}
+
+/// An assembler error located in the source.
+#[derive(Error, Debug)]
+#[error("{line}:{column}: {error}")]
+pub struct TwAsmDiagnostic {
+  pub span: SourceSpan,
+  pub line: usize,
+  pub column: usize,
+  pub error: anyhow::Error,
+}
+
+/// All errors found while assembling a script.
+#[derive(Debug)]
+pub struct TwAsmErrors(pub Vec<TwAsmDiagnostic>);
+
+impl std::error::Error for TwAsmErrors {}
+
+impl Display for TwAsmErrors {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    for (i, x) in self.0.iter().enumerate() {
+      if i != 0 {
+        writeln!(f)?;
+      }
+      write!(f, "{}", x)?;
+    }
+    Ok(())
+  }
 }
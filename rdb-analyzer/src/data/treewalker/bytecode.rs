@@ -1,7 +1,36 @@
+use std::fmt::Display;
+
+use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use smallvec::{smallvec, SmallVec};
+use thiserror::Error;
+
+use super::{
+  asm::SourceSpan,
+  vm_value::{VmConst, VmType},
+};
+
+/// Magic bytes at the start of an encoded script.
+pub const SCRIPT_ENCODING_MAGIC: &[u8; 4] = b"TWSC";
+
+/// Current version of the script encoding. Bump this on incompatible changes, and keep decoding
+/// older versions.
+pub const SCRIPT_ENCODING_VERSION: u16 = 1;
+
+/// Max size of an encoded script, including the header.
+pub const MAX_ENCODED_SCRIPT_SIZE: usize = 16 * 1024 * 1024;
 
-use super::vm_value::{VmConst, VmType};
+#[derive(Error, Debug)]
+pub enum ScriptEncodingError {
+  #[error("bad magic")]
+  BadMagic,
+
+  #[error("unsupported encoding version: {0}")]
+  UnsupportedVersion(u16),
+
+  #[error("encoded script too large: {0} bytes")]
+  TooLarge(usize),
+}
 
 #[derive(Serialize, Deserialize, Default, Debug)]
 pub struct TwScript {
@@ -10,6 +39,125 @@ pub struct TwScript {
   pub consts: Vec<VmConst>,
   pub idents: Vec<String>,
   pub types: Vec<VmType<String>>,
+
+  /// Positions of the nodes in the assembly source, if the script was assembled from source.
+  #[serde(default)]
+  pub source_map: Option<TwSourceMap>,
+}
+
+/// Maps the nodes of a script to the assembly source that generated them.
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct TwSourceMap {
+  /// Per graph, the source position of each node.
+  pub graphs: Vec<Vec<Option<TwSourcePosition>>>,
+
+  /// Per graph, the names of the params.
+  #[serde(default)]
+  pub param_names: Vec<Vec<String>>,
+}
+
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct TwSourcePosition {
+  pub span: SourceSpan,
+
+  /// One-based line number.
+  pub line: u32,
+
+  /// One-based column number, in characters.
+  pub column: u32,
+}
+
+impl Display for TwSourcePosition {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}:{}", self.line, self.column)
+  }
+}
+
+impl TwScript {
+  /// Returns the position in the assembly source of a node, if known.
+  pub fn node_position(&self, graph_index: usize, node_index: u32) -> Option<TwSourcePosition> {
+    *self
+      .source_map
+      .as_ref()?
+      .graphs
+      .get(graph_index)?
+      .get(node_index as usize)?
+  }
+
+  /// A listing of the graphs of this script and their nodes, as printed by `rdbctl explain`.
+  pub fn explain(&self) -> String {
+    let mut out = String::new();
+    for (i, g) in self.graphs.iter().enumerate() {
+      out.push_str(&format!(
+        "{}graph {} ({}):\n",
+        if g.exported { "export " } else { "" },
+        g.name,
+        i
+      ));
+      let param_types = g
+        .param_types
+        .iter()
+        .map(|x| format!("{:?}", self.types[*x as usize]))
+        .collect::<Vec<_>>();
+      out.push_str(&format!("  params: [{}]\n", param_types.join(", ")));
+      for (i, x) in &g.param_constraints {
+        out.push_str(&format!("  param {}: {}\n", i, x));
+      }
+      for (j, (node, in_edges, precondition)) in g.nodes.iter().enumerate() {
+        out.push_str(&format!("  {:>4}: {:?} <- {:?}", j, node, in_edges));
+        if let Some(x) = precondition {
+          out.push_str(&format!(" if {}", x));
+        }
+        out.push('\n');
+      }
+      if let Some(x) = g.output {
+        out.push_str(&format!("  output: {}\n", x));
+      }
+      for (name, x) in &g.named_outputs {
+        out.push_str(&format!(
+          "  output {}: {}\n",
+          self
+            .idents
+            .get(*name as usize)
+            .map(|x| x.as_str())
+            .unwrap_or("?"),
+          x
+        ));
+      }
+    }
+    out
+  }
+
+  /// Encodes this script into the versioned binary format:
+  ///
+  /// `magic (4 bytes) | version (u16, big endian) | msgpack payload with named fields`
+  pub fn encode(&self) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(4096);
+    out.extend_from_slice(SCRIPT_ENCODING_MAGIC);
+    out.extend_from_slice(&SCRIPT_ENCODING_VERSION.to_be_bytes());
+    rmp_serde::encode::write_named(&mut out, self)?;
+    if out.len() > MAX_ENCODED_SCRIPT_SIZE {
+      return Err(ScriptEncodingError::TooLarge(out.len()).into());
+    }
+    Ok(out)
+  }
+
+  pub fn decode(data: &[u8]) -> Result<Self> {
+    if data.len() > MAX_ENCODED_SCRIPT_SIZE {
+      return Err(ScriptEncodingError::TooLarge(data.len()).into());
+    }
+    let data = data
+      .strip_prefix(&SCRIPT_ENCODING_MAGIC[..])
+      .ok_or_else(|| ScriptEncodingError::BadMagic)?;
+    if data.len() < 2 {
+      return Err(ScriptEncodingError::BadMagic.into());
+    }
+    let version = u16::from_be_bytes([data[0], data[1]]);
+    match version {
+      1 => Ok(rmp_serde::from_slice(&data[2..])?),
+      _ => Err(ScriptEncodingError::UnsupportedVersion(version).into()),
+    }
+  }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -23,11 +171,26 @@ pub struct TwGraph {
   /// Topologically sorted nodes.
   ///
   /// (node, in_edges, precondition)
+  ///
+  /// A node whose precondition evaluates to false is skipped. The skip propagates to its dependents,
+  /// except `Select` nodes which are only skipped if all of their candidates are skipped. A skipped
+  /// output yields a null of the output type.
   pub nodes: Vec<(TwGraphNode, Vec<u32>, Option<u32>)>,
 
   /// The output value of this graph.
   pub output: Option<u32>,
 
+  /// Named output values of this graph, as (ident (name), node). If there are any, the graph has
+  /// no `output` and its output is a map from the names to the values. A skipped node yields a
+  /// null of its type.
+  #[serde(default)]
+  pub named_outputs: Vec<(u32, u32)>,
+
+  /// Checks on the params of this graph, as (param_index, constraint). They are run before any
+  /// node of the graph.
+  #[serde(default)]
+  pub param_constraints: Vec<(u32, TwParamConstraint)>,
+
   /// Param types.
   pub param_types: Vec<u32>,
 
@@ -35,6 +198,38 @@ pub struct TwGraph {
   pub output_type: Option<u32>,
 }
 
+/// A check on the value of a graph param. Null values pass all checks.
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub enum TwParamConstraint {
+  /// Min number of characters of a string, or bytes of a byte string.
+  MinLength(u64),
+
+  /// Max number of characters of a string, or bytes of a byte string.
+  MaxLength(u64),
+
+  /// Inclusive lower bound of an int64 or double.
+  Min(i64),
+
+  /// Inclusive upper bound of an int64 or double.
+  Max(i64),
+
+  /// A regular expression that must match the whole string.
+  Pattern(String),
+}
+
+impl Display for TwParamConstraint {
+  /// Formats the constraint as its annotation in the assembly.
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::MinLength(x) => write!(f, "@min_length({})", x),
+      Self::MaxLength(x) => write!(f, "@max_length({})", x),
+      Self::Min(x) => write!(f, "@min({})", x),
+      Self::Max(x) => write!(f, "@max({})", x),
+      Self::Pattern(x) => write!(f, "@pattern({})", serde_json::to_string(x).unwrap()),
+    }
+  }
+}
+
 #[derive(Copy, Clone, Serialize, Deserialize, Debug)]
 pub enum TwGraphNode {
   /// T
@@ -49,6 +244,8 @@ pub enum TwGraphNode {
 
   /// Map -> Table<T>
   ///
+  /// Map entries that are not fields of the table are dropped.
+  ///
   /// Const param: ident (table_type)
   BuildTable(u32),
 
@@ -77,9 +274,22 @@ pub enum TwGraphNode {
   ///
   /// Subgraph: (U, P, T) -> P
   ///
+  /// A `Map<string, T>` is reduced like a set keyed by strings, with `map { key: string, value: T }`
+  /// items.
+  ///
   /// Const param: (subgraph_index, has_range)
   Reduce(u32, bool),
 
+  /// U -> P -> P
+  ///
+  /// Subgraph: (U, P) -> P
+  ///
+  /// Repeatedly applies the subgraph to the accumulator, until the subgraph returns null or
+  /// `max_iters` is reached. Runs iteratively and does not count towards the recursion limit.
+  ///
+  /// Const param: (subgraph_index, max_iters)
+  LoopUntil(u32, u32),
+
   /// (Map | Table<T>) -> T
   ///
   /// Const param: ident
@@ -109,8 +319,9 @@ pub enum TwGraphNode {
   /// Const param: ident
   InsertIntoTable(u32),
 
-  /// T -> Set<T> -> ()
+  /// T -> Set<T> -> T::PrimaryKeyValue
   ///
+  /// Outputs the primary key of the inserted member, generated if the key is `@auto` and null.
   /// This is an effect node.
   InsertIntoSet,
 
@@ -177,9 +388,199 @@ pub enum TwGraphNode {
 
   /// string -> !
   Throw,
+
+  /// Bool -> ()
+  ///
+  /// Fails with the message, graph index and node index if the condition is false or null.
+  ///
+  /// Const param: ident (message)
+  Assert(u32),
+
+  /// Call subgraph, catching errors thrown by the script.
+  ///
+  /// Effects already performed by the subgraph are not rolled back.
+  ///
+  /// T* -> OneOf<R, Error>
+  ///
+  /// Const param: subgraph index
+  TryCall(u32),
+
+  /// OneOf<T, Error> -> Bool
+  IsError,
+
+  /// OneOf<T, Error> -> T
+  ///
+  /// Null if the value is an error.
+  UnwrapValue,
+
+  /// OneOf<T, Error> -> string
+  ///
+  /// Null if the value is not an error, or if `null` was thrown.
+  ErrorMessage,
+
+  /// T -> List<T> -> ()
+  ///
+  /// Appends to the end of a persisted list.
+  /// This is an effect node.
+  ListPush,
+
+  /// List<T> -> T
+  ///
+  /// Removes the last element of a persisted list and returns it. Null if the list is empty.
+  /// This is an effect node.
+  ListPopBack,
+
+  /// int64 -> List<T> -> T
+  ///
+  /// Gets the element at an index of a list. Null if the index is out of range.
+  GetListElement,
+
+  /// string -> Map<string, T> -> T
+  ///
+  /// Point-get on a persisted map.
+  GetMapEntry,
+
+  /// string -> T -> Map<string, T> -> ()
+  ///
+  /// This is an effect node.
+  PutMapEntry,
+
+  /// string -> Map<string, T> -> ()
+  ///
+  /// This is an effect node.
+  DeleteMapEntry,
+
+  /// If has_range: U -> P -> T::SortKeyValue (start_inclusive) -> T::SortKeyValue (end_exclusive) -> Set<T> -> P
+  /// Otherwise: U -> P -> Set<T> -> P
+  ///
+  /// Subgraph: (U, P, T) -> P
+  ///
+  /// Like `Reduce`, but visits the members of the set in the order of their `@sort_key` field.
+  /// Members with a null sort key come first.
+  ///
+  /// Const param: (subgraph_index, has_range)
+  ReduceBySortKey(u32, bool),
+
+  /// U -> P -> (Set<T> | Map<string, T>) -> string (prefix) -> P
+  ///
+  /// Subgraph: (U, P, T) -> P
+  ///
+  /// Like `Reduce`, but only visits the members whose primary key, or map entries whose key,
+  /// starts with the prefix. If by_sort_key, visits the members whose string `@sort_key` field
+  /// starts with the prefix instead, in the order of that field. A null prefix matches all
+  /// members.
+  ///
+  /// Const param: (subgraph_index, by_sort_key)
+  ReduceByPrefix(u32, bool),
+
+  /// Set<T> -> Set<T>
+  ///
+  /// The same set, with members deleted from a `@soft_delete` set visible to reads.
+  IncludeDeleted,
+
+  /// int64 (cutoff) -> Set<T> -> int64
+  ///
+  /// Permanently removes the members of a `@soft_delete` set deleted before the cutoff time in
+  /// milliseconds. Returns the number of removed members.
+  /// This is an effect node.
+  PurgeDeleted,
+
+  /// U -> P -> Map -> P
+  ///
+  /// Subgraph: (U, P, string, V) -> P
+  ///
+  /// Visits the entries of a map in key order, passing the key and the value of each entry.
+  /// The values of the map must have a common type `V`. Stops early if the subgraph returns null.
+  ///
+  /// Const param: subgraph_index
+  ReduceMap(u32),
+
+  /// Map -> List<string>
+  ///
+  /// The keys of a map, in order.
+  MapKeys,
+
+  /// Map -> List<V>
+  ///
+  /// The values of a map, in key order. The values must have a common type `V`.
+  MapValues,
+
+  /// (Map -> Map -> Map) | (Table<T> -> (Map | Table<T>) -> Table<T>)
+  ///
+  /// Recursively merges the right value into the left one. Where both sides have a map or table
+  /// at the same key, they are merged; otherwise the right value wins. A map merged into a table
+  /// only sets fields of the table. Only fresh tables can be merged.
+  Merge,
+
+  /// Map -> Table<T> -> ()
+  ///
+  /// Writes each field of the map to the same field of the table, like one `InsertIntoTable`
+  /// per field. A null value deletes the field. Fields not in the map are left as is.
+  /// This is an effect node.
+  ApplyPatch,
+
+  /// string (cursor) -> Set<T> -> map { items: List<T>, next_cursor: string, total_estimate: int64 }
+  ///
+  /// Collects up to `page_size` members of the set in primary key order, starting after the
+  /// cursor, or from the first member if the cursor is null. See `serialize::page_type`.
+  /// `next_cursor` is null on the last page, and `total_estimate` is null for persisted sets.
+  ///
+  /// Const param: page_size
+  CollectPage(u32),
+
+  /// Table<T> -> int64
+  ///
+  /// The length in bytes of a `@blob` field of the table, without reading its chunks.
+  ///
+  /// Const param: ident (field)
+  BlobLength(u32),
+
+  /// int64 (offset) -> int64 (length) -> Table<T> -> bytes
+  ///
+  /// Reads at most `length` bytes of a `@blob` field of the table, starting at `offset`. Only the
+  /// chunks that overlap the range are read. Empty if `offset` is past the end of the blob.
+  ///
+  /// Const param: ident (field)
+  BlobRead(u32),
+
+  /// bytes -> Table<T> -> ()
+  ///
+  /// Appends to a `@blob` field of the table, creating it if it is null. Only the last chunk of
+  /// the blob is rewritten.
+  /// This is an effect node.
+  ///
+  /// Const param: ident (field)
+  BlobAppend(u32),
+
+  /// If has_range: U -> P -> T::PrimaryKeyValue (start_inclusive) -> T::PrimaryKeyValue (end_exclusive) -> (Set<T> | Map<string, T>) -> P
+  /// Otherwise: U -> P -> (Set<T> | Map<string, T>) -> P
+  ///
+  /// Subgraph: (U, P, T) -> P
+  ///
+  /// Like `Reduce`, but visits the members of a set or map in descending key order.
+  ///
+  /// Const param: (subgraph_index, has_range)
+  ReduceReverse(u32, bool),
+
+  /// Map -> Table<T>
+  ///
+  /// Like `BuildTable`, but fails the node if the map has entries that are not fields of the
+  /// table.
+  ///
+  /// Const param: ident (table_type)
+  BuildTableStrict(u32),
 }
 
 impl TwGraphNode {
+  /// A `Reduce`, or a `ReduceReverse` if reverse.
+  pub fn reduce(subgraph_index: u32, has_range: bool, reverse: bool) -> Self {
+    if reverse {
+      Self::ReduceReverse(subgraph_index, has_range)
+    } else {
+      Self::Reduce(subgraph_index, has_range)
+    }
+  }
+
   pub fn is_select(&self) -> bool {
     match self {
       Self::Select => true,
@@ -190,11 +591,34 @@ impl TwGraphNode {
     match self {
       Self::FilterSet(x) => smallvec![*x],
       Self::Call(x) => smallvec![*x],
+      Self::TryCall(x) => smallvec![*x],
       Self::Reduce(x, _) => smallvec![*x],
+      Self::ReduceReverse(x, _) => smallvec![*x],
+      Self::ReduceBySortKey(x, _) => smallvec![*x],
+      Self::ReduceByPrefix(x, _) => smallvec![*x],
+      Self::ReduceMap(x) => smallvec![*x],
+      Self::LoopUntil(x, _) => smallvec![*x],
       _ => smallvec![],
     }
   }
 
+  /// Whether this node writes to the store. Effects of subgraphs are not included.
+  pub fn is_effect(&self) -> bool {
+    match self {
+      Self::InsertIntoTable(_)
+      | Self::InsertIntoSet
+      | Self::DeleteFromSet
+      | Self::ListPush
+      | Self::ListPopBack
+      | Self::PutMapEntry
+      | Self::DeleteMapEntry
+      | Self::PurgeDeleted
+      | Self::ApplyPatch
+      | Self::BlobAppend(_) => true,
+      _ => false,
+    }
+  }
+
   pub fn is_optional_chained(&self) -> bool {
     match self {
       TwGraphNode::IsNull
@@ -202,7 +626,14 @@ impl TwGraphNode {
       | TwGraphNode::InsertIntoMap(_)
       | TwGraphNode::DeleteFromMap(_)
       | TwGraphNode::Reduce(_, _)
-      | TwGraphNode::Throw => false,
+      | TwGraphNode::ReduceReverse(_, _)
+      | TwGraphNode::ReduceBySortKey(_, _)
+      | TwGraphNode::ReduceByPrefix(_, _)
+      | TwGraphNode::ReduceMap(_)
+      | TwGraphNode::CollectPage(_)
+      | TwGraphNode::LoopUntil(_, _)
+      | TwGraphNode::Throw
+      | TwGraphNode::Assert(_) => false,
       _ => true,
     }
   }
@@ -0,0 +1,171 @@
+use crate::data::treewalker::{
+  asm::codegen::compile_twscript,
+  bytecode::{TwGraphNode, TwScript, MAX_ENCODED_SCRIPT_SIZE},
+};
+
+/// The layout of version 1 scripts. Variants are encoded by index, so only the variants up to
+/// the last one used are mirrored.
+mod v1 {
+  use serde::Serialize;
+
+  #[derive(Serialize)]
+  pub struct TwScript {
+    pub graphs: Vec<TwGraph>,
+    pub entry: u32,
+    pub consts: Vec<()>,
+    pub idents: Vec<String>,
+    pub types: Vec<()>,
+  }
+
+  #[derive(Serialize)]
+  pub struct TwGraph {
+    pub name: String,
+    pub exported: bool,
+    pub nodes: Vec<(TwGraphNode, Vec<u32>, Option<u32>)>,
+    pub output: Option<u32>,
+    pub param_types: Vec<u32>,
+    pub output_type: Option<u32>,
+  }
+
+  #[allow(dead_code)]
+  #[derive(Serialize)]
+  pub enum TwGraphNode {
+    LoadParam(u32),
+    LoadConst(u32),
+    BuildTable(u32),
+    BuildSet,
+    CreateMap,
+    CreateList(u32),
+    PrependToList,
+    PopFromList,
+    ListHead,
+    Reduce(u32, bool),
+  }
+}
+
+#[test]
+fn encode_decode_roundtrip() {
+  let script = compile_twscript(
+    r#"
+    export graph main(root: schema): int64 {
+      if 1 == 2 {
+        throw "unreachable";
+      }
+      return call(fib) [10];
+    }
+    graph fib(x: int64): int64 {
+      if x == 1 || x == 2 {
+        v1 = 1;
+      } else {
+        v2 = call(fib) [x - 1] + call(fib) [x - 2];
+      }
+      return select v1 v2;
+    }
+    "#,
+  )
+  .unwrap();
+  let encoded = script.encode().unwrap();
+  assert_eq!(&encoded[..6], b"TWSC\x00\x01");
+  let decoded = TwScript::decode(&encoded).unwrap();
+  assert_eq!(format!("{:?}", script), format!("{:?}", decoded));
+}
+
+#[test]
+fn decode_v1() {
+  // Version 1 encoding of a script with a single ident and nothing else. Must keep decoding.
+  let mut encoded = b"TWSC\x00\x01".to_vec();
+  encoded.push(0x85);
+  encoded.extend_from_slice(b"\xa6graphs\x90");
+  encoded.extend_from_slice(b"\xa5entry\x00");
+  encoded.extend_from_slice(b"\xa6consts\x90");
+  encoded.extend_from_slice(b"\xa6idents\x91\xa1a");
+  encoded.extend_from_slice(b"\xa5types\x90");
+
+  let decoded = TwScript::decode(&encoded).unwrap();
+  assert!(decoded.graphs.is_empty());
+  assert_eq!(decoded.entry, 0);
+  assert!(decoded.consts.is_empty());
+  assert_eq!(decoded.idents, vec!["a".to_string()]);
+  assert!(decoded.types.is_empty());
+
+  // Version 1 encoding of a script with opcodes whose params changed since.
+  let script = v1::TwScript {
+    graphs: vec![
+      v1::TwGraph {
+        name: "main".into(),
+        exported: true,
+        nodes: vec![
+          (v1::TwGraphNode::CreateMap, vec![], None),
+          (v1::TwGraphNode::BuildTable(0), vec![0], None),
+          (v1::TwGraphNode::CreateList(0), vec![], None),
+          (v1::TwGraphNode::LoadParam(0), vec![], None),
+          (v1::TwGraphNode::Reduce(1, false), vec![0, 2, 3], None),
+          (v1::TwGraphNode::LoadConst(0), vec![], None),
+          (
+            v1::TwGraphNode::Reduce(1, true),
+            vec![0, 2, 3, 5, 5],
+            Some(5),
+          ),
+        ],
+        output: Some(4),
+        param_types: vec![],
+        output_type: None,
+      },
+      v1::TwGraph {
+        name: "collect".into(),
+        exported: false,
+        nodes: vec![(v1::TwGraphNode::PrependToList, vec![2, 1], None)],
+        output: Some(0),
+        param_types: vec![],
+        output_type: None,
+      },
+    ],
+    entry: 0,
+    consts: vec![],
+    idents: vec!["Item".into()],
+    types: vec![],
+  };
+  let mut encoded = b"TWSC\x00\x01".to_vec();
+  rmp_serde::encode::write_named(&mut encoded, &script).unwrap();
+
+  let decoded = TwScript::decode(&encoded).unwrap();
+  assert_eq!(decoded.graphs.len(), 2);
+  let nodes = &decoded.graphs[0].nodes;
+  assert_eq!(nodes.len(), 7);
+  assert!(matches!(nodes[1], (TwGraphNode::BuildTable(0), _, None)));
+  assert!(matches!(nodes[3], (TwGraphNode::LoadParam(0), _, None)));
+  assert!(matches!(nodes[4], (TwGraphNode::Reduce(1, false), _, None)));
+  assert!(matches!(
+    nodes[6],
+    (TwGraphNode::Reduce(1, true), _, Some(5))
+  ));
+  assert_eq!(nodes[6].1, vec![0, 2, 3, 5, 5]);
+  assert_eq!(decoded.graphs[0].output, Some(4));
+  assert!(decoded.graphs[0].named_outputs.is_empty());
+  assert!(matches!(
+    decoded.graphs[1].nodes[0],
+    (TwGraphNode::PrependToList, _, None)
+  ));
+  assert!(decoded.source_map.is_none());
+}
+
+#[test]
+fn decode_rejects_bad_input() {
+  assert_eq!(
+    TwScript::decode(b"XXXX\x00\x01\x80")
+      .unwrap_err()
+      .to_string(),
+    "bad magic"
+  );
+  assert_eq!(
+    TwScript::decode(b"TWSC\x00\x02\x80")
+      .unwrap_err()
+      .to_string(),
+    "unsupported encoding version: 2"
+  );
+  let too_large = vec![0u8; MAX_ENCODED_SCRIPT_SIZE + 1];
+  assert!(TwScript::decode(&too_large)
+    .unwrap_err()
+    .to_string()
+    .starts_with("encoded script too large"));
+}
@@ -0,0 +1,25 @@
+//! Generation of typed clients from the signatures of exported graphs.
+
+pub mod runtime;
+pub mod rust;
+pub mod typescript;
+
+#[cfg(test)]
+mod rust_test;
+
+#[cfg(test)]
+mod typescript_test;
+
+/// Converts a snake_case name to PascalCase.
+fn pascal_case(name: &str) -> String {
+  name
+    .split('_')
+    .map(|x| {
+      let mut chars = x.chars();
+      match chars.next() {
+        Some(c) => c.to_uppercase().chain(chars).collect::<String>(),
+        None => String::new(),
+      }
+    })
+    .collect()
+}
@@ -0,0 +1,214 @@
+//! Support code for generated Rust clients.
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use thiserror::Error;
+
+use crate::data::treewalker::serialize::{SerializedVmValue, TaggedVmValue, PAGE_FIELDS};
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+  #[error("expected {0}, got `{1}`")]
+  UnexpectedValue(&'static str, String),
+
+  #[error("unexpected null value")]
+  UnexpectedNull,
+}
+
+/// Sends calls of exported graphs to a deployment, e.g. over the HTTP API of rdb-server.
+#[async_trait]
+pub trait Transport: Send + Sync {
+  /// Calls an exported graph. `params` has one value per param of the graph, with nulls for the
+  /// params filled in by the server.
+  async fn call(&self, graph: &str, params: Vec<SerializedVmValue>) -> Result<SerializedVmValue>;
+}
+
+pub trait IntoVmValue {
+  fn into_vm_value(self) -> SerializedVmValue;
+}
+
+/// Decodes values in any of the encodings allowed by `VmValueEncodeConfig`.
+pub trait FromVmValue: Sized {
+  fn from_vm_value(x: SerializedVmValue) -> Result<Self>;
+}
+
+fn unexpected<T>(expected: &'static str, x: SerializedVmValue) -> Result<T> {
+  match x {
+    SerializedVmValue::Null(_) => Err(ClientError::UnexpectedNull.into()),
+    _ => Err(ClientError::UnexpectedValue(expected, format!("{:?}", x)).into()),
+  }
+}
+
+impl IntoVmValue for SerializedVmValue {
+  fn into_vm_value(self) -> SerializedVmValue {
+    self
+  }
+}
+
+impl IntoVmValue for String {
+  fn into_vm_value(self) -> SerializedVmValue {
+    SerializedVmValue::String(self)
+  }
+}
+
+impl IntoVmValue for i64 {
+  fn into_vm_value(self) -> SerializedVmValue {
+    SerializedVmValue::Int64(self)
+  }
+}
+
+impl IntoVmValue for f64 {
+  fn into_vm_value(self) -> SerializedVmValue {
+    SerializedVmValue::Double(self)
+  }
+}
+
+impl IntoVmValue for bool {
+  fn into_vm_value(self) -> SerializedVmValue {
+    SerializedVmValue::Bool(self)
+  }
+}
+
+impl IntoVmValue for Vec<u8> {
+  fn into_vm_value(self) -> SerializedVmValue {
+    SerializedVmValue::String(base64::encode(&self))
+  }
+}
+
+impl<T: IntoVmValue> IntoVmValue for Vec<T> {
+  fn into_vm_value(self) -> SerializedVmValue {
+    SerializedVmValue::Tagged(TaggedVmValue::L(
+      self.into_iter().map(|x| x.into_vm_value()).collect(),
+    ))
+  }
+}
+
+impl<T: IntoVmValue> IntoVmValue for Option<T> {
+  fn into_vm_value(self) -> SerializedVmValue {
+    match self {
+      Some(x) => x.into_vm_value(),
+      None => SerializedVmValue::Null(None),
+    }
+  }
+}
+
+impl FromVmValue for SerializedVmValue {
+  fn from_vm_value(x: SerializedVmValue) -> Result<Self> {
+    Ok(x)
+  }
+}
+
+impl FromVmValue for String {
+  fn from_vm_value(x: SerializedVmValue) -> Result<Self> {
+    match x {
+      SerializedVmValue::String(x) => Ok(x),
+      _ => unexpected("string", x),
+    }
+  }
+}
+
+impl FromVmValue for i64 {
+  fn from_vm_value(x: SerializedVmValue) -> Result<Self> {
+    match x {
+      SerializedVmValue::Int64(x) => Ok(x),
+      SerializedVmValue::String(x) => Ok(x.parse()?),
+      _ => unexpected("int64", x),
+    }
+  }
+}
+
+impl FromVmValue for f64 {
+  fn from_vm_value(x: SerializedVmValue) -> Result<Self> {
+    match x {
+      SerializedVmValue::Double(x) => Ok(x),
+      SerializedVmValue::Int64(x) => Ok(x as f64),
+      SerializedVmValue::String(x) => Ok(x.parse()?),
+      _ => unexpected("double", x),
+    }
+  }
+}
+
+impl FromVmValue for bool {
+  fn from_vm_value(x: SerializedVmValue) -> Result<Self> {
+    match x {
+      SerializedVmValue::Bool(x) => Ok(x),
+      _ => unexpected("bool", x),
+    }
+  }
+}
+
+impl FromVmValue for Vec<u8> {
+  fn from_vm_value(x: SerializedVmValue) -> Result<Self> {
+    match x {
+      SerializedVmValue::Bytes(x) => Ok(x),
+      SerializedVmValue::String(x) => Ok(base64::decode(&x)?),
+      _ => unexpected("bytes", x),
+    }
+  }
+}
+
+impl<T: FromVmValue> FromVmValue for Vec<T> {
+  /// Lists cut short by a size limit decode to their leading elements.
+  fn from_vm_value(x: SerializedVmValue) -> Result<Self> {
+    match x {
+      SerializedVmValue::Tagged(TaggedVmValue::L(x))
+      | SerializedVmValue::Tagged(TaggedVmValue::T { elements: x, .. }) => {
+        x.into_iter().map(T::from_vm_value).collect()
+      }
+      _ => unexpected("list", x),
+    }
+  }
+}
+
+impl<T: FromVmValue> FromVmValue for Option<T> {
+  fn from_vm_value(x: SerializedVmValue) -> Result<Self> {
+    match x {
+      SerializedVmValue::Null(_) => Ok(None),
+      _ => T::from_vm_value(x).map(Some),
+    }
+  }
+}
+
+/// Encodes the fields of a generated struct as a map.
+pub fn build_map(fields: Vec<(&str, SerializedVmValue)>) -> SerializedVmValue {
+  SerializedVmValue::Tagged(TaggedVmValue::M(
+    fields
+      .into_iter()
+      .map(|(k, v)| (k.to_string(), v))
+      .collect(),
+  ))
+}
+
+/// Unwraps an encoded map, to decode the fields of a generated struct with `take_field`. Pages
+/// unwrap into their fields.
+pub fn take_map(x: SerializedVmValue) -> Result<BTreeMap<String, SerializedVmValue>> {
+  match x {
+    SerializedVmValue::Tagged(TaggedVmValue::M(x)) => Ok(x),
+    SerializedVmValue::Tagged(TaggedVmValue::P(x)) => {
+      let [items, next_cursor, total_estimate] = PAGE_FIELDS;
+      let mut map = BTreeMap::new();
+      map.insert(
+        items.to_string(),
+        SerializedVmValue::Tagged(TaggedVmValue::L(x.items)),
+      );
+      map.insert(next_cursor.to_string(), x.next_cursor.into_vm_value());
+      map.insert(total_estimate.to_string(), x.total_estimate.into_vm_value());
+      Ok(map)
+    }
+    _ => unexpected("map", x),
+  }
+}
+
+/// Decodes a field of a map. Missing fields decode as null.
+pub fn take_field<T: FromVmValue>(
+  map: &mut BTreeMap<String, SerializedVmValue>,
+  name: &str,
+) -> Result<T> {
+  T::from_vm_value(
+    map
+      .remove(name)
+      .unwrap_or_else(|| SerializedVmValue::Null(None)),
+  )
+}
@@ -0,0 +1,210 @@
+use std::{collections::HashSet, fmt::Write};
+
+use crate::data::treewalker::signature::{GraphSignature, ValueShape};
+
+use super::pascal_case;
+
+/// Path of the runtime module in generated code.
+const RUNTIME: &str = "rdb_analyzer::data::treewalker::clientgen::runtime";
+
+const RUST_KEYWORDS: &[&str] = &[
+  "as", "async", "await", "break", "const", "continue", "dyn", "else", "enum", "extern", "false",
+  "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref",
+  "return", "static", "struct", "trait", "true", "type", "unsafe", "use", "where", "while",
+  "abstract", "become", "box", "do", "final", "macro", "override", "priv", "try", "typeof",
+  "unsized", "virtual", "yield",
+];
+
+/// Generates a Rust module with a client of the exported graphs of a deployment.
+///
+/// The module has a `Client` with one async method per exported graph, and a struct per map type
+/// of the params and outputs. Params filled in by the server are left out of the methods. The
+/// generated code depends on `rdb_analyzer`, `anyhow` and `async_trait`.
+pub fn generate_rust_client(signatures: &[GraphSignature]) -> String {
+  let mut gen = Generator::default();
+  let mut methods = String::new();
+  for sig in signatures {
+    gen.generate_method(sig, &mut methods);
+  }
+
+  let mut out = String::new();
+  writeln!(out, "// Generated by rdbctl. Do not edit.").unwrap();
+  writeln!(out).unwrap();
+  writeln!(out, "#![allow(dead_code, clippy::all)]").unwrap();
+  writeln!(out).unwrap();
+  writeln!(out, "use anyhow::Result;").unwrap();
+  writeln!(
+    out,
+    "use rdb_analyzer::data::treewalker::serialize::SerializedVmValue;"
+  )
+  .unwrap();
+  writeln!(
+    out,
+    "use {}::{{build_map, take_field, take_map, FromVmValue, IntoVmValue, Transport}};",
+    RUNTIME
+  )
+  .unwrap();
+  writeln!(out).unwrap();
+  out.push_str(&gen.structs);
+  writeln!(out, "pub struct Client<T: Transport> {{").unwrap();
+  writeln!(out, "    transport: T,").unwrap();
+  writeln!(out, "}}").unwrap();
+  writeln!(out).unwrap();
+  writeln!(out, "impl<T: Transport> Client<T> {{").unwrap();
+  writeln!(out, "    pub fn new(transport: T) -> Self {{").unwrap();
+  writeln!(out, "        Self {{ transport }}").unwrap();
+  writeln!(out, "    }}").unwrap();
+  out.push_str(&methods);
+  writeln!(out, "}}").unwrap();
+  out
+}
+
+#[derive(Default)]
+struct Generator {
+  structs: String,
+  struct_names: HashSet<String>,
+}
+
+impl Generator {
+  fn generate_method(&mut self, sig: &GraphSignature, out: &mut String) {
+    let mut args = vec![];
+    let mut params = vec![];
+    for (i, p) in sig.params.iter().enumerate() {
+      if p.shape == ValueShape::Implicit {
+        params.push("SerializedVmValue::Null(None)".to_string());
+        continue;
+      }
+      let name = p.name.clone().unwrap_or_else(|| format!("param{}", i));
+      let ident = rust_ident(&name);
+      let ty = self.rust_type(&p.shape, &format!("{}_{}", sig.name, name));
+      args.push(format!("{}: {}", ident, ty));
+      params.push(format!("{}.into_vm_value()", ident));
+    }
+    let output = sig
+      .output
+      .as_ref()
+      .map(|x| self.rust_type(x, &format!("{}_output", sig.name)));
+
+    writeln!(out).unwrap();
+    write!(out, "    pub async fn {}(&self", rust_ident(&sig.name)).unwrap();
+    for x in &args {
+      write!(out, ", {}", x).unwrap();
+    }
+    match &output {
+      Some(x) => writeln!(out, ") -> Result<Option<{}>> {{", x).unwrap(),
+      None => writeln!(out, ") -> Result<()> {{").unwrap(),
+    }
+    let call = format!(
+      "self.transport.call({:?}, vec![{}]).await?",
+      sig.name,
+      params.join(", ")
+    );
+    match &output {
+      Some(_) => writeln!(out, "        FromVmValue::from_vm_value({})", call).unwrap(),
+      None => {
+        writeln!(out, "        {};", call).unwrap();
+        writeln!(out, "        Ok(())").unwrap();
+      }
+    }
+    writeln!(out, "    }}").unwrap();
+  }
+
+  /// Returns the Rust type of values of `shape`, generating structs for its maps. `hint` names
+  /// the generated structs.
+  fn rust_type(&mut self, shape: &ValueShape, hint: &str) -> String {
+    match shape {
+      ValueShape::Bool => "bool".into(),
+      ValueShape::Int64 => "i64".into(),
+      ValueShape::Double => "f64".into(),
+      ValueShape::String => "String".into(),
+      ValueShape::Bytes => "Vec<u8>".into(),
+      ValueShape::List { items } => format!("Vec<{}>", self.rust_type(items, hint)),
+      ValueShape::Map { fields } => {
+        let name = self.struct_name(hint);
+        let fields = fields
+          .iter()
+          .map(|(k, v)| (k, self.rust_type(v, &format!("{}_{}", name, k))))
+          .collect::<Vec<_>>();
+
+        let mut out = String::new();
+        writeln!(out, "#[derive(Debug, Default)]").unwrap();
+        writeln!(out, "pub struct {} {{", name).unwrap();
+        for (k, ty) in &fields {
+          writeln!(out, "    pub {}: Option<{}>,", rust_ident(k), ty).unwrap();
+        }
+        writeln!(out, "}}").unwrap();
+        writeln!(out).unwrap();
+        writeln!(out, "impl IntoVmValue for {} {{", name).unwrap();
+        writeln!(out, "    fn into_vm_value(self) -> SerializedVmValue {{").unwrap();
+        writeln!(out, "        build_map(vec![").unwrap();
+        for (k, _) in &fields {
+          writeln!(
+            out,
+            "            ({:?}, self.{}.into_vm_value()),",
+            k,
+            rust_ident(k)
+          )
+          .unwrap();
+        }
+        writeln!(out, "        ])").unwrap();
+        writeln!(out, "    }}").unwrap();
+        writeln!(out, "}}").unwrap();
+        writeln!(out).unwrap();
+        writeln!(out, "impl FromVmValue for {} {{", name).unwrap();
+        writeln!(
+          out,
+          "    fn from_vm_value(x: SerializedVmValue) -> Result<Self> {{"
+        )
+        .unwrap();
+        if fields.is_empty() {
+          writeln!(out, "        take_map(x)?;").unwrap();
+          writeln!(out, "        Ok(Self {{}})").unwrap();
+        } else {
+          writeln!(out, "        let mut m = take_map(x)?;").unwrap();
+          writeln!(out, "        Ok(Self {{").unwrap();
+          for (k, _) in &fields {
+            writeln!(
+              out,
+              "            {}: take_field(&mut m, {:?})?,",
+              rust_ident(k),
+              k
+            )
+            .unwrap();
+          }
+          writeln!(out, "        }})").unwrap();
+        }
+        writeln!(out, "    }}").unwrap();
+        writeln!(out, "}}").unwrap();
+        writeln!(out).unwrap();
+        self.structs.push_str(&out);
+        name
+      }
+
+      // Passed through as encoded.
+      ValueShape::OneOf { .. }
+      | ValueShape::Error
+      | ValueShape::Implicit
+      | ValueShape::Opaque { .. } => "SerializedVmValue".into(),
+    }
+  }
+
+  /// Converts `hint` to an unused struct name in PascalCase.
+  fn struct_name(&mut self, hint: &str) -> String {
+    let base = pascal_case(hint);
+    let mut name = base.clone();
+    let mut i = 1;
+    while !self.struct_names.insert(name.clone()) || name == "Client" {
+      i += 1;
+      name = format!("{}{}", base, i);
+    }
+    name
+  }
+}
+
+fn rust_ident(name: &str) -> String {
+  match name {
+    "self" | "Self" | "super" | "crate" => format!("{}_", name),
+    _ if RUST_KEYWORDS.contains(&name) => format!("r#{}", name),
+    _ => name.to_string(),
+  }
+}
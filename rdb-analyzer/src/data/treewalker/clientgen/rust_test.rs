@@ -0,0 +1,113 @@
+use bumpalo::Bump;
+
+use crate::{
+  data::treewalker::{
+    asm::codegen::compile_twscript,
+    clientgen::{
+      runtime::{build_map, take_field, take_map, FromVmValue, IntoVmValue},
+      rust::generate_rust_client,
+    },
+    serialize::{SerializedVmValue, TaggedVmValue},
+    signature::{exported_graph_signatures, GraphSignature},
+    typeck::GlobalTyckContext,
+    vm::TwVm,
+  },
+  schema::{compile::compile, grammar::parse},
+  storage_plan::planner::generate_plan_for_schema,
+};
+
+fn signatures(schema: &str, script: &str) -> Vec<GraphSignature> {
+  let alloc = Bump::new();
+  let ast = parse(&alloc, schema).unwrap();
+  let schema = compile(&ast).unwrap();
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let script = compile_twscript(script).unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  exported_graph_signatures(&vm, &type_info)
+}
+
+#[test]
+fn generates_rust_client() {
+  let _ = pretty_env_logger::try_init();
+  let sigs = signatures(
+    r#"
+    type Item {
+      @primary
+      id: string,
+    }
+    export set<Item> items;
+    "#,
+    r#"
+    export graph echo(root: schema, id: string, opts: map { limit: int64, match: string }): map {
+      id: string,
+      limit: int64,
+    } {
+      return m_insert(id) id $ m_insert(limit) opts.limit create_map;
+    }
+    export graph insert(root: schema, id: string) {
+      s_insert root.items $ build_table(Item) $ m_insert(id) id create_map;
+    }
+    "#,
+  );
+  let code = generate_rust_client(&sigs);
+  println!("{}", code);
+
+  assert!(code.contains(
+    "pub async fn echo(&self, id: String, opts: EchoOpts) -> Result<Option<EchoOutput>> {"
+  ));
+  assert!(code.contains("pub struct EchoOpts {"));
+  assert!(code.contains("    pub limit: Option<i64>,"));
+  assert!(code.contains("    pub r#match: Option<String>,"));
+  assert!(code.contains("(\"match\", self.r#match.into_vm_value()),"));
+  assert!(code.contains("pub struct EchoOutput {"));
+
+  // The schema param is filled in by the server.
+  assert!(code.contains("pub async fn insert(&self, id: String) -> Result<()> {"));
+  assert!(code.contains(
+    "self.transport.call(\"insert\", vec![SerializedVmValue::Null(None), id.into_vm_value()])"
+  ));
+}
+
+#[test]
+fn runtime_conversions() {
+  let value = build_map(vec![
+    ("id", "a".to_string().into_vm_value()),
+    ("limit", Some(10i64).into_vm_value()),
+    ("data", vec![1u8, 2, 3].into_vm_value()),
+    ("tags", vec!["x".to_string()].into_vm_value()),
+    ("missing", None::<bool>.into_vm_value()),
+  ]);
+  let mut m = take_map(value).unwrap();
+  assert_eq!(take_field::<String>(&mut m, "id").unwrap(), "a");
+  assert_eq!(
+    take_field::<Option<i64>>(&mut m, "limit").unwrap(),
+    Some(10)
+  );
+  assert_eq!(
+    take_field::<Vec<u8>>(&mut m, "data").unwrap(),
+    vec![1, 2, 3]
+  );
+  assert_eq!(
+    take_field::<Vec<String>>(&mut m, "tags").unwrap(),
+    vec!["x".to_string()]
+  );
+  assert_eq!(take_field::<Option<bool>>(&mut m, "missing").unwrap(), None);
+  assert_eq!(take_field::<Option<bool>>(&mut m, "unknown").unwrap(), None);
+  assert!(take_field::<bool>(&mut m, "unknown").is_err());
+
+  // Integers may be encoded as strings, and truncated lists decode to their elements.
+  assert_eq!(
+    i64::from_vm_value(SerializedVmValue::String("42".into())).unwrap(),
+    42
+  );
+  assert_eq!(
+    Vec::<i64>::from_vm_value(SerializedVmValue::Tagged(TaggedVmValue::T {
+      elements: vec![SerializedVmValue::Int64(1)],
+      omitted: 2,
+    }))
+    .unwrap(),
+    vec![1]
+  );
+  assert!(String::from_vm_value(SerializedVmValue::Bool(true)).is_err());
+}
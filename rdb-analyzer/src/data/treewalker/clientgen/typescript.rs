@@ -0,0 +1,154 @@
+use std::{collections::BTreeMap, fmt::Write};
+
+use crate::data::treewalker::{
+  serialize::{VmValueEncodeConfig, PAGE_FIELDS},
+  signature::{GraphSignature, ValueShape},
+};
+
+use super::pascal_case;
+
+const PRELUDE: &str = r#"export type RdbList<T> =
+  | { L: T[] }
+  | { T: { elements: T[]; omitted: number } };
+export type RdbMap<T> = { M: T };
+export type RdbPage<T> = {
+  P: { items: T[]; next_cursor: string | null; total_estimate: number | null };
+};
+export type RdbError = RdbMap<{ error?: string | null }>;
+"#;
+
+/// Generates TypeScript definitions of the params and outputs of the exported graphs of a
+/// deployment, as encoded by `SerializedVmValue` with `config`.
+///
+/// For each graph `get_item`, `GetItemParams` is the tuple of its params and `GetItemOutput` the
+/// type of its output. `Graphs` maps graph names to both.
+pub fn generate_typescript_definitions(
+  signatures: &[GraphSignature],
+  config: &VmValueEncodeConfig,
+) -> String {
+  let mut out = String::new();
+  writeln!(out, "// Generated by rdbctl. Do not edit.").unwrap();
+  writeln!(out).unwrap();
+  out.push_str(PRELUDE);
+
+  for sig in signatures {
+    let name = pascal_case(&sig.name);
+    writeln!(out).unwrap();
+    writeln!(out, "export type {}Params = [", name).unwrap();
+    for (i, p) in sig.params.iter().enumerate() {
+      let label = p.name.clone().unwrap_or_else(|| format!("param{}", i));
+      writeln!(out, "  /* {} */ {},", label, ts_type(&p.shape, config, 1)).unwrap();
+    }
+    writeln!(out, "];").unwrap();
+    writeln!(
+      out,
+      "export type {}Output = {};",
+      name,
+      sig
+        .output
+        .as_ref()
+        .map(|x| ts_type(x, config, 0))
+        .unwrap_or_else(|| "null".into())
+    )
+    .unwrap();
+  }
+
+  writeln!(out).unwrap();
+  writeln!(out, "export interface Graphs {{").unwrap();
+  for sig in signatures {
+    let name = pascal_case(&sig.name);
+    writeln!(
+      out,
+      "  {}: {{ params: {}Params; output: {}Output }};",
+      ts_property(&sig.name),
+      name,
+      name
+    )
+    .unwrap();
+  }
+  writeln!(out, "}}").unwrap();
+  out
+}
+
+/// Returns the TypeScript type of values of `shape`, including null. `indent` is the nesting
+/// level of the type, for the fields of object types.
+fn ts_type(shape: &ValueShape, config: &VmValueEncodeConfig, indent: usize) -> String {
+  match shape {
+    ValueShape::Implicit => "null".into(),
+    ValueShape::Opaque { .. } => "never".into(),
+    _ => format!("{} | null", non_null_ts_type(shape, config, indent)),
+  }
+}
+
+fn non_null_ts_type(shape: &ValueShape, config: &VmValueEncodeConfig, indent: usize) -> String {
+  match shape {
+    ValueShape::Bool => "boolean".into(),
+    ValueShape::Int64 if config.enable_int64 => "number".into(),
+    ValueShape::Double if config.enable_double => "number".into(),
+    ValueShape::Bytes if config.enable_bytes => "number[]".into(),
+    ValueShape::Int64 | ValueShape::Double | ValueShape::Bytes | ValueShape::String => {
+      "string".into()
+    }
+    ValueShape::List { items } => format!("RdbList<{}>", ts_type(items, config, indent)),
+    ValueShape::Map { fields } if fields.is_empty() => "RdbMap<{}>".into(),
+    ValueShape::Map { fields } if is_page_shape(fields) => match &fields[PAGE_FIELDS[0]] {
+      ValueShape::List { items } => format!("RdbPage<{}>", ts_type(items, config, indent)),
+      _ => unreachable!(),
+    },
+    ValueShape::Map { fields } => {
+      let mut out = "RdbMap<{\n".to_string();
+      for (k, v) in fields {
+        writeln!(
+          out,
+          "{}{}?: {};",
+          "  ".repeat(indent + 1),
+          ts_property(k),
+          ts_type(v, config, indent + 1)
+        )
+        .unwrap();
+      }
+      write!(out, "{}}}>", "  ".repeat(indent)).unwrap();
+      out
+    }
+    ValueShape::OneOf { variants } => {
+      let mut variants = variants
+        .iter()
+        .filter(|x| !matches!(x, ValueShape::Implicit | ValueShape::Opaque { .. }))
+        .map(|x| non_null_ts_type(x, config, indent))
+        .collect::<Vec<_>>();
+      variants.sort();
+      variants.dedup();
+      if variants.is_empty() {
+        "never".into()
+      } else {
+        variants.join(" | ")
+      }
+    }
+    ValueShape::Error => "RdbError".into(),
+    ValueShape::Implicit | ValueShape::Opaque { .. } => "never".into(),
+  }
+}
+
+/// Whether a map is encoded as a page, see `serialize::page_type`.
+fn is_page_shape(fields: &BTreeMap<String, ValueShape>) -> bool {
+  fields.len() == PAGE_FIELDS.len()
+    && matches!(fields.get(PAGE_FIELDS[0]), Some(ValueShape::List { .. }))
+    && matches!(fields.get(PAGE_FIELDS[1]), Some(ValueShape::String))
+    && matches!(fields.get(PAGE_FIELDS[2]), Some(ValueShape::Int64))
+}
+
+fn ts_property(name: &str) -> String {
+  let mut chars = name.chars();
+  let valid = match chars.next() {
+    Some(c) => {
+      (c.is_ascii_alphabetic() || c == '_' || c == '$')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+    }
+    None => false,
+  };
+  if valid {
+    name.to_string()
+  } else {
+    serde_json::to_string(name).unwrap()
+  }
+}
@@ -0,0 +1,85 @@
+use crate::data::treewalker::{
+  clientgen::typescript::generate_typescript_definitions,
+  serialize::VmValueEncodeConfig,
+  signature::{GraphSignature, ParamSignature, ValueShape},
+};
+
+fn signatures() -> Vec<GraphSignature> {
+  vec![
+    GraphSignature {
+      name: "get_item".into(),
+      params: vec![
+        ParamSignature {
+          name: Some("root".into()),
+          shape: ValueShape::Implicit,
+        },
+        ParamSignature {
+          name: Some("id".into()),
+          shape: ValueShape::Int64,
+        },
+      ],
+      output: Some(ValueShape::Map {
+        fields: vec![
+          ("data".to_string(), ValueShape::Bytes),
+          (
+            "tags".to_string(),
+            ValueShape::List {
+              items: Box::new(ValueShape::String),
+            },
+          ),
+          ("created-at".to_string(), ValueShape::Double),
+        ]
+        .into_iter()
+        .collect(),
+      }),
+    },
+    GraphSignature {
+      name: "touch".into(),
+      params: vec![ParamSignature {
+        name: None,
+        shape: ValueShape::OneOf {
+          variants: vec![ValueShape::String, ValueShape::Error, ValueShape::String],
+        },
+      }],
+      output: None,
+    },
+  ]
+}
+
+#[test]
+fn generates_typescript_definitions() {
+  let code = generate_typescript_definitions(&signatures(), &VmValueEncodeConfig::default());
+  println!("{}", code);
+  assert!(code.contains(
+    r#"export type GetItemParams = [
+  /* root */ null,
+  /* id */ string | null,
+];"#
+  ));
+  assert!(code.contains(
+    r#"export type GetItemOutput = RdbMap<{
+  "created-at"?: string | null;
+  data?: string | null;
+  tags?: RdbList<string | null> | null;
+}> | null;"#
+  ));
+  assert!(code.contains("  /* param0 */ RdbError | string | null,"));
+  assert!(code.contains("export type TouchOutput = null;"));
+  assert!(code.contains("  get_item: { params: GetItemParams; output: GetItemOutput };"));
+}
+
+#[test]
+fn follows_encode_config() {
+  let code = generate_typescript_definitions(
+    &signatures(),
+    &VmValueEncodeConfig {
+      enable_bytes: true,
+      enable_int64: true,
+      enable_double: true,
+      ..Default::default()
+    },
+  );
+  assert!(code.contains("  /* id */ number | null,"));
+  assert!(code.contains("  \"created-at\"?: number | null;"));
+  assert!(code.contains("  data?: number[] | null;"));
+}
@@ -0,0 +1,170 @@
+use std::{fmt::Display, sync::Mutex};
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::data::kv::{
+  ConflictInfo, KvEntryIterator, KvError, KvKeyIterator, KvTransaction, ScanOptions,
+};
+
+use super::vm::TwVm;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AccessKind {
+  Read,
+  Write,
+}
+
+/// A key range `[start, end)` accessed by a node.
+#[derive(Clone, Debug)]
+pub struct KeyAccess {
+  pub graph: String,
+  pub node_index: u32,
+  pub kind: AccessKind,
+  pub start: Vec<u8>,
+  pub end: Vec<u8>,
+}
+
+/// Diagnostics of a graph run that conflicted on every attempt.
+#[derive(Clone, Debug, Default)]
+pub struct ConflictReport {
+  /// The conflict reported by the backend on the last attempt.
+  pub conflict: ConflictInfo,
+
+  /// The accesses of the last attempt that overlap a conflicting range. Accesses of nodes that
+  /// run subgraphs are attributed to the nodes of the subgraphs.
+  pub accesses: Vec<KeyAccess>,
+}
+
+impl Display for ConflictReport {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    if self.conflict.ranges.is_empty() {
+      return Ok(());
+    }
+    write!(
+      f,
+      ": {} conflicting key range(s)",
+      self.conflict.ranges.len()
+    )?;
+    for (i, x) in self.accesses.iter().enumerate() {
+      write!(
+        f,
+        "{} {:?} by graph `{}` node {}",
+        if i == 0 { ", accessed as" } else { "," },
+        x.kind,
+        x.graph,
+        x.node_index
+      )?;
+    }
+    Ok(())
+  }
+}
+
+struct RawAccess {
+  graph_index: usize,
+  node_index: u32,
+  kind: AccessKind,
+  start: Vec<u8>,
+  end: Vec<u8>,
+}
+
+/// Key accesses of all nodes in a transaction.
+#[derive(Default)]
+pub(super) struct AccessLog {
+  accesses: Mutex<Vec<RawAccess>>,
+}
+
+impl AccessLog {
+  fn push(&self, graph_index: usize, node_index: u32, kind: AccessKind, start: &[u8], end: &[u8]) {
+    self.accesses.lock().unwrap().push(RawAccess {
+      graph_index,
+      node_index,
+      kind,
+      start: start.to_vec(),
+      end: end.to_vec(),
+    });
+  }
+
+  /// Builds the report of a conflict, keeping the accesses that overlap one of its ranges.
+  pub fn report(self, vm: &TwVm, conflict: ConflictInfo) -> ConflictReport {
+    let accesses = self
+      .accesses
+      .into_inner()
+      .unwrap()
+      .into_iter()
+      .filter(|x| conflict.overlaps(&x.start, &x.end))
+      .map(|x| KeyAccess {
+        graph: vm.script.graphs[x.graph_index].name.clone(),
+        node_index: x.node_index,
+        kind: x.kind,
+        start: x.start,
+        end: x.end,
+      })
+      .collect();
+    ConflictReport { conflict, accesses }
+  }
+}
+
+/// A transaction that records the keys accessed by one node into an `AccessLog`.
+pub(super) struct RecordingTransaction<'t> {
+  pub inner: &'t dyn KvTransaction,
+  pub log: &'t AccessLog,
+  pub graph_index: usize,
+  pub node_index: u32,
+}
+
+impl<'t> RecordingTransaction<'t> {
+  fn record(&self, kind: AccessKind, start: &[u8], end: &[u8]) {
+    self
+      .log
+      .push(self.graph_index, self.node_index, kind, start, end);
+  }
+
+  fn record_key(&self, kind: AccessKind, key: &[u8]) {
+    let mut end = key.to_vec();
+    end.push(0x00);
+    self.record(kind, key, &end);
+  }
+}
+
+#[async_trait]
+impl<'t> KvTransaction for RecordingTransaction<'t> {
+  async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    self.record_key(AccessKind::Read, key);
+    self.inner.get(key).await
+  }
+
+  async fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+    self.record_key(AccessKind::Write, key);
+    self.inner.put(key, value).await
+  }
+
+  async fn delete(&self, key: &[u8]) -> Result<()> {
+    self.record_key(AccessKind::Write, key);
+    self.inner.delete(key).await
+  }
+
+  async fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+    self.record(AccessKind::Write, start, end);
+    self.inner.delete_range(start, end).await
+  }
+
+  async fn scan_keys(&self, start: &[u8], end: &[u8]) -> Result<Box<dyn KvKeyIterator>> {
+    self.record(AccessKind::Read, start, end);
+    self.inner.scan_keys(start, end).await
+  }
+
+  async fn scan<'a>(
+    &'a self,
+    start: &[u8],
+    end: &[u8],
+    options: &ScanOptions,
+  ) -> Result<Box<dyn KvEntryIterator + 'a>> {
+    self.record(AccessKind::Read, start, end);
+    self.inner.scan(start, end, options).await
+  }
+
+  async fn commit(self: Box<Self>) -> Result<(), KvError> {
+    panic!("inconsistency: commit called on a node transaction");
+  }
+}
@@ -0,0 +1,133 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bumpalo::Bump;
+
+use crate::{
+  data::{
+    kv::{ConflictInfo, KeyValueStore, KvError, KvKeyIterator, KvTransaction},
+    mock_kv::MockKv,
+    treewalker::{
+      asm::codegen::compile_twscript,
+      bytecode::TwGraphNode,
+      exec::{generate_root_map, ExecError, Executor},
+      typeck::GlobalTyckContext,
+      vm::TwVm,
+    },
+  },
+  schema::{compile::compile, grammar::parse},
+  storage_plan::planner::generate_plan_for_schema,
+};
+
+use super::conflict::AccessKind;
+
+/// A store whose commits always conflict on the keys they write.
+struct ConflictingKv {
+  inner: MockKv,
+}
+
+struct ConflictingTransaction {
+  inner: Box<dyn KvTransaction>,
+  written: Mutex<Vec<Vec<u8>>>,
+}
+
+#[async_trait]
+impl KeyValueStore for ConflictingKv {
+  async fn begin_transaction(&self) -> Result<Box<dyn KvTransaction>> {
+    Ok(Box::new(ConflictingTransaction {
+      inner: self.inner.begin_transaction().await?,
+      written: Mutex::new(vec![]),
+    }))
+  }
+}
+
+#[async_trait]
+impl KvTransaction for ConflictingTransaction {
+  async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    self.inner.get(key).await
+  }
+
+  async fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+    self.written.lock().unwrap().push(key.to_vec());
+    self.inner.put(key, value).await
+  }
+
+  async fn delete(&self, key: &[u8]) -> Result<()> {
+    self.written.lock().unwrap().push(key.to_vec());
+    self.inner.delete(key).await
+  }
+
+  async fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+    self.inner.delete_range(start, end).await
+  }
+
+  async fn scan_keys(&self, start: &[u8], end: &[u8]) -> Result<Box<dyn KvKeyIterator>> {
+    self.inner.scan_keys(start, end).await
+  }
+
+  async fn commit(self: Box<Self>) -> Result<(), KvError> {
+    let written = self.written.into_inner().unwrap();
+    Err(KvError::Conflict(ConflictInfo::from_keys(
+      written.iter().map(|x| x.as_slice()),
+    )))
+  }
+}
+
+#[tokio::test]
+async fn reports_conflicting_nodes() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+  type Store {
+    a: int64,
+    b: int64,
+  }
+  export Store store;
+  "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let script = compile_twscript(
+    r#"
+  graph main(root: schema) {
+    old = root.store.b;
+    t_insert(a) root.store 1;
+  }
+  "#,
+  )
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let kv = ConflictingKv {
+    inner: MockKv::new(),
+  };
+  let mut executor = Executor::new(&vm, &kv, &type_info);
+  let err = executor
+    .run_graph(0, &[Arc::new(generate_root_map(&schema, &plan).unwrap())])
+    .await
+    .unwrap_err();
+  let report = match err.downcast_ref::<ExecError>() {
+    Some(ExecError::ConflictAfterRetries(x)) => x,
+    _ => panic!("unexpected error: {:?}", err),
+  };
+  assert!(!report.conflict.ranges.is_empty());
+  assert_eq!(executor.conflicts(), 10);
+
+  // The read of `b` does not overlap the conflict.
+  assert!(!report.accesses.is_empty());
+  for access in &report.accesses {
+    assert_eq!(access.graph, "main");
+    assert_eq!(access.kind, AccessKind::Write);
+    assert!(matches!(
+      &script.graphs[0].nodes[access.node_index as usize].0,
+      TwGraphNode::InsertIntoTable(_)
+    ));
+  }
+  assert!(err.to_string().contains("conflicting key range(s)"));
+}
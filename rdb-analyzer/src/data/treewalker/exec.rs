@@ -1,34 +1,75 @@
-use std::{collections::BTreeMap, future::Future, pin::Pin, sync::Arc, time::Duration};
+use std::{
+  collections::BTreeMap,
+  future::Future,
+  ops::Bound,
+  pin::Pin,
+  sync::Arc,
+  time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::Result;
 use async_recursion::async_recursion;
+use byteorder::{BigEndian, ByteOrder};
 use rand::Rng;
 use rpds::{ListSync, RedBlackTreeMapSync};
 use smallvec::{smallvec, SmallVec};
 
 use crate::{
   data::{
-    kv::{KeyValueStore, KvError, KvTransaction},
-    pathwalker::PathWalker,
+    backfill, blob,
+    keyenc::{self, split_sort_key_entry, successor_of_bounded_prefix},
+    kv::{KeyValueStore, KvEntryIterator, KvError, KvTransaction, ScanOptions},
+    pathwalker::{get_with_rename_fallback, PathWalker},
     treewalker::vm_value::{
-      VmListValue, VmMapValue, VmSetType, VmSetValue, VmSetValueKind, VmTableValue,
-      VmTableValueKind, VmType, VmValue,
+      VmDictValue, VmListValue, VmListValueKind, VmMapValue, VmSetType, VmSetValue, VmSetValueKind,
+      VmTableValue, VmTableValueKind, VmType, VmValue,
     },
-    value::PrimitiveValue,
+    value::{PackedValue, PrimitiveValue},
+  },
+  schema::compile::{
+    CompiledSchema, FieldAnnotationList, FieldType, PrimitiveType, CREATED_AT_FIELD,
+    DELETED_AT_FIELD, UPDATED_AT_FIELD,
   },
-  schema::compile::{CompiledSchema, FieldType},
   storage_plan::StoragePlan,
 };
 use thiserror::Error;
 
 use super::{
-  bytecode::{TwGraph, TwGraphNode},
+  bytecode::{TwGraph, TwGraphNode, TwParamConstraint, TwSourcePosition},
+  conflict::{AccessLog, ConflictReport, RecordingTransaction},
+  overlay::EffectOverlay,
+  pool::ValuePool,
+  profile::{GraphProfile, GraphProfiler, ProfilingTransaction},
+  semaphore::Semaphore,
+  serialize::{build_page, decode_page_cursor, encode_page_cursor},
   typeck::GlobalTypeInfo,
+  usage::{ExecUsage, MeteredTransaction, UsageMeter},
   vm::TwVm,
 };
 
+#[derive(Clone, Debug)]
 pub struct ExecConfig {
+  /// Max number of nodes that can run concurrently within a single `run_graph` call, across all
+  /// subgraphs. This also bounds the number of outstanding KV requests. Zero is treated as one.
   pub concurrency: usize,
+
+  /// Max number of KV requests per attempt. Exceeding it fails the run with
+  /// `ExecError::KvOpLimitExceeded`.
+  pub max_kv_ops: Option<u64>,
+
+  /// Max total size of keys and values written per attempt. Exceeding it fails the run with
+  /// `ExecError::WriteLimitExceeded`. Deletes are not counted.
+  pub max_bytes_written: Option<u64>,
+}
+
+impl Default for ExecConfig {
+  fn default() -> Self {
+    Self {
+      concurrency: 64,
+      max_kv_ops: None,
+      max_bytes_written: None,
+    }
+  }
 }
 
 pub struct Executor<'a, 'b> {
@@ -36,8 +77,28 @@ pub struct Executor<'a, 'b> {
   kv: &'b dyn KeyValueStore,
   type_info: &'b GlobalTypeInfo<'a>,
   fire_rule_tables: Vec<FireRuleTable>,
+  concurrency_limit: Semaphore,
   yield_fn: Option<fn() -> Pin<Box<dyn Future<Output = ()> + Send>>>,
   sleep_fn: Option<fn(Duration) -> Pin<Box<dyn Future<Output = ()> + Send>>>,
+  pool: ValuePool<'a>,
+
+  /// Effects of the current attempt on fresh tables and sets.
+  overlay: EffectOverlay<'a>,
+
+  /// Records the keys accessed by each node. Only set on attempts after a conflict.
+  access_log: Option<AccessLog>,
+
+  usage: UsageMeter,
+
+  /// Serializes `@auto` counter updates between concurrent nodes of a run, which share a
+  /// transaction.
+  auto_key_lock: Semaphore,
+
+  profiling: bool,
+  profile: Option<GraphProfile>,
+
+  /// Number of attempts of the last run whose commit conflicted.
+  conflicts: u32,
 }
 
 #[derive(Clone)]
@@ -54,6 +115,13 @@ enum FireRuleKind {
 
 type FireRuleTable = Vec<SmallVec<[FireRuleItem; 4]>>;
 
+#[derive(Clone)]
+enum ParamState<'a> {
+  Pending,
+  Ready(Arc<VmValue<'a>>),
+  Skipped,
+}
+
 #[derive(Error, Debug)]
 pub enum ExecError {
   #[error("not yet implemented: {0}")]
@@ -62,9 +130,27 @@ pub enum ExecError {
   #[error("null value unwrappped")]
   NullUnwrapped,
 
-  #[error("operation is not supported on fresh tables or sets")]
+  #[error("operation is not supported on fresh tables, sets or lists")]
   FreshTableOrSetNotSupported,
 
+  #[error("operation is not supported on persisted lists")]
+  ResidentListNotSupported,
+
+  #[error("bad map key in storage")]
+  BadMapKey,
+
+  #[error("bad sort key entry in storage")]
+  BadSortKeyEntry,
+
+  #[error("the sort key index of set `{0}` is not backfilled yet")]
+  SortIndexNotReady(String),
+
+  #[error("check constraint violated on type `{ty}`: `{check}`")]
+  ConstraintViolation { ty: String, check: String },
+
+  #[error("map entries are not fields of type `{ty}`: {fields}")]
+  UnknownTableFields { ty: String, fields: String },
+
   #[error("export type not supported")]
   ExportTypeNotSupported,
 
@@ -77,14 +163,54 @@ pub enum ExecError {
   #[error("path integrity check failed: missing path(s): {0}")]
   PathIntegrityFailure(String),
 
-  #[error("conflict after retries")]
-  ConflictAfterRetries,
+  #[error("auto primary key overflow")]
+  AutoKeyOverflow,
+
+  #[error("kv op limit exceeded: {0}")]
+  KvOpLimitExceeded(u64),
+
+  #[error("write limit exceeded: {0} bytes")]
+  WriteLimitExceeded(u64),
+
+  #[error("conflict after retries{0}")]
+  ConflictAfterRetries(ConflictReport),
+
+  #[error("script thrown error: `{0}`{}", display_position(.1))]
+  ScriptThrownError(String, Option<TwSourcePosition>),
+
+  #[error("script thrown null{}", display_position(.0))]
+  ScriptThrownNull(Option<TwSourcePosition>),
+
+  #[error("assertion failed at graph {graph_index} node {node_index}: `{message}`{}", display_position(.position))]
+  AssertionFailed {
+    message: String,
+    graph_index: usize,
+    node_index: u32,
+    position: Option<TwSourcePosition>,
+  },
+
+  #[error("write in a snapshot read")]
+  WriteInSnapshotRead,
+
+  #[error("param {param} of graph `{graph}` violates `{constraint}`")]
+  InvalidParam {
+    graph: String,
+    param: String,
+    constraint: TwParamConstraint,
+  },
+}
 
-  #[error("script thrown error: `{0}`")]
-  ScriptThrownError(String),
+/// The length of a string in characters, or of a byte string in bytes.
+fn param_length(x: &VmValue) -> Option<u64> {
+  match x {
+    VmValue::Primitive(PrimitiveValue::String(x)) => Some(x.chars().count() as u64),
+    VmValue::Primitive(PrimitiveValue::Bytes(x)) => Some(x.len() as u64),
+    _ => None,
+  }
+}
 
-  #[error("script thrown null")]
-  ScriptThrownNull,
+fn display_position(x: &Option<TwSourcePosition>) -> String {
+  x.map(|x| format!(" (at {})", x)).unwrap_or_default()
 }
 
 const MAX_RECURSION_DEPTH: usize = 128;
@@ -94,6 +220,15 @@ impl<'a, 'b> Executor<'a, 'b> {
     vm: &'b TwVm<'a>,
     kv: &'b dyn KeyValueStore,
     type_info: &'b GlobalTypeInfo<'a>,
+  ) -> Self {
+    Self::new_with_config(vm, kv, type_info, &ExecConfig::default())
+  }
+
+  pub fn new_with_config(
+    vm: &'b TwVm<'a>,
+    kv: &'b dyn KeyValueStore,
+    type_info: &'b GlobalTypeInfo<'a>,
+    config: &ExecConfig,
   ) -> Self {
     let mut fire_rule_tables = Vec::with_capacity(vm.script.graphs.len());
     for g in &vm.script.graphs {
@@ -104,8 +239,17 @@ impl<'a, 'b> Executor<'a, 'b> {
       kv,
       type_info,
       fire_rule_tables,
+      concurrency_limit: Semaphore::new(config.concurrency.max(1)),
       yield_fn: None,
       sleep_fn: None,
+      pool: ValuePool::new(),
+      overlay: EffectOverlay::new(),
+      access_log: None,
+      usage: UsageMeter::new(config.max_kv_ops, config.max_bytes_written),
+      auto_key_lock: Semaphore::new(1),
+      profiling: false,
+      profile: None,
+      conflicts: 0,
     }
   }
 
@@ -117,22 +261,79 @@ impl<'a, 'b> Executor<'a, 'b> {
     self.sleep_fn = Some(f);
   }
 
+  /// KV usage of the last attempt of the last run.
+  pub fn usage(&self) -> ExecUsage {
+    self.usage.usage()
+  }
+
+  /// Number of attempts of the last run whose commit conflicted. Each of them was retried, unless
+  /// the run failed with `ExecError::ConflictAfterRetries`.
+  pub fn conflicts(&self) -> u32 {
+    self.conflicts
+  }
+
+  /// Enables collecting a profile of each run. Profiling times nodes with `std::time::Instant`,
+  /// which is not available on wasm.
+  pub fn set_profiling(&mut self, enabled: bool) {
+    self.profiling = enabled;
+  }
+
+  /// Profile of the last attempt of the last run, if profiling was enabled and the run succeeded.
+  pub fn profile(&self) -> Option<&GraphProfile> {
+    self.profile.as_ref()
+  }
+
+  /// Runs a graph with profiling enabled, returning its profile alongside the output.
+  pub async fn run_graph_profiled(
+    &mut self,
+    graph_index: usize,
+    graph_params: &[Arc<VmValue<'a>>],
+  ) -> Result<(Option<Arc<VmValue<'a>>>, GraphProfile)> {
+    let profiling = std::mem::replace(&mut self.profiling, true);
+    let ret = self.run_graph(graph_index, graph_params).await;
+    self.profiling = profiling;
+    let ret = ret?;
+    Ok((
+      ret,
+      self
+        .profile
+        .take()
+        .expect("inconsistency: profile not collected"),
+    ))
+  }
+
   pub async fn run_graph(
     &mut self,
     graph_index: usize,
     graph_params: &[Arc<VmValue<'a>>],
   ) -> Result<Option<Arc<VmValue<'a>>>> {
+    let mut last_conflict = None;
+    self.profile = None;
+    self.conflicts = 0;
     for i in 0..10 {
+      // Record key accesses once the graph has conflicted, to report them if it keeps conflicting.
+      self.access_log = if i > 0 {
+        Some(AccessLog::default())
+      } else {
+        None
+      };
       let txn = self.kv.begin_transaction().await?;
+      let profiler = self
+        .profiling
+        .then(|| GraphProfiler::new(self.vm, graph_index));
       let ret = self
-        .recursively_run_graph(graph_index, graph_params, 0, &*txn)
+        .run_graph_metered(graph_index, graph_params, &*txn, profiler.as_ref(), false)
         .await?;
 
       match txn.commit().await {
         Ok(()) => {
+          self.access_log = None;
+          self.profile = profiler.map(|x| x.finish(self.vm));
           return Ok(ret);
         }
-        Err(KvError::Conflict) => {
+        Err(KvError::Conflict(info)) => {
+          last_conflict = Some(info);
+          self.conflicts += 1;
           if let Some(f) = self.sleep_fn {
             let delay_ms = rand::thread_rng().gen_range(1..20);
             log::warn!(
@@ -151,7 +352,75 @@ impl<'a, 'b> Executor<'a, 'b> {
         Err(x) => return Err(x.into()),
       }
     }
-    Err(ExecError::ConflictAfterRetries.into())
+    let report = match (self.access_log.take(), last_conflict) {
+      (Some(log), Some(conflict)) => log.report(self.vm, conflict),
+      _ => ConflictReport::default(),
+    };
+    Err(ExecError::ConflictAfterRetries(report).into())
+  }
+
+  /// Runs a graph in a transaction owned by the caller. The transaction is not committed, so
+  /// conflicts are neither detected nor retried here.
+  pub async fn run_graph_in_txn(
+    &mut self,
+    graph_index: usize,
+    graph_params: &[Arc<VmValue<'a>>],
+    txn: &dyn KvTransaction,
+  ) -> Result<Option<Arc<VmValue<'a>>>> {
+    self.profile = None;
+    self.conflicts = 0;
+    let profiler = self
+      .profiling
+      .then(|| GraphProfiler::new(self.vm, graph_index));
+    let ret = self
+      .run_graph_metered(graph_index, graph_params, txn, profiler.as_ref(), false)
+      .await?;
+    self.profile = profiler.map(|x| x.finish(self.vm));
+    Ok(ret)
+  }
+
+  /// Runs a graph against the store as of `version`, a read version of an earlier transaction.
+  /// Nothing is committed, and graphs that write fail with `ExecError::WriteInSnapshotRead`.
+  pub async fn run_graph_at(
+    &mut self,
+    graph_index: usize,
+    graph_params: &[Arc<VmValue<'a>>],
+    version: i64,
+  ) -> Result<Option<Arc<VmValue<'a>>>> {
+    self.profile = None;
+    self.conflicts = 0;
+    let txn = self.kv.begin_transaction_at(version).await?;
+    let profiler = self
+      .profiling
+      .then(|| GraphProfiler::new(self.vm, graph_index));
+    let ret = self
+      .run_graph_metered(graph_index, graph_params, &*txn, profiler.as_ref(), true)
+      .await?;
+    self.profile = profiler.map(|x| x.finish(self.vm));
+    Ok(ret)
+  }
+
+  async fn run_graph_metered(
+    &self,
+    graph_index: usize,
+    graph_params: &[Arc<VmValue<'a>>],
+    txn: &dyn KvTransaction,
+    profiler: Option<&GraphProfiler>,
+    read_only: bool,
+  ) -> Result<Option<Arc<VmValue<'a>>>> {
+    self.usage.reset();
+    self.overlay.clear();
+    let txn = MeteredTransaction {
+      inner: txn,
+      meter: &self.usage,
+      read_only,
+    };
+    let ret = self
+      .recursively_run_graph(graph_index, graph_params, 0, &txn, profiler)
+      .await?;
+    let ret = ret.map(|x| self.overlay.resolve(&x));
+    self.overlay.clear();
+    Ok(ret)
   }
 
   #[async_recursion]
@@ -161,6 +430,7 @@ impl<'a, 'b> Executor<'a, 'b> {
     graph_params: &[Arc<VmValue<'a>>],
     recursion_depth: usize,
     txn: &dyn KvTransaction,
+    profiler: Option<&GraphProfiler>,
   ) -> Result<Option<Arc<VmValue<'a>>>> {
     if recursion_depth >= MAX_RECURSION_DEPTH {
       return Err(ExecError::MaxRecursionDepthExceeded(recursion_depth).into());
@@ -170,36 +440,119 @@ impl<'a, 'b> Executor<'a, 'b> {
       f().await;
     }
 
-    let recursion_depth = recursion_depth + 1;
+    self.validate_params(graph_index, graph_params)?;
+
+    let start_time = profiler.map(|_| Instant::now());
+    let ret = self
+      .run_graph_nodes(
+        graph_index,
+        graph_params,
+        recursion_depth + 1,
+        txn,
+        profiler,
+      )
+      .await;
+    if let (Some(profiler), Some(start_time)) = (profiler, start_time) {
+      profiler.record_run(start_time.elapsed());
+    }
+    ret
+  }
+
+  /// Checks the params of a graph against its `param_constraints`.
+  fn validate_params(&self, graph_index: usize, graph_params: &[Arc<VmValue<'a>>]) -> Result<()> {
+    let g = &self.vm.script.graphs[graph_index];
+    for (i, constraint) in &g.param_constraints {
+      let value = match graph_params.get(*i as usize) {
+        Some(x) => &**x,
+        None => continue,
+      };
+      let ok = match (constraint, value) {
+        (_, VmValue::Null(_)) => true,
+        (TwParamConstraint::MinLength(min), x) => param_length(x).map(|x| x >= *min) != Some(false),
+        (TwParamConstraint::MaxLength(max), x) => param_length(x).map(|x| x <= *max) != Some(false),
+        (TwParamConstraint::Min(min), VmValue::Primitive(PrimitiveValue::Int64(x))) => x >= min,
+        (TwParamConstraint::Max(max), VmValue::Primitive(PrimitiveValue::Int64(x))) => x <= max,
+        (TwParamConstraint::Min(min), VmValue::Primitive(PrimitiveValue::Double(x))) => {
+          f64::from_bits(*x) >= *min as f64
+        }
+        (TwParamConstraint::Max(max), VmValue::Primitive(PrimitiveValue::Double(x))) => {
+          f64::from_bits(*x) <= *max as f64
+        }
+        (TwParamConstraint::Pattern(pattern), VmValue::Primitive(PrimitiveValue::String(x))) => {
+          self.vm.param_patterns[pattern.as_str()].is_match(x)
+        }
+        _ => true,
+      };
+      if !ok {
+        let param = self
+          .vm
+          .script
+          .source_map
+          .as_ref()
+          .and_then(|x| x.param_names.get(graph_index))
+          .and_then(|x| x.get(*i as usize))
+          .map(|x| format!("`{}`", x))
+          .unwrap_or_else(|| format!("{}", i));
+        return Err(
+          ExecError::InvalidParam {
+            graph: g.name.clone(),
+            param,
+            constraint: constraint.clone(),
+          }
+          .into(),
+        );
+      }
+    }
+    Ok(())
+  }
+
+  async fn run_graph_nodes(
+    &self,
+    graph_index: usize,
+    graph_params: &[Arc<VmValue<'a>>],
+    recursion_depth: usize,
+    txn: &dyn KvTransaction,
+    profiler: Option<&GraphProfiler>,
+  ) -> Result<Option<Arc<VmValue<'a>>>> {
     let g = &self.vm.script.graphs[graph_index];
-    let type_info = &self.type_info.graphs[graph_index];
     let fire_rules = &self.fire_rule_tables[graph_index];
-    let mut deps_satisfied: SmallVec<[SmallVec<[Option<Arc<VmValue<'a>>>; 3]>; 16]> = g
+    let type_info = &self.type_info.graphs[graph_index];
+    let mut param_state: SmallVec<[SmallVec<[ParamState<'a>; 3]>; 16]> = g
+      .nodes
+      .iter()
+      .map(|(_, x, _)| smallvec![ParamState::Pending; x.len()])
+      .collect();
+
+    // `None` if the precondition is not yet evaluated.
+    let mut precondition_state: SmallVec<[Option<bool>; 16]> = g
       .nodes
       .iter()
-      .map(|(_, x, _)| smallvec![None; x.len()])
+      .map(|(_, _, x)| if x.is_none() { Some(true) } else { None })
       .collect();
-    let mut precondition_satisfied: SmallVec<[bool; 16]> =
-      g.nodes.iter().map(|(_, _, x)| x.is_none()).collect();
+
+    // Whether a node is already fired or skipped.
+    let mut done: SmallVec<[bool; 16]> = smallvec![false; g.nodes.len()];
 
     // The initial batch
     let mut futures: Vec<
       Pin<Box<dyn Future<Output = (u32, Result<Option<Arc<VmValue<'a>>>>)> + Send>>,
     > = vec![];
-    for (i, (n, in_edges, precondition)) in g.nodes.iter().enumerate() {
+    for (i, (_, in_edges, precondition)) in g.nodes.iter().enumerate() {
       if in_edges.is_empty() && precondition.is_none() {
+        done[i] = true;
         let txn = &*txn;
         futures.push(Box::pin(async move {
           (
             i as u32,
             self
               .run_node(
-                n,
+                graph_index,
+                i as u32,
                 vec![],
                 txn,
                 graph_params,
-                type_info.nodes[i].as_ref(),
                 recursion_depth,
+                profiler,
               )
               .await,
           )
@@ -208,6 +561,11 @@ impl<'a, 'b> Executor<'a, 'b> {
     }
 
     let mut ret: Option<Arc<VmValue<'a>>> = None;
+    let mut output_skipped = false;
+
+    // Values of the named outputs. `None` if not yet produced or skipped.
+    let mut named_outputs: SmallVec<[Option<Arc<VmValue<'a>>>; 4]> =
+      smallvec![None; g.named_outputs.len()];
 
     loop {
       if futures.is_empty() {
@@ -220,6 +578,11 @@ impl<'a, 'b> Executor<'a, 'b> {
       if Some(node_index) == g.output {
         ret = result.clone();
       }
+      for (i, (_, x)) in g.named_outputs.iter().enumerate() {
+        if *x == node_index {
+          named_outputs[i] = result.clone();
+        }
+      }
 
       let to_fire = fire_rules[node_index as usize].as_slice();
       for item in to_fire {
@@ -232,89 +595,224 @@ impl<'a, 'b> Executor<'a, 'b> {
                 )
               });
 
-            deps_satisfied[item.target_node as usize][*param_position as usize] =
-              Some(result.clone());
+            param_state[item.target_node as usize][*param_position as usize] =
+              ParamState::Ready(result.clone());
           }
           FireRuleKind::Precondition => {
-            precondition_satisfied[item.target_node as usize] = match result.as_ref().map(|x| &**x)
-            {
-              Some(VmValue::Bool(x)) => *x,
-              Some(VmValue::Null(_)) => false,
-              None => true,
-              _ => panic!("inconsistency detected: invalid precondition: {:?}", result),
-            };
+            precondition_state[item.target_node as usize] =
+              Some(match result.as_ref().map(|x| &**x) {
+                Some(VmValue::Bool(x)) => *x,
+                Some(VmValue::Null(_)) => false,
+                None => true,
+                _ => panic!("inconsistency detected: invalid precondition: {:?}", result),
+              });
           }
         }
       }
 
       // Do this in another iteration in case that a single source node is connect to a single target node's
       // multiple parameters.
-      for item in to_fire {
-        let target_node = item.target_node as usize;
+      let mut to_check: Vec<u32> = to_fire.iter().map(|x| x.target_node).collect();
+      while let Some(target_node) = to_check.pop() {
+        let target_node = target_node as usize;
         let node_info = &g.nodes[target_node].0;
+        let params = &param_state[target_node];
 
-        // If all deps and the precondition are satisfied...
-        if precondition_satisfied[item.target_node as usize] {
-          if node_info.is_select() {
-            if deps_satisfied[item.target_node as usize].is_empty() {
-              return Err(ExecError::BothSelectCandidatesFired.into());
-            }
-
-            if let Some(x) = deps_satisfied[item.target_node as usize]
+        if done[target_node] {
+          if node_info.is_select()
+            && params
               .iter()
-              .find_map(|x| x.as_ref())
-            {
-              let x = x.clone();
-
-              // Fire only once!
-              deps_satisfied[item.target_node as usize] = smallvec![];
+              .filter(|x| matches!(x, ParamState::Ready(_)))
+              .count()
+              > 1
+          {
+            return Err(ExecError::BothSelectCandidatesFired.into());
+          }
+          continue;
+        }
 
-              futures.push(Box::pin(async move { (target_node as u32, Ok(Some(x))) }))
-            }
+        // A node is skipped if its precondition is false, or if its parameters can never be satisfied.
+        // The skip propagates down to all dependents.
+        let skip = precondition_state[target_node] == Some(false)
+          || if node_info.is_select() {
+            params.iter().all(|x| matches!(x, ParamState::Skipped))
           } else {
-            if deps_satisfied[item.target_node as usize]
-              .iter()
-              .find(|x| x.is_none())
-              .is_none()
-            {
-              let params =
-                std::mem::replace(&mut deps_satisfied[item.target_node as usize], smallvec![])
-                  .into_iter()
-                  .map(|x| x.unwrap())
-                  .collect::<Vec<_>>();
-              let txn = &*txn;
-              futures.push(Box::pin(async move {
-                (
-                  target_node as u32,
-                  self
-                    .run_node(
-                      node_info,
-                      params,
-                      txn,
-                      graph_params,
-                      type_info.nodes[target_node].as_ref(),
-                      recursion_depth,
-                    )
-                    .await,
-                )
-              }))
+            params.iter().any(|x| matches!(x, ParamState::Skipped))
+          };
+        if skip {
+          log::trace!("skipping node {} in graph {}", target_node, graph_index);
+          done[target_node] = true;
+          if Some(target_node as u32) == g.output {
+            output_skipped = true;
+          }
+          for item in fire_rules[target_node].iter() {
+            match &item.kind {
+              FireRuleKind::ParamDep(param_position) => {
+                param_state[item.target_node as usize][*param_position as usize] =
+                  ParamState::Skipped;
+              }
+              FireRuleKind::Precondition => {
+                precondition_state[item.target_node as usize] = Some(false);
+              }
             }
+            to_check.push(item.target_node);
+          }
+          continue;
+        }
+
+        if precondition_state[target_node] != Some(true) {
+          continue;
+        }
+
+        if node_info.is_select() {
+          if let Some(x) = params.iter().find_map(|x| match x {
+            ParamState::Ready(x) => Some(x.clone()),
+            _ => None,
+          }) {
+            done[target_node] = true;
+            futures.push(Box::pin(async move { (target_node as u32, Ok(Some(x))) }))
           }
+        } else if params.iter().all(|x| matches!(x, ParamState::Ready(_))) {
+          done[target_node] = true;
+          let params = std::mem::replace(&mut param_state[target_node], smallvec![])
+            .into_iter()
+            .map(|x| match x {
+              ParamState::Ready(x) => x,
+              _ => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+          let txn = &*txn;
+          futures.push(Box::pin(async move {
+            (
+              target_node as u32,
+              self
+                .run_node(
+                  graph_index,
+                  target_node as u32,
+                  params,
+                  txn,
+                  graph_params,
+                  recursion_depth,
+                  profiler,
+                )
+                .await,
+            )
+          }))
         }
       }
     }
+
+    // If the output node is in a region whose condition is false, the output is a typed null.
+    if output_skipped {
+      ret = g
+        .output
+        .and_then(|x| type_info.nodes[x as usize].as_ref())
+        .map(|x| self.pool.null(x));
+    }
+
+    if !g.named_outputs.is_empty() {
+      let mut elements = RedBlackTreeMapSync::new_sync();
+      for ((name, x), value) in g.named_outputs.iter().zip(named_outputs) {
+        let value = match value {
+          Some(x) => x,
+          None => self.pool.null(
+            type_info.nodes[*x as usize]
+              .as_ref()
+              .expect("inconsistency: untyped named output"),
+          ),
+        };
+        elements.insert_mut(self.vm.script.idents[*name as usize].as_str(), value);
+      }
+      ret = Some(Arc::new(VmValue::Map(VmMapValue { elements })));
+    }
     Ok(ret)
   }
 
+  #[allow(clippy::too_many_arguments)]
   async fn run_node(
     &self,
-    n: &TwGraphNode,
+    graph_index: usize,
+    node_index: u32,
+    params: Vec<Arc<VmValue<'a>>>,
+    txn: &dyn KvTransaction,
+    graph_params: &[Arc<VmValue<'a>>],
+    recursion_depth: usize,
+    profiler: Option<&GraphProfiler>,
+  ) -> Result<Option<Arc<VmValue<'a>>>> {
+    let n = &self.vm.script.graphs[graph_index].nodes[node_index as usize].0;
+
+    // Nodes that only run subgraphs do not hold a permit - otherwise a deep enough call chain
+    // would deadlock waiting for permits held by its own ancestors.
+    let _permit = if n.subgraph_references().is_empty() {
+      Some(self.concurrency_limit.acquire().await)
+    } else {
+      None
+    };
+
+    // Accesses of nodes that run subgraphs are recorded by the nodes of the subgraphs.
+    let recording;
+    let txn = match &self.access_log {
+      Some(log) if n.subgraph_references().is_empty() => {
+        recording = RecordingTransaction {
+          inner: txn,
+          log,
+          graph_index,
+          node_index,
+        };
+        &recording as &dyn KvTransaction
+      }
+      _ => txn,
+    };
+
+    // Accesses of nodes that run subgraphs are counted both by them and by the nodes of the
+    // subgraphs.
+    let node_profiler = profiler.map(|x| x.node(node_index));
+    let profiling;
+    let txn = match node_profiler {
+      Some(node) => {
+        profiling = ProfilingTransaction { inner: txn, node };
+        &profiling as &dyn KvTransaction
+      }
+      None => txn,
+    };
+    let subgraph_profiler: Option<Arc<GraphProfiler>> = node_profiler.and_then(|x| {
+      n.subgraph_references()
+        .first()
+        .map(|&i| x.subgraph(self.vm, i as usize))
+    });
+
+    let start_time = node_profiler.map(|_| Instant::now());
+    let ret = self
+      .eval_node(
+        graph_index,
+        node_index,
+        params,
+        txn,
+        graph_params,
+        recursion_depth,
+        subgraph_profiler.as_deref(),
+      )
+      .await;
+    if let (Some(node_profiler), Some(start_time)) = (node_profiler, start_time) {
+      node_profiler.record_run(start_time.elapsed());
+    }
+    ret
+  }
+
+  #[allow(clippy::too_many_arguments)]
+  async fn eval_node(
+    &self,
+    graph_index: usize,
+    node_index: u32,
     params: Vec<Arc<VmValue<'a>>>,
     txn: &dyn KvTransaction,
     graph_params: &[Arc<VmValue<'a>>],
-    type_info: Option<&VmType<&'a str>>,
     recursion_depth: usize,
+    subgraph_profiler: Option<&GraphProfiler>,
   ) -> Result<Option<Arc<VmValue<'a>>>> {
+    let n = &self.vm.script.graphs[graph_index].nodes[node_index as usize].0;
+    let type_info = self.type_info.graphs[graph_index].nodes[node_index as usize].as_ref();
+
     // Optional chain
     if n.is_optional_chained() {
       for (i, p) in params.iter().enumerate() {
@@ -325,7 +823,7 @@ impl<'a, 'b> Executor<'a, 'b> {
             i,
             p
           );
-          return Ok(type_info.map(|x| Arc::new(VmValue::Null(x.clone()))));
+          return Ok(type_info.map(|x| self.pool.null(x)));
         }
       }
     }
@@ -342,38 +840,58 @@ impl<'a, 'b> Executor<'a, 'b> {
         })
         .set_primary_key(self.vm.schema)
         .expect("inconsistency: primary key not found");
-        for n in &list.node {
+        for n in fresh_list_node(list)? {
           let primary_key_value = match &n.unwrap_table().kind {
-            VmTableValueKind::Fresh(x) => x
-              .get(primary_key)
-              .unwrap()
-              .unwrap_primitive()
-              .serialize_for_key_component(),
+            VmTableValueKind::Fresh(_) => {
+              let value = self.read_table_element(txn, n, primary_key).await?;
+              self.encode_fresh_primary_key(&list.member_ty, value.unwrap_primitive())
+            }
             _ => {
               return Err(ExecError::NotImplemented("table copy is not implemented".into()).into())
             }
           };
-          members.insert(primary_key_value.to_vec(), n.clone());
+          members.insert(primary_key_value, n.clone());
         }
         let set = VmSetValue {
           member_ty: list.member_ty.clone(),
           kind: VmSetValueKind::Fresh(members),
+          include_deleted: false,
         };
         Some(Arc::new(VmValue::Set(set)))
       }
-      TwGraphNode::BuildTable(table_ty) => {
+      TwGraphNode::BuildTable(table_ty) | TwGraphNode::BuildTableStrict(table_ty) => {
         let map = match &*params[0] {
           VmValue::Map(x) => &x.elements,
           _ => unreachable!(),
         };
-        let ty = self.vm.script.idents[*table_ty as usize].as_str();
+        let specialized_ty = self
+          .vm
+          .interner
+          .ident_type(*table_ty)
+          .expect("inconsistency: table type not found");
+        let ty = &*specialized_ty.name;
+        if matches!(n, TwGraphNode::BuildTableStrict(_)) {
+          let unknown_fields = map
+            .keys()
+            .filter(|x| !specialized_ty.fields.contains_key(**x))
+            .map(|x| format!("`{}`", x))
+            .collect::<Vec<_>>();
+          if !unknown_fields.is_empty() {
+            return Err(
+              ExecError::UnknownTableFields {
+                ty: ty.to_string(),
+                fields: unknown_fields.join(", "),
+              }
+              .into(),
+            );
+          }
+        }
         let mut table: BTreeMap<&'a str, Arc<VmValue<'a>>> = BTreeMap::new();
-        let specialized_ty = self.vm.schema.types.get(ty).unwrap();
         for (field, (ty, _)) in &specialized_ty.fields {
           let field_value = map
             .get(&**field)
             .cloned()
-            .unwrap_or_else(|| Arc::new(VmValue::Null(VmType::from(ty))));
+            .unwrap_or_else(|| self.pool.null(&VmType::from(ty)));
           table.insert(&**field, field_value);
         }
         Some(Arc::new(VmValue::Table(VmTableValue {
@@ -404,7 +922,17 @@ impl<'a, 'b> Executor<'a, 'b> {
               .cloned()
               .unwrap_or_else(|| panic!("map field not found: {}", key)),
           ),
-          VmValue::Table(table) => Some(self.read_table_element(txn, table, key).await?),
+          VmValue::Table(table) => Some(match &table.kind {
+            VmTableValueKind::Fresh(_) => self.read_table_element(txn, &params[0], key).await?,
+            VmTableValueKind::Resident(walker) => {
+              let (key, field) = self
+                .vm
+                .interner
+                .field(table.ty, *key_index)
+                .expect("inconsistency: field not found in table");
+              self.read_resident_field(txn, walker, key, field).await?
+            }
+          }),
           _ => unreachable!(),
         }
       }
@@ -424,12 +952,28 @@ impl<'a, 'b> Executor<'a, 'b> {
         match &set.kind {
           VmSetValueKind::Resident(walker) => {
             let walker = walker.enter_set(primary_key_value).unwrap();
+            if !set.include_deleted
+              && self.is_soft_delete_set(set)
+              && self.is_deleted(txn, &walker).await?
+            {
+              return Ok(type_info.map(|x| self.pool.null(x)));
+            }
             Some(Arc::new(VmValue::Table(VmTableValue {
               ty: member_ty,
               kind: VmTableValueKind::Resident(walker),
             })))
           }
-          VmSetValueKind::Fresh(_) => return Err(ExecError::FreshTableOrSetNotSupported.into()),
+          VmSetValueKind::Fresh(members) => {
+            let primary_key_raw = self.encode_fresh_primary_key(&set.member_ty, primary_key_value);
+            match self
+              .overlay
+              .get_member(&params[1], &primary_key_raw)
+              .unwrap_or_else(|| members.get(&primary_key_raw).cloned())
+            {
+              Some(x) => Some(x),
+              None => return Ok(type_info.map(|x| self.pool.null(x))),
+            }
+          }
         }
       }
       TwGraphNode::InsertIntoMap(key_index) => {
@@ -445,33 +989,73 @@ impl<'a, 'b> Executor<'a, 'b> {
       }
       TwGraphNode::InsertIntoSet => {
         // Effect node
-        let value = params[0].clone();
-        let (primary_key, _) = VmType::<&'a str>::from(&*params[1])
+        let mut value = params[0].clone();
+        let set_ty = VmType::<&'a str>::from(&*params[1]);
+        let (primary_key, _) = set_ty
           .set_primary_key(self.vm.schema)
           .expect("inconsistency: primary key not found for set member");
-        let primary_key_value = self
-          .read_table_element(txn, value.unwrap_table(), primary_key)
-          .await?;
+        let mut primary_key_value = self.read_table_element(txn, &value, primary_key).await?;
         let set = params[1].unwrap_set();
-        let primary_key_value = primary_key_value
-          .unwrap_primitive()
-          .serialize_for_key_component();
+        let is_auto = match &set.member_ty {
+          VmType::Table(x) => self
+            .vm
+            .schema
+            .types
+            .get(x.name)
+            .and_then(|x| x.auto_primary_key())
+            .is_some(),
+          _ => false,
+        };
 
         match &set.kind {
           VmSetValueKind::Resident(walker) => {
+            if is_auto {
+              let explicit = match &*primary_key_value {
+                VmValue::Primitive(PrimitiveValue::Int64(x)) => Some(*x),
+                _ => None,
+              };
+              let key = self.bump_auto_counter(txn, walker, explicit).await?;
+              if explicit.is_none() {
+                primary_key_value = Arc::new(VmValue::Primitive(PrimitiveValue::Int64(key)));
+                value = with_table_field(&value, primary_key, primary_key_value.clone())?;
+              }
+            }
+            if primary_key_value.is_null() {
+              return Err(ExecError::NullUnwrapped.into());
+            }
+            let primary_key_raw = walker.encode_primary_key(primary_key_value.unwrap_primitive());
+
             let mut fast_scan_key = walker.set_fast_scan_prefix().unwrap();
-            fast_scan_key.extend_from_slice(&primary_key_value);
+            fast_scan_key.extend_from_slice(&primary_key_raw);
             txn.put(&fast_scan_key, &[]).await?;
 
-            let walker = walker.enter_set_raw(&primary_key_value).unwrap();
+            // The old sort key value must be read before the member is overwritten.
+            if let Some((sort_key, _)) = set_ty.set_sort_key(self.vm.schema) {
+              let sort_key_value = self.read_table_element(txn, &value, sort_key).await?;
+              self
+                .delete_sort_key_entry(txn, walker, &primary_key_raw, sort_key)
+                .await?;
+              self
+                .put_sort_key_entry(txn, walker, &primary_key_raw, &sort_key_value)
+                .await?;
+            }
+
+            let walker = walker.enter_set_raw(&primary_key_raw).unwrap();
             self.walk_and_insert(txn, walker, value).await?;
           }
           VmSetValueKind::Fresh(_) => {
-            return Err(ExecError::FreshTableOrSetNotSupported.into());
+            if primary_key_value.is_null() {
+              return Err(ExecError::NullUnwrapped.into());
+            }
+            let primary_key_raw =
+              self.encode_fresh_primary_key(&set.member_ty, primary_key_value.unwrap_primitive());
+            self
+              .overlay
+              .put_member(&params[1], primary_key_raw, Some(value));
           }
         }
 
-        None
+        Some(primary_key_value)
       }
       TwGraphNode::InsertIntoTable(key_index) => {
         // Effect node
@@ -480,18 +1064,61 @@ impl<'a, 'b> Executor<'a, 'b> {
         let table = params[1].unwrap_table();
         match &table.kind {
           VmTableValueKind::Resident(walker) => {
-            let walker = walker.enter_field(key.as_str()).unwrap();
-            self.walk_and_insert(txn, walker, value).await?;
+            let field_walker = walker.enter_field(key.as_str()).unwrap();
+            self.walk_and_insert(txn, field_walker, value).await?;
+            if self.vm.schema.types[table.ty].has_timestamps() {
+              self.touch_timestamps(txn, walker, false).await?;
+            }
+          }
+          VmTableValueKind::Fresh(_) => {
+            let (key, _) = self
+              .vm
+              .interner
+              .field(table.ty, *key_index)
+              .expect("inconsistency: field not found in table");
+            self.overlay.put_field(&params[1], key, value);
+          }
+        }
+        None
+      }
+      TwGraphNode::ApplyPatch => {
+        // Effect node
+        let patch = match &*params[0] {
+          VmValue::Map(x) => x,
+          _ => unreachable!(),
+        };
+        let table = params[1].unwrap_table();
+        match &table.kind {
+          VmTableValueKind::Resident(walker) => {
+            for (key, value) in patch.elements.iter() {
+              let field_walker = walker.enter_field(key).unwrap();
+              self
+                .walk_and_insert(txn, field_walker, value.clone())
+                .await?;
+            }
+            if !patch.elements.is_empty() && self.vm.schema.types[table.ty].has_timestamps() {
+              self.touch_timestamps(txn, walker, false).await?;
+            }
           }
           VmTableValueKind::Fresh(_) => {
-            return Err(ExecError::FreshTableOrSetNotSupported.into());
+            for (key, value) in patch.elements.iter() {
+              self.overlay.put_field(&params[1], *key, value.clone());
+            }
           }
         }
         None
       }
       TwGraphNode::LoadConst(const_index) => {
-        let value = self.vm.consts[*const_index as usize].clone();
-        Some(value)
+        let value = &self.vm.consts[*const_index as usize];
+        match &**value {
+          // Effects on fresh tables and sets are keyed by the identity of the value in the
+          // overlay, so each load of such a constant must produce a distinct value.
+          VmValue::Table(_) | VmValue::Set(_) => Some(Arc::new(VmValue::from_const(
+            self.vm.schema,
+            &self.vm.script.consts[*const_index as usize],
+          )?)),
+          _ => Some(value.clone()),
+        }
       }
       TwGraphNode::LoadParam(param_index) => Some(graph_params[*param_index as usize].clone()),
       TwGraphNode::DeleteFromSet => {
@@ -505,93 +1132,281 @@ impl<'a, 'b> Executor<'a, 'b> {
         };
         match &set.kind {
           VmSetValueKind::Resident(walker) => {
+            let primary_key_raw = walker.encode_primary_key(primary_key_value);
+            if self.is_soft_delete_set(set) {
+              // The member keeps its fast scan key and sort key entry, so that it can still be
+              // read through `IncludeDeleted` until purged.
+              let member = walker.enter_set_raw(&primary_key_raw).unwrap();
+              if txn.get(member.key()).await?.is_some() && !self.is_deleted(txn, &member).await? {
+                let deleted_at = member.enter_field(DELETED_AT_FIELD).unwrap();
+                txn
+                  .put(
+                    deleted_at.key(),
+                    &rmp_serde::to_vec(&PrimitiveValue::Int64(current_millis()))?,
+                  )
+                  .await?;
+              }
+              return Ok(None);
+            }
+            if let Some((sort_key, _)) =
+              VmType::<&'a str>::from(&*params[1]).set_sort_key(self.vm.schema)
+            {
+              self
+                .delete_sort_key_entry(txn, walker, &primary_key_raw, sort_key)
+                .await?;
+            }
             self
-              .delete_entry_from_set(txn, walker, primary_key_value)
+              .delete_entry_from_set(txn, walker, &primary_key_raw)
               .await?;
             None
           }
-          VmSetValueKind::Fresh(_) => return Err(ExecError::FreshTableOrSetNotSupported.into()),
+          VmSetValueKind::Fresh(_) => {
+            let primary_key_raw = self.encode_fresh_primary_key(&set.member_ty, primary_key_value);
+            self.overlay.put_member(&params[1], primary_key_raw, None);
+            None
+          }
         }
       }
-      TwGraphNode::Eq => Some(Arc::new(VmValue::Bool(params[0] == params[1]))),
-      TwGraphNode::Ne => Some(Arc::new(VmValue::Bool(params[0] != params[1]))),
-      TwGraphNode::And => Some(Arc::new(VmValue::Bool(
-        params[0].unwrap_bool() & params[1].unwrap_bool(),
-      ))),
-      TwGraphNode::Or => Some(Arc::new(VmValue::Bool(
-        params[0].unwrap_bool() | params[1].unwrap_bool(),
-      ))),
-      TwGraphNode::Not => Some(Arc::new(VmValue::Bool(!params[0].unwrap_bool()))),
+      TwGraphNode::Eq => Some(self.pool.bool(params[0] == params[1])),
+      TwGraphNode::Ne => Some(self.pool.bool(params[0] != params[1])),
+      TwGraphNode::And => Some(
+        self
+          .pool
+          .bool(params[0].unwrap_bool() & params[1].unwrap_bool()),
+      ),
+      TwGraphNode::Or => Some(
+        self
+          .pool
+          .bool(params[0].unwrap_bool() | params[1].unwrap_bool()),
+      ),
+      TwGraphNode::Not => Some(self.pool.bool(!params[0].unwrap_bool())),
       TwGraphNode::IsPresent => {
         let walker = match &*params[0] {
           VmValue::Set(x) => match &x.kind {
-            VmSetValueKind::Fresh(_) => return Ok(Some(Arc::new(VmValue::Bool(true)))),
+            VmSetValueKind::Fresh(_) => return Ok(Some(self.pool.bool(true))),
             VmSetValueKind::Resident(x) => x,
           },
           VmValue::Table(x) => match &x.kind {
-            VmTableValueKind::Fresh(_) => return Ok(Some(Arc::new(VmValue::Bool(true)))),
+            VmTableValueKind::Fresh(_) => return Ok(Some(self.pool.bool(true))),
             VmTableValueKind::Resident(x) => x,
           },
+          // A member looked up in a fresh set.
+          VmValue::Null(_) => return Ok(Some(self.pool.bool(false))),
           _ => unreachable!(),
         };
-        Some(Arc::new(VmValue::Bool(
-          txn.get(&walker.generate_key()).await?.is_some(),
-        )))
+        Some(self.pool.bool(txn.get(walker.key()).await?.is_some()))
       }
-      TwGraphNode::IsNull => Some(Arc::new(VmValue::Bool(params[0].is_null()))),
+      TwGraphNode::IsNull => Some(self.pool.bool(params[0].is_null())),
       TwGraphNode::Nop => Some(params[0].clone()),
       TwGraphNode::Call(subgraph_index) => {
         let output = self
-          .recursively_run_graph(*subgraph_index as usize, &params, recursion_depth, txn)
+          .recursively_run_graph(
+            *subgraph_index as usize,
+            &params,
+            recursion_depth,
+            txn,
+            subgraph_profiler,
+          )
           .await?;
         output
       }
-      TwGraphNode::Add => Some(Arc::new(match (&*params[0], &*params[1]) {
-        (
-          VmValue::Primitive(PrimitiveValue::Int64(l)),
-          VmValue::Primitive(PrimitiveValue::Int64(r)),
-        ) => VmValue::Primitive(PrimitiveValue::Int64(l.wrapping_add(*r))),
-        (
-          VmValue::Primitive(PrimitiveValue::Double(l)),
-          VmValue::Primitive(PrimitiveValue::Double(r)),
-        ) => VmValue::Primitive(PrimitiveValue::Double(
-          (f64::from_bits(*l) + f64::from_bits(*r)).to_bits(),
-        )),
-        (
-          VmValue::Primitive(PrimitiveValue::String(l)),
-          VmValue::Primitive(PrimitiveValue::String(r)),
-        ) => VmValue::Primitive(PrimitiveValue::String(format!("{}{}", l, r))),
-        _ => unreachable!(),
-      })),
-      TwGraphNode::Sub => Some(Arc::new(match (&*params[0], &*params[1]) {
-        (
-          VmValue::Primitive(PrimitiveValue::Int64(l)),
-          VmValue::Primitive(PrimitiveValue::Int64(r)),
-        ) => VmValue::Primitive(PrimitiveValue::Int64(l.wrapping_sub(*r))),
-        (
-          VmValue::Primitive(PrimitiveValue::Double(l)),
-          VmValue::Primitive(PrimitiveValue::Double(r)),
-        ) => VmValue::Primitive(PrimitiveValue::Double(
-          (f64::from_bits(*l) - f64::from_bits(*r)).to_bits(),
-        )),
-        _ => unreachable!(),
-      })),
-      TwGraphNode::CreateList(member_ty) => {
-        let member_ty = self.vm.types.get(*member_ty as usize).unwrap().clone();
-        Some(Arc::new(VmValue::List(VmListValue {
-          member_ty,
-          node: ListSync::new_sync(),
-        })))
+      TwGraphNode::TryCall(subgraph_index) => {
+        match self
+          .recursively_run_graph(
+            *subgraph_index as usize,
+            &params,
+            recursion_depth,
+            txn,
+            subgraph_profiler,
+          )
+          .await
+        {
+          Ok(output) => output,
+          Err(e) => match e.downcast::<ExecError>() {
+            Ok(ExecError::ScriptThrownError(msg, _)) => Some(Arc::new(VmValue::Error(Some(msg)))),
+            Ok(ExecError::ScriptThrownNull(_)) => Some(Arc::new(VmValue::Error(None))),
+            Ok(e) => return Err(e.into()),
+            Err(e) => return Err(e),
+          },
+        }
       }
-      TwGraphNode::PrependToList => {
-        let value = params[0].clone();
+      TwGraphNode::IsError => Some(self.pool.bool(params[0].is_error())),
+      TwGraphNode::UnwrapValue => {
+        if params[0].is_error() {
+          Some(
+            self
+              .pool
+              .null(type_info.expect("inconsistency: UnwrapValue is not typed")),
+          )
+        } else {
+          Some(params[0].clone())
+        }
+      }
+      TwGraphNode::ErrorMessage => Some(Arc::new(match &*params[0] {
+        VmValue::Error(Some(msg)) => VmValue::Primitive(PrimitiveValue::String(msg.clone())),
+        _ => VmValue::Null(VmType::Primitive(PrimitiveType::String)),
+      })),
+      TwGraphNode::ListPush => {
+        // Effect node
+        let value = params[0].clone();
+        let list = match &*params[1] {
+          VmValue::List(x) => x,
+          _ => unreachable!(),
+        };
+        match &list.kind {
+          VmListValueKind::Resident(walker) => {
+            let len = self.read_list_length(txn, walker).await?;
+            self
+              .walk_and_insert(txn, walker.enter_list(len).unwrap(), value)
+              .await?;
+            txn
+              .put(walker.key(), &rmp_serde::to_vec(&(len + 1))?)
+              .await?;
+          }
+          VmListValueKind::Fresh(_) => return Err(ExecError::FreshTableOrSetNotSupported.into()),
+        }
+        None
+      }
+      TwGraphNode::ListPopBack => {
+        // Effect node
+        let list = match &*params[0] {
+          VmValue::List(x) => x,
+          _ => unreachable!(),
+        };
+        match &list.kind {
+          VmListValueKind::Resident(walker) => {
+            let len = self.read_list_length(txn, walker).await?;
+            if len == 0 {
+              Some(self.pool.null(&list.member_ty))
+            } else {
+              let element_walker = walker.enter_list(len - 1).unwrap();
+              let value = self
+                .read_leaf(txn, &element_walker, &list.member_ty)
+                .await?;
+              txn.delete(element_walker.key()).await?;
+              txn
+                .put(walker.key(), &rmp_serde::to_vec(&(len - 1))?)
+                .await?;
+              Some(value)
+            }
+          }
+          VmListValueKind::Fresh(_) => return Err(ExecError::FreshTableOrSetNotSupported.into()),
+        }
+      }
+      TwGraphNode::GetListElement => {
+        let index = match &*params[0] {
+          VmValue::Primitive(PrimitiveValue::Int64(x)) => *x,
+          _ => unreachable!(),
+        };
+        let list = match &*params[1] {
+          VmValue::List(x) => x,
+          _ => unreachable!(),
+        };
+        let element = if index < 0 {
+          None
+        } else {
+          match &list.kind {
+            VmListValueKind::Fresh(node) => node.iter().nth(index as usize).cloned(),
+            VmListValueKind::Resident(walker) => Some(
+              self
+                .read_leaf(
+                  txn,
+                  &walker.enter_list(index as u64).unwrap(),
+                  &list.member_ty,
+                )
+                .await?,
+            ),
+          }
+        };
+        Some(element.unwrap_or_else(|| self.pool.null(&list.member_ty)))
+      }
+      TwGraphNode::GetMapEntry => {
+        let key = match &*params[0] {
+          VmValue::Primitive(x) => x,
+          _ => unreachable!(),
+        };
+        let dict = match &*params[1] {
+          VmValue::Dict(x) => x,
+          _ => unreachable!(),
+        };
+        Some(
+          self
+            .read_dict_entry(txn, dict.walker.enter_set(key).unwrap(), dict)
+            .await?,
+        )
+      }
+      TwGraphNode::PutMapEntry => {
+        // Effect node
+        let value = params[1].clone();
+        let dict = match &*params[2] {
+          VmValue::Dict(x) => x,
+          _ => unreachable!(),
+        };
+        let key = dict.walker.encode_primary_key(params[0].unwrap_primitive());
+        let mut fast_scan_key = dict.walker.set_fast_scan_prefix().unwrap();
+        fast_scan_key.extend_from_slice(&key);
+        txn.put(&fast_scan_key, &[]).await?;
+
+        let walker = dict.walker.enter_set_raw(&key).unwrap();
+        self.walk_and_insert(txn, walker, value).await?;
+        None
+      }
+      TwGraphNode::DeleteMapEntry => {
+        // Effect node
+        let dict = match &*params[1] {
+          VmValue::Dict(x) => x,
+          _ => unreachable!(),
+        };
+        let key = dict.walker.encode_primary_key(params[0].unwrap_primitive());
+        self.delete_entry_from_set(txn, &dict.walker, &key).await?;
+        None
+      }
+      TwGraphNode::Add => Some(Arc::new(match (&*params[0], &*params[1]) {
+        (
+          VmValue::Primitive(PrimitiveValue::Int64(l)),
+          VmValue::Primitive(PrimitiveValue::Int64(r)),
+        ) => VmValue::Primitive(PrimitiveValue::Int64(l.wrapping_add(*r))),
+        (
+          VmValue::Primitive(PrimitiveValue::Double(l)),
+          VmValue::Primitive(PrimitiveValue::Double(r)),
+        ) => VmValue::Primitive(PrimitiveValue::double(
+          f64::from_bits(*l) + f64::from_bits(*r),
+        )),
+        (
+          VmValue::Primitive(PrimitiveValue::String(l)),
+          VmValue::Primitive(PrimitiveValue::String(r)),
+        ) => VmValue::Primitive(PrimitiveValue::String(format!("{}{}", l, r))),
+        _ => unreachable!(),
+      })),
+      TwGraphNode::Sub => Some(Arc::new(match (&*params[0], &*params[1]) {
+        (
+          VmValue::Primitive(PrimitiveValue::Int64(l)),
+          VmValue::Primitive(PrimitiveValue::Int64(r)),
+        ) => VmValue::Primitive(PrimitiveValue::Int64(l.wrapping_sub(*r))),
+        (
+          VmValue::Primitive(PrimitiveValue::Double(l)),
+          VmValue::Primitive(PrimitiveValue::Double(r)),
+        ) => VmValue::Primitive(PrimitiveValue::double(
+          f64::from_bits(*l) - f64::from_bits(*r),
+        )),
+        _ => unreachable!(),
+      })),
+      TwGraphNode::CreateList(member_ty) => {
+        let member_ty = self.vm.types.get(*member_ty as usize).unwrap().clone();
+        Some(Arc::new(VmValue::List(VmListValue {
+          member_ty,
+          kind: VmListValueKind::Fresh(ListSync::new_sync()),
+        })))
+      }
+      TwGraphNode::PrependToList => {
+        let value = params[0].clone();
         let list = match &*params[1] {
           VmValue::List(x) => x,
           _ => unreachable!(),
         };
         Some(Arc::new(VmValue::List(VmListValue {
           member_ty: list.member_ty.clone(),
-          node: list.node.push_front(value),
+          kind: VmListValueKind::Fresh(fresh_list_node(list)?.push_front(value)),
         })))
       }
       TwGraphNode::PopFromList => {
@@ -599,10 +1414,10 @@ impl<'a, 'b> Executor<'a, 'b> {
           VmValue::List(x) => x,
           _ => unreachable!(),
         };
-        Some(Arc::new(match list.node.drop_first() {
+        Some(Arc::new(match fresh_list_node(list)?.drop_first() {
           Some(x) => VmValue::List(VmListValue {
             member_ty: list.member_ty.clone(),
-            node: x,
+            kind: VmListValueKind::Fresh(x),
           }),
           None => VmValue::Null(VmType::from(&*params[0])),
         }))
@@ -613,16 +1428,91 @@ impl<'a, 'b> Executor<'a, 'b> {
           _ => unreachable!(),
         };
 
-        Some(match list.node.first() {
+        Some(match fresh_list_node(list)?.first() {
           Some(x) => x.clone(),
-          None => Arc::new(VmValue::Null(list.member_ty.clone())),
+          None => self.pool.null(&list.member_ty),
         })
       }
       TwGraphNode::Select => panic!("inconsistency: got select in run_node"),
       TwGraphNode::FilterSet(_) => {
         return Err(ExecError::NotImplemented(format!("{:?}", n)).into())
       }
-      TwGraphNode::Reduce(subgraph_index, has_range) => {
+      TwGraphNode::IncludeDeleted => {
+        let set = params[0].unwrap_set();
+        let kind = match &set.kind {
+          VmSetValueKind::Resident(x) => VmSetValueKind::Resident(x.clone()),
+          VmSetValueKind::Fresh(x) => VmSetValueKind::Fresh(x.clone()),
+        };
+        Some(Arc::new(VmValue::Set(VmSetValue {
+          member_ty: set.member_ty.clone(),
+          kind,
+          include_deleted: true,
+        })))
+      }
+      TwGraphNode::PurgeDeleted => {
+        // Effect node
+        let cutoff = match &*params[0] {
+          VmValue::Primitive(PrimitiveValue::Int64(x)) => *x,
+          _ => unreachable!(),
+        };
+        let set = params[1].unwrap_set();
+        let walker = match &set.kind {
+          VmSetValueKind::Resident(x) => x,
+          VmSetValueKind::Fresh(_) => return Err(ExecError::FreshTableOrSetNotSupported.into()),
+        };
+        let mut purged = vec![];
+        if self.is_soft_delete_set(set) {
+          let (range_prefix, range_start, range_end) = fast_scan_range(walker, None);
+          let mut it = txn
+            .scan(&range_start, &range_end, &Default::default())
+            .await?;
+          while let Some((k, _)) = it.next().await? {
+            let k = k.strip_prefix(range_prefix.as_slice()).unwrap();
+            let deleted_at = self
+              .read_deleted_at(txn, &walker.enter_set_raw(k).unwrap())
+              .await?;
+            if matches!(deleted_at, Some(x) if x < cutoff) {
+              purged.push(k.to_vec());
+            }
+          }
+        }
+        let sort_key = VmType::<&'a str>::from(&*params[1])
+          .set_sort_key(self.vm.schema)
+          .map(|x| x.0);
+        for primary_key_raw in &purged {
+          if let Some(sort_key) = sort_key {
+            self
+              .delete_sort_key_entry(txn, walker, primary_key_raw, sort_key)
+              .await?;
+          }
+          self
+            .delete_entry_from_set(txn, walker, primary_key_raw)
+            .await?;
+        }
+        Some(Arc::new(VmValue::Primitive(PrimitiveValue::Int64(
+          purged.len() as i64,
+        ))))
+      }
+      TwGraphNode::Reduce(subgraph_index, _)
+      | TwGraphNode::ReduceReverse(subgraph_index, _)
+      | TwGraphNode::ReduceBySortKey(subgraph_index, _)
+      | TwGraphNode::ReduceByPrefix(subgraph_index, _) => {
+        let (has_range, by_sort_key) = match n {
+          TwGraphNode::Reduce(_, has_range) | TwGraphNode::ReduceReverse(_, has_range) => {
+            (*has_range, false)
+          }
+          TwGraphNode::ReduceBySortKey(_, has_range) => (*has_range, true),
+          TwGraphNode::ReduceByPrefix(_, by_sort_key) => (false, *by_sort_key),
+          _ => unreachable!(),
+        };
+        let prefix = match n {
+          TwGraphNode::ReduceByPrefix(_, _) => Some(&*params[3]),
+          _ => None,
+        };
+        let scan_options = ScanOptions {
+          reverse: matches!(n, TwGraphNode::ReduceReverse(_, _)),
+          ..Default::default()
+        };
         let subgraph_param = &params[0];
         let reduce_init = &params[1];
         let list_or_set = &params[2];
@@ -631,33 +1521,64 @@ impl<'a, 'b> Executor<'a, 'b> {
         // Check list_or_set only
         if list_or_set.is_null() {
           log::trace!("optional chaining a `reduce` node because the provided list_or_set is null",);
-          return Ok(type_info.map(|x| Arc::new(VmValue::Null(x.clone()))));
+          return Ok(type_info.map(|x| self.pool.null(x)));
         }
 
         let mut subgraph_params = vec![
           subgraph_param.clone(),
           reduce_init.clone(),
-          Arc::new(VmValue::Bool(false)), // placeholder
+          self.pool.bool(false), // placeholder
         ];
         match &**list_or_set {
-          VmValue::List(list) => {
-            for n in &list.node {
-              subgraph_params[2] = n.clone();
-              let output = self
-                .recursively_run_graph(
-                  *subgraph_index as usize,
-                  &subgraph_params,
-                  recursion_depth,
-                  txn,
-                )
-                .await?
-                .expect("inconsistency: ReduceList did not get an output from subgraph");
-              if output.is_null() {
-                break;
+          VmValue::List(list) => match &list.kind {
+            VmListValueKind::Fresh(node) => {
+              for n in node {
+                subgraph_params[2] = n.clone();
+                let output = self
+                  .recursively_run_graph(
+                    *subgraph_index as usize,
+                    &subgraph_params,
+                    recursion_depth,
+                    txn,
+                    subgraph_profiler,
+                  )
+                  .await?
+                  .expect("inconsistency: ReduceList did not get an output from subgraph");
+                if output.is_null() {
+                  break;
+                }
+                subgraph_params[1] = output;
               }
-              subgraph_params[1] = output;
             }
-          }
+            VmListValueKind::Resident(walker) => {
+              let len = self.read_list_length(txn, walker).await?;
+              let mut elements = ListElementScan::new(txn, walker, len).await?;
+              for i in 0..len {
+                subgraph_params[2] = match &mut elements {
+                  Some(x) => self.decode_leaf(x.get(i).await?, &list.member_ty)?,
+                  None => {
+                    self
+                      .read_leaf(txn, &walker.enter_list(i).unwrap(), &list.member_ty)
+                      .await?
+                  }
+                };
+                let output = self
+                  .recursively_run_graph(
+                    *subgraph_index as usize,
+                    &subgraph_params,
+                    recursion_depth,
+                    txn,
+                    subgraph_profiler,
+                  )
+                  .await?
+                  .expect("inconsistency: ReduceList did not get an output from subgraph");
+                if output.is_null() {
+                  break;
+                }
+                subgraph_params[1] = output;
+              }
+            }
+          },
           VmValue::Set(set) => {
             let walker = match &set.kind {
               VmSetValueKind::Resident(x) => x,
@@ -667,28 +1588,18 @@ impl<'a, 'b> Executor<'a, 'b> {
               VmType::Table(x) => self.vm.schema.types.get(x.name).unwrap(),
               _ => unreachable!(),
             };
-            let range_prefix = walker.set_fast_scan_prefix().unwrap();
-            let mut range_start = range_prefix.clone();
-            let mut range_end = range_start.clone();
-            *range_end.last_mut().unwrap() += 1;
-
-            // If we've got a range, update our scan ranges with it...
-            if *has_range {
-              let maybe_start = &params[3];
-              let maybe_end = &params[4];
-
-              if !maybe_start.is_null() {
-                range_start
-                  .extend_from_slice(&maybe_start.unwrap_primitive().serialize_for_key_component());
-              }
-
-              if !maybe_end.is_null() {
-                // Revert the "all entries" assumption
-                *range_end.last_mut().unwrap() -= 1;
-                range_end
-                  .extend_from_slice(&maybe_end.unwrap_primitive().serialize_for_key_component());
-              }
+            if by_sort_key && !backfill::sort_index_ready(txn, walker).await? {
+              return Err(ExecError::SortIndexNotReady(walker.generate_key_pretty()).into());
             }
+            let skip_deleted = !set.include_deleted && specialized_ty.has_soft_delete();
+            let range = has_range.then(|| (&*params[3], &*params[4]));
+            let (range_prefix, range_start, range_end) = if let Some(prefix) = prefix {
+              key_prefix_range(walker, by_sort_key, prefix)
+            } else if by_sort_key {
+              sort_key_range(walker, range)
+            } else {
+              fast_scan_range(walker, range)
+            };
 
             log::trace!(
               "reduce set: scan keys: {} {}",
@@ -696,10 +1607,18 @@ impl<'a, 'b> Executor<'a, 'b> {
               base64::encode(&range_end)
             );
 
-            let mut it = txn.scan_keys(&range_start, &range_end).await?;
-            while let Some(k) = it.next().await? {
-              let k = k.strip_prefix(range_prefix.as_slice()).unwrap();
+            let mut it = txn.scan(&range_start, &range_end, &scan_options).await?;
+            while let Some((k, _)) = it.next().await? {
+              let mut k = k.strip_prefix(range_prefix.as_slice()).unwrap();
+              if by_sort_key {
+                k = split_sort_key_entry(k)
+                  .ok_or_else(|| ExecError::BadSortKeyEntry)?
+                  .1;
+              }
               let walker = walker.enter_set_raw(k).unwrap();
+              if skip_deleted && self.is_deleted(txn, &walker).await? {
+                continue;
+              }
               subgraph_params[2] = Arc::new(VmValue::Table(VmTableValue {
                 ty: &*specialized_ty.name,
                 kind: VmTableValueKind::Resident(walker),
@@ -710,6 +1629,7 @@ impl<'a, 'b> Executor<'a, 'b> {
                   &subgraph_params,
                   recursion_depth,
                   txn,
+                  subgraph_profiler,
                 )
                 .await?
                 .expect("inconsistency: ReduceList did not get an output from subgraph");
@@ -719,70 +1639,482 @@ impl<'a, 'b> Executor<'a, 'b> {
               subgraph_params[1] = output;
             }
           }
+          VmValue::Dict(dict) => {
+            let (range_prefix, range_start, range_end) = if let Some(prefix) = prefix {
+              key_prefix_range(&dict.walker, false, prefix)
+            } else {
+              fast_scan_range(&dict.walker, has_range.then(|| (&*params[3], &*params[4])))
+            };
+
+            log::trace!(
+              "reduce map: scan keys: {} {}",
+              base64::encode(&range_start),
+              base64::encode(&range_end)
+            );
+
+            let mut it = txn.scan(&range_start, &range_end, &scan_options).await?;
+            while let Some((k, _)) = it.next().await? {
+              let k = k.strip_prefix(range_prefix.as_slice()).unwrap();
+              let key = keyenc::decode_string_key(k).ok_or_else(|| ExecError::BadMapKey)?;
+              let value = self
+                .read_dict_entry(txn, dict.walker.enter_set_raw(k).unwrap(), dict)
+                .await?;
+              let mut elements = RedBlackTreeMapSync::new_sync();
+              elements.insert_mut(
+                "key",
+                Arc::new(VmValue::Primitive(PrimitiveValue::String(key))),
+              );
+              elements.insert_mut("value", value);
+              subgraph_params[2] = Arc::new(VmValue::Map(VmMapValue { elements }));
+              let output = self
+                .recursively_run_graph(
+                  *subgraph_index as usize,
+                  &subgraph_params,
+                  recursion_depth,
+                  txn,
+                  subgraph_profiler,
+                )
+                .await?
+                .expect("inconsistency: ReduceList did not get an output from subgraph");
+              if output.is_null() {
+                break;
+              }
+              subgraph_params[1] = output;
+            }
+          }
+          _ => unreachable!(),
+        }
+        Some(subgraph_params[1].clone())
+      }
+      TwGraphNode::LoopUntil(subgraph_index, max_iters) => {
+        let mut subgraph_params = vec![params[0].clone(), params[1].clone()];
+
+        // We disabled the default optional chaining behavior so we need to handle it manually here
+        // Check the accumulator only
+        if subgraph_params[1].is_null() {
+          return Ok(Some(subgraph_params[1].clone()));
+        }
+
+        for _ in 0..*max_iters {
+          let output = self
+            .recursively_run_graph(
+              *subgraph_index as usize,
+              &subgraph_params,
+              recursion_depth,
+              txn,
+              subgraph_profiler,
+            )
+            .await?
+            .expect("inconsistency: LoopUntil did not get an output from subgraph");
+          if output.is_null() {
+            break;
+          }
+          subgraph_params[1] = output;
+        }
+        Some(subgraph_params[1].clone())
+      }
+      TwGraphNode::ReduceMap(subgraph_index) => {
+        // Optional chaining is disabled, same as `Reduce`.
+        let map = match &*params[2] {
+          VmValue::Map(x) => x,
+          VmValue::Null(_) => return Ok(type_info.map(|x| self.pool.null(x))),
           _ => unreachable!(),
+        };
+        let mut subgraph_params = vec![
+          params[0].clone(),
+          params[1].clone(),
+          self.pool.bool(false), // placeholder
+          self.pool.bool(false), // placeholder
+        ];
+        for (k, v) in map.elements.iter() {
+          subgraph_params[2] = Arc::new(VmValue::Primitive(PrimitiveValue::String(k.to_string())));
+          subgraph_params[3] = v.clone();
+          let output = self
+            .recursively_run_graph(
+              *subgraph_index as usize,
+              &subgraph_params,
+              recursion_depth,
+              txn,
+              subgraph_profiler,
+            )
+            .await?
+            .expect("inconsistency: ReduceMap did not get an output from subgraph");
+          if output.is_null() {
+            break;
+          }
+          subgraph_params[1] = output;
         }
         Some(subgraph_params[1].clone())
       }
+      TwGraphNode::MapKeys | TwGraphNode::MapValues => {
+        let map = match &*params[0] {
+          VmValue::Map(x) => x,
+          _ => unreachable!(),
+        };
+        let member_ty = match type_info {
+          Some(VmType::List(x)) => (*x.ty).clone(),
+          _ => unreachable!(),
+        };
+        let mut elements = ListSync::new_sync();
+        for (k, v) in map.elements.iter().rev() {
+          elements.push_front_mut(match n {
+            TwGraphNode::MapKeys => {
+              Arc::new(VmValue::Primitive(PrimitiveValue::String(k.to_string())))
+            }
+            _ => v.clone(),
+          });
+        }
+        Some(Arc::new(VmValue::List(VmListValue {
+          member_ty,
+          kind: VmListValueKind::Fresh(elements),
+        })))
+      }
+      TwGraphNode::Merge => {
+        let left = self.overlay.resolve(&params[0]);
+        let right = self.overlay.resolve(&params[1]);
+        Some(merge_values(&left, &right)?)
+      }
+      TwGraphNode::CollectPage(page_size) => {
+        // Optional chaining is disabled, since a null cursor asks for the first page.
+        let set = self.overlay.resolve(&params[1]);
+        let set = match &*set {
+          VmValue::Set(x) => x,
+          VmValue::Null(_) => return Ok(type_info.map(|x| self.pool.null(x))),
+          _ => unreachable!(),
+        };
+        let after = match &*params[0] {
+          VmValue::Primitive(PrimitiveValue::String(x)) => Some(decode_page_cursor(x)?),
+          _ => None,
+        };
+        let page_size = *page_size as usize;
+
+        // Primary keys and members of the page, and whether there are more members after it.
+        let mut items: Vec<(Vec<u8>, Arc<VmValue<'a>>)> = Vec::new();
+        let mut more = false;
+        let total_estimate = match &set.kind {
+          VmSetValueKind::Resident(walker) => {
+            let specialized_ty = match &set.member_ty {
+              VmType::Table(x) => self.vm.schema.types.get(x.name).unwrap(),
+              _ => unreachable!(),
+            };
+            let skip_deleted = !set.include_deleted && specialized_ty.has_soft_delete();
+            let (range_prefix, mut range_start, range_end) = fast_scan_range(walker, None);
+            if let Some(after) = &after {
+              // The smallest key after the cursor.
+              range_start.extend_from_slice(after);
+              range_start.push(0x00);
+            }
+            let mut it = txn
+              .scan(&range_start, &range_end, &Default::default())
+              .await?;
+            while let Some((k, _)) = it.next().await? {
+              let k = k.strip_prefix(range_prefix.as_slice()).unwrap();
+              let walker = walker.enter_set_raw(k).unwrap();
+              if skip_deleted && self.is_deleted(txn, &walker).await? {
+                continue;
+              }
+              if items.len() == page_size {
+                more = true;
+                break;
+              }
+              items.push((
+                k.to_vec(),
+                Arc::new(VmValue::Table(VmTableValue {
+                  ty: &*specialized_ty.name,
+                  kind: VmTableValueKind::Resident(walker),
+                })),
+              ));
+            }
+            None
+          }
+          VmSetValueKind::Fresh(members) => {
+            let start = match &after {
+              Some(x) => Bound::Excluded(x.clone()),
+              None => Bound::Unbounded,
+            };
+            for (k, v) in members.range((start, Bound::Unbounded)) {
+              if items.len() == page_size {
+                more = true;
+                break;
+              }
+              items.push((k.clone(), v.clone()));
+            }
+            Some(members.len() as i64)
+          }
+        };
+        let next_cursor = if more {
+          items.last().map(|(k, _)| encode_page_cursor(k))
+        } else {
+          None
+        };
+        Some(Arc::new(build_page(
+          set.member_ty.clone(),
+          items.into_iter().map(|(_, v)| v).collect(),
+          next_cursor,
+          total_estimate,
+        )))
+      }
+      TwGraphNode::BlobLength(key_index) => {
+        let key = self.vm.script.idents[*key_index as usize].as_str();
+        let table = params[0].unwrap_table();
+        let len = match &table.kind {
+          VmTableValueKind::Resident(walker) => {
+            blob::blob_length(txn, &walker.enter_field(key)?).await?
+          }
+          VmTableValueKind::Fresh(_) => {
+            match &*self.read_table_element(txn, &params[0], key).await? {
+              VmValue::Primitive(PrimitiveValue::Bytes(x)) => Some(x.len() as u64),
+              _ => None,
+            }
+          }
+        };
+        Some(match len {
+          Some(x) => Arc::new(VmValue::Primitive(PrimitiveValue::Int64(x as i64))),
+          None => self.pool.null(&VmType::Primitive(PrimitiveType::Int64)),
+        })
+      }
+      TwGraphNode::BlobRead(key_index) => {
+        let (offset, len) = match (&*params[0], &*params[1]) {
+          (
+            VmValue::Primitive(PrimitiveValue::Int64(offset)),
+            VmValue::Primitive(PrimitiveValue::Int64(len)),
+          ) => ((*offset).max(0) as u64, (*len).max(0) as u64),
+          _ => unreachable!(),
+        };
+        let key = self.vm.script.idents[*key_index as usize].as_str();
+        let table = params[2].unwrap_table();
+        let data = match &table.kind {
+          VmTableValueKind::Resident(walker) => {
+            blob::read_blob_range(txn, &walker.enter_field(key)?, offset, len).await?
+          }
+          VmTableValueKind::Fresh(_) => {
+            match &*self.read_table_element(txn, &params[2], key).await? {
+              VmValue::Primitive(PrimitiveValue::Bytes(x)) => {
+                let start = offset.min(x.len() as u64) as usize;
+                let end = offset.saturating_add(len).min(x.len() as u64) as usize;
+                Some(x[start..end].to_vec())
+              }
+              _ => None,
+            }
+          }
+        };
+        Some(match data {
+          Some(x) => Arc::new(VmValue::Primitive(PrimitiveValue::Bytes(x))),
+          None => self.pool.null(&VmType::Primitive(PrimitiveType::Bytes)),
+        })
+      }
+      TwGraphNode::BlobAppend(key_index) => {
+        // Effect node
+        let data = match &*params[0] {
+          VmValue::Primitive(PrimitiveValue::Bytes(x)) => x,
+          _ => unreachable!(),
+        };
+        let table = params[1].unwrap_table();
+        match &table.kind {
+          VmTableValueKind::Resident(walker) => {
+            let key = self.vm.script.idents[*key_index as usize].as_str();
+            blob::append_blob(txn, &walker.enter_field(key)?, data).await?;
+            if self.vm.schema.types[table.ty].has_timestamps() {
+              self.touch_timestamps(txn, walker, false).await?;
+            }
+          }
+          VmTableValueKind::Fresh(_) => {
+            let (key, _) = self
+              .vm
+              .interner
+              .field(table.ty, *key_index)
+              .expect("inconsistency: field not found in table");
+            let mut value = match &*self.read_table_element(txn, &params[1], key).await? {
+              VmValue::Primitive(PrimitiveValue::Bytes(x)) => x.clone(),
+              _ => vec![],
+            };
+            value.extend_from_slice(data);
+            self.overlay.put_field(
+              &params[1],
+              key,
+              Arc::new(VmValue::Primitive(PrimitiveValue::Bytes(value))),
+            );
+          }
+        }
+        None
+      }
+      TwGraphNode::Assert(message_index) => {
+        // Null is treated as false
+        if params[0].is_null() || !params[0].unwrap_bool() {
+          return Err(
+            ExecError::AssertionFailed {
+              message: self.vm.script.idents[*message_index as usize].clone(),
+              graph_index,
+              node_index,
+              position: self.vm.script.node_position(graph_index, node_index),
+            }
+            .into(),
+          );
+        }
+        None
+      }
       TwGraphNode::Throw => {
         let msg = &params[0];
+        let position = self.vm.script.node_position(graph_index, node_index);
         if msg.is_null() {
-          return Err(ExecError::ScriptThrownNull.into());
+          return Err(ExecError::ScriptThrownNull(position).into());
         } else {
           return Err(
-            ExecError::ScriptThrownError(msg.unwrap_primitive().unwrap_string().clone()).into(),
+            ExecError::ScriptThrownError(msg.unwrap_primitive().unwrap_string().clone(), position)
+              .into(),
           );
         }
       }
     })
   }
 
+  /// Encodes the primary key of a member of a fresh set, as the key of `VmSetValueKind::Fresh`.
+  fn encode_fresh_primary_key(
+    &self,
+    member_ty: &VmType<&'a str>,
+    primary_key_value: &PrimitiveValue,
+  ) -> Vec<u8> {
+    let primary_key = VmType::Set(VmSetType {
+      ty: Box::new(member_ty.clone()),
+    })
+    .set_primary_key(self.vm.schema)
+    .map(|(x, _)| x);
+    let key_collation = match (member_ty, primary_key) {
+      (VmType::Table(x), Some(primary_key)) => self
+        .vm
+        .schema
+        .types
+        .get(x.name)
+        .and_then(|x| x.collation(primary_key)),
+      _ => None,
+    };
+    keyenc::encode_key(&primary_key_value.collated(key_collation)).to_vec()
+  }
+
+  /// Reads a field of a table, seeing the pending writes to fresh tables.
   async fn read_table_element(
     &self,
     txn: &dyn KvTransaction,
-    table: &VmTableValue<'a>,
+    value: &Arc<VmValue<'a>>,
     key: &str,
   ) -> Result<Arc<VmValue<'a>>> {
+    let table = value.unwrap_table();
     Ok(match &table.kind {
-      VmTableValueKind::Fresh(x) => x
-        .get(key)
-        .cloned()
-        .unwrap_or_else(|| panic!("read_table_element: key not found in table: {}", key)),
+      VmTableValueKind::Fresh(x) => match self.overlay.get_field(value, key) {
+        Some(x) => x,
+        None => x
+          .get(key)
+          .cloned()
+          .unwrap_or_else(|| panic!("read_table_element: key not found in table: {}", key)),
+      },
       VmTableValueKind::Resident(walker) => {
         let specialized_ty = self.vm.schema.types.get(table.ty).unwrap();
         let (field, _) = specialized_ty.fields.get(key).unwrap();
-        let walker = walker
-          .enter_field(key)
-          .expect("inconsistency: field not found in table");
-
-        match field {
-          x @ FieldType::Primitive(_) => {
-            // This is a primitive type - we cannot defer any more.
-            // Let's load from the database.
-            let key = walker.generate_key();
-            let raw_data: Option<PrimitiveValue> = txn
-              .get(&key)
-              .await?
-              .map(|x| rmp_serde::from_slice(&x))
-              .transpose()?;
-            Arc::new(
-              raw_data
-                .map(VmValue::Primitive)
-                .unwrap_or_else(|| VmValue::Null(VmType::from(x))),
-            )
-          }
-          FieldType::Set(member_ty) => Arc::new(VmValue::Set(VmSetValue {
-            member_ty: VmType::from(&**member_ty),
-            kind: VmSetValueKind::Resident(walker),
-          })),
-          FieldType::Table(x) => Arc::new(VmValue::Table(VmTableValue {
-            ty: &**x,
-            kind: VmTableValueKind::Resident(walker),
-          })),
-        }
+        self.read_resident_field(txn, walker, key, field).await?
+      }
+    })
+  }
+
+  async fn read_resident_field(
+    &self,
+    txn: &dyn KvTransaction,
+    walker: &Arc<PathWalker<'a>>,
+    key: &str,
+    field: &'a FieldType,
+  ) -> Result<Arc<VmValue<'a>>> {
+    let walker = walker
+      .enter_field(key)
+      .expect("inconsistency: field not found in table");
+
+    Ok(match field {
+      FieldType::Primitive(_) if walker.node().blob => match blob::read_blob(txn, &walker).await? {
+        Some(x) => Arc::new(VmValue::Primitive(PrimitiveValue::Bytes(x))),
+        None => self.pool.null(&VmType::from(field)),
+      },
+      FieldType::Primitive(_) | FieldType::Struct(_) => {
+        // This is a leaf - we cannot defer any more.
+        // Let's load from the database.
+        self.read_leaf(txn, &walker, &VmType::from(field)).await?
+      }
+      FieldType::Map(member_ty) => Arc::new(VmValue::Dict(VmDictValue {
+        member_ty: VmType::from(&**member_ty),
+        walker,
+      })),
+      FieldType::List(member_ty) => Arc::new(VmValue::List(VmListValue {
+        member_ty: VmType::from(&**member_ty),
+        kind: VmListValueKind::Resident(walker),
+      })),
+      FieldType::Set(member_ty) => Arc::new(VmValue::Set(VmSetValue {
+        member_ty: VmType::from(&**member_ty),
+        kind: VmSetValueKind::Resident(walker),
+        include_deleted: false,
+      })),
+      FieldType::Table(x) => Arc::new(VmValue::Table(VmTableValue {
+        ty: &**x,
+        kind: VmTableValueKind::Resident(walker),
+      })),
+    })
+  }
+
+  /// Loads a primitive or a packed struct.
+  async fn read_leaf(
+    &self,
+    txn: &dyn KvTransaction,
+    walker: &PathWalker<'a>,
+    ty: &VmType<&'a str>,
+  ) -> Result<Arc<VmValue<'a>>> {
+    let raw_data = get_with_rename_fallback(txn, walker).await?;
+    self.decode_leaf(raw_data, ty)
+  }
+
+  /// Decodes a primitive or a packed struct read from the KV store.
+  fn decode_leaf(
+    &self,
+    raw_data: Option<Vec<u8>>,
+    ty: &VmType<&'a str>,
+  ) -> Result<Arc<VmValue<'a>>> {
+    Ok(match (raw_data, ty) {
+      (Some(x), VmType::Primitive(_)) => {
+        Arc::new(VmValue::Primitive(PrimitiveValue::decode_stored(x)?))
       }
+      (Some(x), _) => Arc::new(VmValue::unpack(
+        ty,
+        &rmp_serde::from_slice::<PackedValue>(&x)?,
+      )),
+      (None, _) => self.pool.null(ty),
     })
   }
 
+  async fn read_dict_entry(
+    &self,
+    txn: &dyn KvTransaction,
+    walker: Arc<PathWalker<'a>>,
+    dict: &VmDictValue<'a>,
+  ) -> Result<Arc<VmValue<'a>>> {
+    match &dict.member_ty {
+      VmType::Table(x) => Ok(Arc::new(VmValue::Table(VmTableValue {
+        ty: x.name,
+        kind: VmTableValueKind::Resident(walker),
+      }))),
+      _ => self.read_leaf(txn, &walker, &dict.member_ty).await,
+    }
+  }
+
+  async fn read_list_length(
+    &self,
+    txn: &dyn KvTransaction,
+    walker: &PathWalker<'a>,
+  ) -> Result<u64> {
+    Ok(
+      txn
+        .get(walker.key())
+        .await?
+        .map(|x| rmp_serde::from_slice(&x))
+        .transpose()?
+        .unwrap_or(0),
+    )
+  }
+
   #[async_recursion]
   async fn walk_and_insert(
     &self,
@@ -790,16 +2122,30 @@ impl<'a, 'b> Executor<'a, 'b> {
     walker: Arc<PathWalker<'a>>,
     value: Arc<VmValue<'a>>,
   ) -> Result<()> {
+    // Data under the old name of a renamed leaf field is superseded by any write.
+    if let Some(key) = walker.generate_rename_fallback_key() {
+      txn.delete(&key).await?;
+    }
+
+    // Pending writes to fresh values are written along with them.
+    let value = self.overlay.resolve(&value);
+
     match &*value {
+      VmValue::Null(_) if walker.node().blob => {
+        blob::delete_blob(txn, &walker).await?;
+      }
       VmValue::Null(_) => {
-        txn.delete(&walker.generate_key()).await?;
+        txn.delete(walker.key()).await?;
+      }
+      VmValue::Primitive(PrimitiveValue::Bytes(x)) if walker.node().blob => {
+        blob::write_blob(txn, &walker, x).await?;
       }
       VmValue::Primitive(x) => {
-        let value = rmp_serde::to_vec(x).unwrap();
-        txn.put(&walker.generate_key(), &value).await?;
+        let value = x.clone().normalized().encode_stored();
+        txn.put(walker.key(), &value).await?;
       }
       VmValue::Set(x) => {
-        txn.put(&walker.generate_key(), &[]).await?;
+        txn.put(walker.key(), &[]).await?;
         match &x.kind {
           VmSetValueKind::Fresh(members) => {
             // Clear set
@@ -807,11 +2153,21 @@ impl<'a, 'b> Executor<'a, 'b> {
 
             // Need to clone this. Otherwise `async_recursion` errors
             let members = members.clone();
+            let sort_key = VmType::<&'a str>::from(&*value)
+              .set_sort_key(self.vm.schema)
+              .map(|x| x.0);
             for (primary_key_value, member) in members {
               let mut fast_scan_key = walker.set_fast_scan_prefix().unwrap();
               fast_scan_key.extend_from_slice(&primary_key_value);
               txn.put(&fast_scan_key, &[]).await?;
 
+              if let Some(sort_key) = sort_key {
+                let sort_key_value = self.read_table_element(txn, &member, sort_key).await?;
+                self
+                  .put_sort_key_entry(txn, &walker, &primary_key_value, &sort_key_value)
+                  .await?;
+              }
+
               let walker = walker.enter_set_raw(&primary_key_value).unwrap();
               self.walk_and_insert(txn, walker, member).await?;
             }
@@ -822,23 +2178,94 @@ impl<'a, 'b> Executor<'a, 'b> {
         }
       }
       VmValue::Table(x) => {
-        txn.put(&walker.generate_key(), &[]).await?;
+        txn.put(walker.key(), &[]).await?;
         match &x.kind {
           VmTableValueKind::Fresh(fields) => {
+            let specialized_ty = self.vm.schema.types.get(x.ty).unwrap();
+            for check in &specialized_ty.checks {
+              let field = |name: &str| match fields.get(name).map(|x| &**x) {
+                Some(VmValue::Primitive(x)) => Some(x.clone()),
+                _ => None,
+              };
+              if check.is_violated_by(&field) {
+                return Err(
+                  ExecError::ConstraintViolation {
+                    ty: x.ty.to_string(),
+                    check: check.to_string(),
+                  }
+                  .into(),
+                );
+              }
+            }
+
             // Need to clone this. Otherwise `async_recursion` errors
             let fields = fields.clone();
             for (k, v) in fields {
+              // Maintained below, whatever the script wrote.
+              let annotations = specialized_ty.fields[k].1.as_slice();
+              if annotations.is_timestamp() || annotations.is_deleted_at() {
+                continue;
+              }
+              debug_assert!(
+                specialized_ty
+                  .fields
+                  .get(k)
+                  .map(|(ty, _)| VmType::from(ty).is_covariant_from(&VmType::from(&*v)))
+                  .unwrap_or(false),
+                "inconsistency: walk_and_insert encountered a value of type `{}` for field `{}` of `{}`",
+                VmType::from(&*v),
+                k,
+                x.ty
+              );
               let walker = walker.enter_field(k).unwrap();
               let v = v.clone();
               self.walk_and_insert(txn, walker, v).await?;
             }
+            if specialized_ty.has_timestamps() {
+              self.touch_timestamps(txn, &walker, true).await?;
+            }
+
+            // Re-inserting a deleted member restores it.
+            if specialized_ty.has_soft_delete() {
+              txn
+                .delete(walker.enter_field(DELETED_AT_FIELD)?.key())
+                .await?;
+            }
           }
           VmTableValueKind::Resident(_) => {
             return Err(ExecError::NotImplemented("table copy is not implemented".into()).into())
           }
         }
       }
-      VmValue::Bool(_) | VmValue::Map(_) | VmValue::List(_) => {
+      VmValue::Map(_) => {
+        let value = rmp_serde::to_vec(&value.pack()?)?;
+        txn.put(walker.key(), &value).await?;
+      }
+      VmValue::List(x) => match &x.kind {
+        VmListValueKind::Fresh(node) => {
+          // Lists share the layout of sets.
+          self.delete_set(txn, &walker).await?;
+
+          // Need to clone this. Otherwise `async_recursion` errors
+          let node = node.clone();
+          let mut len = 0u64;
+          for member in node.iter() {
+            let member_walker = walker.enter_list(len).unwrap();
+            self
+              .walk_and_insert(txn, member_walker, member.clone())
+              .await?;
+            len += 1;
+          }
+          txn.put(walker.key(), &rmp_serde::to_vec(&len)?).await?;
+        }
+        VmListValueKind::Resident(_) => {
+          return Err(ExecError::NotImplemented("list copy is not implemented".into()).into())
+        }
+      },
+      VmValue::Dict(_) => {
+        return Err(ExecError::NotImplemented("map copy is not implemented".into()).into())
+      }
+      VmValue::Bool(_) | VmValue::Error(_) => {
         panic!(
           "inconsistency: walk_and_insert encountered non-storable type: {:?}",
           value
@@ -848,38 +2275,182 @@ impl<'a, 'b> Executor<'a, 'b> {
     Ok(())
   }
 
+  /// Sets `updated_at` of the `@timestamps` table at `walker` to the current time. With `created`,
+  /// also sets `created_at` if the table has none yet.
+  async fn touch_timestamps(
+    &self,
+    txn: &dyn KvTransaction,
+    walker: &Arc<PathWalker<'a>>,
+    created: bool,
+  ) -> Result<()> {
+    let now = rmp_serde::to_vec(&PrimitiveValue::Int64(current_millis()))?;
+    if created {
+      let created_at = walker.enter_field(CREATED_AT_FIELD)?;
+      if txn.get(created_at.key()).await?.is_none() {
+        txn.put(created_at.key(), &now).await?;
+      }
+    }
+    let updated_at = walker.enter_field(UPDATED_AT_FIELD)?;
+    txn.put(updated_at.key(), &now).await?;
+    Ok(())
+  }
+
   async fn delete_set(&self, txn: &dyn KvTransaction, walker: &Arc<PathWalker<'a>>) -> Result<()> {
+    // Subspace prefixes end with a subspace byte, so they always have a successor.
     let fast_scan_start_key = walker.set_fast_scan_prefix().unwrap();
-    let mut fast_scan_end_key = fast_scan_start_key.clone();
-    *fast_scan_end_key.last_mut().unwrap() += 1;
+    let fast_scan_end_key = successor_of_bounded_prefix(&fast_scan_start_key);
 
     let data_start_key = walker.set_data_prefix().unwrap();
-    let mut data_end_key = data_start_key.clone();
-    *data_end_key.last_mut().unwrap() += 1;
+    let data_end_key = successor_of_bounded_prefix(&data_start_key);
+
+    let sort_key_start_key = walker.set_sort_key_prefix().unwrap();
+    let sort_key_end_key = successor_of_bounded_prefix(&sort_key_start_key);
 
     txn
       .delete_range(&fast_scan_start_key, &fast_scan_end_key)
       .await?;
     txn.delete_range(&data_start_key, &data_end_key).await?;
+    txn
+      .delete_range(&sort_key_start_key, &sort_key_end_key)
+      .await?;
+    Ok(())
+  }
+
+  /// Deletes the sort key entry of a set member for its currently stored sort key value.
+  async fn delete_sort_key_entry(
+    &self,
+    txn: &dyn KvTransaction,
+    walker: &Arc<PathWalker<'a>>,
+    primary_key_value: &[u8],
+    sort_key: &str,
+  ) -> Result<()> {
+    let field_walker = walker
+      .enter_set_raw(primary_key_value)
+      .unwrap()
+      .enter_field(sort_key)
+      .unwrap();
+    let old_value = get_with_rename_fallback(txn, &field_walker)
+      .await?
+      .map(PrimitiveValue::decode_stored)
+      .transpose()?;
+    txn
+      .delete(
+        &walker
+          .set_sort_key_entry(old_value.as_ref(), primary_key_value)
+          .unwrap(),
+      )
+      .await?;
     Ok(())
   }
 
+  async fn put_sort_key_entry(
+    &self,
+    txn: &dyn KvTransaction,
+    walker: &Arc<PathWalker<'a>>,
+    primary_key_value: &[u8],
+    sort_key_value: &VmValue<'a>,
+  ) -> Result<()> {
+    let sort_key_value = match sort_key_value {
+      VmValue::Primitive(x) => Some(x),
+      _ => None,
+    };
+    txn
+      .put(
+        &walker
+          .set_sort_key_entry(sort_key_value, primary_key_value)
+          .unwrap(),
+        &[],
+      )
+      .await?;
+    Ok(())
+  }
+
+  /// Advances the `@auto` primary key counter of a set and returns the generated key. Explicit
+  /// keys are kept, and move the counter past them so that they are never generated later.
+  ///
+  /// Concurrent transactions inserting into the same set conflict on the counter and are retried.
+  async fn bump_auto_counter(
+    &self,
+    txn: &dyn KvTransaction,
+    walker: &Arc<PathWalker<'a>>,
+    explicit: Option<i64>,
+  ) -> Result<i64> {
+    let _permit = self.auto_key_lock.acquire().await;
+    let counter_key = walker.set_auto_counter_key().unwrap();
+    let current: i64 = txn
+      .get(&counter_key)
+      .await?
+      .map(|x| rmp_serde::from_slice(&x))
+      .transpose()?
+      .unwrap_or(0);
+    let next = match explicit {
+      Some(x) if x <= current => return Ok(x),
+      Some(x) => x,
+      None => current
+        .checked_add(1)
+        .ok_or_else(|| ExecError::AutoKeyOverflow)?,
+    };
+    txn.put(&counter_key, &rmp_serde::to_vec(&next)?).await?;
+    Ok(next)
+  }
+
+  /// Whether the members of `set` are `@soft_delete` tables.
+  fn is_soft_delete_set(&self, set: &VmSetValue<'a>) -> bool {
+    match &set.member_ty {
+      VmType::Table(x) => self
+        .vm
+        .schema
+        .types
+        .get(x.name)
+        .map(|x| x.has_soft_delete())
+        .unwrap_or(false),
+      _ => false,
+    }
+  }
+
+  /// Returns the time a `@soft_delete` set member was deleted at, if it is deleted.
+  async fn read_deleted_at(
+    &self,
+    txn: &dyn KvTransaction,
+    member: &Arc<PathWalker<'a>>,
+  ) -> Result<Option<i64>> {
+    let deleted_at = member.enter_field(DELETED_AT_FIELD)?;
+    Ok(
+      match txn
+        .get(deleted_at.key())
+        .await?
+        .map(|x| rmp_serde::from_slice(&x))
+        .transpose()?
+      {
+        Some(PrimitiveValue::Int64(x)) => Some(x),
+        _ => None,
+      },
+    )
+  }
+
+  async fn is_deleted(
+    &self,
+    txn: &dyn KvTransaction,
+    member: &Arc<PathWalker<'a>>,
+  ) -> Result<bool> {
+    Ok(self.read_deleted_at(txn, member).await?.is_some())
+  }
+
   async fn delete_entry_from_set(
     &self,
     txn: &dyn KvTransaction,
     walker: &Arc<PathWalker<'a>>,
-    primary_key_value: &PrimitiveValue,
+    primary_key_value_raw: &[u8],
   ) -> Result<()> {
-    let primary_key_value_raw = primary_key_value.serialize_for_key_component();
     let mut fast_scan_key = walker.set_fast_scan_prefix().unwrap();
     fast_scan_key.extend_from_slice(&primary_key_value_raw);
 
+    // Encoded primary keys are self-delimiting or never contain 0x00 (see `keyenc`), so this
+    // range only covers the data of this member.
     let mut data_start_key = walker.set_data_prefix().unwrap();
     data_start_key.extend_from_slice(&primary_key_value_raw);
     data_start_key.push(0x00);
-
-    let mut data_end_key = data_start_key.clone();
-    *data_end_key.last_mut().unwrap() = 0x01;
+    let data_end_key = successor_of_bounded_prefix(&data_start_key);
 
     txn.delete(&fast_scan_key).await?;
     txn.delete_range(&data_start_key, &data_end_key).await?;
@@ -887,6 +2458,249 @@ impl<'a, 'b> Executor<'a, 'b> {
   }
 }
 
+/// Returns `(prefix, start, end)` of the fast scan keys of a set or map, optionally limited to the
+/// range `[start, end)`. A null bound leaves that side of the range open.
+fn fast_scan_range(
+  walker: &PathWalker,
+  range: Option<(&VmValue, &VmValue)>,
+) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+  let range_prefix = walker.set_fast_scan_prefix().unwrap();
+  let mut range_start = range_prefix.clone();
+  let mut range_end = successor_of_bounded_prefix(&range_prefix);
+
+  // If we've got a range, update our scan ranges with it...
+  if let Some((maybe_start, maybe_end)) = range {
+    if !maybe_start.is_null() {
+      range_start.extend_from_slice(&walker.encode_primary_key(maybe_start.unwrap_primitive()));
+    }
+
+    if !maybe_end.is_null() {
+      range_end = range_prefix.clone();
+      range_end.extend_from_slice(&walker.encode_primary_key(maybe_end.unwrap_primitive()));
+    }
+  }
+  (range_prefix, range_start, range_end)
+}
+
+/// Returns `(prefix, start, end)` of the sort key entries of a set, optionally limited to the sort
+/// key range `[start, end)`. A null bound leaves that side of the range open.
+fn sort_key_range(
+  walker: &PathWalker,
+  range: Option<(&VmValue, &VmValue)>,
+) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+  let range_prefix = walker.set_sort_key_prefix().unwrap();
+  let mut range_start = range_prefix.clone();
+  let mut range_end = successor_of_bounded_prefix(&range_prefix);
+
+  if let Some((maybe_start, maybe_end)) = range {
+    if !maybe_start.is_null() {
+      range_start.extend_from_slice(&walker.encode_sort_key(maybe_start.unwrap_primitive()));
+    }
+
+    if !maybe_end.is_null() {
+      range_end = range_prefix.clone();
+      range_end.extend_from_slice(&walker.encode_sort_key(maybe_end.unwrap_primitive()));
+    }
+  }
+  (range_prefix, range_start, range_end)
+}
+
+/// Returns `(prefix, start, end)` of the fast scan keys of a set or map whose key starts with the
+/// string `prefix`, or of the sort key entries of a set whose sort key starts with it if
+/// `by_sort_key`. A null prefix matches all keys.
+fn key_prefix_range(
+  walker: &PathWalker,
+  by_sort_key: bool,
+  prefix: &VmValue,
+) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+  let range_prefix = if by_sort_key {
+    walker.set_sort_key_prefix().unwrap()
+  } else {
+    walker.set_fast_scan_prefix().unwrap()
+  };
+  let empty = PrimitiveValue::String(String::new());
+  let prefix = if prefix.is_null() {
+    &empty
+  } else {
+    prefix.unwrap_primitive()
+  };
+  let mut range_start = range_prefix.clone();
+  if by_sort_key {
+    range_start.extend_from_slice(&walker.encode_sort_key_prefix(prefix));
+  } else {
+    range_start.extend_from_slice(&walker.encode_primary_key_prefix(prefix));
+  }
+
+  // The encoded prefix starts with a type tag, so it always has a successor.
+  let range_end = successor_of_bounded_prefix(&range_start);
+  (range_prefix, range_start, range_end)
+}
+
+/// Reads the elements of a resident list in index order with a single scan, instead of a point
+/// get per element.
+struct ListElementScan<'c> {
+  it: Box<dyn KvEntryIterator + 'c>,
+  prefix_len: usize,
+
+  /// The key suffix of an element after its index.
+  suffix: Vec<u8>,
+  peeked: Option<(u64, Vec<u8>)>,
+}
+
+impl<'c> ListElementScan<'c> {
+  /// Starts a scan over the first `len` elements of the list at `walker`. Returns `None` if
+  /// elements may be stored under the keys they had before a rename, which a scan would miss.
+  async fn new(
+    txn: &'c dyn KvTransaction,
+    walker: &Arc<PathWalker<'_>>,
+    len: u64,
+  ) -> Result<Option<ListElementScan<'c>>> {
+    let member_node = walker
+      .node()
+      .set
+      .as_deref()
+      .expect("inconsistency: list without a member node");
+    if len == 0 || member_node.rename_fallback.is_some() {
+      return Ok(None);
+    }
+
+    // Element keys are `prefix | index | 0x00 | member_key`.
+    let mut suffix = vec![0x00u8];
+    suffix.extend_from_slice(&member_node.key);
+    let first = walker.enter_list(0)?.generate_key();
+    let prefix = &first[..first.len() - 8 - suffix.len()];
+    let end = walker.enter_list(len)?.generate_key();
+    let it = txn
+      .scan(
+        prefix,
+        &end,
+        &ScanOptions {
+          values: true,
+          ..Default::default()
+        },
+      )
+      .await?;
+    Ok(Some(Self {
+      it,
+      prefix_len: prefix.len(),
+      suffix,
+      peeked: None,
+    }))
+  }
+
+  /// Returns the stored value of the element at `index`. Must be called with increasing indices.
+  async fn get(&mut self, index: u64) -> Result<Option<Vec<u8>>> {
+    loop {
+      if let Some((i, _)) = &self.peeked {
+        if *i > index {
+          return Ok(None);
+        }
+        if *i == index {
+          return Ok(self.peeked.take().map(|x| x.1));
+        }
+        self.peeked = None;
+      }
+      match self.it.next().await? {
+        Some((k, v)) => {
+          let rest = &k[self.prefix_len..];
+          if rest.len() == 8 + self.suffix.len() && rest[8..] == self.suffix[..] {
+            self.peeked = Some((BigEndian::read_u64(&rest[..8]), v));
+          }
+        }
+        None => return Ok(None),
+      }
+    }
+  }
+}
+
+pub(crate) fn current_millis() -> i64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|x| x.as_millis() as i64)
+    .unwrap_or(0)
+}
+
+/// Returns a copy of a fresh table with `field` set to `value`.
+fn with_table_field<'a>(
+  table: &VmValue<'a>,
+  field: &'a str,
+  value: Arc<VmValue<'a>>,
+) -> Result<Arc<VmValue<'a>>> {
+  let table = table.unwrap_table();
+  match &table.kind {
+    VmTableValueKind::Fresh(fields) => {
+      let mut fields = fields.clone();
+      fields.insert(field, value);
+      Ok(Arc::new(VmValue::Table(VmTableValue {
+        ty: table.ty,
+        kind: VmTableValueKind::Fresh(fields),
+      })))
+    }
+    VmTableValueKind::Resident(_) => {
+      Err(ExecError::NotImplemented("table copy is not implemented".into()).into())
+    }
+  }
+}
+
+/// Merges `right` into `left`, recursing into the maps and fresh tables present on both sides.
+fn merge_values<'a>(left: &Arc<VmValue<'a>>, right: &Arc<VmValue<'a>>) -> Result<Arc<VmValue<'a>>> {
+  let patch = match &**right {
+    VmValue::Map(x) => &x.elements,
+    VmValue::Table(VmTableValue {
+      kind: VmTableValueKind::Fresh(x),
+      ..
+    }) => {
+      return merge_values(
+        left,
+        &Arc::new(VmValue::Map(VmMapValue {
+          elements: x.iter().map(|(k, v)| (*k, v.clone())).collect(),
+        })),
+      )
+    }
+    VmValue::Table(_) => return Err(ExecError::FreshTableOrSetNotSupported.into()),
+    _ => unreachable!(),
+  };
+  let merge_field = |l: Option<&Arc<VmValue<'a>>>, r: &Arc<VmValue<'a>>| match l {
+    Some(l) if is_mergeable_value(l) && is_mergeable_value(r) => merge_values(l, r),
+    _ => Ok(r.clone()),
+  };
+  Ok(Arc::new(match &**left {
+    VmValue::Map(x) => {
+      let mut elements = x.elements.clone();
+      for (k, v) in patch {
+        elements.insert_mut(*k, merge_field(x.elements.get(k), v)?);
+      }
+      VmValue::Map(VmMapValue { elements })
+    }
+    VmValue::Table(VmTableValue {
+      ty,
+      kind: VmTableValueKind::Fresh(x),
+    }) => {
+      let mut fields = x.clone();
+      for (k, v) in patch {
+        fields.insert(*k, merge_field(x.get(k), v)?);
+      }
+      VmValue::Table(VmTableValue {
+        ty: *ty,
+        kind: VmTableValueKind::Fresh(fields),
+      })
+    }
+    VmValue::Table(_) => return Err(ExecError::FreshTableOrSetNotSupported.into()),
+    _ => unreachable!(),
+  }))
+}
+
+fn is_mergeable_value(x: &VmValue) -> bool {
+  matches!(x, VmValue::Map(_) | VmValue::Table(_))
+}
+
+fn fresh_list_node<'a, 'c>(list: &'c VmListValue<'a>) -> Result<&'c ListSync<Arc<VmValue<'a>>>> {
+  match &list.kind {
+    VmListValueKind::Fresh(x) => Ok(x),
+    VmListValueKind::Resident(_) => Err(ExecError::ResidentListNotSupported.into()),
+  }
+}
+
 fn generate_fire_rules(g: &TwGraph) -> FireRuleTable {
   let mut m: FireRuleTable = (0..g.nodes.len()).map(|_| smallvec![]).collect();
   for (target_node, (_, in_edges, precondition)) in g.nodes.iter().enumerate() {
@@ -928,6 +2742,7 @@ pub fn generate_root_map<'a>(
           Arc::new(VmValue::Set(VmSetValue {
             member_ty: VmType::from(&**x),
             kind: VmSetValueKind::Resident(PathWalker::from_export(plan, &**field_name).unwrap()),
+            include_deleted: false,
           })),
         );
       }
@@ -4,13 +4,15 @@ use bumpalo::Bump;
 
 use crate::{
   data::{
+    kv::{KeyValueStore, KvError},
     mock_kv::MockKv,
     treewalker::{
+      asm::codegen::compile_twscript,
       bytecode::{TwGraph, TwGraphNode, TwScript},
-      exec::{generate_root_map, Executor},
+      exec::{generate_root_map, ExecError, Executor},
       typeck::GlobalTyckContext,
       vm::TwVm,
-      vm_value::{VmConst, VmType},
+      vm_value::{VmConst, VmSetValueKind, VmTableValueKind, VmType},
     },
     value::PrimitiveValue,
   },
@@ -64,6 +66,8 @@ async fn basic_exec() {
         (TwGraphNode::InsertIntoTable(1), vec![8, 1], None), // 0
       ],
       output: Some(7),
+      named_outputs: vec![],
+      param_constraints: vec![],
       output_type: Some(1),
       param_types: vec![0],
     }],
@@ -97,6 +101,7 @@ async fn basic_exec() {
       ),
       VmType::Primitive(PrimitiveType::Int64),
     ],
+    source_map: None,
   };
   let vm = TwVm::new(&schema, &plan, &script).unwrap();
   let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
@@ -133,6 +138,8 @@ async fn basic_exec() {
         (TwGraphNode::GetField(1), vec![1], None), // 2
       ],
       output: Some(2),
+      named_outputs: vec![],
+      param_constraints: vec![],
       output_type: Some(1),
       param_types: vec![0],
     }],
@@ -140,6 +147,7 @@ async fn basic_exec() {
     consts: vec![],
     idents: vec!["some_item".into(), "name".into()],
     types: vec![VmType::Schema, VmType::Primitive(PrimitiveType::String)],
+    source_map: None,
   };
   let vm = TwVm::new(&schema, &plan, &script).unwrap();
   let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
@@ -195,6 +203,8 @@ async fn set_queries() {
         (TwGraphNode::InsertIntoSet, vec![6, 7], None),    // 8
       ],
       output: None,
+      named_outputs: vec![],
+      param_constraints: vec![],
       output_type: None,
       param_types: vec![0],
     }],
@@ -210,6 +220,7 @@ async fn set_queries() {
       "Item<>".into(),
     ],
     types: vec![VmType::Schema],
+    source_map: None,
   };
   let vm = TwVm::new(&schema, &plan, &script).unwrap();
   let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
@@ -233,6 +244,8 @@ async fn set_queries() {
         (TwGraphNode::GetField(1), vec![3], None),      // 4
       ],
       output: Some(4),
+      named_outputs: vec![],
+      param_constraints: vec![],
       output_type: Some(1),
       param_types: vec![0],
     }],
@@ -240,6 +253,7 @@ async fn set_queries() {
     consts: vec![VmConst::Primitive(PrimitiveValue::String("test_id".into()))],
     idents: vec!["some_item".into(), "name".into()],
     types: vec![VmType::Schema, VmType::Primitive(PrimitiveType::String)],
+    source_map: None,
   };
   let vm = TwVm::new(&schema, &plan, &script).unwrap();
   let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
@@ -265,6 +279,8 @@ async fn set_queries() {
         (TwGraphNode::DeleteFromSet, vec![1, 2], None), // 3
       ],
       output: None,
+      named_outputs: vec![],
+      param_constraints: vec![],
       output_type: None,
       param_types: vec![0],
     }],
@@ -275,6 +291,7 @@ async fn set_queries() {
       VmType::<String>::from(&schema),
       VmType::Primitive(PrimitiveType::String),
     ],
+    source_map: None,
   };
   let vm = TwVm::new(&schema, &plan, &script).unwrap();
   let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
@@ -296,6 +313,8 @@ async fn set_queries() {
         (TwGraphNode::GetField(1), vec![3], None),      // 4
       ],
       output: Some(4),
+      named_outputs: vec![],
+      param_constraints: vec![],
       output_type: Some(1),
       param_types: vec![0],
     }],
@@ -303,6 +322,7 @@ async fn set_queries() {
     consts: vec![VmConst::Primitive(PrimitiveValue::String("test_id".into()))],
     idents: vec!["some_item".into(), "name".into()],
     types: vec![VmType::Schema, VmType::Primitive(PrimitiveType::String)],
+    source_map: None,
   };
   let vm = TwVm::new(&schema, &plan, &script).unwrap();
   let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
@@ -317,3 +337,347 @@ async fn set_queries() {
     _ => unreachable!(),
   };
 }
+
+#[tokio::test]
+async fn runs_share_caller_transaction() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+  type Store {
+    value: int64,
+  }
+  export Store store;
+  "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let script = compile_twscript(
+    r#"
+  export graph read(root: schema): int64 {
+    return root.store.value;
+  }
+  export graph write(root: schema, x: int64) {
+    t_insert(value) root.store x;
+  }
+  "#,
+  )
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let kv = MockKv::new();
+  let root = Arc::new(generate_root_map(&schema, &plan).unwrap());
+  let int64 = |x| Arc::new(VmValue::Primitive(PrimitiveValue::Int64(x)));
+  let mut executor = Executor::new(&vm, &kv, &type_info);
+  let read = vm.lookup_exported_graph_by_name("read").unwrap();
+  let write = vm.lookup_exported_graph_by_name("write").unwrap();
+
+  executor
+    .run_graph(write, &[root.clone(), int64(1)])
+    .await
+    .unwrap();
+  let txn = kv.begin_transaction().await.unwrap();
+  let output = executor
+    .run_graph_in_txn(read, &[root.clone()], &*txn)
+    .await
+    .unwrap()
+    .unwrap();
+  assert_eq!(*output, *int64(1));
+
+  executor
+    .run_graph(write, &[root.clone(), int64(2)])
+    .await
+    .unwrap();
+  let output = executor
+    .run_graph_in_txn(read, &[root.clone()], &*txn)
+    .await
+    .unwrap()
+    .unwrap();
+  assert_eq!(*output, *int64(1));
+  let output = executor.run_graph(read, &[root]).await.unwrap().unwrap();
+  assert_eq!(*output, *int64(2));
+}
+
+#[tokio::test]
+async fn runs_at_past_versions() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+  type Store {
+    value: int64,
+  }
+  export Store store;
+  "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let script = compile_twscript(
+    r#"
+  export graph read(root: schema): int64 {
+    return root.store.value;
+  }
+  export graph write(root: schema, x: int64) {
+    t_insert(value) root.store x;
+  }
+  "#,
+  )
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let kv = MockKv::new();
+  let root = Arc::new(generate_root_map(&schema, &plan).unwrap());
+  let int64 = |x| Arc::new(VmValue::Primitive(PrimitiveValue::Int64(x)));
+  let mut executor = Executor::new(&vm, &kv, &type_info);
+  let read = vm.lookup_exported_graph_by_name("read").unwrap();
+  let write = vm.lookup_exported_graph_by_name("write").unwrap();
+
+  executor
+    .run_graph(write, &[root.clone(), int64(1)])
+    .await
+    .unwrap();
+  let version = kv
+    .begin_transaction()
+    .await
+    .unwrap()
+    .read_version()
+    .await
+    .unwrap()
+    .unwrap();
+  executor
+    .run_graph(write, &[root.clone(), int64(2)])
+    .await
+    .unwrap();
+
+  let output = executor
+    .run_graph_at(read, &[root.clone()], version)
+    .await
+    .unwrap()
+    .unwrap();
+  assert_eq!(*output, *int64(1));
+  let output = executor
+    .run_graph(read, &[root.clone()])
+    .await
+    .unwrap()
+    .unwrap();
+  assert_eq!(*output, *int64(2));
+
+  let e = executor
+    .run_graph_at(write, &[root.clone(), int64(3)], version)
+    .await
+    .unwrap_err();
+  assert!(matches!(
+    e.downcast_ref::<ExecError>(),
+    Some(ExecError::WriteInSnapshotRead)
+  ));
+  let e = executor
+    .run_graph_at(read, &[root], version + 100)
+    .await
+    .unwrap_err();
+  assert!(matches!(
+    e.downcast_ref::<KvError>(),
+    Some(KvError::SnapshotUnavailable(_))
+  ));
+}
+
+#[tokio::test]
+async fn effects_on_fresh_values() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+  type Item {
+    @primary
+    id: string,
+    name: string,
+  }
+  export set<Item> items;
+  "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let script = compile_twscript(
+    r#"
+  export graph read_back(root: schema): string {
+    s = build_set $ create_list(Item);
+    k = s_insert s $ build_table(Item) $ m_insert(id) "a" $ m_insert(name) "first" create_map;
+    return (point_get s k).name;
+  }
+  export graph rename(root: schema): Item {
+    item = build_table(Item) $ m_insert(id) "a" $ m_insert(name) "first" create_map;
+    t_insert(name) item "second";
+    return item;
+  }
+  "#,
+  )
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let kv = MockKv::new();
+  let root = Arc::new(generate_root_map(&schema, &plan).unwrap());
+  let string = |x: &str| VmValue::Primitive(PrimitiveValue::String(x.into()));
+  let mut executor = Executor::new(&vm, &kv, &type_info);
+
+  let read_back = vm.lookup_exported_graph_by_name("read_back").unwrap();
+  let output = executor
+    .run_graph(read_back, &[root.clone()])
+    .await
+    .unwrap()
+    .unwrap();
+  assert_eq!(*output, string("first"));
+
+  // Pending effects are applied to the output.
+  let rename = vm.lookup_exported_graph_by_name("rename").unwrap();
+  let output = executor.run_graph(rename, &[root]).await.unwrap().unwrap();
+  match &*output {
+    VmValue::Table(x) => match &x.kind {
+      VmTableValueKind::Fresh(x) => assert_eq!(*x["name"], string("second")),
+      _ => unreachable!(),
+    },
+    _ => unreachable!(),
+  }
+}
+
+#[tokio::test]
+async fn lookups_in_fresh_sets() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+  type Item {
+    @primary
+    id: string,
+    name: string,
+  }
+  export set<Item> items;
+  "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let script = compile_twscript(
+    r#"
+  graph item(id: string, name: string): Item {
+    return build_table(Item) $ m_insert(id) id $ m_insert(name) name create_map;
+  }
+  graph items(): set<Item> {
+    a = call(item) ["a", "first"];
+    b = call(item) ["b", "second"];
+    return build_set $ a : b : create_list(Item);
+  }
+  export graph name(root: schema, id: string): string {
+    s = call(items) [];
+    return (point_get s id).name;
+  }
+  export graph present(root: schema, id: string): bool {
+    s = call(items) [];
+    return is_present $ point_get s id;
+  }
+  "#,
+  )
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let kv = MockKv::new();
+  let root = Arc::new(generate_root_map(&schema, &plan).unwrap());
+  let string = |x: &str| Arc::new(VmValue::Primitive(PrimitiveValue::String(x.into())));
+  let mut executor = Executor::new(&vm, &kv, &type_info);
+  let name = vm.lookup_exported_graph_by_name("name").unwrap();
+  let present = vm.lookup_exported_graph_by_name("present").unwrap();
+
+  let output = executor
+    .run_graph(name, &[root.clone(), string("b")])
+    .await
+    .unwrap()
+    .unwrap();
+  assert_eq!(*output, *string("second"));
+  let output = executor
+    .run_graph(name, &[root.clone(), string("c")])
+    .await
+    .unwrap()
+    .unwrap();
+  assert!(output.is_null());
+
+  let output = executor
+    .run_graph(present, &[root.clone(), string("a")])
+    .await
+    .unwrap()
+    .unwrap();
+  assert_eq!(*output, VmValue::Bool(true));
+  let output = executor
+    .run_graph(present, &[root, string("c")])
+    .await
+    .unwrap()
+    .unwrap();
+  assert_eq!(*output, VmValue::Bool(false));
+}
+
+#[tokio::test]
+async fn identical_fresh_literals_are_distinct() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+  type Item {
+    @primary
+    id: string,
+  }
+  export set<Item> items;
+  "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let source = r#"
+  export graph main(root: schema): set<Item> {
+    a = empty_set<Item>;
+    b = empty_set<Item>;
+    s_insert a $ build_table(Item) $ m_insert(id) "x" create_map;
+    return b;
+  }
+  "#;
+  let kv = MockKv::new();
+  let root = Arc::new(generate_root_map(&schema, &plan).unwrap());
+
+  // The literals share a constant, and with optimization also a node.
+  for &optimized in &[false, true] {
+    let mut script = compile_twscript(source).unwrap();
+    let vm = if optimized {
+      TwVm::new_optimized(&schema, &plan, &mut script).unwrap()
+    } else {
+      TwVm::new(&schema, &plan, &script).unwrap()
+    };
+    let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+    let mut executor = Executor::new(&vm, &kv, &type_info);
+    let output = executor
+      .run_graph(0, &[root.clone()])
+      .await
+      .unwrap()
+      .unwrap();
+    match &*output {
+      VmValue::Set(x) => match &x.kind {
+        VmSetValueKind::Fresh(x) => assert!(x.is_empty(), "optimized: {}", optimized),
+        _ => unreachable!(),
+      },
+      _ => unreachable!(),
+    }
+  }
+}
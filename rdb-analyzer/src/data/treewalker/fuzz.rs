@@ -0,0 +1,340 @@
+use std::{collections::BTreeSet, sync::Arc};
+
+use anyhow::Result;
+use arbitrary::Unstructured;
+use bumpalo::Bump;
+
+use crate::{
+  data::{mock_kv::MockKv, value::PrimitiveValue},
+  schema::{
+    compile::{compile, CompiledSchema, PrimitiveType},
+    grammar::parse,
+  },
+  storage_plan::{planner::generate_plan_for_schema, StoragePlan},
+};
+
+use super::{
+  bytecode::{TwGraph, TwGraphNode, TwScript},
+  exec::{generate_root_map, Executor},
+  typeck::GlobalTyckContext,
+  vm::TwVm,
+  vm_value::{VmConst, VmListType, VmSetType, VmTableType, VmType, VmValue},
+};
+
+/// The schema that generated scripts run against.
+pub const FUZZ_SCHEMA: &str = r#"
+type Item {
+  @primary
+  id: string,
+  value: int64,
+  ratio: double,
+  tags: list<string>,
+  counts: map<string, int64>,
+  children: set<Item>,
+}
+export set<Item> items;
+export Item single;
+export int64 counter;
+"#;
+
+/// Bounds on the size of generated scripts.
+///
+/// Subgraphs only reference graphs with a higher index, so the number of nodes run by a script is
+/// bounded by about `max_nodes ^ max_graphs`.
+#[derive(Clone, Debug)]
+pub struct ScriptLimits {
+  pub max_graphs: usize,
+  pub max_nodes: usize,
+  pub max_params: usize,
+  pub max_in_edges: usize,
+  pub max_consts: usize,
+  pub max_loop_iterations: u32,
+}
+
+impl Default for ScriptLimits {
+  fn default() -> Self {
+    Self {
+      max_graphs: 3,
+      max_nodes: 24,
+      max_params: 2,
+      max_in_edges: 3,
+      max_consts: 8,
+      max_loop_iterations: 16,
+    }
+  }
+}
+
+/// Identifier and type pools that generated nodes index into.
+struct Pools {
+  idents: Vec<String>,
+  types: Vec<VmType<String>>,
+  num_consts: usize,
+  num_graphs: usize,
+}
+
+/// Generates a bounded, arbitrary script against `schema`.
+///
+/// Indices in the generated nodes are mostly in bounds and in edges always point to earlier
+/// nodes, so that most scripts get past the structural checks and reach typeck and the executor.
+/// Graph 0 is the entry and takes the schema as its first param.
+pub fn arbitrary_script(
+  u: &mut Unstructured,
+  schema: &CompiledSchema,
+  limits: &ScriptLimits,
+) -> arbitrary::Result<TwScript> {
+  let mut idents = BTreeSet::new();
+  for name in schema.exports.keys() {
+    idents.insert(name.to_string());
+  }
+  for (name, ty) in &schema.types {
+    idents.insert(name.to_string());
+    for field_name in ty.fields.keys() {
+      idents.insert(field_name.to_string());
+    }
+  }
+  idents.insert("key".to_string());
+  idents.insert("value".to_string());
+
+  let mut types = vec![
+    VmType::Schema,
+    VmType::Bool,
+    VmType::Unknown,
+    VmType::Primitive(PrimitiveType::Int64),
+    VmType::Primitive(PrimitiveType::Double),
+    VmType::Primitive(PrimitiveType::String),
+    VmType::Primitive(PrimitiveType::Bytes),
+    VmType::List(VmListType {
+      ty: Box::new(VmType::Primitive(PrimitiveType::String)),
+    }),
+  ];
+  for name in schema.types.keys() {
+    let table = VmType::Table(VmTableType {
+      name: name.to_string(),
+    });
+    types.push(VmType::Set(VmSetType {
+      ty: Box::new(table.clone()),
+    }));
+    types.push(table);
+  }
+
+  let mut consts = vec![];
+  for _ in 0..u.int_in_range(0..=limits.max_consts)? {
+    consts.push(match u.int_in_range(0..=5u8)? {
+      0 => VmConst::Primitive(PrimitiveValue::Int64(u.arbitrary()?)),
+      1 => VmConst::Primitive(PrimitiveValue::Double(u.arbitrary()?)),
+      2 => VmConst::Primitive(PrimitiveValue::String(u.arbitrary()?)),
+      3 => VmConst::Primitive(PrimitiveValue::Bytes(u.arbitrary()?)),
+      4 => VmConst::Bool(u.arbitrary()?),
+      _ => VmConst::Null(u.choose(&types)?.clone()),
+    });
+  }
+
+  let pools = Pools {
+    idents: idents.into_iter().collect(),
+    types,
+    num_consts: consts.len(),
+    num_graphs: u.int_in_range(1..=limits.max_graphs.max(1))?,
+  };
+
+  let mut graphs = vec![];
+  for graph_index in 0..pools.num_graphs {
+    let mut param_types = vec![];
+    if graph_index == 0 {
+      param_types.push(0);
+    }
+    for _ in 0..u.int_in_range(0..=limits.max_params)? {
+      param_types.push(index(u, pools.types.len())?);
+    }
+    let output_type = if u.arbitrary()? {
+      Some(index(u, pools.types.len())?)
+    } else {
+      None
+    };
+
+    let mut nodes = vec![];
+    for i in 0..u.int_in_range(0..=limits.max_nodes)? {
+      let node = arbitrary_node(u, &pools, graph_index, param_types.len(), limits)?;
+      let mut in_edges = vec![];
+      let mut precondition = None;
+      if i != 0 {
+        for _ in 0..u.int_in_range(0..=limits.max_in_edges)? {
+          in_edges.push(index(u, i)?);
+        }
+        if u.ratio(1, 8)? {
+          precondition = Some(index(u, i)?);
+        }
+      }
+      nodes.push((node, in_edges, precondition));
+    }
+    let output = if !nodes.is_empty() && u.arbitrary()? {
+      Some(index(u, nodes.len())?)
+    } else {
+      None
+    };
+    let mut named_outputs = vec![];
+    if !nodes.is_empty() && u.ratio(1, 8)? {
+      for _ in 0..u.int_in_range(1..=3u8)? {
+        named_outputs.push((index(u, pools.idents.len())?, index(u, nodes.len())?));
+      }
+    }
+
+    graphs.push(TwGraph {
+      name: format!("g{}", graph_index),
+      exported: graph_index == 0,
+      nodes,
+      output,
+      named_outputs,
+      param_constraints: vec![],
+      param_types,
+      output_type,
+    });
+  }
+
+  Ok(TwScript {
+    graphs,
+    entry: 0,
+    consts,
+    idents: pools.idents,
+    types: pools.types,
+    source_map: None,
+  })
+}
+
+fn arbitrary_node(
+  u: &mut Unstructured,
+  pools: &Pools,
+  graph_index: usize,
+  num_params: usize,
+  limits: &ScriptLimits,
+) -> arbitrary::Result<TwGraphNode> {
+  use TwGraphNode as N;
+  let ident = |u: &mut Unstructured| index(u, pools.idents.len());
+  let ty = |u: &mut Unstructured| index(u, pools.types.len());
+
+  // Only call graphs after this one. The entry graph is never called.
+  let subgraph = |u: &mut Unstructured| -> arbitrary::Result<u32> {
+    if graph_index + 1 < pools.num_graphs {
+      u.int_in_range(graph_index as u32 + 1..=pools.num_graphs as u32 - 1)
+    } else {
+      // Out of bounds, and rejected by the verifier.
+      Ok(pools.num_graphs as u32)
+    }
+  };
+
+  Ok(match u.int_in_range(0..=57u8)? {
+    0 => N::LoadParam(index(u, num_params)?),
+    1 => N::LoadConst(index(u, pools.num_consts)?),
+    2 => N::BuildTable(ident(u)?),
+    3 => N::BuildSet,
+    4 => N::CreateMap,
+    5 => N::CreateList(ty(u)?),
+    6 => N::PrependToList,
+    7 => N::PopFromList,
+    8 => N::ListHead,
+    9 => N::Reduce(subgraph(u)?, u.arbitrary()?),
+    10 => N::LoopUntil(
+      subgraph(u)?,
+      u.int_in_range(0..=limits.max_loop_iterations)?,
+    ),
+    11 => N::GetField(ident(u)?),
+    12 => N::GetSetElement,
+    13 => N::FilterSet(subgraph(u)?),
+    14 => N::InsertIntoMap(ident(u)?),
+    15 => N::InsertIntoTable(ident(u)?),
+    16 => N::InsertIntoSet,
+    17 => N::DeleteFromSet,
+    18 => N::DeleteFromMap(ident(u)?),
+    19 => N::Eq,
+    20 => N::Ne,
+    21 => N::And,
+    22 => N::Or,
+    23 => N::Not,
+    24 => N::Select,
+    25 => N::IsPresent,
+    26 => N::IsNull,
+    27 => N::Nop,
+    28 => N::Call(subgraph(u)?),
+    29 => N::Add,
+    30 => N::Sub,
+    31 => N::Throw,
+    32 => N::Assert(ident(u)?),
+    33 => N::TryCall(subgraph(u)?),
+    34 => N::IsError,
+    35 => N::UnwrapValue,
+    36 => N::ErrorMessage,
+    37 => N::ListPush,
+    38 => N::ListPopBack,
+    39 => N::GetListElement,
+    40 => N::GetMapEntry,
+    41 => N::PutMapEntry,
+    42 => N::DeleteMapEntry,
+    43 => N::IncludeDeleted,
+    44 => N::PurgeDeleted,
+    45 => N::ReduceByPrefix(subgraph(u)?, u.arbitrary()?),
+    46 => N::ReduceMap(subgraph(u)?),
+    47 => N::MapKeys,
+    48 => N::MapValues,
+    49 => N::Merge,
+    50 => N::ApplyPatch,
+    51 => N::CollectPage(u.arbitrary()?),
+    52 => N::BlobLength(ident(u)?),
+    53 => N::BlobRead(ident(u)?),
+    54 => N::BlobAppend(ident(u)?),
+    55 => N::ReduceReverse(subgraph(u)?, u.arbitrary()?),
+    56 => N::BuildTableStrict(ident(u)?),
+    _ => N::ReduceBySortKey(subgraph(u)?, u.arbitrary()?),
+  })
+}
+
+/// An index below `len`, or 0 if `len` is 0.
+fn index(u: &mut Unstructured, len: usize) -> arbitrary::Result<u32> {
+  if len == 0 {
+    Ok(0)
+  } else {
+    u.int_in_range(0..=len as u32 - 1)
+  }
+}
+
+/// Compiles `FUZZ_SCHEMA` and generates a fresh storage plan for it.
+pub fn fuzz_schema() -> Result<(CompiledSchema, StoragePlan)> {
+  let alloc = Bump::new();
+  let ast = parse(&alloc, FUZZ_SCHEMA)?;
+  let schema = compile(&ast)?;
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema)?;
+  Ok((schema, plan))
+}
+
+/// Verifies, typechecks and runs the entry graph of `script` against `kv`, with the root map as
+/// its first param.
+///
+/// Errors are expected for most generated scripts. Panics are bugs: scripts that pass typeck must
+/// never make the executor panic, or write a value whose type does not match the schema.
+pub async fn run_script(
+  schema: &CompiledSchema,
+  plan: &StoragePlan,
+  script: &TwScript,
+  kv: &MockKv,
+) -> Result<()> {
+  let vm = TwVm::new(schema, plan, script)?;
+  let type_info = GlobalTyckContext::new(&vm)?.typeck()?;
+  let mut executor = Executor::new(&vm, kv, &type_info);
+  let mut params = vec![Arc::new(generate_root_map(schema, plan)?)];
+  for ty in type_info.graphs[0].params.iter().skip(1) {
+    params.push(Arc::new(VmValue::Null(ty.clone())));
+  }
+  executor.run_graph(0, &params).await?;
+  Ok(())
+}
+
+/// Runs one fuzz input: generates a script from `data` and runs it against an empty `MockKv`.
+pub fn run_fuzz_input(schema: &CompiledSchema, plan: &StoragePlan, data: &[u8]) {
+  let mut u = Unstructured::new(data);
+  let script = match arbitrary_script(&mut u, schema, &ScriptLimits::default()) {
+    Ok(x) => x,
+    Err(_) => return,
+  };
+  let kv = MockKv::new();
+  if let Err(e) = futures::executor::block_on(run_script(schema, plan, &script, &kv)) {
+    log::debug!("fuzz input rejected: {}", e);
+  }
+}
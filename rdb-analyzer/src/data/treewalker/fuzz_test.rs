@@ -0,0 +1,15 @@
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use super::fuzz::{fuzz_schema, run_fuzz_input};
+
+#[test]
+fn generated_scripts_do_not_panic() {
+  let _ = pretty_env_logger::try_init();
+  let (schema, plan) = fuzz_schema().unwrap();
+  let mut rng = StdRng::seed_from_u64(42);
+  for _ in 0..256 {
+    let len = rng.gen_range(0..1024);
+    let data = (0..len).map(|_| rng.gen()).collect::<Vec<u8>>();
+    run_fuzz_input(&schema, &plan, &data);
+  }
+}
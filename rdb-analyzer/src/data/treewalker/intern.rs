@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+
+use crate::schema::compile::{CompiledSchema, FieldType, SpecializedType};
+
+use super::bytecode::TwScript;
+
+/// Schema types and script idents mapped to compact ids once per VM, so that hot nodes do not
+/// search the schema by name.
+pub struct TwInterner<'a> {
+  type_ids: HashMap<&'a str, u32>,
+  types: Vec<&'a SpecializedType>,
+
+  /// For each ident, the first ident with the same name. Field tables are keyed by these.
+  canonical_idents: Vec<u32>,
+
+  /// For each ident, the id of the schema type it names.
+  ident_types: Vec<Option<u32>>,
+
+  /// For each type id, the fields that are named by an ident of the script.
+  fields: Vec<HashMap<u32, (&'a str, &'a FieldType)>>,
+}
+
+impl<'a> TwInterner<'a> {
+  pub fn new(schema: &'a CompiledSchema, script: &'a TwScript) -> Self {
+    let mut type_ids = HashMap::new();
+    let mut types = vec![];
+    for (name, ty) in &schema.types {
+      type_ids.insert(&**name, types.len() as u32);
+      types.push(ty);
+    }
+
+    let mut ident_ids: HashMap<&str, u32> = HashMap::new();
+    let canonical_idents = script
+      .idents
+      .iter()
+      .enumerate()
+      .map(|(i, x)| *ident_ids.entry(x.as_str()).or_insert(i as u32))
+      .collect();
+    let ident_types = script
+      .idents
+      .iter()
+      .map(|x| type_ids.get(x.as_str()).copied())
+      .collect();
+    let fields = types
+      .iter()
+      .map(|ty| {
+        ty.fields
+          .iter()
+          .filter_map(|(name, (field, _))| {
+            ident_ids
+              .get(&**name)
+              .map(|&ident| (ident, (&**name, field)))
+          })
+          .collect()
+      })
+      .collect();
+
+    Self {
+      type_ids,
+      types,
+      canonical_idents,
+      ident_types,
+      fields,
+    }
+  }
+
+  /// The schema type named by `ident`.
+  pub fn ident_type(&self, ident: u32) -> Option<&'a SpecializedType> {
+    self
+      .ident_types
+      .get(ident as usize)
+      .copied()
+      .flatten()
+      .map(|x| self.types[x as usize])
+  }
+
+  /// The field named by `ident` in the type `ty`, with its name borrowed from the schema.
+  pub fn field(&self, ty: &str, ident: u32) -> Option<(&'a str, &'a FieldType)> {
+    let ty = *self.type_ids.get(ty)?;
+    let ident = *self.canonical_idents.get(ident as usize)?;
+    self.fields[ty as usize].get(&ident).copied()
+  }
+}
@@ -0,0 +1,61 @@
+use bumpalo::Bump;
+
+use crate::schema::{
+  compile::{compile, FieldType, PrimitiveType},
+  grammar::parse,
+};
+
+use super::{bytecode::TwScript, intern::TwInterner};
+
+#[test]
+fn interned_lookups() {
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+  type Item {
+    id: string,
+    child: Child,
+  }
+  type Child {
+    id: int64,
+  }
+  export Item item;
+  "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  let script = TwScript {
+    graphs: vec![],
+    entry: 0,
+    consts: vec![],
+    idents: vec![
+      "Item".into(),
+      "id".into(),
+      "child".into(),
+      "id".into(),
+      "missing".into(),
+    ],
+    types: vec![],
+    source_map: None,
+  };
+  let interner = TwInterner::new(&schema, &script);
+
+  assert_eq!(&*interner.ident_type(0).unwrap().name, "Item");
+  assert!(interner.ident_type(1).is_none());
+  assert!(interner.ident_type(5).is_none());
+
+  // Duplicated idents resolve to the same field.
+  for &ident in &[1, 3] {
+    let (name, ty) = interner.field("Child", ident).unwrap();
+    assert_eq!(name, "id");
+    assert!(matches!(ty, FieldType::Primitive(PrimitiveType::Int64)));
+  }
+  assert!(matches!(
+    interner.field("Item", 2).unwrap().1,
+    FieldType::Table(_)
+  ));
+  assert!(interner.field("Child", 2).is_none());
+  assert!(interner.field("Item", 4).is_none());
+  assert!(interner.field("Missing", 1).is_none());
+}
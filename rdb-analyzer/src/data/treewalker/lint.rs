@@ -0,0 +1,301 @@
+//! Checks for suspicious but well-typed patterns in scripts.
+
+use std::{
+  collections::{BTreeMap, BTreeSet},
+  fmt::Display,
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+  bytecode::{TwGraphNode, TwSourcePosition},
+  typeck::GlobalTypeInfo,
+  vm::TwVm,
+  vm_value::VmType,
+};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LintRule {
+  /// An effect node, or a call to a subgraph with effects, in a graph marked read-only.
+  EffectInReadOnlyGraph,
+
+  /// A graph param that is never loaded. Params of reduce, filter and loop subgraphs are exempt.
+  UnusedParam,
+
+  /// A reduce without a range over a persisted set, which visits every member.
+  UnboundedReduce,
+
+  /// `Eq` or `Ne` between values of different types, e.g. a map and a map with extra fields.
+  MismatchedComparison,
+
+  /// A throw whose message may be null.
+  NullableThrow,
+}
+
+impl LintRule {
+  pub fn name(&self) -> &'static str {
+    match self {
+      LintRule::EffectInReadOnlyGraph => "effect_in_read_only_graph",
+      LintRule::UnusedParam => "unused_param",
+      LintRule::UnboundedReduce => "unbounded_reduce",
+      LintRule::MismatchedComparison => "mismatched_comparison",
+      LintRule::NullableThrow => "nullable_throw",
+    }
+  }
+
+  pub fn default_severity(&self) -> Severity {
+    match self {
+      LintRule::EffectInReadOnlyGraph => Severity::Error,
+      LintRule::UnusedParam => Severity::Warning,
+      LintRule::UnboundedReduce => Severity::Info,
+      LintRule::MismatchedComparison => Severity::Warning,
+      LintRule::NullableThrow => Severity::Warning,
+    }
+  }
+}
+
+impl Display for LintRule {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}", self.name())
+  }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+  Info,
+  Warning,
+  Error,
+}
+
+impl Display for Severity {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Severity::Info => write!(f, "info"),
+      Severity::Warning => write!(f, "warning"),
+      Severity::Error => write!(f, "error"),
+    }
+  }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct LintConfig {
+  /// Severities replacing the defaults. A rule mapped to `None` is turned off.
+  #[serde(default)]
+  pub severities: BTreeMap<LintRule, Option<Severity>>,
+
+  /// Names of the graphs that must not write to the store.
+  #[serde(default)]
+  pub read_only_graphs: BTreeSet<String>,
+}
+
+impl LintConfig {
+  /// The severity of a rule, or `None` if it is turned off.
+  pub fn severity(&self, rule: LintRule) -> Option<Severity> {
+    match self.severities.get(&rule) {
+      Some(x) => *x,
+      None => Some(rule.default_severity()),
+    }
+  }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LintWarning {
+  pub rule: LintRule,
+  pub severity: Severity,
+  pub graph_index: u32,
+  pub graph_name: String,
+
+  /// The offending node. `None` for findings in the graph signature.
+  pub node_index: Option<u32>,
+
+  /// The position of the node in the assembly source, if the script has a source map.
+  pub position: Option<TwSourcePosition>,
+
+  pub message: String,
+}
+
+impl Display for LintWarning {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "{}[{}]: graph {} `{}`",
+      self.severity, self.rule, self.graph_index, self.graph_name
+    )?;
+    if let Some(i) = self.node_index {
+      write!(f, ", node {}", i)?;
+    }
+    if let Some(x) = &self.position {
+      write!(f, " at {}", x)?;
+    }
+    write!(f, ": {}", self.message)
+  }
+}
+
+/// Lints a typechecked script. Findings are ordered by graph and node.
+pub fn lint(vm: &TwVm, type_info: &GlobalTypeInfo, config: &LintConfig) -> Vec<LintWarning> {
+  let script = vm.script;
+  let writes = graphs_with_effects(vm);
+
+  // The params of reduce, filter and loop subgraphs are fixed by the caller.
+  let mut callbacks = vec![false; script.graphs.len()];
+  for g in &script.graphs {
+    for (node, _, _) in &g.nodes {
+      if !matches!(node, TwGraphNode::Call(_) | TwGraphNode::TryCall(_)) {
+        for x in node.subgraph_references() {
+          if let Some(x) = callbacks.get_mut(x as usize) {
+            *x = true;
+          }
+        }
+      }
+    }
+  }
+  let mut out = vec![];
+
+  for (graph_index, g) in script.graphs.iter().enumerate() {
+    let info = &type_info.graphs[graph_index];
+    let read_only = config.read_only_graphs.contains(&g.name);
+    let mut push = |rule: LintRule, node_index: Option<u32>, message: String| {
+      if let Some(severity) = config.severity(rule) {
+        out.push(LintWarning {
+          rule,
+          severity,
+          graph_index: graph_index as u32,
+          graph_name: g.name.clone(),
+          node_index,
+          position: node_index.and_then(|x| script.node_position(graph_index, x)),
+          message,
+        });
+      }
+    };
+
+    let mut param_used = vec![false; g.param_types.len()];
+    for (node_index, (node, in_edges, _)) in g.nodes.iter().enumerate() {
+      let node_index = node_index as u32;
+      let ty_of = |x: u32| info.nodes.get(x as usize).and_then(|x| x.as_ref());
+
+      if let TwGraphNode::LoadParam(x) = node {
+        if let Some(x) = param_used.get_mut(*x as usize) {
+          *x = true;
+        }
+      }
+
+      if read_only {
+        if node.is_effect() {
+          push(
+            LintRule::EffectInReadOnlyGraph,
+            Some(node_index),
+            format!("{:?} writes to the store", node),
+          );
+        } else if let Some(x) = node
+          .subgraph_references()
+          .into_iter()
+          .find(|x| writes[*x as usize])
+        {
+          push(
+            LintRule::EffectInReadOnlyGraph,
+            Some(node_index),
+            format!(
+              "subgraph `{}` writes to the store",
+              script.graphs[x as usize].name
+            ),
+          );
+        }
+      }
+
+      match node {
+        TwGraphNode::Reduce(_, false)
+        | TwGraphNode::ReduceReverse(_, false)
+        | TwGraphNode::ReduceBySortKey(_, false) => {
+          let set = match in_edges.last() {
+            Some(x) => *x,
+            None => continue,
+          };
+          let persisted = !matches!(
+            g.nodes.get(set as usize),
+            Some((TwGraphNode::BuildSet, _, _))
+          );
+          if let (Some(VmType::Set(x)), true) = (ty_of(set), persisted) {
+            push(
+              LintRule::UnboundedReduce,
+              Some(node_index),
+              format!("reduce over all members of `set<{}>`", x.ty),
+            );
+          }
+        }
+        TwGraphNode::Eq | TwGraphNode::Ne if in_edges.len() == 2 => {
+          if let (Some(left), Some(right)) = (ty_of(in_edges[0]), ty_of(in_edges[1])) {
+            if left != right {
+              push(
+                LintRule::MismatchedComparison,
+                Some(node_index),
+                format!("comparing `{}` with `{}`", left, right),
+              );
+            }
+          }
+        }
+        TwGraphNode::Throw if in_edges.len() == 1 => {
+          if info.nullable.get(in_edges[0] as usize).copied() == Some(true) {
+            push(
+              LintRule::NullableThrow,
+              Some(node_index),
+              "the thrown message may be null".into(),
+            );
+          }
+        }
+        _ => {}
+      }
+    }
+
+    for (i, used) in param_used.iter().enumerate() {
+      if !used && !callbacks[graph_index] {
+        let name = script
+          .source_map
+          .as_ref()
+          .and_then(|x| x.param_names.get(graph_index))
+          .and_then(|x| x.get(i))
+          .map(|x| format!("`{}`", x))
+          .unwrap_or_else(|| format!("{}", i));
+        push(
+          LintRule::UnusedParam,
+          None,
+          format!("param {} is never used", name),
+        );
+      }
+    }
+  }
+
+  out
+}
+
+/// Whether each graph of the script writes to the store, directly or through its subgraphs.
+fn graphs_with_effects(vm: &TwVm) -> Vec<bool> {
+  let graphs = &vm.script.graphs;
+  let mut writes = graphs
+    .iter()
+    .map(|g| g.nodes.iter().any(|(x, _, _)| x.is_effect()))
+    .collect::<Vec<_>>();
+
+  // Propagate through calls until a fixed point, since the call graph may have cycles.
+  loop {
+    let mut changed = false;
+    for (i, g) in graphs.iter().enumerate() {
+      if writes[i] {
+        continue;
+      }
+      let calls_writer = g.nodes.iter().any(|(x, _, _)| {
+        x.subgraph_references()
+          .into_iter()
+          .any(|x| writes.get(x as usize).copied().unwrap_or(false))
+      });
+      if calls_writer {
+        writes[i] = true;
+        changed = true;
+      }
+    }
+    if !changed {
+      return writes;
+    }
+  }
+}
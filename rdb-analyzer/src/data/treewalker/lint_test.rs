@@ -0,0 +1,132 @@
+use crate::{
+  data::treewalker::{
+    asm::codegen::compile_twscript,
+    lint::{lint, LintConfig, LintRule, LintWarning, Severity},
+    typeck::GlobalTyckContext,
+    vm::TwVm,
+  },
+  storage_plan::planner::generate_plan_for_schema,
+  testutil::compile_schema_source,
+};
+
+const SCHEMA: &str = r#"
+type Item {
+  @primary
+  id: string,
+  value: int64,
+}
+export set<Item> items;
+"#;
+
+const SCRIPT: &str = r#"
+export graph get(root: schema, id: string, unused: int64): int64 {
+  return (point_get root.items id).value;
+}
+export graph sum(root: schema): int64 {
+  return reduce(add) create_map 0 root.items;
+}
+export graph sum_fresh(root: schema): int64 {
+  return reduce(add) create_map 0 $ build_set $ create_list(Item);
+}
+graph add(ctx: map{}, acc: int64, item: Item): int64 {
+  return acc + item.value;
+}
+export graph compare(a: map{x: int64}, b: map{x: int64, y: int64}): bool {
+  return a == b;
+}
+export graph fail(root: schema, id: string) {
+  throw (point_get root.items id).id;
+}
+export graph write(root: schema, id: string) {
+  call(insert) [root, id];
+}
+graph insert(root: schema, id: string) {
+  s_insert root.items $ build_table(Item) $ m_insert(id) id $ m_insert(value) 0 create_map;
+}
+"#;
+
+fn run_lint(config: &LintConfig) -> Vec<LintWarning> {
+  let schema = compile_schema_source(SCHEMA);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let script = compile_twscript(SCRIPT).unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  lint(&vm, &type_info, config)
+}
+
+fn findings(warnings: &[LintWarning]) -> Vec<(LintRule, &str)> {
+  warnings
+    .iter()
+    .map(|x| (x.rule, x.graph_name.as_str()))
+    .collect()
+}
+
+#[test]
+fn default_rules() {
+  let _ = pretty_env_logger::try_init();
+  let warnings = run_lint(&Default::default());
+  for w in &warnings {
+    println!("{}", w);
+  }
+  assert_eq!(
+    findings(&warnings),
+    vec![
+      (LintRule::UnusedParam, "get"),
+      (LintRule::UnboundedReduce, "sum"),
+      (LintRule::MismatchedComparison, "compare"),
+      (LintRule::NullableThrow, "fail"),
+    ]
+  );
+
+  let unused = &warnings[0];
+  assert_eq!(unused.severity, Severity::Warning);
+  assert!(unused.node_index.is_none());
+  assert!(unused.message.contains("`unused`"));
+
+  let reduce = &warnings[1];
+  assert_eq!(reduce.severity, Severity::Info);
+  assert_eq!(reduce.position.unwrap().line, 6);
+  assert!(reduce
+    .to_string()
+    .starts_with("info[unbounded_reduce]: graph 1 `sum`"));
+}
+
+#[test]
+fn effects_in_read_only_graphs() {
+  let config = LintConfig {
+    read_only_graphs: vec!["get", "write", "insert"]
+      .into_iter()
+      .map(String::from)
+      .collect(),
+    ..Default::default()
+  };
+  let warnings = run_lint(&config)
+    .into_iter()
+    .filter(|x| x.rule == LintRule::EffectInReadOnlyGraph)
+    .collect::<Vec<_>>();
+  assert_eq!(
+    findings(&warnings),
+    vec![
+      (LintRule::EffectInReadOnlyGraph, "write"),
+      (LintRule::EffectInReadOnlyGraph, "insert"),
+    ]
+  );
+  assert!(warnings.iter().all(|x| x.severity == Severity::Error));
+  assert!(warnings[0].message.contains("subgraph `insert`"));
+}
+
+#[test]
+fn configured_severities() {
+  let mut config = LintConfig::default();
+  config.severities.insert(LintRule::UnusedParam, None);
+  config
+    .severities
+    .insert(LintRule::UnboundedReduce, Some(Severity::Error));
+  let warnings = run_lint(&config);
+  assert!(warnings.iter().all(|x| x.rule != LintRule::UnusedParam));
+  let reduce = warnings
+    .iter()
+    .find(|x| x.rule == LintRule::UnboundedReduce)
+    .unwrap();
+  assert_eq!(reduce.severity, Severity::Error);
+}
@@ -1,13 +1,60 @@
 pub mod asm;
 pub mod bytecode;
+pub mod clientgen;
+pub mod conflict;
 pub mod exec;
+#[cfg(feature = "fuzzing")]
+pub mod fuzz;
+pub mod intern;
+pub mod lint;
+pub mod opt;
+mod overlay;
+mod pool;
+pub mod profile;
+mod semaphore;
 pub mod serialize;
+pub mod signature;
+pub mod testing;
 pub mod typeck;
+pub mod usage;
 pub mod vm;
 pub mod vm_value;
 
+#[cfg(test)]
+mod bytecode_test;
+
 #[cfg(test)]
 mod typeck_test;
 
 #[cfg(test)]
 mod exec_test;
+
+#[cfg(test)]
+mod opt_test;
+
+#[cfg(test)]
+mod intern_test;
+
+#[cfg(test)]
+mod conflict_test;
+
+#[cfg(test)]
+mod usage_test;
+
+#[cfg(test)]
+mod profile_test;
+
+#[cfg(test)]
+mod serialize_test;
+
+#[cfg(test)]
+mod signature_test;
+
+#[cfg(test)]
+mod testing_test;
+
+#[cfg(test)]
+mod lint_test;
+
+#[cfg(all(test, feature = "fuzzing"))]
+mod fuzz_test;
@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+
+use crate::data::value::PrimitiveValue;
+
+use super::{
+  bytecode::{TwGraph, TwGraphNode, TwScript, TwSourcePosition},
+  vm_value::VmConst,
+};
+
+/// Optimizes a script in place.
+///
+/// Run by `TwVm::new_optimized`. This must run before the VM is created since the VM borrows the
+/// script. The passes are:
+///
+/// - Folding of operators whose operands are all unconditional constants.
+/// - Merging of duplicate `LoadConst` and `GetField` nodes. Loads of table and set constants are
+///   not merged since each of them is a distinct value.
+/// - Elimination of nodes whose outputs are never consumed and that are not effects.
+pub fn optimize(script: &mut TwScript) {
+  let mut const_pool: HashMap<VmConst, u32> = script
+    .consts
+    .iter()
+    .enumerate()
+    .map(|(i, x)| (x.clone(), i as u32))
+    .collect();
+
+  for (i, g) in script.graphs.iter_mut().enumerate() {
+    // Malformed graphs are left as-is for typeck to report.
+    if !is_well_formed(g) {
+      continue;
+    }
+    fold_and_merge(g, &mut script.consts, &mut const_pool);
+    let positions = script.source_map.as_mut().and_then(|x| x.graphs.get_mut(i));
+    eliminate_dead_nodes(g, positions);
+  }
+}
+
+fn is_well_formed(g: &TwGraph) -> bool {
+  g.output
+    .iter()
+    .chain(g.named_outputs.iter().map(|(_, x)| x))
+    .all(|x| (*x as usize) < g.nodes.len())
+    && g
+      .nodes
+      .iter()
+      .enumerate()
+      .all(|(i, (_, in_edges, precondition))| {
+        in_edges
+          .iter()
+          .chain(precondition.iter())
+          .all(|x| (*x as usize) < i)
+      })
+}
+
+#[derive(Hash, Eq, PartialEq)]
+enum MergeKey {
+  LoadConst(u32, Option<u32>),
+  GetField(u32, u32, Option<u32>),
+}
+
+fn fold_and_merge(
+  g: &mut TwGraph,
+  consts: &mut Vec<VmConst>,
+  const_pool: &mut HashMap<VmConst, u32>,
+) {
+  // Maps each node to its merged representative.
+  let mut replace: Vec<u32> = (0..g.nodes.len() as u32).collect();
+  let mut seen: HashMap<MergeKey, u32> = HashMap::new();
+
+  for i in 0..g.nodes.len() {
+    {
+      let (_, in_edges, precondition) = &mut g.nodes[i];
+      for x in in_edges.iter_mut() {
+        *x = replace[*x as usize];
+      }
+      if let Some(x) = precondition {
+        *x = replace[*x as usize];
+      }
+    }
+
+    // Constant folding. Conditional operands are not folded to preserve skip semantics.
+    let operands = g.nodes[i]
+      .1
+      .iter()
+      .map(|x| match &g.nodes[*x as usize] {
+        (TwGraphNode::LoadConst(c), _, None) => Some(&consts[*c as usize]),
+        _ => None,
+      })
+      .collect::<Option<Vec<_>>>();
+    let folded = match operands {
+      Some(x) if !x.is_empty() => fold(&g.nodes[i].0, &x),
+      _ => None,
+    };
+    if let Some(folded) = folded {
+      let index = *const_pool.entry(folded.clone()).or_insert_with(|| {
+        consts.push(folded);
+        (consts.len() - 1) as u32
+      });
+      g.nodes[i].0 = TwGraphNode::LoadConst(index);
+      g.nodes[i].1.clear();
+    }
+
+    // Merge duplicates
+    let (node, in_edges, precondition) = &g.nodes[i];
+    let key = match node {
+      // Each load of a table or set constant is a distinct fresh value that effects can apply to.
+      TwGraphNode::LoadConst(x) if !is_fresh_container(&consts[*x as usize]) => {
+        MergeKey::LoadConst(*x, *precondition)
+      }
+      TwGraphNode::GetField(x) if in_edges.len() == 1 => {
+        MergeKey::GetField(*x, in_edges[0], *precondition)
+      }
+      _ => continue,
+    };
+    if let Some(x) = seen.get(&key) {
+      replace[i] = *x;
+    } else {
+      seen.insert(key, i as u32);
+    }
+  }
+
+  for x in g
+    .output
+    .iter_mut()
+    .chain(g.named_outputs.iter_mut().map(|(_, x)| x))
+  {
+    *x = replace[*x as usize];
+  }
+}
+
+fn is_fresh_container(x: &VmConst) -> bool {
+  matches!(x, VmConst::Table(_) | VmConst::Set(_))
+}
+
+fn eliminate_dead_nodes(g: &mut TwGraph, positions: Option<&mut Vec<Option<TwSourcePosition>>>) {
+  let mut live = vec![false; g.nodes.len()];
+  for x in g
+    .output
+    .iter()
+    .chain(g.named_outputs.iter().map(|(_, x)| x))
+  {
+    live[*x as usize] = true;
+  }
+
+  // Nodes are topologically sorted, so a single reverse pass is enough.
+  for i in (0..g.nodes.len()).rev() {
+    let (node, in_edges, precondition) = &g.nodes[i];
+    if has_side_effect(node) {
+      live[i] = true;
+    }
+    if !live[i] {
+      continue;
+    }
+    for x in in_edges {
+      live[*x as usize] = true;
+    }
+    if let Some(x) = precondition {
+      live[*x as usize] = true;
+    }
+  }
+
+  let mut new_index: Vec<Option<u32>> = vec![None; g.nodes.len()];
+  let mut nodes = Vec::with_capacity(g.nodes.len());
+  for (i, (node, in_edges, precondition)) in std::mem::take(&mut g.nodes).into_iter().enumerate() {
+    if !live[i] {
+      continue;
+    }
+    new_index[i] = Some(nodes.len() as u32);
+    let in_edges = in_edges
+      .into_iter()
+      .map(|x| new_index[x as usize].unwrap())
+      .collect();
+    let precondition = precondition.map(|x| new_index[x as usize].unwrap());
+    nodes.push((node, in_edges, precondition));
+  }
+  g.nodes = nodes;
+  g.output = g.output.map(|x| new_index[x as usize].unwrap());
+  for (_, x) in &mut g.named_outputs {
+    *x = new_index[*x as usize].unwrap();
+  }
+
+  if let Some(positions) = positions {
+    if positions.len() == live.len() {
+      *positions = std::mem::take(positions)
+        .into_iter()
+        .zip(live.iter())
+        .filter(|(_, live)| **live)
+        .map(|(x, _)| x)
+        .collect();
+    }
+  }
+}
+
+fn has_side_effect(n: &TwGraphNode) -> bool {
+  match n {
+    _ if n.is_effect() => true,
+    TwGraphNode::Throw | TwGraphNode::Assert(_) => true,
+
+    // Subgraphs may contain effects.
+    _ => !n.subgraph_references().is_empty(),
+  }
+}
+
+fn fold(n: &TwGraphNode, operands: &[&VmConst]) -> Option<VmConst> {
+  use PrimitiveValue as P;
+  use VmConst as C;
+
+  Some(match (n, operands) {
+    (TwGraphNode::Add, [C::Primitive(P::Int64(l)), C::Primitive(P::Int64(r))]) => {
+      C::Primitive(P::Int64(l.wrapping_add(*r)))
+    }
+    (TwGraphNode::Add, [C::Primitive(P::Double(l)), C::Primitive(P::Double(r))]) => {
+      C::Primitive(P::double(f64::from_bits(*l) + f64::from_bits(*r)))
+    }
+    (TwGraphNode::Add, [C::Primitive(P::String(l)), C::Primitive(P::String(r))]) => {
+      C::Primitive(P::String(format!("{}{}", l, r)))
+    }
+    (TwGraphNode::Sub, [C::Primitive(P::Int64(l)), C::Primitive(P::Int64(r))]) => {
+      C::Primitive(P::Int64(l.wrapping_sub(*r)))
+    }
+    (TwGraphNode::Sub, [C::Primitive(P::Double(l)), C::Primitive(P::Double(r))]) => {
+      C::Primitive(P::double(f64::from_bits(*l) - f64::from_bits(*r)))
+    }
+    (TwGraphNode::Eq, [l @ C::Primitive(_), r @ C::Primitive(_)])
+    | (TwGraphNode::Eq, [l @ C::Bool(_), r @ C::Bool(_)]) => C::Bool(l == r),
+    (TwGraphNode::Ne, [l @ C::Primitive(_), r @ C::Primitive(_)])
+    | (TwGraphNode::Ne, [l @ C::Bool(_), r @ C::Bool(_)]) => C::Bool(l != r),
+    (TwGraphNode::And, [C::Bool(l), C::Bool(r)]) => C::Bool(*l & *r),
+    (TwGraphNode::Or, [C::Bool(l), C::Bool(r)]) => C::Bool(*l | *r),
+    (TwGraphNode::Not, [C::Bool(x)]) => C::Bool(!*x),
+    _ => return None,
+  })
+}
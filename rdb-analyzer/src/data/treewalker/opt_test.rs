@@ -0,0 +1,116 @@
+use std::sync::Arc;
+
+use bumpalo::Bump;
+
+use crate::{
+  data::{
+    mock_kv::MockKv,
+    treewalker::{
+      asm::codegen::compile_twscript,
+      bytecode::TwGraphNode,
+      exec::{generate_root_map, Executor},
+      opt::optimize,
+      typeck::GlobalTyckContext,
+      vm::TwVm,
+      vm_value::VmValue,
+    },
+    value::PrimitiveValue,
+  },
+  schema::{compile::compile, grammar::parse},
+  storage_plan::planner::generate_plan_for_schema,
+};
+
+#[tokio::test]
+async fn fold_merge_and_eliminate() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+  type Item {
+    a: int64,
+    b: int64,
+  }
+  export Item item;
+  "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+
+  let mut script = compile_twscript(
+    r#"
+    graph main(root: schema): int64 {
+      unused = root.item.b;
+      x = 1 + 2 + 3;
+      return x + (root.item.a ?? 0) + (root.item.a ?? 0);
+    }
+    "#,
+  )
+  .unwrap();
+  let num_nodes_before = script.graphs[0].nodes.len();
+  optimize(&mut script);
+  let g = &script.graphs[0];
+  println!("{:?}", g);
+  assert!(g.nodes.len() < num_nodes_before);
+
+  // The source map follows the eliminated nodes.
+  let positions = &script.source_map.as_ref().unwrap().graphs[0];
+  assert_eq!(positions.len(), g.nodes.len());
+  assert_eq!(
+    positions[g.output.unwrap() as usize].map(|x| (x.line, x.column)),
+    Some((5, 14))
+  );
+
+  // `1 + 2 + 3` is folded, and `root.item` is only loaded once.
+  assert!(!g
+    .nodes
+    .iter()
+    .any(|(n, in_edges, _)| matches!(n, TwGraphNode::Add)
+      && in_edges
+        .iter()
+        .all(|x| matches!(g.nodes[*x as usize].0, TwGraphNode::LoadConst(_)))));
+  assert_eq!(
+    g.nodes
+      .iter()
+      .filter(|(n, _, _)| matches!(n, TwGraphNode::GetField(_)))
+      .count(),
+    2
+  );
+
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let kv = MockKv::new();
+  let mut executor = Executor::new(&vm, &kv, &type_info);
+  let output = executor
+    .run_graph(0, &[Arc::new(generate_root_map(&schema, &plan).unwrap())])
+    .await
+    .unwrap();
+  assert_eq!(
+    **output.as_ref().unwrap(),
+    VmValue::Primitive(PrimitiveValue::Int64(6))
+  );
+}
+
+#[test]
+fn vm_construction_optimizes() {
+  let schema =
+    compile(&parse(&Bump::new(), "type Item { a: int64, }\nexport Item item;").unwrap()).unwrap();
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let mut script = compile_twscript(
+    r#"
+    graph main(root: schema): int64 {
+      return 1 + 2;
+    }
+    "#,
+  )
+  .unwrap();
+  let vm = TwVm::new_optimized(&schema, &plan, &mut script).unwrap();
+  let g = &vm.script.graphs[0];
+  assert!(!g
+    .nodes
+    .iter()
+    .any(|(n, _, _)| matches!(n, TwGraphNode::Add)));
+}
@@ -0,0 +1,177 @@
+use std::{
+  collections::{BTreeMap, HashMap},
+  sync::{Arc, Mutex},
+};
+
+use super::vm_value::{VmSetValue, VmSetValueKind, VmTableValue, VmTableValueKind, VmValue};
+
+/// Pending effects on fresh tables and sets within one run.
+///
+/// Fresh values are immutable and have no storage key, so effects on them cannot go through the
+/// transaction like effects on resident values do. Instead they are recorded here, keyed by the
+/// identity of the value, and reads of the value consult the overlay first. This way a read
+/// observes all prior effects of the run, whether the value is fresh or resident.
+///
+/// The overlay holds a reference to each value it has entries for, so that the address of the
+/// value is not reused while the run lasts.
+pub struct EffectOverlay<'a> {
+  entries: Mutex<HashMap<usize, OverlayEntry<'a>>>,
+}
+
+struct OverlayEntry<'a> {
+  _value: Arc<VmValue<'a>>,
+  kind: OverlayEntryKind<'a>,
+}
+
+enum OverlayEntryKind<'a> {
+  /// Fields written to a fresh table.
+  Table(BTreeMap<&'a str, Arc<VmValue<'a>>>),
+
+  /// Members inserted into a fresh set by encoded primary key, or `None` if deleted.
+  Set(BTreeMap<Vec<u8>, Option<Arc<VmValue<'a>>>>),
+}
+
+fn identity(value: &Arc<VmValue>) -> usize {
+  Arc::as_ptr(value) as *const u8 as usize
+}
+
+impl<'a> EffectOverlay<'a> {
+  pub fn new() -> Self {
+    Self {
+      entries: Mutex::new(HashMap::new()),
+    }
+  }
+
+  /// Drops all pending effects, at the start of a run.
+  pub fn clear(&self) {
+    self.entries.lock().unwrap().clear();
+  }
+
+  pub fn put_field(&self, table: &Arc<VmValue<'a>>, field: &'a str, value: Arc<VmValue<'a>>) {
+    let mut entries = self.entries.lock().unwrap();
+    let entry = entries
+      .entry(identity(table))
+      .or_insert_with(|| OverlayEntry {
+        _value: table.clone(),
+        kind: OverlayEntryKind::Table(BTreeMap::new()),
+      });
+    if let OverlayEntryKind::Table(x) = &mut entry.kind {
+      x.insert(field, value);
+    }
+  }
+
+  pub fn get_field(&self, table: &Arc<VmValue<'a>>, field: &str) -> Option<Arc<VmValue<'a>>> {
+    let entries = self.entries.lock().unwrap();
+    match &entries.get(&identity(table))?.kind {
+      OverlayEntryKind::Table(x) => x.get(field).cloned(),
+      _ => None,
+    }
+  }
+
+  /// Records an insert into a fresh set, or a delete if `member` is `None`.
+  pub fn put_member(
+    &self,
+    set: &Arc<VmValue<'a>>,
+    primary_key: Vec<u8>,
+    member: Option<Arc<VmValue<'a>>>,
+  ) {
+    let mut entries = self.entries.lock().unwrap();
+    let entry = entries
+      .entry(identity(set))
+      .or_insert_with(|| OverlayEntry {
+        _value: set.clone(),
+        kind: OverlayEntryKind::Set(BTreeMap::new()),
+      });
+    if let OverlayEntryKind::Set(x) = &mut entry.kind {
+      x.insert(primary_key, member);
+    }
+  }
+
+  /// The pending effect on a member of a fresh set: `Some(None)` if it was deleted, and `None` if
+  /// there is no pending effect.
+  pub fn get_member(
+    &self,
+    set: &Arc<VmValue<'a>>,
+    primary_key: &[u8],
+  ) -> Option<Option<Arc<VmValue<'a>>>> {
+    let entries = self.entries.lock().unwrap();
+    match &entries.get(&identity(set))?.kind {
+      OverlayEntryKind::Set(x) => x.get(primary_key).cloned(),
+      _ => None,
+    }
+  }
+
+  /// Returns `value` with the pending effects on it and on the fresh values nested in it applied,
+  /// or `value` itself if there are none.
+  pub fn resolve(&self, value: &Arc<VmValue<'a>>) -> Arc<VmValue<'a>> {
+    if self.entries.lock().unwrap().is_empty() {
+      return value.clone();
+    }
+    self.resolve_nested(value)
+  }
+
+  fn resolve_nested(&self, value: &Arc<VmValue<'a>>) -> Arc<VmValue<'a>> {
+    match &**value {
+      VmValue::Table(VmTableValue {
+        ty,
+        kind: VmTableValueKind::Fresh(fields),
+      }) => {
+        let mut fields = fields.clone();
+        if let Some(OverlayEntryKind::Table(x)) = self
+          .entries
+          .lock()
+          .unwrap()
+          .get(&identity(value))
+          .map(|x| &x.kind)
+        {
+          for (k, v) in x {
+            fields.insert(*k, v.clone());
+          }
+        }
+        for v in fields.values_mut() {
+          *v = self.resolve_nested(v);
+        }
+        Arc::new(VmValue::Table(VmTableValue {
+          ty: *ty,
+          kind: VmTableValueKind::Fresh(fields),
+        }))
+      }
+      VmValue::Set(VmSetValue {
+        member_ty,
+        kind: VmSetValueKind::Fresh(members),
+        include_deleted,
+      }) => {
+        let mut members = members.clone();
+        if let Some(OverlayEntryKind::Set(x)) = self
+          .entries
+          .lock()
+          .unwrap()
+          .get(&identity(value))
+          .map(|x| &x.kind)
+        {
+          for (k, v) in x {
+            match v {
+              Some(v) => members.insert(k.clone(), v.clone()),
+              None => members.remove(k),
+            };
+          }
+        }
+        for v in members.values_mut() {
+          *v = self.resolve_nested(v);
+        }
+        Arc::new(VmValue::Set(VmSetValue {
+          member_ty: member_ty.clone(),
+          kind: VmSetValueKind::Fresh(members),
+          include_deleted: *include_deleted,
+        }))
+      }
+      _ => value.clone(),
+    }
+  }
+}
+
+impl<'a> Default for EffectOverlay<'a> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
@@ -0,0 +1,51 @@
+use std::{
+  collections::HashMap,
+  sync::{Arc, Mutex},
+};
+
+use super::vm_value::{VmType, VmValue};
+
+/// Immutable values shared between node results.
+///
+/// Booleans and typed nulls make up most of the results of hot graphs, and carry no identity, so
+/// the executor hands out clones of pooled `Arc`s for them instead of allocating a fresh one per
+/// node.
+pub struct ValuePool<'a> {
+  true_value: Arc<VmValue<'a>>,
+  false_value: Arc<VmValue<'a>>,
+  nulls: Mutex<HashMap<VmType<&'a str>, Arc<VmValue<'a>>>>,
+}
+
+impl<'a> ValuePool<'a> {
+  pub fn new() -> Self {
+    Self {
+      true_value: Arc::new(VmValue::Bool(true)),
+      false_value: Arc::new(VmValue::Bool(false)),
+      nulls: Mutex::new(HashMap::new()),
+    }
+  }
+
+  pub fn bool(&self, x: bool) -> Arc<VmValue<'a>> {
+    if x {
+      self.true_value.clone()
+    } else {
+      self.false_value.clone()
+    }
+  }
+
+  pub fn null(&self, ty: &VmType<&'a str>) -> Arc<VmValue<'a>> {
+    let mut nulls = self.nulls.lock().unwrap();
+    if let Some(x) = nulls.get(ty) {
+      return x.clone();
+    }
+    let x = Arc::new(VmValue::Null(ty.clone()));
+    nulls.insert(ty.clone(), x.clone());
+    x
+  }
+}
+
+impl<'a> Default for ValuePool<'a> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
@@ -0,0 +1,269 @@
+use std::{
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+  },
+  time::Duration,
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::Serialize;
+
+use crate::data::kv::{KvEntryIterator, KvError, KvKeyIterator, KvTransaction, ScanOptions};
+
+use super::vm::TwVm;
+
+/// Profile of the runs of a graph during one attempt of a graph run.
+///
+/// Counters and times of a node include the subgraph it runs. Runs of a subgraph by the same node,
+/// e.g. once per element of a `reduce`, are merged into one profile.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct GraphProfile {
+  pub graph: String,
+
+  /// Number of times the graph ran.
+  pub runs: u64,
+
+  /// Total time spent in the runs of the graph.
+  pub elapsed_us: u64,
+
+  /// Profiles of all nodes of the graph, indexed by node index.
+  pub nodes: Vec<NodeProfile>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct NodeProfile {
+  /// The node, as printed by `rdbctl explain`.
+  pub node: String,
+
+  /// Number of times the node ran. Zero if it was skipped.
+  pub runs: u64,
+
+  pub gets: u64,
+  pub puts: u64,
+
+  /// Number of `delete` and `delete_range` requests.
+  pub deletes: u64,
+
+  pub scans: u64,
+
+  /// Total size of keys and values returned by gets and scans.
+  pub bytes_read: u64,
+
+  /// Total size of keys and values passed to puts.
+  pub bytes_written: u64,
+
+  /// Total time spent running the node, not including the time waiting for a concurrency permit.
+  pub elapsed_us: u64,
+
+  /// Profile of the subgraph run by this node, if any.
+  pub subgraph: Option<Box<GraphProfile>>,
+}
+
+/// Collects the profile of one graph while it runs.
+pub(super) struct GraphProfiler {
+  graph_index: usize,
+  runs: AtomicU64,
+  elapsed_ns: AtomicU64,
+  nodes: Vec<NodeProfiler>,
+}
+
+pub(super) struct NodeProfiler {
+  counters: Arc<NodeCounters>,
+
+  /// Created on the first subgraph run, since recursive graphs would otherwise nest forever.
+  subgraph: Mutex<Option<Arc<GraphProfiler>>>,
+}
+
+#[derive(Default)]
+struct NodeCounters {
+  runs: AtomicU64,
+  gets: AtomicU64,
+  puts: AtomicU64,
+  deletes: AtomicU64,
+  scans: AtomicU64,
+  bytes_read: AtomicU64,
+  bytes_written: AtomicU64,
+  elapsed_ns: AtomicU64,
+}
+
+impl GraphProfiler {
+  pub fn new(vm: &TwVm, graph_index: usize) -> Self {
+    Self {
+      graph_index,
+      runs: AtomicU64::new(0),
+      elapsed_ns: AtomicU64::new(0),
+      nodes: vm.script.graphs[graph_index]
+        .nodes
+        .iter()
+        .map(|_| NodeProfiler {
+          counters: Default::default(),
+          subgraph: Mutex::new(None),
+        })
+        .collect(),
+    }
+  }
+
+  pub fn node(&self, node_index: u32) -> &NodeProfiler {
+    &self.nodes[node_index as usize]
+  }
+
+  pub fn record_run(&self, elapsed: Duration) {
+    self.runs.fetch_add(1, Ordering::Relaxed);
+    self
+      .elapsed_ns
+      .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+  }
+
+  pub fn finish(&self, vm: &TwVm) -> GraphProfile {
+    let g = &vm.script.graphs[self.graph_index];
+    GraphProfile {
+      graph: g.name.clone(),
+      runs: self.runs.load(Ordering::Relaxed),
+      elapsed_us: self.elapsed_ns.load(Ordering::Relaxed) / 1000,
+      nodes: self
+        .nodes
+        .iter()
+        .zip(g.nodes.iter())
+        .map(|(x, (node, _, _))| {
+          let c = &x.counters;
+          NodeProfile {
+            node: format!("{:?}", node),
+            runs: c.runs.load(Ordering::Relaxed),
+            gets: c.gets.load(Ordering::Relaxed),
+            puts: c.puts.load(Ordering::Relaxed),
+            deletes: c.deletes.load(Ordering::Relaxed),
+            scans: c.scans.load(Ordering::Relaxed),
+            bytes_read: c.bytes_read.load(Ordering::Relaxed),
+            bytes_written: c.bytes_written.load(Ordering::Relaxed),
+            elapsed_us: c.elapsed_ns.load(Ordering::Relaxed) / 1000,
+            subgraph: x
+              .subgraph
+              .lock()
+              .unwrap()
+              .as_ref()
+              .map(|x| Box::new(x.finish(vm))),
+          }
+        })
+        .collect(),
+    }
+  }
+}
+
+impl NodeProfiler {
+  /// The profiler of the subgraph `graph_index` run by this node.
+  pub fn subgraph(&self, vm: &TwVm, graph_index: usize) -> Arc<GraphProfiler> {
+    self
+      .subgraph
+      .lock()
+      .unwrap()
+      .get_or_insert_with(|| Arc::new(GraphProfiler::new(vm, graph_index)))
+      .clone()
+  }
+
+  pub fn record_run(&self, elapsed: Duration) {
+    self.counters.runs.fetch_add(1, Ordering::Relaxed);
+    self
+      .counters
+      .elapsed_ns
+      .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+  }
+}
+
+/// A transaction that counts the requests made by one node into its `NodeProfiler`.
+pub(super) struct ProfilingTransaction<'t> {
+  pub inner: &'t dyn KvTransaction,
+  pub node: &'t NodeProfiler,
+}
+
+struct ProfilingKeyIterator {
+  inner: Box<dyn KvKeyIterator>,
+  counters: Arc<NodeCounters>,
+}
+
+struct ProfilingEntryIterator<'t> {
+  inner: Box<dyn KvEntryIterator + 't>,
+  counters: Arc<NodeCounters>,
+}
+
+fn count(x: &AtomicU64, n: usize) {
+  x.fetch_add(n as u64, Ordering::Relaxed);
+}
+
+#[async_trait]
+impl<'t> KvTransaction for ProfilingTransaction<'t> {
+  async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    let c = &self.node.counters;
+    count(&c.gets, 1);
+    let value = self.inner.get(key).await?;
+    if let Some(x) = &value {
+      count(&c.bytes_read, key.len() + x.len());
+    }
+    Ok(value)
+  }
+
+  async fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+    let c = &self.node.counters;
+    count(&c.puts, 1);
+    count(&c.bytes_written, key.len() + value.len());
+    self.inner.put(key, value).await
+  }
+
+  async fn delete(&self, key: &[u8]) -> Result<()> {
+    count(&self.node.counters.deletes, 1);
+    self.inner.delete(key).await
+  }
+
+  async fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+    count(&self.node.counters.deletes, 1);
+    self.inner.delete_range(start, end).await
+  }
+
+  async fn scan_keys(&self, start: &[u8], end: &[u8]) -> Result<Box<dyn KvKeyIterator>> {
+    count(&self.node.counters.scans, 1);
+    Ok(Box::new(ProfilingKeyIterator {
+      inner: self.inner.scan_keys(start, end).await?,
+      counters: self.node.counters.clone(),
+    }))
+  }
+
+  async fn scan<'a>(
+    &'a self,
+    start: &[u8],
+    end: &[u8],
+    options: &ScanOptions,
+  ) -> Result<Box<dyn KvEntryIterator + 'a>> {
+    count(&self.node.counters.scans, 1);
+    Ok(Box::new(ProfilingEntryIterator {
+      inner: self.inner.scan(start, end, options).await?,
+      counters: self.node.counters.clone(),
+    }))
+  }
+
+  async fn commit(self: Box<Self>) -> Result<(), KvError> {
+    panic!("inconsistency: commit called on a node transaction");
+  }
+}
+
+#[async_trait]
+impl KvKeyIterator for ProfilingKeyIterator {
+  async fn next(&mut self) -> Result<Option<Vec<u8>>> {
+    let key = self.inner.next().await?;
+    if let Some(x) = &key {
+      count(&self.counters.bytes_read, x.len());
+    }
+    Ok(key)
+  }
+}
+
+#[async_trait]
+impl<'t> KvEntryIterator for ProfilingEntryIterator<'t> {
+  async fn next(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    let entry = self.inner.next().await?;
+    if let Some((k, v)) = &entry {
+      count(&self.counters.bytes_read, k.len() + v.len());
+    }
+    Ok(entry)
+  }
+}
@@ -0,0 +1,113 @@
+use std::sync::Arc;
+
+use bumpalo::Bump;
+
+use crate::{
+  data::{
+    mock_kv::MockKv,
+    treewalker::{
+      asm::codegen::compile_twscript,
+      exec::{generate_root_map, Executor},
+      profile::{GraphProfile, NodeProfile},
+      typeck::GlobalTyckContext,
+      vm::TwVm,
+      vm_value::VmValue,
+    },
+    value::PrimitiveValue,
+  },
+  schema::{compile::compile, grammar::parse},
+  storage_plan::planner::generate_plan_for_schema,
+};
+
+fn total(profile: &GraphProfile, f: impl Fn(&NodeProfile) -> u64) -> u64 {
+  profile.nodes.iter().map(f).sum()
+}
+
+#[tokio::test]
+async fn profiles_nodes_and_subgraphs() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+  type Item {
+    @primary
+    id: string,
+    value: int64,
+  }
+  export set<Item> items;
+  "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let script = compile_twscript(
+    r#"
+  export graph write(root: schema) {
+    s_insert root.items $ build_table(Item) $ m_insert(id) "a" $ m_insert(value) 1 create_map;
+    s_insert root.items $ build_table(Item) $ m_insert(id) "b" $ m_insert(value) 2 create_map;
+    s_insert root.items $ build_table(Item) $ m_insert(id) "c" $ m_insert(value) 3 create_map;
+  }
+  export graph sum(root: schema): int64 {
+    return reduce(add) create_map 0 root.items;
+  }
+  graph add(ctx: map{}, acc: int64, item: Item): int64 {
+    return acc + item.value;
+  }
+  "#,
+  )
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let root_map = Arc::new(generate_root_map(&schema, &plan).unwrap());
+  let kv = MockKv::new();
+  let write = vm.lookup_exported_graph_by_name("write").unwrap();
+  let sum = vm.lookup_exported_graph_by_name("sum").unwrap();
+
+  let mut executor = Executor::new(&vm, &kv, &type_info);
+  let (_, profile) = executor
+    .run_graph_profiled(write, &[root_map.clone()])
+    .await
+    .unwrap();
+  assert_eq!(profile.graph, "write");
+  assert_eq!(profile.runs, 1);
+  assert_eq!(profile.nodes.len(), script.graphs[write].nodes.len());
+  assert!(total(&profile, |x| x.puts) >= 3);
+  assert!(total(&profile, |x| x.bytes_written) > 0);
+  assert_eq!(total(&profile, |x| x.scans), 0);
+
+  let (output, profile) = executor
+    .run_graph_profiled(sum, &[root_map.clone()])
+    .await
+    .unwrap();
+  assert_eq!(
+    *output.unwrap(),
+    VmValue::Primitive(PrimitiveValue::Int64(6))
+  );
+  let reduce = profile.nodes.iter().find(|x| x.subgraph.is_some()).unwrap();
+  assert_eq!(reduce.runs, 1);
+  assert_eq!(reduce.scans, 1);
+  assert_eq!(total(&profile, |x| x.puts), 0);
+
+  // Accesses of the subgraph are counted by the node that runs it, too.
+  let add = reduce.subgraph.as_ref().unwrap();
+  assert_eq!(add.graph, "add");
+  assert_eq!(add.runs, 3);
+  let add_gets = total(add, |x| x.gets);
+  assert!(add_gets >= 3);
+  assert!(reduce.gets >= add_gets);
+  assert!(reduce.bytes_read >= total(add, |x| x.bytes_read));
+
+  let json = serde_json::to_value(&profile).unwrap();
+  assert!(json["nodes"]
+    .as_array()
+    .unwrap()
+    .iter()
+    .any(|x| x["subgraph"]["graph"] == "add"));
+
+  // Profiling is off by default.
+  executor.run_graph(sum, &[root_map.clone()]).await.unwrap();
+  assert!(executor.profile().is_none());
+}
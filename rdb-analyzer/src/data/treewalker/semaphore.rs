@@ -0,0 +1,51 @@
+use futures::{
+  channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+  lock::Mutex,
+  StreamExt,
+};
+
+/// A runtime-agnostic counting semaphore.
+///
+/// The analyzer does not depend on any specific async runtime (it also runs in the wasm
+/// playground), so this is built from `futures` primitives: the channel holds one token per
+/// available permit.
+pub struct Semaphore {
+  tx: UnboundedSender<()>,
+  rx: Mutex<UnboundedReceiver<()>>,
+}
+
+pub struct SemaphorePermit<'a> {
+  sem: &'a Semaphore,
+}
+
+impl Semaphore {
+  pub fn new(permits: usize) -> Self {
+    let (tx, rx) = unbounded();
+    for _ in 0..permits {
+      tx.unbounded_send(())
+        .expect("Semaphore::new: receiver dropped");
+    }
+    Self {
+      tx,
+      rx: Mutex::new(rx),
+    }
+  }
+
+  pub async fn acquire(&self) -> SemaphorePermit<'_> {
+    self
+      .rx
+      .lock()
+      .await
+      .next()
+      .await
+      .expect("Semaphore::acquire: sender dropped");
+    SemaphorePermit { sem: self }
+  }
+}
+
+impl<'a> Drop for SemaphorePermit<'a> {
+  fn drop(&mut self) {
+    // The receiver lives as long as `self.sem`, so this never fails.
+    let _ = self.sem.tx.unbounded_send(());
+  }
+}
@@ -1,13 +1,23 @@
-use std::{collections::BTreeMap, sync::Arc};
+use std::{
+  collections::{BTreeMap, BTreeSet},
+  str::FromStr,
+  sync::Arc,
+};
 
 use anyhow::Result;
+use rpds::{ListSync, RedBlackTreeMapSync};
 
 use crate::{
   data::{
-    treewalker::vm_value::{VmListValue, VmMapValue},
+    pathwalker::PathWalker,
+    treewalker::vm_value::{
+      VmListType, VmListValue, VmListValueKind, VmMapValue, VmSetValue, VmSetValueKind,
+      VmTableValue, VmTableValueKind,
+    },
     value::PrimitiveValue,
   },
-  schema::compile::PrimitiveType,
+  schema::compile::{CompiledSchema, FieldType, PrimitiveType},
+  storage_plan::StoragePlan,
 };
 
 use super::vm_value::{VmType, VmValue};
@@ -30,6 +40,30 @@ pub enum SerializeError {
 
   #[error("missing required field: `{0}`")]
   MissingRequiredField(String),
+
+  #[error("bad selection: {0}")]
+  BadSelection(String),
+
+  #[error("selected field not found: `{0}`")]
+  SelectedFieldNotFound(String),
+
+  #[error("cannot select fields of a value that is not a map")]
+  SelectionOnNonMap,
+
+  #[error("result too large: exceeds the limit of {0} bytes")]
+  ResultTooLarge(usize),
+
+  #[error("export reference not found: `{0}`")]
+  ExportRefNotFound(String),
+
+  #[error("export reference `{0}` is of type `{1}`, expecting `{2}`")]
+  ExportRefTypeMismatch(String, String, String),
+
+  #[error("export references can only be decoded against a schema")]
+  ExportRefWithoutSchema,
+
+  #[error("bad page cursor")]
+  BadPageCursor,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -42,12 +76,119 @@ pub enum SerializedVmValue {
   Double(f64),
   Null(Option<Never>),
   Tagged(TaggedVmValue),
+  ExportRef(ExportRef),
+}
+
+/// A reference to a resident table or set, written as `{ "export": "path" }`. The path is the
+/// name of an export, optionally followed by dot-separated table fields, e.g. `store.items`.
+///
+/// Decodes into the resident value itself, so that a graph can be written generically over the
+/// export it operates on. Never produced by encoding.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExportRef {
+  pub export: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum TaggedVmValue {
   M(BTreeMap<String, SerializedVmValue>),
   L(Vec<SerializedVmValue>),
+
+  /// A list cut short by a `ResultSizeLimit`: its leading elements, and the number of elements
+  /// left out after them.
+  T {
+    elements: Vec<SerializedVmValue>,
+    omitted: u64,
+  },
+
+  /// One page of a paginated result. Encoded from maps of `page_type`.
+  P(SerializedPage),
+}
+
+/// The field names of a page, in the order of `SerializedPage`.
+pub const PAGE_FIELDS: [&str; 3] = ["items", "next_cursor", "total_estimate"];
+
+/// One page of a paginated result, e.g. of a `CollectPage` node.
+///
+/// The page is a map of `page_type` in the VM, so that graphs can build, inspect and pass on
+/// pages like any other map. Unselected outputs of that shape encode into this form.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SerializedPage {
+  pub items: Vec<SerializedVmValue>,
+
+  /// Opaque. Passed back to fetch the page after this one, and `None` on the last page.
+  pub next_cursor: Option<String>,
+
+  /// The number of items on all pages, if known.
+  pub total_estimate: Option<i64>,
+}
+
+/// The type of a page of `T`: `map { items: list<T>, next_cursor: string, total_estimate: int64 }`.
+pub fn page_type<'a>(item_ty: VmType<&'a str>) -> VmType<&'a str> {
+  let mut fields = RedBlackTreeMapSync::new_sync();
+  fields.insert_mut(
+    PAGE_FIELDS[0],
+    VmType::List(VmListType {
+      ty: Box::new(item_ty),
+    }),
+  );
+  fields.insert_mut(PAGE_FIELDS[1], VmType::Primitive(PrimitiveType::String));
+  fields.insert_mut(PAGE_FIELDS[2], VmType::Primitive(PrimitiveType::Int64));
+  VmType::Map(fields)
+}
+
+/// Builds a page of `page_type(item_ty)`. A `None` cursor or estimate becomes a typed null.
+pub fn build_page<'a>(
+  item_ty: VmType<&'a str>,
+  items: Vec<Arc<VmValue<'a>>>,
+  next_cursor: Option<String>,
+  total_estimate: Option<i64>,
+) -> VmValue<'a> {
+  let mut elements = RedBlackTreeMapSync::new_sync();
+  elements.insert_mut(
+    PAGE_FIELDS[0],
+    Arc::new(VmValue::List(VmListValue {
+      member_ty: item_ty,
+      kind: VmListValueKind::Fresh(items.into_iter().rev().fold(
+        ListSync::new_sync(),
+        |mut list, x| {
+          list.push_front_mut(x);
+          list
+        },
+      )),
+    })),
+  );
+  elements.insert_mut(
+    PAGE_FIELDS[1],
+    Arc::new(match next_cursor {
+      Some(x) => VmValue::Primitive(PrimitiveValue::String(x)),
+      None => VmValue::Null(VmType::Primitive(PrimitiveType::String)),
+    }),
+  );
+  elements.insert_mut(
+    PAGE_FIELDS[2],
+    Arc::new(match total_estimate {
+      Some(x) => VmValue::Primitive(PrimitiveValue::Int64(x)),
+      None => VmValue::Null(VmType::Primitive(PrimitiveType::Int64)),
+    }),
+  );
+  VmValue::Map(VmMapValue { elements })
+}
+
+/// Encodes the storage key of the last item of a page into a cursor.
+pub fn encode_page_cursor(key: &[u8]) -> String {
+  base64::encode_config(key, base64::URL_SAFE_NO_PAD)
+}
+
+pub fn decode_page_cursor(cursor: &str) -> Result<Vec<u8>, SerializeError> {
+  base64::decode_config(cursor, base64::URL_SAFE_NO_PAD).map_err(|_| SerializeError::BadPageCursor)
+}
+
+fn is_page_type(ty: &RedBlackTreeMapSync<&str, VmType<&str>>) -> bool {
+  ty.size() == PAGE_FIELDS.len()
+    && matches!(ty.get(PAGE_FIELDS[0]), Some(VmType::List(_)))
+    && ty.get(PAGE_FIELDS[1]) == Some(&VmType::Primitive(PrimitiveType::String))
+    && ty.get(PAGE_FIELDS[2]) == Some(&VmType::Primitive(PrimitiveType::Int64))
 }
 
 #[derive(Default, Debug, Clone)]
@@ -55,11 +196,161 @@ pub struct VmValueEncodeConfig {
   pub enable_bytes: bool,
   pub enable_int64: bool,
   pub enable_double: bool,
+
+  /// Unlimited if `None`.
+  pub size_limit: Option<ResultSizeLimit>,
+}
+
+/// Bounds the size of encoded values, so that a runaway output fails or gets cut short instead of
+/// exhausting memory.
+#[derive(Debug, Clone, Copy)]
+pub struct ResultSizeLimit {
+  /// Maximum size in bytes. Counts strings, bytes, numbers and map keys, but not the framing of
+  /// the encoding.
+  pub max_bytes: usize,
+
+  /// Whether lists that do not fit are cut short and encoded as `TaggedVmValue::T`, instead of
+  /// failing with `ResultTooLarge`. Other values that do not fit still fail.
+  pub truncate_lists: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Never {}
 
+/// The fields of a graph output to encode, in the style of GraphQL selection sets.
+///
+/// Written as `id name: display_name items { id }`: fields are separated by whitespace or commas,
+/// `alias: field` renames a field in the output, and a braced selection after a field applies to
+/// the map it holds, or to each element of the list it holds.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Selection {
+  pub fields: Vec<SelectedField>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SelectedField {
+  pub name: String,
+  pub alias: Option<String>,
+
+  /// Encodes the whole value if `None`.
+  pub selection: Option<Selection>,
+}
+
+impl SelectedField {
+  /// The key of this field in the output.
+  pub fn output_name(&self) -> &str {
+    self.alias.as_deref().unwrap_or(&self.name)
+  }
+}
+
+impl FromStr for Selection {
+  type Err = SerializeError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let tokens = tokenize_selection(s);
+    let mut pos = 0usize;
+
+    // The outermost braces are optional.
+    let selection = if tokens.first() == Some(&"{") {
+      parse_braced_selection(&tokens, &mut pos)?
+    } else {
+      parse_selection(&tokens, &mut pos)?
+    };
+    match tokens.get(pos) {
+      Some(x) => Err(SerializeError::BadSelection(format!("unexpected `{}`", x))),
+      None => Ok(selection),
+    }
+  }
+}
+
+fn tokenize_selection(s: &str) -> Vec<&str> {
+  let mut tokens = vec![];
+  let mut ident_start = None;
+  for (i, c) in s.char_indices() {
+    if is_ident_char(c) {
+      ident_start.get_or_insert(i);
+      continue;
+    }
+    if let Some(start) = ident_start.take() {
+      tokens.push(&s[start..i]);
+    }
+    if !c.is_whitespace() && c != ',' {
+      tokens.push(&s[i..i + c.len_utf8()]);
+    }
+  }
+  if let Some(start) = ident_start {
+    tokens.push(&s[start..]);
+  }
+  tokens
+}
+
+fn is_ident_char(c: char) -> bool {
+  c.is_alphanumeric() || c == '_'
+}
+
+fn parse_selection(tokens: &[&str], pos: &mut usize) -> Result<Selection, SerializeError> {
+  let mut fields = vec![];
+  let mut output_names = BTreeSet::new();
+  while let Some(&token) = tokens.get(*pos) {
+    if token == "}" {
+      break;
+    }
+    let mut name = expect_selection_ident(tokens, pos)?;
+    let mut alias = None;
+    if tokens.get(*pos) == Some(&":") {
+      *pos += 1;
+      alias = Some(name);
+      name = expect_selection_ident(tokens, pos)?;
+    }
+    let selection = if tokens.get(*pos) == Some(&"{") {
+      Some(parse_braced_selection(tokens, pos)?)
+    } else {
+      None
+    };
+    let field = SelectedField {
+      name: name.to_string(),
+      alias: alias.map(|x| x.to_string()),
+      selection,
+    };
+    if !output_names.insert(field.output_name().to_string()) {
+      return Err(SerializeError::BadSelection(format!(
+        "duplicate field `{}`",
+        field.output_name()
+      )));
+    }
+    fields.push(field);
+  }
+  if fields.is_empty() {
+    return Err(SerializeError::BadSelection("empty selection".into()));
+  }
+  Ok(Selection { fields })
+}
+
+fn parse_braced_selection(tokens: &[&str], pos: &mut usize) -> Result<Selection, SerializeError> {
+  // Skip `{`.
+  *pos += 1;
+  let selection = parse_selection(tokens, pos)?;
+  if tokens.get(*pos) != Some(&"}") {
+    return Err(SerializeError::BadSelection("unclosed `{`".into()));
+  }
+  *pos += 1;
+  Ok(selection)
+}
+
+fn expect_selection_ident<'a>(
+  tokens: &[&'a str],
+  pos: &mut usize,
+) -> Result<&'a str, SerializeError> {
+  match tokens.get(*pos) {
+    Some(x) if x.chars().all(is_ident_char) => {
+      *pos += 1;
+      Ok(x)
+    }
+    Some(x) => Err(SerializeError::BadSelection(format!("unexpected `{}`", x))),
+    None => Err(SerializeError::BadSelection("unexpected end".into())),
+  }
+}
+
 impl SerializedVmValue {
   pub fn try_unwrap_bool(&self) -> Result<bool> {
     match self {
@@ -113,57 +404,70 @@ impl SerializedVmValue {
       _ => Err(SerializeError::UnwrapTypeMismatch.into()),
     }
   }
+  pub fn try_unwrap_page(&self) -> Result<&SerializedPage> {
+    match self {
+      Self::Tagged(TaggedVmValue::P(x)) => Ok(x),
+      _ => Err(SerializeError::UnwrapTypeMismatch.into()),
+    }
+  }
 
   pub fn encode(v: &VmValue, config: &VmValueEncodeConfig) -> Result<Self> {
+    Self::encode_selected(v, config, None)
+  }
+
+  /// Like `encode`, but only keeps the fields in `selection` if there is one.
+  pub fn encode_selected(
+    v: &VmValue,
+    config: &VmValueEncodeConfig,
+    selection: Option<&Selection>,
+  ) -> Result<Self> {
+    Encoder {
+      config,
+      remaining: config.size_limit.map(|x| x.max_bytes),
+    }
+    .encode(v, selection)
+  }
+
+  /// Encodes the elements of a list one at a time, so that the encoded list is never held as a
+  /// whole. Returns `None` if `v` is not a list. The size limit of `config` applies to each
+  /// element.
+  pub fn encode_list_elements<'v, 'a: 'v>(
+    v: &'v VmValue<'a>,
+    config: &'v VmValueEncodeConfig,
+    selection: Option<&'v Selection>,
+  ) -> Option<Box<dyn Iterator<Item = Result<Self>> + Send + 'v>> {
     match v {
-      VmValue::Map(x) => Ok(Self::Tagged(TaggedVmValue::M(
-        x.elements
+      VmValue::List(VmListValue {
+        kind: VmListValueKind::Fresh(node),
+        ..
+      }) => Some(Box::new(
+        node
           .iter()
-          .map(|(k, v)| Self::encode(&**v, config).map(|x| (k.to_string(), x)))
-          .collect::<Result<_>>()?,
-      ))),
-      VmValue::Null(_) => Ok(Self::Null(None)),
-      VmValue::Bool(x) => Ok(Self::Bool(*x)),
-      VmValue::Primitive(x) => match x {
-        PrimitiveValue::Bytes(x) => {
-          if config.enable_bytes {
-            Ok(Self::Bytes(x.clone()))
-          } else {
-            Ok(Self::String(base64::encode(x)))
-          }
-        }
-        PrimitiveValue::Double(x) => {
-          if config.enable_double {
-            Ok(Self::Double(f64::from_bits(*x)))
-          } else {
-            Ok(Self::String(format!("{}", f64::from_bits(*x))))
-          }
-        }
-        PrimitiveValue::Int64(x) => {
-          if config.enable_int64 {
-            Ok(Self::Int64(*x))
-          } else {
-            Ok(Self::String(format!("{}", x)))
-          }
-        }
-        PrimitiveValue::String(x) => Ok(Self::String(x.clone())),
-      },
-      VmValue::List(x) => {
-        let out = x
-          .node
-          .iter()
-          .map(|x| Self::encode(&**x, config))
-          .collect::<Result<_>>()?;
-        Ok(Self::Tagged(TaggedVmValue::L(out)))
-      }
-      _ => {
-        log::debug!("encode: unserializable: {:?}", v);
-        Err(SerializeError::Unserializable.into())
-      }
+          .map(move |x| Self::encode_selected(&**x, config, selection)),
+      )),
+      _ => None,
     }
   }
 
   pub fn decode<'a>(&self, ty: &VmType<&'a str>) -> Result<VmValue<'a>> {
+    self.decode_inner(ty, None)
+  }
+
+  /// Like `decode`, also resolving `ExportRef`s into resident values.
+  pub fn decode_in<'a>(
+    &self,
+    ty: &VmType<&'a str>,
+    schema: &'a CompiledSchema,
+    plan: &'a StoragePlan,
+  ) -> Result<VmValue<'a>> {
+    self.decode_inner(ty, Some((schema, plan)))
+  }
+
+  fn decode_inner<'a>(
+    &self,
+    ty: &VmType<&'a str>,
+    env: Option<(&'a CompiledSchema, &'a StoragePlan)>,
+  ) -> Result<VmValue<'a>> {
     use SerializedVmValue as S;
     match (self, ty) {
       (S::Tagged(TaggedVmValue::M(x)), VmType::Map(map_ty)) => {
@@ -172,7 +476,9 @@ impl SerializedVmValue {
         };
         for (k, field_ty) in map_ty {
           if let Some(v) = x.get(*k) {
-            res.elements.insert_mut(*k, Arc::new(v.decode(field_ty)?));
+            res
+              .elements
+              .insert_mut(*k, Arc::new(v.decode_inner(field_ty, env)?));
           } else {
             res
               .elements
@@ -181,17 +487,37 @@ impl SerializedVmValue {
         }
         Ok(VmValue::Map(res))
       }
+      (S::Tagged(TaggedVmValue::P(x)), VmType::Map(map_ty)) if is_page_type(map_ty) => {
+        let item_ty = match map_ty.get(PAGE_FIELDS[0]) {
+          Some(VmType::List(x)) => &*x.ty,
+          _ => unreachable!(),
+        };
+        Ok(build_page(
+          item_ty.clone(),
+          x.items
+            .iter()
+            .map(|x| x.decode_inner(item_ty, env).map(Arc::new))
+            .collect::<Result<_>>()?,
+          x.next_cursor.clone(),
+          x.total_estimate,
+        ))
+      }
       (S::Tagged(TaggedVmValue::L(x)), VmType::List(list_ty)) => {
         let res = VmListValue {
           member_ty: (*list_ty.ty).clone(),
-          node: x
-            .iter()
-            .map(|x| x.decode(&*list_ty.ty).map(Arc::new))
-            .collect::<Result<_>>()?,
+          kind: VmListValueKind::Fresh(
+            x.iter()
+              .map(|x| x.decode_inner(&*list_ty.ty, env).map(Arc::new))
+              .collect::<Result<_>>()?,
+          ),
         };
         Ok(VmValue::List(res))
       }
       (S::Null(None), _) => Ok(VmValue::Null(ty.clone())),
+      (S::ExportRef(x), _) => {
+        let (schema, plan) = env.ok_or_else(|| SerializeError::ExportRefWithoutSchema)?;
+        x.resolve(ty, schema, plan)
+      }
       (S::Bool(x), VmType::Bool) => Ok(VmValue::Bool(*x)),
       (S::String(x), VmType::Primitive(PrimitiveType::String)) => {
         Ok(VmValue::Primitive(PrimitiveValue::String(x.clone())))
@@ -209,13 +535,13 @@ impl SerializedVmValue {
         Ok(VmValue::Primitive(PrimitiveValue::Int64(*x as i64)))
       }
       (S::String(x), VmType::Primitive(PrimitiveType::Double)) => {
-        Ok(VmValue::Primitive(PrimitiveValue::Double(x.parse()?)))
+        Ok(VmValue::Primitive(PrimitiveValue::double(x.parse()?)))
+      }
+      (S::Int64(x), VmType::Primitive(PrimitiveType::Double)) => {
+        Ok(VmValue::Primitive(PrimitiveValue::double(*x as f64)))
       }
-      (S::Int64(x), VmType::Primitive(PrimitiveType::Double)) => Ok(VmValue::Primitive(
-        PrimitiveValue::Double((*x as f64).to_bits()),
-      )),
       (S::Double(x), VmType::Primitive(PrimitiveType::Double)) => {
-        Ok(VmValue::Primitive(PrimitiveValue::Double(x.to_bits())))
+        Ok(VmValue::Primitive(PrimitiveValue::double(*x)))
       }
       (S::String(x), VmType::Primitive(PrimitiveType::Bytes)) => Ok(VmValue::Primitive(
         PrimitiveValue::Bytes(base64::decode(x)?),
@@ -230,3 +556,267 @@ impl SerializedVmValue {
     }
   }
 }
+
+impl ExportRef {
+  fn resolve<'a>(
+    &self,
+    ty: &VmType<&'a str>,
+    schema: &'a CompiledSchema,
+    plan: &'a StoragePlan,
+  ) -> Result<VmValue<'a>> {
+    let not_found = || SerializeError::ExportRefNotFound(self.export.clone());
+    let mut segments = self.export.split('.');
+    let export_name = segments.next().unwrap_or_default();
+    let mut field_ty = schema.exports.get(export_name).ok_or_else(not_found)?;
+    let mut walker = PathWalker::from_export(plan, export_name)?;
+    for segment in segments {
+      let table_ty = match field_ty {
+        FieldType::Table(x) => schema.types.get(x).ok_or_else(not_found)?,
+        _ => return Err(not_found().into()),
+      };
+      field_ty = table_ty
+        .fields
+        .get(segment)
+        .map(|x| &x.0)
+        .ok_or_else(not_found)?;
+      walker = walker.enter_field(segment)?;
+    }
+
+    let actual_ty = VmType::<&'a str>::from(field_ty);
+    if actual_ty != *ty {
+      return Err(
+        SerializeError::ExportRefTypeMismatch(
+          self.export.clone(),
+          actual_ty.to_string(),
+          ty.to_string(),
+        )
+        .into(),
+      );
+    }
+    match field_ty {
+      FieldType::Table(x) => Ok(VmValue::Table(VmTableValue {
+        ty: &**x,
+        kind: VmTableValueKind::Resident(walker),
+      })),
+      FieldType::Set(x) => Ok(VmValue::Set(VmSetValue {
+        member_ty: VmType::from(&**x),
+        kind: VmSetValueKind::Resident(walker),
+        include_deleted: false,
+      })),
+      _ => Err(
+        SerializeError::ExportRefTypeMismatch(
+          self.export.clone(),
+          actual_ty.to_string(),
+          "table or set".into(),
+        )
+        .into(),
+      ),
+    }
+  }
+}
+
+struct Encoder<'c> {
+  config: &'c VmValueEncodeConfig,
+
+  /// Bytes left under the size limit, if any.
+  remaining: Option<usize>,
+}
+
+impl<'c> Encoder<'c> {
+  fn encode(&mut self, v: &VmValue, selection: Option<&Selection>) -> Result<SerializedVmValue> {
+    use SerializedVmValue as S;
+    match (v, selection) {
+      (VmValue::Map(x), None) if is_page_value(x) => self.encode_page(x),
+      (VmValue::Map(x), None) => {
+        let mut m = BTreeMap::new();
+        for (k, v) in x.elements.iter() {
+          self.charge(k.len())?;
+          m.insert(k.to_string(), self.encode(&**v, None)?);
+        }
+        Ok(S::Tagged(TaggedVmValue::M(m)))
+      }
+      (VmValue::Map(x), Some(selection)) => {
+        let mut m = BTreeMap::new();
+        for field in &selection.fields {
+          let value = x
+            .elements
+            .get(field.name.as_str())
+            .ok_or_else(|| SerializeError::SelectedFieldNotFound(field.name.clone()))?;
+          self.charge(field.output_name().len())?;
+          m.insert(
+            field.output_name().to_string(),
+            self.encode(&**value, field.selection.as_ref())?,
+          );
+        }
+        Ok(S::Tagged(TaggedVmValue::M(m)))
+      }
+      (
+        VmValue::List(VmListValue {
+          kind: VmListValueKind::Fresh(node),
+          ..
+        }),
+        _,
+      ) => self.encode_list(node, selection),
+      (VmValue::Null(_), _) => {
+        self.charge(1)?;
+        Ok(S::Null(None))
+      }
+      (VmValue::Error(x), _) => {
+        let mut m = BTreeMap::new();
+        self.charge("error".len() + x.as_ref().map(|x| x.len()).unwrap_or(1))?;
+        m.insert(
+          "error".to_string(),
+          x.clone().map(S::String).unwrap_or(S::Null(None)),
+        );
+        Ok(S::Tagged(TaggedVmValue::M(m)))
+      }
+      (_, Some(_)) => Err(SerializeError::SelectionOnNonMap.into()),
+      (VmValue::Bool(x), None) => {
+        self.charge(1)?;
+        Ok(S::Bool(*x))
+      }
+      (VmValue::Primitive(x), None) => {
+        let out = match x {
+          PrimitiveValue::Bytes(x) => {
+            if self.config.enable_bytes {
+              S::Bytes(x.clone())
+            } else {
+              S::String(base64::encode(x))
+            }
+          }
+          PrimitiveValue::Double(x) => {
+            if self.config.enable_double {
+              S::Double(f64::from_bits(*x))
+            } else {
+              S::String(format!("{}", f64::from_bits(*x)))
+            }
+          }
+          PrimitiveValue::Int64(x) => {
+            if self.config.enable_int64 {
+              S::Int64(*x)
+            } else {
+              S::String(format!("{}", x))
+            }
+          }
+          PrimitiveValue::String(x) => S::String(x.clone()),
+        };
+        self.charge(match &out {
+          S::String(x) => x.len(),
+          S::Bytes(x) => x.len(),
+          _ => 8,
+        })?;
+        Ok(out)
+      }
+      _ => {
+        log::debug!("encode: unserializable: {:?}", v);
+        Err(SerializeError::Unserializable.into())
+      }
+    }
+  }
+
+  fn encode_list(
+    &mut self,
+    node: &ListSync<Arc<VmValue>>,
+    selection: Option<&Selection>,
+  ) -> Result<SerializedVmValue> {
+    let truncate_lists = self
+      .config
+      .size_limit
+      .map(|x| x.truncate_lists)
+      .unwrap_or(false);
+    let mut out = Vec::new();
+    for x in node.iter() {
+      let remaining = self.remaining;
+      match self.encode(&**x, selection) {
+        Ok(x) => out.push(x),
+        Err(e)
+          if truncate_lists
+            && matches!(
+              e.downcast_ref::<SerializeError>(),
+              Some(SerializeError::ResultTooLarge(_))
+            ) =>
+        {
+          // Give back what the element used before failing.
+          self.remaining = remaining;
+          let omitted = (node.len() - out.len()) as u64;
+          return Ok(SerializedVmValue::Tagged(TaggedVmValue::T {
+            elements: out,
+            omitted,
+          }));
+        }
+        Err(e) => return Err(e),
+      }
+    }
+    Ok(SerializedVmValue::Tagged(TaggedVmValue::L(out)))
+  }
+
+  /// Encodes a page. Its items are never truncated, since a page is already bounded.
+  fn encode_page(&mut self, x: &VmMapValue) -> Result<SerializedVmValue> {
+    let items = match x.elements.get(PAGE_FIELDS[0]).map(|x| &**x) {
+      Some(VmValue::List(VmListValue {
+        kind: VmListValueKind::Fresh(x),
+        ..
+      })) => x
+        .iter()
+        .map(|x| self.encode(&**x, None))
+        .collect::<Result<Vec<_>>>()?,
+      _ => unreachable!(),
+    };
+    let next_cursor = match x.elements.get(PAGE_FIELDS[1]).map(|x| &**x) {
+      Some(VmValue::Primitive(PrimitiveValue::String(x))) => {
+        self.charge(x.len())?;
+        Some(x.clone())
+      }
+      _ => None,
+    };
+    let total_estimate = match x.elements.get(PAGE_FIELDS[2]).map(|x| &**x) {
+      Some(VmValue::Primitive(PrimitiveValue::Int64(x))) => {
+        self.charge(8)?;
+        Some(*x)
+      }
+      _ => None,
+    };
+    Ok(SerializedVmValue::Tagged(TaggedVmValue::P(
+      SerializedPage {
+        items,
+        next_cursor,
+        total_estimate,
+      },
+    )))
+  }
+
+  fn charge(&mut self, size: usize) -> Result<()> {
+    if let Some(remaining) = &mut self.remaining {
+      if *remaining < size {
+        return Err(
+          SerializeError::ResultTooLarge(self.config.size_limit.unwrap().max_bytes).into(),
+        );
+      }
+      *remaining -= size;
+    }
+    Ok(())
+  }
+}
+
+/// Whether a map has the shape of `page_type`, with a fresh list of items.
+fn is_page_value(x: &VmMapValue) -> bool {
+  let field = |i: usize| x.elements.get(PAGE_FIELDS[i]).map(|x| &**x);
+  x.elements.size() == PAGE_FIELDS.len()
+    && matches!(
+      field(0),
+      Some(VmValue::List(VmListValue {
+        kind: VmListValueKind::Fresh(_),
+        ..
+      }))
+    )
+    && matches!(
+      field(1),
+      Some(VmValue::Primitive(PrimitiveValue::String(_)))
+        | Some(VmValue::Null(VmType::Primitive(PrimitiveType::String)))
+    )
+    && matches!(
+      field(2),
+      Some(VmValue::Primitive(PrimitiveValue::Int64(_)))
+        | Some(VmValue::Null(VmType::Primitive(PrimitiveType::Int64)))
+    )
+}
@@ -0,0 +1,314 @@
+use std::sync::Arc;
+
+use rpds::ListSync;
+
+use bumpalo::Bump;
+
+use crate::{
+  data::{pathwalker::PathWalker, value::PrimitiveValue},
+  schema::{
+    compile::{compile, PrimitiveType},
+    grammar::parse,
+  },
+  storage_plan::planner::generate_plan_for_schema,
+};
+
+use super::{
+  serialize::{
+    build_page, decode_page_cursor, encode_page_cursor, page_type, ResultSizeLimit, Selection,
+    SerializeError, SerializedVmValue, TaggedVmValue, VmValueEncodeConfig,
+  },
+  vm_value::{
+    VmListValue, VmListValueKind, VmMapValue, VmSetValueKind, VmTableValueKind, VmType, VmValue,
+  },
+};
+
+fn string<'a>(x: &str) -> VmValue<'a> {
+  VmValue::Primitive(PrimitiveValue::String(x.to_string()))
+}
+
+fn map<'a>(elements: Vec<(&'a str, VmValue<'a>)>) -> VmValue<'a> {
+  VmValue::Map(VmMapValue {
+    elements: elements
+      .into_iter()
+      .map(|(k, v)| (k, Arc::new(v)))
+      .collect(),
+  })
+}
+
+fn item<'a>(id: &str) -> VmValue<'a> {
+  map(vec![
+    ("id", string(id)),
+    ("name", string("n")),
+    ("body", string("b")),
+  ])
+}
+
+fn encode(v: &VmValue, selection: &str) -> anyhow::Result<serde_json::Value> {
+  let selection: Selection = selection.parse()?;
+  let out = SerializedVmValue::encode_selected(v, &Default::default(), Some(&selection))?;
+  Ok(serde_json::to_value(&out)?)
+}
+
+#[test]
+fn parse_selection() {
+  let selection: Selection = "{ id, title: name items { id } }".parse().unwrap();
+  assert_eq!(selection, "id title:name items{id}".parse().unwrap());
+  assert_eq!(selection.fields.len(), 3);
+  assert_eq!(selection.fields[1].name, "name");
+  assert_eq!(selection.fields[1].output_name(), "title");
+  assert_eq!(
+    selection.fields[2].selection.as_ref().unwrap().fields.len(),
+    1
+  );
+
+  for bad in [
+    "", "id {", "id }", "a: b: c", "{}", "id id", "x: a x", "id $",
+  ] {
+    assert!(
+      matches!(
+        bad.parse::<Selection>(),
+        Err(SerializeError::BadSelection(_))
+      ),
+      "{}",
+      bad
+    );
+  }
+}
+
+#[test]
+fn encode_selected() {
+  let value = map(vec![
+    ("total", VmValue::Primitive(PrimitiveValue::Int64(2))),
+    (
+      "items",
+      VmValue::List(VmListValue {
+        member_ty: VmType::Unknown,
+        kind: VmListValueKind::Fresh(
+          ListSync::new_sync()
+            .push_front(Arc::new(item("b")))
+            .push_front(Arc::new(item("a"))),
+        ),
+      }),
+    ),
+    ("first", item("a")),
+    ("missing", VmValue::Null(VmType::Unknown)),
+  ]);
+
+  assert_eq!(
+    encode(&value, "items { id } top: first { name } missing { id }").unwrap(),
+    serde_json::json!({
+      "M": {
+        "items": { "L": [{ "M": { "id": "a" } }, { "M": { "id": "b" } }] },
+        "top": { "M": { "name": "n" } },
+        "missing": null,
+      }
+    })
+  );
+
+  // Without a selection, the value is encoded whole.
+  let whole = SerializedVmValue::encode_selected(&value, &Default::default(), None).unwrap();
+  match whole {
+    SerializedVmValue::Tagged(TaggedVmValue::M(x)) => assert_eq!(x.len(), 4),
+    _ => unreachable!(),
+  }
+
+  for (selection, error) in [
+    ("nope", "selected field not found: `nope`"),
+    (
+      "total { id }",
+      "cannot select fields of a value that is not a map",
+    ),
+  ] {
+    assert_eq!(encode(&value, selection).unwrap_err().to_string(), error);
+  }
+}
+
+#[test]
+fn size_limit() {
+  let list = VmValue::List(VmListValue {
+    member_ty: VmType::Unknown,
+    kind: VmListValueKind::Fresh(
+      (0..10)
+        .map(|i| Arc::new(string(&format!("item{}", i))))
+        .collect(),
+    ),
+  });
+  let value = map(vec![("list", list)]);
+  let config = |max_bytes, truncate_lists| VmValueEncodeConfig {
+    size_limit: Some(ResultSizeLimit {
+      max_bytes,
+      truncate_lists,
+    }),
+    ..Default::default()
+  };
+
+  // "list" and ten elements of 5 bytes.
+  assert!(SerializedVmValue::encode(&value, &config(54, false)).is_ok());
+  assert_eq!(
+    SerializedVmValue::encode(&value, &config(53, false))
+      .unwrap_err()
+      .to_string(),
+    "result too large: exceeds the limit of 53 bytes"
+  );
+
+  let truncated = SerializedVmValue::encode(&value, &config(20, true)).unwrap();
+  assert_eq!(
+    serde_json::to_value(&truncated).unwrap(),
+    serde_json::json!({
+      "M": {
+        "list": { "T": { "elements": ["item0", "item1", "item2"], "omitted": 7 } },
+      }
+    })
+  );
+
+  // Values outside of lists cannot be truncated.
+  assert!(SerializedVmValue::encode(&value, &config(3, true)).is_err());
+}
+
+#[test]
+fn list_elements() {
+  let list = VmValue::List(VmListValue {
+    member_ty: VmType::Unknown,
+    kind: VmListValueKind::Fresh((0..3).map(|i| Arc::new(item(&format!("{}", i)))).collect()),
+  });
+  let selection: Selection = "id".parse().unwrap();
+  let config = VmValueEncodeConfig {
+    size_limit: Some(ResultSizeLimit {
+      max_bytes: 8,
+      truncate_lists: false,
+    }),
+    ..Default::default()
+  };
+
+  // The size limit applies to each element, not to the whole list.
+  let elements = SerializedVmValue::encode_list_elements(&list, &config, Some(&selection))
+    .unwrap()
+    .map(|x| serde_json::to_value(&x.unwrap()).unwrap())
+    .collect::<Vec<_>>();
+  assert_eq!(
+    elements,
+    vec![
+      serde_json::json!({ "M": { "id": "0" } }),
+      serde_json::json!({ "M": { "id": "1" } }),
+      serde_json::json!({ "M": { "id": "2" } }),
+    ]
+  );
+  assert!(SerializedVmValue::encode_list_elements(&string("x"), &config, None).is_none());
+}
+
+#[test]
+fn pages() {
+  let page = build_page(
+    VmType::Primitive(PrimitiveType::String),
+    vec![Arc::new(string("a")), Arc::new(string("b"))],
+    Some(encode_page_cursor(b"b")),
+    None,
+  );
+  let encoded = SerializedVmValue::encode(&page, &Default::default()).unwrap();
+  assert_eq!(
+    serde_json::to_value(&encoded).unwrap(),
+    serde_json::json!({
+      "P": { "items": ["a", "b"], "next_cursor": "Yg", "total_estimate": null }
+    })
+  );
+  assert_eq!(
+    decode_page_cursor(
+      encoded
+        .try_unwrap_page()
+        .unwrap()
+        .next_cursor
+        .as_ref()
+        .unwrap()
+    )
+    .unwrap(),
+    b"b"
+  );
+  assert!(matches!(
+    decode_page_cursor("not a cursor"),
+    Err(SerializeError::BadPageCursor)
+  ));
+
+  let ty = page_type(VmType::Primitive(PrimitiveType::String));
+  assert_eq!(encoded.decode(&ty).unwrap(), page);
+
+  // Maps of other shapes are not pages.
+  let not_page = map(vec![("items", string("a")), ("next_cursor", string("b"))]);
+  assert!(matches!(
+    SerializedVmValue::encode(&not_page, &Default::default()).unwrap(),
+    SerializedVmValue::Tagged(TaggedVmValue::M(_))
+  ));
+}
+
+#[test]
+fn decode_export_ref() {
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+    type Item {
+      @primary
+      id: string,
+      name: string,
+    }
+    type Store {
+      items: set<Item>,
+    }
+    export set<Item> items;
+    export Store store;
+    "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let set_ty = VmType::<&str>::from(schema.exports.get("items").unwrap());
+  let store_ty = VmType::<&str>::from(schema.exports.get("store").unwrap());
+
+  let export_ref = |x: &str| -> SerializedVmValue {
+    serde_json::from_value(serde_json::json!({ "export": x })).unwrap()
+  };
+
+  match export_ref("store.items")
+    .decode_in(&set_ty, &schema, &plan)
+    .unwrap()
+  {
+    VmValue::Set(x) => match &x.kind {
+      VmSetValueKind::Resident(walker) => assert_eq!(
+        walker.generate_key(),
+        PathWalker::from_export(&plan, "store")
+          .unwrap()
+          .enter_field("items")
+          .unwrap()
+          .generate_key()
+      ),
+      _ => panic!("expecting a resident set"),
+    },
+    x => panic!("expecting a set, got {:?}", x),
+  }
+  match export_ref("store")
+    .decode_in(&store_ty, &schema, &plan)
+    .unwrap()
+  {
+    VmValue::Table(x) => {
+      assert_eq!(x.ty, "Store");
+      assert!(matches!(x.kind, VmTableValueKind::Resident(_)));
+    }
+    x => panic!("expecting a table, got {:?}", x),
+  }
+
+  let err = |x: anyhow::Result<VmValue>| x.unwrap_err().downcast::<SerializeError>().unwrap();
+  assert!(matches!(
+    err(export_ref("items").decode(&set_ty)),
+    SerializeError::ExportRefWithoutSchema
+  ));
+  assert!(matches!(
+    err(export_ref("store.nope").decode_in(&set_ty, &schema, &plan)),
+    SerializeError::ExportRefNotFound(_)
+  ));
+  assert!(matches!(
+    err(export_ref("store").decode_in(&set_ty, &schema, &plan)),
+    SerializeError::ExportRefTypeMismatch(_, _, _)
+  ));
+}
@@ -0,0 +1,182 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::schema::compile::PrimitiveType;
+
+use super::{serialize::VmValueEncodeConfig, typeck::GlobalTypeInfo, vm::TwVm, vm_value::VmType};
+
+/// The params and output of an exported graph, for generating typed clients.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct GraphSignature {
+  pub name: String,
+  pub params: Vec<ParamSignature>,
+
+  /// `None` if the graph has no output.
+  pub output: Option<ValueShape>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ParamSignature {
+  /// The name in the assembly source, if known.
+  pub name: Option<String>,
+  pub shape: ValueShape,
+}
+
+/// The shape of a value as encoded by `SerializedVmValue`. Any value may also be null.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ValueShape {
+  Bool,
+  Int64,
+  Double,
+  String,
+  Bytes,
+  List {
+    items: Box<ValueShape>,
+  },
+
+  /// A map with a fixed set of fields. Missing fields decode as null.
+  Map {
+    fields: BTreeMap<String, ValueShape>,
+  },
+
+  OneOf {
+    variants: Vec<ValueShape>,
+  },
+
+  /// An error caught by `try_call`, encoded as a map with an `error` field holding the message,
+  /// or null if the script threw null.
+  Error,
+
+  /// A param filled in by the caller of the graph: the root of the schema, or the auth context on
+  /// the server. Clients pass null.
+  Implicit,
+
+  /// A persisted table, set or map, which can be neither passed nor returned.
+  Opaque {
+    vm_type: String,
+  },
+}
+
+/// Returns the signatures of the exported graphs of a typechecked script, ordered by graph index.
+pub fn exported_graph_signatures(vm: &TwVm, type_info: &GlobalTypeInfo) -> Vec<GraphSignature> {
+  let param_names = vm.script.source_map.as_ref().map(|x| &x.param_names);
+  vm.script
+    .graphs
+    .iter()
+    .enumerate()
+    .filter(|(_, g)| g.exported)
+    .map(|(i, g)| {
+      let names = param_names.and_then(|x| x.get(i));
+      GraphSignature {
+        name: g.name.clone(),
+        params: type_info.graphs[i]
+          .params
+          .iter()
+          .enumerate()
+          .map(|(j, ty)| ParamSignature {
+            name: names.and_then(|x| x.get(j)).cloned(),
+            shape: ValueShape::from(ty),
+          })
+          .collect(),
+        output: type_info.graphs[i].output.as_ref().map(ValueShape::from),
+      }
+    })
+    .collect()
+}
+
+impl<K: AsRef<str> + Clone + Ord + PartialOrd + Eq + PartialEq> From<&VmType<K>> for ValueShape {
+  fn from(ty: &VmType<K>) -> Self {
+    match ty {
+      VmType::Bool => Self::Bool,
+      VmType::Primitive(PrimitiveType::Int64) => Self::Int64,
+      VmType::Primitive(PrimitiveType::Double) => Self::Double,
+      VmType::Primitive(PrimitiveType::String) => Self::String,
+      VmType::Primitive(PrimitiveType::Bytes) => Self::Bytes,
+      VmType::List(x) => Self::List {
+        items: Box::new(Self::from(&*x.ty)),
+      },
+      VmType::Map(x) => Self::Map {
+        fields: x
+          .iter()
+          .map(|(k, v)| (k.as_ref().to_string(), Self::from(v)))
+          .collect(),
+      },
+      VmType::OneOf(x) => Self::OneOf {
+        variants: x.iter().map(Self::from).collect(),
+      },
+      VmType::Error => Self::Error,
+      VmType::Schema | VmType::AuthContext => Self::Implicit,
+      VmType::Table(_) | VmType::Set(_) | VmType::Dict(_) | VmType::Unknown => Self::Opaque {
+        vm_type: ty.to_string(),
+      },
+    }
+  }
+}
+
+impl ValueShape {
+  /// Converts this shape into a JSON Schema of the values encoded with `config`. Nulls are
+  /// allowed everywhere.
+  pub fn to_json_schema(&self, config: &VmValueEncodeConfig) -> Value {
+    match self {
+      Self::Bool => json!({ "type": ["boolean", "null"] }),
+      Self::Int64 if config.enable_int64 => json!({ "type": ["integer", "null"] }),
+      Self::Int64 => json!({ "type": ["string", "null"], "format": "int64" }),
+      Self::Double if config.enable_double => json!({ "type": ["number", "null"] }),
+      Self::Double => json!({ "type": ["string", "null"], "format": "double" }),
+      Self::String => json!({ "type": ["string", "null"] }),
+      Self::Bytes if config.enable_bytes => json!({
+        "type": ["array", "null"],
+        "items": { "type": "integer", "minimum": 0, "maximum": 255 }
+      }),
+      Self::Bytes => json!({ "type": ["string", "null"], "contentEncoding": "base64" }),
+      Self::List { items } => tagged_json_schema(
+        "L",
+        json!({
+          "type": "array",
+          "items": items.to_json_schema(config)
+        }),
+      ),
+      Self::Map { fields } => tagged_json_schema(
+        "M",
+        json!({
+          "type": "object",
+          "properties": fields
+            .iter()
+            .map(|(k, v)| (k.clone(), v.to_json_schema(config)))
+            .collect::<serde_json::Map<_, _>>(),
+          "additionalProperties": false
+        }),
+      ),
+      Self::OneOf { variants } => json!({
+        "anyOf": variants.iter().map(|x| x.to_json_schema(config)).collect::<Vec<_>>()
+      }),
+      Self::Error => Self::Map {
+        fields: std::iter::once(("error".to_string(), Self::String)).collect(),
+      }
+      .to_json_schema(config),
+      Self::Implicit => json!({ "type": "null" }),
+      Self::Opaque { vm_type } => json!({
+        "not": {},
+        "description": format!("unserializable type `{}`", vm_type)
+      }),
+    }
+  }
+}
+
+/// Maps and lists are encoded as `TaggedVmValue`, an object with a single key naming the variant.
+fn tagged_json_schema(tag: &str, inner: Value) -> Value {
+  json!({
+    "anyOf": [
+      {
+        "type": "object",
+        "properties": { tag: inner },
+        "required": [tag],
+        "additionalProperties": false
+      },
+      { "type": "null" }
+    ]
+  })
+}
@@ -0,0 +1,142 @@
+use bumpalo::Bump;
+use serde_json::json;
+
+use crate::{
+  data::treewalker::{
+    asm::codegen::compile_twscript,
+    serialize::VmValueEncodeConfig,
+    signature::{exported_graph_signatures, GraphSignature, ParamSignature, ValueShape},
+    typeck::GlobalTyckContext,
+    vm::TwVm,
+  },
+  schema::{compile::compile, grammar::parse},
+  storage_plan::planner::generate_plan_for_schema,
+};
+
+fn signatures(schema: &str, script: &str) -> Vec<GraphSignature> {
+  let alloc = Bump::new();
+  let ast = parse(&alloc, schema).unwrap();
+  let schema = compile(&ast).unwrap();
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let script = compile_twscript(script).unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  exported_graph_signatures(&vm, &type_info)
+}
+
+#[test]
+fn extracts_exported_graph_signatures() {
+  let _ = pretty_env_logger::try_init();
+  let sigs = signatures(
+    r#"
+    type Item {
+      @primary
+      id: string,
+    }
+    export set<Item> items;
+    "#,
+    r#"
+    export graph echo(root: schema, id: string, opts: map { limit: int64, tags: list<string> }): map {
+      id: string,
+      limit: int64,
+    } {
+      return m_insert(id) id $ m_insert(limit) opts.limit create_map;
+    }
+    export graph insert(root: schema, id: string) {
+      s_insert root.items $ build_table(Item) $ m_insert(id) id create_map;
+    }
+    export graph item(root: schema, id: string): Item {
+      return point_get root.items id;
+    }
+    graph helper(x: int64): int64 {
+      return x;
+    }
+    "#,
+  );
+  assert_eq!(
+    sigs.iter().map(|x| x.name.as_str()).collect::<Vec<_>>(),
+    vec!["echo", "insert", "item"]
+  );
+
+  let echo = &sigs[0];
+  assert_eq!(
+    echo.params,
+    vec![
+      ParamSignature {
+        name: Some("root".into()),
+        shape: ValueShape::Implicit,
+      },
+      ParamSignature {
+        name: Some("id".into()),
+        shape: ValueShape::String,
+      },
+      ParamSignature {
+        name: Some("opts".into()),
+        shape: ValueShape::Map {
+          fields: vec![
+            ("limit".to_string(), ValueShape::Int64),
+            (
+              "tags".to_string(),
+              ValueShape::List {
+                items: Box::new(ValueShape::String),
+              },
+            ),
+          ]
+          .into_iter()
+          .collect(),
+        },
+      },
+    ]
+  );
+  assert!(matches!(echo.output, Some(ValueShape::Map { .. })));
+  assert_eq!(sigs[1].output, None);
+  assert!(matches!(sigs[2].output, Some(ValueShape::Opaque { .. })));
+
+  // Signatures are serializable for clients.
+  let json = serde_json::to_value(&sigs[1]).unwrap();
+  assert_eq!(
+    json,
+    json!({
+      "name": "insert",
+      "params": [
+        { "name": "root", "shape": { "kind": "implicit" } },
+        { "name": "id", "shape": { "kind": "string" } }
+      ],
+      "output": null
+    })
+  );
+}
+
+#[test]
+fn json_schemas_follow_encoding() {
+  let shape = ValueShape::Map {
+    fields: vec![
+      ("n".to_string(), ValueShape::Int64),
+      (
+        "l".to_string(),
+        ValueShape::List {
+          items: Box::new(ValueShape::Bool),
+        },
+      ),
+    ]
+    .into_iter()
+    .collect(),
+  };
+  let schema = shape.to_json_schema(&Default::default());
+  let m = &schema["anyOf"][0]["properties"]["M"];
+  assert_eq!(m["properties"]["n"]["type"], json!(["string", "null"]));
+  assert_eq!(
+    m["properties"]["l"]["anyOf"][0]["properties"]["L"]["items"]["type"],
+    json!(["boolean", "null"])
+  );
+
+  let config = VmValueEncodeConfig {
+    enable_int64: true,
+    ..Default::default()
+  };
+  let schema = shape.to_json_schema(&config);
+  assert_eq!(
+    schema["anyOf"][0]["properties"]["M"]["properties"]["n"]["type"],
+    json!(["integer", "null"])
+  );
+}
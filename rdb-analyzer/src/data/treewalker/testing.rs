@@ -0,0 +1,280 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use bumpalo::Bump;
+use thiserror::Error;
+
+use crate::{
+  data::mock_kv::MockKv,
+  schema::{
+    compile::{compile, CompiledSchema, FieldType},
+    grammar::parse,
+  },
+  storage_plan::{planner::generate_plan_for_schema, StoragePlan},
+};
+
+use super::{
+  asm::codegen::compile_twscript,
+  bytecode::{TwGraph, TwGraphNode, TwScript},
+  exec::{generate_root_map, Executor},
+  serialize::{SerializedVmValue, VmValueEncodeConfig},
+  typeck::GlobalTyckContext,
+  vm::TwVm,
+  vm_value::{VmConst, VmType},
+};
+
+#[derive(Error, Debug)]
+pub enum TestingError {
+  #[error("export not found: `{0}`")]
+  ExportNotFound(String),
+
+  #[error("fixture of export `{0}` must be a table, or a set or table for set exports")]
+  InvalidFixture(String),
+
+  #[error("param count mismatch: expected {0}, got {1}")]
+  ParamCountMismatch(usize, usize),
+
+  #[error("output of graph `{graph}` mismatch:\nexpected: {expected}\nactual: {actual}")]
+  OutputMismatch {
+    graph: String,
+    expected: String,
+    actual: String,
+  },
+}
+
+/// Data inserted into an export before the graph under test runs.
+///
+/// For a set export, `value` is either a set whose members are all inserted, or a single member.
+/// For a table export, `value` is a table whose fields are written into the export.
+#[derive(Clone, Debug)]
+pub struct Fixture {
+  pub export: String,
+  pub value: VmConst,
+}
+
+impl Fixture {
+  pub fn new(export: impl Into<String>, value: VmConst) -> Self {
+    Self {
+      export: export.into(),
+      value,
+    }
+  }
+}
+
+/// Runs the graphs of a script against a schema and a fresh `MockKv`, without a server. Exported
+/// graphs take serialized params, like in the HTTP API:
+///
+/// ```ignore
+/// let harness = GraphTestHarness::new(SCHEMA, SCRIPT)?;
+/// harness.load_fixtures(&[Fixture::new("items", item)]).await?;
+/// harness
+///   .assert_output("get_name", &[SerializedVmValue::String("a".into())], json!("first"))
+///   .await?;
+/// ```
+pub struct GraphTestHarness {
+  schema: CompiledSchema,
+  plan: StoragePlan,
+  script: TwScript,
+  kv: MockKv,
+  encode_config: VmValueEncodeConfig,
+}
+
+impl GraphTestHarness {
+  /// Compiles a schema and a script, with a fresh storage plan.
+  pub fn new(schema: &str, script: &str) -> Result<Self> {
+    let schema = compile(&parse(&Bump::new(), schema)?)?;
+    let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema)?;
+    let script = compile_twscript(script)?;
+    Self::from_parts(schema, plan, script)
+  }
+
+  /// Creates a harness from an already compiled schema, plan and script. The script is optimized
+  /// and typechecked upfront.
+  pub fn from_parts(
+    schema: CompiledSchema,
+    plan: StoragePlan,
+    mut script: TwScript,
+  ) -> Result<Self> {
+    {
+      let vm = TwVm::new_optimized(&schema, &plan, &mut script)?;
+      GlobalTyckContext::new(&vm)?.typeck()?;
+    }
+    Ok(Self {
+      schema,
+      plan,
+      script,
+      kv: MockKv::new(),
+      encode_config: VmValueEncodeConfig::default(),
+    })
+  }
+
+  /// Sets the config used to encode graph outputs.
+  pub fn set_encode_config(&mut self, config: VmValueEncodeConfig) {
+    self.encode_config = config;
+  }
+
+  /// The store the graphs run against.
+  pub fn kv(&self) -> &MockKv {
+    &self.kv
+  }
+
+  /// Inserts fixture data into the store, in one transaction.
+  pub async fn load_fixtures(&self, fixtures: &[Fixture]) -> Result<()> {
+    let script = generate_fixture_script(&self.schema, fixtures)?;
+    let vm = TwVm::new(&self.schema, &self.plan, &script)?;
+    let type_info = GlobalTyckContext::new(&vm)?.typeck()?;
+    let root_map = Arc::new(generate_root_map(&self.schema, &self.plan)?);
+    Executor::new(&vm, &self.kv, &type_info)
+      .run_graph(0, &[root_map])
+      .await?;
+    Ok(())
+  }
+
+  /// Runs an exported graph and returns its serialized output. Params of the `schema` type are
+  /// filled in automatically and must not be included in `params`.
+  pub async fn run(&self, graph: &str, params: &[SerializedVmValue]) -> Result<SerializedVmValue> {
+    let vm = TwVm::new(&self.schema, &self.plan, &self.script)?;
+    let type_info = GlobalTyckContext::new(&vm)?.typeck()?;
+    let root_map = Arc::new(generate_root_map(&self.schema, &self.plan)?);
+
+    let graph_index = vm.lookup_exported_graph_by_name(graph)?;
+    let param_types = &type_info.graphs[graph_index].params;
+    let raw_param_types = self.script.graphs[graph_index]
+      .param_types
+      .iter()
+      .map(|x| &vm.types[*x as usize])
+      .collect::<Vec<_>>();
+    let num_user_params = raw_param_types
+      .iter()
+      .filter(|x| !matches!(x, VmType::Schema))
+      .count();
+    if num_user_params != params.len() {
+      return Err(TestingError::ParamCountMismatch(num_user_params, params.len()).into());
+    }
+
+    let mut user_params = params.iter();
+    let params = param_types
+      .iter()
+      .zip(raw_param_types)
+      .map(|(ty, raw_ty)| match raw_ty {
+        VmType::Schema => Ok(root_map.clone()),
+        _ => user_params
+          .next()
+          .unwrap()
+          .decode_in(ty, &self.schema, &self.plan)
+          .map(Arc::new),
+      })
+      .collect::<Result<Vec<_>>>()?;
+
+    let output = Executor::new(&vm, &self.kv, &type_info)
+      .run_graph(graph_index, &params)
+      .await?;
+    Ok(
+      output
+        .map(|x| SerializedVmValue::encode(&*x, &self.encode_config))
+        .transpose()?
+        .unwrap_or_else(|| SerializedVmValue::Null(None)),
+    )
+  }
+
+  /// Runs an exported graph and compares its output, in JSON, with `expected`.
+  pub async fn assert_output(
+    &self,
+    graph: &str,
+    params: &[SerializedVmValue],
+    expected: serde_json::Value,
+  ) -> Result<()> {
+    let actual = serde_json::to_value(&self.run(graph, params).await?)?;
+    if actual != expected {
+      return Err(
+        TestingError::OutputMismatch {
+          graph: graph.to_string(),
+          expected: expected.to_string(),
+          actual: actual.to_string(),
+        }
+        .into(),
+      );
+    }
+    Ok(())
+  }
+}
+
+/// Generates a script with a single graph that takes the schema and inserts `fixtures`.
+fn generate_fixture_script(schema: &CompiledSchema, fixtures: &[Fixture]) -> Result<TwScript> {
+  let mut script = TwScript {
+    types: vec![VmType::Schema],
+    ..Default::default()
+  };
+  let mut nodes = vec![(TwGraphNode::LoadParam(0), vec![], None)];
+
+  for fixture in fixtures {
+    let export_ty = schema
+      .exports
+      .get(fixture.export.as_str())
+      .ok_or_else(|| TestingError::ExportNotFound(fixture.export.clone()))?;
+    let export = nodes.len() as u32;
+    let ident = push_ident(&mut script, &fixture.export);
+    nodes.push((TwGraphNode::GetField(ident), vec![0], None));
+
+    match (export_ty, &fixture.value) {
+      (FieldType::Set(_), VmConst::Set(x)) => {
+        for member in &x.members {
+          let value = push_const(&mut script, &mut nodes, member.clone());
+          nodes.push((TwGraphNode::InsertIntoSet, vec![value, export], None));
+        }
+      }
+      (FieldType::Set(_), VmConst::Table(_)) => {
+        let value = push_const(&mut script, &mut nodes, fixture.value.clone());
+        nodes.push((TwGraphNode::InsertIntoSet, vec![value, export], None));
+      }
+      (FieldType::Table(_), VmConst::Table(x)) => {
+        for (name, field) in &x.fields {
+          let value = push_const(&mut script, &mut nodes, field.clone());
+          let ident = push_ident(&mut script, name);
+          nodes.push((
+            TwGraphNode::InsertIntoTable(ident),
+            vec![value, export],
+            None,
+          ));
+        }
+      }
+      _ => return Err(TestingError::InvalidFixture(fixture.export.clone()).into()),
+    }
+  }
+
+  script.graphs.push(TwGraph {
+    name: "load_fixtures".into(),
+    exported: false,
+    nodes,
+    output: None,
+    named_outputs: vec![],
+    param_constraints: vec![],
+    param_types: vec![0],
+    output_type: None,
+  });
+  Ok(script)
+}
+
+fn push_ident(script: &mut TwScript, ident: &str) -> u32 {
+  match script.idents.iter().position(|x| x == ident) {
+    Some(x) => x as u32,
+    None => {
+      script.idents.push(ident.to_string());
+      (script.idents.len() - 1) as u32
+    }
+  }
+}
+
+fn push_const(
+  script: &mut TwScript,
+  nodes: &mut Vec<(TwGraphNode, Vec<u32>, Option<u32>)>,
+  value: VmConst,
+) -> u32 {
+  script.consts.push(value);
+  nodes.push((
+    TwGraphNode::LoadConst((script.consts.len() - 1) as u32),
+    vec![],
+    None,
+  ));
+  (nodes.len() - 1) as u32
+}
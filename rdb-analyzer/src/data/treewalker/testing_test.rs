@@ -0,0 +1,142 @@
+use serde_json::json;
+
+use crate::data::{
+  treewalker::{
+    serialize::SerializedVmValue,
+    testing::{Fixture, GraphTestHarness, TestingError},
+    vm_value::{VmConst, VmConstSetValue, VmConstTableValue},
+  },
+  value::PrimitiveValue,
+};
+
+const SCHEMA: &str = r#"
+type Item {
+  @primary
+  id: string,
+  name: string,
+}
+type Config {
+  title: string,
+}
+export set<Item> items;
+export Config config;
+"#;
+
+const SCRIPT: &str = r#"
+export graph get_item_name(root: schema, id: string): string {
+  return (point_get root.items id).name;
+}
+export graph get_title(root: schema): string {
+  return root.config.title;
+}
+"#;
+
+fn item(id: &str, name: &str) -> VmConst {
+  VmConst::Table(VmConstTableValue {
+    ty: "Item".into(),
+    fields: vec![
+      (
+        "id".to_string(),
+        VmConst::Primitive(PrimitiveValue::String(id.into())),
+      ),
+      (
+        "name".to_string(),
+        VmConst::Primitive(PrimitiveValue::String(name.into())),
+      ),
+    ]
+    .into_iter()
+    .collect(),
+  })
+}
+
+#[tokio::test]
+async fn run_graphs_against_fixtures() {
+  let _ = pretty_env_logger::try_init();
+  let harness = GraphTestHarness::new(SCHEMA, SCRIPT).unwrap();
+  harness
+    .load_fixtures(&[
+      Fixture::new(
+        "items",
+        VmConst::Set(VmConstSetValue {
+          member_ty: "Item".into(),
+          members: vec![item("a", "first"), item("b", "second")],
+        }),
+      ),
+      Fixture::new("items", item("c", "third")),
+      Fixture::new(
+        "config",
+        VmConst::Table(VmConstTableValue {
+          ty: "Config".into(),
+          fields: vec![(
+            "title".to_string(),
+            VmConst::Primitive(PrimitiveValue::String("hello".into())),
+          )]
+          .into_iter()
+          .collect(),
+        }),
+      ),
+    ])
+    .await
+    .unwrap();
+
+  for (id, name) in &[("a", "first"), ("b", "second"), ("c", "third")] {
+    harness
+      .assert_output(
+        "get_item_name",
+        &[SerializedVmValue::String(id.to_string())],
+        json!(name),
+      )
+      .await
+      .unwrap();
+  }
+  harness
+    .assert_output(
+      "get_item_name",
+      &[SerializedVmValue::String("d".into())],
+      json!(null),
+    )
+    .await
+    .unwrap();
+  harness
+    .assert_output("get_title", &[], json!("hello"))
+    .await
+    .unwrap();
+
+  let err = harness
+    .assert_output("get_title", &[], json!("bye"))
+    .await
+    .unwrap_err();
+  assert!(matches!(
+    err.downcast_ref::<TestingError>(),
+    Some(TestingError::OutputMismatch { .. })
+  ));
+  assert!(harness
+    .run("get_title", &[SerializedVmValue::Bool(true)])
+    .await
+    .is_err());
+}
+
+#[tokio::test]
+async fn reject_invalid_fixtures() {
+  let harness = GraphTestHarness::new(SCHEMA, SCRIPT).unwrap();
+  let err = harness
+    .load_fixtures(&[Fixture::new("nothing", item("a", "b"))])
+    .await
+    .unwrap_err();
+  assert!(matches!(
+    err.downcast_ref::<TestingError>(),
+    Some(TestingError::ExportNotFound(_))
+  ));
+
+  let err = harness
+    .load_fixtures(&[Fixture::new(
+      "config",
+      VmConst::Primitive(PrimitiveValue::String("config".into())),
+    )])
+    .await
+    .unwrap_err();
+  assert!(matches!(
+    err.downcast_ref::<TestingError>(),
+    Some(TestingError::InvalidFixture(_))
+  ));
+}
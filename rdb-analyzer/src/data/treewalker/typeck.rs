@@ -1,5 +1,6 @@
 use std::{
   collections::{HashMap, HashSet},
+  fmt::Display,
   sync::Arc,
 };
 
@@ -10,10 +11,11 @@ use thiserror::Error;
 
 use crate::{
   data::treewalker::{
-    bytecode::TwGraphNode,
-    vm_value::{VmListType, VmSetType, VmTableType},
+    bytecode::{TwGraphNode, TwParamConstraint, TwSourcePosition},
+    serialize::page_type,
+    vm_value::{VmListType, VmSetType, VmTableType, AUTH_CONTEXT_FIELDS},
   },
-  schema::compile::{FieldAnnotationList, PrimitiveType},
+  schema::compile::{FieldAnnotationList, PrimitiveType, SpecializedType},
 };
 
 use super::{bytecode::TwGraph, vm::TwVm, vm_value::VmType};
@@ -42,6 +44,8 @@ pub enum TypeckError {
   ExpectingList(String),
   #[error("expecting set, got `{0}`")]
   ExpectingSet(String),
+  #[error("expecting map<string, T>, got `{0}`")]
+  ExpectingDict(String),
   #[error("type `{0}` is not covariant from `{1}`")]
   NonCovariantTypes(String, String),
   #[error("type `{0}` is not equal to `{1}`")]
@@ -80,8 +84,12 @@ pub enum TypeckError {
   NotPrimaryKey(String, Arc<str>),
   #[error("unknown type of param {0} is not resolved in subgraph {1}")]
   UnknownParamTypeNotResolved(u32, u32),
-  #[error("multiple candidate types for param {0} in subgraph {1}: {2}")]
+  #[error(
+    "ambiguous type for param {0} in subgraph {1}: callers pass {2}, and none of them accepts all others"
+  )]
   MultipleParamTypeCandidates(u32, u32, String),
+  #[error("output type of subgraph {0} cannot be inferred: add a return type annotation")]
+  UnknownOutputTypeNotResolved(u32),
   #[error("param count mismatch in {0}: expected {1}, got {2}")]
   ParamCountMismatch(&'static str, u32, u32),
   #[error("select type mismatch: `{0}` != `{1}`")]
@@ -96,20 +104,151 @@ pub enum TypeckError {
   CannotBuildSetFromList(String),
   #[error("not a list: `{0}`")]
   NotList(String),
-  #[error("not a list or set: `{0}`")]
+  #[error("not a list, set or map<string, T>: `{0}`")]
   NotListOrSet(String),
   #[error("missing output from a reduce function")]
   MissingOutputFromReduce,
+  #[error("missing output from a loop_until function")]
+  MissingOutputFromLoop,
   #[error("cannot insert primary key into a table")]
   CannotInsertPrimaryKey,
-  #[error("range reduce used on a non-set type")]
+  #[error("cannot insert sort key into a table: re-insert the set member instead")]
+  CannotInsertSortKey,
+  #[error("cannot insert into a field maintained by `@timestamps`")]
+  CannotInsertTimestamp,
+  #[error(
+    "cannot insert into a field maintained by `@soft_delete`: delete the set member instead"
+  )]
+  CannotInsertDeletedAt,
+  #[error("range reduce used on a non-set, non-map type")]
   RangeReduceOnNonSet,
+  #[error("reverse reduce used on a non-set, non-map type")]
+  ReverseReduceOnNonSet,
+  #[error("reduce by sort key used on a type that is not a set with a sort key: `{0}`")]
+  NoSortKey(String),
+  #[error("select candidates {0} and {1} are both unconditional and would always fire together")]
+  UnconditionalSelectCandidates(u32, u32),
+  #[error("missing output from a try_call subgraph")]
+  MissingOutputFromTryCall,
+  #[error("expecting one_of<T, error>, got `{0}`")]
+  ExpectingFallible(String),
+  #[error(
+    "param {0} of `{1}` is not optional but may be null: guard it with `is_null`, `is_present` or `??` first"
+  )]
+  PossiblyNullParam(u32, &'static str),
+  #[error("cannot find a common type for the values of map `{0}`")]
+  NoCommonMapValueType(String),
+  #[error("cannot merge `{1}` into `{0}`")]
+  BadMergeOperands(String, String),
+  #[error("page size must be greater than zero")]
+  ZeroPageSize,
+  #[error("a graph cannot have both an output and named outputs")]
+  OutputAndNamedOutputs,
+  #[error("duplicate output name: `{0}`")]
+  DuplicateOutputName(String),
+  #[error("`{0}` does not apply to params of type `{1}`")]
+  BadParamConstraint(String, String),
+  #[error("field `{0}` of table `{1}` is not a `@blob` field")]
+  NotBlobField(String, Arc<str>),
+}
+
+/// A typeck error with its location in the script.
+#[derive(Debug)]
+pub struct TypeckDiagnostic {
+  pub graph_index: u32,
+  pub graph_name: String,
+
+  /// The node the error was found at. `None` for errors in the graph signature.
+  pub node_index: Option<u32>,
+
+  /// The opcode of the node, in debug format.
+  pub opcode: Option<String>,
+
+  /// The position of the node in the assembly source, if the script has a source map.
+  pub position: Option<TwSourcePosition>,
+
+  /// The expected and actual types, for type mismatches.
+  pub expected: Option<String>,
+  pub actual: Option<String>,
+
+  /// The innermost mismatching part of the expected and actual types, if they are nested.
+  pub difference: Option<String>,
+
+  /// The node producing the offending value.
+  pub related_node: Option<RelatedNode>,
+
+  pub error: anyhow::Error,
+}
+
+#[derive(Debug, Clone)]
+pub struct RelatedNode {
+  pub index: u32,
+  pub opcode: String,
+  pub ty: Option<String>,
+}
+
+impl std::error::Error for TypeckDiagnostic {}
+
+impl Display for TypeckDiagnostic {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "graph {} `{}`", self.graph_index, self.graph_name)?;
+    if let Some(i) = self.node_index {
+      write!(f, ", node {}", i)?;
+    }
+    if let Some(x) = &self.opcode {
+      write!(f, " ({})", x)?;
+    }
+    if let Some(x) = &self.position {
+      write!(f, " at {}", x)?;
+    }
+    write!(f, ": {}", self.error)?;
+    if let Some(x) = &self.expected {
+      write!(f, "\n  expected: `{}`", x)?;
+    }
+    if let Some(x) = &self.actual {
+      write!(f, "\n  actual:   `{}`", x)?;
+    }
+    if let Some(x) = &self.difference {
+      write!(f, "\n  {}", x)?;
+    }
+    if let Some(x) = &self.related_node {
+      write!(f, "\n  related: node {} ({})", x.index, x.opcode)?;
+      if let Some(ty) = &x.ty {
+        write!(f, " of type `{}`", ty)?;
+      }
+    }
+    Ok(())
+  }
+}
+
+/// A type error with the mismatching types, rendered for diagnostics.
+#[derive(Error, Debug)]
+#[error("{error}")]
+struct TypeMismatch {
+  expected: String,
+  actual: String,
+  difference: Option<String>,
+  error: TypeckError,
+}
+
+impl TypeMismatch {
+  fn new<'a>(expected: &VmType<&'a str>, actual: &VmType<&'a str>, error: TypeckError) -> Self {
+    Self {
+      expected: expected.to_string(),
+      actual: actual.to_string(),
+      difference: first_difference(expected, actual, ""),
+      error,
+    }
+  }
 }
 
 pub struct GlobalTyckContext<'a, 'b> {
   vm: &'b TwVm<'a>,
   scc_post_order: Vec<HashSet<u32>>,
   subgraph_expected_param_types: Vec<Vec<HashSet<VmType<&'a str>>>>,
+
+  /// Output types of subgraphs declared as `unknown`, once inferred from their output nodes.
+  inferred_output_types: Vec<Option<VmType<&'a str>>>,
 }
 
 #[derive(Debug)]
@@ -121,6 +260,12 @@ pub struct GlobalTypeInfo<'a> {
 pub struct GraphTypeInfo<'a> {
   pub params: Vec<VmType<&'a str>>,
   pub nodes: Vec<Option<VmType<&'a str>>>,
+
+  /// The declared output type, or the inferred one if declared as `unknown`.
+  pub output: Option<VmType<&'a str>>,
+
+  /// Whether each node may evaluate to null. See `node_may_be_null`.
+  pub nullable: Vec<bool>,
 }
 
 impl<'a, 'b> GlobalTyckContext<'a, 'b> {
@@ -167,37 +312,89 @@ impl<'a, 'b> GlobalTyckContext<'a, 'b> {
       vm,
       scc_post_order: all_sccs,
       subgraph_expected_param_types,
+      inferred_output_types: vm.script.graphs.iter().map(|_| None).collect(),
     })
   }
 
+  /// Typechecks all graphs, inferring `unknown` param and output types.
+  ///
+  /// Param types are inferred from the callers, and output types from the output nodes. Since
+  /// either may depend on the other, graphs that hit an unresolved type are retried until no more
+  /// graphs can be typechecked.
   pub fn typeck(&mut self) -> Result<GlobalTypeInfo<'a>> {
-    let mut type_info = GlobalTypeInfo {
-      graphs: (0..self.vm.script.graphs.len())
-        .map(|_| GraphTypeInfo::default())
-        .collect(),
-    };
+    let mut num_resolved = 0usize;
+    loop {
+      let mut type_info = GlobalTypeInfo {
+        graphs: (0..self.vm.script.graphs.len())
+          .map(|_| GraphTypeInfo::default())
+          .collect(),
+      };
+      let mut first_unresolved: Option<anyhow::Error> = None;
+      let mut resolved = 0usize;
 
-    // Typecheck subgraphs in reversed scc_post_order, to ensure param types can be inferred.
-    for scc in self.scc_post_order.iter().rev() {
-      let mut subgraph_expected_param_types_sink: HashMap<u32, Vec<HashSet<VmType<&'a str>>>> =
-        HashMap::new();
-      for i in scc {
-        log::trace!("typeck: scc {:p}, subgraph {}", scc, i);
-        type_info.graphs[*i as usize] =
-          self.typeck_graph(*i as usize, &mut subgraph_expected_param_types_sink)?;
-      }
-
-      for (i, x) in subgraph_expected_param_types_sink {
-        let y = &mut self.subgraph_expected_param_types[i as usize];
-        assert_eq!(x.len(), y.len());
-        for (x, y) in x.into_iter().zip(y.iter_mut()) {
-          for elem in x {
-            y.insert(elem);
+      // Typecheck subgraphs in reversed scc_post_order, to ensure param types can be inferred.
+      for scc in self.scc_post_order.iter().rev() {
+        let mut subgraph_expected_param_types_sink: HashMap<u32, Vec<HashSet<VmType<&'a str>>>> =
+          HashMap::new();
+        for i in scc {
+          log::trace!("typeck: scc {:p}, subgraph {}", scc, i);
+          match self
+            .typeck_graph(*i as usize, &mut subgraph_expected_param_types_sink)
+            .map_err(|e| self.diagnostic(*i as usize, None, &[], e))
+          {
+            Ok(x) => {
+              if self.declared_output_type(*i) == Some(&VmType::Unknown) {
+                self.inferred_output_types[*i as usize] = x.output.clone();
+              }
+              type_info.graphs[*i as usize] = x;
+              resolved += 1;
+            }
+            Err(e) if is_unresolved_type(&e) => {
+              if first_unresolved.is_none() {
+                first_unresolved = Some(e);
+              }
+            }
+            Err(e) => return Err(e),
+          }
+        }
+
+        for (i, x) in subgraph_expected_param_types_sink {
+          let y = &mut self.subgraph_expected_param_types[i as usize];
+          assert_eq!(x.len(), y.len());
+          for (x, y) in x.into_iter().zip(y.iter_mut()) {
+            for elem in x {
+              y.insert(elem);
+            }
           }
         }
       }
+
+      match first_unresolved {
+        None => return Ok(type_info),
+        Some(e) if resolved <= num_resolved => return Err(e),
+        Some(_) => {
+          log::trace!("typeck: {} subgraphs resolved, retrying", resolved);
+          num_resolved = resolved;
+        }
+      }
+    }
+  }
+
+  fn declared_output_type(&self, graph_index: u32) -> Option<&VmType<&'a str>> {
+    self.vm.script.graphs[graph_index as usize]
+      .output_type
+      .and_then(|x| self.vm.types.get(x as usize))
+  }
+
+  /// The output type of a called subgraph, or `None` if it has no output.
+  fn subgraph_output_type(&self, subgraph_index: u32) -> Result<Option<VmType<&'a str>>> {
+    match self.declared_output_type(subgraph_index) {
+      Some(VmType::Unknown) => self.inferred_output_types[subgraph_index as usize]
+        .clone()
+        .map(Some)
+        .ok_or_else(|| TypeckError::UnknownOutputTypeNotResolved(subgraph_index).into()),
+      x => Ok(x.cloned()),
     }
-    Ok(type_info)
   }
 
   fn typeck_graph(
@@ -212,6 +409,9 @@ impl<'a, 'b> GlobalTyckContext<'a, 'b> {
         return Err(TypeckError::GraphOutputIndexOob.into());
       }
     }
+    if g.output.is_some() && !g.named_outputs.is_empty() {
+      return Err(TypeckError::OutputAndNamedOutputs.into());
+    }
 
     let output_type = g
       .output_type
@@ -242,17 +442,23 @@ impl<'a, 'b> GlobalTyckContext<'a, 'b> {
         }
         (_, true) => {}
         (VmType::Unknown, false) => {
-          if expected.len() != 1 {
-            return Err(
+          // The most general candidate, that all other candidates are covariant to.
+          let ty = expected
+            .iter()
+            .find(|x| expected.iter().all(|y| x.is_covariant_from(y)))
+            .cloned()
+            .ok_or_else(|| {
+              let mut candidates = expected
+                .iter()
+                .map(|x| format!("`{}`", x))
+                .collect::<Vec<_>>();
+              candidates.sort();
               TypeckError::MultipleParamTypeCandidates(
                 i as u32,
                 graph_index as u32,
-                format!("{:?}", expected),
+                candidates.join(", "),
               )
-              .into(),
-            );
-          }
-          let ty = (*expected.iter().next().unwrap()).clone();
+            })?;
           log::trace!(
             "inferred type `{:?}` for subgraph {} param {}",
             ty,
@@ -268,485 +474,850 @@ impl<'a, 'b> GlobalTyckContext<'a, 'b> {
         }
       }
 
-      // Step 2: Special case for the schema type
+      // Step 2: Special case for the schema and auth context types
       match p {
         VmType::Schema => {
           *p = VmType::from(vm.schema);
         }
+        VmType::AuthContext => {
+          *p = VmType::Map(
+            AUTH_CONTEXT_FIELDS
+              .iter()
+              .map(|x| (*x, VmType::Primitive(PrimitiveType::String)))
+              .collect(),
+          );
+        }
         _ => {}
       }
     }
 
+    for (i, constraint) in &g.param_constraints {
+      let ty = params
+        .get(*i as usize)
+        .ok_or_else(|| TypeckError::ParamIndexOob)?;
+      if !constraint_applies_to(constraint, ty) {
+        return Err(
+          TypeckError::BadParamConstraint(constraint.to_string(), format!("{}", ty)).into(),
+        );
+      }
+    }
+
     let mut types: Vec<Option<VmType<&'a str>>> = Vec::with_capacity(g.nodes.len());
+
+    // Whether each node is in a conditional region, i.e. may be skipped at runtime because
+    // a precondition on its path evaluates to false. Skipped nodes produce a typed null at the
+    // graph output.
+    let mut conditional: Vec<bool> = Vec::with_capacity(g.nodes.len());
+    let mut nullable: Vec<bool> = Vec::with_capacity(g.nodes.len());
     for (i, (node, in_edges, precondition)) in g.nodes.iter().enumerate() {
-      // Check in_edges invariant
-      for j in in_edges {
-        let j = *j as usize;
-        if j >= i {
-          return Err(TypeckError::InvalidInEdge.into());
+      let ty = self
+        .typeck_node(
+          graph_index,
+          i,
+          &params,
+          &types,
+          &conditional,
+          subgraph_expected_param_types_sink,
+        )
+        .map_err(|e| self.diagnostic(graph_index, Some(i), &types, e))?;
+      let in_edge_nullable = in_edges
+        .iter()
+        .map(|x| nullable[*x as usize] && !is_null_guarded(g, *precondition, *x))
+        .collect::<Vec<_>>();
+      if let Some((opname, non_optional)) = non_optional_params(node) {
+        if let Some(j) = non_optional.iter().find(|x| in_edge_nullable[**x]) {
+          return Err(self.diagnostic(
+            graph_index,
+            Some(i),
+            &types,
+            TypeckError::PossiblyNullParam(*j as u32, opname).into(),
+          ));
         }
       }
+      nullable.push(ty.is_some() && node_may_be_null(vm, node, &in_edge_nullable));
+      types.push(ty);
+      conditional.push(
+        precondition.is_some()
+          || if node.is_select() {
+            in_edges.iter().all(|x| conditional[*x as usize])
+          } else {
+            in_edges.iter().any(|x| conditional[*x as usize])
+          },
+      );
+    }
 
-      // Check precondition
-      if let Some(j) = precondition {
-        if *j as usize >= i {
-          return Err(TypeckError::InvalidPrecondition.into());
+    let output_node_type = |x: u32| {
+      types
+        .get(x as usize)
+        .ok_or_else(|| TypeckError::OutputNodeIndexOob)
+        .and_then(|x| ensure_type(x.as_ref()))
+    };
+    let actual_output_ty = if g.named_outputs.is_empty() {
+      g.output.map(output_node_type).transpose()?.cloned()
+    } else {
+      // Named outputs are returned as a map.
+      let mut fields = RedBlackTreeMapSync::new_sync();
+      for (name, x) in &g.named_outputs {
+        let name = vm
+          .script
+          .idents
+          .get(*name as usize)
+          .ok_or_else(|| TypeckError::IdentIndexOob)?
+          .as_str();
+        if fields.contains_key(name) {
+          return Err(TypeckError::DuplicateOutputName(name.to_string()).into());
         }
+        fields.insert_mut(name, output_node_type(*x)?.clone());
+      }
+      Some(VmType::Map(fields))
+    };
+    let actual_output_ty = actual_output_ty.as_ref();
+    let output_position = g
+      .output
+      .or_else(|| g.named_outputs.first().map(|x| x.1))
+      .map(|x| x as usize);
+    match (output_type, actual_output_ty) {
+      (Some(VmType::Unknown), Some(_)) => {}
+      (Some(a), Some(b)) => ensure_covariant(a, b)
+        .map_err(|e| self.diagnostic(graph_index, output_position, &types, e))?,
+      (None, None) => {}
+      _ => {
+        return Err(
+          TypeckError::OutputTypeMismatch(
+            format!("{:?}", output_type),
+            format!("{:?}", actual_output_ty),
+          )
+          .into(),
+        )
+      }
+    }
 
-        // Must be either an effect node or a boolean node
-        if types[*j as usize].is_some() && types[*j as usize] != Some(VmType::Bool) {
-          return Err(TypeckError::InvalidPrecondition.into());
-        }
+    let output = match output_type {
+      Some(VmType::Unknown) => actual_output_ty.cloned(),
+      x => x.cloned(),
+    };
+
+    Ok(GraphTypeInfo {
+      nodes: types,
+      params,
+      output,
+      nullable,
+    })
+  }
+
+  fn typeck_node(
+    &self,
+    graph_index: usize,
+    i: usize,
+    params: &[VmType<&'a str>],
+    types: &[Option<VmType<&'a str>>],
+    conditional: &[bool],
+    subgraph_expected_param_types_sink: &mut HashMap<u32, Vec<HashSet<VmType<&'a str>>>>,
+  ) -> Result<Option<VmType<&'a str>>> {
+    let vm = self.vm;
+    let (node, in_edges, precondition) = &vm.script.graphs[graph_index].nodes[i];
+    // Check in_edges invariant
+    for j in in_edges {
+      let j = *j as usize;
+      if j >= i {
+        return Err(TypeckError::InvalidInEdge.into());
       }
+    }
 
-      let ty: Option<VmType<&'a str>> = match node {
-        TwGraphNode::BuildSet => {
-          let [list_ty] = validate_in_edges::<1>(node, in_edges, &types)?;
-          let element_ty = extract_list_element_type(list_ty)?;
-          if !matches!(element_ty, VmType::Table(_)) {
-            return Err(TypeckError::CannotBuildSetFromList(format!("{:?}", element_ty)).into());
-          }
-          Some(VmType::Set(VmSetType {
-            ty: Box::new(element_ty.clone()),
-          }))
-        }
-        TwGraphNode::BuildTable(table_ty) => {
-          let [map_ty] = validate_in_edges::<1>(node, in_edges, &types)?;
-          let table_ty = vm
-            .script
-            .idents
-            .get(*table_ty as usize)
-            .ok_or_else(|| TypeckError::IdentIndexOob)?;
-          let table_ty = vm
-            .schema
-            .types
-            .get(table_ty.as_str())
-            .ok_or_else(|| TypeckError::TableTypeNotFound(table_ty.clone()))?;
-          match map_ty {
-            VmType::Map(x) => {
-              // Check that all keys that exist in the value also exist in the type,
-              // and the actual type matches the declared type.
-              for (name, actual_ty) in x {
-                if let Some((field_ty, _)) = table_ty.fields.get(*name) {
-                  let field_ty = VmType::from(field_ty);
-                  ensure_covariant(&field_ty, actual_ty)?;
-                } else {
-                  return Err(
-                    TypeckError::MapFieldNotPresentInTable(name.to_string(), table_ty.name.clone())
-                      .into(),
-                  );
-                }
-              }
-            }
-            _ => return Err(TypeckError::NotMap(format!("{:?}", map_ty)).into()),
-          }
+    // Check precondition
+    if let Some(j) = precondition {
+      if *j as usize >= i {
+        return Err(TypeckError::InvalidPrecondition.into());
+      }
 
-          Some(VmType::Table(VmTableType {
-            name: &*table_ty.name,
-          }))
+      // Must be either an effect node or a boolean node
+      if types[*j as usize].is_some() && types[*j as usize] != Some(VmType::Bool) {
+        return Err(TypeckError::InvalidPrecondition.into());
+      }
+    }
+
+    let ty: Option<VmType<&'a str>> = match node {
+      TwGraphNode::BuildSet => {
+        let [list_ty] = validate_in_edges::<1>(node, in_edges, &types)?;
+        let element_ty = extract_list_element_type(list_ty)?;
+        if !matches!(element_ty, VmType::Table(_)) {
+          return Err(TypeckError::CannotBuildSetFromList(format!("{:?}", element_ty)).into());
         }
-        TwGraphNode::CreateList(member_ty) => {
-          let member_ty = vm
-            .types
-            .get(*member_ty as usize)
-            .ok_or_else(|| TypeckError::TypeIndexOob)?;
-
-          Some(VmType::List(VmListType {
-            ty: Box::new(member_ty.clone()),
-          }))
-        }
-        TwGraphNode::CreateMap => Some(VmType::Map(RedBlackTreeMapSync::new_sync())),
-        TwGraphNode::DeleteFromSet => {
-          let [primary_key_value_ty, set_ty] = validate_in_edges::<2>(node, in_edges, &types)?;
-          let set_member_ty = extract_set_element_type(set_ty)?;
-          let (key, _) = set_ty.set_primary_key(vm.schema).unwrap();
-          match set_member_ty {
-            VmType::Table(x) => {
-              let table_ty = vm
-                .schema
-                .types
-                .get(x.name)
-                .ok_or_else(|| TypeckError::TableTypeNotFound(x.name.to_string()))?;
-              let (field_ty, _) = table_ty.fields.get(key).ok_or_else(|| {
-                TypeckError::FieldNotPresentInTable(key.to_string(), table_ty.name.clone())
-              })?;
-              let field_ty = VmType::from(field_ty);
-              ensure_covariant(&field_ty, primary_key_value_ty)?;
-              None
+        Some(VmType::Set(VmSetType {
+          ty: Box::new(element_ty.clone()),
+        }))
+      }
+      TwGraphNode::BuildTable(table_ty) | TwGraphNode::BuildTableStrict(table_ty) => {
+        let [map_ty] = validate_in_edges::<1>(node, in_edges, &types)?;
+        let table_ty = vm
+          .script
+          .idents
+          .get(*table_ty as usize)
+          .ok_or_else(|| TypeckError::IdentIndexOob)?;
+        let table_ty = vm
+          .schema
+          .types
+          .get(table_ty.as_str())
+          .ok_or_else(|| TypeckError::TableTypeNotFound(table_ty.clone()))?;
+        match map_ty {
+          VmType::Map(x) => {
+            // Check that all keys that exist in the value also exist in the type,
+            // and the actual type matches the declared type.
+            for (name, actual_ty) in x {
+              if let Some((field_ty, _)) = table_ty.fields.get(*name) {
+                let field_ty = VmType::from(field_ty);
+                ensure_covariant(&field_ty, actual_ty)?;
+              } else {
+                return Err(
+                  TypeckError::MapFieldNotPresentInTable(name.to_string(), table_ty.name.clone())
+                    .into(),
+                );
+              }
             }
-            _ => return Err(TypeckError::NotTable(format!("{:?}", set_member_ty)).into()),
           }
+          _ => return Err(TypeckError::NotMap(format!("{:?}", map_ty)).into()),
         }
-        TwGraphNode::DeleteFromMap(key_index) => {
-          let [map_ty] = validate_in_edges::<1>(node, in_edges, &types)?;
-          let key = vm
-            .script
-            .idents
-            .get(*key_index as usize)
-            .ok_or_else(|| TypeckError::IdentIndexOob)?;
-          match map_ty {
-            VmType::Map(x) => {
-              let mut x = x.clone();
-              x.remove_mut(key.as_str());
-              Some(VmType::Map(x))
-            }
-            _ => return Err(TypeckError::NotMap(format!("{:?}", map_ty)).into()),
+
+        Some(VmType::Table(VmTableType {
+          name: &*table_ty.name,
+        }))
+      }
+      TwGraphNode::CreateList(member_ty) => {
+        let member_ty = vm
+          .types
+          .get(*member_ty as usize)
+          .ok_or_else(|| TypeckError::TypeIndexOob)?;
+
+        Some(VmType::List(VmListType {
+          ty: Box::new(member_ty.clone()),
+        }))
+      }
+      TwGraphNode::CreateMap => Some(VmType::Map(RedBlackTreeMapSync::new_sync())),
+      TwGraphNode::DeleteFromSet => {
+        let [primary_key_value_ty, set_ty] = validate_in_edges::<2>(node, in_edges, &types)?;
+        let set_member_ty = extract_set_element_type(set_ty)?;
+        let (key, _) = set_ty.set_primary_key(vm.schema).unwrap();
+        match set_member_ty {
+          VmType::Table(x) => {
+            let table_ty = vm
+              .schema
+              .types
+              .get(x.name)
+              .ok_or_else(|| TypeckError::TableTypeNotFound(x.name.to_string()))?;
+            let (field_ty, _) = table_ty.fields.get(key).ok_or_else(|| {
+              TypeckError::FieldNotPresentInTable(key.to_string(), table_ty.name.clone())
+            })?;
+            let field_ty = VmType::from(field_ty);
+            ensure_covariant(&field_ty, primary_key_value_ty)?;
+            None
           }
+          _ => return Err(TypeckError::NotTable(format!("{:?}", set_member_ty)).into()),
         }
-        TwGraphNode::GetField(key_index) => {
-          let [map_or_table_ty] = validate_in_edges::<1>(node, in_edges, &types)?;
-          let key = vm
-            .script
-            .idents
-            .get(*key_index as usize)
-            .ok_or_else(|| TypeckError::IdentIndexOob)?;
-          match map_or_table_ty {
-            VmType::Map(x) => Some(
-              x.get(key.as_str())
-                .cloned()
-                .ok_or_else(|| TypeckError::FieldNotPresentInMap(key.clone()))?,
-            ),
-            VmType::Table(x) => {
-              let table_ty = vm
-                .schema
-                .types
-                .get(x.name)
-                .ok_or_else(|| TypeckError::TableTypeNotFound(x.name.to_string()))?;
-              Some(
-                table_ty
-                  .fields
-                  .get(key.as_str())
-                  .map(|x| VmType::from(&x.0))
-                  .ok_or_else(|| {
-                    TypeckError::FieldNotPresentInTable(key.clone(), table_ty.name.clone())
-                  })?,
-              )
-            }
-            _ => return Err(TypeckError::NotMapOrTable(format!("{:?}", map_or_table_ty)).into()),
+      }
+      TwGraphNode::DeleteFromMap(key_index) => {
+        let [map_ty] = validate_in_edges::<1>(node, in_edges, &types)?;
+        let key = vm
+          .script
+          .idents
+          .get(*key_index as usize)
+          .ok_or_else(|| TypeckError::IdentIndexOob)?;
+        match map_ty {
+          VmType::Map(x) => {
+            let mut x = x.clone();
+            x.remove_mut(key.as_str());
+            Some(VmType::Map(x))
           }
+          _ => return Err(TypeckError::NotMap(format!("{:?}", map_ty)).into()),
         }
-        TwGraphNode::GetSetElement => {
-          let [primary_key_value_ty, set_ty] = validate_in_edges::<2>(node, in_edges, &types)?;
-          let set_member_ty = extract_set_element_type(set_ty)?;
-          let (key, _) = set_ty.set_primary_key(vm.schema).unwrap();
-          match set_member_ty {
-            VmType::Table(x) => {
-              let table_ty = vm
-                .schema
-                .types
-                .get(x.name)
-                .ok_or_else(|| TypeckError::TableTypeNotFound(x.name.to_string()))?;
-              let (field_ty, _) = table_ty.fields.get(key).ok_or_else(|| {
-                TypeckError::FieldNotPresentInTable(key.to_string(), table_ty.name.clone())
-              })?;
-              let field_ty = VmType::from(field_ty);
-              ensure_covariant(&field_ty, primary_key_value_ty)?;
-              Some(set_member_ty.clone())
-            }
-            _ => return Err(TypeckError::NotTable(format!("{:?}", set_member_ty)).into()),
+      }
+      TwGraphNode::GetField(key_index) => {
+        let [map_or_table_ty] = validate_in_edges::<1>(node, in_edges, &types)?;
+        let key = vm
+          .script
+          .idents
+          .get(*key_index as usize)
+          .ok_or_else(|| TypeckError::IdentIndexOob)?;
+        match map_or_table_ty {
+          VmType::Map(x) => Some(
+            x.get(key.as_str())
+              .cloned()
+              .ok_or_else(|| TypeckError::FieldNotPresentInMap(key.clone()))?,
+          ),
+          VmType::Table(x) => {
+            let table_ty = vm
+              .schema
+              .types
+              .get(x.name)
+              .ok_or_else(|| TypeckError::TableTypeNotFound(x.name.to_string()))?;
+            Some(
+              table_ty
+                .fields
+                .get(key.as_str())
+                .map(|x| VmType::from(&x.0))
+                .ok_or_else(|| {
+                  TypeckError::FieldNotPresentInTable(key.clone(), table_ty.name.clone())
+                })?,
+            )
           }
+          _ => return Err(TypeckError::NotMapOrTable(format!("{:?}", map_or_table_ty)).into()),
         }
-        TwGraphNode::FilterSet(subgraph_index) => {
-          let [subgraph_param, set_ty] = validate_in_edges::<2>(node, in_edges, &types)?;
-          let set_member_ty = extract_set_element_type(set_ty)?;
-          let subgraph = self.validate_subgraph_call(
-            "FilterSet",
-            *subgraph_index,
-            subgraph_expected_param_types_sink,
-            vec![set_member_ty.clone(), subgraph_param.clone()],
-          )?;
-          let output = subgraph
-            .output_type
-            .and_then(|x| vm.script.types.get(x as usize).map(VmType::<&'a str>::from));
-          if let Some(VmType::Bool) = output {
+      }
+      TwGraphNode::GetSetElement => {
+        let [primary_key_value_ty, set_ty] = validate_in_edges::<2>(node, in_edges, &types)?;
+        let set_member_ty = extract_set_element_type(set_ty)?;
+        let (key, _) = set_ty.set_primary_key(vm.schema).unwrap();
+        match set_member_ty {
+          VmType::Table(x) => {
+            let table_ty = vm
+              .schema
+              .types
+              .get(x.name)
+              .ok_or_else(|| TypeckError::TableTypeNotFound(x.name.to_string()))?;
+            let (field_ty, _) = table_ty.fields.get(key).ok_or_else(|| {
+              TypeckError::FieldNotPresentInTable(key.to_string(), table_ty.name.clone())
+            })?;
+            let field_ty = VmType::from(field_ty);
+            ensure_covariant(&field_ty, primary_key_value_ty)?;
             Some(set_member_ty.clone())
-          } else {
-            return Err(
-              TypeckError::ExpectingBoolOutputForFilterSubgraphs(format!("{:?}", output)).into(),
-            );
           }
+          _ => return Err(TypeckError::NotTable(format!("{:?}", set_member_ty)).into()),
         }
-        TwGraphNode::InsertIntoMap(key_index) => {
-          let [value_ty, map_ty] = validate_in_edges::<2>(node, in_edges, &types)?;
-          let key = vm
-            .script
-            .idents
-            .get(*key_index as usize)
-            .ok_or_else(|| TypeckError::IdentIndexOob)?;
-          match map_ty {
-            VmType::Map(x) => {
-              let mut x = x.clone();
-              x.insert_mut(key.as_str(), value_ty.clone());
-              Some(VmType::Map(x))
-            }
-            _ => return Err(TypeckError::NotMap(format!("{:?}", map_ty)).into()),
-          }
+      }
+      TwGraphNode::FilterSet(subgraph_index) => {
+        let [subgraph_param, set_ty] = validate_in_edges::<2>(node, in_edges, &types)?;
+        let set_member_ty = extract_set_element_type(set_ty)?;
+        self.validate_subgraph_call(
+          "FilterSet",
+          *subgraph_index,
+          subgraph_expected_param_types_sink,
+          vec![set_member_ty.clone(), subgraph_param.clone()],
+        )?;
+        let output = self.subgraph_output_type(*subgraph_index)?;
+        if let Some(VmType::Bool) = output {
+          Some(set_member_ty.clone())
+        } else {
+          return Err(
+            TypeckError::ExpectingBoolOutputForFilterSubgraphs(format!("{:?}", output)).into(),
+          );
         }
-        TwGraphNode::InsertIntoSet => {
-          let [value_ty, set_ty] = validate_in_edges::<2>(node, in_edges, &types)?;
-          match set_ty {
-            VmType::Set(x) => {
-              ensure_covariant(&x.ty, value_ty)?;
-              None
-            }
-            _ => return Err(TypeckError::NotSet(format!("{:?}", set_ty)).into()),
+      }
+      TwGraphNode::InsertIntoMap(key_index) => {
+        let [value_ty, map_ty] = validate_in_edges::<2>(node, in_edges, &types)?;
+        let key = vm
+          .script
+          .idents
+          .get(*key_index as usize)
+          .ok_or_else(|| TypeckError::IdentIndexOob)?;
+        match map_ty {
+          VmType::Map(x) => {
+            let mut x = x.clone();
+            x.insert_mut(key.as_str(), value_ty.clone());
+            Some(VmType::Map(x))
           }
+          _ => return Err(TypeckError::NotMap(format!("{:?}", map_ty)).into()),
         }
-        TwGraphNode::InsertIntoTable(key_index) => {
-          let [value_ty, table_ty] = validate_in_edges::<2>(node, in_edges, &types)?;
-          let key = vm
-            .script
-            .idents
-            .get(*key_index as usize)
-            .ok_or_else(|| TypeckError::IdentIndexOob)?;
-          match table_ty {
-            VmType::Table(x) => {
-              let table_ty = vm
-                .schema
-                .types
-                .get(x.name)
-                .ok_or_else(|| TypeckError::TableTypeNotFound(x.name.to_string()))?;
-              let (field_ty, field_annotations) = table_ty
-                .fields
-                .get(key.as_str())
-                .map(|x| (VmType::from(&x.0), &x.1))
-                .ok_or_else(|| {
-                  TypeckError::FieldNotPresentInTable(key.clone(), table_ty.name.clone())
-                })?;
-              if field_annotations.as_slice().is_primary() {
-                return Err(TypeckError::CannotInsertPrimaryKey.into());
-              }
-              ensure_covariant(&field_ty, value_ty)?;
-              None
-            }
-            _ => return Err(TypeckError::NotTable(format!("{:?}", table_ty)).into()),
+      }
+      TwGraphNode::InsertIntoSet => {
+        let [value_ty, set_ty] = validate_in_edges::<2>(node, in_edges, &types)?;
+        match set_ty {
+          VmType::Set(x) => {
+            ensure_covariant(&x.ty, value_ty)?;
+            let (_, primary_key_ty) = set_ty
+              .set_primary_key(vm.schema)
+              .ok_or_else(|| TypeckError::NotTable(format!("{:?}", x.ty)))?;
+            Some(VmType::from(primary_key_ty))
           }
+          _ => return Err(TypeckError::NotSet(format!("{:?}", set_ty)).into()),
         }
-        TwGraphNode::LoadConst(const_index) => {
-          validate_in_edges::<0>(node, in_edges, &types)?;
-          let const_value = vm
-            .consts
-            .get(*const_index as usize)
-            .ok_or_else(|| TypeckError::ConstIndexOob)?;
-          Some(VmType::from(&**const_value))
-        }
-        TwGraphNode::LoadParam(param_index) => {
-          if *param_index as usize >= params.len() {
-            return Err(TypeckError::ParamIndexOob.into());
-          }
-          Some(params[*param_index as usize].clone())
-        }
-        TwGraphNode::Eq => {
-          let [left, right] = validate_in_edges::<2>(node, in_edges, &types)?;
-          ensure_covariant(left, right)?;
-          Some(VmType::Bool)
-        }
-        TwGraphNode::Ne => {
-          let [left, right] = validate_in_edges::<2>(node, in_edges, &types)?;
-          ensure_covariant(left, right)?;
-          Some(VmType::Bool)
-        }
-        TwGraphNode::And | TwGraphNode::Or => {
-          let [left, right] = validate_in_edges::<2>(node, in_edges, &types)?;
-          ensure_type_eq(left, &VmType::Bool)?;
-          ensure_type_eq(right, &VmType::Bool)?;
-          Some(VmType::Bool)
-        }
-        TwGraphNode::Not => {
-          let [x] = validate_in_edges::<1>(node, in_edges, &types)?;
-          ensure_type_eq(x, &VmType::Bool)?;
-          Some(VmType::Bool)
-        }
-        TwGraphNode::Select => {
-          let [left, right] = validate_in_edges::<2>(node, in_edges, &types)?;
-          if left != right {
-            return Err(
-              TypeckError::SelectTypeMismatch(format!("{:?}", left), format!("{:?}", right)).into(),
-            );
+      }
+      TwGraphNode::InsertIntoTable(key_index) => {
+        let [value_ty, table_ty] = validate_in_edges::<2>(node, in_edges, &types)?;
+        let key = vm
+          .script
+          .idents
+          .get(*key_index as usize)
+          .ok_or_else(|| TypeckError::IdentIndexOob)?;
+        match table_ty {
+          VmType::Table(x) => {
+            let table_ty = vm
+              .schema
+              .types
+              .get(x.name)
+              .ok_or_else(|| TypeckError::TableTypeNotFound(x.name.to_string()))?;
+            ensure_table_field_writable(table_ty, key, value_ty)?;
+            None
           }
-          Some(left.clone())
+          _ => return Err(TypeckError::NotTable(format!("{:?}", table_ty)).into()),
         }
-        TwGraphNode::IsPresent => {
-          let [x] = validate_in_edges::<1>(node, in_edges, &types)?;
-          match x {
-            VmType::Set(_) | VmType::Table(_) => Some(VmType::Bool),
-            _ => {
-              return Err(TypeckError::PresenceCheckOnUnsupportedType(format!("{:?}", x)).into())
+      }
+      TwGraphNode::ApplyPatch => {
+        let [patch_ty, table_ty] = validate_in_edges::<2>(node, in_edges, &types)?;
+        let table_ty = match table_ty {
+          VmType::Table(x) => vm
+            .schema
+            .types
+            .get(x.name)
+            .ok_or_else(|| TypeckError::TableTypeNotFound(x.name.to_string()))?,
+          _ => return Err(TypeckError::NotTable(format!("{:?}", table_ty)).into()),
+        };
+        match patch_ty {
+          VmType::Map(x) => {
+            for (key, value_ty) in x {
+              ensure_table_field_writable(table_ty, key, value_ty)?;
             }
           }
+          _ => return Err(TypeckError::NotMap(format!("{:?}", patch_ty)).into()),
         }
-        TwGraphNode::IsNull => {
-          let [_] = validate_in_edges::<1>(node, in_edges, &types)?;
-          Some(VmType::Bool)
+        None
+      }
+      TwGraphNode::LoadConst(const_index) => {
+        validate_in_edges::<0>(node, in_edges, &types)?;
+        let const_value = vm
+          .consts
+          .get(*const_index as usize)
+          .ok_or_else(|| TypeckError::ConstIndexOob)?;
+        Some(VmType::from(&**const_value))
+      }
+      TwGraphNode::LoadParam(param_index) => {
+        if *param_index as usize >= params.len() {
+          return Err(TypeckError::ParamIndexOob.into());
         }
-        TwGraphNode::Nop => {
-          let [x] = validate_in_edges::<1>(node, in_edges, &types)?;
-          Some(x.clone())
+        Some(params[*param_index as usize].clone())
+      }
+      TwGraphNode::Eq => {
+        let [left, right] = validate_in_edges::<2>(node, in_edges, &types)?;
+        ensure_covariant(left, right)?;
+        Some(VmType::Bool)
+      }
+      TwGraphNode::Ne => {
+        let [left, right] = validate_in_edges::<2>(node, in_edges, &types)?;
+        ensure_covariant(left, right)?;
+        Some(VmType::Bool)
+      }
+      TwGraphNode::And | TwGraphNode::Or => {
+        let [left, right] = validate_in_edges::<2>(node, in_edges, &types)?;
+        ensure_type_eq(left, &VmType::Bool)?;
+        ensure_type_eq(right, &VmType::Bool)?;
+        Some(VmType::Bool)
+      }
+      TwGraphNode::Not => {
+        let [x] = validate_in_edges::<1>(node, in_edges, &types)?;
+        ensure_type_eq(x, &VmType::Bool)?;
+        Some(VmType::Bool)
+      }
+      TwGraphNode::Select => {
+        let [left, right] = validate_in_edges::<2>(node, in_edges, &types)?;
+        if !conditional[in_edges[0] as usize] && !conditional[in_edges[1] as usize] {
+          return Err(TypeckError::UnconditionalSelectCandidates(in_edges[0], in_edges[1]).into());
         }
-        TwGraphNode::Call(subgraph_index) => {
-          let param_types = in_edges
-            .iter()
-            .map(|x| ensure_type(types[*x as usize].as_ref()).map(|x| x.clone()))
-            .collect::<Result<Vec<_>, TypeckError>>()?;
-          let subgraph = self.validate_subgraph_call(
-            "Call",
-            *subgraph_index,
-            subgraph_expected_param_types_sink,
-            param_types,
-          )?;
-          let output = subgraph
-            .output_type
-            .and_then(|x| vm.script.types.get(x as usize).map(VmType::<&'a str>::from));
-          output
-        }
-        TwGraphNode::Add => {
-          let [l, r] = validate_in_edges::<2>(node, in_edges, &types)?;
-          match (l, r) {
-            (VmType::Primitive(PrimitiveType::Int64), VmType::Primitive(PrimitiveType::Int64)) => {
-              Some(VmType::Primitive(PrimitiveType::Int64))
-            }
-            (
-              VmType::Primitive(PrimitiveType::Double),
-              VmType::Primitive(PrimitiveType::Double),
-            ) => Some(VmType::Primitive(PrimitiveType::Double)),
-            (
-              VmType::Primitive(PrimitiveType::String),
-              VmType::Primitive(PrimitiveType::String),
-            ) => Some(VmType::Primitive(PrimitiveType::String)),
-            _ => {
-              return Err(
-                TypeckError::BadBinopOperands(format!("{:?}", l), format!("{:?}", r)).into(),
-              )
-            }
+        if left != right {
+          return Err(
+            TypeckError::SelectTypeMismatch(format!("{:?}", left), format!("{:?}", right)).into(),
+          );
+        }
+        Some(left.clone())
+      }
+      TwGraphNode::IsPresent => {
+        let [x] = validate_in_edges::<1>(node, in_edges, &types)?;
+        match x {
+          VmType::Set(_) | VmType::Table(_) => Some(VmType::Bool),
+          _ => return Err(TypeckError::PresenceCheckOnUnsupportedType(format!("{:?}", x)).into()),
+        }
+      }
+      TwGraphNode::IsNull => {
+        let [_] = validate_in_edges::<1>(node, in_edges, &types)?;
+        Some(VmType::Bool)
+      }
+      TwGraphNode::Nop => {
+        let [x] = validate_in_edges::<1>(node, in_edges, &types)?;
+        Some(x.clone())
+      }
+      TwGraphNode::Call(subgraph_index) => {
+        let param_types = in_edges
+          .iter()
+          .map(|x| ensure_type(types[*x as usize].as_ref()).map(|x| x.clone()))
+          .collect::<Result<Vec<_>, TypeckError>>()?;
+        self.validate_subgraph_call(
+          "Call",
+          *subgraph_index,
+          subgraph_expected_param_types_sink,
+          param_types,
+        )?;
+        self.subgraph_output_type(*subgraph_index)?
+      }
+      TwGraphNode::TryCall(subgraph_index) => {
+        let param_types = in_edges
+          .iter()
+          .map(|x| ensure_type(types[*x as usize].as_ref()).map(|x| x.clone()))
+          .collect::<Result<Vec<_>, TypeckError>>()?;
+        self.validate_subgraph_call(
+          "TryCall",
+          *subgraph_index,
+          subgraph_expected_param_types_sink,
+          param_types,
+        )?;
+        let output = self
+          .subgraph_output_type(*subgraph_index)?
+          .ok_or_else(|| TypeckError::MissingOutputFromTryCall)?;
+        Some(VmType::OneOf(vec![output, VmType::Error]))
+      }
+      TwGraphNode::IsError => {
+        let [x] = validate_in_edges::<1>(node, in_edges, &types)?;
+        extract_fallible_value_type(x)?;
+        Some(VmType::Bool)
+      }
+      TwGraphNode::UnwrapValue => {
+        let [x] = validate_in_edges::<1>(node, in_edges, &types)?;
+        Some(extract_fallible_value_type(x)?.clone())
+      }
+      TwGraphNode::ErrorMessage => {
+        let [x] = validate_in_edges::<1>(node, in_edges, &types)?;
+        extract_fallible_value_type(x)?;
+        Some(VmType::Primitive(PrimitiveType::String))
+      }
+      TwGraphNode::Add => {
+        let [l, r] = validate_in_edges::<2>(node, in_edges, &types)?;
+        match (l, r) {
+          (VmType::Primitive(PrimitiveType::Int64), VmType::Primitive(PrimitiveType::Int64)) => {
+            Some(VmType::Primitive(PrimitiveType::Int64))
+          }
+          (VmType::Primitive(PrimitiveType::Double), VmType::Primitive(PrimitiveType::Double)) => {
+            Some(VmType::Primitive(PrimitiveType::Double))
+          }
+          (VmType::Primitive(PrimitiveType::String), VmType::Primitive(PrimitiveType::String)) => {
+            Some(VmType::Primitive(PrimitiveType::String))
+          }
+          _ => {
+            return Err(
+              TypeckError::BadBinopOperands(format!("{:?}", l), format!("{:?}", r)).into(),
+            )
           }
         }
-        TwGraphNode::Sub => {
-          let [l, r] = validate_in_edges::<2>(node, in_edges, &types)?;
-          match (l, r) {
-            (VmType::Primitive(PrimitiveType::Int64), VmType::Primitive(PrimitiveType::Int64)) => {
-              Some(VmType::Primitive(PrimitiveType::Int64))
-            }
-            (
-              VmType::Primitive(PrimitiveType::Double),
-              VmType::Primitive(PrimitiveType::Double),
-            ) => Some(VmType::Primitive(PrimitiveType::Double)),
-            _ => {
-              return Err(
-                TypeckError::BadBinopOperands(format!("{:?}", l), format!("{:?}", r)).into(),
-              )
-            }
+      }
+      TwGraphNode::Sub => {
+        let [l, r] = validate_in_edges::<2>(node, in_edges, &types)?;
+        match (l, r) {
+          (VmType::Primitive(PrimitiveType::Int64), VmType::Primitive(PrimitiveType::Int64)) => {
+            Some(VmType::Primitive(PrimitiveType::Int64))
+          }
+          (VmType::Primitive(PrimitiveType::Double), VmType::Primitive(PrimitiveType::Double)) => {
+            Some(VmType::Primitive(PrimitiveType::Double))
+          }
+          _ => {
+            return Err(
+              TypeckError::BadBinopOperands(format!("{:?}", l), format!("{:?}", r)).into(),
+            )
           }
         }
-        TwGraphNode::PrependToList => {
-          let [value, list] = validate_in_edges::<2>(node, in_edges, &types)?;
-          match list {
-            VmType::List(x) if x.ty.is_covariant_from(value) => Some(list.clone()),
-            _ => {
-              return Err(
-                TypeckError::InvalidListPrepend(format!("{:?}", list), format!("{:?}", value))
-                  .into(),
-              );
-            }
+      }
+      TwGraphNode::PrependToList => {
+        let [value, list] = validate_in_edges::<2>(node, in_edges, &types)?;
+        match list {
+          VmType::List(x) if x.ty.is_covariant_from(value) => Some(list.clone()),
+          _ => {
+            return Err(
+              TypeckError::InvalidListPrepend(format!("{:?}", list), format!("{:?}", value)).into(),
+            );
           }
         }
-        TwGraphNode::PopFromList => {
-          let [list] = validate_in_edges::<1>(node, in_edges, &types)?;
-          if !matches!(list, VmType::List(_)) {
+      }
+      TwGraphNode::PopFromList => {
+        let [list] = validate_in_edges::<1>(node, in_edges, &types)?;
+        if !matches!(list, VmType::List(_)) {
+          return Err(TypeckError::NotList(format!("{:?}", list)).into());
+        }
+        Some(list.clone())
+      }
+      TwGraphNode::ListHead => {
+        let [list] = validate_in_edges::<1>(node, in_edges, &types)?;
+        match list {
+          VmType::List(x) => Some((*x.ty).clone()),
+          _ => {
             return Err(TypeckError::NotList(format!("{:?}", list)).into());
           }
-          Some(list.clone())
         }
-        TwGraphNode::ListHead => {
-          let [list] = validate_in_edges::<1>(node, in_edges, &types)?;
-          match list {
-            VmType::List(x) => Some((*x.ty).clone()),
+      }
+      TwGraphNode::ListPush => {
+        let [value, list] = validate_in_edges::<2>(node, in_edges, &types)?;
+        ensure_covariant(extract_list_element_type(list)?, value)?;
+        None
+      }
+      TwGraphNode::ListPopBack => {
+        let [list] = validate_in_edges::<1>(node, in_edges, &types)?;
+        Some(extract_list_element_type(list)?.clone())
+      }
+      TwGraphNode::GetListElement => {
+        let [index, list] = validate_in_edges::<2>(node, in_edges, &types)?;
+        ensure_type_eq(&VmType::Primitive(PrimitiveType::Int64), index)?;
+        Some(extract_list_element_type(list)?.clone())
+      }
+      TwGraphNode::GetMapEntry => {
+        let [key, map] = validate_in_edges::<2>(node, in_edges, &types)?;
+        ensure_type_eq(&VmType::Primitive(PrimitiveType::String), key)?;
+        Some(extract_dict_value_type(map)?.clone())
+      }
+      TwGraphNode::PutMapEntry => {
+        let [key, value, map] = validate_in_edges::<3>(node, in_edges, &types)?;
+        ensure_type_eq(&VmType::Primitive(PrimitiveType::String), key)?;
+        ensure_covariant(extract_dict_value_type(map)?, value)?;
+        None
+      }
+      TwGraphNode::DeleteMapEntry => {
+        let [key, map] = validate_in_edges::<2>(node, in_edges, &types)?;
+        ensure_type_eq(&VmType::Primitive(PrimitiveType::String), key)?;
+        extract_dict_value_type(map)?;
+        None
+      }
+      TwGraphNode::IncludeDeleted => {
+        let [set] = validate_in_edges::<1>(node, in_edges, &types)?;
+        extract_set_element_type(set)?;
+        Some(set.clone())
+      }
+      TwGraphNode::PurgeDeleted => {
+        let [cutoff, set] = validate_in_edges::<2>(node, in_edges, &types)?;
+        ensure_type_eq(&VmType::Primitive(PrimitiveType::Int64), cutoff)?;
+        extract_set_element_type(set)?;
+        Some(VmType::Primitive(PrimitiveType::Int64))
+      }
+      TwGraphNode::Reduce(subgraph_index, _)
+      | TwGraphNode::ReduceReverse(subgraph_index, _)
+      | TwGraphNode::ReduceBySortKey(subgraph_index, _)
+      | TwGraphNode::ReduceByPrefix(subgraph_index, _) => {
+        let (has_range, by_sort_key, by_prefix) = match node {
+          TwGraphNode::Reduce(_, has_range) | TwGraphNode::ReduceReverse(_, has_range) => {
+            (*has_range, false, false)
+          }
+          TwGraphNode::ReduceBySortKey(_, has_range) => (*has_range, true, false),
+          TwGraphNode::ReduceByPrefix(_, by_sort_key) => (false, *by_sort_key, true),
+          _ => unreachable!(),
+        };
+        let reverse = matches!(node, TwGraphNode::ReduceReverse(_, _));
+        let key_ty_of = |list_or_set_ty: &VmType<&'a str>| -> Result<VmType<&'a str>> {
+          Ok(match list_or_set_ty {
+            _ if by_sort_key => {
+              let (_, sort_key_ty) = list_or_set_ty
+                .set_sort_key(vm.schema)
+                .ok_or_else(|| TypeckError::NoSortKey(format!("{:?}", list_or_set_ty)))?;
+              VmType::from(sort_key_ty)
+            }
+            VmType::Dict(_) => VmType::Primitive(PrimitiveType::String),
             _ => {
-              return Err(TypeckError::NotList(format!("{:?}", list)).into());
+              let (_, primary_key_ty) = list_or_set_ty
+                .set_primary_key(vm.schema)
+                .ok_or_else(|| TypeckError::RangeReduceOnNonSet)?;
+              VmType::from(primary_key_ty)
             }
-          }
+          })
+        };
+        let subgraph_param;
+        let reduce_init;
+        let list_or_set_ty;
+        if has_range {
+          let [subgraph_param_, reduce_init_, list_or_set_ty_, start_key, end_key] =
+            validate_in_edges::<5>(node, in_edges, &types)?;
+          subgraph_param = subgraph_param_;
+          reduce_init = reduce_init_;
+          list_or_set_ty = list_or_set_ty_;
+
+          let key_ty = key_ty_of(list_or_set_ty)?;
+          ensure_type_eq(&key_ty, start_key)?;
+          ensure_type_eq(&key_ty, end_key)?;
+        } else if by_prefix {
+          let [subgraph_param_, reduce_init_, list_or_set_ty_, prefix] =
+            validate_in_edges::<4>(node, in_edges, &types)?;
+          subgraph_param = subgraph_param_;
+          reduce_init = reduce_init_;
+          list_or_set_ty = list_or_set_ty_;
+
+          // Prefixes only apply to string keys.
+          ensure_type_eq(&VmType::Primitive(PrimitiveType::String), prefix)?;
+          ensure_type_eq(&key_ty_of(list_or_set_ty)?, prefix)?;
+        } else {
+          let [subgraph_param_, reduce_init_, list_or_set_ty_] =
+            validate_in_edges::<3>(node, in_edges, &types)?;
+          subgraph_param = subgraph_param_;
+          reduce_init = reduce_init_;
+          list_or_set_ty = list_or_set_ty_;
         }
-        TwGraphNode::Reduce(subgraph_index, has_range) => {
-          let subgraph_param;
-          let reduce_init;
-          let list_or_set_ty;
-          if *has_range {
-            let [subgraph_param_, reduce_init_, list_or_set_ty_, start_key, end_key] =
-              validate_in_edges::<5>(node, in_edges, &types)?;
-            subgraph_param = subgraph_param_;
-            reduce_init = reduce_init_;
-            list_or_set_ty = list_or_set_ty_;
-
-            let (_, primary_key_ty) = list_or_set_ty
-              .set_primary_key(vm.schema)
-              .ok_or_else(|| TypeckError::RangeReduceOnNonSet)?;
-            let primary_key_ty = VmType::from(primary_key_ty);
-            ensure_type_eq(&primary_key_ty, start_key)?;
-            ensure_type_eq(&primary_key_ty, end_key)?;
-          } else {
-            let [subgraph_param_, reduce_init_, list_or_set_ty_] =
-              validate_in_edges::<3>(node, in_edges, &types)?;
-            subgraph_param = subgraph_param_;
-            reduce_init = reduce_init_;
-            list_or_set_ty = list_or_set_ty_;
-          }
-          let member_ty = match list_or_set_ty {
-            VmType::List(x) => &*x.ty,
-            VmType::Set(x) => &*x.ty,
-            _ => return Err(TypeckError::NotListOrSet(format!("{:?}", list_or_set_ty)).into()),
-          };
-          let subgraph = self.validate_subgraph_call(
-            "Reduce",
-            *subgraph_index,
-            subgraph_expected_param_types_sink,
-            vec![
-              subgraph_param.clone(),
-              reduce_init.clone(),
-              member_ty.clone(),
-            ],
-          )?;
-          let output = subgraph
-            .output_type
-            .and_then(|x| vm.script.types.get(x as usize).map(VmType::<&'a str>::from))
-            .ok_or_else(|| TypeckError::MissingOutputFromReduce)?;
-          ensure_covariant(reduce_init, &output)?;
-          Some(output.clone())
-        }
-        TwGraphNode::Throw => {
-          let [msg] = validate_in_edges::<1>(node, in_edges, &types)?;
-          ensure_type_eq(&VmType::Primitive(PrimitiveType::String), msg)?;
-          None
+        if reverse && matches!(list_or_set_ty, VmType::List(_)) {
+          return Err(TypeckError::ReverseReduceOnNonSet.into());
         }
-      };
-      types.push(ty);
+        if by_sort_key && list_or_set_ty.set_sort_key(vm.schema).is_none() {
+          return Err(TypeckError::NoSortKey(format!("{:?}", list_or_set_ty)).into());
+        }
+        let member_ty = match list_or_set_ty {
+          VmType::List(x) => (*x.ty).clone(),
+          VmType::Set(x) => (*x.ty).clone(),
+          VmType::Dict(_) => list_or_set_ty.dict_entry_type().unwrap(),
+          _ => return Err(TypeckError::NotListOrSet(format!("{:?}", list_or_set_ty)).into()),
+        };
+        self.validate_subgraph_call(
+          "Reduce",
+          *subgraph_index,
+          subgraph_expected_param_types_sink,
+          vec![subgraph_param.clone(), reduce_init.clone(), member_ty],
+        )?;
+        let output = self
+          .subgraph_output_type(*subgraph_index)?
+          .ok_or_else(|| TypeckError::MissingOutputFromReduce)?;
+        ensure_covariant(reduce_init, &output)?;
+        Some(output.clone())
+      }
+      TwGraphNode::ReduceMap(subgraph_index) => {
+        let [subgraph_param, reduce_init, map] = validate_in_edges::<3>(node, in_edges, &types)?;
+        let value_ty = extract_map_value_type(map)?;
+        self.validate_subgraph_call(
+          "ReduceMap",
+          *subgraph_index,
+          subgraph_expected_param_types_sink,
+          vec![
+            subgraph_param.clone(),
+            reduce_init.clone(),
+            VmType::Primitive(PrimitiveType::String),
+            value_ty,
+          ],
+        )?;
+        let output = self
+          .subgraph_output_type(*subgraph_index)?
+          .ok_or_else(|| TypeckError::MissingOutputFromReduce)?;
+        ensure_covariant(reduce_init, &output)?;
+        Some(output.clone())
+      }
+      TwGraphNode::MapKeys => {
+        let [map] = validate_in_edges::<1>(node, in_edges, &types)?;
+        if !matches!(map, VmType::Map(_)) {
+          return Err(TypeckError::NotMap(format!("{:?}", map)).into());
+        }
+        Some(VmType::List(VmListType {
+          ty: Box::new(VmType::Primitive(PrimitiveType::String)),
+        }))
+      }
+      TwGraphNode::MapValues => {
+        let [map] = validate_in_edges::<1>(node, in_edges, &types)?;
+        Some(VmType::List(VmListType {
+          ty: Box::new(extract_map_value_type(map)?),
+        }))
+      }
+      TwGraphNode::Merge => {
+        let [left, right] = validate_in_edges::<2>(node, in_edges, &types)?;
+        Some(merged_type(vm, left, right)?)
+      }
+      TwGraphNode::CollectPage(page_size) => {
+        let [cursor, set] = validate_in_edges::<2>(node, in_edges, &types)?;
+        ensure_type_eq(&VmType::Primitive(PrimitiveType::String), cursor)?;
+        let member_ty = match set {
+          VmType::Set(x) => (*x.ty).clone(),
+          _ => return Err(TypeckError::NotSet(format!("{:?}", set)).into()),
+        };
+        if *page_size == 0 {
+          return Err(TypeckError::ZeroPageSize.into());
+        }
+        Some(page_type(member_ty))
+      }
+      TwGraphNode::BlobLength(key_index) => {
+        let [table_ty] = validate_in_edges::<1>(node, in_edges, &types)?;
+        ensure_blob_field(vm, table_ty, *key_index)?;
+        Some(VmType::Primitive(PrimitiveType::Int64))
+      }
+      TwGraphNode::BlobRead(key_index) => {
+        let [offset, length, table_ty] = validate_in_edges::<3>(node, in_edges, &types)?;
+        ensure_type_eq(&VmType::Primitive(PrimitiveType::Int64), offset)?;
+        ensure_type_eq(&VmType::Primitive(PrimitiveType::Int64), length)?;
+        ensure_blob_field(vm, table_ty, *key_index)?;
+        Some(VmType::Primitive(PrimitiveType::Bytes))
+      }
+      TwGraphNode::BlobAppend(key_index) => {
+        let [data, table_ty] = validate_in_edges::<2>(node, in_edges, &types)?;
+        ensure_type_eq(&VmType::Primitive(PrimitiveType::Bytes), data)?;
+        ensure_blob_field(vm, table_ty, *key_index)?;
+        None
+      }
+      TwGraphNode::Assert(message_index) => {
+        let [x] = validate_in_edges::<1>(node, in_edges, &types)?;
+        vm.script
+          .idents
+          .get(*message_index as usize)
+          .ok_or_else(|| TypeckError::IdentIndexOob)?;
+        ensure_type_eq(&VmType::Bool, x)?;
+        None
+      }
+      TwGraphNode::LoopUntil(subgraph_index, _) => {
+        let [subgraph_param, loop_init] = validate_in_edges::<2>(node, in_edges, &types)?;
+        self.validate_subgraph_call(
+          "LoopUntil",
+          *subgraph_index,
+          subgraph_expected_param_types_sink,
+          vec![subgraph_param.clone(), loop_init.clone()],
+        )?;
+        let output = self
+          .subgraph_output_type(*subgraph_index)?
+          .ok_or_else(|| TypeckError::MissingOutputFromLoop)?;
+        ensure_covariant(loop_init, &output)?;
+        Some(output.clone())
+      }
+      TwGraphNode::Throw => {
+        let [msg] = validate_in_edges::<1>(node, in_edges, &types)?;
+        ensure_type_eq(&VmType::Primitive(PrimitiveType::String), msg)?;
+        None
+      }
+    };
+    Ok(ty)
+  }
+
+  /// Attaches the location of an error in the script, and the types involved if known.
+  fn diagnostic(
+    &self,
+    graph_index: usize,
+    node_index: Option<usize>,
+    types: &[Option<VmType<&'a str>>],
+    error: anyhow::Error,
+  ) -> anyhow::Error {
+    if error.is::<TypeckDiagnostic>() {
+      return error;
     }
+    let g = &self.vm.script.graphs[graph_index];
+    let (error, expected, actual, difference) = match error.downcast::<TypeMismatch>() {
+      Ok(x) => (
+        anyhow::Error::from(x.error),
+        Some(x.expected),
+        Some(x.actual),
+        x.difference,
+      ),
+      Err(error) => (error, None, None, None),
+    };
+    let node = node_index.and_then(|i| g.nodes.get(i));
 
-    let actual_output_ty = g
-      .output
-      .map(|x| {
-        types
-          .get(x as usize)
-          .ok_or_else(|| TypeckError::OutputNodeIndexOob)
-          .and_then(|x| ensure_type(x.as_ref()))
+    // The producer of the bad value is the in edge of the actual type, or the only in edge.
+    let related_node = node.and_then(|(_, in_edges, _)| {
+      let type_of = |j: u32| types.get(j as usize).and_then(|x| x.as_ref());
+      let j = match &actual {
+        Some(actual) => in_edges
+          .iter()
+          .copied()
+          .find(|j| type_of(*j).map(|x| x.to_string()).as_ref() == Some(actual)),
+        None if in_edges.len() == 1 => Some(in_edges[0]),
+        None => None,
+      }?;
+      Some(RelatedNode {
+        index: j,
+        opcode: format!("{:?}", g.nodes.get(j as usize)?.0),
+        ty: type_of(j).map(|x| x.to_string()),
       })
-      .transpose()?;
-    match (output_type, actual_output_ty) {
-      (Some(a), Some(b)) => ensure_covariant(a, b)?,
-      (None, None) => {}
-      _ => {
-        return Err(
-          TypeckError::OutputTypeMismatch(
-            format!("{:?}", output_type),
-            format!("{:?}", actual_output_ty),
-          )
-          .into(),
-        )
-      }
-    }
+    });
 
-    Ok(GraphTypeInfo {
-      nodes: types,
-      params,
-    })
+    TypeckDiagnostic {
+      graph_index: graph_index as u32,
+      graph_name: g.name.clone(),
+      node_index: node_index.map(|x| x as u32),
+      opcode: node.map(|x| format!("{:?}", x.0)),
+      position: node_index.and_then(|i| self.vm.script.node_position(graph_index, i as u32)),
+      expected,
+      actual,
+      difference,
+      related_node,
+      error,
+    }
+    .into()
   }
 
   fn validate_subgraph_call(
@@ -755,7 +1326,7 @@ impl<'a, 'b> GlobalTyckContext<'a, 'b> {
     subgraph_index: u32,
     sink: &mut HashMap<u32, Vec<HashSet<VmType<&'a str>>>>,
     param_types: Vec<VmType<&'a str>>,
-  ) -> Result<&'a TwGraph> {
+  ) -> Result<()> {
     let subgraph = self
       .vm
       .script
@@ -780,7 +1351,101 @@ impl<'a, 'b> GlobalTyckContext<'a, 'b> {
     for (x, y) in param_types.into_iter().zip(v.iter_mut()) {
       y.insert(x);
     }
-    Ok(subgraph)
+    Ok(())
+  }
+}
+
+/// Whether a typeck error is caused by a type that may be inferred later.
+fn is_unresolved_type(e: &anyhow::Error) -> bool {
+  let e = match e.downcast_ref::<TypeckDiagnostic>() {
+    Some(x) => &x.error,
+    None => e,
+  };
+  matches!(
+    e.downcast_ref::<TypeckError>(),
+    Some(TypeckError::UnknownParamTypeNotResolved(_, _))
+      | Some(TypeckError::UnknownOutputTypeNotResolved(_))
+  )
+}
+
+fn constraint_applies_to(constraint: &TwParamConstraint, ty: &VmType<&str>) -> bool {
+  match constraint {
+    TwParamConstraint::MinLength(_) | TwParamConstraint::MaxLength(_) => matches!(
+      ty,
+      VmType::Primitive(PrimitiveType::String) | VmType::Primitive(PrimitiveType::Bytes)
+    ),
+    TwParamConstraint::Min(_) | TwParamConstraint::Max(_) => matches!(
+      ty,
+      VmType::Primitive(PrimitiveType::Int64) | VmType::Primitive(PrimitiveType::Double)
+    ),
+    TwParamConstraint::Pattern(_) => matches!(ty, VmType::Primitive(PrimitiveType::String)),
+  }
+}
+
+/// Whether a node may evaluate to null, given which of its in edges may be null.
+///
+/// Nulls come from null constants and from lookups that can miss. Table fields are assumed to be
+/// present since the schema has no notion of optional fields. Optional-chained nodes are null if
+/// any of their params is null.
+fn node_may_be_null(vm: &TwVm, node: &TwGraphNode, in_edge_nullable: &[bool]) -> bool {
+  match node {
+    TwGraphNode::LoadConst(x) => vm.consts.get(*x as usize).map(|x| x.is_null()) == Some(true),
+    TwGraphNode::GetSetElement
+    | TwGraphNode::ListHead
+    | TwGraphNode::ListPopBack
+    | TwGraphNode::GetListElement
+    | TwGraphNode::GetMapEntry
+    | TwGraphNode::UnwrapValue
+    | TwGraphNode::ErrorMessage => true,
+    TwGraphNode::Select | TwGraphNode::Nop => in_edge_nullable.iter().any(|x| *x),
+    TwGraphNode::Reduce(..)
+    | TwGraphNode::ReduceReverse(..)
+    | TwGraphNode::ReduceBySortKey(..)
+    | TwGraphNode::ReduceByPrefix(..)
+    | TwGraphNode::ReduceMap(_) => in_edge_nullable[2],
+    TwGraphNode::LoopUntil(..) | TwGraphNode::InsertIntoMap(_) | TwGraphNode::CollectPage(_) => {
+      in_edge_nullable[1]
+    }
+    TwGraphNode::DeleteFromMap(_) => in_edge_nullable[0],
+    _ if node.is_optional_chained() => in_edge_nullable.iter().any(|x| *x),
+    _ => false,
+  }
+}
+
+/// The params of a node that opts out of optional chaining but cannot take a null, along with
+/// the name of the node. The reduce and loop params are passed into the subgraph as is.
+fn non_optional_params(node: &TwGraphNode) -> Option<(&'static str, &'static [usize])> {
+  match node {
+    TwGraphNode::Reduce(..)
+    | TwGraphNode::ReduceReverse(..)
+    | TwGraphNode::ReduceBySortKey(..)
+    | TwGraphNode::ReduceByPrefix(..) => Some(("Reduce", &[0, 1])),
+    TwGraphNode::ReduceMap(_) => Some(("ReduceMap", &[0, 1])),
+    TwGraphNode::LoopUntil(..) => Some(("LoopUntil", &[0])),
+    _ => None,
+  }
+}
+
+/// Whether `precondition` only holds if the value of `node` is not null, i.e. it is
+/// `!is_null(node)` or `is_present(node)`, possibly joined with other conditions by `&&`.
+fn is_null_guarded(g: &TwGraph, precondition: Option<u32>, node: u32) -> bool {
+  let precondition = match precondition.and_then(|x| g.nodes.get(x as usize)) {
+    Some(x) => x,
+    None => return false,
+  };
+  match precondition {
+    // `is_present` of a null value is null, which is treated as false.
+    (TwGraphNode::IsPresent, in_edges, _) => in_edges.as_slice() == [node],
+    (TwGraphNode::Not, in_edges, _) if in_edges.len() == 1 => {
+      match g.nodes.get(in_edges[0] as usize) {
+        Some((TwGraphNode::IsNull, x, _)) => x.as_slice() == [node],
+        // The `else` branch of `if !is_present(node)`.
+        Some((TwGraphNode::Not, x, _)) if x.len() == 1 => is_null_guarded(g, Some(x[0]), node),
+        _ => false,
+      }
+    }
+    (TwGraphNode::And, in_edges, _) => in_edges.iter().any(|x| is_null_guarded(g, Some(*x), node)),
+    _ => false,
   }
 }
 
@@ -815,7 +1480,14 @@ fn ensure_covariant<'a>(dst: &VmType<&'a str>, src: &VmType<&'a str>) -> Result<
   if dst.is_covariant_from(src) {
     Ok(())
   } else {
-    Err(TypeckError::NonCovariantTypes(format!("{:?}", dst), format!("{:?}", src)).into())
+    Err(
+      TypeMismatch::new(
+        dst,
+        src,
+        TypeckError::NonCovariantTypes(format!("{:?}", dst), format!("{:?}", src)),
+      )
+      .into(),
+    )
   }
 }
 
@@ -823,7 +1495,47 @@ fn ensure_type_eq<'a>(dst: &VmType<&'a str>, src: &VmType<&'a str>) -> Result<()
   if dst == src {
     Ok(())
   } else {
-    Err(TypeckError::NonEqualTypes(format!("{:?}", dst), format!("{:?}", src)).into())
+    Err(
+      TypeMismatch::new(
+        dst,
+        src,
+        TypeckError::NonEqualTypes(format!("{:?}", dst), format!("{:?}", src)),
+      )
+      .into(),
+    )
+  }
+}
+
+/// Finds the innermost position where `actual` is not covariant to `expected`, and describes it.
+/// Returns `None` if the types differ at the top level.
+fn first_difference<'a>(
+  expected: &VmType<&'a str>,
+  actual: &VmType<&'a str>,
+  path: &str,
+) -> Option<String> {
+  if expected.is_covariant_from(actual) {
+    return None;
+  }
+  let nested = match (expected, actual) {
+    (VmType::Map(x), VmType::Map(y)) => x.iter().find_map(|(k, v_x)| {
+      let path = format!("{}.{}", path, k);
+      match y.get(*k) {
+        Some(v_y) => first_difference(v_x, v_y, &path),
+        None => Some(format!("at `{}`: expected `{}`, got nothing", path, v_x)),
+      }
+    }),
+    (VmType::List(x), VmType::List(y)) => first_difference(&x.ty, &y.ty, &format!("{}[]", path)),
+    (VmType::Set(x), VmType::Set(y)) => first_difference(&x.ty, &y.ty, &format!("{}[]", path)),
+    (VmType::Dict(x), VmType::Dict(y)) => first_difference(&x.ty, &y.ty, &format!("{}[]", path)),
+    _ => None,
+  };
+  match nested {
+    Some(x) => Some(x),
+    None if !path.is_empty() => Some(format!(
+      "at `{}`: expected `{}`, got `{}`",
+      path, expected, actual
+    )),
+    None => None,
   }
 }
 
@@ -840,3 +1552,125 @@ fn extract_set_element_type<'a, 'b>(x: &'b VmType<&'a str>) -> Result<&'b VmType
     _ => Err(TypeckError::ExpectingSet(format!("{:?}", x)).into()),
   }
 }
+
+fn extract_dict_value_type<'a, 'b>(x: &'b VmType<&'a str>) -> Result<&'b VmType<&'a str>> {
+  match x {
+    VmType::Dict(x) => Ok(&*x.ty),
+    _ => Err(TypeckError::ExpectingDict(format!("{:?}", x)).into()),
+  }
+}
+
+/// Checks that a value of `value_ty` can be written to the field `key` of a table by a script.
+fn ensure_table_field_writable<'a>(
+  table_ty: &SpecializedType,
+  key: &str,
+  value_ty: &VmType<&'a str>,
+) -> Result<()> {
+  let (field_ty, field_annotations) = table_ty
+    .fields
+    .get(key)
+    .map(|x| (VmType::from(&x.0), &x.1))
+    .ok_or_else(|| TypeckError::FieldNotPresentInTable(key.to_string(), table_ty.name.clone()))?;
+  if field_annotations.as_slice().is_primary() {
+    return Err(TypeckError::CannotInsertPrimaryKey.into());
+  }
+  if field_annotations.as_slice().is_sort_key() {
+    return Err(TypeckError::CannotInsertSortKey.into());
+  }
+  if field_annotations.as_slice().is_timestamp() {
+    return Err(TypeckError::CannotInsertTimestamp.into());
+  }
+  if field_annotations.as_slice().is_deleted_at() {
+    return Err(TypeckError::CannotInsertDeletedAt.into());
+  }
+  ensure_covariant(&field_ty, value_ty)
+}
+
+/// Checks that the field `key_index` of `table_ty` is a `@blob` field.
+fn ensure_blob_field<'a>(vm: &TwVm<'a>, table_ty: &VmType<&'a str>, key_index: u32) -> Result<()> {
+  let key = vm
+    .script
+    .idents
+    .get(key_index as usize)
+    .ok_or_else(|| TypeckError::IdentIndexOob)?;
+  let table_ty = match table_ty {
+    VmType::Table(x) => vm
+      .schema
+      .types
+      .get(x.name)
+      .ok_or_else(|| TypeckError::TableTypeNotFound(x.name.to_string()))?,
+    _ => return Err(TypeckError::NotTable(format!("{:?}", table_ty)).into()),
+  };
+  let (_, field_annotations) = table_ty
+    .fields
+    .get(key.as_str())
+    .ok_or_else(|| TypeckError::FieldNotPresentInTable(key.to_string(), table_ty.name.clone()))?;
+  if !field_annotations.as_slice().is_blob() {
+    return Err(TypeckError::NotBlobField(key.to_string(), table_ty.name.clone()).into());
+  }
+  Ok(())
+}
+
+/// The type of `Merge` of a `right` value into a `left` value.
+fn merged_type<'a>(
+  vm: &TwVm<'a>,
+  left: &VmType<&'a str>,
+  right: &VmType<&'a str>,
+) -> Result<VmType<&'a str>> {
+  match (left, right) {
+    (VmType::Map(l), VmType::Map(r)) => {
+      let mut out = l.clone();
+      for (k, r) in r {
+        let ty = match l.get(k) {
+          Some(l) if is_mergeable_type(l) && is_mergeable_type(r) => merged_type(vm, l, r)?,
+          _ => r.clone(),
+        };
+        out.insert_mut(*k, ty);
+      }
+      Ok(VmType::Map(out))
+    }
+    (VmType::Table(l), VmType::Table(r)) if l.name == r.name => Ok(left.clone()),
+    (VmType::Table(l), VmType::Map(r)) => {
+      let table_ty = vm
+        .schema
+        .types
+        .get(l.name)
+        .ok_or_else(|| TypeckError::TableTypeNotFound(l.name.to_string()))?;
+      for (name, actual_ty) in r {
+        let (field_ty, _) = table_ty.fields.get(*name).ok_or_else(|| {
+          TypeckError::MapFieldNotPresentInTable(name.to_string(), table_ty.name.clone())
+        })?;
+        let field_ty = VmType::from(field_ty);
+        if is_mergeable_type(&field_ty) && is_mergeable_type(actual_ty) {
+          merged_type(vm, &field_ty, actual_ty)?;
+        } else {
+          ensure_covariant(&field_ty, actual_ty)?;
+        }
+      }
+      Ok(left.clone())
+    }
+    _ => Err(TypeckError::BadMergeOperands(format!("{}", left), format!("{}", right)).into()),
+  }
+}
+
+fn is_mergeable_type<K: Clone + Ord + PartialOrd + Eq + PartialEq>(x: &VmType<K>) -> bool {
+  matches!(x, VmType::Map(_) | VmType::Table(_))
+}
+
+/// The most general type of the values of a map, that the types of all values are covariant to.
+fn extract_map_value_type<'a>(x: &VmType<&'a str>) -> Result<VmType<&'a str>> {
+  let fields = match x {
+    VmType::Map(x) => x,
+    _ => return Err(TypeckError::NotMap(format!("{:?}", x)).into()),
+  };
+  fields
+    .values()
+    .find(|x| fields.values().all(|y| x.is_covariant_from(y)))
+    .cloned()
+    .ok_or_else(|| TypeckError::NoCommonMapValueType(format!("{}", x)).into())
+}
+
+fn extract_fallible_value_type<'a, 'b>(x: &'b VmType<&'a str>) -> Result<&'b VmType<&'a str>> {
+  x.fallible_value_type()
+    .ok_or_else(|| TypeckError::ExpectingFallible(format!("{:?}", x)).into())
+}
@@ -4,8 +4,9 @@ use rpds::RedBlackTreeMapSync;
 use crate::{
   data::{
     treewalker::{
+      asm::codegen::compile_twscript,
       bytecode::{TwGraph, TwGraphNode},
-      typeck::GlobalTyckContext,
+      typeck::{GlobalTyckContext, TypeckDiagnostic},
       vm::TwVm,
       vm_value::{VmConst, VmType},
     },
@@ -106,6 +107,8 @@ fn basic_typeck() {
         (TwGraphNode::GetField(3), vec![3], None), // 4
       ],
       output: Some(4),
+      named_outputs: vec![],
+      param_constraints: vec![],
       output_type: Some(1),
       param_types: vec![0],
     }],
@@ -118,6 +121,7 @@ fn basic_typeck() {
       "value".into(),
     ],
     types: vec![VmType::Schema, VmType::Primitive(PrimitiveType::Int64)],
+    source_map: None,
   };
   let vm = TwVm::new(&schema, &plan, &script).unwrap();
   GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
@@ -144,6 +148,8 @@ fn filter_set() {
           (TwGraphNode::FilterSet(1), vec![2, 1], None), // 3
         ],
         output: Some(3),
+        named_outputs: vec![],
+        param_constraints: vec![],
         output_type: Some(1),
         param_types: vec![0],
       },
@@ -154,6 +160,8 @@ fn filter_set() {
           (TwGraphNode::LoadConst(0), vec![], None), // 0
         ],
         output: Some(0),
+        named_outputs: vec![],
+        param_constraints: vec![],
         output_type: Some(2),
         param_types: vec![3, 3],
       },
@@ -177,6 +185,7 @@ fn filter_set() {
       VmType::Bool,
       VmType::Unknown,
     ],
+    source_map: None,
   };
   let vm = TwVm::new(&schema, &plan, &script).unwrap();
   GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
@@ -203,6 +212,8 @@ fn basic_typeck_fail_unknown_name() {
         (TwGraphNode::GetField(3), vec![3], None), // 4
       ],
       output: Some(4),
+      named_outputs: vec![],
+      param_constraints: vec![],
       output_type: Some(1),
       param_types: vec![0],
     }],
@@ -215,6 +226,7 @@ fn basic_typeck_fail_unknown_name() {
       "value".into(),
     ],
     types: vec![VmType::Schema, VmType::Primitive(PrimitiveType::Int64)],
+    source_map: None,
   };
   let vm = TwVm::new(&schema, &plan, &script).unwrap();
   assert!(
@@ -248,6 +260,8 @@ fn basic_typeck_output_type_mismatch() {
         (TwGraphNode::GetField(3), vec![3], None), // 4
       ],
       output: Some(4),
+      named_outputs: vec![],
+      param_constraints: vec![],
       output_type: Some(1),
       param_types: vec![0],
     }],
@@ -260,6 +274,7 @@ fn basic_typeck_output_type_mismatch() {
       "value".into(),
     ],
     types: vec![VmType::Schema, VmType::Primitive(PrimitiveType::String)],
+    source_map: None,
   };
   let vm = TwVm::new(&schema, &plan, &script).unwrap();
   assert!(
@@ -268,7 +283,7 @@ fn basic_typeck_output_type_mismatch() {
       .typeck()
       .unwrap_err()
       .to_string()
-      == "type `Primitive(String)` is not covariant from `Primitive(Int64)`"
+      == "graph 0 ``, node 4 (GetField(3)): type `Primitive(String)` is not covariant from `Primitive(Int64)`\n  expected: `string`\n  actual:   `int64`"
   );
 }
 
@@ -308,6 +323,8 @@ fn typeck_set_point_get() {
         (TwGraphNode::InsertIntoMap(3), vec![5, 7], None), // 8
       ],
       output: Some(8),
+      named_outputs: vec![],
+      param_constraints: vec![],
       output_type: Some(1),
       param_types: vec![0],
     }],
@@ -321,7 +338,579 @@ fn typeck_set_point_get() {
       "the_item".into(),
     ],
     types: vec![VmType::Schema, VmType::Map(expected_result_type)],
+    source_map: None,
   };
   let vm = TwVm::new(&schema, &plan, &script).unwrap();
   GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
 }
+
+#[test]
+fn typeck_unconditional_select() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(&alloc, SIMPLE_SCHEMA).unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let script = TwScript {
+    graphs: vec![TwGraph {
+      name: "".into(),
+      exported: false,
+      nodes: vec![
+        (TwGraphNode::LoadParam(0), vec![], None), // 0
+        (TwGraphNode::LoadConst(0), vec![], None), // 1
+        (TwGraphNode::LoadConst(1), vec![], None), // 2
+        (TwGraphNode::Select, vec![1, 2], None),   // 3
+      ],
+      output: Some(3),
+      named_outputs: vec![],
+      param_constraints: vec![],
+      output_type: Some(1),
+      param_types: vec![0],
+    }],
+    entry: 0,
+    consts: vec![
+      VmConst::Primitive(PrimitiveValue::Int64(1)),
+      VmConst::Primitive(PrimitiveValue::Int64(2)),
+    ],
+    idents: vec![],
+    types: vec![VmType::Schema, VmType::Primitive(PrimitiveType::Int64)],
+    source_map: None,
+  };
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  assert_eq!(
+    GlobalTyckContext::new(&vm)
+      .unwrap()
+      .typeck()
+      .unwrap_err()
+      .to_string(),
+    "select candidates 1 and 2 are both unconditional and would always fire together"
+  );
+}
+
+#[test]
+fn typeck_diagnostics() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(&alloc, SIMPLE_SCHEMA).unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+
+  let script = compile_twscript(
+    r#"
+    graph main(root: schema): bool {
+      a = 1;
+      b = "x";
+      return a == b;
+    }
+    "#,
+  )
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let e = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap_err();
+  let e = e.downcast_ref::<TypeckDiagnostic>().unwrap();
+  assert_eq!(e.graph_name, "main");
+  assert_eq!(e.node_index, Some(3));
+  assert_eq!(e.opcode.as_deref(), Some("Eq"));
+  assert_eq!(e.position.map(|x| (x.line, x.column)), Some((5, 14)));
+  assert_eq!(e.expected.as_deref(), Some("int64"));
+  assert_eq!(e.actual.as_deref(), Some("string"));
+  assert_eq!(e.difference, None);
+  let related = e.related_node.as_ref().unwrap();
+  assert_eq!(related.index, 2);
+  assert_eq!(related.ty.as_deref(), Some("string"));
+
+  let script = compile_twscript(
+    r#"
+    graph main(root: schema): bool {
+      a = m_insert(value) 1 $ create_map;
+      b = m_insert(value) "x" $ create_map;
+      return a == b;
+    }
+    "#,
+  )
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let e = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap_err();
+  let e = e.downcast_ref::<TypeckDiagnostic>().unwrap();
+  assert_eq!(
+    e.difference.as_deref(),
+    Some("at `.value`: expected `int64`, got `string`")
+  );
+}
+
+#[test]
+fn typeck_auth_context() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(&alloc, SIMPLE_SCHEMA).unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+
+  let script = compile_twscript(
+    r#"
+    export graph main(root: schema, auth: auth_context): bool {
+      return auth.role == "admin";
+    }
+    "#,
+  )
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  assert_eq!(
+    type_info.graphs[0].params[1].to_string(),
+    "map { namespace_id: string, role: string, token_id: string, }"
+  );
+
+  let script = compile_twscript(
+    r#"
+    export graph main(root: schema, auth: auth_context): int64 {
+      return auth.role;
+    }
+    "#,
+  )
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  assert!(GlobalTyckContext::new(&vm).unwrap().typeck().is_err());
+}
+
+#[test]
+fn typeck_null_safety() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(&alloc, SIMPLE_SCHEMA).unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+
+  let count = r#"
+    graph count(limit: int64, acc: int64, x: Item<Duration<int64>>): int64 {
+      return acc + limit;
+    }
+  "#;
+
+  // The field of a missing set member flows into the reduce subgraph.
+  let script = compile_twscript(&format!(
+    r#"
+    graph main(root: schema): int64 {{
+      limit = (point_get root.items "a").inner.start;
+      return reduce(count) limit 0 root.items;
+    }}
+    {}
+    "#,
+    count
+  ))
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let e = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap_err();
+  let e = e.downcast_ref::<TypeckDiagnostic>().unwrap();
+  assert_eq!(e.graph_name, "main");
+  assert_eq!(e.opcode.as_deref(), Some("Reduce(1, false)"));
+  assert!(e.error.to_string().starts_with("param 0 of `Reduce`"));
+
+  // Guarded by `is_null`.
+  let script = compile_twscript(&format!(
+    r#"
+    graph main(root: schema): int64 {{
+      limit = (point_get root.items "a").inner.start;
+      if !(is_null limit) {{
+        r = reduce(count) limit 0 root.items;
+      }}
+      return r;
+    }}
+    {}
+    "#,
+    count
+  ))
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let point_get = script.graphs[0]
+    .nodes
+    .iter()
+    .position(|x| matches!(x.0, TwGraphNode::GetSetElement))
+    .unwrap();
+  assert!(type_info.graphs[0].nullable[point_get]);
+
+  // `??` makes the limit non-null, but the accumulator is still null.
+  let script = compile_twscript(&format!(
+    r#"
+    graph main(root: schema): int64 {{
+      limit = (point_get root.items "a").inner.start;
+      return reduce(count) (limit ?? 1) null<int64> root.items;
+    }}
+    {}
+    "#,
+    count
+  ))
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let e = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap_err();
+  let e = e.downcast_ref::<TypeckDiagnostic>().unwrap();
+  assert!(e.error.to_string().starts_with("param 1 of `Reduce`"));
+}
+
+#[test]
+fn typeck_infer_unknown_types() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(&alloc, SIMPLE_SCHEMA).unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+
+  // The output type of `double` is needed by `main` before `double` is typechecked.
+  let script = compile_twscript(
+    r#"
+    graph main(root: schema): int64 {
+      return call(double) [2];
+    }
+    graph double(x) {
+      return x + x;
+    }
+    "#,
+  )
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  assert_eq!(type_info.graphs[1].params[0].to_string(), "int64");
+  assert_eq!(
+    type_info.graphs[1].output.as_ref().map(|x| x.to_string()),
+    Some("int64".into())
+  );
+
+  // The most general candidate is picked.
+  let script = compile_twscript(
+    r#"
+    graph main(root: schema): int64 {
+      a = call(get_a) [m_insert(a) 1 $ create_map];
+      b = call(get_a) [m_insert(b) "x" $ m_insert(a) 2 $ create_map];
+      return a + b;
+    }
+    graph get_a(m) {
+      return m.a;
+    }
+    "#,
+  )
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  assert_eq!(
+    type_info.graphs[1].params[0].to_string(),
+    "map { a: int64, }"
+  );
+
+  // Ambiguous param type.
+  let script = compile_twscript(
+    r#"
+    graph main(root: schema) {
+      a = call(id) [1];
+      b = call(id) ["x"];
+    }
+    graph id(x) {
+      return x;
+    }
+    "#,
+  )
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let e = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap_err();
+  let e = e.downcast_ref::<TypeckDiagnostic>().unwrap();
+  assert_eq!(e.graph_name, "id");
+  assert!(e.error.to_string().contains("`int64`, `string`"));
+
+  // Recursion without an annotated output type.
+  let script = compile_twscript(
+    r#"
+    graph main(root: schema): int64 {
+      return call(f) [1];
+    }
+    graph f(x: int64) {
+      return call(f) [x];
+    }
+    "#,
+  )
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let e = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap_err();
+  let e = e.downcast_ref::<TypeckDiagnostic>().unwrap();
+  assert_eq!(
+    e.error.to_string(),
+    "output type of subgraph 1 cannot be inferred: add a return type annotation"
+  );
+}
+
+#[test]
+fn typeck_map_iteration() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(&alloc, SIMPLE_SCHEMA).unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+
+  let script = compile_twscript(
+    r#"
+    graph main(root: schema): list<int64> {
+      m = m_insert(a) 1 $ m_insert(b) 2 $ create_map;
+      total = reduce_map(sum) create_map 0 m;
+      return total : map_values m;
+    }
+    graph sum(ctx, acc, key, value) {
+      return acc + value;
+    }
+    "#,
+  )
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let params = type_info.graphs[1]
+    .params
+    .iter()
+    .map(|x| x.to_string())
+    .collect::<Vec<_>>();
+  assert_eq!(params, vec!["map { }", "int64", "string", "int64"]);
+
+  // The values of the map have no common type.
+  let script = compile_twscript(
+    r#"
+    graph main(root: schema) {
+      m = m_insert(a) 1 $ m_insert(b) "x" $ create_map;
+      keys = map_keys m;
+      values = map_values m;
+    }
+    "#,
+  )
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let e = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap_err();
+  let e = e.downcast_ref::<TypeckDiagnostic>().unwrap();
+  assert_eq!(e.opcode.as_deref(), Some("MapValues"));
+  assert!(e
+    .error
+    .to_string()
+    .starts_with("cannot find a common type for the values of map"));
+}
+
+#[test]
+fn typeck_merge() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(&alloc, SIMPLE_SCHEMA).unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let typeck = |code: &str| {
+    let script = compile_twscript(code).unwrap();
+    let vm = TwVm::new(&schema, &plan, &script).unwrap();
+    let result = GlobalTyckContext::new(&vm).unwrap().typeck();
+    result
+      .map(|x| x.graphs[0].output.as_ref().map(|x| x.to_string()))
+      .map_err(|e| e.downcast::<TypeckDiagnostic>().unwrap().error.to_string())
+  };
+
+  assert_eq!(
+    typeck(
+      r#"
+      graph main(root: schema) {
+        return merge (m_insert(a) 1 $ m_insert(b) 2 create_map) (m_insert(b) "x" create_map);
+      }
+      "#
+    ),
+    Ok(Some("map { a: int64, b: string, }".into()))
+  );
+  assert_eq!(
+    typeck(
+      r#"
+      graph main(root: schema) {
+        w = build_table(Wrapper<int64>) $ m_insert(value) 1 create_map;
+        return merge w (m_insert(value) 2 create_map);
+      }
+      "#
+    ),
+    Ok(Some("Wrapper<int64>".into()))
+  );
+  assert_eq!(
+    typeck(
+      r#"
+      graph main(root: schema) {
+        w = build_table(Wrapper<int64>) $ m_insert(value) 1 create_map;
+        return merge w (m_insert(other) 2 create_map);
+      }
+      "#
+    ),
+    Err("map field `other` is not present in table `Wrapper<int64>`".into())
+  );
+  assert_eq!(
+    typeck(
+      r#"
+      graph main(root: schema) {
+        return merge 1 create_map;
+      }
+      "#
+    ),
+    Err("cannot merge `map { }` into `int64`".into())
+  );
+}
+
+#[test]
+fn typeck_apply_patch() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(&alloc, SIMPLE_SCHEMA).unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let typeck = |code: &str| {
+    let script = compile_twscript(code).unwrap();
+    let vm = TwVm::new(&schema, &plan, &script).unwrap();
+    let result = GlobalTyckContext::new(&vm).unwrap().typeck();
+    result
+      .map(|_| ())
+      .map_err(|e| e.downcast::<TypeckDiagnostic>().unwrap().error.to_string())
+  };
+
+  assert_eq!(
+    typeck(
+      r#"
+      graph main(root: schema) {
+        t_patch root.item $ m_insert(inner) null<Recursive<int64>> create_map;
+      }
+      "#
+    ),
+    Ok(())
+  );
+  assert!(typeck(
+    r#"
+    graph main(root: schema) {
+      t_patch root.item $ m_insert(inner) 1 create_map;
+    }
+    "#
+  )
+  .unwrap_err()
+  .contains("is not covariant from"));
+  assert_eq!(
+    typeck(
+      r#"
+      graph main(root: schema) {
+        t_patch (point_get root.items "a") $ m_insert(something_else) "b" create_map;
+      }
+      "#
+    ),
+    Err("cannot insert primary key into a table".into())
+  );
+}
+
+#[test]
+fn typeck_named_outputs() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(&alloc, SIMPLE_SCHEMA).unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let typeck_script = |script: &TwScript| {
+    let vm = TwVm::new(&schema, &plan, script).unwrap();
+    let result = GlobalTyckContext::new(&vm).unwrap().typeck();
+    result
+      .map(|x| x.graphs[0].output.as_ref().map(|x| x.to_string()))
+      .map_err(|e| e.downcast::<TypeckDiagnostic>().unwrap().error.to_string())
+  };
+  let typeck = |code: &str| typeck_script(&compile_twscript(code).unwrap());
+
+  assert_eq!(
+    typeck(
+      r#"
+      graph main(root: schema) {
+        return { b: "x", a: 1 };
+      }
+      "#
+    ),
+    Ok(Some("map { a: int64, b: string, }".into()))
+  );
+  assert!(typeck(
+    r#"
+    graph main(root: schema): map { a: int64 } {
+      return { a: "x" };
+    }
+    "#
+  )
+  .unwrap_err()
+  .contains("is not covariant from"));
+
+  let mut script = compile_twscript(
+    r#"
+    graph main(root: schema) {
+      return { a: 1, b: 2 };
+    }
+    "#,
+  )
+  .unwrap();
+  let (a, _) = script.graphs[0].named_outputs[0];
+  script.graphs[0].named_outputs[1].0 = a;
+  assert_eq!(
+    typeck_script(&script),
+    Err("duplicate output name: `a`".into())
+  );
+  script.graphs[0].output = Some(0);
+  assert_eq!(
+    typeck_script(&script),
+    Err("a graph cannot have both an output and named outputs".into())
+  );
+}
+
+#[test]
+fn typeck_param_constraints() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(&alloc, SIMPLE_SCHEMA).unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let typeck = |code: &str| {
+    let script = compile_twscript(code).unwrap();
+    let vm = TwVm::new(&schema, &plan, &script).unwrap();
+    GlobalTyckContext::new(&vm)
+      .unwrap()
+      .typeck()
+      .map(|_| ())
+      .map_err(|e| e.downcast::<TypeckDiagnostic>().unwrap().error.to_string())
+  };
+
+  assert_eq!(
+    typeck(
+      r#"
+      graph main(root: schema, name: string @max_length(4) @pattern("[a-z]*"), n: int64 @min(0)) {}
+      "#
+    ),
+    Ok(())
+  );
+  assert_eq!(
+    typeck(
+      r#"
+      graph main(root: schema, name: string @min(0)) {}
+      "#
+    ),
+    Err("`@min(0)` does not apply to params of type `string`".into())
+  );
+  assert_eq!(
+    typeck(
+      r#"
+      graph main(root: schema, n: int64 @pattern("[0-9]+")) {}
+      "#
+    ),
+    Err("`@pattern(\"[0-9]+\")` does not apply to params of type `int64`".into())
+  );
+}
@@ -0,0 +1,143 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::data::kv::{KvEntryIterator, KvError, KvKeyIterator, KvTransaction, ScanOptions};
+
+use super::exec::ExecError;
+
+/// KV usage of the last attempt of a graph run.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct ExecUsage {
+  /// Number of KV requests. A scan counts as one request.
+  pub kv_ops: u64,
+
+  /// Total size of keys and values returned by `get`.
+  pub bytes_read: u64,
+
+  /// Total size of keys and values passed to `put`.
+  pub bytes_written: u64,
+}
+
+/// Usage counters of one attempt, checked against the limits in `ExecConfig`.
+#[derive(Default)]
+pub(super) struct UsageMeter {
+  max_kv_ops: Option<u64>,
+  max_bytes_written: Option<u64>,
+  kv_ops: AtomicU64,
+  bytes_read: AtomicU64,
+  bytes_written: AtomicU64,
+}
+
+impl UsageMeter {
+  pub fn new(max_kv_ops: Option<u64>, max_bytes_written: Option<u64>) -> Self {
+    Self {
+      max_kv_ops,
+      max_bytes_written,
+      ..Default::default()
+    }
+  }
+
+  pub fn reset(&self) {
+    self.kv_ops.store(0, Ordering::Relaxed);
+    self.bytes_read.store(0, Ordering::Relaxed);
+    self.bytes_written.store(0, Ordering::Relaxed);
+  }
+
+  pub fn usage(&self) -> ExecUsage {
+    ExecUsage {
+      kv_ops: self.kv_ops.load(Ordering::Relaxed),
+      bytes_read: self.bytes_read.load(Ordering::Relaxed),
+      bytes_written: self.bytes_written.load(Ordering::Relaxed),
+    }
+  }
+
+  fn count_op(&self) -> Result<()> {
+    let n = self.kv_ops.fetch_add(1, Ordering::Relaxed) + 1;
+    match self.max_kv_ops {
+      Some(limit) if n > limit => Err(ExecError::KvOpLimitExceeded(limit).into()),
+      _ => Ok(()),
+    }
+  }
+
+  fn count_write(&self, len: usize) -> Result<()> {
+    let n = self.bytes_written.fetch_add(len as u64, Ordering::Relaxed) + len as u64;
+    match self.max_bytes_written {
+      Some(limit) if n > limit => Err(ExecError::WriteLimitExceeded(limit).into()),
+      _ => Ok(()),
+    }
+  }
+}
+
+/// A transaction that counts the requests made through it into a `UsageMeter`.
+pub(super) struct MeteredTransaction<'t> {
+  pub inner: &'t dyn KvTransaction,
+  pub meter: &'t UsageMeter,
+
+  /// Whether writes fail with `ExecError::WriteInSnapshotRead`.
+  pub read_only: bool,
+}
+
+impl<'t> MeteredTransaction<'t> {
+  fn check_write(&self) -> Result<()> {
+    if self.read_only {
+      return Err(ExecError::WriteInSnapshotRead.into());
+    }
+    self.meter.count_op()
+  }
+}
+
+#[async_trait]
+impl<'t> KvTransaction for MeteredTransaction<'t> {
+  async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    self.meter.count_op()?;
+    let value = self.inner.get(key).await?;
+    if let Some(x) = &value {
+      self
+        .meter
+        .bytes_read
+        .fetch_add((key.len() + x.len()) as u64, Ordering::Relaxed);
+    }
+    Ok(value)
+  }
+
+  async fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+    self.check_write()?;
+    self.meter.count_write(key.len() + value.len())?;
+    self.inner.put(key, value).await
+  }
+
+  async fn delete(&self, key: &[u8]) -> Result<()> {
+    self.check_write()?;
+    self.inner.delete(key).await
+  }
+
+  async fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+    self.check_write()?;
+    self.inner.delete_range(start, end).await
+  }
+
+  async fn scan_keys(&self, start: &[u8], end: &[u8]) -> Result<Box<dyn KvKeyIterator>> {
+    self.meter.count_op()?;
+    self.inner.scan_keys(start, end).await
+  }
+
+  async fn scan<'a>(
+    &'a self,
+    start: &[u8],
+    end: &[u8],
+    options: &ScanOptions,
+  ) -> Result<Box<dyn KvEntryIterator + 'a>> {
+    self.meter.count_op()?;
+    self.inner.scan(start, end, options).await
+  }
+
+  async fn commit(self: Box<Self>) -> Result<(), KvError> {
+    panic!("inconsistency: commit called on a metered transaction");
+  }
+
+  async fn read_version(&self) -> Result<Option<i64>> {
+    self.inner.read_version().await
+  }
+}
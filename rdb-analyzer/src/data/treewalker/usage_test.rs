@@ -0,0 +1,108 @@
+use std::sync::Arc;
+
+use bumpalo::Bump;
+
+use crate::{
+  data::{
+    mock_kv::MockKv,
+    treewalker::{
+      asm::codegen::compile_twscript,
+      exec::{generate_root_map, ExecConfig, ExecError, Executor},
+      typeck::GlobalTyckContext,
+      vm::TwVm,
+    },
+  },
+  schema::{compile::compile, grammar::parse},
+  storage_plan::planner::generate_plan_for_schema,
+};
+
+#[tokio::test]
+async fn reports_and_limits_usage() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+  type Store {
+    a: int64,
+    b: string,
+  }
+  export Store store;
+  "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let script = compile_twscript(
+    r#"
+  export graph write(root: schema) {
+    t_insert(a) root.store 1;
+    t_insert(b) root.store "hello";
+  }
+  export graph read(root: schema): string {
+    return root.store.b;
+  }
+  "#,
+  )
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let root_map = Arc::new(generate_root_map(&schema, &plan).unwrap());
+  let kv = MockKv::new();
+  let write = vm.lookup_exported_graph_by_name("write").unwrap();
+  let read = vm.lookup_exported_graph_by_name("read").unwrap();
+
+  let mut executor = Executor::new(&vm, &kv, &type_info);
+  executor
+    .run_graph(write, &[root_map.clone()])
+    .await
+    .unwrap();
+  let usage = executor.usage();
+  assert!(usage.kv_ops >= 2);
+  assert!(usage.bytes_written > "hello".len() as u64);
+  executor.run_graph(read, &[root_map.clone()]).await.unwrap();
+  let usage = executor.usage();
+  assert!(usage.kv_ops >= 1);
+  assert!(usage.bytes_read > "hello".len() as u64);
+  assert_eq!(usage.bytes_written, 0);
+
+  let mut executor = Executor::new_with_config(
+    &vm,
+    &kv,
+    &type_info,
+    &ExecConfig {
+      max_kv_ops: Some(1),
+      ..Default::default()
+    },
+  );
+  let err = executor
+    .run_graph(write, &[root_map.clone()])
+    .await
+    .unwrap_err();
+  assert!(matches!(
+    err.downcast_ref::<ExecError>(),
+    Some(ExecError::KvOpLimitExceeded(1))
+  ));
+
+  // Reads are still allowed when no writes are.
+  let mut executor = Executor::new_with_config(
+    &vm,
+    &kv,
+    &type_info,
+    &ExecConfig {
+      max_bytes_written: Some(0),
+      ..Default::default()
+    },
+  );
+  executor.run_graph(read, &[root_map.clone()]).await.unwrap();
+  let err = executor
+    .run_graph(write, &[root_map.clone()])
+    .await
+    .unwrap_err();
+  assert!(matches!(
+    err.downcast_ref::<ExecError>(),
+    Some(ExecError::WriteLimitExceeded(0))
+  ));
+}
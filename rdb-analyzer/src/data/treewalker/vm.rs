@@ -1,11 +1,14 @@
 use std::{collections::HashMap, sync::Arc};
 
 use anyhow::Result;
+use regex::Regex;
 
 use crate::{schema::compile::CompiledSchema, storage_plan::StoragePlan};
 
 use super::{
-  bytecode::TwScript,
+  bytecode::{TwParamConstraint, TwScript},
+  intern::TwInterner,
+  opt::optimize,
   vm_value::{VmType, VmValue},
 };
 use thiserror::Error;
@@ -14,6 +17,9 @@ use thiserror::Error;
 pub enum VmError {
   #[error("exported graph not found: `{0}`")]
   ExportedGraphNotFound(String),
+
+  #[error("invalid param pattern `{0}`: {1}")]
+  BadParamPattern(String, regex::Error),
 }
 
 pub struct TwVm<'a> {
@@ -22,7 +28,11 @@ pub struct TwVm<'a> {
   pub script: &'a TwScript,
   pub consts: Vec<Arc<VmValue<'a>>>,
   pub types: Vec<VmType<&'a str>>,
+  pub interner: TwInterner<'a>,
   pub exported_graph_name_index: HashMap<&'a str, usize>,
+
+  /// Compiled `TwParamConstraint::Pattern`s, by source.
+  pub param_patterns: HashMap<&'a str, Regex>,
 }
 
 impl<'a> TwVm<'a> {
@@ -43,10 +53,20 @@ impl<'a> TwVm<'a> {
       .collect::<Vec<_>>();
 
     let mut exported_graph_name_index = HashMap::new();
+    let mut param_patterns = HashMap::new();
     for (i, g) in script.graphs.iter().enumerate() {
       if g.exported {
         exported_graph_name_index.insert(g.name.as_str(), i);
       }
+      for (_, x) in &g.param_constraints {
+        if let TwParamConstraint::Pattern(x) = x {
+          if !param_patterns.contains_key(x.as_str()) {
+            let re = Regex::new(&format!("^(?:{})$", x))
+              .map_err(|e| VmError::BadParamPattern(x.clone(), e))?;
+            param_patterns.insert(x.as_str(), re);
+          }
+        }
+      }
     }
 
     Ok(Self {
@@ -55,10 +75,22 @@ impl<'a> TwVm<'a> {
       script,
       consts,
       types,
+      interner: TwInterner::new(schema, script),
       exported_graph_name_index,
+      param_patterns,
     })
   }
 
+  /// Like `new`, but optimizes the script first. Scripts that are run should go through this.
+  pub fn new_optimized(
+    schema: &'a CompiledSchema,
+    storage_plan: &'a StoragePlan,
+    script: &'a mut TwScript,
+  ) -> Result<Self> {
+    optimize(script);
+    Self::new(schema, storage_plan, script)
+  }
+
   pub fn lookup_exported_graph_by_name(&self, name: &str) -> Result<usize> {
     Ok(
       self
@@ -5,10 +5,17 @@ use std::{collections::BTreeMap, fmt::Display, sync::Arc};
 use thiserror::Error;
 
 use crate::{
-  data::{pathwalker::PathWalker, value::PrimitiveValue},
+  data::{
+    keyenc,
+    pathwalker::PathWalker,
+    value::{PackedValue, PrimitiveValue},
+  },
   schema::compile::{CompiledSchema, FieldAnnotationList, FieldType, PrimitiveType},
 };
 
+/// Fields of the map passed to params of the `auth_context` pseudo-type. All of them are strings.
+pub const AUTH_CONTEXT_FIELDS: &[&str] = &["namespace_id", "token_id", "role"];
+
 #[derive(Debug, PartialEq)]
 pub enum VmValue<'a> {
   Primitive(PrimitiveValue),
@@ -24,12 +31,25 @@ pub enum VmValue<'a> {
   Null(VmType<&'a str>),
 
   List(VmListValue<'a>),
+
+  /// VM-only
+  ///
+  /// An error caught by `TryCall`. The message is `None` if `null` was thrown.
+  Error(Option<String>),
+
+  Dict(VmDictValue<'a>),
 }
 
 #[derive(Debug, PartialEq)]
 pub struct VmListValue<'a> {
   pub member_ty: VmType<&'a str>,
-  pub node: ListSync<Arc<VmValue<'a>>>,
+  pub kind: VmListValueKind<'a>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum VmListValueKind<'a> {
+  Resident(Arc<PathWalker<'a>>),
+  Fresh(ListSync<Arc<VmValue<'a>>>),
 }
 
 #[derive(Debug, PartialEq)]
@@ -48,6 +68,9 @@ pub enum VmTableValueKind<'a> {
 pub struct VmSetValue<'a> {
   pub member_ty: VmType<&'a str>,
   pub kind: VmSetValueKind<'a>,
+
+  /// Whether reads of a resident set of `@soft_delete` members also see deleted members.
+  pub include_deleted: bool,
 }
 
 #[derive(Debug, PartialEq)]
@@ -56,6 +79,13 @@ pub enum VmSetValueKind<'a> {
   Fresh(BTreeMap<Vec<u8>, Arc<VmValue<'a>>>),
 }
 
+/// A persisted `map<string, T>`.
+#[derive(Debug, PartialEq)]
+pub struct VmDictValue<'a> {
+  pub member_ty: VmType<&'a str>,
+  pub walker: Arc<PathWalker<'a>>,
+}
+
 #[derive(Debug, PartialEq)]
 pub struct VmMapValue<'a> {
   pub elements: RedBlackTreeMapSync<&'a str, Arc<VmValue<'a>>>,
@@ -81,6 +111,20 @@ pub enum VmType<K: Clone + Ord + PartialOrd + Eq + PartialEq> {
 
   /// The schema type. Placeholder.
   Schema,
+
+  /// VM-only
+  Error,
+
+  /// VM-only
+  ///
+  /// A value of any one of the listed types.
+  OneOf(Vec<VmType<K>>),
+
+  /// A persisted `map<string, T>`. Unlike `Map`, its keys are dynamic.
+  Dict(VmDictType<K>),
+
+  /// The identity of the caller. Placeholder for a map of the `AUTH_CONTEXT_FIELDS` strings.
+  AuthContext,
 }
 
 impl<K: AsRef<str> + Clone + Ord + PartialOrd + Eq + PartialEq> Display for VmType<K> {
@@ -100,7 +144,21 @@ impl<K: AsRef<str> + Clone + Ord + PartialOrd + Eq + PartialEq> Display for VmTy
       }
       VmType::List(x) => write!(f, "list<{}>", x.ty),
       VmType::Set(x) => write!(f, "set<{}>", x.ty),
+      VmType::Dict(x) => write!(f, "map<string, {}>", x.ty),
       VmType::Schema => write!(f, "schema"),
+      VmType::AuthContext => write!(f, "auth_context"),
+      VmType::Error => write!(f, "error"),
+      VmType::OneOf(x) => {
+        write!(f, "one_of<")?;
+        for (i, ty) in x.iter().enumerate() {
+          if i != 0 {
+            write!(f, ", ")?;
+          }
+          write!(f, "{}", ty)?;
+        }
+        write!(f, ">")?;
+        Ok(())
+      }
     }
   }
 }
@@ -115,6 +173,11 @@ pub struct VmListType<K: Clone + Ord + PartialOrd + Eq + PartialEq> {
   pub ty: Box<VmType<K>>,
 }
 
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize, Hash)]
+pub struct VmDictType<K: Clone + Ord + PartialOrd + Eq + PartialEq> {
+  pub ty: Box<VmType<K>>,
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize, Hash)]
 pub struct VmTableType<K> {
   pub name: K,
@@ -146,6 +209,12 @@ impl<
       ),
       VmType::Unknown => VmType::Unknown,
       VmType::Schema => VmType::Schema,
+      VmType::AuthContext => VmType::AuthContext,
+      VmType::Error => VmType::Error,
+      VmType::OneOf(x) => VmType::OneOf(x.iter().map(Self::from).collect()),
+      VmType::Dict(x) => VmType::Dict(VmDictType {
+        ty: Box::new(Self::from(&*x.ty)),
+      }),
     }
   }
 }
@@ -179,6 +248,10 @@ impl<'a> From<&VmValue<'a>> for VmType<&'a str> {
       ),
       VmValue::Null(x) => x.clone(),
       VmValue::List(x) => x.member_ty.clone(),
+      VmValue::Error(_) => VmType::Error,
+      VmValue::Dict(x) => VmType::Dict(VmDictType {
+        ty: Box::new(x.member_ty.clone()),
+      }),
     }
   }
 }
@@ -195,6 +268,19 @@ impl<'a, T: From<&'a str> + Clone + Ord + PartialOrd + Eq + PartialEq> From<&'a
       FieldType::Set(x) => VmType::Set(VmSetType {
         ty: Box::new(VmType::from(&**x)),
       }),
+      FieldType::List(x) => VmType::List(VmListType {
+        ty: Box::new(VmType::from(&**x)),
+      }),
+      FieldType::Map(x) => VmType::Dict(VmDictType {
+        ty: Box::new(VmType::from(&**x)),
+      }),
+      FieldType::Struct(x) => {
+        let mut members = RedBlackTreeMapSync::new_sync();
+        for (k, v) in x {
+          members.insert_mut(T::from(&**k), VmType::from(v));
+        }
+        VmType::Map(members)
+      }
     }
   }
 }
@@ -219,11 +305,38 @@ impl<'a> VmType<&'a str> {
       }
 
       false
+    } else if let VmType::OneOf(x) = self {
+      if let VmType::OneOf(y) = that {
+        y.iter().all(|y| x.iter().any(|x| x.is_covariant_from(y)))
+      } else {
+        x.iter().any(|x| x.is_covariant_from(that))
+      }
     } else {
       false
     }
   }
 
+  /// The type of the items of a `map<string, T>` when iterated: `map { key: string, value: T }`.
+  pub fn dict_entry_type(&self) -> Option<VmType<&'a str>> {
+    match self {
+      VmType::Dict(x) => {
+        let mut m = RedBlackTreeMapSync::new_sync();
+        m.insert_mut("key", VmType::Primitive(PrimitiveType::String));
+        m.insert_mut("value", (*x.ty).clone());
+        Some(VmType::Map(m))
+      }
+      _ => None,
+    }
+  }
+
+  /// Returns `T` if this is `OneOf<T, Error>`.
+  pub fn fallible_value_type(&self) -> Option<&VmType<&'a str>> {
+    match self {
+      VmType::OneOf(x) if x.len() == 2 && x[1] == VmType::Error => Some(&x[0]),
+      _ => None,
+    }
+  }
+
   pub fn set_primary_key(&self, schema: &'a CompiledSchema) -> Option<(&'a str, &'a FieldType)> {
     match self {
       VmType::Set(x) => match &*x.ty {
@@ -240,6 +353,17 @@ impl<'a> VmType<&'a str> {
     }
   }
 
+  /// Returns the `@sort_key` field of the member type of a set.
+  pub fn set_sort_key(&self, schema: &'a CompiledSchema) -> Option<(&'a str, &'a FieldType)> {
+    match self {
+      VmType::Set(x) => match &*x.ty {
+        VmType::Table(x) => schema.types.get(x.name)?.sort_key(),
+        _ => None,
+      },
+      _ => None,
+    }
+  }
+
   pub fn default_value(&self) -> Option<Arc<VmValue<'a>>> {
     Some(Arc::new(match self {
       VmType::Bool => VmValue::Bool(false),
@@ -251,16 +375,20 @@ impl<'a> VmType<&'a str> {
           .collect::<Option<_>>()?,
       }),
       VmType::Primitive(x) => VmValue::Primitive(PrimitiveValue::default_value_for_type(*x)),
-      VmType::Schema => return None,
+      VmType::Schema | VmType::AuthContext => return None,
       VmType::Set(ty) => VmValue::Set(VmSetValue {
         member_ty: (*ty.ty).clone(),
         kind: VmSetValueKind::Fresh(BTreeMap::new()),
+        include_deleted: false,
       }),
       VmType::Table(x) => VmValue::Table(VmTableValue {
         ty: x.name,
         kind: VmTableValueKind::Fresh(BTreeMap::new()),
       }),
       VmType::Unknown => return None,
+      VmType::Error => return None,
+      VmType::OneOf(_) => return None,
+      VmType::Dict(_) => return None,
     }))
   }
 }
@@ -300,6 +428,8 @@ pub enum VmValueError {
   MissingField(Arc<str>, Arc<str>),
   #[error("primary key not found in a set member type")]
   MissingPrimaryKey,
+  #[error("value of type `{0}` cannot be stored in a struct")]
+  NotPackable(String),
 }
 
 impl<'a> VmValue<'a> {
@@ -347,6 +477,7 @@ impl<'a> VmValue<'a> {
           .types
           .get(x.member_ty.as_str())
           .ok_or_else(|| VmValueError::TypeNotFound(x.member_ty.clone()))?;
+        let specialized_ty = member_ty;
         let member_ty = VmType::Table(VmTableType {
           name: &*member_ty.name,
         });
@@ -355,6 +486,7 @@ impl<'a> VmValue<'a> {
         })
         .set_primary_key(schema)
         .ok_or_else(|| VmValueError::MissingPrimaryKey)?;
+        let key_collation = specialized_ty.collation(primary_key);
         let mut members = BTreeMap::new();
         for member in &x.members {
           let member = Self::from_const(schema, member)?;
@@ -371,11 +503,12 @@ impl<'a> VmValue<'a> {
 
           // XXX: We checked covariance above but is it enough?
           let primary_key_value = match &member.unwrap_table().kind {
-            VmTableValueKind::Fresh(x) => x
-              .get(primary_key)
-              .unwrap()
-              .unwrap_primitive()
-              .serialize_for_key_component(),
+            VmTableValueKind::Fresh(x) => keyenc::encode_key(
+              &x.get(primary_key)
+                .unwrap()
+                .unwrap_primitive()
+                .collated(key_collation),
+            ),
             _ => unreachable!(),
           };
           members.insert(primary_key_value.to_vec(), Arc::new(member));
@@ -383,6 +516,7 @@ impl<'a> VmValue<'a> {
         Ok(Self::Set(VmSetValue {
           member_ty,
           kind: VmSetValueKind::Fresh(members),
+          include_deleted: false,
         }))
       }
       VmConst::Null(x) => Ok(Self::Null(VmType::from(x))),
@@ -390,6 +524,49 @@ impl<'a> VmValue<'a> {
     }
   }
 
+  /// Converts a struct value to the packed form it is stored in. Null members are omitted.
+  pub fn pack(&self) -> Result<PackedValue> {
+    match self {
+      VmValue::Primitive(x) => Ok(PackedValue::P(x.clone().normalized())),
+      VmValue::Map(x) => Ok(PackedValue::M(
+        x.elements
+          .iter()
+          .filter(|(_, v)| !v.is_null())
+          .map(|(k, v)| Ok((k.to_string(), v.pack()?)))
+          .collect::<Result<_>>()?,
+      )),
+      _ => Err(VmValueError::NotPackable(format!("{:?}", VmType::from(self))).into()),
+    }
+  }
+
+  /// Converts a packed value back to a value of type `ty`. Struct members missing from `packed`
+  /// are null.
+  pub fn unpack(ty: &VmType<&'a str>, packed: &PackedValue) -> Self {
+    match (ty, packed) {
+      (VmType::Primitive(_), PackedValue::P(x)) => VmValue::Primitive(x.clone()),
+      (VmType::Map(members), PackedValue::M(fields)) => VmValue::Map(VmMapValue {
+        elements: members
+          .iter()
+          .map(|(name, ty)| {
+            let value = match fields.get(*name) {
+              Some(x) => VmValue::unpack(ty, x),
+              None => VmValue::Null(ty.clone()),
+            };
+            (*name, Arc::new(value))
+          })
+          .collect(),
+      }),
+      _ => VmValue::Null(ty.clone()),
+    }
+  }
+
+  pub fn is_error(&self) -> bool {
+    match self {
+      VmValue::Error(_) => true,
+      _ => false,
+    }
+  }
+
   pub fn unwrap_table<'b>(&'b self) -> &'b VmTableValue<'a> {
     match self {
       VmValue::Table(x) => x,
@@ -1,12 +1,19 @@
-use std::{collections::BTreeMap, fmt::Display, iter::FromIterator};
+use std::{
+  borrow::Cow,
+  collections::BTreeMap,
+  fmt::Display,
+  hash::{Hash, Hasher},
+};
 
-use byteorder::{BigEndian, ByteOrder};
+use anyhow::Result;
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
 use serde::{Deserialize, Serialize};
-use smallvec::{smallvec, SmallVec};
+use thiserror::Error;
 
-use crate::schema::compile::PrimitiveType;
+use crate::schema::compile::{Collation, PrimitiveType};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub enum PackedValue {
   /// Primitive value.
   P(PrimitiveValue),
@@ -18,17 +25,90 @@ pub enum PackedValue {
   S(Vec<PackedValue>),
 }
 
-#[derive(Clone, Serialize, Eq, PartialEq, Hash, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug)]
 #[serde(untagged)]
 pub enum PrimitiveValue {
   String(String),
   Bytes(Vec<u8>),
   Int64(i64),
+
+  /// The bits of an `f64`.
+  ///
+  /// Doubles compare by their normalized bits: all NaNs are equal to each other, and `-0.0` is
+  /// equal to `0.0`. They sort in the order of their key encoding, which is `-inf`, negative
+  /// numbers, zero, positive numbers, `inf` and NaN last. Values are normalized when written.
   Double(u64),
 }
 
 const TOP_BIT: u64 = 1u64 << 63;
 
+/// Encoded values of at least this many bytes are stored compressed, if that makes them smaller.
+pub const COMPRESSION_THRESHOLD: usize = 1024;
+
+/// The first byte of a compressed value. It is never the first byte of a msgpack value, so
+/// compressed and uncompressed values can be told apart without a header on every value.
+const COMPRESSED_VALUE_MARKER: u8 = 0xc1;
+
+/// Compression formats, stored in the byte after `COMPRESSED_VALUE_MARKER`. `0xcc` is taken by
+/// checksummed values (see `kv::checksum`).
+const COMPRESSION_LZ4: u8 = 0x01;
+
+/// Max decompressed size of a stored value. Checked against the size header before allocating,
+/// so that a corrupted header cannot make a read allocate without bound.
+pub const MAX_DECOMPRESSED_VALUE_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum StoredValueError {
+  #[error("unknown compression format: {0}")]
+  UnknownCompression(u8),
+
+  #[error("corrupted compressed value")]
+  CorruptedCompressedValue,
+
+  #[error("decompressed value too large: {0} bytes")]
+  DecompressedValueTooLarge(usize),
+}
+
+/// The NaN that all NaNs are normalized to.
+pub const CANONICAL_NAN_BITS: u64 = 0x7ff8_0000_0000_0000;
+
+/// Maps all NaNs to `CANONICAL_NAN_BITS` and `-0.0` to `0.0`.
+pub fn normalize_double_bits(x: u64) -> u64 {
+  if f64::from_bits(x).is_nan() {
+    CANONICAL_NAN_BITS
+  } else if x == TOP_BIT {
+    0
+  } else {
+    x
+  }
+}
+
+impl PartialEq for PrimitiveValue {
+  fn eq(&self, other: &Self) -> bool {
+    match (self, other) {
+      (Self::String(l), Self::String(r)) => l == r,
+      (Self::Bytes(l), Self::Bytes(r)) => l == r,
+      (Self::Int64(l), Self::Int64(r)) => l == r,
+      (Self::Double(l), Self::Double(r)) => normalize_double_bits(*l) == normalize_double_bits(*r),
+      _ => false,
+    }
+  }
+}
+
+impl Eq for PrimitiveValue {}
+
+impl Hash for PrimitiveValue {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    std::mem::discriminant(self).hash(state);
+    match self {
+      Self::String(x) => x.hash(state),
+      Self::Bytes(x) => x.hash(state),
+      Self::Int64(x) => x.hash(state),
+      Self::Double(x) => normalize_double_bits(*x).hash(state),
+    }
+  }
+}
+
 impl Display for PrimitiveValue {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     match self {
@@ -50,53 +130,31 @@ impl PrimitiveValue {
     }
   }
 
-  pub fn unwrap_string(&self) -> &String {
-    match self {
-      PrimitiveValue::String(x) => x,
-      _ => panic!("PrimitiveValue::unwrap_string: not a string: {:?}", self),
-    }
+  /// A double with normalized bits.
+  pub fn double(x: f64) -> Self {
+    Self::Double(normalize_double_bits(x.to_bits()))
   }
 
-  /// https://activesphere.com/blog/2018/08/17/order-preserving-serialization
-  pub fn serialize_for_key_component(&self) -> SmallVec<[u8; 9]> {
+  /// Normalizes doubles, leaving other values unchanged.
+  pub fn normalized(self) -> Self {
     match self {
-      PrimitiveValue::Bytes(x) => SmallVec::from_iter(
-        std::iter::once(0x01u8)
-          .chain(
-            x.iter()
-              .map(|&x| -> SmallVec<[u8; 2]> {
-                if x == 0 {
-                  smallvec![0x00, 0xff]
-                } else {
-                  smallvec![x]
-                }
-              })
-              .flatten(),
-          )
-          .chain([0x00u8].iter().copied()),
-      ),
-      PrimitiveValue::String(x) => {
-        SmallVec::from_iter(std::iter::once(0x02u8).chain(x.as_bytes().iter().copied()))
-      }
-      PrimitiveValue::Int64(x) => {
-        // Flip the top bit for order preservation.
-        let x = (*x as u64) ^ TOP_BIT;
-
-        let mut buf = smallvec![0u8; 9];
-        buf[0] = 0x03;
-        BigEndian::write_u64(&mut buf[1..], x);
-        buf
-      }
-      PrimitiveValue::Double(x) => {
-        let x = *x;
+      Self::Double(x) => Self::Double(normalize_double_bits(x)),
+      x => x,
+    }
+  }
 
-        let x = if x & TOP_BIT != 0 { !x } else { x ^ TOP_BIT };
+  /// Applies `collation` to a string. Other values are returned as is.
+  pub fn collated(&self, collation: Option<Collation>) -> Cow<'_, Self> {
+    match (self, collation) {
+      (Self::String(x), Some(collation)) => Cow::Owned(Self::String(collation.apply(x))),
+      _ => Cow::Borrowed(self),
+    }
+  }
 
-        let mut buf = smallvec![0u8; 9];
-        buf[0] = 0x04;
-        BigEndian::write_u64(&mut buf[1..], x);
-        buf
-      }
+  pub fn unwrap_string(&self) -> &String {
+    match self {
+      PrimitiveValue::String(x) => x,
+      _ => panic!("PrimitiveValue::unwrap_string: not a string: {:?}", self),
     }
   }
 
@@ -118,4 +176,107 @@ impl PrimitiveValue {
       PrimitiveType::Double => Self::Double(0),
     }
   }
+
+  /// Encodes a value for the KV store, as `rmp_serde::to_vec` does. Encodings of at least
+  /// `COMPRESSION_THRESHOLD` bytes are compressed with LZ4 and prefixed with a two-byte header.
+  pub fn encode_stored(&self) -> Vec<u8> {
+    let encoded = rmp_serde::to_vec(self).unwrap();
+    if encoded.len() < COMPRESSION_THRESHOLD {
+      return encoded;
+    }
+    let mut out = vec![COMPRESSED_VALUE_MARKER, COMPRESSION_LZ4];
+    out.extend_from_slice(&lz4_flex::compress_prepend_size(&encoded));
+    if out.len() < encoded.len() {
+      out
+    } else {
+      encoded
+    }
+  }
+
+  /// Decodes a value written with `encode_stored` or `rmp_serde::to_vec`, taking ownership of the
+  /// buffer read from the KV store.
+  ///
+  /// Strings reuse `buf` in place, and byte arrays are decoded in one pass. Going through
+  /// `rmp_serde` would buffer the untagged value first and copy it again, which dominates reads of
+  /// large fields. Other values are decoded with `rmp_serde`.
+  pub fn decode_stored(mut buf: Vec<u8>) -> Result<Self> {
+    if buf.first() == Some(&COMPRESSED_VALUE_MARKER) {
+      buf = decompress_stored(&buf)?;
+    }
+    if let Some((header_len, len)) = msgpack_str_header(&buf) {
+      if header_len + len == buf.len() {
+        buf.drain(..header_len);
+        return Ok(Self::String(String::from_utf8(buf)?));
+      }
+    }
+    if let Some(x) = decode_msgpack_u8_array(&buf) {
+      return Ok(Self::Bytes(x));
+    }
+    Ok(rmp_serde::from_slice(&buf)?)
+  }
+}
+
+/// Decompresses a value written by `PrimitiveValue::encode_stored` into its msgpack encoding.
+fn decompress_stored(buf: &[u8]) -> Result<Vec<u8>> {
+  match buf.get(1) {
+    Some(&COMPRESSION_LZ4) => {
+      // The payload is prefixed with its decompressed size, as a little endian u32.
+      if buf.len() < 6 {
+        return Err(StoredValueError::CorruptedCompressedValue.into());
+      }
+      let size = LittleEndian::read_u32(&buf[2..6]) as usize;
+      if size > MAX_DECOMPRESSED_VALUE_SIZE {
+        return Err(StoredValueError::DecompressedValueTooLarge(size).into());
+      }
+      let mut out = vec![0u8; size];
+      match lz4_flex::decompress_into(&buf[6..], &mut out) {
+        Ok(n) if n == size => Ok(out),
+        _ => Err(StoredValueError::CorruptedCompressedValue.into()),
+      }
+    }
+    Some(x) => Err(StoredValueError::UnknownCompression(*x).into()),
+    None => Err(StoredValueError::CorruptedCompressedValue.into()),
+  }
+}
+
+/// Returns the header length and payload length of a msgpack string.
+fn msgpack_str_header(buf: &[u8]) -> Option<(usize, usize)> {
+  match *buf.first()? {
+    x @ 0xa0..=0xbf => Some((1, (x & 0x1f) as usize)),
+    0xd9 => Some((2, *buf.get(1)? as usize)),
+    0xda if buf.len() >= 3 => Some((3, BigEndian::read_u16(&buf[1..3]) as usize)),
+    0xdb if buf.len() >= 5 => Some((5, BigEndian::read_u32(&buf[1..5]) as usize)),
+    _ => None,
+  }
+}
+
+/// Decodes a msgpack array whose elements all fit in a `u8`, which is how `rmp_serde` encodes
+/// `Vec<u8>`.
+fn decode_msgpack_u8_array(buf: &[u8]) -> Option<Vec<u8>> {
+  let (header_len, len) = match *buf.first()? {
+    x @ 0x90..=0x9f => (1, (x & 0x0f) as usize),
+    0xdc if buf.len() >= 3 => (3, BigEndian::read_u16(&buf[1..3]) as usize),
+    0xdd if buf.len() >= 5 => (5, BigEndian::read_u32(&buf[1..5]) as usize),
+    _ => return None,
+  };
+  let mut out = Vec::with_capacity(len.min(buf.len()));
+  let mut rest = &buf[header_len..];
+  for _ in 0..len {
+    match *rest.first()? {
+      x @ 0x00..=0x7f => {
+        out.push(x);
+        rest = &rest[1..];
+      }
+      0xcc => {
+        out.push(*rest.get(1)?);
+        rest = &rest[2..];
+      }
+      _ => return None,
+    }
+  }
+  if rest.is_empty() {
+    Some(out)
+  } else {
+    None
+  }
 }
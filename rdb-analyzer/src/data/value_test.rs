@@ -0,0 +1,140 @@
+use std::{
+  collections::hash_map::DefaultHasher,
+  hash::{Hash, Hasher},
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use super::{
+  keyenc,
+  value::{PrimitiveValue, StoredValueError, CANONICAL_NAN_BITS, COMPRESSION_THRESHOLD},
+};
+
+fn check_roundtrip(value: PrimitiveValue) {
+  let encoded = rmp_serde::to_vec(&value).unwrap();
+  let expected: PrimitiveValue = rmp_serde::from_slice(&encoded).unwrap();
+  assert_eq!(PrimitiveValue::decode_stored(encoded).unwrap(), expected);
+}
+
+#[test]
+fn decode_stored_matches_rmp_serde() {
+  for len in &[0usize, 1, 31, 32, 255, 256, 65535, 65536] {
+    check_roundtrip(PrimitiveValue::String("x".repeat(*len)));
+    check_roundtrip(PrimitiveValue::Bytes(
+      (0..*len).map(|x| (x * 7) as u8).collect(),
+    ));
+  }
+  check_roundtrip(PrimitiveValue::String("\u{1f600} unicode".into()));
+  for x in &[0i64, 1, -1, 127, 128, -33, i64::MIN, i64::MAX] {
+    check_roundtrip(PrimitiveValue::Int64(*x));
+  }
+  for x in &[0.0f64, -1.5, f64::MAX, f64::NAN] {
+    check_roundtrip(PrimitiveValue::Double(x.to_bits()));
+  }
+}
+
+#[test]
+fn decode_stored_rejects_truncated_input() {
+  let mut encoded = rmp_serde::to_vec(&PrimitiveValue::String("hello".into())).unwrap();
+  encoded.pop();
+  assert!(PrimitiveValue::decode_stored(encoded).is_err());
+
+  let mut encoded = rmp_serde::to_vec(&PrimitiveValue::Bytes(vec![1, 200, 3])).unwrap();
+  encoded.pop();
+  assert!(PrimitiveValue::decode_stored(encoded).is_err());
+}
+
+#[test]
+fn encode_stored_compresses_large_values() {
+  // Small values are stored as plain msgpack.
+  for value in &[
+    PrimitiveValue::String("hello".into()),
+    PrimitiveValue::Int64(42),
+    PrimitiveValue::String("x".repeat(COMPRESSION_THRESHOLD - 8)),
+  ] {
+    assert_eq!(value.encode_stored(), rmp_serde::to_vec(value).unwrap());
+  }
+
+  for value in &[
+    PrimitiveValue::String("abcd".repeat(COMPRESSION_THRESHOLD)),
+    PrimitiveValue::Bytes(vec![7; 65536]),
+  ] {
+    let encoded = value.encode_stored();
+    assert_eq!(encoded[0], 0xc1);
+    assert!(encoded.len() < rmp_serde::to_vec(value).unwrap().len() / 4);
+    assert_eq!(&PrimitiveValue::decode_stored(encoded).unwrap(), value);
+  }
+
+  // Values that do not compress are stored as they are.
+  let mut rng = StdRng::seed_from_u64(0);
+  let noise = PrimitiveValue::Bytes((0..COMPRESSION_THRESHOLD * 4).map(|_| rng.gen()).collect());
+  let encoded = noise.encode_stored();
+  assert_eq!(encoded, rmp_serde::to_vec(&noise).unwrap());
+  assert_eq!(PrimitiveValue::decode_stored(encoded).unwrap(), noise);
+}
+
+#[test]
+fn decode_stored_rejects_bad_compressed_values() {
+  let mut encoded = PrimitiveValue::String("abcd".repeat(COMPRESSION_THRESHOLD)).encode_stored();
+  encoded[1] = 0x7f;
+  let e = PrimitiveValue::decode_stored(encoded.clone()).unwrap_err();
+  assert!(matches!(
+    e.downcast_ref::<StoredValueError>(),
+    Some(StoredValueError::UnknownCompression(0x7f))
+  ));
+
+  encoded[1] = 0x01;
+  encoded.truncate(encoded.len() - 4);
+  let e = PrimitiveValue::decode_stored(encoded).unwrap_err();
+  assert!(matches!(
+    e.downcast_ref::<StoredValueError>(),
+    Some(StoredValueError::CorruptedCompressedValue)
+  ));
+  assert!(PrimitiveValue::decode_stored(vec![0xc1]).is_err());
+  assert!(PrimitiveValue::decode_stored(vec![0xc1, 0x01, 0x00]).is_err());
+
+  // The size header is checked before allocating.
+  let mut encoded = vec![0xc1, 0x01];
+  encoded.extend_from_slice(&u32::MAX.to_le_bytes());
+  encoded.extend_from_slice(&[0x10, 0x00]);
+  let e = PrimitiveValue::decode_stored(encoded).unwrap_err();
+  assert!(matches!(
+    e.downcast_ref::<StoredValueError>(),
+    Some(StoredValueError::DecompressedValueTooLarge(x)) if *x == u32::MAX as usize
+  ));
+}
+
+fn hash_of(x: &PrimitiveValue) -> u64 {
+  let mut hasher = DefaultHasher::new();
+  x.hash(&mut hasher);
+  hasher.finish()
+}
+
+#[test]
+fn double_normalization() {
+  let negative_nan = PrimitiveValue::Double((-f64::NAN).to_bits());
+  let payload_nan = PrimitiveValue::Double(CANONICAL_NAN_BITS | 1);
+  let nan = PrimitiveValue::double(f64::NAN);
+  assert!(matches!(nan, PrimitiveValue::Double(CANONICAL_NAN_BITS)));
+  for x in &[&negative_nan, &payload_nan] {
+    assert_eq!(*x, &nan);
+    assert_eq!(hash_of(x), hash_of(&nan));
+    assert_eq!(keyenc::encode_key(x), keyenc::encode_key(&nan));
+    assert!(matches!(
+      (*x).clone().normalized(),
+      PrimitiveValue::Double(CANONICAL_NAN_BITS)
+    ));
+  }
+
+  let negative_zero = PrimitiveValue::Double((-0.0f64).to_bits());
+  let zero = PrimitiveValue::double(0.0);
+  assert_eq!(negative_zero, zero);
+  assert_eq!(hash_of(&negative_zero), hash_of(&zero));
+  assert!(matches!(
+    PrimitiveValue::double(-0.0),
+    PrimitiveValue::Double(0)
+  ));
+
+  assert_ne!(PrimitiveValue::double(1.0), PrimitiveValue::double(-1.0));
+  assert_ne!(nan, PrimitiveValue::double(f64::INFINITY));
+}
@@ -1,4 +1,8 @@
 pub mod data;
 pub mod schema;
 pub mod storage_plan;
-mod util;
+#[cfg(any(test, feature = "testutil"))]
+pub mod testutil;
+
+#[cfg(test)]
+mod testutil_test;
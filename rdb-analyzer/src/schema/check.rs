@@ -0,0 +1,217 @@
+use std::{
+  cmp::Ordering,
+  collections::{BTreeMap, BTreeSet},
+  fmt::Display,
+  sync::Arc,
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::data::{keyenc, value::PrimitiveValue};
+
+use super::{
+  compile::{FieldAnnotation, FieldType, PrimitiveType, SchemaCompileError},
+  grammar::ast::{self, Literal},
+};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum CheckOp {
+  Eq,
+  Ne,
+  Lt,
+  Le,
+  Gt,
+  Ge,
+}
+
+/// A compiled `check` clause of a type.
+///
+/// Checks are evaluated against the fields of each table value written, with SQL-like
+/// three-valued logic: a comparison with a null field is unknown, and a check is only violated if
+/// it evaluates to false.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum CheckPredicate {
+  Compare(CheckOp, CheckOperand, CheckOperand),
+  And(Box<CheckPredicate>, Box<CheckPredicate>),
+  Or(Box<CheckPredicate>, Box<CheckPredicate>),
+  Not(Box<CheckPredicate>),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum CheckOperand {
+  Field(Arc<str>),
+  Const(PrimitiveValue),
+}
+
+impl CheckPredicate {
+  /// Compiles a check clause against the resolved fields of `ty`. Only primitive fields can be
+  /// referenced, and both sides of a comparison must have the same type.
+  pub fn compile(
+    ty: &str,
+    fields: &BTreeMap<Arc<str>, (FieldType, Vec<FieldAnnotation>)>,
+    expr: &ast::CheckExpr,
+  ) -> Result<Self> {
+    Ok(match expr {
+      ast::CheckExpr::Compare(op, left, right) => {
+        let (left, left_ty) = compile_operand(ty, fields, left)?;
+        let (right, right_ty) = compile_operand(ty, fields, right)?;
+
+        // Integer literals are accepted for double fields.
+        let (left, right) = match (left, left_ty, right, right_ty) {
+          (l, PrimitiveType::Double, CheckOperand::Const(PrimitiveValue::Int64(x)), _) => (
+            l,
+            CheckOperand::Const(PrimitiveValue::Double((x as f64).to_bits())),
+          ),
+          (CheckOperand::Const(PrimitiveValue::Int64(x)), _, r, PrimitiveType::Double) => (
+            CheckOperand::Const(PrimitiveValue::Double((x as f64).to_bits())),
+            r,
+          ),
+          (l, _, r, _) if left_ty == right_ty => (l, r),
+          _ => {
+            return Err(
+              SchemaCompileError::CheckTypeMismatch(ty.to_string(), left_ty, right_ty).into(),
+            )
+          }
+        };
+        Self::Compare(*op, left, right)
+      }
+      ast::CheckExpr::And(left, right) => Self::And(
+        Box::new(Self::compile(ty, fields, left)?),
+        Box::new(Self::compile(ty, fields, right)?),
+      ),
+      ast::CheckExpr::Or(left, right) => Self::Or(
+        Box::new(Self::compile(ty, fields, left)?),
+        Box::new(Self::compile(ty, fields, right)?),
+      ),
+      ast::CheckExpr::Not(x) => Self::Not(Box::new(Self::compile(ty, fields, x)?)),
+    })
+  }
+
+  /// Evaluates this predicate, looking up field values with `field`. Returns `None` if the result
+  /// is unknown because of null fields.
+  pub fn eval<F: Fn(&str) -> Option<PrimitiveValue>>(&self, field: &F) -> Option<bool> {
+    match self {
+      Self::Compare(op, left, right) => {
+        let resolve = |x: &CheckOperand| match x {
+          CheckOperand::Field(name) => field(name),
+          CheckOperand::Const(x) => Some(x.clone()),
+        };
+        let (left, right) = (resolve(left)?, resolve(right)?);
+        let ordering = keyenc::encode_key(&left).cmp(&keyenc::encode_key(&right));
+        Some(match op {
+          CheckOp::Eq => ordering == Ordering::Equal,
+          CheckOp::Ne => ordering != Ordering::Equal,
+          CheckOp::Lt => ordering == Ordering::Less,
+          CheckOp::Le => ordering != Ordering::Greater,
+          CheckOp::Gt => ordering == Ordering::Greater,
+          CheckOp::Ge => ordering != Ordering::Less,
+        })
+      }
+      Self::And(left, right) => match (left.eval(field), right.eval(field)) {
+        (Some(false), _) | (_, Some(false)) => Some(false),
+        (Some(true), Some(true)) => Some(true),
+        _ => None,
+      },
+      Self::Or(left, right) => match (left.eval(field), right.eval(field)) {
+        (Some(true), _) | (_, Some(true)) => Some(true),
+        (Some(false), Some(false)) => Some(false),
+        _ => None,
+      },
+      Self::Not(x) => x.eval(field).map(|x| !x),
+    }
+  }
+
+  /// Returns the names of the fields this predicate refers to.
+  pub fn referenced_fields(&self) -> BTreeSet<&str> {
+    let mut out = BTreeSet::new();
+    self.collect_fields(&mut out);
+    out
+  }
+
+  fn collect_fields<'a>(&'a self, out: &mut BTreeSet<&'a str>) {
+    match self {
+      Self::Compare(_, left, right) => {
+        for x in [left, right] {
+          if let CheckOperand::Field(name) = x {
+            out.insert(&**name);
+          }
+        }
+      }
+      Self::And(left, right) | Self::Or(left, right) => {
+        left.collect_fields(out);
+        right.collect_fields(out);
+      }
+      Self::Not(x) => x.collect_fields(out),
+    }
+  }
+
+  /// Returns whether a value with these field values violates this check.
+  pub fn is_violated_by<F: Fn(&str) -> Option<PrimitiveValue>>(&self, field: &F) -> bool {
+    self.eval(field) == Some(false)
+  }
+}
+
+fn compile_operand(
+  ty: &str,
+  fields: &BTreeMap<Arc<str>, (FieldType, Vec<FieldAnnotation>)>,
+  operand: &ast::CheckOperand,
+) -> Result<(CheckOperand, PrimitiveType)> {
+  Ok(match operand {
+    ast::CheckOperand::Field(name) => {
+      let (name, (field_ty, _)) = fields.get_key_value(name.0).ok_or_else(|| {
+        SchemaCompileError::CheckOnUnknownField(ty.to_string(), name.0.to_string())
+      })?;
+      match field_ty {
+        FieldType::Primitive(x) => (CheckOperand::Field(name.clone()), *x),
+        _ => {
+          return Err(
+            SchemaCompileError::CheckOnNonPrimitiveField(ty.to_string(), name.to_string()).into(),
+          )
+        }
+      }
+    }
+    ast::CheckOperand::Literal(x) => {
+      let value = match x {
+        Literal::Integer(x) => PrimitiveValue::Int64(*x),
+        Literal::String(x) => PrimitiveValue::String(x.to_string()),
+        Literal::Bytes(x) => PrimitiveValue::Bytes(x.to_vec()),
+      };
+      let ty = value.get_type();
+      (CheckOperand::Const(value), ty)
+    }
+  })
+}
+
+impl Display for CheckOp {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Eq => write!(f, "=="),
+      Self::Ne => write!(f, "!="),
+      Self::Lt => write!(f, "<"),
+      Self::Le => write!(f, "<="),
+      Self::Gt => write!(f, ">"),
+      Self::Ge => write!(f, ">="),
+    }
+  }
+}
+
+impl Display for CheckOperand {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Field(x) => write!(f, "{}", x),
+      Self::Const(x) => write!(f, "{}", x),
+    }
+  }
+}
+
+impl Display for CheckPredicate {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Compare(op, left, right) => write!(f, "{} {} {}", left, op, right),
+      Self::And(left, right) => write!(f, "({} && {})", left, right),
+      Self::Or(left, right) => write!(f, "({} || {})", left, right),
+      Self::Not(x) => write!(f, "!({})", x),
+    }
+  }
+}
@@ -5,7 +5,10 @@ use std::sync::Arc;
 
 use anyhow::Result;
 use thiserror::Error;
+use unicode_normalization::UnicodeNormalization;
 
+use super::check::CheckPredicate;
+use super::diagnostic::LocatedError;
 use super::grammar::ast::{self, TypeExpr};
 use crate::schema::grammar::ast::Literal;
 use crate::schema::grammar::ast::SchemaItem;
@@ -44,15 +47,54 @@ pub enum SchemaCompileError {
   #[error("sets must have exactly one table type parameter")]
   BadSetTypeParameter,
 
+  #[error("struct member `{0}` must be a primitive or struct type, got `{1}`")]
+  BadStructMember(String, String),
+
+  #[error("lists must have exactly one primitive or struct type parameter")]
+  BadListTypeParameter,
+
+  #[error("maps must have a string key type and a primitive, struct or table value type")]
+  BadMapTypeParameter,
+
   #[error("unknown annotation on field `{0}` of type `{1}`: `{2}`")]
   UnknownAnnotationOnField(String, String, String),
 
+  #[error("unknown annotation on type `{0}`: `{1}`")]
+  UnknownAnnotationOnType(String, String),
+
   #[error("field `{0}` of type `{1}`: indexes are only allowed on primitive fields")]
   IndexOnNonPrimitiveField(String, String),
 
   #[error("type `{0}` has multiple primary keys")]
   MultiplePrimaryKeys(String),
 
+  #[error("field `{0}` of type `{1}`: `@auto` is only allowed on int64 primary keys")]
+  BadAutoField(String, String),
+
+  #[error("field `{0}` of type `{1}`: `@collate` is only allowed on string fields")]
+  CollationOnNonStringField(String, String),
+
+  #[error("field `{0}` of type `{1}`: `@blob` is only allowed on bytes fields that are not keys")]
+  BadBlobField(String, String),
+
+  #[error("unknown collation: `{0}`")]
+  UnknownCollation(String),
+
+  #[error("type `{0}` has multiple sort keys")]
+  MultipleSortKeys(String),
+
+  #[error("check on type `{0}` refers to unknown field `{1}`")]
+  CheckOnUnknownField(String, String),
+
+  #[error("check on type `{0}` refers to non-primitive field `{1}`")]
+  CheckOnNonPrimitiveField(String, String),
+
+  #[error("check on type `{0}` compares values of different types: `{1}` and `{2}`")]
+  CheckTypeMismatch(String, PrimitiveType, PrimitiveType),
+
+  #[error("set member type `{0}` has no primary key")]
+  SetMemberWithoutPrimaryKey(String),
+
   #[error("type name must start with an upper-case letter: `{0}`")]
   TypeNameMustStartWithUpperCaseLetter(String),
 }
@@ -80,6 +122,17 @@ impl Display for PrimitiveType {
   }
 }
 
+/// Name of the field maintained on types annotated with `@timestamps`, set when a value is first
+/// written.
+pub const CREATED_AT_FIELD: &str = "created_at";
+
+/// Name of the field maintained on types annotated with `@timestamps`, set on every write.
+pub const UPDATED_AT_FIELD: &str = "updated_at";
+
+/// Name of the field maintained on types annotated with `@soft_delete`, set when a member is
+/// deleted from a set and absent otherwise.
+pub const DELETED_AT_FIELD: &str = "deleted_at";
+
 static PRIMITIVE_TYPES: phf::Map<&'static str, PrimitiveType> = phf::phf_map! {
   "int64" => PrimitiveType::Int64,
   "double" => PrimitiveType::Double,
@@ -116,14 +169,35 @@ pub fn compile<'a>(input: &ast::Schema<'a>) -> Result<CompiledSchema> {
     match item {
       SchemaItem::Export(x) => {
         if result.exports.contains_key(x.table_name.0) {
-          return Err(SchemaCompileError::DuplicateExport(x.table_name.0.to_string()).into());
+          return Err(LocatedError::wrap(
+            x.location,
+            SchemaCompileError::DuplicateExport(x.table_name.0.to_string()).into(),
+          ));
         }
-        let ty = resolution_ctx.resolve_type_expr(&HashMap::new(), &x.ty)?;
+        let ty = resolution_ctx
+          .resolve_type_expr(&HashMap::new(), &x.ty)
+          .map_err(|e| LocatedError::wrap(x.location, e))?;
+        resolution_ctx.record_set_member(&ty, x.location);
         result.exports.insert(Arc::from(x.table_name.0), ty);
       }
       _ => {}
     }
   }
+
+  // Validation: Set members must have a primary key
+  for (member_ty, location) in &resolution_ctx.set_members {
+    let has_primary_key = resolution_ctx.resolved[member_ty]
+      .fields
+      .values()
+      .any(|(_, annotations)| annotations.as_slice().is_primary());
+    if !has_primary_key {
+      return Err(LocatedError::wrap(
+        *location,
+        SchemaCompileError::SetMemberWithoutPrimaryKey(member_ty.to_string()).into(),
+      ));
+    }
+  }
+
   result.types = resolution_ctx.resolved.clone();
   Ok(result)
 }
@@ -132,6 +206,10 @@ pub fn compile<'a>(input: &ast::Schema<'a>) -> Result<CompiledSchema> {
 pub struct SpecializedType {
   pub name: Arc<str>,
   pub fields: BTreeMap<Arc<str>, (FieldType, Vec<FieldAnnotation>)>,
+
+  /// Constraints checked whenever a value of this type is written.
+  #[serde(default)]
+  pub checks: Vec<CheckPredicate>,
 }
 
 pub struct IndexedField<'a> {
@@ -154,6 +232,47 @@ impl SpecializedType {
         is_unique: x.1.as_slice().is_unique(),
       })
   }
+
+  /// Returns the primary key field if its values are generated with `@auto`.
+  pub fn auto_primary_key(&self) -> Option<&str> {
+    self
+      .fields
+      .iter()
+      .find(|(_, (_, annotations))| {
+        annotations.as_slice().is_primary() && annotations.as_slice().is_auto()
+      })
+      .map(|(name, _)| &**name)
+  }
+
+  /// Whether the type is annotated with `@timestamps`.
+  pub fn has_timestamps(&self) -> bool {
+    self
+      .fields
+      .values()
+      .any(|(_, annotations)| annotations.as_slice().is_timestamp())
+  }
+
+  /// Whether the type is annotated with `@soft_delete`.
+  pub fn has_soft_delete(&self) -> bool {
+    self
+      .fields
+      .values()
+      .any(|(_, annotations)| annotations.as_slice().is_deleted_at())
+  }
+
+  /// Returns the collation of a field, if it has one.
+  pub fn collation(&self, field: &str) -> Option<Collation> {
+    self.fields.get(field)?.1.as_slice().collation()
+  }
+
+  /// Returns the field annotated with `@sort_key`, if any.
+  pub fn sort_key(&self) -> Option<(&str, &FieldType)> {
+    self
+      .fields
+      .iter()
+      .find(|(_, (_, annotations))| annotations.as_slice().is_sort_key())
+      .map(|(name, (ty, _))| (&**name, ty))
+  }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
@@ -161,13 +280,97 @@ pub enum FieldAnnotation {
   PrimaryKey,
   Unique,
   Index,
+
+  /// Members of sets of this type are also ordered by this field, so that they can be scanned in
+  /// that order.
+  SortKey,
   RenameFrom(String),
+
+  /// Values of this primary key are generated on insertion when null, from a counter kept in the
+  /// set. Generated keys increase monotonically within a set.
+  Auto,
+
+  /// Fields added by `@timestamps` on the type, holding the time in milliseconds when a value was
+  /// first written and last written. Maintained by the executor and stored under reserved keys.
+  CreatedAt,
+  UpdatedAt,
+
+  /// Field added by `@soft_delete` on the type. Deleting a member from a set sets it to the time
+  /// in milliseconds instead of removing the member.
+  DeletedAt,
+
+  /// Values of this field are collated before they are encoded into keys, when the field is the
+  /// primary key or the sort key of set members.
+  Collate(Collation),
+
+  /// Values of this bytes field are split into chunks stored under consecutive keys, so that they
+  /// can exceed the value size limit of the backend and be read and appended to in parts.
+  Blob,
+}
+
+/// How string values are normalized before they are encoded into keys. Values that collate to
+/// the same string share a key: with `case_insensitive`, `"Foo"` and `"foo"` are the same primary
+/// key. The stored field values are left as written.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct Collation {
+  /// Compare strings after lower-casing them.
+  pub case_insensitive: bool,
+
+  /// Compare strings after Unicode NFC normalization.
+  pub nfc: bool,
+}
+
+impl Collation {
+  /// Parses the arguments of `@collate`: one or more of `"nocase"` and `"nfc"`.
+  pub fn from_args(args: &[&str]) -> Result<Self, SchemaCompileError> {
+    let mut collation = Collation::default();
+    for arg in args {
+      match *arg {
+        "nocase" => collation.case_insensitive = true,
+        "nfc" => collation.nfc = true,
+        _ => return Err(SchemaCompileError::UnknownCollation(arg.to_string())),
+      }
+    }
+    Ok(collation)
+  }
+
+  pub fn apply(&self, s: &str) -> String {
+    let s = if self.case_insensitive {
+      s.to_lowercase()
+    } else {
+      s.to_string()
+    };
+    if self.nfc {
+      s.nfc().collect()
+    } else {
+      s
+    }
+  }
+}
+
+impl Display for Collation {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let mut args = vec![];
+    if self.case_insensitive {
+      args.push("\"nocase\"");
+    }
+    if self.nfc {
+      args.push("\"nfc\"");
+    }
+    write!(f, "@collate({})", args.join(", "))
+  }
 }
 
 pub trait FieldAnnotationList {
   fn is_primary(&self) -> bool;
   fn is_unique(&self) -> bool;
   fn is_index(&self) -> bool;
+  fn is_sort_key(&self) -> bool;
+  fn is_auto(&self) -> bool;
+  fn is_timestamp(&self) -> bool;
+  fn is_deleted_at(&self) -> bool;
+  fn is_blob(&self) -> bool;
+  fn collation(&self) -> Option<Collation>;
 }
 
 impl FieldAnnotationList for &[FieldAnnotation] {
@@ -182,6 +385,33 @@ impl FieldAnnotationList for &[FieldAnnotation] {
   fn is_index(&self) -> bool {
     self.iter().find(|x| x.is_index()).is_some()
   }
+
+  fn is_sort_key(&self) -> bool {
+    self.iter().find(|x| x.is_sort_key()).is_some()
+  }
+
+  fn is_auto(&self) -> bool {
+    self.iter().find(|x| x.is_auto()).is_some()
+  }
+
+  fn is_timestamp(&self) -> bool {
+    self.iter().find(|x| x.is_timestamp()).is_some()
+  }
+
+  fn is_deleted_at(&self) -> bool {
+    self.iter().find(|x| x.is_deleted_at()).is_some()
+  }
+
+  fn is_blob(&self) -> bool {
+    self.iter().find(|x| x.is_blob()).is_some()
+  }
+
+  fn collation(&self) -> Option<Collation> {
+    self.iter().find_map(|x| match x {
+      FieldAnnotation::Collate(x) => Some(*x),
+      _ => None,
+    })
+  }
 }
 
 impl FieldAnnotation {
@@ -203,6 +433,36 @@ impl FieldAnnotation {
       _ => false,
     }
   }
+  pub fn is_sort_key(&self) -> bool {
+    match self {
+      FieldAnnotation::SortKey => true,
+      _ => false,
+    }
+  }
+  pub fn is_auto(&self) -> bool {
+    match self {
+      FieldAnnotation::Auto => true,
+      _ => false,
+    }
+  }
+  pub fn is_timestamp(&self) -> bool {
+    match self {
+      FieldAnnotation::CreatedAt | FieldAnnotation::UpdatedAt => true,
+      _ => false,
+    }
+  }
+  pub fn is_deleted_at(&self) -> bool {
+    match self {
+      FieldAnnotation::DeletedAt => true,
+      _ => false,
+    }
+  }
+  pub fn is_blob(&self) -> bool {
+    match self {
+      FieldAnnotation::Blob => true,
+      _ => false,
+    }
+  }
 }
 
 impl Display for FieldAnnotation {
@@ -211,7 +471,14 @@ impl Display for FieldAnnotation {
       Self::PrimaryKey => write!(f, "@primary"),
       Self::Unique => write!(f, "@unique"),
       Self::Index => write!(f, "@index"),
+      Self::SortKey => write!(f, "@sort_key"),
       Self::RenameFrom(x) => write!(f, "@rename_from({})", serde_json::to_string(x).unwrap()),
+      Self::Auto => write!(f, "@auto"),
+      Self::CreatedAt => write!(f, "@created_at"),
+      Self::UpdatedAt => write!(f, "@updated_at"),
+      Self::DeletedAt => write!(f, "@deleted_at"),
+      Self::Collate(x) => write!(f, "{}", x),
+      Self::Blob => write!(f, "@blob"),
     }
   }
 }
@@ -221,6 +488,15 @@ pub enum FieldType {
   Table(Arc<str>),
   Primitive(PrimitiveType),
   Set(Box<FieldType>),
+
+  /// An inline struct, stored packed under a single key. Exposed as a map in the VM.
+  Struct(BTreeMap<Arc<str>, FieldType>),
+
+  /// An ordered list of primitives or structs, keyed by element index.
+  List(Box<FieldType>),
+
+  /// A map from strings to values of the inner type.
+  Map(Box<FieldType>),
 }
 
 impl Display for FieldType {
@@ -229,20 +505,43 @@ impl Display for FieldType {
       Self::Table(x) => write!(f, "{}", x),
       Self::Primitive(x) => write!(f, "{}", x),
       Self::Set(x) => write!(f, "set<{}>", x),
+      Self::List(x) => write!(f, "list<{}>", x),
+      Self::Map(x) => write!(f, "map<string, {}>", x),
+      Self::Struct(x) => write!(
+        f,
+        "{{{}}}",
+        x.iter()
+          .map(|(k, v)| format!("{}: {}", k, v))
+          .collect::<Vec<_>>()
+          .join(", ")
+      ),
     }
   }
 }
 
 impl Display for SpecializedType {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    if self.has_timestamps() {
+      write!(f, "@timestamps\n")?;
+    }
+    if self.has_soft_delete() {
+      write!(f, "@soft_delete\n")?;
+    }
     write!(f, "type {} {{\n", self.name)?;
     for (k, (ty, annotations)) in &self.fields {
+      // Generated by type annotations.
+      if annotations.as_slice().is_timestamp() || annotations.as_slice().is_deleted_at() {
+        continue;
+      }
       write!(f, "  ")?;
       for x in annotations {
         write!(f, "{} ", x)?;
       }
       write!(f, "{}: {},\n", k, ty)?;
     }
+    for x in &self.checks {
+      write!(f, "  check {},\n", x)?;
+    }
     write!(f, "}}\n")?;
     Ok(())
   }
@@ -251,6 +550,9 @@ impl Display for SpecializedType {
 struct TypeResolutionContext<'a> {
   unresolved: HashMap<&'a str, &'a ast::TypeItem<'a>>,
   resolved: BTreeMap<Arc<str>, SpecializedType>,
+
+  /// Member types of sets, with the location of the field or export that declares the set.
+  set_members: Vec<(Arc<str>, usize)>,
 }
 
 impl<'a> TypeResolutionContext<'a> {
@@ -260,12 +562,16 @@ impl<'a> TypeResolutionContext<'a> {
       match item {
         ast::SchemaItem::Type(x) => {
           if types.contains_key(x.name.0) {
-            return Err(SchemaCompileError::DuplicateType(x.name.0.to_string()).into());
+            return Err(LocatedError::wrap(
+              x.location,
+              SchemaCompileError::DuplicateType(x.name.0.to_string()).into(),
+            ));
           }
           if !x.name.0.starts_with(|x| x >= 'A' && x <= 'Z') {
-            return Err(
+            return Err(LocatedError::wrap(
+              x.location,
               SchemaCompileError::TypeNameMustStartWithUpperCaseLetter(x.name.0.to_string()).into(),
-            );
+            ));
           }
           types.insert(x.name.0, x);
         }
@@ -275,9 +581,18 @@ impl<'a> TypeResolutionContext<'a> {
     Ok(Self {
       unresolved: types,
       resolved: BTreeMap::new(),
+      set_members: vec![],
     })
   }
 
+  fn record_set_member(&mut self, ty: &FieldType, location: usize) {
+    if let FieldType::Set(x) = ty {
+      if let FieldType::Table(x) = &**x {
+        self.set_members.push((x.clone(), location));
+      }
+    }
+  }
+
   fn resolve_type_expr(
     &mut self,
     local_context: &HashMap<&'a str, &FieldType>,
@@ -286,6 +601,7 @@ impl<'a> TypeResolutionContext<'a> {
     let (id, args) = match e {
       TypeExpr::Unit(x) => (x, &[] as _),
       TypeExpr::Specialize(x, args) => (x, args.as_slice()),
+      TypeExpr::Struct(fields) => return self.resolve_struct(local_context, fields),
     };
 
     let args = args
@@ -309,7 +625,7 @@ impl<'a> TypeResolutionContext<'a> {
       return Ok(FieldType::Primitive(*ty));
     }
 
-    // The only special case, `set`...
+    // Special cases: `set`...
     if id.0 == "set" {
       if args.len() != 1 {
         return Err(SchemaCompileError::BadSetTypeParameter.into());
@@ -321,6 +637,31 @@ impl<'a> TypeResolutionContext<'a> {
       }
     }
 
+    // ... and `list`.
+    if id.0 == "list" {
+      if args.len() != 1 {
+        return Err(SchemaCompileError::BadListTypeParameter.into());
+      }
+      match &args[0] {
+        FieldType::Primitive(_) | FieldType::Struct(_) => {
+          return Ok(FieldType::List(Box::new(args[0].clone())))
+        }
+        _ => return Err(SchemaCompileError::BadListTypeParameter.into()),
+      }
+    }
+
+    // ... and `map`.
+    if id.0 == "map" {
+      match args.as_slice() {
+        [FieldType::Primitive(PrimitiveType::String), value @ FieldType::Primitive(_)]
+        | [FieldType::Primitive(PrimitiveType::String), value @ FieldType::Struct(_)]
+        | [FieldType::Primitive(PrimitiveType::String), value @ FieldType::Table(_)] => {
+          return Ok(FieldType::Map(Box::new(value.clone())))
+        }
+        _ => return Err(SchemaCompileError::BadMapTypeParameter.into()),
+      }
+    }
+
     let ty = self
       .unresolved
       .get(id.0)
@@ -361,6 +702,7 @@ impl<'a> TypeResolutionContext<'a> {
       SpecializedType {
         name: repr.clone(),
         fields: BTreeMap::new(),
+        checks: vec![],
       },
     );
 
@@ -372,15 +714,19 @@ impl<'a> TypeResolutionContext<'a> {
     let mut fields: BTreeMap<Arc<str>, (FieldType, Vec<FieldAnnotation>)> = BTreeMap::new();
     for x in &ty.fields {
       if fields.contains_key(x.name.0) {
-        return Err(
+        return Err(LocatedError::wrap(
+          x.location,
           SchemaCompileError::DuplicateField {
             field: x.name.0.to_string(),
             ty: ty.name.0.to_string(),
           }
           .into(),
-        );
+        ));
       }
-      let field_ty = self.resolve_type_expr(&local_context, &x.value)?;
+      let field_ty = self
+        .resolve_type_expr(&local_context, &x.value)
+        .map_err(|e| LocatedError::wrap(x.location, e))?;
+      self.record_set_member(&field_ty, x.location);
 
       let mut annotations = vec![];
       for ann in &x.annotations {
@@ -394,45 +740,151 @@ impl<'a> TypeResolutionContext<'a> {
           ("index", []) => {
             annotations.push(FieldAnnotation::Index);
           }
+          ("sort_key", []) => {
+            annotations.push(FieldAnnotation::SortKey);
+          }
           ("rename_from", [Literal::String(x)]) => {
             annotations.push(FieldAnnotation::RenameFrom(x.to_string()));
           }
+          ("auto", []) => {
+            annotations.push(FieldAnnotation::Auto);
+          }
+          ("blob", []) => {
+            annotations.push(FieldAnnotation::Blob);
+          }
+          ("collate", args)
+            if !args.is_empty() && args.iter().all(|x| matches!(x, Literal::String(_))) =>
+          {
+            let args = args
+              .iter()
+              .filter_map(|x| match x {
+                Literal::String(x) => Some(*x),
+                _ => None,
+              })
+              .collect::<Vec<_>>();
+            let collation =
+              Collation::from_args(&args).map_err(|e| LocatedError::wrap(x.location, e.into()))?;
+            annotations.push(FieldAnnotation::Collate(collation));
+          }
           _ => {
-            return Err(
+            return Err(LocatedError::wrap(
+              x.location,
               SchemaCompileError::UnknownAnnotationOnField(
                 x.name.0.to_string(),
                 repr.to_string(),
                 ann.name.0.to_string(),
               )
               .into(),
-            )
+            ))
           }
         }
       }
 
       // Validate constraints.
-      // Rule 1: Currently, a primary/unique/non-unique index is only allowed on primitive fields.
+      // Rule 1: Currently, a primary/unique/non-unique index or a sort key is only allowed on
+      // primitive fields.
       if annotations
         .iter()
-        .find(|x| x.is_primary() || x.is_unique() || x.is_index())
+        .find(|x| x.is_primary() || x.is_unique() || x.is_index() || x.is_sort_key())
         .is_some()
       {
         match field_ty {
           FieldType::Primitive(_) => {}
           _ => {
-            return Err(
+            return Err(LocatedError::wrap(
+              x.location,
               SchemaCompileError::IndexOnNonPrimitiveField(
                 x.name.0.to_string(),
                 ty.name.0.to_string(),
               )
               .into(),
-            );
+            ));
+          }
+        }
+      }
+
+      // Rule 2: Collations apply to strings.
+      if annotations.as_slice().collation().is_some() {
+        match field_ty {
+          FieldType::Primitive(PrimitiveType::String) => {}
+          _ => {
+            return Err(LocatedError::wrap(
+              x.location,
+              SchemaCompileError::CollationOnNonStringField(
+                x.name.0.to_string(),
+                ty.name.0.to_string(),
+              )
+              .into(),
+            ));
+          }
+        }
+      }
+
+      // Rule 3: Generated values are int64 primary keys.
+      if annotations.as_slice().is_auto() {
+        match field_ty {
+          FieldType::Primitive(PrimitiveType::Int64) if annotations.as_slice().is_primary() => {}
+          _ => {
+            return Err(LocatedError::wrap(
+              x.location,
+              SchemaCompileError::BadAutoField(x.name.0.to_string(), ty.name.0.to_string()).into(),
+            ));
+          }
+        }
+      }
+
+      // Rule 4: Blobs are bytes fields that are never encoded into keys.
+      if annotations.as_slice().is_blob() {
+        let is_key = annotations
+          .iter()
+          .any(|x| x.is_primary() || x.is_unique() || x.is_index() || x.is_sort_key());
+        match field_ty {
+          FieldType::Primitive(PrimitiveType::Bytes) if !is_key => {}
+          _ => {
+            return Err(LocatedError::wrap(
+              x.location,
+              SchemaCompileError::BadBlobField(x.name.0.to_string(), ty.name.0.to_string()).into(),
+            ));
           }
         }
       }
       fields.insert(Arc::from(x.name.0), (field_ty, annotations));
     }
 
+    // Type annotations.
+    for ann in &ty.annotations {
+      let generated = match (ann.name.0, ann.args.as_slice()) {
+        ("timestamps", []) => vec![
+          (CREATED_AT_FIELD, FieldAnnotation::CreatedAt),
+          (UPDATED_AT_FIELD, FieldAnnotation::UpdatedAt),
+        ],
+        ("soft_delete", []) => vec![(DELETED_AT_FIELD, FieldAnnotation::DeletedAt)],
+        _ => {
+          return Err(LocatedError::wrap(
+            ty.location,
+            SchemaCompileError::UnknownAnnotationOnType(repr.to_string(), ann.name.0.to_string())
+              .into(),
+          ))
+        }
+      };
+      for (name, annotation) in generated {
+        if fields.contains_key(name) {
+          return Err(LocatedError::wrap(
+            ty.location,
+            SchemaCompileError::DuplicateField {
+              field: name.to_string(),
+              ty: ty.name.0.to_string(),
+            }
+            .into(),
+          ));
+        }
+        fields.insert(
+          Arc::from(name),
+          (FieldType::Primitive(PrimitiveType::Int64), vec![annotation]),
+        );
+      }
+    }
+
     // Validation: At most one primary key
     {
       let mut primary_key_count = 0usize;
@@ -442,12 +894,69 @@ impl<'a> TypeResolutionContext<'a> {
         }
       }
       if primary_key_count > 1 {
-        return Err(SchemaCompileError::MultiplePrimaryKeys(ty.name.0.to_string()).into());
+        return Err(LocatedError::wrap(
+          ty.location,
+          SchemaCompileError::MultiplePrimaryKeys(ty.name.0.to_string()).into(),
+        ));
       }
     }
 
-    self.resolved.get_mut(&repr).unwrap().fields = fields;
+    // Validation: At most one sort key
+    if fields
+      .values()
+      .filter(|(_, annotations)| annotations.as_slice().is_sort_key())
+      .count()
+      > 1
+    {
+      return Err(LocatedError::wrap(
+        ty.location,
+        SchemaCompileError::MultipleSortKeys(ty.name.0.to_string()).into(),
+      ));
+    }
+
+    let checks = ty
+      .checks
+      .iter()
+      .map(|x| {
+        CheckPredicate::compile(ty.name.0, &fields, &x.expr)
+          .map_err(|e| LocatedError::wrap(x.location, e))
+      })
+      .collect::<Result<Vec<_>>>()?;
+
+    let resolved = self.resolved.get_mut(&repr).unwrap();
+    resolved.fields = fields;
+    resolved.checks = checks;
 
     Ok(FieldType::Table(repr))
   }
+  /// Inline structs are stored packed under one key, so their members must be stored that way
+  /// too: only primitives and other structs are allowed.
+  fn resolve_struct(
+    &mut self,
+    local_context: &HashMap<&'a str, &FieldType>,
+    fields: &[ast::StructField<'a>],
+  ) -> Result<FieldType> {
+    let mut members = BTreeMap::new();
+    for x in fields {
+      let ty = self.resolve_type_expr(local_context, &x.value)?;
+      match ty {
+        FieldType::Primitive(_) | FieldType::Struct(_) => {}
+        _ => {
+          return Err(
+            SchemaCompileError::BadStructMember(x.name.0.to_string(), ty.to_string()).into(),
+          )
+        }
+      }
+      if members.insert(Arc::from(x.name.0), ty).is_some() {
+        return Err(
+          SchemaCompileError::DuplicateField {
+            field: x.name.0.to_string(),
+            ty: "struct".into(),
+          }
+          .into(),
+        );
+      }
+    }
+    Ok(FieldType::Struct(members))
+  }
 }
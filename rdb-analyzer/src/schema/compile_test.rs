@@ -1,6 +1,6 @@
 use bumpalo::Bump;
 
-use super::{compile::compile, grammar::parse};
+use super::{compile::compile, diagnostic::SchemaDiagnostic, grammar::parse};
 
 #[test]
 fn test_compile_simple() {
@@ -11,6 +11,7 @@ fn test_compile_simple() {
     r#"
     type Item<T> {
       inner: T,
+      @primary
       something_else: string,
     }
     type Duration<T> {
@@ -139,3 +140,415 @@ fn at_most_one_primary_key() {
     .to_string()
     .contains("has multiple primary keys"));
 }
+
+#[test]
+fn inline_structs() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+    type Item {
+      @primary
+      id: string,
+      position: { x: double, y: double, meta: { label: string } },
+    }
+    export set<Item> items;
+  "#,
+  )
+  .unwrap();
+  let output = compile(&ast).unwrap();
+  println!("{}", output);
+
+  let ast = parse(
+    &alloc,
+    r#"
+    type Item {
+      inner: { other: Item },
+    }
+    export Item item;
+  "#,
+  )
+  .unwrap();
+  assert!(compile(&ast)
+    .unwrap_err()
+    .to_string()
+    .contains("must be a primitive or struct type"));
+
+  let ast = parse(
+    &alloc,
+    r#"
+    type Item {
+      inner: { x: int64, x: string },
+    }
+    export Item item;
+  "#,
+  )
+  .unwrap();
+  assert!(compile(&ast)
+    .unwrap_err()
+    .to_string()
+    .contains("duplicate field `x`"));
+}
+
+#[test]
+fn lists() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+    type Item {
+      tags: list<string>,
+      points: list<{ x: double, y: double }>,
+    }
+    export Item item;
+  "#,
+  )
+  .unwrap();
+  let output = compile(&ast).unwrap();
+  println!("{}", output);
+
+  let ast = parse(
+    &alloc,
+    r#"
+    type Item {
+      children: list<Item>,
+    }
+    export Item item;
+  "#,
+  )
+  .unwrap();
+  assert!(compile(&ast)
+    .unwrap_err()
+    .to_string()
+    .contains("lists must have exactly one primitive or struct type parameter"));
+}
+
+#[test]
+fn maps() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+    type Item {
+      counts: map<string, int64>,
+      children: map<string, Item>,
+    }
+    export Item item;
+  "#,
+  )
+  .unwrap();
+  let output = compile(&ast).unwrap();
+  println!("{}", output);
+
+  for ty in [
+    "map<int64, string>",
+    "map<string, set<Item>>",
+    "map<string>",
+  ] {
+    let src = format!(
+      "type Item {{ @primary id: string, value: {}, }} export Item item;",
+      ty
+    );
+    let ast = parse(&alloc, &src).unwrap();
+    assert!(compile(&ast)
+      .unwrap_err()
+      .to_string()
+      .contains("maps must have a string key type"));
+  }
+}
+
+#[test]
+fn sort_keys() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+    type Event {
+      @primary id: string,
+      @sort_key at: int64,
+    }
+    export set<Event> events;
+  "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  assert_eq!(
+    schema.types.get("Event<>").unwrap().sort_key().unwrap().0,
+    "at"
+  );
+
+  for (fields, error) in [
+    (
+      "@sort_key a: int64, @sort_key b: int64,",
+      "has multiple sort keys",
+    ),
+    (
+      "@sort_key a: set<Event>,",
+      "indexes are only allowed on primitive fields",
+    ),
+  ] {
+    let src = format!(
+      "type Event {{ @primary id: string, {} }} export set<Event> events;",
+      fields
+    );
+    let ast = parse(&alloc, &src).unwrap();
+    assert!(compile(&ast).unwrap_err().to_string().contains(error));
+  }
+}
+
+#[test]
+fn auto_primary_keys() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+    type Event {
+      @primary @auto id: int64,
+      name: string,
+    }
+    export set<Event> events;
+  "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  assert_eq!(
+    schema.types.get("Event<>").unwrap().auto_primary_key(),
+    Some("id")
+  );
+
+  for fields in [
+    "@primary @auto id: string,",
+    "@primary id: int64, @auto x: int64,",
+  ] {
+    let src = format!("type Event {{ {} }} export set<Event> events;", fields);
+    let ast = parse(&alloc, &src).unwrap();
+    assert!(compile(&ast)
+      .unwrap_err()
+      .to_string()
+      .contains("`@auto` is only allowed on int64 primary keys"));
+  }
+}
+
+#[test]
+fn timestamps() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+    @timestamps
+    type Event {
+      @primary id: string,
+    }
+    export set<Event> events;
+  "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  let ty = schema.types.get("Event<>").unwrap();
+  assert!(ty.has_timestamps());
+  assert!(ty.fields.contains_key("created_at"));
+  assert!(ty.fields.contains_key("updated_at"));
+  assert!(schema
+    .to_string()
+    .starts_with("@timestamps\ntype Event<> {\n  @primary id: string,\n}"));
+
+  for (src, error) in [
+    (
+      "@timestamps type Event { @primary id: string, created_at: int64 } export set<Event> events;",
+      "duplicate field `created_at`",
+    ),
+    (
+      "@unknown type Event { @primary id: string } export set<Event> events;",
+      "unknown annotation on type `Event<>`: `unknown`",
+    ),
+  ] {
+    let ast = parse(&alloc, src).unwrap();
+    assert!(compile(&ast).unwrap_err().to_string().contains(error));
+  }
+}
+
+#[test]
+fn soft_delete() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+    @soft_delete
+    @timestamps
+    type Event {
+      @primary id: string,
+    }
+    export set<Event> events;
+  "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  let ty = schema.types.get("Event<>").unwrap();
+  assert!(ty.has_soft_delete());
+  assert!(ty.has_timestamps());
+  assert!(ty.fields.contains_key("deleted_at"));
+  assert!(schema
+    .to_string()
+    .starts_with("@timestamps\n@soft_delete\ntype Event<> {\n  @primary id: string,\n}"));
+
+  let ast = parse(
+    &alloc,
+    "@soft_delete type Event { @primary id: string, deleted_at: int64 } export set<Event> events;",
+  )
+  .unwrap();
+  assert!(compile(&ast)
+    .unwrap_err()
+    .to_string()
+    .contains("duplicate field `deleted_at`"));
+}
+
+#[test]
+fn collation() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+    type User {
+      @primary @collate("nfc", "nocase") name: string,
+    }
+    export set<User> users;
+  "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  let ty = schema.types.get("User<>").unwrap();
+  let collation = ty.collation("name").unwrap();
+  assert!(collation.case_insensitive && collation.nfc);
+  assert_eq!(collation.apply("E\u{301}COLE"), "\u{e9}cole");
+  assert!(schema
+    .to_string()
+    .contains(r#"@collate("nocase", "nfc") name: string"#));
+
+  for (source, error) in [
+    (
+      r#"type User { @primary @collate("nocase") id: int64 } export set<User> users;"#,
+      "`@collate` is only allowed on string fields",
+    ),
+    (
+      r#"type User { @primary @collate("fr_FR") id: string } export set<User> users;"#,
+      "unknown collation: `fr_FR`",
+    ),
+  ] {
+    let ast = parse(&alloc, source).unwrap();
+    assert!(compile(&ast).unwrap_err().to_string().contains(error));
+  }
+}
+
+#[test]
+fn blob() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+    type File {
+      @primary name: string,
+      @blob content: bytes,
+    }
+    export set<File> files;
+  "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  assert!(schema.to_string().contains("@blob content: bytes"));
+
+  for source in [
+    r#"type File { @primary name: string, @blob content: string } export set<File> files;"#,
+    r#"type File { @primary @blob name: bytes } export set<File> files;"#,
+    r#"type File { @primary name: string, @index @blob content: bytes } export set<File> files;"#,
+  ] {
+    let ast = parse(&alloc, source).unwrap();
+    assert!(compile(&ast)
+      .unwrap_err()
+      .to_string()
+      .contains("`@blob` is only allowed on bytes fields that are not keys"));
+  }
+}
+
+#[test]
+fn checks() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+    type Duration {
+      start: int64,
+      end: int64,
+      scale: double,
+      check end >= start,
+      check !(scale < 0) && (scale != 3 || start == 0),
+    }
+    export Duration duration;
+  "#,
+  )
+  .unwrap();
+  let output = compile(&ast).unwrap();
+  println!("{}", output);
+  let ty = output.types.get("Duration<>").unwrap();
+  assert_eq!(ty.checks.len(), 2);
+  assert_eq!(ty.checks[0].to_string(), "end >= start");
+
+  for (check, error) in [
+    ("check missing > 0", "refers to unknown field `missing`"),
+    ("check inner > 0", "refers to non-primitive field `inner`"),
+    ("check start > \"x\"", "compares values of different types"),
+  ] {
+    let src = format!(
+      "type Inner {{ x: int64 }} type Duration {{ start: int64, inner: Inner, {} }} export Duration d;",
+      check
+    );
+    let ast = parse(&alloc, &src).unwrap();
+    assert!(compile(&ast).unwrap_err().to_string().contains(error));
+  }
+}
+
+#[test]
+fn diagnostics() {
+  let _ = pretty_env_logger::try_init();
+  let diagnose = |source: &str| {
+    let alloc = Bump::new();
+    let e = match parse(&alloc, source).and_then(|x| compile(&x)) {
+      Ok(_) => panic!("diagnostics: did not get expected error"),
+      Err(e) => e,
+    };
+    let x = SchemaDiagnostic::from_error(source, &e).unwrap();
+    println!("{}", x);
+    x
+  };
+
+  let x = diagnose("type A {\n  a: int64,\n  b: B,\n}\nexport A a;\n");
+  assert!(x.message.contains("missing type: B"));
+  assert_eq!((x.line, x.column), (3, 3));
+  assert_eq!(x.snippet, "  b: B,");
+
+  let x = diagnose("type A {\n  a: int64,\n  a: string,\n}\nexport A a;\n");
+  assert!(x.message.contains("duplicate field `a`"));
+  assert_eq!((x.line, x.column), (3, 3));
+
+  let x = diagnose("type A {\n  a: int64,\n}\ntype B {\n  items: set<A>,\n}\nexport B b;\n");
+  assert!(x
+    .message
+    .contains("set member type `A<>` has no primary key"));
+  assert_eq!((x.line, x.column), (5, 3));
+  assert_eq!(
+    x.to_string(),
+    "5:3: set member type `A<>` has no primary key\n  items: set<A>,\n  ^"
+  );
+
+  let x = diagnose("type A {\n  a: int64\n  b: int64,\n}\n");
+  assert_eq!(x.line, 3);
+}
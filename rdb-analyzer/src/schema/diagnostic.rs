@@ -0,0 +1,95 @@
+use std::fmt::Display;
+
+use lalrpop_util::ParseError;
+use serde::Serialize;
+use thiserror::Error;
+
+use super::grammar::error::SchemaError;
+
+/// A compile error attributed to a byte offset in the schema source.
+#[derive(Error, Debug)]
+#[error("{error}")]
+pub struct LocatedError {
+  pub location: usize,
+  pub error: anyhow::Error,
+}
+
+impl LocatedError {
+  /// Attributes an error to `location`, unless it is already attributed to a more specific
+  /// location.
+  pub fn wrap(location: usize, error: anyhow::Error) -> anyhow::Error {
+    if error.is::<Self>() {
+      error
+    } else {
+      Self { location, error }.into()
+    }
+  }
+}
+
+/// A schema error with its position in the source, for reporting to users.
+#[derive(Clone, Debug, Serialize)]
+pub struct SchemaDiagnostic {
+  pub message: String,
+
+  /// One-based line number.
+  pub line: usize,
+
+  /// One-based column number, in characters.
+  pub column: usize,
+
+  /// The source line containing the error.
+  pub snippet: String,
+}
+
+impl SchemaDiagnostic {
+  /// Builds a diagnostic for an error returned by `parse` or `compile` on `source`. Returns `None`
+  /// if the error is not attributed to a location.
+  pub fn from_error(source: &str, error: &anyhow::Error) -> Option<Self> {
+    let location = if let Some(x) = error.downcast_ref::<LocatedError>() {
+      x.location
+    } else if let Some(x) = error.downcast_ref::<ParseError<usize, String, SchemaError>>() {
+      match x {
+        ParseError::InvalidToken { location } | ParseError::UnrecognizedEOF { location, .. } => {
+          *location
+        }
+        ParseError::UnrecognizedToken { token, .. } | ParseError::ExtraToken { token } => token.0,
+        ParseError::User { .. } => return None,
+      }
+    } else {
+      return None;
+    };
+    Some(Self::at(source, location, error.to_string()))
+  }
+
+  fn at(source: &str, location: usize, message: String) -> Self {
+    let mut location = location.min(source.len());
+    while !source.is_char_boundary(location) {
+      location -= 1;
+    }
+    let line_start = source[..location].rfind('\n').map(|x| x + 1).unwrap_or(0);
+    let line_end = source[location..]
+      .find('\n')
+      .map(|x| location + x)
+      .unwrap_or(source.len());
+    Self {
+      message,
+      line: source[..line_start].matches('\n').count() + 1,
+      column: source[line_start..location].chars().count() + 1,
+      snippet: source[line_start..line_end].trim_end().to_string(),
+    }
+  }
+}
+
+impl Display for SchemaDiagnostic {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "{}:{}: {}\n{}\n{}^",
+      self.line,
+      self.column,
+      self.message,
+      self.snippet,
+      " ".repeat(self.column - 1)
+    )
+  }
+}
@@ -0,0 +1,279 @@
+use std::fmt::Write;
+
+use serde::Serialize;
+
+use crate::storage_plan::{StorageNode, StoragePlan};
+
+use super::compile::{CompiledSchema, FieldAnnotationList, SpecializedType};
+
+/// Documentation of a schema and its storage layout, for data catalogs. Serializes to JSON, and
+/// renders to Markdown and HTML.
+#[derive(Clone, Debug, Serialize)]
+pub struct SchemaDoc {
+  pub types: Vec<TypeDoc>,
+  pub exports: Vec<ExportDoc>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TypeDoc {
+  pub name: String,
+
+  /// Annotations on the type itself, e.g. `@timestamps`.
+  pub annotations: Vec<String>,
+  pub fields: Vec<FieldDoc>,
+
+  /// Check constraints, as written in the schema.
+  pub checks: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct FieldDoc {
+  pub name: String,
+  pub ty: String,
+  pub annotations: Vec<String>,
+
+  /// Whether the field is maintained by the executor because of an annotation on the type.
+  pub generated: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ExportDoc {
+  pub name: String,
+  pub ty: String,
+
+  /// Storage keys of the export and of everything nested in it. Empty if the storage plan does
+  /// not have the export.
+  pub keys: Vec<KeyDoc>,
+}
+
+/// A node of the storage plan.
+#[derive(Clone, Debug, Serialize)]
+pub struct KeyDoc {
+  /// Path of the node, in the format of `storage_plan::diff_display`: field names separated by
+  /// dots, and `<set_member>` for the members of sets, lists and maps.
+  pub path: String,
+
+  /// The storage key, hex-encoded.
+  pub key: String,
+
+  /// The key of the node this node refers to, for recursive types.
+  pub subspace_reference: Option<String>,
+}
+
+impl SchemaDoc {
+  pub fn generate(schema: &CompiledSchema, plan: &StoragePlan) -> Self {
+    Self {
+      types: schema.types.values().map(document_type).collect(),
+      exports: schema
+        .exports
+        .iter()
+        .map(|(name, ty)| {
+          let mut keys = vec![];
+          if let Some(node) = plan.nodes.get(name) {
+            collect_keys(name, node, &mut keys);
+          }
+          ExportDoc {
+            name: name.to_string(),
+            ty: ty.to_string(),
+            keys,
+          }
+        })
+        .collect(),
+    }
+  }
+
+  pub fn to_markdown(&self) -> String {
+    let mut out = String::new();
+    writeln!(out, "# Schema").unwrap();
+
+    writeln!(out, "\n## Exports").unwrap();
+    for x in &self.exports {
+      writeln!(out, "\n### `{}`: `{}`\n", x.name, x.ty).unwrap();
+      if x.keys.is_empty() {
+        writeln!(out, "Not in the storage plan.").unwrap();
+        continue;
+      }
+      writeln!(out, "| Path | Storage key |\n| --- | --- |").unwrap();
+      for k in &x.keys {
+        writeln!(
+          out,
+          "| `{}` | `{}` |",
+          markdown_cell(&k.path),
+          markdown_cell(&display_key(k))
+        )
+        .unwrap();
+      }
+    }
+
+    writeln!(out, "\n## Types").unwrap();
+    for x in &self.types {
+      writeln!(out, "\n### `{}`\n", x.name).unwrap();
+      if !x.annotations.is_empty() {
+        writeln!(out, "Annotations: `{}`\n", x.annotations.join(" ")).unwrap();
+      }
+      writeln!(out, "| Field | Type | Annotations |\n| --- | --- | --- |").unwrap();
+      for f in &x.fields {
+        writeln!(
+          out,
+          "| `{}`{} | `{}` | {} |",
+          markdown_cell(&f.name),
+          if f.generated { " (generated)" } else { "" },
+          markdown_cell(&f.ty),
+          f.annotations
+            .iter()
+            .map(|x| format!("`{}`", markdown_cell(x)))
+            .collect::<Vec<_>>()
+            .join(" ")
+        )
+        .unwrap();
+      }
+      if !x.checks.is_empty() {
+        writeln!(out, "\nChecks:\n").unwrap();
+        for c in &x.checks {
+          writeln!(out, "- `{}`", c).unwrap();
+        }
+      }
+    }
+    out
+  }
+
+  /// Renders an HTML fragment, to be embedded into a page.
+  pub fn to_html(&self) -> String {
+    let mut out = String::new();
+    writeln!(out, "<h1>Schema</h1>").unwrap();
+
+    writeln!(out, "<h2>Exports</h2>").unwrap();
+    for x in &self.exports {
+      writeln!(
+        out,
+        "<h3><code>{}</code>: <code>{}</code></h3>",
+        escape_html(&x.name),
+        escape_html(&x.ty)
+      )
+      .unwrap();
+      if x.keys.is_empty() {
+        writeln!(out, "<p>Not in the storage plan.</p>").unwrap();
+        continue;
+      }
+      writeln!(out, "<table>\n<tr><th>Path</th><th>Storage key</th></tr>").unwrap();
+      for k in &x.keys {
+        writeln!(
+          out,
+          "<tr><td><code>{}</code></td><td><code>{}</code></td></tr>",
+          escape_html(&k.path),
+          escape_html(&display_key(k))
+        )
+        .unwrap();
+      }
+      writeln!(out, "</table>").unwrap();
+    }
+
+    writeln!(out, "<h2>Types</h2>").unwrap();
+    for x in &self.types {
+      writeln!(out, "<h3><code>{}</code></h3>", escape_html(&x.name)).unwrap();
+      if !x.annotations.is_empty() {
+        writeln!(
+          out,
+          "<p>Annotations: <code>{}</code></p>",
+          escape_html(&x.annotations.join(" "))
+        )
+        .unwrap();
+      }
+      writeln!(
+        out,
+        "<table>\n<tr><th>Field</th><th>Type</th><th>Annotations</th></tr>"
+      )
+      .unwrap();
+      for f in &x.fields {
+        writeln!(
+          out,
+          "<tr><td><code>{}</code>{}</td><td><code>{}</code></td><td>{}</td></tr>",
+          escape_html(&f.name),
+          if f.generated { " (generated)" } else { "" },
+          escape_html(&f.ty),
+          f.annotations
+            .iter()
+            .map(|x| format!("<code>{}</code>", escape_html(x)))
+            .collect::<Vec<_>>()
+            .join(" ")
+        )
+        .unwrap();
+      }
+      writeln!(out, "</table>").unwrap();
+      if !x.checks.is_empty() {
+        writeln!(out, "<p>Checks:</p>\n<ul>").unwrap();
+        for c in &x.checks {
+          writeln!(out, "<li><code>{}</code></li>", escape_html(c)).unwrap();
+        }
+        writeln!(out, "</ul>").unwrap();
+      }
+    }
+    out
+  }
+}
+
+fn document_type(ty: &SpecializedType) -> TypeDoc {
+  let mut annotations = vec![];
+  if ty.has_timestamps() {
+    annotations.push("@timestamps".to_string());
+  }
+  if ty.has_soft_delete() {
+    annotations.push("@soft_delete".to_string());
+  }
+  TypeDoc {
+    name: ty.name.to_string(),
+    annotations,
+    fields: ty
+      .fields
+      .iter()
+      .map(|(name, (field_ty, field_annotations))| FieldDoc {
+        name: name.to_string(),
+        ty: field_ty.to_string(),
+        annotations: field_annotations.iter().map(|x| x.to_string()).collect(),
+        generated: field_annotations.as_slice().is_timestamp()
+          || field_annotations.as_slice().is_deleted_at(),
+      })
+      .collect(),
+    checks: ty.checks.iter().map(|x| x.to_string()).collect(),
+  }
+}
+
+fn collect_keys(path: &str, node: &StorageNode, out: &mut Vec<KeyDoc>) {
+  out.push(KeyDoc {
+    path: path.to_string(),
+    key: hex::encode(&node.key),
+    subspace_reference: node.subspace_reference.map(|x| hex::encode(&x)),
+  });
+  if let Some(x) = &node.set {
+    collect_keys(&format!("{}.<set_member>", path), x, out);
+  }
+  for (name, child) in &node.children {
+    collect_keys(&format!("{}.{}", path, name), child, out);
+  }
+}
+
+fn display_key(k: &KeyDoc) -> String {
+  match &k.subspace_reference {
+    Some(x) => format!("{} -> {}", k.key, x),
+    None => k.key.clone(),
+  }
+}
+
+fn markdown_cell(x: &str) -> String {
+  x.replace('|', "\\|")
+}
+
+fn escape_html(x: &str) -> String {
+  let mut out = String::with_capacity(x.len());
+  for c in x.chars() {
+    match c {
+      '&' => out.push_str("&amp;"),
+      '<' => out.push_str("&lt;"),
+      '>' => out.push_str("&gt;"),
+      '"' => out.push_str("&quot;"),
+      '\'' => out.push_str("&#39;"),
+      _ => out.push(c),
+    }
+  }
+  out
+}
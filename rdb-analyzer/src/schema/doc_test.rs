@@ -0,0 +1,75 @@
+use bumpalo::Bump;
+
+use crate::storage_plan::planner::generate_plan_for_schema;
+
+use super::{compile::compile, doc::SchemaDoc, grammar::parse};
+
+#[test]
+fn documents_types_exports_and_keys() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+    @timestamps
+    type Item {
+      @primary
+      id: string,
+      @index
+      score: int64,
+      check score >= 0,
+    }
+    type Node {
+      next: Node,
+      value: int64,
+    }
+    export set<Item> items;
+    export Node head;
+  "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let doc = SchemaDoc::generate(&schema, &plan);
+
+  let item = doc.types.iter().find(|x| x.name == "Item<>").unwrap();
+  assert_eq!(item.annotations, vec!["@timestamps"]);
+  assert_eq!(item.checks, vec!["score >= 0"]);
+  let score = item.fields.iter().find(|x| x.name == "score").unwrap();
+  assert_eq!(score.ty, "int64");
+  assert_eq!(score.annotations, vec!["@index"]);
+  assert!(!score.generated);
+  assert!(item.fields.iter().any(|x| x.generated));
+
+  let items = doc.exports.iter().find(|x| x.name == "items").unwrap();
+  assert_eq!(items.ty, "set<Item<>>");
+  let paths = items
+    .keys
+    .iter()
+    .map(|x| x.path.as_str())
+    .collect::<Vec<_>>();
+  assert_eq!(paths[0], "items");
+  assert!(paths.contains(&"items.<set_member>.score"));
+  let items_node = &plan.nodes["items"];
+  assert_eq!(items.keys[0].key, hex::encode(&items_node.key));
+
+  // Recursive types refer to the subspace of their ancestor.
+  let head = doc.exports.iter().find(|x| x.name == "head").unwrap();
+  let next = head.keys.iter().find(|x| x.path == "head.next").unwrap();
+  assert_eq!(
+    next.subspace_reference.as_deref(),
+    Some(head.keys[0].key.as_str())
+  );
+
+  let markdown = doc.to_markdown();
+  assert!(markdown.contains("### `items`: `set<Item<>>`"));
+  assert!(markdown.contains(&format!("| `items` | `{}` |", items.keys[0].key)));
+  assert!(markdown.contains("- `score >= 0`"));
+
+  let html = doc.to_html();
+  assert!(html.contains("<code>set&lt;Item&lt;&gt;&gt;</code>"));
+  assert!(!html.contains("set<Item<>>"));
+
+  let json = serde_json::to_value(&doc).unwrap();
+  assert_eq!(json["exports"][0]["keys"][0]["path"], "head");
+}
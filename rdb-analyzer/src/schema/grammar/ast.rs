@@ -1,9 +1,16 @@
 use bumpalo::collections::vec::Vec;
 
+use crate::schema::check::CheckOp;
+
 pub struct Schema<'a> {
   pub items: Vec<'a, SchemaItem<'a>>,
 }
 
+pub enum TypeMember<'a> {
+  Field(TypeField<'a>),
+  Check(Check<'a>),
+}
+
 pub enum SchemaItem<'a> {
   Type(&'a TypeItem<'a>),
   Export(&'a ExportItem<'a>),
@@ -15,6 +22,7 @@ pub struct TypeItem<'a> {
   pub name: Identifier<'a>,
   pub generics: Vec<'a, Identifier<'a>>,
   pub fields: Vec<'a, TypeField<'a>>,
+  pub checks: Vec<'a, Check<'a>>,
 }
 
 pub struct ExportItem<'a> {
@@ -30,9 +38,33 @@ pub struct TypeField<'a> {
   pub value: TypeExpr<'a>,
 }
 
+/// A `check` clause of a type: a constraint on the fields of each written value.
+pub struct Check<'a> {
+  pub location: usize,
+  pub expr: CheckExpr<'a>,
+}
+
+pub enum CheckExpr<'a> {
+  Compare(CheckOp, CheckOperand<'a>, CheckOperand<'a>),
+  And(&'a CheckExpr<'a>, &'a CheckExpr<'a>),
+  Or(&'a CheckExpr<'a>, &'a CheckExpr<'a>),
+  Not(&'a CheckExpr<'a>),
+}
+
+pub enum CheckOperand<'a> {
+  Field(Identifier<'a>),
+  Literal(Literal<'a>),
+}
+
 pub enum TypeExpr<'a> {
   Unit(Identifier<'a>),
   Specialize(Identifier<'a>, Vec<'a, TypeExpr<'a>>),
+  Struct(Vec<'a, StructField<'a>>),
+}
+
+pub struct StructField<'a> {
+  pub name: Identifier<'a>,
+  pub value: TypeExpr<'a>,
 }
 
 pub struct Annotation<'a> {
@@ -1,5 +1,11 @@
+pub mod check;
 pub mod compile;
+pub mod diagnostic;
+pub mod doc;
 pub mod grammar;
 
 #[cfg(test)]
 mod compile_test;
+
+#[cfg(test)]
+mod doc_test;
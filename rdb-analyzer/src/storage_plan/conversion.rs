@@ -28,6 +28,12 @@ impl From<&StorageNode<StorageKey>> for StorageNode<String> {
       key: base64::encode(&that.key),
       flattened: that.flattened,
       subspace_reference: that.subspace_reference.map(|x| base64::encode(&x)),
+      rename_fallback: that.rename_fallback.map(|x| base64::encode(&x)),
+      conversion: that.conversion,
+      key_collation: that.key_collation,
+      sort_key_collation: that.sort_key_collation,
+      blob: that.blob,
+      sort_index_backfill: that.sort_index_backfill,
       set: that.set.as_ref().map(|x| Box::new(Self::from(&**x))),
       children: that
         .children
@@ -75,6 +81,22 @@ impl TryFrom<&StorageNode<String>> for StorageNode<StorageKey> {
             .map_err(|_| StorageKeyConversionError::Base64Decode)
         })
         .transpose()?,
+      rename_fallback: that
+        .rename_fallback
+        .as_ref()
+        .map(|x| base64::decode(&x))
+        .transpose()
+        .map_err(|_| StorageKeyConversionError::Base64Decode)?
+        .map(|x| {
+          x.try_into()
+            .map_err(|_| StorageKeyConversionError::Base64Decode)
+        })
+        .transpose()?,
+      conversion: that.conversion,
+      key_collation: that.key_collation,
+      sort_key_collation: that.sort_key_collation,
+      blob: that.blob,
+      sort_index_backfill: that.sort_index_backfill,
       set: that
         .set
         .as_ref()
@@ -1,6 +1,13 @@
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use std::{collections::BTreeMap, fmt::Display, io::Write, sync::Arc};
+use std::{
+  collections::{BTreeMap, BTreeSet},
+  fmt::Display,
+  io::Write,
+  sync::Arc,
+};
+
+use crate::{data::convert::PrimitiveConversion, schema::compile::Collation};
 
 pub mod conversion;
 pub mod planner;
@@ -10,7 +17,7 @@ mod planner_test;
 
 pub type StorageKey = [u8; 12];
 
-#[derive(Default, Clone, Serialize, Deserialize)]
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
 pub struct StoragePlan<SK = StorageKey> {
   pub nodes: BTreeMap<Arc<str>, StorageNode<SK>>,
 }
@@ -20,6 +27,38 @@ pub struct StorageNode<SK = StorageKey> {
   pub key: SK,
   pub flattened: bool,
   pub subspace_reference: Option<SK>,
+
+  /// The storage key of a field this field was renamed from, if the old key could not be reused.
+  /// Reads of this field fall back to the old key when nothing is stored under `key`.
+  #[serde(default)]
+  pub rename_fallback: Option<SK>,
+
+  /// A conversion of the stored values of this leaf node that is pending, after the type of the
+  /// field changed. See `data::convert::convert_in_place`.
+  #[serde(default)]
+  pub conversion: Option<PrimitiveConversion>,
+
+  /// The collations of the primary key and the sort key of the members of a set, applied to
+  /// values before they are encoded into keys of the set.
+  #[serde(default)]
+  pub key_collation: Option<Collation>,
+  #[serde(default)]
+  pub sort_key_collation: Option<Collation>,
+
+  /// Whether this leaf node stores a `@blob` field: its key holds the length of the value, and
+  /// the value itself is split into chunks below it. See `data::blob`.
+  #[serde(default)]
+  pub blob: bool,
+
+  /// Set if the sort key index of the members of this set may be incomplete, because the
+  /// `@sort_key` of the member type was added or changed while the set could already have
+  /// members. Scans by sort key fail on such a set until its index is backfilled. The id is
+  /// random and identifies the backfill, so that the progress of an earlier one is never reused.
+  /// See `data::backfill`.
+  #[serde(default)]
+  pub sort_index_backfill: Option<u64>,
+
+  /// The element node of a set, list or map.
   pub set: Option<Box<StorageNode<SK>>>,
   pub children: BTreeMap<Arc<str>, StorageNode<SK>>,
 }
@@ -36,6 +75,60 @@ impl StoragePlan {
   }
 }
 
+impl<SK> StoragePlan<SK> {
+  /// Clears the pending conversions of all nodes, once the values have been converted.
+  pub fn clear_conversions(&mut self) {
+    for node in self.nodes.values_mut() {
+      node.clear_conversions();
+    }
+  }
+
+  /// Returns whether any node has a pending sort key index backfill.
+  pub fn has_sort_index_backfills(&self) -> bool {
+    self.nodes.values().any(|x| x.has_sort_index_backfills())
+  }
+
+  /// Clears the pending sort key index backfills of all nodes, once the indexes of all sets have
+  /// been backfilled.
+  pub fn clear_sort_index_backfills(&mut self) {
+    for node in self.nodes.values_mut() {
+      node.clear_sort_index_backfills();
+    }
+  }
+}
+
+impl<SK> StorageNode<SK> {
+  fn clear_conversions(&mut self) {
+    self.conversion = None;
+    if let Some(x) = &mut self.set {
+      x.clear_conversions();
+    }
+    for child in self.children.values_mut() {
+      child.clear_conversions();
+    }
+  }
+
+  fn has_sort_index_backfills(&self) -> bool {
+    self.sort_index_backfill.is_some()
+      || self
+        .set
+        .as_ref()
+        .map(|x| x.has_sort_index_backfills())
+        .unwrap_or(false)
+      || self.children.values().any(|x| x.has_sort_index_backfills())
+  }
+
+  fn clear_sort_index_backfills(&mut self) {
+    self.sort_index_backfill = None;
+    if let Some(x) = &mut self.set {
+      x.clear_sort_index_backfills();
+    }
+    for child in self.children.values_mut() {
+      child.clear_sort_index_backfills();
+    }
+  }
+}
+
 impl Display for StoragePlan {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     for (node_name, node) in &self.nodes {
@@ -46,17 +139,73 @@ impl Display for StoragePlan {
 }
 
 impl StorageNode {
+  /// Displays the key and flags of this node, without its children.
+  fn display_flat(&self) -> String {
+    let mut out = hex::encode(&self.key);
+    if self.flattened {
+      out.push_str(" flattened");
+    }
+    if let Some(x) = self.subspace_reference {
+      out.push_str(&format!(" subspace_reference({})", hex::encode(&x)));
+    }
+    if let Some(x) = self.rename_fallback {
+      out.push_str(&format!(" rename_fallback({})", hex::encode(&x)));
+    }
+    if let Some(x) = self.conversion {
+      out.push_str(&format!(" conversion({:?})", x));
+    }
+    if let Some(x) = self.key_collation {
+      out.push_str(&format!(" key_collation({})", x));
+    }
+    if let Some(x) = self.sort_key_collation {
+      out.push_str(&format!(" sort_key_collation({})", x));
+    }
+    if self.blob {
+      out.push_str(" blob");
+    }
+    if let Some(x) = self.sort_index_backfill {
+      out.push_str(&format!(" sort_index_backfill({:016x})", x));
+    }
+    out
+  }
+
   fn display_fmt(&self, indent: usize, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     write!(
       f,
-      " {}{}{}",
+      " {}{}{}{}{}{}{}{}{}",
       hex::encode(&self.key.as_ref()),
       if let Some(x) = self.subspace_reference {
         format!(" subspace_reference({})", base64::encode(&x))
       } else {
         "".into()
       },
+      if let Some(x) = self.rename_fallback {
+        format!(" rename_fallback({})", hex::encode(&x))
+      } else {
+        "".into()
+      },
+      if let Some(x) = self.conversion {
+        format!(" conversion({:?})", x)
+      } else {
+        "".into()
+      },
+      if let Some(x) = self.key_collation {
+        format!(" key_collation({})", x)
+      } else {
+        "".into()
+      },
+      if let Some(x) = self.sort_key_collation {
+        format!(" sort_key_collation({})", x)
+      } else {
+        "".into()
+      },
       if self.flattened { " flattened" } else { "" },
+      if self.blob { " blob" } else { "" },
+      if let Some(x) = self.sort_index_backfill {
+        format!(" sort_index_backfill({:016x})", x)
+      } else {
+        "".into()
+      },
     )?;
     write!(f, "\n")?;
 
@@ -87,3 +236,121 @@ impl Display for StorageNode {
     self.display_fmt(0, f)
   }
 }
+
+/// Renders the physical layout changes from `old` to `new`, one line per added (`+`), removed
+/// (`-`) or changed (`~`) node. Nodes are identified by their path and visited in sorted order,
+/// so the output is stable across runs and suitable for review.
+pub fn diff_display(old: &StoragePlan, new: &StoragePlan) -> String {
+  let mut out = String::new();
+  let names: BTreeSet<&Arc<str>> = old.nodes.keys().chain(new.nodes.keys()).collect();
+  for name in names {
+    diff_node(name, old.nodes.get(name), new.nodes.get(name), &mut out);
+  }
+  out
+}
+
+fn diff_node(path: &str, old: Option<&StorageNode>, new: Option<&StorageNode>, out: &mut String) {
+  match (old, new) {
+    (Some(old), Some(new)) => {
+      let mut changes = vec![];
+      if old.key != new.key {
+        changes.push(format!(
+          "key {} -> {}",
+          hex::encode(&old.key),
+          hex::encode(&new.key)
+        ));
+      }
+      if old.flattened != new.flattened {
+        changes.push(format!("flattened {} -> {}", old.flattened, new.flattened));
+      }
+      if old.subspace_reference != new.subspace_reference {
+        changes.push(format!(
+          "subspace_reference {} -> {}",
+          display_optional_key(old.subspace_reference),
+          display_optional_key(new.subspace_reference)
+        ));
+      }
+      if old.rename_fallback != new.rename_fallback {
+        changes.push(format!(
+          "rename_fallback {} -> {}",
+          display_optional_key(old.rename_fallback),
+          display_optional_key(new.rename_fallback)
+        ));
+      }
+      if old.conversion != new.conversion {
+        changes.push(format!(
+          "conversion {} -> {}",
+          old
+            .conversion
+            .map(|x| format!("{:?}", x))
+            .unwrap_or_else(|| "none".into()),
+          new
+            .conversion
+            .map(|x| format!("{:?}", x))
+            .unwrap_or_else(|| "none".into())
+        ));
+      }
+      if old.key_collation != new.key_collation {
+        changes.push(format!(
+          "key_collation {} -> {}",
+          display_optional_collation(old.key_collation),
+          display_optional_collation(new.key_collation)
+        ));
+      }
+      if old.sort_key_collation != new.sort_key_collation {
+        changes.push(format!(
+          "sort_key_collation {} -> {}",
+          display_optional_collation(old.sort_key_collation),
+          display_optional_collation(new.sort_key_collation)
+        ));
+      }
+      if old.blob != new.blob {
+        changes.push(format!("blob {} -> {}", old.blob, new.blob));
+      }
+      if old.sort_index_backfill != new.sort_index_backfill {
+        changes.push(format!(
+          "sort_index_backfill {:?} -> {:?}",
+          old.sort_index_backfill, new.sort_index_backfill
+        ));
+      }
+      if !changes.is_empty() {
+        out.push_str(&format!("~ {} {}\n", path, changes.join(", ")));
+      }
+    }
+    (Some(old), None) => out.push_str(&format!("- {} {}\n", path, old.display_flat())),
+    (None, Some(new)) => out.push_str(&format!("+ {} {}\n", path, new.display_flat())),
+    (None, None) => return,
+  }
+
+  diff_node(
+    &format!("{}.<set_member>", path),
+    old.and_then(|x| x.set.as_deref()),
+    new.and_then(|x| x.set.as_deref()),
+    out,
+  );
+
+  let empty = BTreeMap::new();
+  let old_children = old.map(|x| &x.children).unwrap_or(&empty);
+  let new_children = new.map(|x| &x.children).unwrap_or(&empty);
+  let names: BTreeSet<&Arc<str>> = old_children.keys().chain(new_children.keys()).collect();
+  for name in names {
+    diff_node(
+      &format!("{}.{}", path, name),
+      old_children.get(name),
+      new_children.get(name),
+      out,
+    );
+  }
+}
+
+fn display_optional_key(key: Option<StorageKey>) -> String {
+  key
+    .map(|x| hex::encode(&x))
+    .unwrap_or_else(|| "none".into())
+}
+
+fn display_optional_collation(collation: Option<Collation>) -> String {
+  collation
+    .map(|x| x.to_string())
+    .unwrap_or_else(|| "none".into())
+}
@@ -8,7 +8,10 @@ use anyhow::Result;
 use byteorder::{BigEndian, ByteOrder};
 use rand::RngCore;
 
-use crate::schema::compile::{CompiledSchema, FieldAnnotation, FieldAnnotationList, FieldType};
+use crate::{
+  data::convert::PrimitiveConversion,
+  schema::compile::{CompiledSchema, FieldAnnotation, FieldAnnotationList, FieldType},
+};
 
 use super::{StorageKey, StorageNode, StoragePlan};
 use thiserror::Error;
@@ -22,12 +25,25 @@ pub enum PlannerError {
   SetMemberTypeWithoutPrimaryKey(Arc<str>),
 }
 
+/// Options for storage plan generation.
+#[derive(Default, Clone, Debug)]
+pub struct PlannerOptions {
+  /// Keep the storage keys of primitive fields whose type changed to a type their values can be
+  /// converted to, instead of dropping their data. The stored values must then be converted with
+  /// `data::convert::convert_in_place`.
+  pub convert_primitives: bool,
+}
+
 struct PlanState<'a> {
   old_schema: &'a CompiledSchema,
+  convert_primitives: bool,
   used_storage_keys: HashSet<StorageKey>,
   recursive_types: HashSet<Arc<str>>,
   set_member_types: HashSet<Arc<str>>,
   fields_in_stack: HashMap<Arc<str>, StorageKey>,
+
+  /// Number of list element nodes the current node is nested in.
+  list_depth: usize,
 }
 
 /// A point on the old tree.
@@ -41,9 +57,9 @@ struct OldTreePoint<'a> {
 
 impl<'a> OldTreePoint<'a> {
   fn reduce_set(mut self) -> Option<Self> {
-    if let FieldType::Set(x) = self.ty {
+    if let FieldType::Set(x) | FieldType::List(x) | FieldType::Map(x) = self.ty {
       log::trace!(
-        "set `{}` of type `{}` reduced to `{}`.",
+        "collection `{}` of type `{}` reduced to `{}`.",
         self.name,
         self.ty,
         x
@@ -55,13 +71,13 @@ impl<'a> OldTreePoint<'a> {
           Some(self)
         }
         None => {
-          log::error!("inconsistency detected: a storage node for the `set` type does not have an element node. dropping field. node: {:?}", self.node);
+          log::error!("inconsistency detected: a storage node for the `set`, `list` or `map` type does not have an element node. dropping field. node: {:?}", self.node);
           None
         }
       }
     } else {
       log::warn!(
-        "field `{}` becomes a set - previous value will not be preserved",
+        "field `{}` becomes a collection - previous value will not be preserved",
         self.name
       );
       None
@@ -70,13 +86,23 @@ impl<'a> OldTreePoint<'a> {
 
   fn validate_type(
     self,
+    plan_st: &PlanState,
     expected_ty: &FieldType,
     _expected_annotations: &[FieldAnnotation],
   ) -> Option<Self> {
-    if self.ty != expected_ty {
+    if !is_compatible_type(self.ty, expected_ty, plan_st.convert_primitives) {
       return None;
     }
 
+    if self.ty != expected_ty {
+      log::trace!(
+        "type `{}` of `{}` changed to `{}` with the same generic type - preserving unchanged subfields.",
+        self.ty,
+        self.name,
+        expected_ty
+      );
+    }
+
     Some(self)
   }
 
@@ -145,6 +171,15 @@ pub fn generate_plan_for_schema(
   old_plan: &StoragePlan,
   old_schema: &CompiledSchema,
   schema: &CompiledSchema,
+) -> Result<StoragePlan> {
+  generate_plan_for_schema_with_options(old_plan, old_schema, schema, &Default::default())
+}
+
+pub fn generate_plan_for_schema_with_options(
+  old_plan: &StoragePlan,
+  old_schema: &CompiledSchema,
+  schema: &CompiledSchema,
+  options: &PlannerOptions,
 ) -> Result<StoragePlan> {
   // Collect recursive types
   let mut recursive_types: HashSet<Arc<str>> = HashSet::new();
@@ -169,10 +204,12 @@ pub fn generate_plan_for_schema(
 
   let mut plan_st = PlanState {
     old_schema,
+    convert_primitives: options.convert_primitives,
     used_storage_keys: HashSet::new(),
     recursive_types,
     fields_in_stack: HashMap::new(),
     set_member_types,
+    list_depth: 0,
   };
 
   // Deduplicate also against storage keys used in the previous plan.
@@ -204,7 +241,7 @@ pub fn generate_plan_for_schema(
         _annotations: &[],
         node,
       })
-      .and_then(|x| x.validate_type(export_field, &[]));
+      .and_then(|x| x.validate_type(&plan_st, export_field, &[]));
 
     let node = generate_field(&mut plan_st, schema, export_field, &[], old_point)?;
     plan.nodes.insert(export_name.clone(), node);
@@ -232,6 +269,12 @@ fn generate_field(
             .unwrap_or_else(|| rand_storage_key(plan_st)),
           flattened: false,
           subspace_reference: Some(key),
+          rename_fallback: None,
+          conversion: None,
+          key_collation: None,
+          sort_key_collation: None,
+          blob: false,
+          sort_index_backfill: None,
           set: None,
           children: BTreeMap::new(),
         });
@@ -275,7 +318,7 @@ fn generate_field(
 
         let subfield_old_point = old_point
           .and_then(|x| x.resolve_subfield(plan_st, &altnames))
-          .and_then(|x| x.validate_type(&subfield.1 .0, &subfield.1 .1));
+          .and_then(|x| x.validate_type(plan_st, &subfield.1 .0, &subfield.1 .1));
         match generate_field(
           plan_st,
           schema,
@@ -283,7 +326,17 @@ fn generate_field(
           &subfield.1 .1,
           subfield_old_point,
         ) {
-          Ok(x) => {
+          Ok(mut x) => {
+            let is_leaf = matches!(
+              &subfield.1 .0,
+              FieldType::Primitive(_) | FieldType::Struct(_)
+            );
+            if is_leaf && !x.blob {
+              // Keep track of data written under an old name that we cannot take over.
+              x.rename_fallback =
+                find_rename_fallback(plan_st, old_point, &altnames[1..], &subfield.1 .0, x.key)
+                  .or_else(|| subfield_old_point.and_then(|x| x.node.rename_fallback));
+            }
             children.insert(subfield.0.clone(), x);
           }
           Err(e) => {
@@ -305,24 +358,88 @@ fn generate_field(
         key: storage_key,
         flattened: true,
         subspace_reference: None,
+        rename_fallback: None,
+        conversion: None,
+        key_collation: None,
+        sort_key_collation: None,
+        blob: false,
+        sort_index_backfill: None,
         set: None,
         children,
       })
     }
-    FieldType::Primitive(_) => {
-      // This is a primitive type (leaf node).
+    FieldType::Primitive(_) | FieldType::Struct(_) => {
+      // This is a primitive type or a packed struct (leaf node).
+
+      // Blobs are not stored like other values, so a field that becomes or stops being a blob
+      // is planned as a new one.
+      let blob = annotations.is_blob();
+      let old_point = old_point.filter(|x| x.node.blob == blob);
+      let conversion = old_point.and_then(|x| {
+        if x.ty == field {
+          x.node.conversion
+        } else {
+          conversion_between(x.ty, field)
+        }
+      });
       Ok(StorageNode {
-        key: old_point
-          .map(|x| x.node.key)
+        key: reserved_storage_key(annotations)
+          .or_else(|| old_point.map(|x| x.node.key))
           .unwrap_or_else(|| rand_storage_key(plan_st)),
         flattened: false,
         subspace_reference: None,
+        rename_fallback: None,
+        conversion,
+        key_collation: None,
+        sort_key_collation: None,
+        blob,
+        sort_index_backfill: None,
         set: None,
         children: BTreeMap::new(),
       })
     }
-    FieldType::Set(x) => {
-      // This is a set with dynamic node key.
+    FieldType::Set(x) | FieldType::List(x) | FieldType::Map(x) => {
+      // The collations of set members are part of the key encoding. Existing keys are not
+      // re-encoded, so a set whose collations change is planned as a new one.
+      let (key_collation, sort_key_collation) = match (field, &**x) {
+        (FieldType::Set(_), FieldType::Table(name)) => match schema.types.get(name) {
+          Some(ty) => (
+            ty.fields
+              .iter()
+              .find(|(_, (_, annotations))| annotations.as_slice().is_primary())
+              .and_then(|(name, _)| ty.collation(name)),
+            ty.sort_key().and_then(|(name, _)| ty.collation(name)),
+          ),
+          None => (None, None),
+        },
+        _ => (None, None),
+      };
+      let old_point = old_point.filter(|x| {
+        x.node.key_collation == key_collation && x.node.sort_key_collation == sort_key_collation
+      });
+
+      // The executor only maintains the sort key index of a set whose member type has a sort
+      // key, so the index of an existing set must be backfilled when its sort key changes. A
+      // pending backfill is kept as long as the sort key stays the same. The backfill does not
+      // reach sets nested in list elements, so these are never marked.
+      let sort_index_backfill = match (field, &**x, old_point) {
+        (FieldType::Set(_), FieldType::Table(name), Some(old_point)) if plan_st.list_depth == 0 => {
+          match schema.types.get(name).and_then(|ty| ty.sort_key()) {
+            Some((sort_key, _)) if old_sort_key(plan_st, old_point.ty) != Some(sort_key) => {
+              Some(rand::thread_rng().next_u64())
+            }
+            Some(_) => old_point.node.sort_index_backfill,
+            None => None,
+          }
+        }
+        _ => None,
+      };
+
+      // This is a collection with dynamic node key.
+      let is_list = matches!(field, FieldType::List(_));
+      if is_list {
+        plan_st.list_depth += 1;
+      }
       let inner = generate_field(
         plan_st,
         schema,
@@ -330,14 +447,24 @@ fn generate_field(
         &[],
         old_point
           .and_then(|x| x.reduce_set())
-          .and_then(|y| y.validate_type(x, annotations)),
-      )?;
+          .and_then(|y| y.validate_type(plan_st, x, annotations)),
+      );
+      if is_list {
+        plan_st.list_depth -= 1;
+      }
+      let inner = inner?;
       Ok(StorageNode {
         key: old_point
           .map(|x| x.node.key)
           .unwrap_or_else(|| rand_storage_key(plan_st)),
         flattened: false,
         subspace_reference: None,
+        rename_fallback: None,
+        conversion: None,
+        key_collation,
+        sort_key_collation,
+        blob: false,
+        sort_index_backfill,
         set: Some(Box::new(inner)),
         children: BTreeMap::new(),
       })
@@ -345,6 +472,80 @@ fn generate_field(
   }
 }
 
+/// Finds the storage key of a field in the old tree that a leaf field was renamed from, if that
+/// key is not the one the leaf field is stored under now.
+fn find_rename_fallback<'a>(
+  plan_st: &PlanState<'a>,
+  old_point: Option<OldTreePoint<'a>>,
+  old_names: &[&str],
+  field: &FieldType,
+  key: StorageKey,
+) -> Option<StorageKey> {
+  let old_point = old_point?;
+  old_names.iter().find_map(|name| {
+    old_point
+      .resolve_subfield(plan_st, &[*name])
+      .filter(|x| x.ty == field && !x.node.blob)
+      .map(|x| x.node.key)
+      .filter(|x| *x != key)
+  })
+}
+
+/// The sort key of the members of a set of the old schema.
+fn old_sort_key<'a>(plan_st: &PlanState<'a>, set_ty: &FieldType) -> Option<&'a str> {
+  match set_ty {
+    FieldType::Set(x) => match &**x {
+      FieldType::Table(name) => plan_st.old_schema.types.get(name)?.sort_key().map(|x| x.0),
+      _ => None,
+    },
+    _ => None,
+  }
+}
+
+/// Returns whether data stored for the old type can be (partially) reused for the new type.
+///
+/// Different instantiations of the same generic type are compatible - their subfields are then
+/// validated one by one, so that only the subfields whose concrete types changed are dropped.
+/// With `convert_primitives`, primitive types are compatible if there is a conversion between
+/// them.
+fn is_compatible_type(old: &FieldType, new: &FieldType, convert_primitives: bool) -> bool {
+  match (old, new) {
+    (FieldType::Table(old), FieldType::Table(new)) => generic_name(old) == generic_name(new),
+    (FieldType::Set(old), FieldType::Set(new))
+    | (FieldType::List(old), FieldType::List(new))
+    | (FieldType::Map(old), FieldType::Map(new)) => {
+      is_compatible_type(old, new, convert_primitives)
+    }
+    _ => old == new || (convert_primitives && conversion_between(old, new).is_some()),
+  }
+}
+
+fn conversion_between(old: &FieldType, new: &FieldType) -> Option<PrimitiveConversion> {
+  match (old, new) {
+    (FieldType::Primitive(old), FieldType::Primitive(new)) => {
+      PrimitiveConversion::lookup(*old, *new)
+    }
+    _ => None,
+  }
+}
+
+/// Strips the type arguments from the name of a specialized type, e.g. `Duration<int64>` becomes
+/// `Duration`.
+fn generic_name(repr: &str) -> &str {
+  repr.split('<').next().unwrap()
+}
+
+/// Returns the fixed storage key of a field generated by a type annotation. Random storage keys
+/// start with two zero bytes, so reserved keys never collide with them.
+fn reserved_storage_key(annotations: &[FieldAnnotation]) -> Option<StorageKey> {
+  annotations.iter().find_map(|x| match x {
+    FieldAnnotation::CreatedAt => Some(*b"\xff\xffcreated_at"),
+    FieldAnnotation::UpdatedAt => Some(*b"\xff\xffupdated_at"),
+    FieldAnnotation::DeletedAt => Some(*b"\xff\xffdeleted_at"),
+    _ => None,
+  })
+}
+
 fn rand_storage_key(st: &mut PlanState) -> StorageKey {
   loop {
     let now = SystemTime::now()
@@ -369,6 +570,9 @@ fn rand_storage_key(st: &mut PlanState) -> StorageKey {
 
 fn collect_storage_keys(node: &StorageNode, sink: &mut HashSet<StorageKey>) {
   sink.insert(node.key);
+  if let Some(x) = node.rename_fallback {
+    sink.insert(x);
+  }
   if let Some(x) = &node.set {
     collect_storage_keys(x, sink);
   }
@@ -385,9 +589,11 @@ fn collect_special_types(
   set_member_types_sink: &mut HashSet<Arc<str>>,
 ) -> Result<()> {
   match ty {
-    FieldType::Set(x) => {
-      if let FieldType::Table(x) = &**x {
-        set_member_types_sink.insert(x.clone());
+    FieldType::Set(x) | FieldType::Map(x) => {
+      if let FieldType::Set(_) = ty {
+        if let FieldType::Table(x) = &**x {
+          set_member_types_sink.insert(x.clone());
+        }
       }
       collect_special_types(
         x,
@@ -397,7 +603,7 @@ fn collect_special_types(
         set_member_types_sink,
       )
     }
-    FieldType::Primitive(_) => Ok(()),
+    FieldType::Primitive(_) | FieldType::Struct(_) | FieldType::List(_) => Ok(()),
     FieldType::Table(table_name) => {
       // if a cycle is detected...
       if state.insert(table_name.clone()) == false {
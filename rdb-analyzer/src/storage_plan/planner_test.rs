@@ -5,8 +5,14 @@ use console::Style;
 use similar::{ChangeTag, TextDiff};
 
 use crate::{
+  data::{
+    kv::KeyValueStore,
+    mock_kv::MockKv,
+    pathwalker::{get_with_rename_fallback, PathWalker},
+    value::PrimitiveValue,
+  },
   schema::{compile::compile, grammar::parse},
-  storage_plan::StoragePlan,
+  storage_plan::{diff_display, StoragePlan},
 };
 
 use super::planner::generate_plan_for_schema;
@@ -255,8 +261,7 @@ fn test_set_member_without_primary_key() {
   "#,
   )
   .unwrap();
-  let output = compile(&ast).unwrap();
-  match generate_plan_for_schema(&Default::default(), &Default::default(), &output) {
+  match compile(&ast) {
     Ok(_) => panic!("test_set_member_without_primary_key: did not get expected error"),
     Err(e) => assert!(e.to_string().contains("has no primary key")),
   }
@@ -452,3 +457,269 @@ fn primitive_exports() {
   let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &output).unwrap();
   println!("{}", plan);
 }
+
+#[tokio::test]
+async fn rename_fallback() {
+  let _ = pretty_env_logger::try_init();
+  let old = r#"
+  type Item {
+    a: int64,
+    b: int64,
+  }
+  export Item data;
+  "#;
+  let new = r#"
+  type Item {
+    @rename_from("a")
+    b: int64,
+  }
+  export Item data;
+  "#;
+  let alloc = Bump::new();
+  let schema1 = compile(&parse(&alloc, old).unwrap()).unwrap();
+  let schema2 = compile(&parse(&alloc, new).unwrap()).unwrap();
+  let plan1 = generate_plan_for_schema(&Default::default(), &Default::default(), &schema1).unwrap();
+  let plan2 = generate_plan_for_schema(&plan1, &schema1, &schema2).unwrap();
+
+  // `b` keeps its own key, and falls back to the key of `a`.
+  let old_data = &plan1.nodes["data"];
+  let new_b = &plan2.nodes["data"].children["b"];
+  assert_eq!(new_b.key, old_data.children["b"].key);
+  assert_eq!(new_b.rename_fallback, Some(old_data.children["a"].key));
+
+  // The fallback survives later migrations.
+  let plan3 = generate_plan_for_schema(&plan2, &schema2, &schema2).unwrap();
+  assert_eq!(
+    plan3.nodes["data"].children["b"].rename_fallback,
+    new_b.rename_fallback
+  );
+
+  let kv = MockKv::new();
+  let txn = kv.begin_transaction().await.unwrap();
+  let old_a = PathWalker::from_export(&plan1, "data")
+    .unwrap()
+    .enter_field("a")
+    .unwrap();
+  txn
+    .put(
+      &old_a.generate_key(),
+      &rmp_serde::to_vec(&PrimitiveValue::Int64(42)).unwrap(),
+    )
+    .await
+    .unwrap();
+  txn.commit().await.unwrap();
+
+  let new_b = PathWalker::from_export(&plan2, "data")
+    .unwrap()
+    .enter_field("b")
+    .unwrap();
+  let txn = kv.begin_transaction().await.unwrap();
+  let read = |x: Option<Vec<u8>>| -> PrimitiveValue { rmp_serde::from_slice(&x.unwrap()).unwrap() };
+  assert_eq!(
+    read(get_with_rename_fallback(&*txn, &new_b).await.unwrap()),
+    PrimitiveValue::Int64(42)
+  );
+
+  txn
+    .put(
+      &new_b.generate_key(),
+      &rmp_serde::to_vec(&PrimitiveValue::Int64(43)).unwrap(),
+    )
+    .await
+    .unwrap();
+  txn.commit().await.unwrap();
+  let txn = kv.begin_transaction().await.unwrap();
+  assert_eq!(
+    read(get_with_rename_fallback(&*txn, &new_b).await.unwrap()),
+    PrimitiveValue::Int64(43)
+  );
+}
+
+#[test]
+fn plan_diff() {
+  let _ = pretty_env_logger::try_init();
+  let old = r#"
+  type Item {
+    @primary
+    id: string,
+    a: int64,
+    b: int64,
+  }
+  export set<Item> items;
+  export int64 count;
+  "#;
+  let new = r#"
+  type Item {
+    @primary
+    id: string,
+    @rename_from("a")
+    b: int64,
+    c: string,
+  }
+  export set<Item> items;
+  "#;
+  let alloc = Bump::new();
+  let schema1 = compile(&parse(&alloc, old).unwrap()).unwrap();
+  let schema2 = compile(&parse(&alloc, new).unwrap()).unwrap();
+  let plan1 = generate_plan_for_schema(&Default::default(), &Default::default(), &schema1).unwrap();
+  let plan2 = generate_plan_for_schema(&plan1, &schema1, &schema2).unwrap();
+
+  assert_eq!(diff_display(&plan2, &plan2), "");
+
+  let diff = diff_display(&plan1, &plan2);
+  println!("{}", diff);
+  assert_eq!(diff, diff_display(&plan1, &plan2));
+  let lines = diff
+    .lines()
+    .map(|x| x.split(' ').take(2).collect::<Vec<_>>().join(" "))
+    .collect::<Vec<_>>();
+  assert_eq!(
+    lines,
+    vec![
+      "- count",
+      "- items.<set_member>.a",
+      "~ items.<set_member>.b",
+      "+ items.<set_member>.c",
+    ]
+  );
+  assert!(diff.contains(&format!(
+    "rename_fallback none -> {}",
+    hex::encode(&plan1.nodes["items"].set.as_ref().unwrap().children["a"].key)
+  )));
+}
+
+#[test]
+fn generic_argument_change() {
+  let _ = pretty_env_logger::try_init();
+  let old = r#"
+  type Duration<T> {
+    start: T,
+    end: T,
+    label: string,
+  }
+  type Item<T> {
+    @primary
+    id: string,
+    duration: Duration<T>,
+  }
+  export set<Item<int64>> items;
+  "#;
+  let new = r#"
+  type Duration<T> {
+    start: T,
+    end: T,
+    label: string,
+  }
+  type Item<T> {
+    @primary
+    id: string,
+    duration: Duration<T>,
+  }
+  export set<Item<double>> items;
+  "#;
+  let alloc = Bump::new();
+  let schema1 = compile(&parse(&alloc, old).unwrap()).unwrap();
+  let schema2 = compile(&parse(&alloc, new).unwrap()).unwrap();
+  let plan1 = generate_plan_for_schema(&Default::default(), &Default::default(), &schema1).unwrap();
+  let plan2 = generate_plan_for_schema(&plan1, &schema1, &schema2).unwrap();
+  println!("{}", diff_display(&plan1, &plan2));
+
+  let member = |plan: &StoragePlan| plan.nodes["items"].set.as_ref().unwrap().key;
+  let field = |plan: &StoragePlan, path: &[&str]| {
+    let mut node = &**plan.nodes["items"].set.as_ref().unwrap();
+    for x in path {
+      node = &node.children[*x];
+    }
+    node.key
+  };
+
+  // Subfields with unchanged concrete types are preserved.
+  assert_eq!(member(&plan1), member(&plan2));
+  assert_eq!(field(&plan1, &["id"]), field(&plan2, &["id"]));
+  assert_eq!(field(&plan1, &["duration"]), field(&plan2, &["duration"]));
+  assert_eq!(
+    field(&plan1, &["duration", "label"]),
+    field(&plan2, &["duration", "label"])
+  );
+
+  // Subfields whose types changed get new keys.
+  assert_ne!(
+    field(&plan1, &["duration", "start"]),
+    field(&plan2, &["duration", "start"])
+  );
+  assert_ne!(
+    field(&plan1, &["duration", "end"]),
+    field(&plan2, &["duration", "end"])
+  );
+}
+
+#[test]
+fn collation_change() {
+  let old = r#"
+  type User {
+    @primary
+    name: string,
+  }
+  export set<User> users;
+  "#;
+  let new = r#"
+  type User {
+    @primary @collate("nocase")
+    name: string,
+  }
+  export set<User> users;
+  "#;
+  let alloc = Bump::new();
+  let schema1 = compile(&parse(&alloc, old).unwrap()).unwrap();
+  let schema2 = compile(&parse(&alloc, new).unwrap()).unwrap();
+  let plan1 = generate_plan_for_schema(&Default::default(), &Default::default(), &schema1).unwrap();
+  let plan2 = generate_plan_for_schema(&plan1, &schema1, &schema2).unwrap();
+  let plan3 = generate_plan_for_schema(&plan2, &schema2, &schema2).unwrap();
+  let diff = diff_display(&plan1, &plan2);
+  println!("{}", diff);
+
+  // Keys encoded with the old collation cannot be found with the new one.
+  assert!(plan2.nodes["users"].key_collation.unwrap().case_insensitive);
+  assert_ne!(plan1.nodes["users"].key, plan2.nodes["users"].key);
+  assert!(diff.contains(r#"key_collation none -> @collate("nocase")"#));
+  assert_eq!(plan2.nodes["users"].key, plan3.nodes["users"].key);
+}
+
+#[test]
+fn blob_change() {
+  let old = r#"
+  type File {
+    @primary
+    name: string,
+    content: bytes,
+  }
+  export set<File> files;
+  "#;
+  let new = r#"
+  type File {
+    @primary
+    name: string,
+    @blob
+    content: bytes,
+  }
+  export set<File> files;
+  "#;
+  let alloc = Bump::new();
+  let schema1 = compile(&parse(&alloc, old).unwrap()).unwrap();
+  let schema2 = compile(&parse(&alloc, new).unwrap()).unwrap();
+  let plan1 = generate_plan_for_schema(&Default::default(), &Default::default(), &schema1).unwrap();
+  let plan2 = generate_plan_for_schema(&plan1, &schema1, &schema2).unwrap();
+  let plan3 = generate_plan_for_schema(&plan2, &schema2, &schema2).unwrap();
+  let content =
+    |plan: &StoragePlan| plan.nodes["files"].set.as_ref().unwrap().children["content"].clone();
+  let diff = diff_display(&plan1, &plan2);
+  println!("{}", diff);
+
+  // Values stored inline cannot be read as blobs.
+  assert!(!content(&plan1).blob);
+  assert!(content(&plan2).blob);
+  assert_ne!(content(&plan1).key, content(&plan2).key);
+  assert!(diff.contains("blob false -> true"));
+  assert_eq!(content(&plan2).key, content(&plan3).key);
+  assert!(content(&plan3).blob);
+}
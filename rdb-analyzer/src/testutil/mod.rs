@@ -0,0 +1,155 @@
+pub mod snapshot;
+pub mod stress;
+
+#[cfg(test)]
+mod snapshot_test;
+
+#[cfg(test)]
+mod stress_test;
+
+use std::{cmp::Ordering, fmt::Write};
+
+use bumpalo::Bump;
+use proptest::prelude::*;
+
+use crate::{
+  data::{
+    treewalker::vm_value::{VmConst, VmType},
+    value::PrimitiveValue,
+  },
+  schema::{
+    compile::{compile, CompiledSchema, PrimitiveType},
+    grammar::parse,
+  },
+  storage_plan::{planner::generate_plan_for_schema, StoragePlan},
+};
+
+pub fn arb_primitive_type() -> impl Strategy<Value = PrimitiveType> {
+  prop_oneof![
+    Just(PrimitiveType::Int64),
+    Just(PrimitiveType::Double),
+    Just(PrimitiveType::String),
+    Just(PrimitiveType::Bytes),
+  ]
+}
+
+pub fn arb_primitive_value_of(ty: PrimitiveType) -> BoxedStrategy<PrimitiveValue> {
+  match ty {
+    PrimitiveType::Int64 => any::<i64>().prop_map(PrimitiveValue::Int64).boxed(),
+    PrimitiveType::Double => any::<f64>()
+      .prop_map(|x| PrimitiveValue::Double(x.to_bits()))
+      .boxed(),
+    PrimitiveType::String => any::<String>().prop_map(PrimitiveValue::String).boxed(),
+    PrimitiveType::Bytes => any::<Vec<u8>>().prop_map(PrimitiveValue::Bytes).boxed(),
+  }
+}
+
+pub fn arb_primitive_value() -> impl Strategy<Value = PrimitiveValue> {
+  arb_primitive_type().prop_flat_map(arb_primitive_value_of)
+}
+
+/// Two values of the same primitive type.
+pub fn arb_primitive_value_pair() -> impl Strategy<Value = (PrimitiveValue, PrimitiveValue)> {
+  arb_primitive_type().prop_flat_map(|ty| (arb_primitive_value_of(ty), arb_primitive_value_of(ty)))
+}
+
+/// Constants that can be loaded without a schema: primitives, bools and primitive nulls.
+pub fn arb_vm_const() -> impl Strategy<Value = VmConst> {
+  prop_oneof![
+    arb_primitive_value().prop_map(VmConst::Primitive),
+    any::<bool>().prop_map(VmConst::Bool),
+    arb_primitive_type().prop_map(|x| VmConst::Null(VmType::Primitive(x))),
+  ]
+}
+
+/// The natural order of two primitive values of the same type, that key components must preserve.
+/// Returns `None` for values of different types and for NaNs.
+pub fn natural_order(a: &PrimitiveValue, b: &PrimitiveValue) -> Option<Ordering> {
+  match (a, b) {
+    (PrimitiveValue::Int64(a), PrimitiveValue::Int64(b)) => Some(a.cmp(b)),
+    (PrimitiveValue::Double(a), PrimitiveValue::Double(b)) => {
+      f64::from_bits(*a).partial_cmp(&f64::from_bits(*b))
+    }
+    (PrimitiveValue::String(a), PrimitiveValue::String(b)) => Some(a.as_bytes().cmp(b.as_bytes())),
+    (PrimitiveValue::Bytes(a), PrimitiveValue::Bytes(b)) => Some(a.cmp(b)),
+    _ => None,
+  }
+}
+
+#[derive(Clone, Debug)]
+enum FieldSpec {
+  Primitive(PrimitiveType),
+  Table(usize),
+  Set(usize),
+  List(PrimitiveType),
+  Map(PrimitiveType),
+}
+
+fn arb_field_spec(num_types: usize) -> impl Strategy<Value = FieldSpec> {
+  prop_oneof![
+    3 => arb_primitive_type().prop_map(FieldSpec::Primitive),
+    1 => (0..num_types).prop_map(FieldSpec::Table),
+    1 => (0..num_types).prop_map(FieldSpec::Set),
+    1 => arb_primitive_type().prop_map(FieldSpec::List),
+    1 => arb_primitive_type().prop_map(FieldSpec::Map),
+  ]
+}
+
+/// Source of a schema with up to four types, which may reference each other recursively, and up to
+/// four exports. Every type has a string primary key `id`, so that it can be a set member.
+pub fn arb_schema_source() -> impl Strategy<Value = String> {
+  (1usize..=4)
+    .prop_flat_map(|num_types| {
+      (
+        prop::collection::vec(
+          prop::collection::vec(arb_field_spec(num_types), 0..6),
+          num_types,
+        ),
+        prop::collection::vec((any::<bool>(), 0..num_types), 1..=4),
+      )
+    })
+    .prop_map(|(types, exports)| {
+      let mut out = String::new();
+      for (i, fields) in types.iter().enumerate() {
+        writeln!(out, "type T{} {{\n  @primary\n  id: string,", i).unwrap();
+        for (j, field) in fields.iter().enumerate() {
+          let ty = match field {
+            FieldSpec::Primitive(x) => x.to_string(),
+            FieldSpec::Table(x) => format!("T{}", x),
+            FieldSpec::Set(x) => format!("set<T{}>", x),
+            FieldSpec::List(x) => format!("list<{}>", x),
+            FieldSpec::Map(x) => format!("map<string, {}>", x),
+          };
+          writeln!(out, "  f{}: {},", j, ty).unwrap();
+        }
+        writeln!(out, "}}").unwrap();
+      }
+      for (i, (is_set, ty)) in exports.iter().enumerate() {
+        if *is_set {
+          writeln!(out, "export set<T{}> e{};", ty, i).unwrap();
+        } else {
+          writeln!(out, "export T{} e{};", ty, i).unwrap();
+        }
+      }
+      out
+    })
+}
+
+/// Compiles a schema source. Panics on errors, since generated sources are always valid.
+pub fn compile_schema_source(source: &str) -> CompiledSchema {
+  let alloc = Bump::new();
+  let ast = parse(&alloc, source).unwrap();
+  compile(&ast).unwrap()
+}
+
+pub fn arb_compiled_schema() -> impl Strategy<Value = CompiledSchema> {
+  arb_schema_source().prop_map(|x| compile_schema_source(&x))
+}
+
+/// A schema with a storage plan freshly generated for it.
+pub fn arb_storage_plan() -> impl Strategy<Value = (CompiledSchema, StoragePlan)> {
+  arb_compiled_schema().prop_map(|schema| {
+    let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+    (schema, plan)
+  })
+}
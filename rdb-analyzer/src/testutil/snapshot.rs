@@ -0,0 +1,116 @@
+//! Golden-file snapshots of storage plans, typeck results and script listings.
+
+use std::{collections::HashMap, fmt::Write, path::Path};
+
+use similar::TextDiff;
+
+use crate::{
+  data::treewalker::{bytecode::TwScript, typeck::GlobalTypeInfo},
+  storage_plan::{StorageNode, StoragePlan},
+};
+
+/// The environment variable that makes `assert_snapshot` rewrite the snapshot files.
+pub const UPDATE_SNAPSHOTS_ENV: &str = "UPDATE_SNAPSHOTS";
+
+/// Renders a storage plan as YAML. Storage keys, which the planner allocates randomly, are replaced
+/// with placeholders numbered in the order they are first seen.
+pub fn plan_snapshot(plan: &StoragePlan) -> String {
+  let mut plan = StoragePlan::<String>::from(plan);
+  let mut placeholders = HashMap::new();
+  for node in plan.nodes.values_mut() {
+    replace_keys(node, &mut placeholders);
+  }
+  serde_yaml::to_string(&plan).unwrap()
+}
+
+fn replace_keys(node: &mut StorageNode<String>, placeholders: &mut HashMap<String, String>) {
+  let mut replace = |key: &mut String| {
+    let next = format!("key{}", placeholders.len());
+    *key = placeholders.entry(key.clone()).or_insert(next).clone();
+  };
+  replace(&mut node.key);
+  if let Some(x) = &mut node.subspace_reference {
+    replace(x);
+  }
+  if let Some(x) = &mut node.rename_fallback {
+    replace(x);
+  }
+  if let Some(x) = &mut node.set {
+    replace_keys(x, placeholders);
+  }
+  for child in node.children.values_mut() {
+    replace_keys(child, placeholders);
+  }
+}
+
+/// Renders the inferred types of the params, output and nodes of each graph of a script. Nodes
+/// that may evaluate to null are marked with `?`.
+pub fn type_info_snapshot(script: &TwScript, type_info: &GlobalTypeInfo) -> String {
+  let mut out = String::new();
+  for (g, info) in script.graphs.iter().zip(type_info.graphs.iter()) {
+    writeln!(out, "graph {}:", g.name).unwrap();
+    let params = info
+      .params
+      .iter()
+      .map(|x| x.to_string())
+      .collect::<Vec<_>>();
+    writeln!(out, "  params: [{}]", params.join(", ")).unwrap();
+    if let Some(x) = &info.output {
+      writeln!(out, "  output: {}", x).unwrap();
+    }
+    for (i, ((node, _, _), ty)) in g.nodes.iter().zip(info.nodes.iter()).enumerate() {
+      let ty = ty
+        .as_ref()
+        .map(|x| x.to_string())
+        .unwrap_or_else(|| "-".into());
+      let nullable = if info.nullable.get(i).copied().unwrap_or(false) {
+        "?"
+      } else {
+        ""
+      };
+      writeln!(out, "  {:>4}: {:?}: {}{}", i, node, ty, nullable).unwrap();
+    }
+  }
+  out
+}
+
+/// Renders the graphs of a script, as printed by `rdbctl explain`.
+pub fn explain_snapshot(script: &TwScript) -> String {
+  script.explain()
+}
+
+/// Compares `actual` with the snapshot at `path`, typically under `CARGO_MANIFEST_DIR`.
+///
+/// Writes the snapshot if it does not exist or `UPDATE_SNAPSHOTS` is set. Otherwise panics with a
+/// unified diff from the snapshot to `actual` if they differ.
+pub fn assert_snapshot(path: impl AsRef<Path>, actual: &str) {
+  let path = path.as_ref();
+  let update = std::env::var_os(UPDATE_SNAPSHOTS_ENV).is_some();
+  let expected = match std::fs::read_to_string(path) {
+    Ok(x) if !update => x,
+    _ => {
+      if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).unwrap();
+      }
+      std::fs::write(path, actual)
+        .unwrap_or_else(|e| panic!("cannot write snapshot {}: {}", path.display(), e));
+      return;
+    }
+  };
+  if expected != actual {
+    panic!(
+      "snapshot {} mismatch (set {} to update):\n{}",
+      path.display(),
+      UPDATE_SNAPSHOTS_ENV,
+      snapshot_diff(&expected, actual)
+    );
+  }
+}
+
+/// A unified diff from `expected` to `actual`.
+pub fn snapshot_diff(expected: &str, actual: &str) -> String {
+  TextDiff::from_lines(expected, actual)
+    .unified_diff()
+    .header("expected", "actual")
+    .to_string()
+}
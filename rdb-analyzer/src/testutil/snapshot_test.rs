@@ -0,0 +1,79 @@
+use std::panic::catch_unwind;
+
+use rand::RngCore;
+
+use crate::{
+  data::treewalker::{asm::codegen::compile_twscript, typeck::GlobalTyckContext, vm::TwVm},
+  storage_plan::planner::generate_plan_for_schema,
+  testutil::{
+    compile_schema_source,
+    snapshot::{
+      assert_snapshot, explain_snapshot, plan_snapshot, snapshot_diff, type_info_snapshot,
+    },
+  },
+};
+
+const SCHEMA: &str = r#"
+type Item {
+  @primary
+  id: string,
+  next: Item,
+  tags: list<string>,
+}
+export set<Item> items;
+"#;
+
+#[test]
+fn plan_snapshots_are_deterministic() {
+  let schema = compile_schema_source(SCHEMA);
+  let plan1 = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let plan2 = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let snapshot = plan_snapshot(&plan1);
+  assert_eq!(snapshot, plan_snapshot(&plan2));
+  assert!(snapshot.contains("key0"));
+  // The recursive field refers to the key of the member node.
+  assert!(snapshot.contains("subspace_reference: key"));
+}
+
+#[test]
+fn script_snapshots() {
+  let schema = compile_schema_source(SCHEMA);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let script = compile_twscript(
+    r#"
+  export graph get(root: schema, id: string): string {
+    return (point_get root.items id).next.id;
+  }
+  "#,
+  )
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+
+  let snapshot = type_info_snapshot(&script, &type_info);
+  assert!(snapshot.starts_with("graph get:\n  params: [schema, string]\n  output: string\n"));
+  assert!(snapshot.contains(": Item?\n"));
+
+  let snapshot = explain_snapshot(&script);
+  assert!(snapshot.starts_with("export graph get (0):\n"));
+  assert!(snapshot.contains("GetSetElement"));
+}
+
+#[test]
+fn snapshot_files() {
+  let path = std::env::temp_dir().join(format!(
+    "rdb-snapshot-{}/plan.snap",
+    rand::thread_rng().next_u64()
+  ));
+  assert_snapshot(&path, "a\nb\n");
+  assert_eq!(std::fs::read_to_string(&path).unwrap(), "a\nb\n");
+  assert_snapshot(&path, "a\nb\n");
+
+  let path2 = path.clone();
+  assert!(catch_unwind(move || assert_snapshot(&path2, "a\nc\n")).is_err());
+  std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+
+  let diff = snapshot_diff("a\nb\n", "a\nc\n");
+  assert!(diff.contains("-b\n"));
+  assert!(diff.contains("+c\n"));
+}
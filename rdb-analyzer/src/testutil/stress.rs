@@ -0,0 +1,187 @@
+use std::{
+  future::Future,
+  pin::Pin,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+  },
+  time::Duration,
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::future::join_all;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::data::{
+  kv::{KeyValueStore, KvError, KvKeyIterator, KvTransaction},
+  treewalker::{exec::Executor, typeck::GlobalTypeInfo, vm::TwVm, vm_value::VmValue},
+};
+
+pub type SleepFn = fn(Duration) -> Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// The faults injected by `ConflictInjectingKv`.
+#[derive(Clone, Debug)]
+pub struct FaultSchedule {
+  /// Seed of the schedule. The faults injected into the n-th transaction only depend on the seed
+  /// and n.
+  pub seed: u64,
+
+  /// Probability that a commit fails with `KvError::Conflict` without applying its writes.
+  pub conflict_rate: f64,
+
+  /// Max latency added before each operation. Latencies are uniformly distributed, and only added
+  /// if a sleep function is set.
+  pub max_latency: Duration,
+}
+
+impl Default for FaultSchedule {
+  fn default() -> Self {
+    Self {
+      seed: 0,
+      conflict_rate: 0.1,
+      max_latency: Duration::from_millis(1),
+    }
+  }
+}
+
+/// Counters of the faults injected by `ConflictInjectingKv`.
+#[derive(Default, Debug)]
+pub struct FaultStats {
+  pub transactions: AtomicU64,
+  pub commits: AtomicU64,
+  pub injected_conflicts: AtomicU64,
+}
+
+/// Wraps a `KeyValueStore`, failing commits with artificial conflicts and delaying operations
+/// according to a seeded schedule.
+pub struct ConflictInjectingKv<S> {
+  inner: S,
+  schedule: FaultSchedule,
+  sleep_fn: Option<SleepFn>,
+  stats: Arc<FaultStats>,
+}
+
+struct ConflictInjectingTransaction {
+  inner: Box<dyn KvTransaction>,
+  rng: Mutex<StdRng>,
+  inject_conflict: bool,
+  max_latency: Duration,
+  sleep_fn: Option<SleepFn>,
+  stats: Arc<FaultStats>,
+}
+
+impl<S: KeyValueStore> ConflictInjectingKv<S> {
+  pub fn new(inner: S, schedule: FaultSchedule) -> Self {
+    Self {
+      inner,
+      schedule,
+      sleep_fn: None,
+      stats: Arc::new(FaultStats::default()),
+    }
+  }
+
+  /// Sets the function used to wait for injected latencies. No latency is injected without one.
+  pub fn set_sleep_fn(&mut self, f: SleepFn) {
+    self.sleep_fn = Some(f);
+  }
+
+  pub fn stats(&self) -> &FaultStats {
+    &self.stats
+  }
+
+  pub fn inner(&self) -> &S {
+    &self.inner
+  }
+}
+
+#[async_trait]
+impl<S: KeyValueStore> KeyValueStore for ConflictInjectingKv<S> {
+  async fn begin_transaction(&self) -> Result<Box<dyn KvTransaction>> {
+    let index = self.stats.transactions.fetch_add(1, Ordering::SeqCst);
+    let mut rng =
+      StdRng::seed_from_u64(self.schedule.seed ^ index.wrapping_mul(0x9e3779b97f4a7c15));
+    let inject_conflict = rng.gen_bool(self.schedule.conflict_rate);
+    Ok(Box::new(ConflictInjectingTransaction {
+      inner: self.inner.begin_transaction().await?,
+      rng: Mutex::new(rng),
+      inject_conflict,
+      max_latency: self.schedule.max_latency,
+      sleep_fn: self.sleep_fn,
+      stats: self.stats.clone(),
+    }))
+  }
+}
+
+impl ConflictInjectingTransaction {
+  async fn delay(&self) {
+    if let Some(f) = self.sleep_fn {
+      let latency = self
+        .max_latency
+        .mul_f64(self.rng.lock().unwrap().gen::<f64>());
+      f(latency).await;
+    }
+  }
+}
+
+#[async_trait]
+impl KvTransaction for ConflictInjectingTransaction {
+  async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    self.delay().await;
+    self.inner.get(key).await
+  }
+
+  async fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+    self.delay().await;
+    self.inner.put(key, value).await
+  }
+
+  async fn delete(&self, key: &[u8]) -> Result<()> {
+    self.delay().await;
+    self.inner.delete(key).await
+  }
+
+  async fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+    self.delay().await;
+    self.inner.delete_range(start, end).await
+  }
+
+  async fn scan_keys(&self, start: &[u8], end: &[u8]) -> Result<Box<dyn KvKeyIterator>> {
+    self.delay().await;
+    self.inner.scan_keys(start, end).await
+  }
+
+  async fn commit(self: Box<Self>) -> Result<(), KvError> {
+    self.delay().await;
+    if self.inject_conflict {
+      self.stats.injected_conflicts.fetch_add(1, Ordering::SeqCst);
+      return Err(KvError::Conflict(Default::default()));
+    }
+    self.inner.commit().await?;
+    self.stats.commits.fetch_add(1, Ordering::SeqCst);
+    Ok(())
+  }
+}
+
+/// Runs a graph from `tasks` concurrent executors against `kv`, and returns the result of each
+/// run. The runs are interleaved on the current task, at the await points of the KV operations.
+///
+/// `sleep_fn` is used by the executors to back off between retries.
+pub async fn run_graph_concurrently<'a>(
+  vm: &TwVm<'a>,
+  type_info: &GlobalTypeInfo<'a>,
+  kv: &dyn KeyValueStore,
+  graph_index: usize,
+  params: &[Arc<VmValue<'a>>],
+  tasks: usize,
+  sleep_fn: Option<SleepFn>,
+) -> Vec<Result<Option<Arc<VmValue<'a>>>>> {
+  join_all((0..tasks).map(|_| async move {
+    let mut executor = Executor::new(vm, kv, type_info);
+    if let Some(f) = sleep_fn {
+      executor.set_sleep_fn(f);
+    }
+    executor.run_graph(graph_index, params).await
+  }))
+  .await
+}
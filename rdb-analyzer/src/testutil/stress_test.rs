@@ -0,0 +1,98 @@
+use std::{
+  future::Future,
+  pin::Pin,
+  sync::{atomic::Ordering, Arc},
+  time::Duration,
+};
+
+use bumpalo::Bump;
+
+use crate::{
+  data::{
+    mock_kv::MockKv,
+    treewalker::{
+      asm::codegen::compile_twscript,
+      exec::{generate_root_map, Executor},
+      typeck::GlobalTyckContext,
+      vm::TwVm,
+      vm_value::VmValue,
+    },
+    value::PrimitiveValue,
+  },
+  schema::{compile::compile, grammar::parse},
+  storage_plan::planner::generate_plan_for_schema,
+};
+
+use super::stress::{run_graph_concurrently, ConflictInjectingKv, FaultSchedule};
+
+const SCHEMA: &str = r#"
+type State {
+  value: int64,
+}
+export State state;
+"#;
+
+const INCREMENT: &str = r#"
+graph main(root: schema) {
+  t_insert(value) root.state ((root.state.value ?? 0) + 1);
+}
+"#;
+
+const READ: &str = r#"
+graph main(root: schema): int64 {
+  return root.state.value ?? 0;
+}
+"#;
+
+fn sleep(x: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+  Box::pin(tokio::time::sleep(x))
+}
+
+#[tokio::test]
+async fn counter_under_contention() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(&alloc, SCHEMA).unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let root = Arc::new(generate_root_map(&schema, &plan).unwrap());
+
+  let mut kv = ConflictInjectingKv::new(
+    MockKv::new(),
+    FaultSchedule {
+      seed: 42,
+      conflict_rate: 0.2,
+      max_latency: Duration::from_millis(2),
+    },
+  );
+  kv.set_sleep_fn(sleep);
+
+  let script = compile_twscript(INCREMENT).unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+
+  let mut succeeded = 0;
+  for _ in 0..4 {
+    let results =
+      run_graph_concurrently(&vm, &type_info, &kv, 0, &[root.clone()], 8, Some(sleep)).await;
+    succeeded += results.iter().filter(|x| x.is_ok()).count();
+  }
+
+  let stats = kv.stats();
+  assert!(stats.injected_conflicts.load(Ordering::SeqCst) > 0);
+  assert_eq!(stats.commits.load(Ordering::SeqCst), succeeded as u64);
+
+  // Every successful run increments the counter exactly once, including the runs that were
+  // retried after a conflict.
+  let script = compile_twscript(READ).unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let mut executor = Executor::new(&vm, kv.inner(), &type_info);
+  let output = executor.run_graph(0, &[root]).await.unwrap().unwrap();
+  assert_eq!(
+    *output,
+    VmValue::Primitive(PrimitiveValue::Int64(succeeded as i64))
+  );
+}
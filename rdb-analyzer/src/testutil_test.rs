@@ -0,0 +1,27 @@
+use proptest::prelude::*;
+
+use crate::{
+  data::treewalker::vm_value::VmValue,
+  schema::compile::CompiledSchema,
+  storage_plan::{diff_display, planner::generate_plan_for_schema},
+  testutil::{arb_schema_source, arb_storage_plan, arb_vm_const, compile_schema_source},
+};
+
+proptest! {
+  #[test]
+  fn generated_schemas_compile(source in arb_schema_source()) {
+    compile_schema_source(&source);
+  }
+
+  #[test]
+  fn plan_regeneration_is_idempotent((schema, plan) in arb_storage_plan()) {
+    let regenerated = generate_plan_for_schema(&plan, &schema, &schema).unwrap();
+    prop_assert_eq!(diff_display(&plan, &regenerated), "");
+  }
+
+  #[test]
+  fn consts_load(c in arb_vm_const()) {
+    let schema = CompiledSchema::default();
+    prop_assert!(VmValue::from_const(&schema, &c).is_ok());
+  }
+}
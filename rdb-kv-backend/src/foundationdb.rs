@@ -39,6 +39,19 @@ impl KeyValueStore for FdbKvStore {
       prefix: self.prefix.clone(),
     }))
   }
+
+  async fn begin_transaction_at(&self, version: i64) -> Result<Box<dyn KvTransaction>> {
+    let txn = self.db.create_trx()?;
+    txn.set_option(TransactionOption::ReadYourWritesDisable)?;
+
+    // Reads fail with `transaction_too_old` once the version is out of the MVCC window.
+    txn.set_read_version(version);
+
+    Ok(Box::new(FdbTxn {
+      inner: Arc::new(txn),
+      prefix: self.prefix.clone(),
+    }))
+  }
 }
 
 #[async_trait]
@@ -136,13 +149,17 @@ impl KvTransaction for FdbTxn {
       .map_err(|e| {
         // XXX: Is this correct?
         if e.is_retryable_not_committed() {
-          KvError::Conflict
+          KvError::Conflict(Default::default())
         } else {
           KvError::CommitStateUnknown
         }
       })
       .map(|_| ())
   }
+
+  async fn read_version(&self) -> Result<Option<i64>> {
+    Ok(Some(self.inner.get_read_version().await?))
+  }
 }
 
 pub struct FdbIterator {
@@ -299,7 +299,7 @@ impl KvTransaction for SqliteKvTxn {
             rusqlite::Error::SqliteFailure(_, reason) => {
               if let Some(reason) = reason {
                 if reason == "database is locked" {
-                  return KvError::Conflict;
+                  return KvError::Conflict(Default::default());
                 }
               }
             }
@@ -7,7 +7,7 @@ use std::{
 };
 
 use async_trait::async_trait;
-use rdb_analyzer::data::kv::{KeyValueStore, KvError, KvKeyIterator, KvTransaction};
+use rdb_analyzer::data::kv::{ConflictInfo, KeyValueStore, KvError, KvKeyIterator, KvTransaction};
 use rpds::RedBlackTreeMapSync;
 use std::sync::Mutex;
 
@@ -124,11 +124,16 @@ impl KvTransaction for MemTransaction {
     let modified = self.modified.into_inner().unwrap();
 
     let mut data = self.store.data.lock().unwrap();
-    for (k, initial_version) in &modified {
-      if data.get(k).map(|x| x.1).unwrap_or_default() != *initial_version {
-        log::trace!("[txn {}] commit CONFLICT", self.id);
-        return Err(KvError::Conflict);
-      }
+    let conflicts = modified
+      .iter()
+      .filter(|(k, initial_version)| {
+        data.get(*k).map(|x| x.1).unwrap_or_default() != **initial_version
+      })
+      .map(|(k, _)| k.as_slice())
+      .collect::<Vec<_>>();
+    if !conflicts.is_empty() {
+      log::trace!("[txn {}] commit CONFLICT", self.id);
+      return Err(KvError::Conflict(ConflictInfo::from_keys(conflicts)));
     }
 
     for (k, _) in modified {
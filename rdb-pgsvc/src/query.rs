@@ -101,7 +101,7 @@ pub fn run_vm_query<'a>(
     .zip(param_types.iter())
     .map(|(x, ty)| match ty {
       VmType::Schema => generate_root_map(vm.schema, vm.storage_plan).map(Arc::new),
-      _ => x.decode(ty).map(Arc::new),
+      _ => x.decode_in(ty, vm.schema, vm.storage_plan).map(Arc::new),
     })
     .collect::<Result<Vec<_>>>()?;
   let res = futures::executor::block_on(executor.run_graph(i, &params))?;
@@ -0,0 +1,232 @@
+use std::{
+  sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc,
+  },
+  time::Duration,
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use rand::RngCore;
+use rdb_analyzer::data::{
+  kv::{KeyValueStore, KvEntryIterator, KvError, KvKeyIterator, KvTransaction, ScanOptions},
+  treewalker::serialize::SerializedVmValue,
+};
+use sha2::{Digest, Sha256};
+use tokio::time::sleep;
+
+use crate::{
+  sysquery::{
+    append_audit_entry, delete_audit_entry, list_audit_entries, list_namespaces, AuditEntry,
+  },
+  util::current_millis,
+};
+
+/// Interval between two passes of the retention task.
+const RETENTION_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Writes made through a `WriteCountingKvStore`.
+#[derive(Default)]
+pub struct WriteStats {
+  attempted: AtomicBool,
+  keys_written: AtomicU64,
+}
+
+impl WriteStats {
+  /// Whether any transaction attempted a write, committed or not.
+  pub fn attempted(&self) -> bool {
+    self.attempted.load(Ordering::Relaxed)
+  }
+
+  /// Number of keys written by committed transactions. A range deletion counts as one key.
+  pub fn keys_written(&self) -> u64 {
+    self.keys_written.load(Ordering::Relaxed)
+  }
+}
+
+/// Wraps the data store of a namespace to count the writes of an execution.
+pub fn count_writes(inner: Box<dyn KeyValueStore>) -> (Box<dyn KeyValueStore>, Arc<WriteStats>) {
+  let stats = Arc::new(WriteStats::default());
+  (
+    Box::new(WriteCountingKvStore {
+      inner,
+      stats: stats.clone(),
+    }),
+    stats,
+  )
+}
+
+/// Records an execution that attempted to write in the audit log of its namespace.
+///
+/// Failing to record is logged and otherwise ignored, so that the audit log never fails
+/// requests.
+pub async fn record<T>(
+  namespace_id: &str,
+  query_script_id: &str,
+  graph_name: &str,
+  params: &[SerializedVmValue],
+  result: &Result<T>,
+  stats: &WriteStats,
+  duration: Duration,
+) {
+  if !stats.attempted() {
+    return;
+  }
+  let time = current_millis();
+  let mut suffix = [0u8; 8];
+  rand::thread_rng().fill_bytes(&mut suffix);
+  let params_hash = rmp_serde::to_vec_named(params)
+    .map(|x| hex::encode(Sha256::digest(&x)))
+    .unwrap_or_default();
+  let entry = AuditEntry {
+    id: format!("{}{}", time_to_entry_id(time), hex::encode(&suffix)),
+    time: time as i64,
+    query_script_id: query_script_id.to_string(),
+    graph_name: graph_name.to_string(),
+    params_hash,
+    status: match result {
+      Ok(_) => "ok".to_string(),
+      Err(e) => format!("{}", e),
+    },
+    keys_written: stats.keys_written() as i64,
+    duration_us: duration.as_micros() as i64,
+  };
+  if let Err(e) = append_audit_entry(namespace_id, &entry).await {
+    log::error!(
+      "Failed to append audit entry to namespace `{}`: {:?}",
+      namespace_id,
+      e
+    );
+  }
+}
+
+/// Lists the audit entries recorded in `[start_time, end_time)`, newest first.
+pub async fn list_entries(
+  namespace_id: &str,
+  start_time: u64,
+  end_time: u64,
+) -> Result<Vec<AuditEntry>> {
+  list_audit_entries(
+    namespace_id,
+    &time_to_entry_id(start_time),
+    &time_to_entry_id(end_time),
+  )
+  .await
+}
+
+/// Periodically deletes audit entries older than `retention` from all namespaces.
+pub async fn run_retention(retention: Duration) -> ! {
+  loop {
+    let cutoff = current_millis().saturating_sub(retention.as_millis() as u64);
+    match prune(cutoff).await {
+      Ok(0) => {}
+      Ok(n) => log::info!("Pruned {} audit entries.", n),
+      Err(e) => log::error!("Failed to prune audit entries: {:?}", e),
+    }
+    sleep(RETENTION_INTERVAL).await;
+  }
+}
+
+async fn prune(cutoff: u64) -> Result<usize> {
+  let mut num_deleted = 0usize;
+  for ns in list_namespaces().await? {
+    for entry in list_entries(&ns.id, 0, cutoff).await? {
+      if delete_audit_entry(&ns.id, &entry.id).await? {
+        num_deleted += 1;
+      }
+    }
+  }
+  Ok(num_deleted)
+}
+
+/// Entry ids start with the fixed-width hex of the time, so that they sort by time.
+pub fn time_to_entry_id(time: u64) -> String {
+  format!("{:016x}", time)
+}
+
+struct WriteCountingKvStore {
+  inner: Box<dyn KeyValueStore>,
+  stats: Arc<WriteStats>,
+}
+
+struct WriteCountingKvTransaction {
+  inner: Box<dyn KvTransaction>,
+  stats: Arc<WriteStats>,
+  keys_written: AtomicU64,
+}
+
+impl WriteCountingKvTransaction {
+  fn count_write(&self) {
+    self.stats.attempted.store(true, Ordering::Relaxed);
+    self.keys_written.fetch_add(1, Ordering::Relaxed);
+  }
+}
+
+#[async_trait]
+impl KeyValueStore for WriteCountingKvStore {
+  async fn begin_transaction(&self) -> Result<Box<dyn KvTransaction>> {
+    Ok(Box::new(WriteCountingKvTransaction {
+      inner: self.inner.begin_transaction().await?,
+      stats: self.stats.clone(),
+      keys_written: AtomicU64::new(0),
+    }))
+  }
+
+  async fn begin_transaction_at(&self, version: i64) -> Result<Box<dyn KvTransaction>> {
+    Ok(Box::new(WriteCountingKvTransaction {
+      inner: self.inner.begin_transaction_at(version).await?,
+      stats: self.stats.clone(),
+      keys_written: AtomicU64::new(0),
+    }))
+  }
+}
+
+#[async_trait]
+impl KvTransaction for WriteCountingKvTransaction {
+  async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    self.inner.get(key).await
+  }
+
+  async fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+    self.count_write();
+    self.inner.put(key, value).await
+  }
+
+  async fn delete(&self, key: &[u8]) -> Result<()> {
+    self.count_write();
+    self.inner.delete(key).await
+  }
+
+  async fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+    self.count_write();
+    self.inner.delete_range(start, end).await
+  }
+
+  async fn scan_keys(&self, start: &[u8], end: &[u8]) -> Result<Box<dyn KvKeyIterator>> {
+    self.inner.scan_keys(start, end).await
+  }
+
+  async fn scan<'a>(
+    &'a self,
+    start: &[u8],
+    end: &[u8],
+    options: &ScanOptions,
+  ) -> Result<Box<dyn KvEntryIterator + 'a>> {
+    self.inner.scan(start, end, options).await
+  }
+
+  async fn commit(self: Box<Self>) -> Result<(), KvError> {
+    let me = *self;
+    let keys_written = me.keys_written.load(Ordering::Relaxed);
+    me.inner.commit().await?;
+    me.stats
+      .keys_written
+      .fetch_add(keys_written, Ordering::Relaxed);
+    Ok(())
+  }
+
+  async fn read_version(&self) -> Result<Option<i64>> {
+    self.inner.read_version().await
+  }
+}
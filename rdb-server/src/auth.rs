@@ -0,0 +1,196 @@
+use std::{fmt::Display, str::FromStr, sync::Arc};
+
+use anyhow::Result;
+use rand::RngCore;
+use rdb_analyzer::data::{
+  treewalker::vm_value::{VmMapValue, VmValue, AUTH_CONTEXT_FIELDS},
+  value::PrimitiveValue,
+};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::{
+  state::get_state,
+  sysquery::{add_api_token, lookup_api_token, rotate_api_token, ApiToken},
+  util::current_millis,
+};
+
+#[derive(Error, Debug)]
+pub enum AuthError {
+  #[error("missing api token")]
+  MissingToken,
+
+  #[error("invalid api token")]
+  InvalidToken,
+
+  #[error("unknown role: `{0}`")]
+  UnknownRole(String),
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Role {
+  /// Callers without a token, only allowed when authentication is not required.
+  Anonymous,
+
+  /// Runs graphs in read sessions. Graphs that write fail.
+  Reader,
+
+  Writer,
+}
+
+/// The identity a request is executed as. Passed to params of the `auth_context` pseudo-type.
+#[derive(Clone, Debug)]
+pub struct AuthContext {
+  pub namespace_id: String,
+
+  /// Empty for anonymous callers.
+  pub token_id: String,
+  pub role: Role,
+}
+
+impl Role {
+  pub fn can_write(&self) -> bool {
+    match self {
+      Role::Anonymous | Role::Writer => true,
+      Role::Reader => false,
+    }
+  }
+}
+
+impl Display for Role {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "{}",
+      match self {
+        Role::Anonymous => "anonymous",
+        Role::Reader => "reader",
+        Role::Writer => "writer",
+      }
+    )
+  }
+}
+
+impl FromStr for Role {
+  type Err = AuthError;
+
+  /// Parses the roles that can be granted to tokens.
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "reader" => Ok(Role::Reader),
+      "writer" => Ok(Role::Writer),
+      _ => Err(AuthError::UnknownRole(s.to_string())),
+    }
+  }
+}
+
+impl AuthContext {
+  /// Builds the value of `auth_context` params.
+  pub fn to_vm_value<'a>(&self) -> VmValue<'a> {
+    let values = [
+      self.namespace_id.clone(),
+      self.token_id.clone(),
+      self.role.to_string(),
+    ];
+    VmValue::Map(VmMapValue {
+      elements: AUTH_CONTEXT_FIELDS
+        .iter()
+        .zip(values.iter())
+        .map(|(k, v)| {
+          (
+            *k,
+            Arc::new(VmValue::Primitive(PrimitiveValue::String(v.clone()))),
+          )
+        })
+        .collect(),
+    })
+  }
+}
+
+/// Resolves the `Authorization` header of a request to a namespace.
+///
+/// Tokens are sent as `Bearer <token id>.<secret>`. Requests without a token run as
+/// `Role::Anonymous` unless authentication is required.
+pub async fn authenticate(namespace_id: &str, authorization: Option<&str>) -> Result<AuthContext> {
+  let token = match authorization {
+    Some(x) => x
+      .strip_prefix("Bearer ")
+      .ok_or(AuthError::InvalidToken)?
+      .trim(),
+    None if get_state().require_auth => return Err(AuthError::MissingToken.into()),
+    None => {
+      return Ok(AuthContext {
+        namespace_id: namespace_id.to_string(),
+        token_id: String::new(),
+        role: Role::Anonymous,
+      })
+    }
+  };
+  let (token_id, secret) = token.split_once('.').ok_or(AuthError::InvalidToken)?;
+  let stored = lookup_api_token(namespace_id, token_id)
+    .await?
+    .ok_or(AuthError::InvalidToken)?;
+  if !constant_time_eq(&hash_secret(secret), &stored.secret_hash) {
+    return Err(AuthError::InvalidToken.into());
+  }
+  Ok(AuthContext {
+    namespace_id: namespace_id.to_string(),
+    token_id: stored.id,
+    role: stored.role.parse()?,
+  })
+}
+
+/// Issues a token with `role` in a namespace. Returns the token id and the full token, or `None`
+/// if the namespace does not exist. The secret is only stored hashed.
+pub async fn issue_token(namespace_id: &str, role: Role) -> Result<Option<(String, String)>> {
+  let id = Uuid::new_v4().to_string();
+  let secret = generate_secret();
+  let created = add_api_token(
+    namespace_id,
+    &ApiToken {
+      id: id.clone(),
+      secret_hash: hash_secret(&secret),
+      role: role.to_string(),
+      create_time: current_millis() as i64,
+    },
+  )
+  .await?;
+  Ok(if created {
+    Some((id.clone(), format!("{}.{}", id, secret)))
+  } else {
+    None
+  })
+}
+
+/// Replaces the secret of a token, keeping its id and role. Returns the new full token, or `None`
+/// if the token does not exist.
+pub async fn rotate_token(namespace_id: &str, token_id: &str) -> Result<Option<String>> {
+  let secret = generate_secret();
+  let rotated = rotate_api_token(namespace_id, token_id, &hash_secret(&secret)).await?;
+  Ok(if rotated {
+    Some(format!("{}.{}", token_id, secret))
+  } else {
+    None
+  })
+}
+
+fn generate_secret() -> String {
+  let mut secret = [0u8; 32];
+  rand::thread_rng().fill_bytes(&mut secret);
+  base64::encode_config(&secret, base64::URL_SAFE_NO_PAD)
+}
+
+fn hash_secret(secret: &str) -> Vec<u8> {
+  Sha256::digest(secret.as_bytes()).to_vec()
+}
+
+/// Compares two secrets in constant time. Only their hashes are compared, so that the time taken
+/// does not depend on their lengths either.
+pub fn secret_eq(a: &str, b: &str) -> bool {
+  constant_time_eq(&hash_secret(a), &hash_secret(b))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+  a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
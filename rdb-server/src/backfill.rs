@@ -0,0 +1,121 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use rdb_analyzer::data::backfill::{backfill_sort_indexes, BackfillOptions};
+use tokio::time::sleep;
+use uuid::Uuid;
+
+use crate::{
+  deploy_lock::DeploymentLock,
+  key_encoding::check_key_encoding,
+  server::insert_deployment,
+  state::get_state,
+  sysquery::{
+    list_namespaces, lookup_deployment, lookup_head_deployment, ns_to_kv_prefix_with_appended_zero,
+  },
+};
+
+/// Backfills the sort key indexes that the head deployment of each namespace marks as pending,
+/// every `interval`. New deployments start their backfill right away with `spawn_backfill`, so
+/// this mostly resumes backfills interrupted by a restart.
+pub async fn run_backfill(interval: Duration) -> ! {
+  loop {
+    match backfill_all().await {
+      Ok(0) => {}
+      Ok(n) => log::info!("Backfilled the sort key indexes of {} namespace(s).", n),
+      Err(e) => log::error!("Failed to backfill sort key indexes: {:?}", e),
+    }
+    sleep(interval).await;
+  }
+}
+
+/// Backfills the sort key indexes of a namespace in the background, after a deployment.
+pub fn spawn_backfill(namespace_id: String) {
+  tokio::spawn(async move {
+    if let Err(e) = backfill_namespace(&namespace_id).await {
+      log::warn!(
+        "Failed to backfill the sort key indexes of namespace `{}`: {:?}",
+        namespace_id,
+        e
+      );
+    }
+  });
+}
+
+async fn backfill_all() -> Result<usize> {
+  let mut num_backfilled = 0usize;
+  for ns in list_namespaces().await? {
+    // A failure in one namespace does not hold back the others.
+    match backfill_namespace(&ns.id).await {
+      Ok(true) => num_backfilled += 1,
+      Ok(false) => {}
+      Err(e) => log::warn!(
+        "Failed to backfill the sort key indexes of namespace `{}`: {:?}",
+        ns.id,
+        e
+      ),
+    }
+  }
+  Ok(num_backfilled)
+}
+
+/// Returns `false` if the head deployment of the namespace has no pending backfill, or if it
+/// changed while the backfill ran.
+async fn backfill_namespace(namespace_id: &str) -> Result<bool> {
+  let st = get_state();
+  let deployment_id = match lookup_head_deployment(namespace_id).await? {
+    Some(x) => x,
+    None => return Ok(false),
+  };
+  let schema_ctx = st
+    .vm_pool
+    .get_or_load_schema(namespace_id, &deployment_id)
+    .await?;
+  if !schema_ctx.plan.has_sort_index_backfills() {
+    return Ok(false);
+  }
+
+  let kv_prefix = ns_to_kv_prefix_with_appended_zero(namespace_id).await?;
+  check_key_encoding(namespace_id, &kv_prefix).await?;
+  let kv = (st.data_store_generator)(&kv_prefix);
+  let report = backfill_sort_indexes(
+    &*kv,
+    &schema_ctx.schema,
+    &schema_ctx.plan,
+    &BackfillOptions::default(),
+  )
+  .await?;
+  log::info!(
+    "Backfilled the sort key indexes of namespace `{}`: {:?}",
+    namespace_id,
+    report
+  );
+
+  // All marked sets are indexed now. Drop the marks with a deployment of the same schema on top of
+  // the head, so that scans by sort key stop checking the progress of the backfill.
+  let mut plan = schema_ctx.plan.clone();
+  plan.clear_sort_index_backfills();
+  let deployment = lookup_deployment(namespace_id, &deployment_id).await?;
+  let lock = DeploymentLock::acquire(namespace_id).await?;
+  let ok: Result<bool> = async {
+    // A new head keeps the marks if its sort keys are unchanged, and is handled by the next run.
+    if lookup_head_deployment(namespace_id).await?.as_deref() != Some(deployment_id.as_str()) {
+      return Ok(false);
+    }
+    let ok = insert_deployment(
+      namespace_id,
+      &deployment_id,
+      &Uuid::new_v4().to_string(),
+      "Sort key index backfill",
+      &deployment.schema,
+      &plan,
+    )
+    .await?;
+    Ok(ok)
+  }
+  .await;
+  if let Err(e) = lock.release().await {
+    log::warn!("failed to release deployment lease: {:?}", e);
+  }
+  ok
+}
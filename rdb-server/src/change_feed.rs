@@ -0,0 +1,194 @@
+use std::sync::{
+  atomic::{AtomicBool, Ordering},
+  Arc, Mutex,
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use rdb_analyzer::data::kv::{KeyValueStore, KvError, KvKeyIterator, KvTransaction};
+use tokio::sync::broadcast;
+
+/// Capacity of the change event channel. Subscribers are told when they lag behind and lose
+/// events.
+const CHANGE_FEED_CAPACITY: usize = 1024;
+
+/// Max number of writes of a transaction that are tracked. Events of larger transactions do not
+/// carry their writes.
+const MAX_TRACKED_WRITES: usize = 4096;
+
+/// Notifies subscribers of the keys written to namespaces.
+///
+/// Events are emitted after a transaction that wrote to a namespace successfully commits.
+pub struct ChangeFeed {
+  tx: broadcast::Sender<Arc<ChangeEvent>>,
+}
+
+/// The writes of a committed transaction.
+pub struct ChangeEvent {
+  pub namespace_id: Arc<str>,
+
+  /// The keys written, relative to the namespace, in the order they were written. `None` if the
+  /// transaction wrote too many keys to track, or began before anyone subscribed.
+  pub writes: Option<Vec<KeyWrite>>,
+}
+
+#[derive(Clone, Debug)]
+pub enum KeyWrite {
+  /// A put or delete of a single key.
+  Key(Vec<u8>),
+
+  /// A deletion of the keys in `[start, end)`.
+  Range(Vec<u8>, Vec<u8>),
+}
+
+pub struct ChangeSubscription {
+  namespace_id: String,
+  rx: broadcast::Receiver<Arc<ChangeEvent>>,
+}
+
+impl ChangeFeed {
+  pub fn new() -> Self {
+    let (tx, _) = broadcast::channel(CHANGE_FEED_CAPACITY);
+    Self { tx }
+  }
+
+  pub fn subscribe(&self, namespace_id: &str) -> ChangeSubscription {
+    ChangeSubscription {
+      namespace_id: namespace_id.to_string(),
+      rx: self.tx.subscribe(),
+    }
+  }
+
+  /// Wraps the data store of a namespace so that committed writes emit change events.
+  pub fn wrap(&self, namespace_id: &str, inner: Box<dyn KeyValueStore>) -> Box<dyn KeyValueStore> {
+    Box::new(NotifyingKvStore {
+      inner,
+      namespace_id: Arc::from(namespace_id),
+      tx: self.tx.clone(),
+    })
+  }
+}
+
+impl Default for ChangeFeed {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl ChangeSubscription {
+  /// Waits for the next change in the subscribed namespace. Returns `None` if events were lost
+  /// because the subscriber lagged behind.
+  pub async fn next(&mut self) -> Option<Arc<ChangeEvent>> {
+    loop {
+      match self.rx.recv().await {
+        Ok(x) if &*x.namespace_id == self.namespace_id.as_str() => return Some(x),
+        Ok(_) => {}
+        Err(broadcast::error::RecvError::Lagged(_)) => return None,
+        Err(broadcast::error::RecvError::Closed) => {
+          // The feed lives as long as the server.
+          return futures::future::pending().await;
+        }
+      }
+    }
+  }
+}
+
+struct NotifyingKvStore {
+  inner: Box<dyn KeyValueStore>,
+  namespace_id: Arc<str>,
+  tx: broadcast::Sender<Arc<ChangeEvent>>,
+}
+
+struct NotifyingKvTransaction {
+  inner: Box<dyn KvTransaction>,
+  namespace_id: Arc<str>,
+  tx: broadcast::Sender<Arc<ChangeEvent>>,
+  dirty: AtomicBool,
+  writes: Mutex<Option<Vec<KeyWrite>>>,
+}
+
+impl NotifyingKvStore {
+  fn wrap_transaction(&self, inner: Box<dyn KvTransaction>) -> Box<dyn KvTransaction> {
+    // Writes are only tracked while someone listens.
+    let writes = if self.tx.receiver_count() == 0 {
+      None
+    } else {
+      Some(vec![])
+    };
+    Box::new(NotifyingKvTransaction {
+      inner,
+      namespace_id: self.namespace_id.clone(),
+      tx: self.tx.clone(),
+      dirty: AtomicBool::new(false),
+      writes: Mutex::new(writes),
+    })
+  }
+}
+
+impl NotifyingKvTransaction {
+  fn record(&self, write: KeyWrite) {
+    self.dirty.store(true, Ordering::Relaxed);
+    let mut writes = self.writes.lock().unwrap();
+    let full = matches!(&*writes, Some(x) if x.len() >= MAX_TRACKED_WRITES);
+    if full {
+      *writes = None;
+    } else if let Some(x) = &mut *writes {
+      x.push(write);
+    }
+  }
+}
+
+#[async_trait]
+impl KeyValueStore for NotifyingKvStore {
+  async fn begin_transaction(&self) -> Result<Box<dyn KvTransaction>> {
+    Ok(self.wrap_transaction(self.inner.begin_transaction().await?))
+  }
+
+  async fn begin_transaction_at(&self, version: i64) -> Result<Box<dyn KvTransaction>> {
+    Ok(self.wrap_transaction(self.inner.begin_transaction_at(version).await?))
+  }
+}
+
+#[async_trait]
+impl KvTransaction for NotifyingKvTransaction {
+  async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    self.inner.get(key).await
+  }
+
+  async fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+    self.record(KeyWrite::Key(key.to_vec()));
+    self.inner.put(key, value).await
+  }
+
+  async fn delete(&self, key: &[u8]) -> Result<()> {
+    self.record(KeyWrite::Key(key.to_vec()));
+    self.inner.delete(key).await
+  }
+
+  async fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+    self.record(KeyWrite::Range(start.to_vec(), end.to_vec()));
+    self.inner.delete_range(start, end).await
+  }
+
+  async fn scan_keys(&self, start: &[u8], end: &[u8]) -> Result<Box<dyn KvKeyIterator>> {
+    self.inner.scan_keys(start, end).await
+  }
+
+  async fn commit(self: Box<Self>) -> Result<(), KvError> {
+    let me = *self;
+    let dirty = me.dirty.load(Ordering::Relaxed);
+    me.inner.commit().await?;
+    if dirty {
+      // No receivers is not an error.
+      let _ = me.tx.send(Arc::new(ChangeEvent {
+        namespace_id: me.namespace_id,
+        writes: me.writes.into_inner().unwrap(),
+      }));
+    }
+    Ok(())
+  }
+
+  async fn read_version(&self) -> Result<Option<i64>> {
+    self.inner.read_version().await
+  }
+}
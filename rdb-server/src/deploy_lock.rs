@@ -0,0 +1,71 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::{
+  sysquery::{lookup_deployment_lease, swap_deployment_lease, DeploymentLease},
+  util::current_millis,
+};
+
+/// How long a deployment lease is held before others may take it over, so that a server that dies
+/// while holding it does not block deployments forever.
+const DEPLOYMENT_LEASE_TTL: Duration = Duration::from_secs(30);
+
+#[derive(Error, Debug)]
+pub enum DeploymentLockError {
+  #[error("another deployment to namespace `{0}` is in progress")]
+  Held(String),
+}
+
+/// A held deployment lease, which serializes deployments to a namespace: two deployments planned
+/// concurrently against the same head would each drop the changes of the other. Must be released
+/// with `release` once the deployment is done.
+///
+/// An expired lease can be taken over while its holder is still running, so `add_deployment` also
+/// checks that the base of the new deployment is still the head.
+pub struct DeploymentLock {
+  namespace_id: String,
+  holder: String,
+}
+
+impl DeploymentLock {
+  /// Takes the deployment lease of a namespace. Fails with `DeploymentLockError::Held` if another
+  /// deployment holds it and it has not expired.
+  pub async fn acquire(namespace_id: &str) -> Result<Self> {
+    let current = lookup_deployment_lease(namespace_id).await?;
+    let now = current_millis() as i64;
+    if !current.holder.is_empty() && current.expiry > now {
+      return Err(DeploymentLockError::Held(namespace_id.to_string()).into());
+    }
+
+    // Holders are unique per acquisition, so that a stale holder never matches a newer lease.
+    let holder = Uuid::new_v4().to_string();
+    let lease = DeploymentLease {
+      holder: holder.clone(),
+      expiry: now + DEPLOYMENT_LEASE_TTL.as_millis() as i64,
+    };
+    if !swap_deployment_lease(namespace_id, &current.holder, &lease).await? {
+      return Err(DeploymentLockError::Held(namespace_id.to_string()).into());
+    }
+    Ok(Self {
+      namespace_id: namespace_id.to_string(),
+      holder,
+    })
+  }
+
+  /// Releases the lease. A lease that expired and was taken over is left to its new holder.
+  pub async fn release(self) -> Result<()> {
+    swap_deployment_lease(
+      &self.namespace_id,
+      &self.holder,
+      &DeploymentLease {
+        holder: String::new(),
+        expiry: 0,
+      },
+    )
+    .await?;
+    Ok(())
+  }
+}
@@ -1,18 +1,25 @@
-use std::{panic::AssertUnwindSafe, sync::Arc, time::Duration};
+use std::{
+  future::Future,
+  panic::AssertUnwindSafe,
+  sync::Arc,
+  time::{Duration, Instant},
+};
 
 use anyhow::Result;
+use async_trait::async_trait;
 use futures::FutureExt;
 use rdb_analyzer::data::{
-  kv::KeyValueStore,
+  kv::{KeyValueStore, KvEntryIterator, KvError, KvKeyIterator, KvTransaction, ScanOptions},
   treewalker::{
-    exec::Executor,
-    serialize::{SerializedVmValue, VmValueEncodeConfig},
-    vm_value::VmType,
+    exec::{ExecConfig, Executor},
+    serialize::{Selection, SerializedVmValue, TaggedVmValue, VmValueEncodeConfig},
+    usage::ExecUsage,
+    vm_value::{VmType, VmValue},
   },
 };
-use tokio::{task::yield_now, time::sleep};
+use tokio::{sync::mpsc, task::yield_now, time::sleep};
 
-use crate::exec_core::ExecContext;
+use crate::{auth::AuthContext, exec_core::ExecContext, slow_query::SlowQueryContext};
 use thiserror::Error;
 
 const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
@@ -27,6 +34,45 @@ pub enum ExecError {
 
   #[error("query timeout")]
   Timeout,
+
+  #[error("write in a read session")]
+  WriteInReadSession,
+
+  #[error("graph requires an auth context")]
+  MissingAuthContext,
+}
+
+/// Per-run options of exported graphs.
+#[derive(Clone, Default)]
+pub struct RunOptions {
+  pub exec_config: ExecConfig,
+
+  /// Passed to params of the `auth_context` pseudo-type.
+  pub auth: Option<AuthContext>,
+
+  /// Fields of the output to return. The whole output if `None`.
+  pub selection: Option<Selection>,
+
+  /// Where to report runs slower than the slow query threshold. Runs are not profiled if `None`.
+  pub slow_query: Option<SlowQueryContext>,
+
+  /// If set and the output is a list, its elements are encoded and sent here one at a time, and
+  /// an empty list is returned instead. The size limit then applies to each element, and the
+  /// query timeout also covers waiting for the receiver.
+  pub stream: Option<mpsc::Sender<SerializedVmValue>>,
+}
+
+/// A read-only transaction shared by several graph runs, so that all of them see the same
+/// snapshot.
+pub struct ReadSession<'a> {
+  ctx: &'a ExecContext,
+  kv: &'a dyn KeyValueStore,
+  txn: ReadOnlyTransaction,
+  options: RunOptions,
+}
+
+struct ReadOnlyTransaction {
+  inner: Box<dyn KvTransaction>,
 }
 
 impl ExecContext {
@@ -37,16 +83,53 @@ impl ExecContext {
     params: &[SerializedVmValue],
     serialization_config: &VmValueEncodeConfig,
   ) -> Result<SerializedVmValue> {
-    let run_fut =
-      AssertUnwindSafe(self.run_exported_graph_inner(kv, name, params, serialization_config))
-        .catch_unwind();
-    let timeout_fut = sleep(QUERY_TIMEOUT);
-    tokio::select! {
-      res = run_fut => {
-        res.unwrap_or_else(|_| Err(ExecError::GraphExecutorPanic.into()))
-      }
-      _ = timeout_fut => Err(ExecError::Timeout.into()),
-    }
+    self
+      .run_exported_graph_with_options(kv, name, params, serialization_config, &Default::default())
+      .await
+      .map(|x| x.0)
+  }
+
+  /// Runs an exported graph with the given options, also returning its KV usage.
+  pub async fn run_exported_graph_with_options(
+    &self,
+    kv: &dyn KeyValueStore,
+    name: &str,
+    params: &[SerializedVmValue],
+    serialization_config: &VmValueEncodeConfig,
+    options: &RunOptions,
+  ) -> Result<(SerializedVmValue, ExecUsage)> {
+    guarded(self.run_exported_graph_inner(kv, name, params, serialization_config, options, None))
+      .await
+  }
+
+  /// Opens a read session on `kv`. Graphs run in the session see the snapshot taken when it was
+  /// opened, and fail if they write.
+  pub async fn read_session<'a>(&'a self, kv: &'a dyn KeyValueStore) -> Result<ReadSession<'a>> {
+    Ok(ReadSession {
+      ctx: self,
+      kv,
+      txn: ReadOnlyTransaction {
+        inner: kv.begin_transaction().await?,
+      },
+      options: Default::default(),
+    })
+  }
+
+  /// Opens a read session on `kv` as of `version`, a read version of an earlier transaction.
+  /// Fails if the backend does not support snapshot reads.
+  pub async fn read_session_at<'a>(
+    &'a self,
+    kv: &'a dyn KeyValueStore,
+    version: i64,
+  ) -> Result<ReadSession<'a>> {
+    Ok(ReadSession {
+      ctx: self,
+      kv,
+      txn: ReadOnlyTransaction {
+        inner: kv.begin_transaction_at(version).await?,
+      },
+      options: Default::default(),
+    })
   }
 
   async fn run_exported_graph_inner(
@@ -55,11 +138,74 @@ impl ExecContext {
     name: &str,
     params: &[SerializedVmValue],
     serialization_config: &VmValueEncodeConfig,
-  ) -> Result<SerializedVmValue> {
+    options: &RunOptions,
+    txn: Option<&dyn KvTransaction>,
+  ) -> Result<(SerializedVmValue, ExecUsage)> {
     let graph_index = self.vm().lookup_exported_graph_by_name(name)?;
+    let graph_params = self.decode_params(graph_index, params, options.auth.as_ref())?;
+    let mut executor =
+      Executor::new_with_config(self.vm(), kv, self.type_info(), &options.exec_config);
+    executor.set_yield_fn(|| Box::pin(yield_now()));
+    executor.set_sleep_fn(|x| Box::pin(sleep(x)));
+    executor.set_profiling(options.slow_query.is_some());
+    let start_time = Instant::now();
+    let output = match txn {
+      Some(txn) => {
+        executor
+          .run_graph_in_txn(graph_index, &graph_params, txn)
+          .await
+      }
+      None => executor.run_graph(graph_index, &graph_params).await,
+    };
+    if let Some(slow_query) = &options.slow_query {
+      slow_query.check(
+        name,
+        params,
+        &output,
+        executor.profile(),
+        executor.conflicts(),
+        start_time.elapsed(),
+      );
+    }
+    let output = output?;
+    if let (Some(stream), Some(x)) = (&options.stream, &output) {
+      if let Some(elements) = SerializedVmValue::encode_list_elements(
+        &**x,
+        serialization_config,
+        options.selection.as_ref(),
+      ) {
+        for element in elements {
+          // The receiver is dropped if the client went away.
+          if stream.send(element?).await.is_err() {
+            break;
+          }
+        }
+        return Ok((
+          SerializedVmValue::Tagged(TaggedVmValue::L(vec![])),
+          executor.usage(),
+        ));
+      }
+    }
+    let output = output
+      .map(|x| {
+        SerializedVmValue::encode_selected(&*x, serialization_config, options.selection.as_ref())
+      })
+      .transpose()?;
+    Ok((
+      output.unwrap_or_else(|| SerializedVmValue::Null(None)),
+      executor.usage(),
+    ))
+  }
+
+  fn decode_params<'a>(
+    &'a self,
+    graph_index: usize,
+    params: &[SerializedVmValue],
+    auth: Option<&AuthContext>,
+  ) -> Result<Vec<Arc<VmValue<'a>>>> {
     let param_types = &self.type_info().graphs[graph_index].params;
 
-    // We also need raw types because we need a way to detect the `Schema` pseudo-type.
+    // We also need raw types because we need a way to detect the pseudo-types.
     let raw_param_types = self.vm().script.graphs[graph_index]
       .param_types
       .iter()
@@ -69,23 +215,116 @@ impl ExecContext {
     if param_types.len() != params.len() {
       return Err(ExecError::ParamCountMismatch(param_types.len(), params.len()).into());
     }
-    let mut executor = Executor::new(self.vm(), kv, self.type_info());
-    executor.set_yield_fn(|| Box::pin(yield_now()));
-    executor.set_sleep_fn(|x| Box::pin(sleep(x)));
-    let params = params
+    params
       .iter()
       .zip(param_types)
       .zip(raw_param_types)
       .map(|((v, ty), raw_ty)| match raw_ty {
         VmType::Schema => Ok(self.root_map().clone()),
-        _ => v.decode(ty).map(Arc::new),
+        VmType::AuthContext => auth
+          .map(|x| Arc::new(x.to_vm_value()))
+          .ok_or_else(|| ExecError::MissingAuthContext.into()),
+        _ => v
+          .decode_in(ty, self.vm().schema, self.vm().storage_plan)
+          .map(Arc::new),
       })
-      .collect::<Result<Vec<_>>>()?;
-    let output = executor
-      .run_graph(graph_index, &params)
-      .await?
-      .map(|x| SerializedVmValue::encode(&*x, serialization_config))
-      .transpose()?;
-    Ok(output.unwrap_or_else(|| SerializedVmValue::Null(None)))
+      .collect()
+  }
+}
+
+impl<'a> ReadSession<'a> {
+  /// Sets the options of the graphs run in this session.
+  pub fn with_options(mut self, options: RunOptions) -> Self {
+    self.options = options;
+    self
+  }
+
+  /// The version of the store read by this session, if the backend supports snapshot reads.
+  /// Passing it to `ExecContext::read_session_at` opens a session on the same snapshot.
+  pub async fn read_version(&self) -> Result<Option<i64>> {
+    self.txn.read_version().await
+  }
+
+  pub async fn run_exported_graph(
+    &self,
+    name: &str,
+    params: &[SerializedVmValue],
+    serialization_config: &VmValueEncodeConfig,
+  ) -> Result<SerializedVmValue> {
+    self
+      .run_exported_graph_with_options(name, params, serialization_config, &self.options)
+      .await
+  }
+
+  /// Runs a graph in this session with options other than the ones of the session.
+  pub async fn run_exported_graph_with_options(
+    &self,
+    name: &str,
+    params: &[SerializedVmValue],
+    serialization_config: &VmValueEncodeConfig,
+    options: &RunOptions,
+  ) -> Result<SerializedVmValue> {
+    guarded(self.ctx.run_exported_graph_inner(
+      self.kv,
+      name,
+      params,
+      serialization_config,
+      options,
+      Some(&self.txn as &dyn KvTransaction),
+    ))
+    .await
+    .map(|x| x.0)
+  }
+}
+
+/// Runs a graph with the query timeout, turning panics into errors.
+async fn guarded<T>(fut: impl Future<Output = Result<T>>) -> Result<T> {
+  let run_fut = AssertUnwindSafe(fut).catch_unwind();
+  let timeout_fut = sleep(QUERY_TIMEOUT);
+  tokio::select! {
+    res = run_fut => {
+      res.unwrap_or_else(|_| Err(ExecError::GraphExecutorPanic.into()))
+    }
+    _ = timeout_fut => Err(ExecError::Timeout.into()),
+  }
+}
+
+#[async_trait]
+impl KvTransaction for ReadOnlyTransaction {
+  async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    self.inner.get(key).await
+  }
+
+  async fn put(&self, _key: &[u8], _value: &[u8]) -> Result<()> {
+    Err(ExecError::WriteInReadSession.into())
+  }
+
+  async fn delete(&self, _key: &[u8]) -> Result<()> {
+    Err(ExecError::WriteInReadSession.into())
+  }
+
+  async fn delete_range(&self, _start: &[u8], _end: &[u8]) -> Result<()> {
+    Err(ExecError::WriteInReadSession.into())
+  }
+
+  async fn scan_keys(&self, start: &[u8], end: &[u8]) -> Result<Box<dyn KvKeyIterator>> {
+    self.inner.scan_keys(start, end).await
+  }
+
+  async fn scan<'b>(
+    &'b self,
+    start: &[u8],
+    end: &[u8],
+    options: &ScanOptions,
+  ) -> Result<Box<dyn KvEntryIterator + 'b>> {
+    self.inner.scan(start, end, options).await
+  }
+
+  async fn commit(self: Box<Self>) -> Result<(), KvError> {
+    self.inner.commit().await
+  }
+
+  async fn read_version(&self) -> Result<Option<i64>> {
+    self.inner.read_version().await
   }
 }
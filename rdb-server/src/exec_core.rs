@@ -1,4 +1,4 @@
-use std::{mem::ManuallyDrop, sync::Arc};
+use std::{fmt::Display, mem::ManuallyDrop, sync::Arc};
 
 use anyhow::Result;
 use rdb_analyzer::{
@@ -13,12 +13,38 @@ use rdb_analyzer::{
   schema::compile::CompiledSchema,
   storage_plan::StoragePlan,
 };
+use sha2::{Digest, Sha256};
 
 pub struct SchemaContext {
   pub schema: CompiledSchema,
   pub plan: StoragePlan,
 }
 
+/// Content hash of a compiled script together with the version of the schema it runs against.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct ScriptHash([u8; 32]);
+
+impl ScriptHash {
+  /// Computes the hash over the canonical binary encoding of `script`.
+  ///
+  /// `schema_version` identifies the schema and storage plan, e.g. a deployment id.
+  pub fn compute(script: &TwScript, schema_version: &str) -> Result<Self> {
+    let mut hasher = Sha256::new();
+    hasher.update(&script.encode()?);
+    hasher.update(&(schema_version.len() as u64).to_be_bytes());
+    hasher.update(schema_version.as_bytes());
+    let mut out = [0u8; 32];
+    out.copy_from_slice(&hasher.finalize()[..]);
+    Ok(Self(out))
+  }
+}
+
+impl Display for ScriptHash {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}", hex::encode(&self.0))
+  }
+}
+
 pub struct ExecContext {
   _schema_ctx: Arc<SchemaContext>,
   _script: Box<TwScript>,
@@ -33,8 +59,12 @@ struct DangerousExecContext<'a> {
 
 impl ExecContext {
   pub fn load(schema_ctx: Arc<SchemaContext>, script: &str) -> Result<Self> {
-    let script = Box::new(compile_twscript(script)?);
-    let vm = TwVm::new(&schema_ctx.schema, &schema_ctx.plan, &*script)?;
+    Self::load_compiled(schema_ctx, compile_twscript(script)?)
+  }
+
+  pub fn load_compiled(schema_ctx: Arc<SchemaContext>, script: TwScript) -> Result<Self> {
+    let mut script = Box::new(script);
+    let vm = TwVm::new_optimized(&schema_ctx.schema, &schema_ctx.plan, &mut *script)?;
     let type_info = GlobalTyckContext::new(&vm)?.typeck()?;
     let root_map = Arc::new(generate_root_map(&schema_ctx.schema, &schema_ctx.plan)?);
     let dangerous_ctx = DangerousExecContext {
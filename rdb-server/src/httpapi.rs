@@ -1,27 +1,164 @@
-use std::{fmt::Debug, net::ToSocketAddrs, sync::Arc};
+use std::{convert::Infallible, fmt::Debug, net::ToSocketAddrs, sync::Arc, time::Instant};
 
 use anyhow::Result;
-use bumpalo::Bump;
 use bytes::Bytes;
-use rdb_analyzer::{
-  data::treewalker::serialize::{SerializedVmValue, VmValueEncodeConfig},
-  schema::{compile::compile, grammar::parse},
-  storage_plan::StoragePlan,
+use rdb_analyzer::data::{
+  keyenc::KEY_ENCODING_VERSION,
+  kv::{
+    checksum::{scan_checksums, ChecksumRepair, ChecksumScanOptions},
+    KeyValueStore,
+  },
+  replication::KEYSPACE_END,
+  treewalker::{
+    asm::codegen::compile_twscript,
+    bytecode::TwScript,
+    serialize::{Selection, SerializedVmValue, VmValueEncodeConfig},
+  },
 };
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::mpsc;
 use warp::{
+  http::StatusCode,
   hyper::{Body, Response},
   reject::Reject,
-  reply::Json,
+  reply::{Json, WithStatus},
+  ws::Ws,
   Filter, Rejection,
 };
 
 use crate::{
-  exec_core::{ExecContext, SchemaContext},
+  audit,
+  auth::{authenticate, secret_eq, AuthError},
+  exec::RunOptions,
+  exec_core::ExecContext,
+  key_encoding::{check_key_encoding, record_key_encoding},
   query_cache::QueryCacheKey,
+  quota::QuotaError,
+  slow_query,
   state::get_state,
-  sysquery::{lookup_deployment, lookup_query_script, ns_to_kv_prefix_with_appended_zero},
+  stats::load_namespace_stats,
+  subscription::run_subscription,
+  sysquery::{
+    check_schema_attached, lookup_head_deployment, lookup_query_script,
+    ns_to_kv_prefix_with_appended_zero,
+  },
 };
 
+#[derive(Error, Debug)]
+pub enum HttpApiError {
+  #[error("ad-hoc execution is disabled")]
+  AdhocDisabled,
+
+  #[error("invalid admin token")]
+  InvalidAdminToken,
+
+  #[error("exactly one of `script` and `encoded_script` must be provided")]
+  BadAdhocScript,
+
+  #[error("admin APIs are disabled")]
+  AdminDisabled,
+
+  #[error("data checksums are disabled")]
+  ChecksumsDisabled,
+
+  #[error("namespace `{0}` has no deployment")]
+  NoDeployment(String),
+}
+
+#[derive(Deserialize)]
+struct AdhocRequest {
+  /// Script in assembly text.
+  #[serde(default)]
+  script: Option<String>,
+
+  /// Base64 of a script encoded with `TwScript::encode`.
+  #[serde(default)]
+  encoded_script: Option<String>,
+
+  params: Vec<SerializedVmValue>,
+}
+
+/// Query string of the `query` routes.
+#[derive(Deserialize)]
+struct QueryOptions {
+  /// Selection of the fields of the output to return, e.g. `id items { name }`.
+  #[serde(default)]
+  select: Option<String>,
+
+  /// Version of the store to read, for time-travel reads. The latest version if absent. Graphs
+  /// run at a given version fail if they write.
+  #[serde(default)]
+  read_version: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct BatchQueryRequest {
+  calls: Vec<BatchQueryCall>,
+
+  /// Version of the store to read. The latest version if absent.
+  #[serde(default)]
+  read_version: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct BatchQueryCall {
+  /// Name of the graph.
+  graph: String,
+  params: Vec<SerializedVmValue>,
+
+  /// Selection of the fields of the output to return.
+  #[serde(default)]
+  select: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PrepareRequest {
+  /// A path query, e.g. `.items[id = $id].name`.
+  query: String,
+}
+
+#[derive(Serialize)]
+struct PrepareReply {
+  id: String,
+}
+
+#[derive(Deserialize)]
+struct ExecutePreparedRequest {
+  /// Values of the parameter slots of the statement, in order.
+  params: Vec<SerializedVmValue>,
+}
+
+#[derive(Deserialize)]
+struct ChecksumScanRequest {
+  /// Delete the values whose checksum does not match, so that reads see them as absent instead
+  /// of failing.
+  #[serde(default)]
+  delete_corrupt: bool,
+
+  /// Add checksums to the values stored without one, e.g. before checksums were enabled.
+  #[serde(default)]
+  add_missing: bool,
+}
+
+#[derive(Serialize)]
+struct ChecksumScanReply {
+  values_checked: u64,
+
+  /// Hex-encoded keys of the values whose checksum does not match.
+  corrupt_keys: Vec<String>,
+
+  /// Hex-encoded keys of the values stored without a checksum.
+  missing_checksum_keys: Vec<String>,
+
+  repaired: u64,
+}
+
+#[derive(Serialize)]
+struct UpgradeKeyEncodingReply {
+  key_encoding_version: u32,
+}
+
 struct ApiReject(anyhow::Error);
 
 impl ApiReject {
@@ -44,10 +181,12 @@ pub async fn run_http_server(addr: impl ToSocketAddrs) -> ! {
     .and(warp::path::param()) // namespace
     .and(warp::path::param()) // query script id
     .and(warp::path::param()) // name of the graph
+    .and(warp::query())
     .and(warp::filters::header::exact(
       "Content-Type",
       "application/json",
     ))
+    .and(authorization())
     .and(warp::body::content_length_limit(1024 * 256))
     .and(warp::body::json())
     .and_then(invoke_query);
@@ -55,14 +194,103 @@ pub async fn run_http_server(addr: impl ToSocketAddrs) -> ! {
     .and(warp::path::param()) // namespace
     .and(warp::path::param()) // query script id
     .and(warp::path::param()) // name of the graph
+    .and(warp::query())
     .and(warp::filters::header::exact(
       "Content-Type",
       "application/x-msgpack",
     ))
+    .and(authorization())
     .and(warp::body::content_length_limit(1024 * 256))
     .and(warp::body::bytes())
     .and_then(invoke_query_msgpack);
-  let routes = warp::post().and(query_route_json.or(query_route_msgpack));
+  let batch_query_route = warp::path("batch_query")
+    .and(warp::path::param()) // namespace
+    .and(warp::path::param()) // query script id
+    .and(warp::filters::header::exact(
+      "Content-Type",
+      "application/json",
+    ))
+    .and(authorization())
+    .and(warp::body::content_length_limit(1024 * 256))
+    .and(warp::body::json())
+    .and_then(invoke_batch_query);
+  let adhoc_route = warp::path("adhoc")
+    .and(warp::path::param()) // namespace
+    .and(warp::path::param()) // name of the graph
+    .and(warp::filters::header::optional("X-Rdb-Admin-Token"))
+    .and(warp::filters::header::exact(
+      "Content-Type",
+      "application/json",
+    ))
+    .and(warp::body::content_length_limit(1024 * 1024))
+    .and(warp::body::json())
+    .and_then(invoke_adhoc);
+  let checksum_scan_route = warp::path("checksum_scan")
+    .and(warp::path::param()) // namespace
+    .and(warp::filters::header::optional("X-Rdb-Admin-Token"))
+    .and(warp::filters::header::exact(
+      "Content-Type",
+      "application/json",
+    ))
+    .and(warp::body::content_length_limit(1024))
+    .and(warp::body::json())
+    .and_then(invoke_checksum_scan);
+  let upgrade_key_encoding_route = warp::path("upgrade_key_encoding")
+    .and(warp::path::param()) // namespace
+    .and(warp::filters::header::optional("X-Rdb-Admin-Token"))
+    .and_then(invoke_upgrade_key_encoding);
+  let prepare_route = warp::path("prepare")
+    .and(warp::path::param()) // namespace
+    .and(warp::path::param()) // deployment id
+    .and(warp::filters::header::exact(
+      "Content-Type",
+      "application/json",
+    ))
+    .and(authorization())
+    .and(warp::body::content_length_limit(1024 * 64))
+    .and(warp::body::json())
+    .and_then(prepare_statement);
+  let execute_route = warp::path("execute")
+    .and(warp::path::param()) // namespace
+    .and(warp::path::param()) // prepared statement id
+    .and(warp::filters::header::exact(
+      "Content-Type",
+      "application/json",
+    ))
+    .and(authorization())
+    .and(warp::body::content_length_limit(1024 * 64))
+    .and(warp::body::json())
+    .and_then(execute_prepared);
+  let subscribe_route = warp::path("subscribe")
+    .and(warp::path::param()) // namespace
+    .and(warp::path::param()) // deployment id
+    .and(warp::path::param()) // name of the exported set
+    .and(authorization())
+    .and(warp::ws())
+    .map(
+      |namespace_id: String,
+       deployment_id: String,
+       set_name: String,
+       authorization: Option<String>,
+       ws: Ws| {
+        ws.on_upgrade(move |socket| {
+          run_subscription(socket, namespace_id, deployment_id, set_name, authorization)
+        })
+      },
+    );
+  let routes = warp::post()
+    .and(
+      query_route_json
+        .or(query_route_msgpack)
+        .or(batch_query_route)
+        .or(adhoc_route)
+        .or(checksum_scan_route)
+        .or(upgrade_key_encoding_route)
+        .or(prepare_route)
+        .or(execute_route),
+    )
+    .or(warp::get().and(subscribe_route))
+    .recover(recover_api_error);
   let addr = addr
     .to_socket_addrs()
     .unwrap()
@@ -72,18 +300,31 @@ pub async fn run_http_server(addr: impl ToSocketAddrs) -> ! {
   unreachable!()
 }
 
+/// The `Authorization` header, resolved by `authenticate` once the namespace is known.
+fn authorization() -> impl Filter<Extract = (Option<String>,), Error = Infallible> + Clone {
+  warp::filters::header::optional("Authorization")
+}
+
 async fn invoke_query(
   namespace_id: String,
   query_script_id: String,
   graph_name: String,
+  query_options: QueryOptions,
+  authorization: Option<String>,
   graph_params: Vec<SerializedVmValue>,
 ) -> Result<Json, Rejection> {
+  let selection = parse_selection(query_options.select.as_deref())
+    .map_err(|e| warp::reject::custom(ApiReject::new(e)))?;
   do_invoke_query(
     namespace_id,
     query_script_id,
     graph_name,
-    graph_params,
+    authorization.as_deref(),
+    &graph_params,
     &Default::default(),
+    selection,
+    query_options.read_version,
+    None,
   )
   .await
   .map(|x| warp::reply::json(&x))
@@ -94,20 +335,29 @@ async fn invoke_query_msgpack(
   namespace_id: String,
   query_script_id: String,
   graph_name: String,
+  query_options: QueryOptions,
+  authorization: Option<String>,
   graph_params: Bytes,
 ) -> Result<Response<Body>, Rejection> {
   let graph_params: Vec<SerializedVmValue> = rmp_serde::from_slice(&graph_params)
     .map_err(|e| warp::reject::custom(ApiReject::new(anyhow::Error::from(e))))?;
+  let selection = parse_selection(query_options.select.as_deref())
+    .map_err(|e| warp::reject::custom(ApiReject::new(e)))?;
   do_invoke_query(
     namespace_id,
     query_script_id,
     graph_name,
-    graph_params,
+    authorization.as_deref(),
+    &graph_params,
     &VmValueEncodeConfig {
       enable_bytes: true,
       enable_double: true,
       enable_int64: true,
+      ..Default::default()
     },
+    selection,
+    query_options.read_version,
+    None,
   )
   .await
   .and_then(|x| rmp_serde::to_vec_named(&x).map_err(anyhow::Error::from))
@@ -120,49 +370,467 @@ async fn invoke_query_msgpack(
   .map_err(|e| warp::reject::custom(ApiReject::new(e)))
 }
 
-async fn do_invoke_query(
+async fn invoke_batch_query(
   namespace_id: String,
   query_script_id: String,
+  authorization: Option<String>,
+  req: BatchQueryRequest,
+) -> Result<Json, Rejection> {
+  do_invoke_batch_query(namespace_id, query_script_id, authorization, req)
+    .await
+    .map(|x| warp::reply::json(&x))
+    .map_err(|e| warp::reject::custom(ApiReject::new(e)))
+}
+
+async fn prepare_statement(
+  namespace_id: String,
+  deployment_id: String,
+  authorization: Option<String>,
+  req: PrepareRequest,
+) -> Result<Json, Rejection> {
+  do_prepare_statement(namespace_id, deployment_id, authorization, req)
+    .await
+    .map(|x| warp::reply::json(&x))
+    .map_err(|e| warp::reject::custom(ApiReject::new(e)))
+}
+
+async fn execute_prepared(
+  namespace_id: String,
+  statement_id: String,
+  authorization: Option<String>,
+  req: ExecutePreparedRequest,
+) -> Result<Json, Rejection> {
+  do_execute_prepared(namespace_id, statement_id, authorization, req)
+    .await
+    .map(|x| warp::reply::json(&x))
+    .map_err(|e| warp::reject::custom(ApiReject::new(e)))
+}
+
+/// Replies with `401 Unauthorized` to requests that fail authentication, and with
+/// `429 Too Many Requests` to requests rejected by a quota.
+async fn recover_api_error(r: Rejection) -> Result<WithStatus<Json>, Rejection> {
+  let e = match r.find::<ApiReject>() {
+    Some(x) => &x.0,
+    None => return Err(r),
+  };
+  if let Some(e) = e.downcast_ref::<AuthError>() {
+    let body = serde_json::json!({
+      "error": "unauthorized",
+      "message": e.to_string(),
+    });
+    return Ok(warp::reply::with_status(
+      warp::reply::json(&body),
+      StatusCode::UNAUTHORIZED,
+    ));
+  }
+  let e = match e.downcast_ref::<QuotaError>() {
+    Some(x) => x,
+    None => return Err(r),
+  };
+  let (kind, namespace_id, limit) = match e {
+    QuotaError::RateLimited(ns, limit) => ("rate_limited", ns, limit),
+    QuotaError::KvOpsExceeded(ns, limit) => ("kv_ops_exceeded", ns, limit),
+    QuotaError::StorageExceeded(ns, limit) => ("storage_exceeded", ns, limit),
+  };
+  let body = serde_json::json!({
+    "error": kind,
+    "namespace_id": namespace_id,
+    "limit": limit,
+    "message": e.to_string(),
+  });
+  Ok(warp::reply::with_status(
+    warp::reply::json(&body),
+    StatusCode::TOO_MANY_REQUESTS,
+  ))
+}
+
+async fn invoke_adhoc(
+  namespace_id: String,
   graph_name: String,
-  graph_params: Vec<SerializedVmValue>,
-  serialization_config: &VmValueEncodeConfig,
+  admin_token: Option<String>,
+  req: AdhocRequest,
+) -> Result<Json, Rejection> {
+  do_invoke_adhoc(namespace_id, graph_name, admin_token, req)
+    .await
+    .map(|x| warp::reply::json(&x))
+    .map_err(|e| warp::reject::custom(ApiReject::new(e)))
+}
+
+/// Compiles, typechecks and runs an ad-hoc script against the head deployment of a namespace.
+/// Requires the admin token.
+async fn do_invoke_adhoc(
+  namespace_id: String,
+  graph_name: String,
+  admin_token: Option<String>,
+  req: AdhocRequest,
 ) -> Result<SerializedVmValue> {
   let st = get_state();
+  check_admin_token(admin_token.as_ref(), HttpApiError::AdhocDisabled)?;
+
+  let script = match (&req.script, &req.encoded_script) {
+    (Some(x), None) => compile_twscript(x)?,
+    (None, Some(x)) => TwScript::decode(&base64::decode(x)?)?,
+    _ => return Err(HttpApiError::BadAdhocScript.into()),
+  };
+
+  let deployment_id = lookup_head_deployment(&namespace_id)
+    .await?
+    .ok_or_else(|| HttpApiError::NoDeployment(namespace_id.clone()))?;
+  let kv = namespace_kv(&namespace_id).await?;
+
+  // Ad-hoc scripts are not pooled.
+  let schema_ctx = st
+    .vm_pool
+    .get_or_load_schema(&namespace_id, &deployment_id)
+    .await?;
+  let exec_ctx = ExecContext::load_compiled(schema_ctx, script)?;
+  log::info!(
+    "Running ad-hoc graph `{}` on deployment {} of namespace {}.",
+    graph_name,
+    deployment_id,
+    namespace_id
+  );
+  let (kv, write_stats) = audit::count_writes(kv);
+  let start_time = Instant::now();
+  let res = exec_ctx
+    .run_exported_graph(&*kv, &graph_name, &req.params, &Default::default())
+    .await;
+  audit::record(
+    &namespace_id,
+    &format!("adhoc:{}", deployment_id),
+    &graph_name,
+    &req.params,
+    &res,
+    &write_stats,
+    start_time.elapsed(),
+  )
+  .await;
+  res
+}
+
+/// Fails unless `admin_token` is the admin token of the server. `disabled` is the error returned
+/// if admin APIs are disabled.
+fn check_admin_token(admin_token: Option<&String>, disabled: HttpApiError) -> Result<()> {
+  let expected_token = get_state().admin_token.as_ref().ok_or(disabled)?;
+  match admin_token {
+    Some(x) if secret_eq(x, expected_token) => {}
+    _ => return Err(HttpApiError::InvalidAdminToken.into()),
+  }
+  Ok(())
+}
+
+async fn invoke_checksum_scan(
+  namespace_id: String,
+  admin_token: Option<String>,
+  req: ChecksumScanRequest,
+) -> Result<Json, Rejection> {
+  do_invoke_checksum_scan(namespace_id, admin_token, req)
+    .await
+    .map(|x| warp::reply::json(&x))
+    .map_err(|e| warp::reject::custom(ApiReject::new(e)))
+}
+
+/// Verifies the checksums of all values in the data store of a namespace, and optionally repairs
+/// them. Requires the admin token.
+async fn do_invoke_checksum_scan(
+  namespace_id: String,
+  admin_token: Option<String>,
+  req: ChecksumScanRequest,
+) -> Result<ChecksumScanReply> {
+  let st = get_state();
+  check_admin_token(admin_token.as_ref(), HttpApiError::AdminDisabled)?;
+  let backend_generator = st
+    .checksum_backend_generator
+    .as_ref()
+    .ok_or(HttpApiError::ChecksumsDisabled)?;
+
   let kv_prefix = ns_to_kv_prefix_with_appended_zero(&namespace_id).await?;
-  let kv = (st.data_store_generator)(&kv_prefix);
+  let backend = backend_generator(&kv_prefix);
+  log::info!("Scanning checksums of namespace {}.", namespace_id);
+  let report = scan_checksums(
+    &*backend,
+    &[],
+    &KEYSPACE_END,
+    &ChecksumScanOptions {
+      repair: if req.delete_corrupt {
+        ChecksumRepair::Delete
+      } else {
+        ChecksumRepair::None
+      },
+      add_missing: req.add_missing,
+      ..Default::default()
+    },
+  )
+  .await?;
+
+  let (missing, corrupt): (Vec<_>, Vec<_>) = report
+    .corrupt_values
+    .into_iter()
+    .partition(|x| x.missing_checksum);
+  Ok(ChecksumScanReply {
+    values_checked: report.values_checked,
+    corrupt_keys: corrupt.iter().map(|x| hex::encode(&x.key)).collect(),
+    missing_checksum_keys: missing.iter().map(|x| hex::encode(&x.key)).collect(),
+    repaired: report.repaired,
+  })
+}
 
-  let exec_ctx;
-  if let Some(x) = st
-    .query_cache
-    .get_hot(&namespace_id, &query_script_id)
+async fn invoke_upgrade_key_encoding(
+  namespace_id: String,
+  admin_token: Option<String>,
+) -> Result<Json, Rejection> {
+  do_invoke_upgrade_key_encoding(namespace_id, admin_token)
     .await
-  {
-    exec_ctx = x;
-  } else {
-    let query_script = lookup_query_script(&namespace_id, &query_script_id).await?;
-
-    let qc_key = QueryCacheKey {
-      namespace_id: namespace_id.clone(),
-      query_script_id: query_script_id.clone(),
-      deployment_id: query_script.associated_deployment.clone(),
-      query_script_create_time: query_script.create_time,
+    .map(|x| warp::reply::json(&x))
+    .map_err(|e| warp::reject::custom(ApiReject::new(e)))
+}
+
+/// Records that the keys of a namespace are written with the current key encoding version, so
+/// that the namespace can be used again after a version change. See `record_key_encoding` for
+/// when this is safe. Requires the admin token.
+async fn do_invoke_upgrade_key_encoding(
+  namespace_id: String,
+  admin_token: Option<String>,
+) -> Result<UpgradeKeyEncodingReply> {
+  check_admin_token(admin_token.as_ref(), HttpApiError::AdminDisabled)?;
+  let kv_prefix = ns_to_kv_prefix_with_appended_zero(&namespace_id).await?;
+  record_key_encoding(&kv_prefix).await?;
+  log::info!(
+    "Upgraded namespace {} to key encoding version {}.",
+    namespace_id,
+    KEY_ENCODING_VERSION
+  );
+  Ok(UpgradeKeyEncodingReply {
+    key_encoding_version: KEY_ENCODING_VERSION,
+  })
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn do_invoke_query(
+  namespace_id: String,
+  query_script_id: String,
+  graph_name: String,
+  authorization: Option<&str>,
+  graph_params: &[SerializedVmValue],
+  serialization_config: &VmValueEncodeConfig,
+  selection: Option<Selection>,
+  read_version: Option<i64>,
+  stream: Option<mpsc::Sender<SerializedVmValue>>,
+) -> Result<SerializedVmValue> {
+  let st = get_state();
+  let serialization_config = &VmValueEncodeConfig {
+    size_limit: st.result_size_limit,
+    ..serialization_config.clone()
+  };
+  let auth = authenticate(&namespace_id, authorization).await?;
+  let admission = st.quota.admit(&namespace_id, 1).await?;
+  let kv = namespace_kv(&namespace_id).await?;
+  let exec_ctx = load_query_exec_ctx(&namespace_id, &query_script_id).await?;
+  let can_write = auth.role.can_write();
+  let options = RunOptions {
+    exec_config: admission.exec_config().clone(),
+    auth: Some(auth),
+    selection,
+    slow_query: slow_query::context(&namespace_id, &query_script_id),
+    stream,
+  };
+
+  // Graphs run by readers, or at a past version, fail if they write.
+  if !can_write || read_version.is_some() {
+    let session = match read_version {
+      Some(x) => exec_ctx.read_session_at(&*kv, x).await?,
+      None => exec_ctx.read_session(&*kv).await?,
     };
-    if let Some(x) = st.query_cache.get(&qc_key).await {
-      exec_ctx = x;
-    } else {
-      let deployment =
-        lookup_deployment(&namespace_id, &query_script.associated_deployment).await?;
-      let schema = compile(&parse(&Bump::new(), &deployment.schema)?)?;
-      let plan = StoragePlan::deserialize_compressed(&deployment.plan)?;
-      let schema_ctx = Arc::new(SchemaContext { schema, plan });
-      exec_ctx = Arc::new(ExecContext::load(schema_ctx, &query_script.script)?);
-      log::info!("Loaded query script {:?}.", qc_key);
-      st.query_cache.put(qc_key, exec_ctx.clone()).await;
-    }
+    return session
+      .with_options(options)
+      .run_exported_graph(&graph_name, graph_params, serialization_config)
+      .await
+      .map_err(|e| admission.map_err(e));
   }
 
-  let output = exec_ctx
-    .run_exported_graph(&*kv, &graph_name, &graph_params, serialization_config)
-    .await?;
+  let (kv, write_stats) = audit::count_writes(kv);
+  let start_time = Instant::now();
+  let res = exec_ctx
+    .run_exported_graph_with_options(
+      &*kv,
+      &graph_name,
+      graph_params,
+      serialization_config,
+      &options,
+    )
+    .await
+    .map_err(|e| admission.map_err(e));
+  audit::record(
+    &namespace_id,
+    &query_script_id,
+    &graph_name,
+    graph_params,
+    &res,
+    &write_stats,
+    start_time.elapsed(),
+  )
+  .await;
+  let (output, usage) = res?;
+  st.quota.report_usage(&admission, &usage).await;
   Ok(output)
 }
+
+/// Runs several graphs of a query script against the same snapshot. Graphs that write fail.
+async fn do_invoke_batch_query(
+  namespace_id: String,
+  query_script_id: String,
+  authorization: Option<String>,
+  req: BatchQueryRequest,
+) -> Result<Vec<SerializedVmValue>> {
+  let auth = authenticate(&namespace_id, authorization.as_deref()).await?;
+  let admission = get_state()
+    .quota
+    .admit(&namespace_id, req.calls.len() as u64)
+    .await?;
+  let kv = namespace_kv(&namespace_id).await?;
+  let exec_ctx = load_query_exec_ctx(&namespace_id, &query_script_id).await?;
+  let session = match req.read_version {
+    Some(x) => exec_ctx.read_session_at(&*kv, x).await?,
+    None => exec_ctx.read_session(&*kv).await?,
+  };
+  let serialization_config = VmValueEncodeConfig {
+    size_limit: get_state().result_size_limit,
+    ..Default::default()
+  };
+  let mut options = RunOptions {
+    exec_config: admission.exec_config().clone(),
+    auth: Some(auth),
+    selection: None,
+    slow_query: slow_query::context(&namespace_id, &query_script_id),
+    stream: None,
+  };
+  let mut outputs = Vec::with_capacity(req.calls.len());
+  for call in &req.calls {
+    options.selection = parse_selection(call.select.as_deref())?;
+    outputs.push(
+      session
+        .run_exported_graph_with_options(&call.graph, &call.params, &serialization_config, &options)
+        .await
+        .map_err(|e| admission.map_err(e))?,
+    );
+  }
+  Ok(outputs)
+}
+
+/// Plans a read-only path query against a deployment once, for later execution by id.
+async fn do_prepare_statement(
+  namespace_id: String,
+  deployment_id: String,
+  authorization: Option<String>,
+  req: PrepareRequest,
+) -> Result<PrepareReply> {
+  authenticate(&namespace_id, authorization.as_deref()).await?;
+  let st = get_state();
+  let schema_ctx = st
+    .vm_pool
+    .get_or_load_schema(&namespace_id, &deployment_id)
+    .await?;
+  // Statements are still planned without statistics if they cannot be loaded.
+  let stats = load_namespace_stats(&namespace_id)
+    .await
+    .unwrap_or_else(|e| {
+      log::warn!(
+        "Failed to load statistics of namespace `{}`: {:?}",
+        namespace_id,
+        e
+      );
+      Default::default()
+    });
+  let id = st
+    .prepared_statements
+    .prepare(
+      &namespace_id,
+      &deployment_id,
+      schema_ctx,
+      &stats,
+      &req.query,
+    )
+    .await?;
+  Ok(PrepareReply { id })
+}
+
+/// Runs a prepared statement with the given params, skipping parsing and planning.
+async fn do_execute_prepared(
+  namespace_id: String,
+  statement_id: String,
+  authorization: Option<String>,
+  req: ExecutePreparedRequest,
+) -> Result<Vec<SerializedVmValue>> {
+  authenticate(&namespace_id, authorization.as_deref()).await?;
+  let st = get_state();
+  st.quota.admit(&namespace_id, 1).await?;
+  let statement = st
+    .prepared_statements
+    .get(&namespace_id, &statement_id)
+    .await?;
+  let kv = namespace_kv(&namespace_id).await?;
+  let serialization_config = VmValueEncodeConfig {
+    size_limit: st.result_size_limit,
+    ..Default::default()
+  };
+  statement
+    .execute(&*kv, &req.params, &serialization_config)
+    .await
+}
+
+fn parse_selection(select: Option<&str>) -> Result<Option<Selection>> {
+  Ok(select.map(|x| x.parse::<Selection>()).transpose()?)
+}
+
+pub async fn namespace_kv(namespace_id: &str) -> Result<Box<dyn KeyValueStore>> {
+  let st = get_state();
+  let kv_prefix = ns_to_kv_prefix_with_appended_zero(namespace_id).await?;
+  check_schema_attached(namespace_id).await?;
+  check_key_encoding(namespace_id, &kv_prefix).await?;
+  Ok(
+    st.change_feed
+      .wrap(namespace_id, (st.data_store_generator)(&kv_prefix)),
+  )
+}
+
+async fn load_query_exec_ctx(
+  namespace_id: &str,
+  query_script_id: &str,
+) -> Result<Arc<ExecContext>> {
+  let st = get_state();
+  if let Some(x) = st.query_cache.get_hot(namespace_id, query_script_id).await {
+    return Ok(x);
+  }
+  Ok(
+    load_query_exec_ctx_uncached(namespace_id, query_script_id)
+      .await?
+      .1,
+  )
+}
+
+/// Loads the active version of a query script without looking at hot items.
+pub async fn load_query_exec_ctx_uncached(
+  namespace_id: &str,
+  query_script_id: &str,
+) -> Result<(QueryCacheKey, Arc<ExecContext>)> {
+  let st = get_state();
+  let query_script = lookup_query_script(namespace_id, query_script_id).await?;
+
+  let qc_key = QueryCacheKey {
+    namespace_id: namespace_id.to_string(),
+    query_script_id: query_script_id.to_string(),
+    deployment_id: query_script.associated_deployment.clone(),
+    query_script_activate_time: query_script.activate_time,
+  };
+  if let Some(x) = st.query_cache.get(&qc_key).await {
+    return Ok((qc_key, x));
+  }
+  let script = compile_twscript(&query_script.script)?;
+  let exec_ctx = st
+    .vm_pool
+    .get_or_load(namespace_id, &query_script.associated_deployment, script)
+    .await?;
+  log::info!("Loaded query script {:?}.", qc_key);
+  st.query_cache.put(qc_key.clone(), exec_ctx.clone()).await;
+  Ok((qc_key, exec_ctx))
+}
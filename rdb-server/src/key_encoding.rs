@@ -0,0 +1,71 @@
+use anyhow::Result;
+use rdb_analyzer::data::{keyenc::KEY_ENCODING_VERSION, kv::KeyValueStore};
+use thiserror::Error;
+
+use crate::state::get_state;
+
+/// Key of the key encoding version in the metadata store of a namespace.
+const VERSION_KEY: &[u8] = b"key_encoding_version";
+
+/// Namespaces without a recorded version were created before versions were recorded, and their
+/// keys were written with version 1.
+const UNRECORDED_VERSION: u32 = 1;
+
+#[derive(Error, Debug)]
+pub enum KeyEncodingError {
+  #[error("namespace `{0}` uses key encoding version {1}, expected version {2}")]
+  VersionMismatch(String, u32, u32),
+}
+
+/// Fails if the keys of a namespace were written with another key encoding version than
+/// `KEY_ENCODING_VERSION`, since reading or writing them with this one would corrupt them.
+pub async fn check_key_encoding(namespace_id: &str, kv_prefix: &[u8]) -> Result<()> {
+  let version = read_version(kv_prefix).await?;
+  if version != KEY_ENCODING_VERSION {
+    return Err(
+      KeyEncodingError::VersionMismatch(namespace_id.to_string(), version, KEY_ENCODING_VERSION)
+        .into(),
+    );
+  }
+  Ok(())
+}
+
+/// Records that the keys of a namespace are written with `KEY_ENCODING_VERSION`.
+///
+/// Versions 1 and 2 only encode string keys that contain `0x00` or `0x01` differently, so a
+/// namespace written with version 1 can be upgraded as is if none of its string keys do.
+pub async fn record_key_encoding(kv_prefix: &[u8]) -> Result<()> {
+  let kv = meta_kv(kv_prefix);
+  let txn = kv.begin_transaction().await?;
+  txn
+    .put(VERSION_KEY, &rmp_serde::to_vec(&KEY_ENCODING_VERSION)?)
+    .await?;
+  txn.commit().await?;
+  Ok(())
+}
+
+/// Forgets the recorded key encoding version of a namespace.
+pub async fn clear_key_encoding(kv_prefix: &[u8]) -> Result<()> {
+  let kv = meta_kv(kv_prefix);
+  let txn = kv.begin_transaction().await?;
+  txn.delete(VERSION_KEY).await?;
+  txn.commit().await?;
+  Ok(())
+}
+
+async fn read_version(kv_prefix: &[u8]) -> Result<u32> {
+  let kv = meta_kv(kv_prefix);
+  let txn = kv.begin_transaction().await?;
+  Ok(match txn.get(VERSION_KEY).await? {
+    Some(x) => rmp_serde::from_slice(&x)?,
+    None => UNRECORDED_VERSION,
+  })
+}
+
+/// The metadata of a namespace is stored at `0x02` in its keyspace, next to its data at `0x00`.
+/// `kv_prefix` is the prefix of its data.
+fn meta_kv(kv_prefix: &[u8]) -> Box<dyn KeyValueStore> {
+  let mut kv_prefix = kv_prefix.to_vec();
+  *kv_prefix.last_mut().unwrap() = 0x02;
+  (get_state().data_store_generator)(&kv_prefix)
+}
@@ -1,35 +1,66 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use anyhow::Result;
 use foundationdb::{tuple::Subspace, Database};
-use rdb_analyzer::data::kv::KeyValueStore;
-use rdb_proto::{proto::rdb_control_server::RdbControlServer, tonic::transport::Server};
+use rdb_analyzer::data::{
+  kv::{
+    checksum::ChecksummedKv,
+    encrypted::{EncryptedKv, EncryptionKey, KeyProvider, StaticKeyProvider},
+    KeyValueStore,
+  },
+  treewalker::serialize::ResultSizeLimit,
+};
+use rdb_kv_backend::{
+  foundationdb::FdbKvStore,
+  sqlite::{GlobalSqliteStore, SqliteKvStore},
+};
+use rdb_proto::{
+  proto::{rdb_control_server::RdbControlServer, rdb_query_server::RdbQueryServer},
+  tonic::transport::Server,
+};
 use structopt::StructOpt;
 use tokio::runtime::Runtime;
 
 use crate::{
+  audit,
+  change_feed::ChangeFeed,
   httpapi::run_http_server,
-  kv_backend::{
-    foundationdb::FdbKvStore,
-    sqlite::{GlobalSqliteStore, SqliteKvStore},
-  },
   opt::Opt,
+  prepared::PreparedStatements,
   query_cache::{QueryCache, QueryCacheParams},
+  query_server::QueryServer,
+  quota::QuotaManager,
+  reload::Reloader,
   server::ControlServer,
+  slow_query::SlowQueryConfig,
   state::{set_state, DataStoreGenerator, ServerState},
   system::SystemSchema,
+  vm_pool::VmPool,
 };
+mod audit;
+mod auth;
+mod backfill;
+mod change_feed;
+mod deploy_lock;
 mod exec;
 mod exec_core;
 mod httpapi;
-mod kv_backend;
+mod key_encoding;
 mod opt;
+mod prepared;
 mod query_cache;
+mod query_server;
+mod quota;
+mod reload;
 mod server;
+mod slow_query;
 mod state;
+mod stats;
+mod subscription;
 mod sysquery;
 mod system;
 mod util;
+mod vm_pool;
 
 fn main() {
   pretty_env_logger::init_timed();
@@ -47,7 +78,7 @@ fn main() {
 async fn run() -> Result<()> {
   let opt = Opt::from_args();
 
-  let data_store_generator: DataStoreGenerator;
+  let mut data_store_generator: DataStoreGenerator;
   let system_store: Box<dyn KeyValueStore>;
   let system_metadata_store: Box<dyn KeyValueStore>;
   if let Some(x) = &opt.fdb_cluster {
@@ -97,21 +128,72 @@ async fn run() -> Result<()> {
     panic!("no kv backend selected");
   }
 
+  // Checksums go below encryption, so that they cover the stored ciphertext.
+  let mut checksum_backend_generator: Option<DataStoreGenerator> = None;
+  if opt.data_checksums {
+    let inner = Arc::new(data_store_generator);
+    let backend = inner.clone();
+    checksum_backend_generator = Some(Box::new(move |namespace| backend(namespace)));
+    data_store_generator =
+      Box::new(move |namespace| Box::new(ChecksummedKv::new(inner(namespace))));
+    log::info!("Data checksums enabled.");
+  }
+
+  if let Some(x) = &opt.data_encryption_key_file {
+    let key = hex::decode(std::fs::read_to_string(x)?.trim())?;
+    if key.len() != std::mem::size_of::<EncryptionKey>() {
+      panic!("data encryption key must be 256 bits");
+    }
+    let mut k: EncryptionKey = Default::default();
+    k.copy_from_slice(&key);
+    let provider: Arc<dyn KeyProvider> = Arc::new(StaticKeyProvider::new(0, k));
+    let inner = data_store_generator;
+    data_store_generator =
+      Box::new(move |namespace| Box::new(EncryptedKv::new(inner(namespace), provider.clone())));
+    log::info!("Data encryption enabled.");
+  }
+
   let system_schema = SystemSchema::new(
     opt.migration_hash.clone(),
+    opt.bootstrap,
     &*system_store,
     &*system_metadata_store,
   )
   .await;
+  if opt.bootstrap {
+    log::info!("Bootstrap completed.");
+    return Ok(());
+  }
+
   let query_cache = QueryCache::new(QueryCacheParams {
     process_memory_threshold_kb: opt.process_memory_threshold_kb,
   });
 
   set_state(ServerState {
     data_store_generator,
+    checksum_backend_generator,
     system_store,
     system_schema,
     query_cache,
+    vm_pool: VmPool::new(),
+    prepared_statements: PreparedStatements::new(),
+    change_feed: ChangeFeed::new(),
+    quota: QuotaManager::new(),
+    reloader: Reloader::new(),
+    admin_token: opt.admin_token.clone(),
+    require_auth: opt.require_auth,
+    result_size_limit: if opt.max_result_size_kb == 0 {
+      None
+    } else {
+      Some(ResultSizeLimit {
+        max_bytes: (opt.max_result_size_kb * 1024) as usize,
+        truncate_lists: opt.truncate_large_results,
+      })
+    },
+    slow_query: opt.slow_query_threshold_ms.map(|x| SlowQueryConfig {
+      threshold: Duration::from_millis(x),
+      store: opt.store_slow_queries,
+    }),
   });
 
   log::info!("RefineDB started.");
@@ -119,8 +201,27 @@ async fn run() -> Result<()> {
   let http_listen = opt.http_listen.clone();
   tokio::spawn(async move { run_http_server(http_listen).await });
 
+  if opt.audit_retention_days != 0 {
+    let retention = Duration::from_secs(opt.audit_retention_days * 86400);
+    tokio::spawn(async move { audit::run_retention(retention).await });
+    if opt.store_slow_queries {
+      tokio::spawn(async move { slow_query::run_retention(retention).await });
+    }
+  }
+
+  if opt.stats_interval_secs != 0 {
+    let interval = Duration::from_secs(opt.stats_interval_secs);
+    tokio::spawn(async move { stats::run_collection(interval).await });
+  }
+
+  if opt.backfill_interval_secs != 0 {
+    let interval = Duration::from_secs(opt.backfill_interval_secs);
+    tokio::spawn(async move { backfill::run_backfill(interval).await });
+  }
+
   Server::builder()
     .add_service(RdbControlServer::new(ControlServer))
+    .add_service(RdbQueryServer::new(QueryServer))
     .serve(opt.grpc_listen.parse()?)
     .await?;
 
@@ -15,6 +15,17 @@ pub struct Opt {
   #[structopt(long)]
   pub sqlite_db: Option<String>,
 
+  /// Path to a file with a hex-encoded 256-bit key. If set, values in namespace data stores are
+  /// encrypted with it.
+  #[structopt(long)]
+  pub data_encryption_key_file: Option<String>,
+
+  /// Store values in namespace data stores with a checksum, and verify it on every read. Values
+  /// written before this was enabled fail to read until checksums are added to them with the
+  /// `checksum_scan` admin API.
+  #[structopt(long)]
+  pub data_checksums: bool,
+
   /// GRPC listen address.
   #[structopt(long)]
   pub grpc_listen: String,
@@ -23,6 +34,10 @@ pub struct Opt {
   #[structopt(long)]
   pub http_listen: String,
 
+  /// Initialize or migrate the system schema, and then exit.
+  #[structopt(long)]
+  pub bootstrap: bool,
+
   /// Migration hash.
   #[structopt(long)]
   pub migration_hash: Option<String>,
@@ -30,4 +45,45 @@ pub struct Opt {
   /// Process memory threshold (in KiB) for query cache.
   #[structopt(long, default_value = "524288")]
   pub process_memory_threshold_kb: u64,
+
+  /// Token for admin HTTP APIs, e.g. ad-hoc script execution. Admin APIs are disabled if unset.
+  #[structopt(long)]
+  pub admin_token: Option<String>,
+
+  /// Reject query requests without a valid API token. Otherwise they run as anonymous writers.
+  #[structopt(long)]
+  pub require_auth: bool,
+
+  /// Days to keep audit log entries and stored slow queries for. Entries are kept forever if zero.
+  #[structopt(long, default_value = "30")]
+  pub audit_retention_days: u64,
+
+  /// Seconds between two collections of the statistics used to plan prepared statements.
+  /// Statistics are not collected if zero.
+  #[structopt(long, default_value = "3600")]
+  pub stats_interval_secs: u64,
+
+  /// Seconds between two checks for pending sort key index backfills, which resume backfills
+  /// interrupted by a restart. Backfills of new deployments start right away. Only deployments
+  /// start backfills if zero.
+  #[structopt(long, default_value = "600")]
+  pub backfill_interval_secs: u64,
+
+  /// Maximum size (in KiB) of the output of a query. Unlimited if zero.
+  #[structopt(long, default_value = "16384")]
+  pub max_result_size_kb: u64,
+
+  /// Cut lists short in query outputs larger than `max-result-size-kb`, instead of failing the
+  /// query.
+  #[structopt(long)]
+  pub truncate_large_results: bool,
+
+  /// Log query graph runs that take at least this many milliseconds, with their profile. Runs are
+  /// profiled only if set, which adds a small overhead to each node.
+  #[structopt(long)]
+  pub slow_query_threshold_ms: Option<u64>,
+
+  /// Also store slow queries in the system schema, for `audit-retention-days`.
+  #[structopt(long)]
+  pub store_slow_queries: bool,
 }
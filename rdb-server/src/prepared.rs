@@ -0,0 +1,198 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use lru::LruCache;
+use rdb_analyzer::data::{
+  kv::KeyValueStore,
+  query::{
+    exec::exec_query_plan,
+    parser::parse_statement,
+    planner::{QueryPlan, QueryPlanError, QueryPlanner},
+  },
+  stats::SchemaStats,
+  treewalker::{
+    serialize::{SerializedVmValue, VmValueEncodeConfig},
+    vm_value::{VmType, VmValue},
+  },
+  value::PrimitiveValue,
+};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio::sync::Mutex;
+
+use crate::exec_core::SchemaContext;
+
+/// Max number of prepared statements kept per server.
+const PREPARED_STATEMENT_CACHE_SIZE: usize = 4096;
+
+#[derive(Error, Debug)]
+pub enum PreparedStatementError {
+  #[error("prepared statement not found: `{0}`")]
+  NotFound(String),
+
+  #[error("only reads and aggregates can be prepared")]
+  NotReadOnly,
+
+  #[error("parameter {0} must be a non-null primitive")]
+  BadParam(usize),
+}
+
+/// A path query planned once and executed by id with different parameters.
+pub struct PreparedStatement {
+  pub deployment_id: String,
+  schema_ctx: Arc<SchemaContext>,
+  plan: QueryPlan,
+}
+
+/// Prepared statements of all namespaces, kept in memory.
+///
+/// Ids are derived from the namespace, deployment and query text, so preparing the same query
+/// twice returns the same id. Statements are dropped when their deployment is invalidated or
+/// evicted, after which clients must prepare them again.
+pub struct PreparedStatements {
+  statements: Mutex<LruCache<(String, String), Arc<PreparedStatement>>>,
+}
+
+impl PreparedStatements {
+  pub fn new() -> Self {
+    Self {
+      statements: Mutex::new(LruCache::new(PREPARED_STATEMENT_CACHE_SIZE)),
+    }
+  }
+
+  /// Parses and plans `query` against a deployment, and returns the id of the statement. Scans are
+  /// chosen from `stats` where the sets have statistics.
+  pub async fn prepare(
+    &self,
+    namespace_id: &str,
+    deployment_id: &str,
+    schema_ctx: Arc<SchemaContext>,
+    stats: &SchemaStats,
+    query: &str,
+  ) -> Result<String> {
+    let id = statement_id(namespace_id, deployment_id, query);
+    let key = (namespace_id.to_string(), id.clone());
+    if self.statements.lock().await.get(&key).is_some() {
+      return Ok(id);
+    }
+
+    let mut planner = QueryPlanner::with_stats(&schema_ctx.schema, stats);
+    planner.add_statement(&parse_statement(query)?)?;
+    let plan = planner.finish()?;
+    if !plan.is_read_only() {
+      return Err(PreparedStatementError::NotReadOnly.into());
+    }
+    log::info!(
+      "Prepared statement {} on deployment {} of namespace {}.",
+      id,
+      deployment_id,
+      namespace_id
+    );
+    self.statements.lock().await.put(
+      key,
+      Arc::new(PreparedStatement {
+        deployment_id: deployment_id.to_string(),
+        schema_ctx,
+        plan,
+      }),
+    );
+    Ok(id)
+  }
+
+  pub async fn get(&self, namespace_id: &str, id: &str) -> Result<Arc<PreparedStatement>> {
+    self
+      .statements
+      .lock()
+      .await
+      .get(&(namespace_id.to_string(), id.to_string()))
+      .cloned()
+      .ok_or_else(|| PreparedStatementError::NotFound(id.to_string()).into())
+  }
+
+  /// Invalidation hook. Called when a deployment is deleted or replaced.
+  pub async fn invalidate_deployment(&self, namespace_id: &str, deployment_id: &str) {
+    let mut statements = self.statements.lock().await;
+    let removed = statements
+      .iter()
+      .filter(|((ns, _), x)| ns == namespace_id && x.deployment_id == deployment_id)
+      .map(|(k, _)| k.clone())
+      .collect::<Vec<_>>();
+    for k in removed {
+      statements.pop(&k);
+    }
+  }
+
+  /// Invalidation hook. Called when a namespace is deleted.
+  pub async fn invalidate_namespace(&self, namespace_id: &str) {
+    let mut statements = self.statements.lock().await;
+    let removed = statements
+      .iter()
+      .filter(|((ns, _), _)| ns == namespace_id)
+      .map(|(k, _)| k.clone())
+      .collect::<Vec<_>>();
+    for k in removed {
+      statements.pop(&k);
+    }
+  }
+}
+
+impl Default for PreparedStatements {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl PreparedStatement {
+  /// Binds `params` to the parameter slots of the plan, in order, and runs it in a transaction
+  /// that is never committed. Returns the fulfilled values.
+  pub async fn execute(
+    &self,
+    kv: &dyn KeyValueStore,
+    params: &[SerializedVmValue],
+    config: &VmValueEncodeConfig,
+  ) -> Result<Vec<SerializedVmValue>> {
+    if params.len() != self.plan.params.len() {
+      return Err(
+        QueryPlanError::ParamCountMismatch {
+          expected: self.plan.params.len(),
+          got: params.len(),
+        }
+        .into(),
+      );
+    }
+    let args = params
+      .iter()
+      .zip(&self.plan.params)
+      .enumerate()
+      .map(|(i, (arg, param))| {
+        match arg
+          .decode(&VmType::Primitive(param.ty))
+          .map_err(|_| PreparedStatementError::BadParam(i))?
+        {
+          VmValue::Primitive(x) => Ok(x),
+          _ => Err(PreparedStatementError::BadParam(i).into()),
+        }
+      })
+      .collect::<Result<Vec<PrimitiveValue>>>()?;
+    let plan = self.plan.bind(&args)?;
+
+    let txn = kv.begin_transaction().await?;
+    exec_query_plan(
+      &self.schema_ctx.schema,
+      &self.schema_ctx.plan,
+      &*txn,
+      &plan,
+      config,
+    )
+    .await
+  }
+}
+
+fn statement_id(namespace_id: &str, deployment_id: &str, query: &str) -> String {
+  let mut hasher = Sha256::new();
+  for x in &[namespace_id, deployment_id, query] {
+    hasher.update(&(x.len() as u64).to_be_bytes());
+    hasher.update(x.as_bytes());
+  }
+  hex::encode(&hasher.finalize()[..16])
+}
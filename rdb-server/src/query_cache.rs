@@ -17,9 +17,13 @@ const QUERY_CACHE_SHRINK_STEP_SIZE: usize = 16;
 
 const HOT_ITEM_TTL: Duration = Duration::from_secs(3);
 
+/// Replaced contexts still used by requests after this long are logged as stuck.
+const DRAIN_WARN_THRESHOLD: Duration = Duration::from_secs(60);
+
 pub struct QueryCache {
   items: Mutex<LruCache<QueryCacheKey, Arc<ExecContext>>>,
   hot_items: Mutex<LruCache<(String, String), HotItem>>,
+  draining: Mutex<Vec<DrainingItem>>,
   params: QueryCacheParams,
 }
 
@@ -28,6 +32,14 @@ struct HotItem {
   create_time: Instant,
 }
 
+/// A context replaced by `swap` that may still be used by in-flight requests.
+struct DrainingItem {
+  key: QueryCacheKey,
+  exec_ctx: Weak<ExecContext>,
+  retire_time: Instant,
+  warned: bool,
+}
+
 #[derive(Clone, Debug)]
 pub struct QueryCacheParams {
   pub process_memory_threshold_kb: u64,
@@ -44,8 +56,8 @@ pub struct QueryCacheKey {
   /// User-provided query script id.
   pub query_script_id: String,
 
-  /// In case the query script is updated.
-  pub query_script_create_time: i64,
+  /// In case the query script is updated or another version is activated.
+  pub query_script_activate_time: i64,
 }
 
 impl QueryCache {
@@ -53,6 +65,7 @@ impl QueryCache {
     let me = Arc::new(Self {
       items: Mutex::new(LruCache::unbounded()),
       hot_items: Mutex::new(LruCache::unbounded()),
+      draining: Mutex::new(Vec::new()),
       params,
     });
     let me_weak = Arc::downgrade(&me);
@@ -100,6 +113,99 @@ impl QueryCache {
     self.items.lock().await.put(key, value);
   }
 
+  /// Makes `value` the context of its query script for subsequent requests, and drops the other
+  /// cached versions of the query script.
+  ///
+  /// Requests that already hold an old version finish on it. Old versions are tracked until
+  /// dropped.
+  pub async fn swap(&self, key: QueryCacheKey, value: Arc<ExecContext>) {
+    let hot_key = (key.namespace_id.clone(), key.query_script_id.clone());
+    let mut items = self.items.lock().await;
+    let old = items
+      .iter()
+      .filter(|(k, _)| {
+        k.namespace_id == key.namespace_id && k.query_script_id == key.query_script_id && **k != key
+      })
+      .map(|(k, _)| k.clone())
+      .collect::<Vec<_>>();
+    let old = old
+      .into_iter()
+      .filter_map(|k| items.pop(&k).map(|v| (k, v)))
+      .collect::<Vec<_>>();
+    items.put(key, value.clone());
+    drop(items);
+
+    self.hot_items.lock().await.put(
+      hot_key,
+      HotItem {
+        exec_ctx: value,
+        create_time: Instant::now(),
+      },
+    );
+
+    let now = Instant::now();
+    let mut draining = self.draining.lock().await;
+    for (key, exec_ctx) in old {
+      draining.push(DrainingItem {
+        key,
+        exec_ctx: Arc::downgrade(&exec_ctx),
+        retire_time: now,
+        warned: false,
+      });
+    }
+  }
+
+  /// Invalidation hook. Called when a query script is updated or deleted.
+  pub async fn invalidate_query_script(&self, namespace_id: &str, query_script_id: &str) {
+    self
+      .hot_items
+      .lock()
+      .await
+      .pop(&(namespace_id.to_string(), query_script_id.to_string()));
+    self
+      .remove_items(|k| k.namespace_id == namespace_id && k.query_script_id == query_script_id)
+      .await;
+  }
+
+  /// Invalidation hook. Called when a deployment is deleted.
+  pub async fn invalidate_deployment(&self, namespace_id: &str, deployment_id: &str) {
+    // Hot items don't carry the deployment id and are short-lived. Drop all of them for this namespace.
+    self.remove_hot_items(namespace_id).await;
+    self
+      .remove_items(|k| k.namespace_id == namespace_id && k.deployment_id == deployment_id)
+      .await;
+  }
+
+  /// Invalidation hook. Called when a namespace is deleted.
+  pub async fn invalidate_namespace(&self, namespace_id: &str) {
+    self.remove_hot_items(namespace_id).await;
+    self.remove_items(|k| k.namespace_id == namespace_id).await;
+  }
+
+  async fn remove_hot_items(&self, namespace_id: &str) {
+    let mut hot_items = self.hot_items.lock().await;
+    let removed = hot_items
+      .iter()
+      .filter(|((ns, _), _)| ns == namespace_id)
+      .map(|(k, _)| k.clone())
+      .collect::<Vec<_>>();
+    for k in removed {
+      hot_items.pop(&k);
+    }
+  }
+
+  async fn remove_items(&self, f: impl Fn(&QueryCacheKey) -> bool) {
+    let mut items = self.items.lock().await;
+    let removed = items
+      .iter()
+      .filter(|(k, _)| f(*k))
+      .map(|(k, _)| k.clone())
+      .collect::<Vec<_>>();
+    for k in removed {
+      items.pop(&k);
+    }
+  }
+
   async fn gc(me: Weak<Self>) {
     let system = System::new_all();
     loop {
@@ -136,7 +242,31 @@ impl QueryCache {
         }
       }
 
-      // Step 2: Process memory threshold.
+      // Step 2: Report replaced contexts no longer used by any request.
+      {
+        let mut draining = me.draining.lock().await;
+        let now = Instant::now();
+        let mut i = 0;
+        while i < draining.len() {
+          let x = &mut draining[i];
+          if x.exec_ctx.strong_count() == 0 {
+            log::info!("Drained replaced query script {:?}.", x.key);
+            draining.swap_remove(i);
+            continue;
+          }
+          if !x.warned && now.duration_since(x.retire_time) > DRAIN_WARN_THRESHOLD {
+            log::warn!(
+              "Replaced query script {:?} is still in use after {:?}.",
+              x.key,
+              DRAIN_WARN_THRESHOLD
+            );
+            x.warned = true;
+          }
+          i += 1;
+        }
+      }
+
+      // Step 3: Process memory threshold.
       let process = system
         .get_process(get_current_pid().unwrap())
         .expect("cannot get current process");
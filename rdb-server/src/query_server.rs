@@ -0,0 +1,219 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::{channel::mpsc as stream_mpsc, SinkExt};
+use rdb_analyzer::data::{
+  kv::KvError,
+  treewalker::{
+    exec::ExecError as GraphExecError,
+    serialize::{Selection, SerializeError, SerializedVmValue, TaggedVmValue, VmValueEncodeConfig},
+    typeck::TypeckError,
+    vm::VmError,
+  },
+};
+use rdb_proto::proto::{rdb_query_server::RdbQuery, *};
+use rdb_proto::tonic::{metadata::MetadataMap, Request, Response, Status};
+use tokio::sync::mpsc;
+
+use crate::{
+  auth::AuthError, exec::ExecError, httpapi::do_invoke_query, quota::QuotaError,
+  sysquery::SysQueryError,
+};
+
+/// Max number of list elements per chunk in streaming responses.
+const STREAM_CHUNK_SIZE: usize = 64;
+
+pub struct QueryServer;
+
+type ChunkStream = stream_mpsc::Receiver<Result<ExecuteQueryScriptChunk, Status>>;
+
+#[async_trait]
+impl RdbQuery for QueryServer {
+  async fn execute_query_script(
+    &self,
+    request: Request<ExecuteQueryScriptRequest>,
+  ) -> Result<Response<ExecuteQueryScriptReply>, Status> {
+    let output = execute(request.metadata(), request.get_ref(), None).await?;
+    let value = encode(&output)?;
+    Ok(Response::new(ExecuteQueryScriptReply { value }))
+  }
+
+  type ExecuteQueryScriptStreamingStream = ChunkStream;
+
+  /// Elements of a list output are sent in chunks as they are encoded, so that the encoded list
+  /// is never held as a whole. Other outputs are sent as a single chunk.
+  async fn execute_query_script_streaming(
+    &self,
+    request: Request<ExecuteQueryScriptRequest>,
+  ) -> Result<Response<Self::ExecuteQueryScriptStreamingStream>, Status> {
+    let metadata = request.metadata().clone();
+    let r = request.into_inner();
+    let (mut chunk_tx, chunk_rx) = stream_mpsc::channel(1);
+    tokio::spawn(async move {
+      let (element_tx, mut element_rx) = mpsc::channel(STREAM_CHUNK_SIZE);
+      let run = execute(&metadata, &r, Some(element_tx));
+      let mut forward_tx = chunk_tx.clone();
+      let forward = async move {
+        let mut list_elements = Vec::with_capacity(STREAM_CHUNK_SIZE);
+        while let Some(x) = element_rx.recv().await {
+          list_elements.push(encode(&x)?);
+          if list_elements.len() == STREAM_CHUNK_SIZE
+            && !send_elements(&mut forward_tx, &mut list_elements).await
+          {
+            // The client went away. Dropping the receiver stops the run.
+            return Ok(());
+          }
+        }
+        send_elements(&mut forward_tx, &mut list_elements).await;
+        Ok::<_, Status>(())
+      };
+      let (output, forwarded) = futures::join!(run, forward);
+
+      // The output of a list is empty after its elements were streamed.
+      let last = forwarded.and(output).and_then(|output| match output {
+        SerializedVmValue::Tagged(TaggedVmValue::L(x)) if x.is_empty() => Ok(None),
+        _ => Ok(Some(ExecuteQueryScriptChunk {
+          value: encode(&output)?,
+          list_elements: vec![],
+        })),
+      });
+      if let Some(x) = last.transpose() {
+        let _ = chunk_tx.send(x).await;
+      }
+    });
+    Ok(Response::new(chunk_rx))
+  }
+}
+
+/// Sends the buffered list elements as a chunk. Returns `false` if the client went away.
+async fn send_elements(
+  tx: &mut stream_mpsc::Sender<Result<ExecuteQueryScriptChunk, Status>>,
+  list_elements: &mut Vec<Vec<u8>>,
+) -> bool {
+  if list_elements.is_empty() {
+    return true;
+  }
+  let chunk = ExecuteQueryScriptChunk {
+    value: vec![],
+    list_elements: std::mem::replace(list_elements, Vec::with_capacity(STREAM_CHUNK_SIZE)),
+  };
+  tx.send(Ok(chunk)).await.is_ok()
+}
+
+async fn execute(
+  metadata: &MetadataMap,
+  r: &ExecuteQueryScriptRequest,
+  stream: Option<mpsc::Sender<SerializedVmValue>>,
+) -> Result<SerializedVmValue, Status> {
+  let graph_params: Vec<SerializedVmValue> = rmp_serde::from_slice(&r.params)
+    .map_err(|e| Status::invalid_argument(format!("cannot decode params: {}", e)))?;
+  let authorization = metadata
+    .get("authorization")
+    .map(|x| x.to_str())
+    .transpose()
+    .map_err(|_| Status::unauthenticated("bad authorization metadata"))?;
+  let selection = if r.select.is_empty() {
+    None
+  } else {
+    Some(
+      r.select
+        .parse::<Selection>()
+        .map_err(|e| Status::invalid_argument(format!("{}", e)))?,
+    )
+  };
+  do_invoke_query(
+    r.namespace_id.clone(),
+    r.query_script_id.clone(),
+    r.graph_name.clone(),
+    authorization,
+    &graph_params,
+    &VmValueEncodeConfig {
+      enable_bytes: true,
+      enable_double: true,
+      enable_int64: true,
+      ..Default::default()
+    },
+    selection,
+    if r.read_version == 0 {
+      None
+    } else {
+      Some(r.read_version)
+    },
+    stream,
+  )
+  .await
+  .map_err(exec_error_to_status)
+}
+
+fn encode(x: &SerializedVmValue) -> Result<Vec<u8>, Status> {
+  rmp_serde::to_vec_named(x).map_err(|e| Status::internal(format!("{}", e)))
+}
+
+/// Maps errors from query execution to gRPC status codes.
+pub fn exec_error_to_status(e: anyhow::Error) -> Status {
+  let message = format!("{}", e);
+  if let Some(x) = e.downcast_ref::<ExecError>() {
+    return match x {
+      ExecError::Timeout => Status::deadline_exceeded(message),
+      ExecError::ParamCountMismatch(..) => Status::invalid_argument(message),
+      ExecError::GraphExecutorPanic => Status::internal(message),
+      ExecError::WriteInReadSession => Status::permission_denied(message),
+      ExecError::MissingAuthContext => Status::invalid_argument(message),
+    };
+  }
+  if e.downcast_ref::<AuthError>().is_some() {
+    return Status::unauthenticated(message);
+  }
+  if e.downcast_ref::<QuotaError>().is_some() {
+    return Status::resource_exhausted(message);
+  }
+  if let Some(x) = e.downcast_ref::<GraphExecError>() {
+    return match x {
+      GraphExecError::ScriptThrownError(..)
+      | GraphExecError::ScriptThrownNull(_)
+      | GraphExecError::AssertionFailed { .. }
+      | GraphExecError::NullUnwrapped => Status::failed_precondition(message),
+      GraphExecError::InvalidParam { .. } => Status::invalid_argument(message),
+      GraphExecError::ConflictAfterRetries(_) => Status::aborted(message),
+      // Retryable once the backfill of the index completes.
+      GraphExecError::SortIndexNotReady(_) => Status::unavailable(message),
+      GraphExecError::MaxRecursionDepthExceeded(_)
+      | GraphExecError::KvOpLimitExceeded(_)
+      | GraphExecError::WriteLimitExceeded(_) => Status::resource_exhausted(message),
+      GraphExecError::NotImplemented(_)
+      | GraphExecError::FreshTableOrSetNotSupported
+      | GraphExecError::ExportTypeNotSupported => Status::unimplemented(message),
+      _ => Status::internal(message),
+    };
+  }
+  if let Some(x) = e.downcast_ref::<SysQueryError>() {
+    return match x {
+      SysQueryError::SchemaDetached => Status::failed_precondition(message),
+      _ => Status::not_found(message),
+    };
+  }
+  if let Some(x) = e.downcast_ref::<VmError>() {
+    return match x {
+      VmError::ExportedGraphNotFound(_) => Status::not_found(message),
+      VmError::BadParamPattern(..) => Status::invalid_argument(message),
+    };
+  }
+  if e.downcast_ref::<TypeckError>().is_some() {
+    return Status::invalid_argument(message);
+  }
+  if let Some(x) = e.downcast_ref::<SerializeError>() {
+    return match x {
+      SerializeError::ResultTooLarge(_) => Status::resource_exhausted(message),
+      _ => Status::invalid_argument(message),
+    };
+  }
+  if let Some(x) = e.downcast_ref::<KvError>() {
+    return match x {
+      KvError::Conflict(_) => Status::aborted(message),
+      KvError::CommitStateUnknown => Status::unavailable(message),
+      KvError::SnapshotUnsupported => Status::unimplemented(message),
+      KvError::SnapshotUnavailable(_) => Status::failed_precondition(message),
+    };
+  }
+  log::error!("query error: {:?}", e);
+  Status::internal(message)
+}
@@ -0,0 +1,260 @@
+use std::{
+  collections::HashMap,
+  sync::Arc,
+  time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use rdb_analyzer::data::{
+  kv::ScanOptions,
+  treewalker::{
+    exec::{ExecConfig, ExecError as GraphExecError},
+    usage::ExecUsage,
+  },
+};
+use thiserror::Error;
+use tokio::sync::Mutex;
+
+use crate::{
+  state::get_state,
+  sysquery::{lookup_quota, ns_to_kv_prefix_with_appended_zero, Quota},
+};
+
+/// How long a loaded quota is used before reloading it from the system store.
+const QUOTA_TTL: Duration = Duration::from_secs(5);
+
+/// How long a storage measurement is used before measuring again. Bytes written in between are
+/// added to the last measurement.
+const STORAGE_USAGE_TTL: Duration = Duration::from_secs(60);
+
+/// Max number of entries read in a single transaction when measuring storage usage.
+const STORAGE_SCAN_BATCH_SIZE: usize = 1000;
+
+#[derive(Error, Debug)]
+pub enum QuotaError {
+  #[error("namespace `{0}` exceeded its rate limit of {1} execution(s) per second")]
+  RateLimited(String, u64),
+
+  #[error("namespace `{0}` exceeded its limit of {1} kv op(s) per execution")]
+  KvOpsExceeded(String, u64),
+
+  #[error("namespace `{0}` exceeded its storage quota of {1} byte(s)")]
+  StorageExceeded(String, u64),
+}
+
+/// Enforces per-namespace quotas.
+///
+/// Rate limits and storage usage are tracked in memory, so with several server instances each
+/// one enforces the full rate limit on its own.
+pub struct QuotaManager {
+  namespaces: Mutex<HashMap<String, NamespaceState>>,
+}
+
+struct NamespaceState {
+  quota: Quota,
+  quota_load_time: Instant,
+
+  /// Token bucket of the rate limiter. Holds at most one second of executions.
+  tokens: f64,
+  last_refill: Instant,
+
+  storage_bytes: u64,
+  storage_measure_time: Option<Instant>,
+  measuring: bool,
+}
+
+/// Limits granted to admitted executions.
+pub struct Admission {
+  namespace_id: String,
+  quota: Quota,
+  exec_config: ExecConfig,
+}
+
+impl QuotaManager {
+  pub fn new() -> Arc<Self> {
+    Arc::new(Self {
+      namespaces: Mutex::new(HashMap::new()),
+    })
+  }
+
+  /// Admits `executions` graph runs in a namespace, or fails with `QuotaError::RateLimited`.
+  pub async fn admit(self: &Arc<Self>, namespace_id: &str, executions: u64) -> Result<Admission> {
+    let now = Instant::now();
+    let stale = match self.namespaces.lock().await.get(namespace_id) {
+      Some(x) => now.duration_since(x.quota_load_time) >= QUOTA_TTL,
+      None => true,
+    };
+    if stale {
+      let quota = lookup_quota(namespace_id).await?;
+      let mut namespaces = self.namespaces.lock().await;
+      let ns = namespaces
+        .entry(namespace_id.to_string())
+        .or_insert_with(|| NamespaceState {
+          quota: Quota::default(),
+          quota_load_time: now,
+          tokens: quota.max_executions_per_sec as f64,
+          last_refill: now,
+          storage_bytes: 0,
+          storage_measure_time: None,
+          measuring: false,
+        });
+      ns.quota = quota;
+      ns.quota_load_time = now;
+    }
+
+    let mut namespaces = self.namespaces.lock().await;
+    let ns = match namespaces.get_mut(namespace_id) {
+      Some(x) => x,
+
+      // Invalidated concurrently. Not worth a reload.
+      None => {
+        return Ok(Admission {
+          namespace_id: namespace_id.to_string(),
+          quota: Quota::default(),
+          exec_config: Default::default(),
+        })
+      }
+    };
+    let quota = ns.quota.clone();
+
+    if quota.max_executions_per_sec != 0 {
+      let rate = quota.max_executions_per_sec as f64;
+      let elapsed = now.saturating_duration_since(ns.last_refill).as_secs_f64();
+      ns.tokens = (ns.tokens + elapsed * rate).min(rate);
+      ns.last_refill = now;
+      if ns.tokens < executions as f64 {
+        return Err(
+          QuotaError::RateLimited(namespace_id.to_string(), quota.max_executions_per_sec).into(),
+        );
+      }
+      ns.tokens -= executions as f64;
+    }
+
+    let mut exec_config = ExecConfig::default();
+    if quota.max_kv_ops_per_execution != 0 {
+      exec_config.max_kv_ops = Some(quota.max_kv_ops_per_execution);
+    }
+    if quota.max_storage_bytes != 0 {
+      let measure_stale = ns
+        .storage_measure_time
+        .map(|x| now.duration_since(x) >= STORAGE_USAGE_TTL)
+        .unwrap_or(true);
+      if measure_stale && !ns.measuring {
+        ns.measuring = true;
+        let me = self.clone();
+        let namespace_id = namespace_id.to_string();
+        tokio::spawn(async move {
+          me.measure(namespace_id).await;
+        });
+      }
+      exec_config.max_bytes_written =
+        Some(quota.max_storage_bytes.saturating_sub(ns.storage_bytes));
+    }
+
+    Ok(Admission {
+      namespace_id: namespace_id.to_string(),
+      quota,
+      exec_config,
+    })
+  }
+
+  /// Accounts the KV usage of an admitted execution.
+  pub async fn report_usage(&self, admission: &Admission, usage: &ExecUsage) {
+    if let Some(ns) = self
+      .namespaces
+      .lock()
+      .await
+      .get_mut(&admission.namespace_id)
+    {
+      ns.storage_bytes += usage.bytes_written;
+    }
+  }
+
+  /// Drops the cached quota and usage of a namespace, e.g. after its quota is changed.
+  pub async fn invalidate(&self, namespace_id: &str) {
+    self.namespaces.lock().await.remove(namespace_id);
+  }
+
+  async fn measure(&self, namespace_id: String) {
+    let res = measure_storage_bytes(&namespace_id).await;
+    let mut namespaces = self.namespaces.lock().await;
+    let ns = match namespaces.get_mut(&namespace_id) {
+      Some(x) => x,
+      None => return,
+    };
+    ns.measuring = false;
+    match res {
+      Ok(x) => {
+        ns.storage_bytes = x;
+        ns.storage_measure_time = Some(Instant::now());
+      }
+      Err(e) => {
+        log::error!(
+          "Failed to measure storage usage of namespace `{}`: {:?}",
+          namespace_id,
+          e
+        );
+      }
+    }
+  }
+}
+
+impl Admission {
+  pub fn exec_config(&self) -> &ExecConfig {
+    &self.exec_config
+  }
+
+  /// Turns executor limit errors into quota errors.
+  pub fn map_err(&self, e: anyhow::Error) -> anyhow::Error {
+    match e.downcast_ref::<GraphExecError>() {
+      Some(GraphExecError::KvOpLimitExceeded(limit)) => {
+        QuotaError::KvOpsExceeded(self.namespace_id.clone(), *limit).into()
+      }
+      Some(GraphExecError::WriteLimitExceeded(_)) => {
+        QuotaError::StorageExceeded(self.namespace_id.clone(), self.quota.max_storage_bytes).into()
+      }
+      _ => e,
+    }
+  }
+}
+
+/// Sums the sizes of all keys and values in a namespace, `STORAGE_SCAN_BATCH_SIZE` entries per
+/// transaction.
+async fn measure_storage_bytes(namespace_id: &str) -> Result<u64> {
+  let st = get_state();
+  let mut kv_prefix = ns_to_kv_prefix_with_appended_zero(namespace_id).await?;
+  kv_prefix.pop().unwrap();
+  let kv = (st.data_store_generator)(&kv_prefix);
+
+  let end = [0x01u8];
+  let mut start = vec![0x00u8];
+  let mut total = 0u64;
+  loop {
+    let txn = kv.begin_transaction().await?;
+    let mut it = txn
+      .scan(
+        &start,
+        &end,
+        &ScanOptions {
+          limit: Some(STORAGE_SCAN_BATCH_SIZE),
+          values: true,
+          ..Default::default()
+        },
+      )
+      .await?;
+    let mut count = 0usize;
+    let mut last_key = None;
+    while let Some((k, v)) = it.next().await? {
+      total += (k.len() + v.len()) as u64;
+      count += 1;
+      last_key = Some(k);
+    }
+    match last_key {
+      Some(mut k) if count == STORAGE_SCAN_BATCH_SIZE => {
+        k.push(0x00);
+        start = k;
+      }
+      _ => return Ok(total),
+    }
+  }
+}
@@ -0,0 +1,130 @@
+use tokio::sync::broadcast;
+
+use crate::{httpapi::load_query_exec_ctx_uncached, state::get_state};
+
+/// Capacity of the reload event channel. Lagging subscribers treat lost events as a reload.
+const RELOAD_CHANNEL_CAPACITY: usize = 256;
+
+#[derive(Clone, Debug)]
+pub enum ReloadEvent {
+  /// A query script was created, updated, activated at another version or deleted.
+  QueryScript {
+    namespace_id: String,
+    query_script_id: String,
+  },
+
+  /// A deployment was deleted.
+  Deployment {
+    namespace_id: String,
+    deployment_id: String,
+  },
+}
+
+/// Swaps the contexts used by requests when deployments and query scripts change, and notifies
+/// subscribers afterwards.
+///
+/// A changed query script is compiled before it replaces the cached one, so that requests never
+/// wait on compilation. Requests already running on the old version keep their context and finish
+/// on it. The query cache reports when an old version is drained.
+///
+/// Reloads only apply to this server instance. Other instances pick up changes when their hot
+/// items expire.
+pub struct Reloader {
+  tx: broadcast::Sender<ReloadEvent>,
+}
+
+pub struct ReloadSubscription {
+  namespace_id: String,
+  deployment_id: String,
+  rx: broadcast::Receiver<ReloadEvent>,
+}
+
+impl Reloader {
+  pub fn new() -> Self {
+    let (tx, _) = broadcast::channel(RELOAD_CHANNEL_CAPACITY);
+    Self { tx }
+  }
+
+  pub fn subscribe(&self, namespace_id: &str, deployment_id: &str) -> ReloadSubscription {
+    ReloadSubscription {
+      namespace_id: namespace_id.to_string(),
+      deployment_id: deployment_id.to_string(),
+      rx: self.tx.subscribe(),
+    }
+  }
+
+  /// Applies a change. Requests started after this returns run on the new version.
+  pub async fn reload(&self, event: ReloadEvent) {
+    let st = get_state();
+    match &event {
+      ReloadEvent::QueryScript {
+        namespace_id,
+        query_script_id,
+      } => match load_query_exec_ctx_uncached(namespace_id, query_script_id).await {
+        Ok((key, exec_ctx)) => {
+          st.query_cache.swap(key, exec_ctx).await;
+          log::info!(
+            "Reloaded query script `{}` in namespace `{}`.",
+            query_script_id,
+            namespace_id
+          );
+        }
+        Err(e) => {
+          // Deleted, or not loadable any more. Later requests fail on their own.
+          log::debug!(
+            "Dropping query script `{}` in namespace `{}` from cache: {:?}",
+            query_script_id,
+            namespace_id,
+            e
+          );
+          st.query_cache
+            .invalidate_query_script(namespace_id, query_script_id)
+            .await;
+        }
+      },
+      ReloadEvent::Deployment {
+        namespace_id,
+        deployment_id,
+      } => {
+        st.query_cache
+          .invalidate_deployment(namespace_id, deployment_id)
+          .await;
+        st.vm_pool
+          .invalidate_deployment(namespace_id, deployment_id)
+          .await;
+        st.prepared_statements
+          .invalidate_deployment(namespace_id, deployment_id)
+          .await;
+      }
+    }
+
+    // Fails only without subscribers.
+    let _ = self.tx.send(event);
+  }
+}
+
+impl Default for Reloader {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl ReloadSubscription {
+  /// Waits until the subscribed deployment may have been deleted.
+  pub async fn deployment_deleted(&mut self) {
+    loop {
+      match self.rx.recv().await {
+        Ok(ReloadEvent::Deployment {
+          namespace_id,
+          deployment_id,
+        }) if namespace_id == self.namespace_id && deployment_id == self.deployment_id => return,
+        Ok(_) => {}
+        Err(broadcast::error::RecvError::Lagged(_)) => return,
+        Err(broadcast::error::RecvError::Closed) => {
+          // The reloader lives as long as the server.
+          futures::future::pending::<()>().await;
+        }
+      }
+    }
+  }
+}
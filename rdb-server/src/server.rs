@@ -4,11 +4,15 @@ use std::sync::Arc;
 use async_trait::async_trait;
 use bumpalo::Bump;
 use maplit::btreemap;
-use rand::RngCore;
+use rdb_analyzer::data::treewalker::asm::codegen::compile_twscript;
+use rdb_analyzer::data::treewalker::lint::{lint, LintConfig, Severity};
 use rdb_analyzer::data::treewalker::serialize::{
   SerializedVmValue, TaggedVmValue, VmValueEncodeConfig,
 };
-use rdb_analyzer::schema::compile::compile;
+use rdb_analyzer::data::treewalker::typeck::GlobalTyckContext;
+use rdb_analyzer::data::treewalker::vm::TwVm;
+use rdb_analyzer::schema::compile::{compile, CompiledSchema};
+use rdb_analyzer::schema::diagnostic::SchemaDiagnostic;
 use rdb_analyzer::schema::grammar::parse;
 use rdb_analyzer::storage_plan::planner::generate_plan_for_schema;
 use rdb_analyzer::storage_plan::{StorageKey, StoragePlan};
@@ -17,9 +21,18 @@ use rdb_proto::proto::*;
 use rdb_proto::tonic::{Request, Response, Status};
 use uuid::Uuid;
 
+use crate::audit::list_entries;
+use crate::auth::{issue_token, rotate_token, Role};
+use crate::backfill::spawn_backfill;
+use crate::deploy_lock::{DeploymentLock, DeploymentLockError};
 use crate::exec_core::{ExecContext, SchemaContext};
+use crate::reload::ReloadEvent;
 use crate::state::get_state;
-use crate::sysquery::{lookup_deployment, lookup_query_script, ns_to_kv_prefix_with_appended_zero};
+use crate::sysquery::{
+  attach_schema, create_namespace, delete_api_token, delete_namespace, detach_schema,
+  list_api_tokens, list_namespaces, lookup_deployment, lookup_head_deployment, lookup_query_script,
+  lookup_quota, set_quota, Quota,
+};
 use crate::util::current_millis;
 use thiserror::Error;
 
@@ -27,6 +40,9 @@ use thiserror::Error;
 pub enum ServerError {
   #[error("invalid storage plan")]
   InvalidStoragePlan,
+
+  #[error("script rejected by lint:\n{0}")]
+  LintFailed(String),
 }
 
 pub struct ControlServer;
@@ -38,29 +54,7 @@ impl RdbControl for ControlServer {
     request: Request<CreateNamespaceRequest>,
   ) -> Result<Response<CreateNamespaceReply>, Status> {
     let r = request.get_ref();
-    let st = get_state();
-
-    let mut kv_prefix: [u8; 16] = [0u8; 16];
-    rand::thread_rng().fill_bytes(&mut kv_prefix);
-
-    let res = st
-      .system_schema
-      .exec_ctx
-      .run_exported_graph(
-        &*st.system_store,
-        "add_namespace",
-        &[
-          SerializedVmValue::Null(None),
-          SerializedVmValue::String(r.id.clone()),
-          SerializedVmValue::String(base64::encode(&kv_prefix)),
-          SerializedVmValue::String(format!("{}", current_millis())),
-        ],
-        &Default::default(),
-      )
-      .await
-      .translate_err()?;
-    res.check_nonnull().translate_err()?;
-    let ok = res.try_unwrap_bool().translate_err()?;
+    let ok = create_namespace(&r.id).await.translate_err()?;
     Ok(Response::new(CreateNamespaceReply { created: ok }))
   }
 
@@ -68,38 +62,16 @@ impl RdbControl for ControlServer {
     &self,
     _request: Request<ListNamespaceRequest>,
   ) -> Result<Response<ListNamespaceReply>, Status> {
-    let st = get_state();
-    let res = st
-      .system_schema
-      .exec_ctx
-      .run_exported_graph(
-        &*st.system_store,
-        "list_namespaces",
-        &[SerializedVmValue::Null(None)],
-        &VmValueEncodeConfig {
-          enable_bytes: true,
-          enable_double: true,
-          enable_int64: true,
-        },
-      )
+    let namespaces = list_namespaces()
       .await
-      .translate_err()?;
-    res.check_nonnull().translate_err()?;
-    let res = res.try_unwrap_list().translate_err()?;
-    let mut namespaces: Vec<NamespaceBasicInfo> = Vec::new();
-    for x in res {
-      let m = x.try_unwrap_map(&["id", "create_time"]).translate_err()?;
-      let id = m.get("id").unwrap().try_unwrap_string().translate_err()?;
-      let create_time: i64 = m
-        .get("create_time")
-        .unwrap()
-        .try_unwrap_int64()
-        .translate_err()?;
-      namespaces.push(NamespaceBasicInfo {
-        id: id.clone(),
-        create_time,
-      });
-    }
+      .translate_err()?
+      .into_iter()
+      .map(|x| NamespaceBasicInfo {
+        id: x.id,
+        create_time: x.create_time,
+        schema_detach_time: x.schema_detach_time,
+      })
+      .collect();
     Ok(Response::new(ListNamespaceReply { namespaces }))
   }
 
@@ -109,36 +81,29 @@ impl RdbControl for ControlServer {
   ) -> Result<Response<DeleteNamespaceReply>, Status> {
     let r = request.get_ref();
     let st = get_state();
+    let ok = delete_namespace(&r.id).await.translate_err()?;
+    st.query_cache.invalidate_namespace(&r.id).await;
+    st.vm_pool.invalidate_namespace(&r.id).await;
+    st.prepared_statements.invalidate_namespace(&r.id).await;
+    Ok(Response::new(DeleteNamespaceReply { deleted: ok }))
+  }
 
-    // Delete all data in this namespace
-    if let Ok(mut kv_prefix) = ns_to_kv_prefix_with_appended_zero(&r.id).await {
-      // Remove trailing zero
-      let popped = kv_prefix.pop().unwrap();
-      assert_eq!(popped, 0);
-
-      let full_range = (st.data_store_generator)(&kv_prefix);
-      let txn = full_range.begin_transaction().await.translate_err()?;
-      txn.delete_range(&[0x00], &[0x01]).await.translate_err()?;
-      txn.commit().await.translate_err()?;
-    }
+  async fn attach_schema(
+    &self,
+    request: Request<AttachSchemaRequest>,
+  ) -> Result<Response<AttachSchemaReply>, Status> {
+    let r = request.get_ref();
+    let ok = attach_schema(&r.namespace_id).await.translate_err()?;
+    Ok(Response::new(AttachSchemaReply { attached: ok }))
+  }
 
-    let res = st
-      .system_schema
-      .exec_ctx
-      .run_exported_graph(
-        &*st.system_store,
-        "delete_namespace",
-        &[
-          SerializedVmValue::Null(None),
-          SerializedVmValue::String(r.id.clone()),
-        ],
-        &Default::default(),
-      )
-      .await
-      .translate_err()?;
-    res.check_nonnull().translate_err()?;
-    let ok = res.try_unwrap_bool().translate_err()?;
-    Ok(Response::new(DeleteNamespaceReply { deleted: ok }))
+  async fn detach_schema(
+    &self,
+    request: Request<DetachSchemaRequest>,
+  ) -> Result<Response<DetachSchemaReply>, Status> {
+    let r = request.get_ref();
+    let ok = detach_schema(&r.namespace_id).await.translate_err()?;
+    Ok(Response::new(DetachSchemaReply { detached: ok }))
   }
 
   async fn create_deployment(
@@ -146,12 +111,9 @@ impl RdbControl for ControlServer {
     request: Request<CreateDeploymentRequest>,
   ) -> Result<Response<CreateDeploymentReply>, Status> {
     let r = request.get_ref();
-    let st = get_state();
-
     let id = Uuid::new_v4().to_string();
-    let now = current_millis();
 
-    let new_schema = compile(&parse(&Bump::new(), &r.schema).translate_err()?).translate_err()?;
+    let new_schema = compile_uploaded_schema(&r.schema)?;
     let new_plan: StoragePlan<String> = serde_yaml::from_str(&r.plan).translate_err()?;
     let new_plan = StoragePlan::<StorageKey>::try_from(&new_plan).translate_err()?;
 
@@ -165,30 +127,77 @@ impl RdbControl for ControlServer {
     }
 
     // And finally, update our system schema.
-    let res = st
-      .system_schema
-      .exec_ctx
-      .run_exported_graph(
-        &*st.system_store,
-        "add_deployment",
-        &[
-          SerializedVmValue::Null(None),
-          SerializedVmValue::String(r.namespace_id.clone()),
-          SerializedVmValue::Tagged(TaggedVmValue::M(btreemap! {
-            "id".to_string() => SerializedVmValue::String(id.clone()),
-            "description".to_string() => SerializedVmValue::String(r.description.clone()),
-            "schema".to_string() => SerializedVmValue::String(r.schema.clone()),
-            "plan".to_string() => SerializedVmValue::String(base64::encode(&generated_plan.serialize_compressed().translate_err()?)),
-            "create_time".to_string() => SerializedVmValue::String(format!("{}", now)),
-          })),
-        ],
-        &Default::default(),
+    let lock = acquire_deployment_lock(&r.namespace_id).await?;
+    let ok = insert_deployment(
+      &r.namespace_id,
+      &r.base_deployment_id,
+      &id,
+      &r.description,
+      &r.schema,
+      &generated_plan,
+    )
+    .await;
+    release_deployment_lock(lock).await;
+    let ok = ok?;
+    if ok {
+      spawn_backfill(r.namespace_id.clone());
+    }
+    Ok(Response::new(CreateDeploymentReply {
+      deployment_id: ok.then(|| DeploymentId { id }),
+    }))
+  }
+
+  async fn rollback_deployment(
+    &self,
+    request: Request<RollbackDeploymentRequest>,
+  ) -> Result<Response<RollbackDeploymentReply>, Status> {
+    let r = request.get_ref();
+    let id = Uuid::new_v4().to_string();
+
+    // Planning happens here rather than on the client, so the lease also covers it.
+    let lock = acquire_deployment_lock(&r.namespace_id).await?;
+    let ok = async {
+      let base = lookup_deployment(&r.namespace_id, &r.base_deployment_id)
+        .await
+        .translate_err()?;
+      let target = lookup_deployment(&r.namespace_id, &r.target_deployment_id)
+        .await
+        .translate_err()?;
+
+      let base_schema =
+        compile(&parse(&Bump::new(), &base.schema).translate_err()?).translate_err()?;
+      let base_plan = StoragePlan::deserialize_compressed(&base.plan).translate_err()?;
+      let target_schema =
+        compile(&parse(&Bump::new(), &target.schema).translate_err()?).translate_err()?;
+
+      // Plan the target schema on top of the base plan instead of reusing the target's own plan,
+      // so that fields added in between keep their keys and data written under the base
+      // deployment stays readable where the schemas agree.
+      let plan =
+        generate_plan_for_schema(&base_plan, &base_schema, &target_schema).translate_err()?;
+
+      let description = if r.description.is_empty() {
+        format!("Rollback to {}", target.id)
+      } else {
+        r.description.clone()
+      };
+      insert_deployment(
+        &r.namespace_id,
+        &base.id,
+        &id,
+        &description,
+        &target.schema,
+        &plan,
       )
       .await
-      .translate_err()?;
-    res.check_nonnull().translate_err()?;
-    let ok = res.try_unwrap_bool().translate_err()?;
-    Ok(Response::new(CreateDeploymentReply {
+    }
+    .await;
+    release_deployment_lock(lock).await;
+    let ok = ok?;
+    if ok {
+      spawn_backfill(r.namespace_id.clone());
+    }
+    Ok(Response::new(RollbackDeploymentReply {
       deployment_id: ok.then(|| DeploymentId { id }),
     }))
   }
@@ -214,6 +223,7 @@ impl RdbControl for ControlServer {
           enable_bytes: true,
           enable_double: true,
           enable_int64: true,
+          ..Default::default()
         },
       )
       .await
@@ -287,6 +297,7 @@ impl RdbControl for ControlServer {
           enable_bytes: true,
           enable_double: true,
           enable_int64: true,
+          ..Default::default()
         },
       )
       .await
@@ -315,7 +326,15 @@ impl RdbControl for ControlServer {
         description: description.clone(),
       });
     }
-    Ok(Response::new(ListDeploymentReply { deployments }))
+    deployments.sort_by_key(|x| std::cmp::Reverse(x.create_time));
+    let head_deployment_id = lookup_head_deployment(&r.namespace_id)
+      .await
+      .translate_err()?
+      .unwrap_or_default();
+    Ok(Response::new(ListDeploymentReply {
+      deployments,
+      head_deployment_id,
+    }))
   }
 
   async fn delete_deployment(
@@ -341,6 +360,12 @@ impl RdbControl for ControlServer {
       .translate_err()?;
     res.check_nonnull().translate_err()?;
     let deleted = res.try_unwrap_bool().translate_err()?;
+    st.reloader
+      .reload(ReloadEvent::Deployment {
+        namespace_id: r.namespace_id.clone(),
+        deployment_id: r.id.clone(),
+      })
+      .await;
     Ok(Response::new(DeleteDeploymentReply { deleted }))
   }
 
@@ -358,8 +383,33 @@ impl RdbControl for ControlServer {
     // Validation
     let schema = compile(&parse(&Bump::new(), &depl.schema).translate_err()?).translate_err()?;
     let plan = StoragePlan::deserialize_compressed(&depl.plan).translate_err()?;
+    let script = compile_twscript(&r.script).translate_err()?;
+    let lint_config = LintConfig {
+      read_only_graphs: r.read_only_graphs.iter().cloned().collect(),
+      ..Default::default()
+    };
+    let warnings = {
+      let vm = TwVm::new(&schema, &plan, &script).translate_err()?;
+      let type_info = GlobalTyckContext::new(&vm)
+        .and_then(|mut x| x.typeck())
+        .translate_err()?;
+      lint(&vm, &type_info, &lint_config)
+    };
+    if warnings.iter().any(|x| x.severity == Severity::Error) {
+      let errors = warnings
+        .iter()
+        .filter(|x| x.severity == Severity::Error)
+        .map(|x| x.to_string())
+        .collect::<Vec<_>>();
+      return Err(Status::invalid_argument(
+        ServerError::LintFailed(errors.join("\n")).to_string(),
+      ));
+    }
     let schema_ctx = Arc::new(SchemaContext { schema, plan });
-    ExecContext::load(schema_ctx, &r.script).translate_err()?;
+    ExecContext::load_compiled(schema_ctx, script).translate_err()?;
+
+    let version_id = Uuid::new_v4().to_string();
+    let now = format!("{}", current_millis());
 
     let res = st
       .system_schema
@@ -374,8 +424,10 @@ impl RdbControl for ControlServer {
             "id".to_string() => SerializedVmValue::String(r.id.clone()),
             "associated_deployment".to_string() => SerializedVmValue::String(r.associated_deployment.clone()),
             "script".to_string() => SerializedVmValue::String(r.script.clone()),
-            "create_time".to_string() => SerializedVmValue::String(format!("{}", current_millis())),
+            "create_time".to_string() => SerializedVmValue::String(now.clone()),
+            "activate_time".to_string() => SerializedVmValue::String(now),
           })),
+          SerializedVmValue::String(version_id.clone()),
         ],
         &Default::default(),
       )
@@ -383,7 +435,17 @@ impl RdbControl for ControlServer {
       .translate_err()?;
     res.check_nonnull().translate_err()?;
     let created = res.try_unwrap_bool().translate_err()?;
-    Ok(Response::new(CreateQueryScriptReply { created }))
+    st.reloader
+      .reload(ReloadEvent::QueryScript {
+        namespace_id: r.namespace_id.clone(),
+        query_script_id: r.id.clone(),
+      })
+      .await;
+    Ok(Response::new(CreateQueryScriptReply {
+      created,
+      version_id: if created { version_id } else { String::new() },
+      lint_warnings: warnings.iter().map(|x| x.to_string()).collect(),
+    }))
   }
 
   async fn delete_query_script(
@@ -409,6 +471,12 @@ impl RdbControl for ControlServer {
       .translate_err()?;
     res.check_nonnull().translate_err()?;
     let deleted = res.try_unwrap_bool().translate_err()?;
+    st.reloader
+      .reload(ReloadEvent::QueryScript {
+        namespace_id: r.namespace_id.clone(),
+        query_script_id: r.id.clone(),
+      })
+      .await;
     Ok(Response::new(DeleteQueryScriptReply { deleted }))
   }
 
@@ -426,6 +494,7 @@ impl RdbControl for ControlServer {
         associated_deployment: qs.associated_deployment,
         script: qs.script,
         create_time: qs.create_time,
+        activate_time: qs.activate_time,
       }),
     }))
   }
@@ -450,6 +519,7 @@ impl RdbControl for ControlServer {
           enable_bytes: true,
           enable_double: true,
           enable_int64: true,
+          ..Default::default()
         },
       )
       .await
@@ -480,6 +550,306 @@ impl RdbControl for ControlServer {
     }
     Ok(Response::new(ListQueryScriptReply { query_scripts }))
   }
+
+  async fn list_query_script_versions(
+    &self,
+    request: Request<ListQueryScriptVersionsRequest>,
+  ) -> Result<Response<ListQueryScriptVersionsReply>, Status> {
+    let r = request.get_ref();
+    let st = get_state();
+    let res = st
+      .system_schema
+      .exec_ctx
+      .run_exported_graph(
+        &*st.system_store,
+        "list_query_script_versions",
+        &[
+          SerializedVmValue::Null(None),
+          SerializedVmValue::String(r.namespace_id.clone()),
+          SerializedVmValue::String(r.query_script_id.clone()),
+        ],
+        &VmValueEncodeConfig {
+          enable_bytes: true,
+          enable_double: true,
+          enable_int64: true,
+          ..Default::default()
+        },
+      )
+      .await
+      .translate_err()?;
+    res.check_nonnull().translate_err()?;
+    let res = res.try_unwrap_list().translate_err()?;
+    let mut versions: Vec<QueryScriptVersionInfo> = Vec::new();
+    for x in res {
+      let m = x
+        .try_unwrap_map(&["id", "associated_deployment", "create_time"])
+        .translate_err()?;
+      let id = m.get("id").unwrap().try_unwrap_string().translate_err()?;
+      let associated_deployment = m
+        .get("associated_deployment")
+        .unwrap()
+        .try_unwrap_string()
+        .translate_err()?;
+      let create_time: i64 = m
+        .get("create_time")
+        .unwrap()
+        .try_unwrap_int64()
+        .translate_err()?;
+      versions.push(QueryScriptVersionInfo {
+        id: id.clone(),
+        associated_deployment: associated_deployment.clone(),
+        create_time,
+      });
+    }
+    versions.sort_by_key(|x| std::cmp::Reverse(x.create_time));
+    Ok(Response::new(ListQueryScriptVersionsReply { versions }))
+  }
+
+  async fn activate_query_script_version(
+    &self,
+    request: Request<ActivateQueryScriptVersionRequest>,
+  ) -> Result<Response<ActivateQueryScriptVersionReply>, Status> {
+    let r = request.get_ref();
+    let st = get_state();
+    let res = st
+      .system_schema
+      .exec_ctx
+      .run_exported_graph(
+        &*st.system_store,
+        "activate_query_script_version",
+        &[
+          SerializedVmValue::Null(None),
+          SerializedVmValue::String(r.namespace_id.clone()),
+          SerializedVmValue::String(r.query_script_id.clone()),
+          SerializedVmValue::String(r.version_id.clone()),
+          SerializedVmValue::String(format!("{}", current_millis())),
+        ],
+        &Default::default(),
+      )
+      .await
+      .translate_err()?;
+    res.check_nonnull().translate_err()?;
+    let activated = res.try_unwrap_bool().translate_err()?;
+
+    // Cached contexts are keyed by `activate_time`, so those of the old version are never hit
+    // again. Swap in the new version eagerly.
+    st.reloader
+      .reload(ReloadEvent::QueryScript {
+        namespace_id: r.namespace_id.clone(),
+        query_script_id: r.query_script_id.clone(),
+      })
+      .await;
+    Ok(Response::new(ActivateQueryScriptVersionReply { activated }))
+  }
+
+  async fn get_namespace_quota(
+    &self,
+    request: Request<GetNamespaceQuotaRequest>,
+  ) -> Result<Response<GetNamespaceQuotaReply>, Status> {
+    let r = request.get_ref();
+    let quota = lookup_quota(&r.namespace_id).await.translate_err()?;
+    Ok(Response::new(GetNamespaceQuotaReply {
+      quota: Some(NamespaceQuota {
+        max_executions_per_sec: quota.max_executions_per_sec,
+        max_kv_ops_per_execution: quota.max_kv_ops_per_execution,
+        max_storage_bytes: quota.max_storage_bytes,
+      }),
+    }))
+  }
+
+  async fn set_namespace_quota(
+    &self,
+    request: Request<SetNamespaceQuotaRequest>,
+  ) -> Result<Response<SetNamespaceQuotaReply>, Status> {
+    let r = request.get_ref();
+    let st = get_state();
+    let quota = r.quota.clone().unwrap_or_default();
+    let updated = set_quota(
+      &r.namespace_id,
+      &Quota {
+        max_executions_per_sec: quota.max_executions_per_sec,
+        max_kv_ops_per_execution: quota.max_kv_ops_per_execution,
+        max_storage_bytes: quota.max_storage_bytes,
+      },
+    )
+    .await
+    .translate_err()?;
+    st.quota.invalidate(&r.namespace_id).await;
+    Ok(Response::new(SetNamespaceQuotaReply { updated }))
+  }
+
+  async fn create_api_token(
+    &self,
+    request: Request<CreateApiTokenRequest>,
+  ) -> Result<Response<CreateApiTokenReply>, Status> {
+    let r = request.get_ref();
+    let role: Role = r
+      .role
+      .parse()
+      .map_err(|e| Status::invalid_argument(format!("{}", e)))?;
+    let res = issue_token(&r.namespace_id, role).await.translate_err()?;
+    let (token_id, token) = res.unwrap_or_default();
+    Ok(Response::new(CreateApiTokenReply {
+      created: !token_id.is_empty(),
+      token_id,
+      token,
+    }))
+  }
+
+  async fn rotate_api_token(
+    &self,
+    request: Request<RotateApiTokenRequest>,
+  ) -> Result<Response<RotateApiTokenReply>, Status> {
+    let r = request.get_ref();
+    let token = rotate_token(&r.namespace_id, &r.token_id)
+      .await
+      .translate_err()?;
+    Ok(Response::new(RotateApiTokenReply {
+      rotated: token.is_some(),
+      token: token.unwrap_or_default(),
+    }))
+  }
+
+  async fn delete_api_token(
+    &self,
+    request: Request<DeleteApiTokenRequest>,
+  ) -> Result<Response<DeleteApiTokenReply>, Status> {
+    let r = request.get_ref();
+    let deleted = delete_api_token(&r.namespace_id, &r.token_id)
+      .await
+      .translate_err()?;
+    Ok(Response::new(DeleteApiTokenReply { deleted }))
+  }
+
+  async fn list_api_tokens(
+    &self,
+    request: Request<ListApiTokensRequest>,
+  ) -> Result<Response<ListApiTokensReply>, Status> {
+    let r = request.get_ref();
+    let tokens = list_api_tokens(&r.namespace_id)
+      .await
+      .translate_err()?
+      .into_iter()
+      .map(|x| ApiTokenInfo {
+        id: x.id,
+        role: x.role,
+        create_time: x.create_time,
+      })
+      .collect();
+    Ok(Response::new(ListApiTokensReply { tokens }))
+  }
+
+  async fn list_audit_log(
+    &self,
+    request: Request<ListAuditLogRequest>,
+  ) -> Result<Response<ListAuditLogReply>, Status> {
+    let r = request.get_ref();
+    let end_time = if r.end_time == 0 {
+      current_millis() + 1
+    } else {
+      r.end_time
+    };
+    let mut entries = list_entries(&r.namespace_id, r.start_time, end_time)
+      .await
+      .translate_err()?;
+    if r.limit != 0 {
+      entries.truncate(r.limit as usize);
+    }
+    let entries = entries
+      .into_iter()
+      .map(|x| AuditEntry {
+        id: x.id,
+        time: x.time,
+        query_script_id: x.query_script_id,
+        graph_name: x.graph_name,
+        params_hash: x.params_hash,
+        status: x.status,
+        keys_written: x.keys_written,
+        duration_us: x.duration_us,
+      })
+      .collect();
+    Ok(Response::new(ListAuditLogReply { entries }))
+  }
+}
+
+/// Inserts a deployment derived from `base_deployment_id` and makes it the head of the namespace.
+/// Fails with `ABORTED` if the base is no longer the head.
+pub async fn insert_deployment(
+  namespace_id: &str,
+  base_deployment_id: &str,
+  id: &str,
+  description: &str,
+  schema: &str,
+  plan: &StoragePlan,
+) -> Result<bool, Status> {
+  let st = get_state();
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "add_deployment",
+      &[
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String(namespace_id.to_string()),
+        SerializedVmValue::Tagged(TaggedVmValue::M(btreemap! {
+          "id".to_string() => SerializedVmValue::String(id.to_string()),
+          "description".to_string() => SerializedVmValue::String(description.to_string()),
+          "schema".to_string() => SerializedVmValue::String(schema.to_string()),
+          "plan".to_string() => SerializedVmValue::String(base64::encode(&plan.serialize_compressed().translate_err()?)),
+          "create_time".to_string() => SerializedVmValue::String(format!("{}", current_millis())),
+        })),
+        SerializedVmValue::String(base_deployment_id.to_string()),
+      ],
+      &Default::default(),
+    )
+    .await
+    .translate_err()?;
+  res.check_nonnull().translate_err()?;
+  let ok = res.try_unwrap_bool().translate_err()?;
+  if !ok {
+    // The head only moves forward, so a head that differs now also differed at insertion.
+    if let Some(head) = lookup_head_deployment(namespace_id).await.translate_err()? {
+      if head != base_deployment_id {
+        return Err(Status::aborted(format!(
+          "deployment base `{}` is stale: the head deployment is now `{}`",
+          base_deployment_id, head
+        )));
+      }
+    }
+  }
+  Ok(ok)
+}
+
+async fn acquire_deployment_lock(namespace_id: &str) -> Result<DeploymentLock, Status> {
+  match DeploymentLock::acquire(namespace_id).await {
+    Ok(x) => Ok(x),
+    Err(e) if e.downcast_ref::<DeploymentLockError>().is_some() => {
+      Err(Status::aborted(e.to_string()))
+    }
+    Err(e) => Err(e).translate_err(),
+  }
+}
+
+/// Releases a deployment lease. Failures are only logged, since the lease expires anyway.
+async fn release_deployment_lock(lock: DeploymentLock) {
+  if let Err(e) = lock.release().await {
+    log::warn!("failed to release deployment lease: {:?}", e);
+  }
+}
+
+/// Compiles a schema uploaded by a client. Errors are reported as invalid arguments, with the
+/// position of the error in the schema source when available.
+fn compile_uploaded_schema(source: &str) -> Result<CompiledSchema, Status> {
+  parse(&Bump::new(), source)
+    .and_then(|x| compile(&x))
+    .map_err(|e| {
+      log::warn!("rejected schema: {:?}", e);
+      match SchemaDiagnostic::from_error(source, &e) {
+        Some(x) => Status::invalid_argument(x.to_string()),
+        None => Status::invalid_argument(e.to_string()),
+      }
+    })
 }
 
 trait ErrorTranslate {
@@ -0,0 +1,179 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use rand::RngCore;
+use rdb_analyzer::data::treewalker::{
+  profile::GraphProfile,
+  serialize::{SerializedVmValue, TaggedVmValue},
+};
+use tokio::time::sleep;
+
+use crate::{
+  audit::time_to_entry_id,
+  state::get_state,
+  sysquery::{append_slow_query, delete_slow_query, list_namespaces, list_slow_queries, SlowQuery},
+  util::current_millis,
+};
+
+/// Interval between two passes of the retention task.
+const RETENTION_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Slow query logging options of the server.
+#[derive(Clone, Debug)]
+pub struct SlowQueryConfig {
+  /// Runs that take at least this long are logged.
+  pub threshold: Duration,
+
+  /// Whether slow queries are also stored in the system schema.
+  pub store: bool,
+}
+
+/// Identifies the graph runs of a request for slow query logging. Runs are profiled while set.
+#[derive(Clone, Debug)]
+pub struct SlowQueryContext {
+  pub namespace_id: String,
+  pub query_script_id: String,
+  config: SlowQueryConfig,
+}
+
+/// Returns the slow query context of runs of a query script, or `None` if slow query logging is
+/// disabled.
+pub fn context(namespace_id: &str, query_script_id: &str) -> Option<SlowQueryContext> {
+  get_state()
+    .slow_query
+    .as_ref()
+    .map(|config| SlowQueryContext {
+      namespace_id: namespace_id.to_string(),
+      query_script_id: query_script_id.to_string(),
+      config: config.clone(),
+    })
+}
+
+impl SlowQueryContext {
+  /// Logs a graph run if it took at least the threshold, and stores it in the background if
+  /// enabled. `profile` is the profile of the last attempt, if the run succeeded.
+  pub fn check<T>(
+    &self,
+    graph_name: &str,
+    params: &[SerializedVmValue],
+    result: &Result<T>,
+    profile: Option<&GraphProfile>,
+    conflicts: u32,
+    duration: Duration,
+  ) {
+    if duration < self.config.threshold {
+      return;
+    }
+    let params = summarize_params(params);
+    let status = match result {
+      Ok(_) => "ok".to_string(),
+      Err(e) => format!("{}", e),
+    };
+    let profile = profile
+      .and_then(|x| serde_json::to_string(x).ok())
+      .unwrap_or_default();
+    log::warn!(
+      "Slow query: graph `{}` of query script `{}` in namespace `{}` took {} ms with {} conflict(s). Status: {}. Params: {}. Profile: {}",
+      graph_name,
+      self.query_script_id,
+      self.namespace_id,
+      duration.as_millis(),
+      conflicts,
+      status,
+      params,
+      profile,
+    );
+    if !self.config.store {
+      return;
+    }
+
+    let time = current_millis();
+    let mut suffix = [0u8; 8];
+    rand::thread_rng().fill_bytes(&mut suffix);
+    let entry = SlowQuery {
+      id: format!("{}{}", time_to_entry_id(time), hex::encode(&suffix)),
+      time: time as i64,
+      query_script_id: self.query_script_id.clone(),
+      graph_name: graph_name.to_string(),
+      params,
+      status,
+      duration_us: duration.as_micros() as i64,
+      conflicts: conflicts as i64,
+      profile,
+    };
+    let namespace_id = self.namespace_id.clone();
+
+    // Stored outside of the run, so that it neither delays the response nor counts towards the
+    // query timeout.
+    tokio::spawn(async move {
+      if let Err(e) = append_slow_query(&namespace_id, &entry).await {
+        log::error!(
+          "Failed to store slow query in namespace `{}`: {:?}",
+          namespace_id,
+          e
+        );
+      }
+    });
+  }
+}
+
+/// Describes the types and sizes of params, without their values, e.g.
+/// `[string(5), int64, map{id, name}, list(3)]`.
+fn summarize_params(params: &[SerializedVmValue]) -> String {
+  format!(
+    "[{}]",
+    params
+      .iter()
+      .map(summarize_value)
+      .collect::<Vec<_>>()
+      .join(", ")
+  )
+}
+
+fn summarize_value(value: &SerializedVmValue) -> String {
+  match value {
+    SerializedVmValue::String(x) => format!("string({})", x.len()),
+    SerializedVmValue::Bool(_) => "bool".to_string(),
+    SerializedVmValue::Bytes(x) => format!("bytes({})", x.len()),
+    SerializedVmValue::Int64(_) => "int64".to_string(),
+    SerializedVmValue::Double(_) => "double".to_string(),
+    SerializedVmValue::Null(_) => "null".to_string(),
+    SerializedVmValue::ExportRef(x) => format!("export({})", x.export),
+    SerializedVmValue::Tagged(TaggedVmValue::M(x)) => format!(
+      "map{{{}}}",
+      x.keys().map(|x| x.as_str()).collect::<Vec<_>>().join(", ")
+    ),
+    SerializedVmValue::Tagged(TaggedVmValue::L(x)) => format!("list({})", x.len()),
+    SerializedVmValue::Tagged(TaggedVmValue::T { elements, omitted }) => {
+      format!("list({})", elements.len() as u64 + omitted)
+    }
+    SerializedVmValue::Tagged(TaggedVmValue::P(_)) => "page".to_string(),
+  }
+}
+
+/// Periodically deletes stored slow queries older than `retention` from all namespaces.
+pub async fn run_retention(retention: Duration) -> ! {
+  loop {
+    let cutoff = current_millis().saturating_sub(retention.as_millis() as u64);
+    match prune(cutoff).await {
+      Ok(0) => {}
+      Ok(n) => log::info!("Pruned {} slow queries.", n),
+      Err(e) => log::error!("Failed to prune slow queries: {:?}", e),
+    }
+    sleep(RETENTION_INTERVAL).await;
+  }
+}
+
+async fn prune(cutoff: u64) -> Result<usize> {
+  let mut num_deleted = 0usize;
+  for ns in list_namespaces().await? {
+    let entries =
+      list_slow_queries(&ns.id, &time_to_entry_id(0), &time_to_entry_id(cutoff)).await?;
+    for entry in entries {
+      if delete_slow_query(&ns.id, &entry.id).await? {
+        num_deleted += 1;
+      }
+    }
+  }
+  Ok(num_deleted)
+}
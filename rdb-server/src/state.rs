@@ -1,17 +1,42 @@
 use std::sync::Arc;
 
 use once_cell::sync::OnceCell;
-use rdb_analyzer::data::kv::KeyValueStore;
+use rdb_analyzer::data::{kv::KeyValueStore, treewalker::serialize::ResultSizeLimit};
 
-use crate::{query_cache::QueryCache, system::SystemSchema};
+use crate::{
+  change_feed::ChangeFeed, prepared::PreparedStatements, query_cache::QueryCache,
+  quota::QuotaManager, reload::Reloader, slow_query::SlowQueryConfig, system::SystemSchema,
+  vm_pool::VmPool,
+};
 
 pub type DataStoreGenerator = Box<dyn Fn(&[u8]) -> Box<dyn KeyValueStore> + Send + Sync>;
 
 pub struct ServerState {
   pub data_store_generator: DataStoreGenerator,
+
+  /// The data stores below the checksum layer, if data checksums are enabled.
+  pub checksum_backend_generator: Option<DataStoreGenerator>,
+
   pub system_store: Box<dyn KeyValueStore>,
   pub system_schema: SystemSchema,
   pub query_cache: Arc<QueryCache>,
+  pub vm_pool: VmPool,
+  pub prepared_statements: PreparedStatements,
+  pub change_feed: ChangeFeed,
+  pub quota: Arc<QuotaManager>,
+  pub reloader: Reloader,
+
+  /// Token required by admin APIs. Admin APIs are disabled if not set.
+  pub admin_token: Option<String>,
+
+  /// Whether query APIs reject requests without an API token.
+  pub require_auth: bool,
+
+  /// Applied to the outputs of query APIs.
+  pub result_size_limit: Option<ResultSizeLimit>,
+
+  /// Slow query logging of query APIs. Disabled if not set.
+  pub slow_query: Option<SlowQueryConfig>,
 }
 
 static STATE: OnceCell<ServerState> = OnceCell::new();
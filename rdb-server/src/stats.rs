@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use rdb_analyzer::data::{
+  kv::KeyValueStore,
+  stats::{collect_stats, load_stats, save_stats, SchemaStats, StatsOptions},
+};
+use tokio::time::sleep;
+
+use crate::{
+  key_encoding::check_key_encoding,
+  state::get_state,
+  sysquery::{list_namespaces, lookup_head_deployment, ns_to_kv_prefix_with_appended_zero},
+};
+
+/// Collects the statistics of the data of each namespace every `interval`, against its head
+/// deployment.
+pub async fn run_collection(interval: Duration) -> ! {
+  loop {
+    match collect_all().await {
+      Ok(0) => {}
+      Ok(n) => log::info!("Collected statistics of {} namespace(s).", n),
+      Err(e) => log::error!("Failed to collect statistics: {:?}", e),
+    }
+    sleep(interval).await;
+  }
+}
+
+async fn collect_all() -> Result<usize> {
+  let mut num_collected = 0usize;
+  for ns in list_namespaces().await? {
+    // A failure in one namespace does not hold back the others.
+    match collect_namespace(&ns.id).await {
+      Ok(true) => num_collected += 1,
+      Ok(false) => {}
+      Err(e) => log::warn!(
+        "Failed to collect statistics of namespace `{}`: {:?}",
+        ns.id,
+        e
+      ),
+    }
+  }
+  Ok(num_collected)
+}
+
+/// Returns `false` if the namespace has no deployment yet.
+async fn collect_namespace(namespace_id: &str) -> Result<bool> {
+  let st = get_state();
+  let deployment_id = match lookup_head_deployment(namespace_id).await? {
+    Some(x) => x,
+    None => return Ok(false),
+  };
+  let schema_ctx = st
+    .vm_pool
+    .get_or_load_schema(namespace_id, &deployment_id)
+    .await?;
+  let kv_prefix = ns_to_kv_prefix_with_appended_zero(namespace_id).await?;
+  check_key_encoding(namespace_id, &kv_prefix).await?;
+  let kv = (st.data_store_generator)(&kv_prefix);
+  let stats = collect_stats(
+    &*kv,
+    &schema_ctx.schema,
+    &schema_ctx.plan,
+    &StatsOptions::default(),
+  )
+  .await?;
+  save_stats(&*stats_kv(namespace_id).await?, &stats).await?;
+  Ok(true)
+}
+
+/// Loads the last statistics collected for a namespace.
+pub async fn load_namespace_stats(namespace_id: &str) -> Result<SchemaStats> {
+  load_stats(&*stats_kv(namespace_id).await?).await
+}
+
+/// The statistics of a namespace are stored at `0x01` in its keyspace, next to its data at
+/// `0x00`.
+async fn stats_kv(namespace_id: &str) -> Result<Box<dyn KeyValueStore>> {
+  let st = get_state();
+  let mut kv_prefix = ns_to_kv_prefix_with_appended_zero(namespace_id).await?;
+  *kv_prefix.last_mut().unwrap() = 0x01;
+  Ok((st.data_store_generator)(&kv_prefix))
+}
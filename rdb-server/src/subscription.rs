@@ -0,0 +1,384 @@
+use std::{collections::BTreeSet, sync::Arc};
+
+use anyhow::Result;
+use futures::{stream::SplitSink, SinkExt, StreamExt};
+use rdb_analyzer::{
+  data::{
+    keyenc::{decode_key, successor_of_bounded_prefix},
+    kv::KeyValueStore,
+    pathwalker::PathWalker,
+    query::{
+      ast::{CompareOp, Operand, PathQuery, PathSegment, Predicate},
+      exec::exec_query_plan,
+      planner::QueryPlanner,
+    },
+    treewalker::{
+      serialize::{SerializedVmValue, VmValueEncodeConfig},
+      vm_value::{VmType, VmValue},
+    },
+    value::PrimitiveValue,
+  },
+  schema::compile::{FieldAnnotationList, FieldType},
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use warp::ws::{Message, WebSocket};
+
+use crate::{
+  auth::authenticate,
+  change_feed::{ChangeEvent, KeyWrite},
+  exec_core::SchemaContext,
+  httpapi::namespace_kv,
+  state::get_state,
+};
+
+#[derive(Error, Debug)]
+pub enum SubscriptionError {
+  #[error("bad subscription request")]
+  BadRequest,
+
+  #[error("export `{0}` is not a set")]
+  NotSet(String),
+
+  #[error("the bounds must be non-null primitives of the primary key type")]
+  BadBound,
+
+  #[error("bad primary key in changed key")]
+  BadKey,
+}
+
+/// The first message sent by the client on a subscription.
+#[derive(Deserialize)]
+struct SubscribeRequest {
+  /// Inclusive lower bound of the primary keys of the members to watch. Unbounded if absent.
+  #[serde(default)]
+  start: Option<SerializedVmValue>,
+
+  /// Exclusive upper bound of the primary keys of the members to watch. Unbounded if absent.
+  #[serde(default)]
+  end: Option<SerializedVmValue>,
+
+  /// Whether to send the members in the range right after subscribing.
+  #[serde(default)]
+  initial_snapshot: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum SubscriptionMessage {
+  /// All members in the range. Sent on request, and in place of the events the server could not
+  /// track, e.g. after large transactions.
+  Snapshot(SerializedVmValue),
+
+  /// A member was inserted or changed.
+  Put {
+    key: SerializedVmValue,
+    value: SerializedVmValue,
+  },
+
+  /// A member was deleted.
+  Delete {
+    key: SerializedVmValue,
+  },
+
+  Error(String),
+}
+
+/// The members of a set export with primary keys in a range, on a deployment of a namespace.
+struct Subscription {
+  set_name: String,
+  schema_ctx: Arc<SchemaContext>,
+  kv: Box<dyn KeyValueStore>,
+  primary_key: String,
+  start: Option<PrimitiveValue>,
+  end: Option<PrimitiveValue>,
+  config: VmValueEncodeConfig,
+}
+
+/// Pushes the changes of the members of a set export with primary keys in a range to the client.
+///
+/// Committed writes to the namespace are mapped to the members they touch, and each touched member
+/// in the range is read again and sent as a put, or as a delete if it is gone. No graph is run.
+/// Writes that cannot be mapped to members, and events lost because the client lags behind, are
+/// followed by a snapshot of the range.
+///
+/// Primary keys in events have the collation of the primary key applied.
+pub async fn run_subscription(
+  socket: WebSocket,
+  namespace_id: String,
+  deployment_id: String,
+  set_name: String,
+  authorization: Option<String>,
+) {
+  let (mut tx, mut rx) = socket.split();
+  let req = match rx.next().await {
+    Some(Ok(x)) => x
+      .to_str()
+      .ok()
+      .and_then(|x| serde_json::from_str::<SubscribeRequest>(x).ok()),
+    _ => None,
+  };
+  let req = match req {
+    Some(x) => x,
+    None => {
+      let msg = SubscriptionMessage::Error(format!("{}", SubscriptionError::BadRequest));
+      let _ = send(&mut tx, &msg).await;
+      return;
+    }
+  };
+
+  // Subscribe before the first read so that no change is missed.
+  let st = get_state();
+  let mut changes = st.change_feed.subscribe(&namespace_id);
+  let mut reloads = st.reloader.subscribe(&namespace_id, &deployment_id);
+  let sub = match Subscription::open(
+    &namespace_id,
+    &deployment_id,
+    set_name,
+    authorization.as_deref(),
+    &req,
+  )
+  .await
+  {
+    Ok(x) => x,
+    Err(e) => {
+      let _ = send(&mut tx, &SubscriptionMessage::Error(format!("{}", e))).await;
+      return;
+    }
+  };
+
+  let mut resync = req.initial_snapshot;
+  loop {
+    if resync {
+      resync = false;
+      let msg = match sub.snapshot().await {
+        Ok(x) => SubscriptionMessage::Snapshot(x),
+        Err(e) => SubscriptionMessage::Error(format!("{}", e)),
+      };
+      if send(&mut tx, &msg).await.is_err() {
+        return;
+      }
+    }
+
+    tokio::select! {
+      event = changes.next() => {
+        let members = match event {
+          Some(x) => sub.changed_members(&x),
+          None => Ok(None),
+        };
+        match members {
+          Ok(Some(members)) => {
+            for key in members {
+              let msg = match sub.member_event(&key).await {
+                Ok(x) => x,
+                Err(e) => SubscriptionMessage::Error(format!("{}", e)),
+              };
+              if send(&mut tx, &msg).await.is_err() {
+                return;
+              }
+            }
+          }
+          Ok(None) => resync = true,
+          Err(e) => {
+            let _ = send(&mut tx, &SubscriptionMessage::Error(format!("{}", e))).await;
+            return;
+          }
+        }
+      }
+      _ = reloads.deployment_deleted() => {
+        // Lagging behind the reloader also ends up here, so check whether the deployment is
+        // really gone.
+        if let Err(e) = st.vm_pool.get_or_load_schema(&namespace_id, &deployment_id).await {
+          let _ = send(&mut tx, &SubscriptionMessage::Error(format!("{}", e))).await;
+          return;
+        }
+      }
+      msg = rx.next() => match msg {
+        Some(Ok(x)) if !x.is_close() => {}
+        _ => return,
+      }
+    }
+  }
+}
+
+async fn send(
+  tx: &mut SplitSink<WebSocket, Message>,
+  msg: &SubscriptionMessage,
+) -> Result<(), warp::Error> {
+  tx.send(Message::text(serde_json::to_string(msg).unwrap()))
+    .await
+}
+
+impl Subscription {
+  async fn open(
+    namespace_id: &str,
+    deployment_id: &str,
+    set_name: String,
+    authorization: Option<&str>,
+    req: &SubscribeRequest,
+  ) -> Result<Self> {
+    let st = get_state();
+    authenticate(namespace_id, authorization).await?;
+    st.quota.admit(namespace_id, 1).await?;
+    let kv = namespace_kv(namespace_id).await?;
+    let schema_ctx = st
+      .vm_pool
+      .get_or_load_schema(namespace_id, deployment_id)
+      .await?;
+
+    let member_ty = match schema_ctx.schema.exports.get(set_name.as_str()) {
+      Some(FieldType::Set(x)) => x,
+      _ => return Err(SubscriptionError::NotSet(set_name).into()),
+    };
+    let (primary_key, primary_key_ty) = match &**member_ty {
+      FieldType::Table(x) => schema_ctx
+        .schema
+        .types
+        .get(x)
+        .and_then(|x| {
+          x.fields
+            .iter()
+            .find(|(_, (_, annotations))| annotations.as_slice().is_primary())
+        })
+        .and_then(|(name, (ty, _))| match ty {
+          FieldType::Primitive(x) => Some((name.to_string(), *x)),
+          _ => None,
+        })
+        .ok_or_else(|| SubscriptionError::NotSet(set_name.clone()))?,
+      _ => return Err(SubscriptionError::NotSet(set_name).into()),
+    };
+    let decode_bound = |x: &Option<SerializedVmValue>| -> Result<Option<PrimitiveValue>> {
+      x.as_ref()
+        .map(|x| match x.decode(&VmType::Primitive(primary_key_ty)) {
+          Ok(VmValue::Primitive(x)) => Ok(x),
+          _ => Err(anyhow::Error::from(SubscriptionError::BadBound)),
+        })
+        .transpose()
+    };
+    let start = decode_bound(&req.start)?;
+    let end = decode_bound(&req.end)?;
+
+    Ok(Self {
+      set_name,
+      schema_ctx,
+      kv,
+      primary_key,
+      start,
+      end,
+      config: VmValueEncodeConfig {
+        size_limit: st.result_size_limit,
+        ..Default::default()
+      },
+    })
+  }
+
+  /// Returns the encoded primary keys of the members in the range touched by the writes of
+  /// `event`, or `None` if the writes are not known or some cannot be mapped to members.
+  fn changed_members(&self, event: &ChangeEvent) -> Result<Option<BTreeSet<Vec<u8>>>> {
+    let writes = match &event.writes {
+      Some(x) => x,
+      None => return Ok(None),
+    };
+    let walker = PathWalker::from_export(&self.schema_ctx.plan, &self.set_name)?;
+    let set_start = walker.key();
+    let set_end = successor_of_bounded_prefix(set_start);
+    let start = self.start.as_ref().map(|x| walker.encode_primary_key(x));
+    let end = self.end.as_ref().map(|x| walker.encode_primary_key(x));
+    let in_range = |key: &[u8]| {
+      start.as_ref().map(|x| key >= &x[..]).unwrap_or(true)
+        && end.as_ref().map(|x| key < &x[..]).unwrap_or(true)
+    };
+
+    let mut members = BTreeSet::new();
+    for write in writes {
+      let member = match write {
+        KeyWrite::Key(key) => walker.set_member_of_key(key)?,
+        KeyWrite::Range(from, to) => {
+          if &to[..] <= set_start || &from[..] >= &set_end[..] {
+            continue;
+          }
+          // Deleting a member deletes the range of its data.
+          match walker.set_member_of_key(from)? {
+            Some(x) => {
+              let member_start = [&walker.set_data_prefix()?[..], &x[..], &[0x00u8][..]].concat();
+              if from[..] < member_start[..]
+                || to[..] > successor_of_bounded_prefix(&member_start)[..]
+              {
+                return Ok(None);
+              }
+              Some(x)
+            }
+            None => return Ok(None),
+          }
+        }
+      };
+      if let Some(x) = member {
+        if in_range(&x) {
+          members.insert(x);
+        }
+      }
+    }
+    Ok(Some(members))
+  }
+
+  /// Reads the member with the encoded primary key `key`.
+  async fn member_event(&self, key: &[u8]) -> Result<SubscriptionMessage> {
+    let key = decode_key(key).ok_or(SubscriptionError::BadKey)?;
+    let value = self
+      .read(vec![PathSegment::Filter(vec![Predicate {
+        field: self.primary_key.clone(),
+        op: CompareOp::Eq,
+        value: Operand::Literal(key.clone()),
+      }])])
+      .await?;
+    let key = SerializedVmValue::encode(&VmValue::Primitive(key), &self.config)?;
+    Ok(match value {
+      SerializedVmValue::Null(_) => SubscriptionMessage::Delete { key },
+      value => SubscriptionMessage::Put { key, value },
+    })
+  }
+
+  /// Reads all members in the range.
+  async fn snapshot(&self) -> Result<SerializedVmValue> {
+    let mut predicates = vec![];
+    for (bound, op) in &[(&self.start, CompareOp::Ge), (&self.end, CompareOp::Lt)] {
+      if let Some(x) = bound {
+        predicates.push(Predicate {
+          field: self.primary_key.clone(),
+          op: *op,
+          value: Operand::Literal(x.clone()),
+        });
+      }
+    }
+    let segments = if predicates.is_empty() {
+      vec![]
+    } else {
+      vec![PathSegment::Filter(predicates)]
+    };
+    self.read(segments).await
+  }
+
+  /// Runs a path query on the set in a transaction that is never committed.
+  async fn read(&self, segments: Vec<PathSegment>) -> Result<SerializedVmValue> {
+    let mut planner = QueryPlanner::new(&self.schema_ctx.schema);
+    planner.add_query(&PathQuery {
+      root: self.set_name.clone(),
+      segments,
+    })?;
+    let plan = planner.finish()?;
+    let txn = self.kv.begin_transaction().await?;
+    let mut output = exec_query_plan(
+      &self.schema_ctx.schema,
+      &self.schema_ctx.plan,
+      &*txn,
+      &plan,
+      &self.config,
+    )
+    .await?;
+    Ok(
+      output
+        .pop()
+        .expect("inconsistency: a query fulfills one value"),
+    )
+  }
+}
@@ -1,9 +1,21 @@
 use anyhow::Result;
-use rdb_analyzer::data::treewalker::serialize::{SerializedVmValue, VmValueEncodeConfig};
+use maplit::btreemap;
+use rand::RngCore;
+use rdb_analyzer::data::{
+  kv::KeyValueStore,
+  treewalker::serialize::{SerializedVmValue, TaggedVmValue, VmValueEncodeConfig},
+};
 
-use crate::state::get_state;
+use crate::{
+  key_encoding::{clear_key_encoding, record_key_encoding},
+  state::get_state,
+  util::current_millis,
+};
 use thiserror::Error;
 
+/// Max number of keys deleted in a single transaction when wiping a namespace.
+const WIPE_BATCH_SIZE: usize = 1000;
+
 #[derive(Error, Debug)]
 pub enum SysQueryError {
   #[error("namespace not found")]
@@ -11,15 +23,91 @@ pub enum SysQueryError {
 
   #[error("query script not found")]
   QueryScriptNotFound,
+
+  #[error("the schema of the namespace is detached")]
+  SchemaDetached,
+}
+
+pub struct Namespace {
+  pub id: String,
+  pub kv_prefix: Vec<u8>,
+  pub create_time: i64,
+
+  /// When the schema was detached, or zero if it is attached.
+  pub schema_detach_time: i64,
 }
 
 pub struct QueryScript {
   pub id: String,
+
+  /// Creation time of the active version.
   pub create_time: i64,
+
+  /// When the active version was uploaded or last activated.
+  pub activate_time: i64,
   pub associated_deployment: String,
   pub script: String,
 }
 
+pub struct ApiToken {
+  pub id: String,
+  pub secret_hash: Vec<u8>,
+  pub role: String,
+  pub create_time: i64,
+}
+
+pub struct AuditEntry {
+  /// Hex of the time followed by a random suffix, so that ids sort by time.
+  pub id: String,
+  pub time: i64,
+  pub query_script_id: String,
+  pub graph_name: String,
+  pub params_hash: String,
+
+  /// `ok`, or the error message.
+  pub status: String,
+  pub keys_written: i64,
+  pub duration_us: i64,
+}
+
+pub struct SlowQuery {
+  /// Hex of the time followed by a random suffix, so that ids sort by time.
+  pub id: String,
+  pub time: i64,
+  pub query_script_id: String,
+  pub graph_name: String,
+
+  /// Types and sizes of the params, without their values.
+  pub params: String,
+
+  /// `ok`, or the error message.
+  pub status: String,
+  pub duration_us: i64,
+
+  /// Number of attempts whose commit conflicted.
+  pub conflicts: i64,
+
+  /// JSON of the `GraphProfile` of the last attempt, or empty if the run failed.
+  pub profile: String,
+}
+
+/// Per-namespace limits. Zero means unlimited.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Quota {
+  pub max_executions_per_sec: u64,
+  pub max_kv_ops_per_execution: u64,
+  pub max_storage_bytes: u64,
+}
+
+/// The lease that serializes deployments to a namespace. The holder is empty if the lease is not
+/// held.
+pub struct DeploymentLease {
+  pub holder: String,
+
+  /// Milliseconds since the epoch.
+  pub expiry: i64,
+}
+
 pub struct Deployment {
   pub id: String,
   pub description: String,
@@ -28,6 +116,201 @@ pub struct Deployment {
   pub create_time: i64,
 }
 
+/// Creates a namespace with a freshly allocated KV prefix. Returns `false` if it already exists.
+pub async fn create_namespace(ns_id: &str) -> Result<bool> {
+  let st = get_state();
+
+  let mut kv_prefix: [u8; 16] = [0u8; 16];
+  rand::thread_rng().fill_bytes(&mut kv_prefix);
+
+  // Recorded before the namespace becomes visible, so that it is never used without a version.
+  let mut data_prefix = kv_prefix.to_vec();
+  data_prefix.push(0);
+  record_key_encoding(&data_prefix).await?;
+
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "add_namespace",
+      &[
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String(ns_id.into()),
+        SerializedVmValue::String(base64::encode(&kv_prefix)),
+        SerializedVmValue::String(format!("{}", current_millis())),
+      ],
+      &Default::default(),
+    )
+    .await?;
+  res.check_nonnull()?;
+  let created = res.try_unwrap_bool()?;
+  if !created {
+    clear_key_encoding(&data_prefix).await?;
+  }
+  Ok(created)
+}
+
+pub async fn list_namespaces() -> Result<Vec<Namespace>> {
+  let st = get_state();
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "list_namespaces",
+      &[SerializedVmValue::Null(None)],
+      &VmValueEncodeConfig {
+        enable_bytes: true,
+        enable_double: true,
+        enable_int64: true,
+        ..Default::default()
+      },
+    )
+    .await?;
+  res.check_nonnull()?;
+  res
+    .try_unwrap_list()?
+    .iter()
+    .map(|x| {
+      let m = x.try_unwrap_map(&["id", "kv_prefix", "create_time", "schema_detach_time"])?;
+      Ok(Namespace {
+        id: m.get("id").unwrap().try_unwrap_string()?.clone(),
+        kv_prefix: m.get("kv_prefix").unwrap().try_unwrap_bytes()?.clone(),
+        create_time: m.get("create_time").unwrap().try_unwrap_int64()?,
+        schema_detach_time: m.get("schema_detach_time").unwrap().try_unwrap_int64()?,
+      })
+    })
+    .collect()
+}
+
+/// Wipes all data in a namespace, and then deletes the namespace itself. Returns `false` if the
+/// namespace does not exist.
+pub async fn delete_namespace(ns_id: &str) -> Result<bool> {
+  let st = get_state();
+
+  if let Ok(mut kv_prefix) = ns_to_kv_prefix_with_appended_zero(ns_id).await {
+    // Remove trailing zero
+    let popped = kv_prefix.pop().unwrap();
+    assert_eq!(popped, 0);
+
+    // Data at `0x00`, statistics at `0x01` and metadata at `0x02`.
+    let full_range = (st.data_store_generator)(&kv_prefix);
+    let num_deleted = wipe_range_in_batches(&*full_range, &[0x00], &[0x03]).await?;
+    log::info!("Wiped {} key(s) from namespace `{}`.", num_deleted, ns_id);
+  }
+
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "delete_namespace",
+      &[
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String(ns_id.into()),
+      ],
+      &Default::default(),
+    )
+    .await?;
+  res.check_nonnull()?;
+  res.try_unwrap_bool()
+}
+
+/// Deletes all keys in `[start, end)`, at most `WIPE_BATCH_SIZE` keys per transaction, so that
+/// large namespaces don't exceed transaction size limits of the backend.
+async fn wipe_range_in_batches(kv: &dyn KeyValueStore, start: &[u8], end: &[u8]) -> Result<usize> {
+  let mut num_deleted = 0usize;
+  loop {
+    let txn = kv.begin_transaction().await?;
+    let mut it = txn.scan_keys(start, end).await?;
+    let mut batch = Vec::with_capacity(WIPE_BATCH_SIZE);
+    while batch.len() < WIPE_BATCH_SIZE {
+      match it.next().await? {
+        Some(x) => batch.push(x),
+        None => break,
+      }
+    }
+    drop(it);
+    if batch.is_empty() {
+      return Ok(num_deleted);
+    }
+    for k in &batch {
+      txn.delete(k).await?;
+    }
+    txn.commit().await?;
+    num_deleted += batch.len();
+  }
+}
+
+/// Detaches the schema from a namespace, so that its data cannot be queried until the schema is
+/// attached again. Returns `false` if the namespace does not exist or is already detached.
+pub async fn detach_schema(ns_id: &str) -> Result<bool> {
+  let st = get_state();
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "detach_schema",
+      &[
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String(ns_id.into()),
+        SerializedVmValue::String(format!("{}", current_millis())),
+      ],
+      &Default::default(),
+    )
+    .await?;
+  res.check_nonnull()?;
+  res.try_unwrap_bool()
+}
+
+/// Attaches the schema of the head deployment to a namespace again. Returns `false` if the
+/// namespace does not exist or is not detached.
+pub async fn attach_schema(ns_id: &str) -> Result<bool> {
+  let st = get_state();
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "attach_schema",
+      &[
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String(ns_id.into()),
+      ],
+      &Default::default(),
+    )
+    .await?;
+  res.check_nonnull()?;
+  res.try_unwrap_bool()
+}
+
+/// Fails with `SysQueryError::SchemaDetached` if the schema of the namespace is detached.
+pub async fn check_schema_attached(ns_id: &str) -> Result<()> {
+  let st = get_state();
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "get_schema_detach_time",
+      &[
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String(ns_id.into()),
+      ],
+      &VmValueEncodeConfig {
+        enable_int64: true,
+        ..Default::default()
+      },
+    )
+    .await?;
+  if res.try_unwrap_int64()? != 0 {
+    return Err(SysQueryError::SchemaDetached.into());
+  }
+  Ok(())
+}
+
 pub async fn ns_to_kv_prefix_with_appended_zero(ns_id: &str) -> Result<Vec<u8>> {
   let st = get_state();
   let res = st
@@ -44,6 +327,7 @@ pub async fn ns_to_kv_prefix_with_appended_zero(ns_id: &str) -> Result<Vec<u8>>
         enable_bytes: true,
         enable_double: true,
         enable_int64: true,
+        ..Default::default()
       },
     )
     .await?;
@@ -74,16 +358,24 @@ pub async fn lookup_query_script(ns_id: &str, qs_id: &str) -> Result<QueryScript
         enable_bytes: true,
         enable_double: true,
         enable_int64: true,
+        ..Default::default()
       },
     )
     .await?;
   match res {
     SerializedVmValue::Null(_) => Err(SysQueryError::QueryScriptNotFound.into()),
     _ => {
-      let m = res.try_unwrap_map(&["id", "create_time", "associated_deployment", "script"])?;
+      let m = res.try_unwrap_map(&[
+        "id",
+        "create_time",
+        "activate_time",
+        "associated_deployment",
+        "script",
+      ])?;
       Ok(QueryScript {
         id: m.get("id").unwrap().try_unwrap_string()?.clone(),
         create_time: m.get("create_time").unwrap().try_unwrap_int64()?,
+        activate_time: m.get("activate_time").unwrap().try_unwrap_int64()?,
         associated_deployment: m
           .get("associated_deployment")
           .unwrap()
@@ -112,6 +404,7 @@ pub async fn lookup_deployment(namespace_id: &str, deployment_id: &str) -> Resul
         enable_bytes: true,
         enable_double: true,
         enable_int64: true,
+        ..Default::default()
       },
     )
     .await?;
@@ -125,3 +418,522 @@ pub async fn lookup_deployment(namespace_id: &str, deployment_id: &str) -> Resul
   };
   Ok(depl)
 }
+
+/// The deployment that new deployments to a namespace must be derived from, or `None` if the
+/// namespace has none.
+pub async fn lookup_head_deployment(namespace_id: &str) -> Result<Option<String>> {
+  let st = get_state();
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "get_head_deployment",
+      &[
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String(namespace_id.into()),
+      ],
+      &Default::default(),
+    )
+    .await?;
+  if let SerializedVmValue::Null(_) = res {
+    return Ok(None);
+  }
+  Ok(Some(res.try_unwrap_string()?.clone()))
+}
+
+pub async fn lookup_deployment_lease(namespace_id: &str) -> Result<DeploymentLease> {
+  let st = get_state();
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "get_deployment_lease",
+      &[
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String(namespace_id.into()),
+      ],
+      &VmValueEncodeConfig {
+        enable_int64: true,
+        ..Default::default()
+      },
+    )
+    .await?;
+  if let SerializedVmValue::Null(_) = res {
+    return Err(SysQueryError::NamespaceNotFound.into());
+  }
+  let m = res.try_unwrap_map(&[])?;
+
+  // Fields are null until the lease is first taken.
+  Ok(DeploymentLease {
+    holder: match m.get("holder") {
+      None | Some(SerializedVmValue::Null(_)) => String::new(),
+      Some(x) => x.try_unwrap_string()?.clone(),
+    },
+    expiry: match m.get("expiry") {
+      None | Some(SerializedVmValue::Null(_)) => 0,
+      Some(x) => x.try_unwrap_int64()?,
+    },
+  })
+}
+
+/// Replaces the deployment lease of a namespace, if its holder is still `expected_holder`.
+/// Returns `false` otherwise, or if the namespace does not exist.
+pub async fn swap_deployment_lease(
+  namespace_id: &str,
+  expected_holder: &str,
+  lease: &DeploymentLease,
+) -> Result<bool> {
+  let st = get_state();
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "swap_deployment_lease",
+      &[
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String(namespace_id.into()),
+        SerializedVmValue::String(expected_holder.into()),
+        SerializedVmValue::Tagged(TaggedVmValue::M(btreemap! {
+          "holder".to_string() => SerializedVmValue::String(lease.holder.clone()),
+          "expiry".to_string() => SerializedVmValue::String(format!("{}", lease.expiry)),
+        })),
+      ],
+      &Default::default(),
+    )
+    .await?;
+  res.check_nonnull()?;
+  res.try_unwrap_bool()
+}
+
+pub async fn lookup_quota(ns_id: &str) -> Result<Quota> {
+  let st = get_state();
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "get_quota",
+      &[
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String(ns_id.into()),
+      ],
+      &VmValueEncodeConfig {
+        enable_bytes: true,
+        enable_double: true,
+        enable_int64: true,
+        ..Default::default()
+      },
+    )
+    .await?;
+  if let SerializedVmValue::Null(_) = res {
+    return Err(SysQueryError::NamespaceNotFound.into());
+  }
+  let m = res.try_unwrap_map(&[])?;
+
+  // Fields are null until a quota is set.
+  let limit = |name: &str| -> Result<u64> {
+    match m.get(name) {
+      None | Some(SerializedVmValue::Null(_)) => Ok(0),
+      Some(x) => Ok(x.try_unwrap_int64()?.max(0) as u64),
+    }
+  };
+  Ok(Quota {
+    max_executions_per_sec: limit("max_executions_per_sec")?,
+    max_kv_ops_per_execution: limit("max_kv_ops_per_execution")?,
+    max_storage_bytes: limit("max_storage_bytes")?,
+  })
+}
+
+/// Sets the quota of a namespace. Returns `false` if the namespace does not exist.
+pub async fn set_quota(ns_id: &str, quota: &Quota) -> Result<bool> {
+  let st = get_state();
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "set_quota",
+      &[
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String(ns_id.into()),
+        SerializedVmValue::Tagged(TaggedVmValue::M(btreemap! {
+          "max_executions_per_sec".to_string() => SerializedVmValue::String(format!("{}", quota.max_executions_per_sec)),
+          "max_kv_ops_per_execution".to_string() => SerializedVmValue::String(format!("{}", quota.max_kv_ops_per_execution)),
+          "max_storage_bytes".to_string() => SerializedVmValue::String(format!("{}", quota.max_storage_bytes)),
+        })),
+      ],
+      &Default::default(),
+    )
+    .await?;
+  res.check_nonnull()?;
+  res.try_unwrap_bool()
+}
+
+/// Adds an API token. Returns `false` if the namespace does not exist or the token id is taken.
+pub async fn add_api_token(ns_id: &str, token: &ApiToken) -> Result<bool> {
+  let st = get_state();
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "add_api_token",
+      &[
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String(ns_id.into()),
+        SerializedVmValue::Tagged(TaggedVmValue::M(btreemap! {
+          "id".to_string() => SerializedVmValue::String(token.id.clone()),
+          "secret_hash".to_string() => SerializedVmValue::String(base64::encode(&token.secret_hash)),
+          "role".to_string() => SerializedVmValue::String(token.role.clone()),
+          "create_time".to_string() => SerializedVmValue::String(format!("{}", token.create_time)),
+        })),
+      ],
+      &Default::default(),
+    )
+    .await?;
+  res.check_nonnull()?;
+  res.try_unwrap_bool()
+}
+
+pub async fn lookup_api_token(ns_id: &str, token_id: &str) -> Result<Option<ApiToken>> {
+  let st = get_state();
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "get_api_token",
+      &[
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String(ns_id.into()),
+        SerializedVmValue::String(token_id.into()),
+      ],
+      &VmValueEncodeConfig {
+        enable_bytes: true,
+        enable_double: true,
+        enable_int64: true,
+        ..Default::default()
+      },
+    )
+    .await?;
+  if let SerializedVmValue::Null(_) = res {
+    return Ok(None);
+  }
+  let m = res.try_unwrap_map(&["id", "secret_hash", "role", "create_time"])?;
+  Ok(Some(ApiToken {
+    id: m.get("id").unwrap().try_unwrap_string()?.clone(),
+    secret_hash: m.get("secret_hash").unwrap().try_unwrap_bytes()?.clone(),
+    role: m.get("role").unwrap().try_unwrap_string()?.clone(),
+    create_time: m.get("create_time").unwrap().try_unwrap_int64()?,
+  }))
+}
+
+/// Replaces the secret of an API token. Returns `false` if the token does not exist.
+pub async fn rotate_api_token(ns_id: &str, token_id: &str, secret_hash: &[u8]) -> Result<bool> {
+  let st = get_state();
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "rotate_api_token",
+      &[
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String(ns_id.into()),
+        SerializedVmValue::String(token_id.into()),
+        SerializedVmValue::String(base64::encode(secret_hash)),
+        SerializedVmValue::String(format!("{}", current_millis())),
+      ],
+      &Default::default(),
+    )
+    .await?;
+  res.check_nonnull()?;
+  res.try_unwrap_bool()
+}
+
+pub async fn delete_api_token(ns_id: &str, token_id: &str) -> Result<bool> {
+  let st = get_state();
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "delete_api_token",
+      &[
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String(ns_id.into()),
+        SerializedVmValue::String(token_id.into()),
+      ],
+      &Default::default(),
+    )
+    .await?;
+  res.check_nonnull()?;
+  res.try_unwrap_bool()
+}
+
+/// Lists the API tokens of a namespace. Secret hashes are left empty.
+pub async fn list_api_tokens(ns_id: &str) -> Result<Vec<ApiToken>> {
+  let st = get_state();
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "list_api_tokens",
+      &[
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String(ns_id.into()),
+      ],
+      &VmValueEncodeConfig {
+        enable_bytes: true,
+        enable_double: true,
+        enable_int64: true,
+        ..Default::default()
+      },
+    )
+    .await?;
+  if let SerializedVmValue::Null(_) = res {
+    return Err(SysQueryError::NamespaceNotFound.into());
+  }
+  res
+    .try_unwrap_list()?
+    .iter()
+    .map(|x| {
+      let m = x.try_unwrap_map(&["id", "role", "create_time"])?;
+      Ok(ApiToken {
+        id: m.get("id").unwrap().try_unwrap_string()?.clone(),
+        secret_hash: vec![],
+        role: m.get("role").unwrap().try_unwrap_string()?.clone(),
+        create_time: m.get("create_time").unwrap().try_unwrap_int64()?,
+      })
+    })
+    .collect()
+}
+
+pub async fn append_audit_entry(ns_id: &str, entry: &AuditEntry) -> Result<bool> {
+  let st = get_state();
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "append_audit_entry",
+      &[
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String(ns_id.into()),
+        SerializedVmValue::Tagged(TaggedVmValue::M(btreemap! {
+          "id".to_string() => SerializedVmValue::String(entry.id.clone()),
+          "time".to_string() => SerializedVmValue::String(format!("{}", entry.time)),
+          "query_script_id".to_string() => SerializedVmValue::String(entry.query_script_id.clone()),
+          "graph_name".to_string() => SerializedVmValue::String(entry.graph_name.clone()),
+          "params_hash".to_string() => SerializedVmValue::String(entry.params_hash.clone()),
+          "status".to_string() => SerializedVmValue::String(entry.status.clone()),
+          "keys_written".to_string() => SerializedVmValue::String(format!("{}", entry.keys_written)),
+          "duration_us".to_string() => SerializedVmValue::String(format!("{}", entry.duration_us)),
+        })),
+      ],
+      &Default::default(),
+    )
+    .await?;
+  res.check_nonnull()?;
+  res.try_unwrap_bool()
+}
+
+/// Lists the audit entries with ids in `[start_id, end_id)`, newest first.
+pub async fn list_audit_entries(
+  ns_id: &str,
+  start_id: &str,
+  end_id: &str,
+) -> Result<Vec<AuditEntry>> {
+  let st = get_state();
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "list_audit_entries",
+      &[
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String(ns_id.into()),
+        SerializedVmValue::String(start_id.into()),
+        SerializedVmValue::String(end_id.into()),
+      ],
+      &VmValueEncodeConfig {
+        enable_bytes: true,
+        enable_double: true,
+        enable_int64: true,
+        ..Default::default()
+      },
+    )
+    .await?;
+  if let SerializedVmValue::Null(_) = res {
+    return Err(SysQueryError::NamespaceNotFound.into());
+  }
+  res
+    .try_unwrap_list()?
+    .iter()
+    .map(|x| {
+      let m = x.try_unwrap_map(&[
+        "id",
+        "time",
+        "query_script_id",
+        "graph_name",
+        "params_hash",
+        "status",
+        "keys_written",
+        "duration_us",
+      ])?;
+      Ok(AuditEntry {
+        id: m.get("id").unwrap().try_unwrap_string()?.clone(),
+        time: m.get("time").unwrap().try_unwrap_int64()?,
+        query_script_id: m
+          .get("query_script_id")
+          .unwrap()
+          .try_unwrap_string()?
+          .clone(),
+        graph_name: m.get("graph_name").unwrap().try_unwrap_string()?.clone(),
+        params_hash: m.get("params_hash").unwrap().try_unwrap_string()?.clone(),
+        status: m.get("status").unwrap().try_unwrap_string()?.clone(),
+        keys_written: m.get("keys_written").unwrap().try_unwrap_int64()?,
+        duration_us: m.get("duration_us").unwrap().try_unwrap_int64()?,
+      })
+    })
+    .collect()
+}
+
+pub async fn delete_audit_entry(ns_id: &str, entry_id: &str) -> Result<bool> {
+  let st = get_state();
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "delete_audit_entry",
+      &[
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String(ns_id.into()),
+        SerializedVmValue::String(entry_id.into()),
+      ],
+      &Default::default(),
+    )
+    .await?;
+  res.check_nonnull()?;
+  res.try_unwrap_bool()
+}
+
+pub async fn append_slow_query(ns_id: &str, entry: &SlowQuery) -> Result<bool> {
+  let st = get_state();
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "append_slow_query",
+      &[
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String(ns_id.into()),
+        SerializedVmValue::Tagged(TaggedVmValue::M(btreemap! {
+          "id".to_string() => SerializedVmValue::String(entry.id.clone()),
+          "time".to_string() => SerializedVmValue::String(format!("{}", entry.time)),
+          "query_script_id".to_string() => SerializedVmValue::String(entry.query_script_id.clone()),
+          "graph_name".to_string() => SerializedVmValue::String(entry.graph_name.clone()),
+          "params".to_string() => SerializedVmValue::String(entry.params.clone()),
+          "status".to_string() => SerializedVmValue::String(entry.status.clone()),
+          "duration_us".to_string() => SerializedVmValue::String(format!("{}", entry.duration_us)),
+          "conflicts".to_string() => SerializedVmValue::String(format!("{}", entry.conflicts)),
+          "profile".to_string() => SerializedVmValue::String(entry.profile.clone()),
+        })),
+      ],
+      &Default::default(),
+    )
+    .await?;
+  res.check_nonnull()?;
+  res.try_unwrap_bool()
+}
+
+/// Lists the slow queries with ids in `[start_id, end_id)`, newest first.
+pub async fn list_slow_queries(
+  ns_id: &str,
+  start_id: &str,
+  end_id: &str,
+) -> Result<Vec<SlowQuery>> {
+  let st = get_state();
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "list_slow_queries",
+      &[
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String(ns_id.into()),
+        SerializedVmValue::String(start_id.into()),
+        SerializedVmValue::String(end_id.into()),
+      ],
+      &VmValueEncodeConfig {
+        enable_bytes: true,
+        enable_double: true,
+        enable_int64: true,
+        ..Default::default()
+      },
+    )
+    .await?;
+  if let SerializedVmValue::Null(_) = res {
+    return Err(SysQueryError::NamespaceNotFound.into());
+  }
+  res
+    .try_unwrap_list()?
+    .iter()
+    .map(|x| {
+      let m = x.try_unwrap_map(&[
+        "id",
+        "time",
+        "query_script_id",
+        "graph_name",
+        "params",
+        "status",
+        "duration_us",
+        "conflicts",
+        "profile",
+      ])?;
+      Ok(SlowQuery {
+        id: m.get("id").unwrap().try_unwrap_string()?.clone(),
+        time: m.get("time").unwrap().try_unwrap_int64()?,
+        query_script_id: m
+          .get("query_script_id")
+          .unwrap()
+          .try_unwrap_string()?
+          .clone(),
+        graph_name: m.get("graph_name").unwrap().try_unwrap_string()?.clone(),
+        params: m.get("params").unwrap().try_unwrap_string()?.clone(),
+        status: m.get("status").unwrap().try_unwrap_string()?.clone(),
+        duration_us: m.get("duration_us").unwrap().try_unwrap_int64()?,
+        conflicts: m.get("conflicts").unwrap().try_unwrap_int64()?,
+        profile: m.get("profile").unwrap().try_unwrap_string()?.clone(),
+      })
+    })
+    .collect()
+}
+
+pub async fn delete_slow_query(ns_id: &str, entry_id: &str) -> Result<bool> {
+  let st = get_state();
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "delete_slow_query",
+      &[
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String(ns_id.into()),
+        SerializedVmValue::String(entry_id.into()),
+      ],
+      &Default::default(),
+    )
+    .await?;
+  res.check_nonnull()?;
+  res.try_unwrap_bool()
+}
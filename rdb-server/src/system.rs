@@ -20,8 +20,12 @@ pub const SCHEMA: &str = include_str!("./system_schema.rschema");
 pub const SYS_RASM: &str = include_str!("./sys.rasm");
 
 impl SystemSchema {
+  /// Loads the system schema, migrating it if it changed since the last run.
+  ///
+  /// If the system schema does not exist yet, it is only created when `bootstrap` is set.
   pub async fn new(
     migration_hash: Option<String>,
+    bootstrap: bool,
     _store: &dyn KeyValueStore,
     meta_store: &dyn KeyValueStore,
   ) -> Self {
@@ -75,6 +79,12 @@ impl SystemSchema {
       }
       new_plan
     } else {
+      if !bootstrap {
+        log::error!(
+          "System schema not found. Please rerun the server with `--bootstrap` to initialize it."
+        );
+        std::process::abort();
+      }
       let new_plan =
         generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
       log::warn!("Creating system schema.");
@@ -0,0 +1,143 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use bumpalo::Bump;
+use lru::LruCache;
+use rdb_analyzer::{
+  data::treewalker::bytecode::TwScript,
+  schema::{compile::compile, grammar::parse},
+  storage_plan::StoragePlan,
+};
+use tokio::sync::Mutex;
+
+use crate::{
+  exec_core::{ExecContext, SchemaContext, ScriptHash},
+  sysquery::lookup_deployment,
+};
+
+/// Max number of compiled schemas kept in the pool.
+const SCHEMA_POOL_SIZE: usize = 256;
+
+/// Max number of prepared VMs kept in the pool.
+const VM_POOL_SIZE: usize = 1024;
+
+/// Prepared VMs and type info, shared across requests.
+///
+/// Schemas are compiled once per deployment, and VMs are prepared once per (deployment, script).
+pub struct VmPool {
+  schemas: Mutex<LruCache<DeploymentKey, Arc<SchemaContext>>>,
+  vms: Mutex<LruCache<VmPoolKey, Arc<ExecContext>>>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+struct DeploymentKey {
+  namespace_id: String,
+  deployment_id: String,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+struct VmPoolKey {
+  deployment: DeploymentKey,
+  script_hash: ScriptHash,
+}
+
+impl VmPool {
+  pub fn new() -> Self {
+    Self {
+      schemas: Mutex::new(LruCache::new(SCHEMA_POOL_SIZE)),
+      vms: Mutex::new(LruCache::new(VM_POOL_SIZE)),
+    }
+  }
+
+  /// Returns the prepared VM for `script` on the given deployment, preparing it if not present.
+  pub async fn get_or_load(
+    &self,
+    namespace_id: &str,
+    deployment_id: &str,
+    script: TwScript,
+  ) -> Result<Arc<ExecContext>> {
+    let deployment = DeploymentKey {
+      namespace_id: namespace_id.to_string(),
+      deployment_id: deployment_id.to_string(),
+    };
+    let key = VmPoolKey {
+      deployment: deployment.clone(),
+      script_hash: ScriptHash::compute(&script, deployment_id)?,
+    };
+    if let Some(x) = self.vms.lock().await.get(&key) {
+      return Ok(x.clone());
+    }
+
+    let schema_ctx = self.get_or_load_schema_by_key(deployment).await?;
+    let exec_ctx = Arc::new(ExecContext::load_compiled(schema_ctx, script)?);
+    log::info!(
+      "Prepared VM for script {} on deployment {:?}.",
+      key.script_hash,
+      key.deployment
+    );
+    self.vms.lock().await.put(key, exec_ctx.clone());
+    Ok(exec_ctx)
+  }
+
+  /// Returns the compiled schema and storage plan of the given deployment.
+  pub async fn get_or_load_schema(
+    &self,
+    namespace_id: &str,
+    deployment_id: &str,
+  ) -> Result<Arc<SchemaContext>> {
+    self
+      .get_or_load_schema_by_key(DeploymentKey {
+        namespace_id: namespace_id.to_string(),
+        deployment_id: deployment_id.to_string(),
+      })
+      .await
+  }
+
+  async fn get_or_load_schema_by_key(&self, key: DeploymentKey) -> Result<Arc<SchemaContext>> {
+    if let Some(x) = self.schemas.lock().await.get(&key) {
+      return Ok(x.clone());
+    }
+
+    let deployment = lookup_deployment(&key.namespace_id, &key.deployment_id).await?;
+    let schema = compile(&parse(&Bump::new(), &deployment.schema)?)?;
+    let plan = StoragePlan::deserialize_compressed(&deployment.plan)?;
+    let schema_ctx = Arc::new(SchemaContext { schema, plan });
+    self.schemas.lock().await.put(key, schema_ctx.clone());
+    Ok(schema_ctx)
+  }
+
+  /// Invalidation hook. Called when a deployment is deleted or replaced.
+  pub async fn invalidate_deployment(&self, namespace_id: &str, deployment_id: &str) {
+    let matches =
+      |x: &DeploymentKey| x.namespace_id == namespace_id && x.deployment_id == deployment_id;
+    retain(&mut *self.schemas.lock().await, |k| !matches(k));
+    retain(&mut *self.vms.lock().await, |k| !matches(&k.deployment));
+  }
+
+  /// Invalidation hook. Called when a namespace is deleted.
+  pub async fn invalidate_namespace(&self, namespace_id: &str) {
+    retain(&mut *self.schemas.lock().await, |k| {
+      k.namespace_id != namespace_id
+    });
+    retain(&mut *self.vms.lock().await, |k| {
+      k.deployment.namespace_id != namespace_id
+    });
+  }
+}
+
+impl Default for VmPool {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+fn retain<K: Clone + Eq + std::hash::Hash, V>(cache: &mut LruCache<K, V>, f: impl Fn(&K) -> bool) {
+  let removed = cache
+    .iter()
+    .filter(|(k, _)| !f(*k))
+    .map(|(k, _)| k.clone())
+    .collect::<Vec<_>>();
+  for k in removed {
+    cache.pop(&k);
+  }
+}
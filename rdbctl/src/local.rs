@@ -0,0 +1,579 @@
+use std::{convert::TryFrom, sync::Arc};
+
+use anyhow::Result;
+use bumpalo::Bump;
+use clap::Clap;
+use foundationdb::{tuple::Subspace, Database};
+use rdb_analyzer::{
+  data::{
+    inspect, keyenc,
+    kv::{
+      checksum::ChecksummedKv,
+      encrypted::{EncryptedKv, EncryptionKey, StaticKeyProvider},
+      KeyValueStore,
+    },
+    mock_kv::MockKv,
+    treewalker::{
+      asm::codegen::compile_twscript,
+      bytecode::TwScript,
+      clientgen::{rust::generate_rust_client, typescript::generate_typescript_definitions},
+      exec::{generate_root_map, Executor},
+      lint::{lint, LintConfig, Severity},
+      opt::optimize,
+      profile::GraphProfile,
+      serialize::{SerializedVmValue, VmValueEncodeConfig},
+      signature::exported_graph_signatures,
+      typeck::GlobalTyckContext,
+      vm::TwVm,
+      vm_value::VmType,
+    },
+  },
+  schema::{
+    compile::{compile, CompiledSchema},
+    diagnostic::SchemaDiagnostic,
+    doc::SchemaDoc,
+    grammar::parse,
+  },
+  storage_plan::{
+    planner::{generate_plan_for_schema, generate_plan_for_schema_with_options, PlannerOptions},
+    StorageKey, StoragePlan,
+  },
+};
+use rdb_kv_backend::{
+  foundationdb::FdbKvStore,
+  sqlite::{GlobalSqliteStore, SqliteKvStore},
+};
+use thiserror::Error;
+
+#[derive(Clap)]
+pub struct CompileSchema {
+  /// Path to the schema.
+  #[clap(long)]
+  schema: String,
+}
+
+#[derive(Clap)]
+pub struct Plan {
+  /// Path to the schema.
+  #[clap(long)]
+  schema: String,
+
+  /// Path to the old schema to migrate from.
+  #[clap(long)]
+  old_schema: Option<String>,
+
+  /// Path to the old storage plan (YAML) to migrate from.
+  #[clap(long)]
+  old_plan: Option<String>,
+
+  /// Keep the data of primitive fields whose type changed to a type they can be converted to.
+  /// The stored values must be converted before the new plan is used.
+  #[clap(long)]
+  convert_primitives: bool,
+}
+
+#[derive(Clap)]
+pub struct Doc {
+  /// Path to the schema.
+  #[clap(long)]
+  schema: String,
+
+  /// Path to the storage plan (YAML). A fresh plan is generated if not provided.
+  #[clap(long)]
+  plan: Option<String>,
+
+  /// Output format: `json`, `markdown` or `html`.
+  #[clap(long, default_value = "markdown")]
+  format: String,
+}
+
+#[derive(Clap)]
+pub struct CheckScript {
+  /// Path to the schema.
+  #[clap(long)]
+  schema: String,
+
+  /// Path to the storage plan (YAML). A fresh plan is generated if not provided.
+  #[clap(long)]
+  plan: Option<String>,
+
+  /// Path to the script.
+  #[clap(long)]
+  script: String,
+
+  /// Graphs that must not write to the store.
+  #[clap(long)]
+  read_only: Vec<String>,
+}
+
+#[derive(Clap)]
+pub struct ClientGen {
+  /// Path to the schema.
+  #[clap(long)]
+  schema: String,
+
+  /// Path to the storage plan (YAML). A fresh plan is generated if not provided.
+  #[clap(long)]
+  plan: Option<String>,
+
+  /// Path to the script.
+  #[clap(long)]
+  script: String,
+
+  /// Language of the client: `rust`, or `typescript` for definitions of the values accepted and
+  /// returned by the HTTP API.
+  #[clap(long, default_value = "rust")]
+  lang: String,
+}
+
+#[derive(Clap)]
+pub struct Run {
+  /// Path to the schema.
+  #[clap(long)]
+  schema: String,
+
+  /// Path to the storage plan (YAML). A fresh plan is generated if not provided.
+  #[clap(long)]
+  plan: Option<String>,
+
+  /// Path to the script.
+  #[clap(long)]
+  script: String,
+
+  /// Name of the exported graph to run.
+  #[clap(long)]
+  graph: String,
+
+  /// Graph parameters as a JSON array. The `schema` parameter is filled in automatically.
+  #[clap(long, default_value = "[]")]
+  params: String,
+
+  /// Print a profile of the run to stderr, with KV requests and time per node.
+  #[clap(long)]
+  profile: bool,
+
+  /// FoundationDB cluster file to run against, with `--fdb-keyspace`. Runs against an in-memory
+  /// store if no backend is selected.
+  #[clap(long)]
+  fdb_cluster: Option<String>,
+
+  /// FoundationDB keyspace of the server.
+  #[clap(long)]
+  fdb_keyspace: Option<String>,
+
+  /// Path to the SQLite database of the server to run against.
+  #[clap(long)]
+  sqlite_db: Option<String>,
+
+  /// Hex-encoded KV prefix of the namespace, including the trailing zero byte.
+  #[clap(long, default_value = "")]
+  prefix: String,
+
+  /// Whether the server writes data with checksums.
+  #[clap(long)]
+  data_checksums: bool,
+
+  /// Path to the hex-encoded 256-bit key the server encrypts data with.
+  #[clap(long)]
+  data_encryption_key_file: Option<String>,
+}
+
+#[derive(Clap)]
+pub struct Explain {
+  /// Path to the script.
+  #[clap(long)]
+  script: String,
+
+  /// Show the script after optimization.
+  #[clap(long)]
+  optimize: bool,
+}
+
+#[derive(Clap)]
+pub struct Inspect {
+  /// Path to the schema.
+  #[clap(long)]
+  schema: String,
+
+  /// Path to the storage plan (YAML) the data was written with.
+  #[clap(long)]
+  plan: String,
+
+  /// Path to the SQLite database of the server.
+  #[clap(long)]
+  sqlite_db: String,
+
+  /// Table of the SQLite database to read.
+  #[clap(long, default_value = "user_data")]
+  table: String,
+
+  /// Hex-encoded KV prefix of the namespace, including the trailing zero byte.
+  #[clap(long, default_value = "")]
+  prefix: String,
+
+  /// Hex-encoded start of the key range, relative to the prefix.
+  #[clap(long, default_value = "")]
+  start: String,
+
+  /// Hex-encoded exclusive end of the key range, relative to the prefix. Defaults to the end of
+  /// the prefix.
+  #[clap(long)]
+  end: Option<String>,
+
+  /// Max number of entries to print.
+  #[clap(long, default_value = "100")]
+  limit: u32,
+}
+
+#[derive(Error, Debug)]
+enum LocalError {
+  #[error("param count mismatch: expected {0}, got {1}")]
+  ParamCountMismatch(usize, usize),
+
+  #[error("unknown format: `{0}`")]
+  UnknownFormat(String),
+
+  #[error("unknown language: `{0}`")]
+  UnknownLanguage(String),
+
+  #[error("invalid table name: `{0}`")]
+  InvalidTableName(String),
+
+  #[error("cannot select multiple kv backends")]
+  MultipleBackends,
+
+  #[error("missing fdb-keyspace")]
+  MissingFdbKeyspace,
+
+  #[error("data encryption key must be 256 bits")]
+  BadEncryptionKey,
+}
+
+pub fn compile_schema(subopts: &CompileSchema) -> Result<()> {
+  let schema = load_schema(&subopts.schema)?;
+  print!("{}", schema);
+  Ok(())
+}
+
+pub fn plan(subopts: &Plan) -> Result<()> {
+  let schema = load_schema(&subopts.schema)?;
+  let plan = match (&subopts.old_schema, &subopts.old_plan) {
+    (Some(old_schema), Some(old_plan)) => {
+      let old_schema = load_schema(old_schema)?;
+      let old_plan = load_plan(old_plan)?;
+      generate_plan_for_schema_with_options(
+        &old_plan,
+        &old_schema,
+        &schema,
+        &PlannerOptions {
+          convert_primitives: subopts.convert_primitives,
+        },
+      )?
+    }
+    _ => generate_plan_for_schema(&Default::default(), &Default::default(), &schema)?,
+  };
+  print!(
+    "{}",
+    serde_yaml::to_string(&StoragePlan::<String>::from(&plan))?
+  );
+  Ok(())
+}
+
+pub fn doc(subopts: &Doc) -> Result<()> {
+  let schema = load_schema(&subopts.schema)?;
+  let plan = load_plan_or_generate(subopts.plan.as_deref(), &schema)?;
+  let doc = SchemaDoc::generate(&schema, &plan);
+  match subopts.format.as_str() {
+    "json" => println!("{}", serde_json::to_string_pretty(&doc)?),
+    "markdown" => print!("{}", doc.to_markdown()),
+    "html" => print!("{}", doc.to_html()),
+    _ => return Err(LocalError::UnknownFormat(subopts.format.clone()).into()),
+  }
+  Ok(())
+}
+
+pub fn check_script(subopts: &CheckScript) -> Result<()> {
+  let schema = load_schema(&subopts.schema)?;
+  let plan = load_plan_or_generate(subopts.plan.as_deref(), &schema)?;
+  let script = load_script(&subopts.script)?;
+  let vm = TwVm::new(&schema, &plan, &script)?;
+  let type_info = GlobalTyckContext::new(&vm)?.typeck()?;
+  let config = LintConfig {
+    read_only_graphs: subopts.read_only.iter().cloned().collect(),
+    ..Default::default()
+  };
+  let warnings = lint(&vm, &type_info, &config);
+  println!(
+    "{}",
+    serde_json::to_string(&serde_json::json!({
+      "ok": warnings.iter().all(|x| x.severity != Severity::Error),
+      "lint": warnings,
+    }))?
+  );
+  Ok(())
+}
+
+pub fn client_gen(subopts: &ClientGen) -> Result<()> {
+  let schema = load_schema(&subopts.schema)?;
+  let plan = load_plan_or_generate(subopts.plan.as_deref(), &schema)?;
+  let script = load_script(&subopts.script)?;
+  let vm = TwVm::new(&schema, &plan, &script)?;
+  let type_info = GlobalTyckContext::new(&vm)?.typeck()?;
+  let signatures = exported_graph_signatures(&vm, &type_info);
+  match subopts.lang.as_str() {
+    "rust" => print!("{}", generate_rust_client(&signatures)),
+    "typescript" => print!(
+      "{}",
+      generate_typescript_definitions(&signatures, &VmValueEncodeConfig::default())
+    ),
+    _ => return Err(LocalError::UnknownLanguage(subopts.lang.clone()).into()),
+  }
+  Ok(())
+}
+
+/// Lists a key range of a SQLite store and prints the path and value of each entry. Entries that
+/// cannot be decoded are printed with their raw key and value.
+pub fn inspect(subopts: &Inspect) -> Result<()> {
+  let schema = load_schema(&subopts.schema)?;
+  let plan = load_plan(&subopts.plan)?;
+  if !subopts
+    .table
+    .chars()
+    .all(|x| x.is_ascii_alphanumeric() || x == '_')
+  {
+    return Err(LocalError::InvalidTableName(subopts.table.clone()).into());
+  }
+
+  let prefix = hex::decode(&subopts.prefix)?;
+  let start = [prefix.clone(), hex::decode(&subopts.start)?].concat();
+  let end = match &subopts.end {
+    Some(x) => Some([prefix.clone(), hex::decode(x)?].concat()),
+    None => keyenc::successor_prefix(&prefix),
+  };
+
+  let conn = rusqlite::Connection::open_with_flags(
+    &subopts.sqlite_db,
+    rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
+  )?;
+  let mut stmt;
+  let mut rows = match &end {
+    Some(end) => {
+      stmt = conn.prepare(&format!(
+        "select k, v from {} where k >= ? and k < ? order by k limit ?",
+        subopts.table
+      ))?;
+      stmt.query(rusqlite::params![start, end, subopts.limit])?
+    }
+    None => {
+      stmt = conn.prepare(&format!(
+        "select k, v from {} where k >= ? order by k limit ?",
+        subopts.table
+      ))?;
+      stmt.query(rusqlite::params![start, subopts.limit])?
+    }
+  };
+  while let Some(row) = rows.next()? {
+    let key: Vec<u8> = row.get(0)?;
+    let value: Vec<u8> = row.get(1)?;
+    let key = &key[prefix.len()..];
+    let path = match inspect::decode_key(&plan, &schema, key) {
+      Ok(Some(x)) => x,
+      Ok(None) => {
+        println!("{} = {}", hex::encode(key), hex::encode(&value));
+        continue;
+      }
+      Err(e) => {
+        println!("{} = {} ({})", hex::encode(key), hex::encode(&value), e);
+        continue;
+      }
+    };
+    match path.decode_value(&value) {
+      Ok(x) => println!("{} = {}", path, x),
+      Err(e) => println!("{} = {} ({})", path, hex::encode(&value), e),
+    }
+  }
+  Ok(())
+}
+
+pub async fn run(subopts: &Run) -> Result<()> {
+  let schema = load_schema(&subopts.schema)?;
+  let plan = load_plan_or_generate(subopts.plan.as_deref(), &schema)?;
+  let mut script = load_script(&subopts.script)?;
+
+  // Declared before the store so that the network is stopped after the store is dropped.
+  let _network = match subopts.fdb_cluster {
+    Some(_) => Some(unsafe { foundationdb::boot() }),
+    None => None,
+  };
+  let kv = open_data_store(subopts)?;
+  let (output, profile) = run_exported_graph_profiled(
+    &schema,
+    &plan,
+    &mut script,
+    &*kv,
+    &subopts.graph,
+    &subopts.params,
+    subopts.profile,
+  )
+  .await?;
+  println!("{}", serde_json::to_string_pretty(&output)?);
+  if let Some(profile) = profile {
+    eprintln!("{}", serde_json::to_string_pretty(&profile)?);
+  }
+  Ok(())
+}
+
+/// Opens the namespace store selected by the options of `run`, wrapped the same way as the server
+/// does, or an in-memory store if no backend is selected. The FoundationDB network must be booted
+/// before an FDB store is opened.
+fn open_data_store(subopts: &Run) -> Result<Box<dyn KeyValueStore>> {
+  let prefix = hex::decode(&subopts.prefix)?;
+  let mut kv: Box<dyn KeyValueStore> = if let Some(x) = &subopts.fdb_cluster {
+    if subopts.sqlite_db.is_some() {
+      return Err(LocalError::MultipleBackends.into());
+    }
+    let keyspace = Subspace::from_bytes(
+      subopts
+        .fdb_keyspace
+        .as_ref()
+        .ok_or(LocalError::MissingFdbKeyspace)?
+        .as_bytes(),
+    );
+    let db = Arc::new(Database::new(Some(x))?);
+    Box::new(FdbKvStore::new(
+      db,
+      &[keyspace.subspace(&"D").bytes(), &prefix[..]].concat(),
+    ))
+  } else if let Some(x) = &subopts.sqlite_db {
+    if subopts.fdb_keyspace.is_some() {
+      return Err(LocalError::MultipleBackends.into());
+    }
+    Box::new(SqliteKvStore::new(
+      GlobalSqliteStore::open_leaky(x)?,
+      "user_data",
+      &prefix,
+    ))
+  } else {
+    Box::new(MockKv::new())
+  };
+
+  // Checksums go below encryption, so that they cover the stored ciphertext.
+  if subopts.data_checksums {
+    kv = Box::new(ChecksummedKv::new(kv));
+  }
+  if let Some(x) = &subopts.data_encryption_key_file {
+    let key = hex::decode(std::fs::read_to_string(x)?.trim())?;
+    if key.len() != std::mem::size_of::<EncryptionKey>() {
+      return Err(LocalError::BadEncryptionKey.into());
+    }
+    let mut k: EncryptionKey = Default::default();
+    k.copy_from_slice(&key);
+    kv = Box::new(EncryptedKv::new(kv, Arc::new(StaticKeyProvider::new(0, k))));
+  }
+  Ok(kv)
+}
+
+/// Runs an exported graph with parameters in JSON, after optimizing the script. Parameters of the
+/// `schema` type are filled in automatically.
+pub async fn run_exported_graph(
+  schema: &CompiledSchema,
+  plan: &StoragePlan,
+  script: &mut TwScript,
+  kv: &dyn KeyValueStore,
+  graph_name: &str,
+  params: &str,
+) -> Result<SerializedVmValue> {
+  run_exported_graph_profiled(schema, plan, script, kv, graph_name, params, false)
+    .await
+    .map(|x| x.0)
+}
+
+/// Like `run_exported_graph`, also returning the profile of the run if `profile` is set.
+pub async fn run_exported_graph_profiled(
+  schema: &CompiledSchema,
+  plan: &StoragePlan,
+  script: &mut TwScript,
+  kv: &dyn KeyValueStore,
+  graph_name: &str,
+  params: &str,
+  profile: bool,
+) -> Result<(SerializedVmValue, Option<GraphProfile>)> {
+  let vm = TwVm::new_optimized(schema, plan, script)?;
+  let type_info = GlobalTyckContext::new(&vm)?.typeck()?;
+  let root_map = Arc::new(generate_root_map(schema, plan)?);
+
+  let graph_index = vm.lookup_exported_graph_by_name(graph_name)?;
+  let param_types = &type_info.graphs[graph_index].params;
+  let raw_param_types = vm.script.graphs[graph_index]
+    .param_types
+    .iter()
+    .map(|x| &vm.types[*x as usize])
+    .collect::<Vec<_>>();
+
+  // Schema params are not provided by the user.
+  let params: Vec<SerializedVmValue> = serde_json::from_str(params)?;
+  let num_user_params = raw_param_types
+    .iter()
+    .filter(|x| !matches!(x, VmType::Schema))
+    .count();
+  if num_user_params != params.len() {
+    return Err(LocalError::ParamCountMismatch(num_user_params, params.len()).into());
+  }
+  let mut user_params = params.iter();
+  let params = param_types
+    .iter()
+    .zip(raw_param_types)
+    .map(|(ty, raw_ty)| match raw_ty {
+      VmType::Schema => Ok(root_map.clone()),
+      _ => user_params
+        .next()
+        .unwrap()
+        .decode_in(ty, schema, plan)
+        .map(Arc::new),
+    })
+    .collect::<Result<Vec<_>>>()?;
+
+  let mut executor = Executor::new(&vm, kv, &type_info);
+  executor.set_profiling(profile);
+  let output = executor.run_graph(graph_index, &params).await?;
+  let output = output
+    .map(|x| SerializedVmValue::encode(&*x, &VmValueEncodeConfig::default()))
+    .transpose()?
+    .unwrap_or_else(|| SerializedVmValue::Null(None));
+  Ok((output, executor.profile().cloned()))
+}
+
+pub fn explain(subopts: &Explain) -> Result<()> {
+  let mut script = load_script(&subopts.script)?;
+  if subopts.optimize {
+    optimize(&mut script);
+  }
+  print!("{}", script.explain());
+  Ok(())
+}
+
+pub fn load_schema(path: &str) -> Result<CompiledSchema> {
+  let text = std::fs::read_to_string(path)?;
+  parse(&Bump::new(), &text)
+    .and_then(|x| compile(&x))
+    .map_err(|e| match SchemaDiagnostic::from_error(&text, &e) {
+      Some(x) => anyhow::anyhow!("{}:{}", path, x),
+      None => e,
+    })
+}
+
+fn load_plan(path: &str) -> Result<StoragePlan> {
+  let plan: StoragePlan<String> = serde_yaml::from_str(&std::fs::read_to_string(path)?)?;
+  Ok(StoragePlan::<StorageKey>::try_from(&plan)?)
+}
+
+pub fn load_plan_or_generate(path: Option<&str>, schema: &CompiledSchema) -> Result<StoragePlan> {
+  match path {
+    Some(x) => load_plan(x),
+    None => generate_plan_for_schema(&Default::default(), &Default::default(), schema),
+  }
+}
+
+fn load_script(path: &str) -> Result<TwScript> {
+  compile_twscript(&std::fs::read_to_string(path)?)
+}
@@ -1,4 +1,6 @@
 mod diff;
+mod local;
+mod repl;
 
 use std::convert::TryFrom;
 
@@ -13,10 +15,10 @@ use rdb_analyzer::{
 };
 use rdb_proto::{
   proto::{
-    rdb_control_client::RdbControlClient, CreateDeploymentRequest, CreateNamespaceRequest,
-    CreateQueryScriptRequest, DeleteNamespaceRequest, DeleteQueryScriptRequest,
-    GetDeploymentRequest, GetQueryScriptRequest, ListDeploymentRequest, ListNamespaceRequest,
-    ListQueryScriptRequest,
+    rdb_control_client::RdbControlClient, AttachSchemaRequest, CreateDeploymentRequest,
+    CreateNamespaceRequest, CreateQueryScriptRequest, DeleteNamespaceRequest,
+    DeleteQueryScriptRequest, DetachSchemaRequest, GetDeploymentRequest, GetQueryScriptRequest,
+    ListDeploymentRequest, ListNamespaceRequest, ListQueryScriptRequest,
   },
   tonic::Request,
 };
@@ -30,9 +32,9 @@ use crate::diff::print_diff;
 #[clap(version = "0.1", author = "Heyang Zhou <zhy20000919@hotmail.com>")]
 #[clap(setting = AppSettings::ColoredHelp)]
 struct Opts {
-  /// Server URL.
+  /// Server URL. Not needed by local subcommands.
   #[clap(short, long)]
-  server: String,
+  server: Option<String>,
   #[clap(subcommand)]
   subcmd: SubCommand,
 }
@@ -48,6 +50,12 @@ enum SubCommand {
   /// Delete a namespace.
   DeleteNamespace(DeleteNamespace),
 
+  /// Attach the schema of the head deployment to a namespace again.
+  AttachSchema(AttachSchema),
+
+  /// Detach the schema from a namespace, so that it cannot be queried.
+  DetachSchema(DetachSchema),
+
   /// Create a deployment.
   CreateDeployment(CreateDeployment),
 
@@ -65,6 +73,33 @@ enum SubCommand {
 
   /// List query scripts.
   ListQueryScript(ListQueryScript),
+
+  /// Compile a schema locally and print the normalized result.
+  CompileSchema(local::CompileSchema),
+
+  /// Generate a storage plan locally, optionally migrating from an old schema and plan.
+  Plan(local::Plan),
+
+  /// Compile and typecheck a script against a schema locally.
+  CheckScript(local::CheckScript),
+
+  /// Generate documentation of a schema and its storage keys.
+  Doc(local::Doc),
+
+  /// Generate a typed client of the exported graphs of a script.
+  ClientGen(local::ClientGen),
+
+  /// Run an exported graph locally against an in-memory store, or the store of a server.
+  Run(local::Run),
+
+  /// Print the compiled graphs of a script.
+  Explain(local::Explain),
+
+  /// Start an interactive session with an in-memory store.
+  Repl(repl::Repl),
+
+  /// List a key range of a SQLite store with decoded paths and values.
+  Inspect(local::Inspect),
 }
 
 #[derive(Clap)]
@@ -80,6 +115,16 @@ struct DeleteNamespace {
   namespace_id: String,
 }
 
+#[derive(Clap)]
+struct AttachSchema {
+  namespace_id: String,
+}
+
+#[derive(Clap)]
+struct DetachSchema {
+  namespace_id: String,
+}
+
 #[derive(Clap)]
 struct CreateDeployment {
   /// The source deployment to migrate from.
@@ -121,6 +166,10 @@ struct CreateQueryScript {
   /// Path to the script.
   #[clap(short, long)]
   script: String,
+
+  /// Graphs that must not write to the store. Uploading fails if any of them does.
+  #[clap(long)]
+  read_only: Vec<String>,
 }
 
 #[derive(Clap)]
@@ -163,6 +212,9 @@ enum CliError {
 
   #[error("query script not found")]
   QueryScriptNotFound,
+
+  #[error("missing server url")]
+  MissingServerUrl,
 }
 
 #[tokio::main]
@@ -180,7 +232,25 @@ async fn main() -> Result<()> {
     std::process::exit(1);
   })?;
 
-  let mut client = RdbControlClient::connect(opts.server.clone()).await?;
+  // Local subcommands don't need a server.
+  match &opts.subcmd {
+    SubCommand::CompileSchema(x) => return local::compile_schema(x),
+    SubCommand::Plan(x) => return local::plan(x),
+    SubCommand::CheckScript(x) => return local::check_script(x),
+    SubCommand::Doc(x) => return local::doc(x),
+    SubCommand::ClientGen(x) => return local::client_gen(x),
+    SubCommand::Run(x) => return local::run(x).await,
+    SubCommand::Explain(x) => return local::explain(x),
+    SubCommand::Repl(x) => return repl::run_repl(x).await,
+    SubCommand::Inspect(x) => return local::inspect(x),
+    _ => {}
+  }
+
+  let server = opts
+    .server
+    .clone()
+    .ok_or_else(|| CliError::MissingServerUrl)?;
+  let mut client = RdbControlClient::connect(server).await?;
 
   match &opts.subcmd {
     SubCommand::CreateNamespace(x) => {
@@ -208,6 +278,7 @@ async fn main() -> Result<()> {
             .map(|x| serde_json::json!({
               "id": x.id,
               "create_time": x.create_time,
+              "schema_detach_time": x.schema_detach_time,
             }))
             .collect::<Vec<_>>()
         )?
@@ -225,6 +296,30 @@ async fn main() -> Result<()> {
         }))?
       );
     }
+    SubCommand::AttachSchema(x) => {
+      let req = Request::new(AttachSchemaRequest {
+        namespace_id: x.namespace_id.clone(),
+      });
+      let res = client.attach_schema(req).await?;
+      println!(
+        "{}",
+        serde_json::to_string(&serde_json::json!({
+          "attached": res.get_ref().attached,
+        }))?
+      );
+    }
+    SubCommand::DetachSchema(x) => {
+      let req = Request::new(DetachSchemaRequest {
+        namespace_id: x.namespace_id.clone(),
+      });
+      let res = client.detach_schema(req).await?;
+      println!(
+        "{}",
+        serde_json::to_string(&serde_json::json!({
+          "detached": res.get_ref().detached,
+        }))?
+      );
+    }
     SubCommand::CreateDeployment(subopts) => {
       let schema_text = std::fs::read_to_string(&subopts.schema)?;
 
@@ -271,6 +366,7 @@ async fn main() -> Result<()> {
           schema: schema_text,
           plan: serde_yaml::to_string(&StoragePlan::<String>::from(&new_plan))?,
           description: subopts.description.clone().unwrap_or_default(),
+          base_deployment_id: subopts.migrate_from.clone().unwrap_or_default(),
         }))
         .await?;
       let deployment_id = res
@@ -313,12 +409,14 @@ async fn main() -> Result<()> {
         id: subopts.id.clone(),
         associated_deployment: subopts.deployment.clone(),
         script,
+        read_only_graphs: subopts.read_only.clone(),
       });
       let res = client.create_query_script(req).await?;
       println!(
         "{}",
         serde_json::to_string(&serde_json::json!({
           "created": res.get_ref().created,
+          "lint_warnings": res.get_ref().lint_warnings,
         }))?
       );
     }
@@ -374,9 +472,19 @@ async fn main() -> Result<()> {
           "script": info.script,
           "associated_deployment": info.associated_deployment,
           "create_time": info.create_time,
+          "activate_time": info.activate_time,
         }))?
       );
     }
+    SubCommand::CompileSchema(_)
+    | SubCommand::Plan(_)
+    | SubCommand::CheckScript(_)
+    | SubCommand::Doc(_)
+    | SubCommand::ClientGen(_)
+    | SubCommand::Run(_)
+    | SubCommand::Explain(_)
+    | SubCommand::Repl(_)
+    | SubCommand::Inspect(_) => unreachable!(),
   }
 
   Ok(())
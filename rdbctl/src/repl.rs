@@ -0,0 +1,173 @@
+use std::io::{BufRead, Write};
+
+use anyhow::Result;
+use clap::Clap;
+use rdb_analyzer::{
+  data::{
+    kv::KeyValueStore,
+    mock_kv::MockKv,
+    query::{exec::exec_query_plan, parser::parse_statement, planner::QueryPlanner},
+    treewalker::{
+      asm::codegen::compile_twscript,
+      serialize::{SerializedVmValue, VmValueEncodeConfig},
+    },
+  },
+  schema::compile::CompiledSchema,
+  storage_plan::StoragePlan,
+};
+use tokio::task::block_in_place;
+
+use crate::local::{load_plan_or_generate, load_schema, run_exported_graph};
+
+#[derive(Clap)]
+pub struct Repl {
+  /// Path to the schema.
+  #[clap(long)]
+  schema: String,
+
+  /// Path to the storage plan (YAML). A fresh plan is generated if not provided.
+  #[clap(long)]
+  plan: Option<String>,
+}
+
+const HELP: &str = r#"Enter graph definitions, terminated by an empty line. Definitions are kept across inputs.
+
+Commands:
+  :run <graph> [params]  Run an exported graph. `params` is a JSON array without the `schema` parameter.
+  :query <statement>     Run a path query, e.g. `.items[id = 42].name`, `.items[id = 42].name = "x"`,
+                         `delete .items[id = 42]` or `insert .items { id: 42, name: "x" }`.
+  :graphs                List defined graphs.
+  :schema                Print the schema.
+  :reset                 Drop all graph definitions. Data in the store is kept.
+  :help                  Show this message.
+  :quit                  Exit.
+"#;
+
+/// An interactive session with a compiled schema and an in-memory store that lives as long as the
+/// session.
+pub async fn run_repl(subopts: &Repl) -> Result<()> {
+  let schema = load_schema(&subopts.schema)?;
+  let plan = load_plan_or_generate(subopts.plan.as_deref(), &schema)?;
+  let kv = MockKv::new();
+
+  // Accepted graph definitions.
+  let mut source = String::new();
+
+  // Lines of the definition being entered.
+  let mut pending = String::new();
+
+  println!("{}", HELP);
+  loop {
+    let line = match read_line(if pending.is_empty() { "> " } else { ". " })? {
+      Some(x) => x,
+      None => break,
+    };
+    let trimmed = line.trim();
+
+    if !pending.is_empty() {
+      if !trimmed.is_empty() {
+        pending.push_str(&line);
+        pending.push('\n');
+        continue;
+      }
+
+      // Validate the new definitions together with the existing ones before accepting them.
+      let candidate = format!("{}{}", source, pending);
+      pending.clear();
+      match compile_twscript(&candidate) {
+        Ok(_) => source = candidate,
+        Err(e) => println!("error: {}", e),
+      }
+      continue;
+    }
+
+    if trimmed.is_empty() {
+      continue;
+    }
+    if !trimmed.starts_with(':') {
+      pending.push_str(&line);
+      pending.push('\n');
+      continue;
+    }
+
+    let (cmd, args) = match trimmed.find(' ') {
+      Some(i) => (&trimmed[..i], trimmed[i + 1..].trim()),
+      None => (trimmed, ""),
+    };
+    match cmd {
+      ":run" => {
+        let (graph, params) = match args.find(' ') {
+          Some(i) => (&args[..i], args[i + 1..].trim()),
+          None => (args, "[]"),
+        };
+        let res = match compile_twscript(&source) {
+          Ok(mut script) => {
+            run_exported_graph(&schema, &plan, &mut script, &kv, graph, params).await
+          }
+          Err(e) => Err(e),
+        };
+        match res {
+          Ok(x) => println!("{}", serde_json::to_string_pretty(&x)?),
+          Err(e) => println!("error: {}", e),
+        }
+      }
+      ":query" => match run_path_query(&schema, &plan, &kv, args).await {
+        Ok(x) => println!("{}", serde_json::to_string_pretty(&x)?),
+        Err(e) => println!("error: {}", e),
+      },
+      ":graphs" => match compile_twscript(&source) {
+        Ok(script) => {
+          for g in &script.graphs {
+            println!("{}{}", if g.exported { "export " } else { "" }, g.name);
+          }
+        }
+        Err(e) => println!("error: {}", e),
+      },
+      ":schema" => print!("{}", schema),
+      ":reset" => source.clear(),
+      ":help" => println!("{}", HELP),
+      ":quit" => break,
+      _ => println!("unknown command: {}", cmd),
+    }
+  }
+  Ok(())
+}
+
+async fn run_path_query(
+  schema: &CompiledSchema,
+  plan: &StoragePlan,
+  kv: &dyn KeyValueStore,
+  stmt: &str,
+) -> Result<SerializedVmValue> {
+  let mut planner = QueryPlanner::new(schema);
+  planner.add_statement(&parse_statement(stmt)?)?;
+  let query_plan = planner.finish()?;
+  let txn = kv.begin_transaction().await?;
+  let output = exec_query_plan(
+    schema,
+    plan,
+    &*txn,
+    &query_plan,
+    &VmValueEncodeConfig::default(),
+  )
+  .await?;
+  txn.commit().await?;
+  Ok(
+    output
+      .into_iter()
+      .next()
+      .unwrap_or_else(|| SerializedVmValue::Null(None)),
+  )
+}
+
+fn read_line(prompt: &str) -> Result<Option<String>> {
+  block_in_place(|| {
+    print!("{}", prompt);
+    std::io::stdout().flush()?;
+    let mut line = String::new();
+    if std::io::stdin().lock().read_line(&mut line)? == 0 {
+      return Ok(None);
+    }
+    Ok(Some(line.trim_end_matches(&['\r', '\n'][..]).to_string()))
+  })
+}